@@ -69,6 +69,11 @@ fn bench_re_constraint(c: &mut Criterion) {
     c.bench_function("re_email3_get_valid_continuations", |b| {
         b.iter(|| re.get_valid_continuations(&state))
     });
+    let re = re.with_sorted_continuations();
+    let state = re.get_state(b"test").unwrap();
+    c.bench_function("re_email1_sorted_get_valid_continuations", |b| {
+        b.iter(|| re.get_valid_continuations(&state))
+    });
 
     let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/re-examples");
     let files = ["json.txt", "template.txt", "triples.txt"];
@@ -96,6 +101,11 @@ fn bench_re_constraint(c: &mut Criterion) {
             &format!("re_file_{file_name}_get_valid_continuations"),
             |b| b.iter(|| re.get_valid_continuations(&state)),
         );
+        let re = re.with_sorted_continuations();
+        c.bench_function(
+            &format!("re_file_{file_name}_sorted_get_valid_continuations"),
+            |b| b.iter(|| re.get_valid_continuations(&state)),
+        );
     }
 }
 
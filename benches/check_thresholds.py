@@ -0,0 +1,75 @@
+"""
+
+Compares the latest run of the benchmark suite against a saved baseline and
+fails if a benchmark got slower than the threshold of its group allows.
+
+Save a baseline before a change and check after it:
+    cargo bench --bench suite -- --save-baseline main
+    cargo bench --bench suite -- --baseline main
+    python benches/check_thresholds.py main
+
+Thresholds are maximum slowdowns of the mean in percent per benchmark group,
+see benches/thresholds.json; groups without an entry use the default.
+
+"""
+
+import argparse
+import json
+import sys
+from pathlib import Path
+
+ROOT = Path(__file__).parent.parent
+
+
+def mean(path: Path) -> float:
+    with open(path / "estimates.json") as f:
+        return json.load(f)["mean"]["point_estimate"]
+
+
+def parse_args() -> argparse.Namespace:
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("baseline", help="name of the saved criterion baseline")
+    parser.add_argument(
+        "--criterion-dir",
+        type=Path,
+        default=ROOT / "target" / "criterion",
+    )
+    parser.add_argument(
+        "--thresholds",
+        type=Path,
+        default=Path(__file__).parent / "thresholds.json",
+    )
+    return parser.parse_args()
+
+
+def main() -> int:
+    args = parse_args()
+    with open(args.thresholds) as f:
+        thresholds = json.load(f)
+
+    regressions = []
+    compared = 0
+    # criterion keeps every benchmark in <group>/<function>/<parameter>
+    for new in sorted(args.criterion_dir.glob("*/*/*/new")):
+        base = new.parent / args.baseline
+        if not (base / "estimates.json").exists():
+            continue
+        group = new.relative_to(args.criterion_dir).parts[0]
+        threshold = thresholds.get(group, thresholds["default"])
+        change = (mean(new) / mean(base) - 1) * 100
+        compared += 1
+        name = "/".join(new.parent.relative_to(args.criterion_dir).parts)
+        if change > threshold:
+            regressions.append((name, change, threshold))
+
+    if compared == 0:
+        print(f"no benchmarks with baseline {args.baseline} found", file=sys.stderr)
+        return 1
+    for name, change, threshold in regressions:
+        print(f"{name}: {change:+.1f}% (threshold {threshold:.1f}%)")
+    print(f"{compared} benchmarks compared, {len(regressions)} regressions")
+    return 1 if regressions else 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
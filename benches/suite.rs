@@ -0,0 +1,253 @@
+// benchmark suite over fixture vocabularies with the sizes of real tokenizers,
+// grouped by feature so regressions can be checked per group against a saved
+// baseline, see benches/thresholds.json and benches/check_thresholds.py
+use std::fs;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Once;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use grammar_utils::{
+    Constraint, ExactLR1GrammarConstraint, LR1GrammarConstraint, RegularExpressionConstraint,
+};
+use lru::LruCache;
+
+const VOCABS: [&str; 4] = ["llama2", "t5", "gpt2", "llama3"];
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+// the synthetic vocabularies are not checked in, but generated
+// deterministically on first use, which takes about a second
+fn generate_vocabs() {
+    static GENERATE: Once = Once::new();
+    GENERATE.call_once(|| {
+        let dir = manifest_dir().join("resources/bench");
+        if VOCABS
+            .iter()
+            .filter(|&&name| name != "llama2")
+            .all(|name| dir.join(format!("{name}.vocab")).exists())
+        {
+            return;
+        }
+        let status = Command::new("python3")
+            .arg(dir.join("generate_vocabs.py"))
+            .status()
+            .expect("python3 is needed to generate the benchmark vocabularies");
+        assert!(
+            status.success(),
+            "failed to generate the benchmark vocabularies"
+        );
+    });
+}
+
+// llama2 is the vocabulary from the tests, the others are synthetic fixtures
+// generated by resources/bench/generate_vocabs.py
+fn load_vocab(name: &str) -> Vec<Vec<u8>> {
+    if name == "llama2" {
+        let json = fs::read(manifest_dir().join("resources/test/continuations.json")).unwrap();
+        return serde_json::from_slice::<Vec<String>>(&json)
+            .unwrap()
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+    }
+    generate_vocabs();
+    let data = fs::read(manifest_dir().join(format!("resources/bench/{name}.vocab"))).unwrap();
+    let (count, mut rest) = data.split_at(4);
+    let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+    let mut vocab = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = rest[0] as usize;
+        vocab.push(rest[1..=len].to_vec());
+        rest = &rest[len + 1..];
+    }
+    assert!(rest.is_empty(), "trailing bytes in vocabulary {name}");
+    vocab
+}
+
+fn grammar_files(name: &str) -> (PathBuf, PathBuf) {
+    let dir = manifest_dir().join("grammars").join(name);
+    (dir.join(format!("{name}.y")), dir.join(format!("{name}.l")))
+}
+
+fn example(grammar: &str, name: &str) -> Vec<u8> {
+    let path = manifest_dir()
+        .join("grammars")
+        .join(grammar)
+        .join("examples")
+        .join(format!("{name}.txt"));
+    fs::read_to_string(path)
+        .unwrap()
+        .trim_end_matches(['\r', '\n'])
+        .as_bytes()
+        .to_vec()
+}
+
+// states along the greedy encoding of the text, like during generation;
+// stops early if the vocabulary cannot encode the rest, e.g. t5 has no
+// byte tokens for newlines
+fn trajectory<C>(constraint: &C, vocab: &[Vec<u8>], text: &[u8]) -> Vec<C::State>
+where
+    C: Constraint,
+    C::State: Clone,
+{
+    let mut state = constraint.get_start_state();
+    let mut states = vec![state.clone()];
+    let mut i = 0;
+    while i < text.len() {
+        let Some((cont, len)) = constraint
+            .get_valid_continuations(&state)
+            .into_iter()
+            .map(|cont| (cont, vocab[cont].len()))
+            .filter(|&(cont, len)| len > 0 && text[i..].starts_with(&vocab[cont]))
+            .max_by_key(|&(_, len)| len)
+        else {
+            break;
+        };
+        state = constraint.get_next_state(&state, cont).unwrap();
+        states.push(state.clone());
+        i += len;
+    }
+    states
+}
+
+fn bench_masks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("masks");
+    let (grammar, lexer) = grammar_files("json");
+    let text = example("json", "glossary");
+    for name in VOCABS {
+        let vocab = load_vocab(name);
+        let mut mask = vec![0; vocab.len().div_ceil(32)];
+
+        let re = RegularExpressionConstraint::new(r"\w+@\w+\.(com|de|org)", vocab.clone())
+            .unwrap()
+            .with_sorted_continuations();
+        let state = re.get_state(b"test@gmai").unwrap();
+        group.bench_function(BenchmarkId::new("re_continuations", name), |b| {
+            b.iter(|| re.get_valid_continuations(&state))
+        });
+        group.bench_function(BenchmarkId::new("re_pack_u32", name), |b| {
+            b.iter(|| re.pack_mask_u32(&state, &mut mask))
+        });
+
+        let lr1 = LR1GrammarConstraint::from_files(&grammar, &lexer, vocab.clone()).unwrap();
+        let state = lr1.get_state(&text[..text.len() / 2]).unwrap();
+        group.bench_function(BenchmarkId::new("lr1_json_continuations", name), |b| {
+            b.iter(|| lr1.get_valid_continuations(&state))
+        });
+        group.bench_function(BenchmarkId::new("lr1_json_pack_u32", name), |b| {
+            b.iter(|| lr1.pack_mask_u32(&state, &mut mask))
+        });
+    }
+    group.finish();
+}
+
+fn bench_batch_advance(c: &mut Criterion) {
+    // advancing one state by all of its valid continuations, like when
+    // scoring candidates or expanding beams
+    let mut group = c.benchmark_group("batch_advance");
+    for (grammar_name, example_name) in [("json", "glossary"), ("sparql", "select_where")] {
+        let (grammar, lexer) = grammar_files(grammar_name);
+        let text = example(grammar_name, example_name);
+        for name in VOCABS {
+            let vocab = load_vocab(name);
+            let lr1 = LR1GrammarConstraint::from_files(&grammar, &lexer, vocab).unwrap();
+            let state = lr1.get_state(&text[..text.len() / 2]).unwrap();
+            let conts = lr1.get_valid_continuations(&state);
+            group.bench_function(BenchmarkId::new(grammar_name, name), |b| {
+                b.iter(|| {
+                    conts
+                        .iter()
+                        .filter_map(|&cont| lr1.get_next_state(&state, cont))
+                        .count()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_exact_vs_regular(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exact_vs_regular");
+    for (grammar_name, example_name) in [("json", "numbers"), ("sparql", "prefix")] {
+        let (grammar, lexer) = grammar_files(grammar_name);
+        let text = example(grammar_name, example_name);
+        let prefix = &text[..text.len() / 2];
+        for name in VOCABS {
+            let vocab = load_vocab(name);
+            let exact =
+                ExactLR1GrammarConstraint::from_files(&grammar, &lexer, vocab.clone()).unwrap();
+            let state = exact.get_state(prefix).unwrap();
+            group.bench_function(
+                BenchmarkId::new(format!("exact_{grammar_name}"), name),
+                |b| b.iter(|| exact.get_valid_continuations(&state)),
+            );
+            let regular = LR1GrammarConstraint::from_files(&grammar, &lexer, vocab).unwrap();
+            let state = regular.get_state(prefix).unwrap();
+            group.bench_function(
+                BenchmarkId::new(format!("regular_{grammar_name}"), name),
+                |b| b.iter(|| regular.get_valid_continuations(&state)),
+            );
+        }
+    }
+    group.finish();
+}
+
+// valid continuations of all states along a trajectory, optionally cached per
+// state like the lru cache of the python constraints
+fn replay<C: Constraint>(
+    constraint: &C,
+    states: &[C::State],
+    cache: Option<&mut LruCache<C::State, Vec<usize>>>,
+) -> usize
+where
+    C::State: Clone + Hash + Eq,
+{
+    match cache {
+        Some(cache) => states
+            .iter()
+            .map(|state| {
+                cache
+                    .get_or_insert_ref(state, || constraint.get_valid_continuations(state))
+                    .len()
+            })
+            .sum(),
+        None => states
+            .iter()
+            .map(|state| constraint.get_valid_continuations(state).len())
+            .sum(),
+    }
+}
+
+fn bench_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache");
+    let (grammar, lexer) = grammar_files("json");
+    let text = example("json", "numbers");
+    for name in VOCABS {
+        let vocab = load_vocab(name);
+        let lr1 = LR1GrammarConstraint::from_files(&grammar, &lexer, vocab.clone()).unwrap();
+        let states = trajectory(&lr1, &vocab, &text);
+        group.bench_function(BenchmarkId::new("off", name), |b| {
+            b.iter(|| replay(&lr1, &states, None))
+        });
+        // the cache stays warm across iterations, as for repeated requests
+        let mut cache = LruCache::new(NonZeroUsize::new(1024).unwrap());
+        group.bench_function(BenchmarkId::new("on", name), |b| {
+            b.iter(|| replay(&lr1, &states, Some(&mut cache)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_masks,
+    bench_batch_advance,
+    bench_exact_vs_regular,
+    bench_cache
+);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+%start csv
+// rfc 4180, a trailing newline ends the last record with an empty field
+
+%%
+
+csv
+    : record
+    | csv 'NEWLINE' record
+    ;
+
+record
+    : field
+    | record ',' field
+    ;
+
+field
+    :
+    | 'FIELD'
+    | 'QUOTED'
+    ;
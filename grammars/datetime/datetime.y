@@ -0,0 +1,19 @@
+%start datetime
+// iso 8601 dates with optional time of day and time zone in extended format
+
+%%
+
+datetime
+    : 'DATE'
+    | 'DATE' 'T' time
+    ;
+
+time
+    : 'TIME'
+    | 'TIME' zone
+    ;
+
+zone
+    : 'Z'
+    | 'OFFSET'
+    ;
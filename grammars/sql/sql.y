@@ -0,0 +1,162 @@
+%start query
+// a subset of sql: single select statements with joins, filters,
+// grouping, ordering and limits
+
+%%
+
+query
+    : select
+    | select ';'
+    ;
+
+select
+    : 'SELECT' distinct columns 'FROM' tables where group having order limit
+    ;
+
+distinct
+    :
+    | 'DISTINCT'
+    ;
+
+columns
+    : '*'
+    | column_list
+    ;
+
+column_list
+    : column
+    | column_list ',' column
+    ;
+
+column
+    : expr
+    | expr 'AS' 'IDENT'
+    ;
+
+tables
+    : table
+    | tables ',' table
+    | tables join table 'ON' expr
+    ;
+
+join
+    : 'JOIN'
+    | 'INNER' 'JOIN'
+    | 'LEFT' 'JOIN'
+    | 'LEFT' 'OUTER' 'JOIN'
+    ;
+
+table
+    : name
+    | name 'IDENT'
+    | name 'AS' 'IDENT'
+    ;
+
+name
+    : 'IDENT'
+    | 'IDENT' '.' 'IDENT'
+    ;
+
+where
+    :
+    | 'WHERE' expr
+    ;
+
+group
+    :
+    | 'GROUP' 'BY' exprs
+    ;
+
+having
+    :
+    | 'HAVING' expr
+    ;
+
+order
+    :
+    | 'ORDER' 'BY' order_items
+    ;
+
+order_items
+    : order_item
+    | order_items ',' order_item
+    ;
+
+order_item
+    : expr
+    | expr 'ASC'
+    | expr 'DESC'
+    ;
+
+limit
+    :
+    | 'LIMIT' 'INTEGER'
+    | 'LIMIT' 'INTEGER' 'OFFSET' 'INTEGER'
+    ;
+
+exprs
+    : expr
+    | exprs ',' expr
+    ;
+
+expr
+    : expr 'OR' conjunction
+    | conjunction
+    ;
+
+conjunction
+    : conjunction 'AND' negation
+    | negation
+    ;
+
+negation
+    : 'NOT' negation
+    | comparison
+    ;
+
+comparison
+    : sum
+    | sum compare sum
+    | sum 'IS' 'NULL'
+    | sum 'IS' 'NOT' 'NULL'
+    | sum 'IN' '(' exprs ')'
+    | sum 'LIKE' sum
+    | sum 'BETWEEN' sum 'AND' sum
+    ;
+
+compare
+    : '='
+    | '<>'
+    | '!='
+    | '<'
+    | '<='
+    | '>'
+    | '>='
+    ;
+
+sum
+    : sum '+' product
+    | sum '-' product
+    | product
+    ;
+
+product
+    : product '*' factor
+    | product '/' factor
+    | factor
+    ;
+
+factor
+    : 'INTEGER'
+    | 'FLOAT'
+    | 'STRING'
+    | 'NULL'
+    | 'TRUE'
+    | 'FALSE'
+    | name
+    | 'IDENT' '(' ')'
+    | 'IDENT' '(' '*' ')'
+    | 'IDENT' '(' exprs ')'
+    | '(' expr ')'
+    | '-' factor
+    ;
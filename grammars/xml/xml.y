@@ -0,0 +1,22 @@
+%start fragment
+// xml fragments, i.e. a sequence of elements, text and comments without
+// prolog or doctype; closing tags are not checked to match the opening ones
+
+%%
+
+fragment
+    :
+    | fragment item
+    ;
+
+item
+    : element
+    | 'TEXT'
+    | 'COMMENT'
+    | 'CDATA'
+    ;
+
+element
+    : 'EMPTY_TAG'
+    | 'START_TAG' fragment 'END_TAG'
+    ;
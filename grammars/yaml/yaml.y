@@ -0,0 +1,65 @@
+%start yaml
+// a yaml subset: a flat block mapping or sequence, or a flow collection;
+// indentation is not significant, so nesting uses flow collections
+
+%%
+
+yaml
+    : document
+    | 'NEWLINE' document
+    ;
+
+document
+    : mapping
+    | sequence
+    | flow
+    | flow 'NEWLINE'
+    ;
+
+mapping
+    : pair
+    | pair 'NEWLINE'
+    | pair 'NEWLINE' mapping
+    ;
+
+pair
+    : scalar ':' value
+    ;
+
+sequence
+    : item
+    | item 'NEWLINE'
+    | item 'NEWLINE' sequence
+    ;
+
+item
+    : '-' value
+    ;
+
+value
+    : scalar
+    | flow
+    ;
+
+flow
+    : '[' ']'
+    | '[' flow_items ']'
+    | '{' '}'
+    | '{' flow_pairs '}'
+    ;
+
+flow_items
+    : value
+    | flow_items ',' value
+    ;
+
+flow_pairs
+    : pair
+    | flow_pairs ',' pair
+    ;
+
+scalar
+    : 'PLAIN'
+    | 'DOUBLE_QUOTED'
+    | 'SINGLE_QUOTED'
+    ;
@@ -1,5 +1,7 @@
 from importlib import metadata
 
+from grammar_utils._internal import Guide, grammar, json_schema, regex  # noqa
+
 try:
     __version__ = metadata.version("grammar_utils")
 except metadata.PackageNotFoundError:
@@ -1,35 +1,4849 @@
 """Type stubs for grammar_utils._internal module."""
 
-from typing import Any, final
+from typing import Any, Callable, ClassVar, Iterable, TypeAlias, final
 
 import numpy as np
 import numpy.typing as npt
 
+Continuations: TypeAlias = (
+    Iterable[bytes | list[int]] | Callable[[], Iterable[bytes | list[int]]]
+)
+"""
+Continuations (vocabulary) as any iterable of byte sequences, e.g. a list or a
+generator, or as a callable returning one. They are converted one at a time, so
+a generator avoids keeping the whole vocabulary as a Python list.
+"""
+
+def set_memory_limit(limit: int | None = None, policy: str = "deny") -> None:
+    """
+    Set the global memory budget shared by all constraints.
+
+    Args:
+        limit: Budget in bytes, None for no limit (default: None)
+        policy: What to do when the budget is exhausted, either "deny"
+            (constructing constraints fails, caches stop growing) or "evict"
+            (evict cache entries first) (default: "deny")
+    """
+    ...
+
+def memory_used() -> int:
+    """
+    Get the number of bytes currently accounted against the global memory budget.
+
+    Returns:
+        Number of bytes
+    """
+    ...
+
+def abnf_to_lr1(abnf: str) -> tuple[str, str]:
+    """
+    Convert a grammar in ABNF notation (RFC 5234, with the %s and %i strings
+    of RFC 7405) into an LR(1) grammar and lexer usable with LR1Constraint
+    and LR1Parser. The first rule is the start rule, rule names and quoted
+    strings are case-insensitive, and core rules like ALPHA, DIGIT or CRLF
+    are added when referenced. Like for gbnf_to_lr1, regular rules are
+    inlined into tokens. Prose values (<...>) are not supported.
+
+    Args:
+        abnf: Grammar in ABNF notation
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def distinguish_regex(left: str, right: str) -> tuple[bytes | None, bytes | None]:
+    """
+    Find the shortest inputs matched by only one of two regular
+    expressions. Both are None if and only if the regular expressions
+    are equivalent.
+
+    Args:
+        left: First regular expression
+        right: Second regular expression
+
+    Returns:
+        Shortest input only left matches and shortest input only right matches
+    """
+    ...
+
+def ebnf_to_lr1(ebnf: str) -> tuple[str, str]:
+    """
+    Convert a grammar in ISO (name = a, b | c ;) or W3C (Name ::= a b | c)
+    EBNF notation into an LR(1) grammar and lexer usable with LR1Constraint
+    and LR1Parser. The notation is detected by the ::= of W3C rules and the
+    first rule is the start rule. Like for gbnf_to_lr1, regular rules are
+    inlined into tokens. Exceptions (a - b) are only supported between sets
+    of single characters.
+
+    Args:
+        ebnf: Grammar in ISO or W3C EBNF notation
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def gbnf_to_lr1(gbnf: str) -> tuple[str, str]:
+    """
+    Convert a grammar in the GBNF format of llama.cpp into an LR(1)
+    grammar and lexer usable with LR1Constraint and LR1Parser. Regular
+    rules are inlined into tokens, which works as long as the input can
+    be split into these tokens by longest match. The start rule is root.
+
+    Args:
+        gbnf: Grammar in GBNF format
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def guidance_to_lr1(json: str) -> tuple[str, str]:
+    """
+    Convert a grammar in the JSON format of guidance / llguidance into
+    an LR(1) grammar and lexer usable with LR1Constraint and LR1Parser.
+    Supported are String, Join, Select, Lexeme and Gen (without stop
+    regex) nodes, node 0 is the start node.
+
+    Args:
+        json: Grammar in guidance JSON format
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def json_schema_to_lr1(schema: str) -> tuple[str, str]:
+    """
+    Convert a JSON schema into an LR(1) grammar and lexer for JSON documents
+    following the schema. Supported is roughly the strict mode subset of
+    structured outputs: properties are generated in schema order, and no
+    additional properties are generated if properties are given. String
+    patterns are matched against the raw string content and must not
+    overlap with each other.
+
+    Args:
+        schema: JSON schema as string
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def dtd_to_lr1(dtd: str, root: str) -> tuple[str, str]:
+    """
+    Convert a document type definition into an LR(1) grammar and lexer for
+    XML documents with the given root element. Content models, matching
+    close tags and attributes with enumerated or fixed values are enforced;
+    attributes have to be written in declaration order and > in text as
+    &gt;. Parameter entities are not supported.
+
+    Args:
+        dtd: Document type definition as string, optionally wrapped in a
+            <!DOCTYPE root [...]> declaration
+        root: Name of the root element
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def proto_to_lr1(proto: str, message: str, format: str = "text") -> tuple[str, str]:
+    """
+    Convert a message of a .proto definition into an LR(1) grammar and lexer
+    for that message in protobuf text format or the canonical JSON mapping.
+    Fields are generated in declaration order, required fields must be set
+    and at most one field of a oneof is set; 64 bit integers are quoted in
+    JSON. Imports, groups and extensions are not supported.
+
+    Args:
+        proto: .proto definition as string
+        message: Name of the message, e.g. Person or pkg.Outer.Inner
+        format: Output format, text or json (default: text)
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def strftime_to_regex(layout: str) -> str:
+    """
+    Convert a strftime layout like %Y-%m-%dT%H:%M:%SZ into a regular
+    expression matching exactly the valid dates and times in that layout,
+    including leap days. Supported are %Y %y %m %b %B %d %e %j %H %I %M %S
+    %f %p %a %A %u %w %z %:z %Z %F %T %R %D %n %t and %%. Weekday names are
+    not checked against the date.
+
+    Args:
+        layout: strftime layout
+
+    Returns:
+        Regular expression for the layout
+    """
+    ...
+
+def lark_to_lr1(lark: str) -> tuple[str, str]:
+    """
+    Convert a Lark grammar with rules, terminals, %import of the common
+    terminals and %ignore into an LR(1) grammar and lexer usable with
+    LR1Constraint and LR1Parser. The start rule is start. Aliases and
+    rule modifiers only shape the Lark parse tree and are dropped. Unlike
+    the contextual lexer of Lark, the lexer always takes the longest match,
+    preferring higher priorities and then literals on ties.
+
+    Args:
+        lark: Grammar in Lark format
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def grammar_docs(grammar: str, lexer: str, format: str = "markdown") -> str:
+    """
+    Generate human readable documentation for an LR(1) grammar and lexer,
+    with a syntax diagram and an example per rule and a table of all
+    terminals with their patterns and examples.
+
+    Args:
+        grammar: Grammar definition
+        lexer: Lexer definition
+        format: Output format, either markdown or html (default: markdown)
+
+    Returns:
+        Documentation in the given format
+    """
+    ...
+
+def inline_rules(grammar: str) -> str:
+    """
+    Inline rules with a single production into the rules using them,
+    which shrinks the LR(1) table without changing the language.
+    Rules in a cycle, with precedence, and the start rule are kept.
+
+    Args:
+        grammar: Grammar definition without actions
+
+    Returns:
+        Grammar with the rules inlined
+    """
+    ...
+
+def builtin_grammar(name: str) -> tuple[str, str]:
+    """
+    Get a built-in grammar for a common format, ready to use with
+    LR1Constraint and LR1Parser.
+
+    Args:
+        name: Name of the grammar, one of builtin_grammars()
+
+    Returns:
+        Tuple of grammar and lexer definition
+    """
+    ...
+
+def builtin_grammars() -> list[str]:
+    """
+    Get the names of all built-in grammars, e.g. json, yaml (a flat
+    subset), csv, xml (fragments), datetime (ISO 8601), sql (a select
+    subset) and sparql.
+
+    Returns:
+        List of grammar names in alphabetical order
+    """
+    ...
+
+def lr1_to_guidance(grammar: str, lexer: str) -> str:
+    """
+    Convert an LR(1) grammar and lexer into the JSON format of
+    guidance / llguidance.
+
+    Args:
+        grammar: Grammar definition
+        lexer: Lexer definition
+
+    Returns:
+        Grammar in guidance JSON format
+    """
+    ...
+
+def select(tree: dict[str, Any], query: str) -> list[dict[str, Any]]:
+    """
+    Select the nodes of a parse tree matching an XPath-like query, e.g.
+    call/args//arg[2]. Steps are separated by / for children and // for
+    descendants, a leading / matches the root itself and * matches any name.
+    Predicates are [n] for the n-th match below the same node (negative from
+    the end), [="text"] for the node text, [name] for nodes with a child of
+    that name and [name="text"] for nodes with such a child with that text.
+
+    Args:
+        tree: Parse tree as returned by LR1Parser.parse
+        query: Query to select nodes with
+
+    Returns:
+        Matching nodes of the tree in document order
+    """
+    ...
+
+def run_length_order(continuations: Continuations) -> list[int]:
+    """
+    Get a vocabulary order that keeps continuations with common prefixes
+    adjacent, so valid continuations form few ranges after reordering.
+
+    Args:
+        continuations: List of continuations as byte sequences
+
+    Returns:
+        Permutation of the continuation indices
+    """
+    ...
+
+def json_schema(
+    schema: str | dict[str, Any],
+    vocab: Continuations,
+    on_invalid: str = "sticky",
+) -> Guide:
+    """
+    Create a guide for JSON documents following a JSON schema.
+
+    Args:
+        schema: JSON schema as string or dict
+        vocab: Continuations as byte sequences, e.g. the tokenizer vocabulary
+        on_invalid: What advance does with invalid tokens, one of "sticky",
+            "raise" or "reset" (default: "sticky")
+
+    Returns:
+        Guide for the schema
+    """
+    ...
+
+def regex(
+    pattern: str,
+    vocab: Continuations,
+    on_invalid: str = "sticky",
+) -> Guide:
+    """
+    Create a guide for texts matching a regular expression.
+
+    Args:
+        pattern: Regular expression
+        vocab: Continuations as byte sequences, e.g. the tokenizer vocabulary
+        on_invalid: What advance does with invalid tokens, one of "sticky",
+            "raise" or "reset" (default: "sticky")
+
+    Returns:
+        Guide for the regular expression
+    """
+    ...
+
+def grammar(
+    grammar: str,
+    lexer: str,
+    vocab: Continuations,
+    on_invalid: str = "sticky",
+) -> Guide:
+    """
+    Create a guide for texts in the language of an LR(1) grammar.
+
+    Args:
+        grammar: LR(1) grammar definition
+        lexer: Lexer definition
+        vocab: Continuations as byte sequences, e.g. the tokenizer vocabulary
+        on_invalid: What advance does with invalid tokens, one of "sticky",
+            "raise" or "reset" (default: "sticky")
+
+    Returns:
+        Guide for the grammar
+    """
+    ...
+
+@final
+class Guide:
+    """
+    High level constraint returned by json_schema, regex and grammar, with
+    the same interface regardless of the constraint behind it.
+    """
+
+    @property
+    def finished(self) -> bool:
+        """Whether no further token can follow, because the text is complete or invalid."""
+        ...
+
+    @property
+    def is_match(self) -> bool:
+        """Whether the current text is a complete match."""
+        ...
+
+    @property
+    def vocab_size(self) -> int:
+        """Size of the vocabulary the guide was created with."""
+        ...
+
+    def mask(self) -> npt.NDArray[np.bool_]:
+        """
+        Get the allowed tokens in the current state.
+
+        Returns:
+            Boolean array of the vocabulary size, True for allowed tokens
+        """
+        ...
+
+    def allowed(self) -> npt.NDArray[np.int32]:
+        """
+        Get the indices of the allowed tokens in the current state.
+
+        Returns:
+            Array of allowed token indices
+        """
+        ...
+
+    def advance(self, token: int) -> None:
+        """
+        Advance the state by the chosen token.
+        Invalid tokens are handled according to on_invalid.
+
+        Args:
+            token: Token index to advance by
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the guide to the state after the given prefix.
+
+        Args:
+            prefix: Prefix to reset to, None for an empty prefix (default: None)
+        """
+        ...
+
+    def clone(self) -> Guide:
+        """
+        Clone the guide, sharing the compiled constraint but not the state.
+
+        Returns:
+            Cloned guide
+        """
+        ...
+
+@final
+class ChoiceConstraint:
+    """
+    Constraint allowing exactly one of a fixed set of strings, e.g. tens of
+    thousands of entity names. The options are stored in a trie, which is
+    much faster to build and smaller than an alternation regex.
+    """
+
+    def __init__(
+        self,
+        options: list[str],
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a choice constraint.
+
+        Args:
+            options: Allowed strings, duplicates are ignored
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_file(
+        path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> ChoiceConstraint:
+        """
+        Create a choice constraint from a file with one option per line.
+
+        Args:
+            path: Path to the options file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            ChoiceConstraint instance
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> ChoiceConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned ChoiceConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
 @final
 class RegexConstraint:
     """Constraint based on a regular expression."""
 
-    def __init__(self, regex: str, continuations: list[list[int]]) -> None:
+    def __init__(
+        self,
+        regex: str,
+        continuations: Continuations,
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+        case_insensitive: bool = False,
+        unicode: bool = True,
+        dot_matches_new_line: bool = False,
+        unanchored_start: bool = False,
+        unanchored_end: bool = False,
+        precompute_max_states: int | None = None,
+    ) -> None:
+        """
+        Create a regex constraint.
+        Use the (?-u) flag to match raw bytes, e.g. (?-u:[\\x80-\\xFF]+).
+
+        Args:
+            regex: Regular expression pattern
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            case_insensitive: Match letters regardless of case (default: False)
+            unicode: Match classes like \\w and . against UTF-8 encoded
+                characters instead of single bytes (default: True)
+            dot_matches_new_line: Let . match newlines (default: False)
+            unanchored_start: Allow any output before the match (default: False)
+            unanchored_end: Allow any output after the match (default: False)
+            precompute_max_states: Compute the valid continuations of all DFA
+                states up front, so getting them is a single lookup; raises
+                if the DFA has more states than this (default: None)
+        """
+        ...
+
+    @staticmethod
+    def complement(
+        regex: str,
+        continuations: Continuations,
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+    ) -> RegexConstraint:
+        """
+        Create a constraint accepting everything the regex does not match,
+        e.g. (?s-u:.*bad.*) for outputs that never contain bad. Note that .
+        matches neither newlines nor invalid UTF-8 without the s and -u flags.
+
+        Args:
+            regex: Regular expression pattern of the forbidden outputs
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            RegexConstraint instance
+        """
+        ...
+
+    @staticmethod
+    def from_strftime(
+        layout: str,
+        continuations: Continuations,
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+    ) -> RegexConstraint:
+        """
+        Create a constraint accepting the valid dates and times in a strftime
+        layout, e.g. %Y-%m-%d, see strftime_to_regex.
+
+        Args:
+            layout: strftime layout
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            RegexConstraint instance
+        """
+        ...
+
+    @staticmethod
+    def from_csv(
+        columns: list[tuple[str, str | list[str] | tuple[int, int] | None]],
+        continuations: Continuations,
+        header: bool = True,
+        delimiter: str = ",",
+        quoting: str = "never",
+        quote: str = '"',
+        line_terminator: str = "\n",
+        min_rows: int = 1,
+        max_rows: int | None = None,
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+    ) -> RegexConstraint:
+        """
+        Create a constraint for csv output with typed columns, optionally
+        starting with a header row of the column names.
+
+        Args:
+            columns: Column names and types, a type is a regex pattern, a
+                list of allowed values, an inclusive integer range (min, max)
+                or None for free text
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            header: Whether the output starts with a header row (default: True)
+            delimiter: Field delimiter (default: ,)
+            quoting: Whether fields are quoted, one of never, optional or
+                always (default: never)
+            quote: Quote character, inside quoted free text fields it is
+                escaped by doubling it (default: ")
+            line_terminator: Row terminator (default: newline)
+            min_rows: Minimum number of data rows (default: 1)
+            max_rows: Maximum number of data rows (default: None, unbounded)
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            RegexConstraint instance
+        """
+        ...
+
+    @staticmethod
+    def from_file(
+        path: str,
+        continuations: Continuations,
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+        case_insensitive: bool = False,
+        unicode: bool = True,
+        dot_matches_new_line: bool = False,
+        unanchored_start: bool = False,
+        unanchored_end: bool = False,
+        precompute_max_states: int | None = None,
+    ) -> RegexConstraint:
+        """
+        Create a regex constraint from a file.
+
+        Args:
+            path: Path to a file containing the regex pattern
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            case_insensitive: Match letters regardless of case (default: False)
+            unicode: Match classes like \\w and . against UTF-8 encoded
+                characters instead of single bytes (default: True)
+            dot_matches_new_line: Let . match newlines (default: False)
+            unanchored_start: Allow any output before the match (default: False)
+            unanchored_end: Allow any output after the match (default: False)
+            precompute_max_states: Compute the valid continuations of all DFA
+                states up front, so getting them is a single lookup; raises
+                if the DFA has more states than this (default: None)
+
+        Returns:
+            RegexConstraint instance
+        """
+        ...
+
+    @staticmethod
+    def unrolled(
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        depth: int,
+        overflow: str = "reject",
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+    ) -> RegexConstraint:
+        """
+        Create a regex constraint from an LR(1) grammar by unrolling its
+        recursive rules. Lists written with left or right recursion can
+        be of any length.
+
+        Args:
+            grammar: Grammar definition without actions
+            lexer: Lexer definition
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            depth: How often a rule can be nested within itself
+            overflow: What happens beyond the depth: reject rejects deeper
+                nesting, approximate accepts any sequence of tokens from the
+                first overflowing rule on (default: reject)
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            RegexConstraint instance
+        """
+        ...
+
+    @staticmethod
+    def new_batch(
+        regexes: list[str],
+        continuations: Continuations,
+        sorted_continuations: bool = False,
+        on_invalid: str = "sticky",
+    ) -> list[RegexConstraint]:
+        """
+        Create many regex constraints at once, compiling them in parallel.
+        The constraints share a single copy of the continuations.
+
+        Args:
+            regexes: List of regular expression patterns
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            sorted_continuations: Walk the continuations in sorted order, so
+                shared prefixes are only checked once (default: False)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            List of RegexConstraint instances, in the order of the patterns
+        """
+        ...
+
+    def serialize(self) -> bytes:
+        """
+        Serialize the compiled constraint, including its continuations.
+
+        Returns:
+            Bytes that can be loaded with RegexConstraint.deserialize
+        """
+        ...
+
+    @staticmethod
+    def deserialize(data: bytes, on_invalid: str = "sticky") -> RegexConstraint:
+        """
+        Load a constraint serialized with serialize, without compiling
+        the pattern again.
+
+        Args:
+            data: Serialized constraint
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            RegexConstraint instance
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> RegexConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned RegexConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def get_mask(self) -> npt.NDArray[np.bool_]:
+        """
+        Get the valid continuations for the current state as a boolean
+        mask with one entry per continuation.
+
+        Returns:
+            Boolean array of vocabulary size, True for valid continuations
+        """
+        ...
+
+    def minimal_completion(self, max_len: int = 64) -> bytes | None:
+        """
+        Get the shortest bytes that complete the current state to a match,
+        e.g. to close brackets and quotes when generation hits a length limit.
+        Readable bytes are preferred among completions of equal length.
+
+        Args:
+            max_len: Maximum length of the completion (default: 64)
+
+        Returns:
+            Completion bytes, or None if there is none within max_len
+        """
+        ...
+
+    def enumerate_completions(
+        self, max_count: int = 10, max_len: int = 16
+    ) -> list[bytes]:
+        """
+        Get distinct outputs that complete the current state to a match,
+        shortest first, e.g. for autocompletion or to see what the
+        constraint allows next.
+
+        Args:
+            max_count: Maximum number of completions (default: 10)
+            max_len: Maximum length of a completion (default: 16)
+
+        Returns:
+            List of completions, ordered by length
+        """
+        ...
+
+    def sample(self, max_len: int = 64, seed: int | None = None) -> bytes | None:
+        """
+        Sample a random output accepted by the constraint, independent of
+        the current state, e.g. for synthetic data or test fixtures. Every
+        step picks evenly between stopping at a match and the distinct
+        next states, so alternatives are sampled evenly.
+
+        Args:
+            max_len: Maximum length of the output (default: 64)
+            seed: Seed for reproducible samples (default: random)
+
+        Returns:
+            Output bytes, or None if no output fits within max_len
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def states_equal(self, a: bytes, b: bytes) -> bool:
+        """
+        Check whether two prefixes reach the same constraint state, e.g. to
+        share decoding caches or deduplicate branches.
+
+        Args:
+            a: First byte prefix
+            b: Second byte prefix
+
+        Returns:
+            True if both prefixes are valid and reach the same state
+        """
+        ...
+
+    def fingerprint(self, prefix: bytes | None = None) -> int:
+        """
+        Get a fingerprint of a constraint state that is stable across runs.
+        Equal states have equal fingerprints, fingerprints are only
+        comparable between states of the same constraint.
+
+        Args:
+            prefix: Byte prefix to get the state for, the current
+                state if None (default: None)
+
+        Returns:
+            Fingerprint as 64 bit integer
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def record_transcript(
+        self, token_ids: list[int], prefix: bytes | None = None
+    ) -> Transcript:
+        """
+        Record a transcript of a generation, with the fingerprint of the
+        state after each continuation and whether it was the only valid one.
+
+        Args:
+            token_ids: Generated continuation indices
+            prefix: Optional prefix the generation started from
+
+        Returns:
+            Transcript of the generation, invalid continuations are
+            recorded without a fingerprint
+        """
+        ...
+
+    def verify_transcript(self, transcript: Transcript) -> TranscriptVerification:
+        """
+        Replay a transcript, e.g. one recorded with an older version of
+        the constraint, and compare the result with the recorded steps.
+
+        Args:
+            transcript: Transcript to verify
+
+        Returns:
+            Result of the verification
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation, e.g. to force-feed
+        a prefix into a decoder. Longer continuations are preferred.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded, with the byte
+                position at which encoding got stuck
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the shared automaton and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def segment(self, output: bytes) -> dict[str, tuple[int, int]]:
+        """
+        Map the named groups of the regex to byte spans of a complete output.
+
+        Args:
+            output: Output bytes fully matching the regex
+
+        Returns:
+            Dict from group name to (start, end) byte span, groups that
+            did not participate in the match are omitted
+
+        Raises:
+            RuntimeError: If the constraint does not accept the output
+        """
+        ...
+
+@final
+class LR1Constraint:
+    """Constraint based on an LR(1) grammar."""
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        progress: Callable[[str, int, int | None], None] | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+        stall_steps: int | None = None,
+        on_stall: str = "stop",
+        whitespace: str = "preserve",
+    ) -> None:
+        """
+        Create an LR(1) grammar constraint.
+
+        Args:
+            grammar: Grammar definition string
+            lexer: Lexer definition string, may be empty if the grammar
+                only uses quoted literals, which are lexed as is
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            exact: Use exact constraint matching (default: False)
+            lru_cache_size: Size of the LRU cache (default: 8192)
+            progress: Optional callback called with (phase, done, total)
+                during compilation, phase is one of grammar, lexer,
+                table or done; in the table phase done is the number of
+                LR(0) states counted so far and then the number of LR
+                states, done is reported once the constraint exists
+            cache_policy: Eviction policy of the state cache, one of
+                lru, lfu, 2q or unbounded (default: lru)
+            cache_hasher: Hash function of the state cache, one of
+                siphash, ahash or fxhash (default: siphash)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            stall_steps: Number of consecutive steps that only advance over
+                skippable input like whitespace after which the generation
+                is stalled, None to not detect stalls (default: None)
+            on_stall: What happens once the generation stalled: stop ends it
+                if the state is a match and marks the constraint invalid
+                otherwise, report only reports it with is_stalled
+                (default: stop)
+            whitespace: How whitespace between tokens is lexed: preserve
+                keeps the ignore tokens of the lexer, forbid allows none,
+                single_space allows at most one space before each token and
+                free_form any spaces, tabs and newlines (default: preserve)
+        """
+        ...
+
+    @staticmethod
+    def compile_in_background(
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+        stall_steps: int | None = None,
+        on_stall: str = "stop",
+        whitespace: str = "preserve",
+    ) -> LR1Compilation:
+        """
+        Compile an LR(1) grammar constraint on a background thread.
+
+        Args:
+            grammar: Grammar definition string
+            lexer: Lexer definition string, may be empty if the grammar
+                only uses quoted literals, which are lexed as is
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            exact: Use exact constraint matching (default: False)
+            lru_cache_size: Size of the LRU cache (default: 8192)
+            cache_policy: Eviction policy of the state cache, one of
+                lru, lfu, 2q or unbounded (default: lru)
+            cache_hasher: Hash function of the state cache, one of
+                siphash, ahash or fxhash (default: siphash)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            stall_steps: Number of consecutive steps that only advance over
+                skippable input like whitespace after which the generation
+                is stalled, None to not detect stalls (default: None)
+            on_stall: What happens once the generation stalled: stop ends it
+                if the state is a match and marks the constraint invalid
+                otherwise, report only reports it with is_stalled
+                (default: stop)
+            whitespace: How whitespace between tokens is lexed: preserve
+                keeps the ignore tokens of the lexer, forbid allows none,
+                single_space allows at most one space before each token and
+                free_form any spaces, tabs and newlines (default: preserve)
+
+        Returns:
+            LR1Compilation handle to poll and retrieve the constraint
+        """
+        ...
+
+    @staticmethod
+    def from_files(
+        grammar_path: str,
+        lexer_path: str,
+        continuations: Continuations,
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+        stall_steps: int | None = None,
+        on_stall: str = "stop",
+        whitespace: str = "preserve",
+    ) -> LR1Constraint:
+        """
+        Create an LR(1) grammar constraint from files.
+
+        Args:
+            grammar_path: Path to the grammar file
+            lexer_path: Path to the lexer file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            exact: Use exact constraint matching (default: False)
+            lru_cache_size: Size of the LRU cache (default: 8192)
+            cache_policy: Eviction policy of the state cache, one of
+                lru, lfu, 2q or unbounded (default: lru)
+            cache_hasher: Hash function of the state cache, one of
+                siphash, ahash or fxhash (default: siphash)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            stall_steps: Number of consecutive steps that only advance over
+                skippable input like whitespace after which the generation
+                is stalled, None to not detect stalls (default: None)
+            on_stall: What happens once the generation stalled: stop ends it
+                if the state is a match and marks the constraint invalid
+                otherwise, report only reports it with is_stalled
+                (default: stop)
+            whitespace: How whitespace between tokens is lexed: preserve
+                keeps the ignore tokens of the lexer, forbid allows none,
+                single_space allows at most one space before each token and
+                free_form any spaces, tabs and newlines (default: preserve)
+
+        Returns:
+            LR1Constraint instance
+        """
+        ...
+
+    @staticmethod
+    def from_json_schema(
+        schema: str,
+        continuations: Continuations,
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+        stall_steps: int | None = None,
+        on_stall: str = "stop",
+        whitespace: str = "preserve",
+    ) -> LR1Constraint:
+        """
+        Create a constraint for JSON documents following a JSON schema,
+        see json_schema_to_lr1 for the supported subset.
+
+        Args:
+            schema: JSON schema as string
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            exact: Use exact constraint matching (default: False)
+            lru_cache_size: Size of the LRU cache (default: 8192)
+            cache_policy: Eviction policy of the state cache, one of
+                lru, lfu, 2q or unbounded (default: lru)
+            cache_hasher: Hash function of the state cache, one of
+                siphash, ahash or fxhash (default: siphash)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            stall_steps: Number of consecutive steps that only advance over
+                skippable input like whitespace after which the generation
+                is stalled, None to not detect stalls (default: None)
+            on_stall: What happens once the generation stalled: stop ends it
+                if the state is a match and marks the constraint invalid
+                otherwise, report only reports it with is_stalled
+                (default: stop)
+            whitespace: How whitespace between tokens is lexed: preserve
+                keeps the ignore tokens of the lexer, forbid allows none,
+                single_space allows at most one space before each token and
+                free_form any spaces, tabs and newlines (default: preserve)
+
+        Returns:
+            LR1Constraint instance
+        """
+        ...
+
+    @staticmethod
+    def from_proto(
+        proto: str,
+        message: str,
+        continuations: Continuations,
+        format: str = "text",
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+        stall_steps: int | None = None,
+        on_stall: str = "stop",
+        whitespace: str = "preserve",
+    ) -> LR1Constraint:
+        """
+        Create a constraint for a protobuf message in text format or the
+        canonical JSON mapping, see proto_to_lr1 for the supported subset.
+
+        Args:
+            proto: .proto definition as string
+            message: Name of the message, e.g. Person or pkg.Outer.Inner
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            format: Output format, text or json (default: text)
+            exact: Use exact constraint matching (default: False)
+            lru_cache_size: Size of the LRU cache (default: 8192)
+            cache_policy: Eviction policy of the state cache, one of
+                lru, lfu, 2q or unbounded (default: lru)
+            cache_hasher: Hash function of the state cache, one of
+                siphash, ahash or fxhash (default: siphash)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            stall_steps: Number of consecutive steps that only advance over
+                skippable input like whitespace after which the generation
+                is stalled, None to not detect stalls (default: None)
+            on_stall: What happens once the generation stalled: stop ends it
+                if the state is a match and marks the constraint invalid
+                otherwise, report only reports it with is_stalled
+                (default: stop)
+            whitespace: How whitespace between tokens is lexed: preserve
+                keeps the ignore tokens of the lexer, forbid allows none,
+                single_space allows at most one space before each token and
+                free_form any spaces, tabs and newlines (default: preserve)
+
+        Returns:
+            LR1Constraint instance
+        """
+        ...
+
+    @staticmethod
+    def from_dtd(
+        dtd: str,
+        root: str,
+        continuations: Continuations,
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+        stall_steps: int | None = None,
+        on_stall: str = "stop",
+        whitespace: str = "preserve",
+    ) -> LR1Constraint:
+        """
+        Create a constraint for XML documents conforming to a document type
+        definition, see dtd_to_lr1 for the supported subset.
+
+        Args:
+            dtd: Document type definition as string
+            root: Name of the root element
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            exact: Use exact constraint matching (default: False)
+            lru_cache_size: Size of the LRU cache (default: 8192)
+            cache_policy: Eviction policy of the state cache, one of
+                lru, lfu, 2q or unbounded (default: lru)
+            cache_hasher: Hash function of the state cache, one of
+                siphash, ahash or fxhash (default: siphash)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            stall_steps: Number of consecutive steps that only advance over
+                skippable input like whitespace after which the generation
+                is stalled, None to not detect stalls (default: None)
+            on_stall: What happens once the generation stalled: stop ends it
+                if the state is a match and marks the constraint invalid
+                otherwise, report only reports it with is_stalled
+                (default: stop)
+            whitespace: How whitespace between tokens is lexed: preserve
+                keeps the ignore tokens of the lexer, forbid allows none,
+                single_space allows at most one space before each token and
+                free_form any spaces, tabs and newlines (default: preserve)
+
+        Returns:
+            LR1Constraint instance
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> LR1Constraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned LR1Constraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_stalled(self) -> bool:
+        """
+        Check if the generation stalled, i.e. the last stall_steps steps
+        only advanced over skippable input like whitespace.
+
+        Returns:
+            True if the generation stalled, always False if stall
+            detection is disabled
+        """
+        ...
+
+    def states_equal(self, a: bytes, b: bytes) -> bool:
+        """
+        Check whether two prefixes reach the same constraint state, e.g. to
+        share decoding caches or deduplicate branches.
+
+        Args:
+            a: First byte prefix
+            b: Second byte prefix
+
+        Returns:
+            True if both prefixes are valid and reach the same state
+        """
+        ...
+
+    def fingerprint(self, prefix: bytes | None = None) -> int:
+        """
+        Get a fingerprint of a constraint state that is stable across runs.
+        Equal states have equal fingerprints, fingerprints are only
+        comparable between states of the same constraint.
+
+        Args:
+            prefix: Byte prefix to get the state for, the current
+                state if None (default: None)
+
+        Returns:
+            Fingerprint as 64 bit integer
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def record_transcript(
+        self, token_ids: list[int], prefix: bytes | None = None
+    ) -> Transcript:
+        """
+        Record a transcript of a generation, with the fingerprint of the
+        state after each continuation and whether it was the only valid one.
+
+        Args:
+            token_ids: Generated continuation indices
+            prefix: Optional prefix the generation started from
+
+        Returns:
+            Transcript of the generation, invalid continuations are
+            recorded without a fingerprint
+        """
+        ...
+
+    def verify_transcript(self, transcript: Transcript) -> TranscriptVerification:
+        """
+        Replay a transcript, e.g. one recorded with an older version of
+        the constraint, and compare the result with the recorded steps.
+
+        Args:
+            transcript: Transcript to verify
+
+        Returns:
+            Result of the verification
+        """
+        ...
+
+    def expected_terminals(self) -> list[str]:
+        """
+        Get the terminals the parser accepts next, including the one
+        currently being lexed, e.g. for error messages or completion.
+        Terminals are named by their %token "alias" declaration if given,
+        literals are quoted and the end of the input is "end of input".
+
+        Returns:
+            List of terminal display names
+        """
+        ...
+
+    def minimal_completion(self, max_len: int = 64) -> bytes | None:
+        """
+        Get the shortest bytes that complete the current state to a match,
+        e.g. to close brackets and quotes when generation hits a length limit.
+        Readable bytes are preferred among completions of equal length.
+
+        Args:
+            max_len: Maximum length of the completion (default: 64)
+
+        Returns:
+            Completion bytes, or None if there is none within max_len
+        """
+        ...
+
+    def enumerate_completions(
+        self, max_count: int = 10, max_len: int = 16
+    ) -> list[bytes]:
+        """
+        Get distinct outputs that complete the current state to a match,
+        shortest first, e.g. for autocompletion or to see what the
+        constraint allows next.
+
+        Args:
+            max_count: Maximum number of completions (default: 10)
+            max_len: Maximum length of a completion (default: 16)
+
+        Returns:
+            List of completions, ordered by length
+        """
+        ...
+
+    def sample(self, max_len: int = 64, seed: int | None = None) -> bytes | None:
+        """
+        Sample a random output accepted by the constraint, independent of
+        the current state, e.g. for synthetic data or test fixtures. Every
+        step picks evenly between stopping at a match and the distinct
+        next states, so alternatives are sampled evenly.
+
+        Args:
+            max_len: Maximum length of the output (default: 64)
+            seed: Seed for reproducible samples (default: random)
+
+        Returns:
+            Output bytes, or None if no output fits within max_len
+        """
+        ...
+
+    def explain(self, index: int, prefix: bytes | None = None) -> Explanation:
+        """
+        Explain why a continuation is valid or invalid, that is which
+        terminals it completes or starts, which lexer rule rejected it, or
+        which terminal the parser did not expect.
+
+        Args:
+            index: Continuation index / token id to explain
+            prefix: Explain in the state after this prefix instead of the
+                current state
+
+        Returns:
+            Explanation of the continuation
+        """
+        ...
+
+    def openers(self) -> dict[str, list[int]]:
+        """
+        Get the continuations valid at the start of the input grouped by the
+        terminal they commit to, e.g. to bias generation towards objects
+        over arrays. Continuations that are only whitespace or could still
+        start several terminals commit to none and are left out.
+
+        Returns:
+            Mapping from terminal display names to sorted continuation indices
+        """
+        ...
+
+    def distinguish(
+        self,
+        other: LR1Constraint,
+        max_len: int = 16,
+    ) -> tuple[bytes | None, bytes | None]:
+        """
+        Find the shortest inputs matched by only one of two constraints over
+        the same continuations, e.g. to test a grammar refactoring. The
+        search is bounded, so None only means there is no such input of at
+        most max_len bytes.
+
+        Args:
+            other: Constraint to compare with
+            max_len: Maximum input length in bytes (default: 16)
+
+        Returns:
+            Shortest input only this constraint matches and shortest input
+            only the other constraint matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation, e.g. to force-feed
+        a prefix into a decoder. Longer continuations are preferred.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded, with the byte
+                position at which encoding got stuck
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including shared tables, caches, and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+@final
+class CheckReport:
+    """Result of checking a full text against a constraint."""
+
+    is_match: bool
+    """Whether the text conforms to the constraint."""
+    is_prefix: bool
+    """Whether the text can still be completed to conform to the constraint."""
+    valid_up_to: int
+    """Length in bytes of the longest prefix of the text that can be completed."""
+
+@final
+class Explanation:
+    """Why a continuation is valid or invalid in a state of an LR(1) constraint."""
+
+    index: int
+    """Index of the explained continuation."""
+    is_valid: bool
+    """Whether the continuation is valid in the state."""
+    continues_lexeme: bool
+    """Whether the continuation continues a lexeme pending in the state."""
+    completed: list[tuple[str | None, tuple[int, int]]]
+    """Terminals the continuation completes with their byte spans in it,
+    None for skipped input like whitespace."""
+    pending: list[str]
+    """Terminals the lexeme pending after the continuation can still become."""
+    rejection: str | None
+    """Kind of the rejection, one of no_lexer_match, too_many_tokens,
+    unexpected_terminal or unexpected_lexeme, None if valid."""
+    reason: str | None
+    """Human readable rejection reason, None if valid."""
+    offset: int | None
+    """Byte offset in the continuation no lexer rule matches from."""
+    rejected: list[str]
+    """Rejected terminal, or the terminals the rejected lexeme can become."""
+    expected: list[str]
+    """Terminals the parser would have accepted instead."""
+
+@final
+class Transcript:
+    """Record of a constrained generation, serializable to json or msgpack."""
+
+    @staticmethod
+    def from_json(json: str) -> Transcript:
+        """
+        Load a transcript from json.
+
+        Args:
+            json: Transcript as json
+
+        Returns:
+            Transcript
+        """
+        ...
+
+    @staticmethod
+    def from_msgpack(data: bytes) -> Transcript:
+        """
+        Load a transcript from msgpack.
+
+        Args:
+            data: Transcript as msgpack
+
+        Returns:
+            Transcript
+        """
+        ...
+
+    def to_json(self) -> str:
+        """
+        Serialize the transcript to json.
+
+        Returns:
+            Transcript as json
+        """
+        ...
+
+    def to_msgpack(self) -> bytes:
+        """
+        Serialize the transcript to msgpack.
+
+        Returns:
+            Transcript as msgpack
+        """
+        ...
+
+    def prefix(self) -> bytes:
+        """
+        Get the prefix the generation started from.
+
+        Returns:
+            Prefix bytes
+        """
+        ...
+
+    def output(self) -> bytes:
+        """
+        Get the generated bytes, without the prefix.
+
+        Returns:
+            Output bytes
+        """
+        ...
+
+    def token_ids(self) -> list[int]:
+        """
+        Get the generated continuation indices.
+
+        Returns:
+            Continuation indices
+        """
+        ...
+
+    def fingerprints(self) -> list[int | None]:
+        """
+        Get the state fingerprints after each step.
+
+        Returns:
+            Fingerprints, None for invalid steps
+        """
+        ...
+
+    def forced(self) -> list[bool]:
+        """
+        Get whether each step was the only valid continuation.
+
+        Returns:
+            Forced flags
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Get whether the output was a match when recorded.
+
+        Returns:
+            True if the output was a match
+        """
+        ...
+
+    def __len__(self) -> int: ...
+
+@final
+class TranscriptVerification:
+    """Result of replaying a transcript with a constraint."""
+
+    steps: int
+    """Number of steps in the transcript."""
+    valid_steps: int
+    """Number of leading steps the constraint still accepts."""
+    is_match: bool
+    """Whether the replayed output is a match."""
+    is_valid: bool
+    """Whether the constraint still accepts the full output."""
+    is_unchanged: bool
+    """Whether the constraint behaves exactly as during recording."""
+    vocabulary_mismatch: int | None
+    """First step whose bytes differ from the continuation of its index."""
+    fingerprint_mismatches: list[int]
+    """Steps whose state fingerprint changed."""
+    forced_mismatches: list[int]
+    """Steps whose forced flag changed."""
+
+@final
+class Classification:
+    """Validity of a prefix with respect to a constraint."""
+
+    Valid: ClassVar[Classification]
+    """Complete match, possibly with further valid continuations."""
+    ValidPrefix: ClassVar[Classification]
+    """No match yet, but can be extended with the continuations."""
+    Invalid: ClassVar[Classification]
+    """Can never become a match with the continuations."""
+
+@final
+class LR1Compilation:
+    """Handle to an LR(1) grammar constraint compiled in the background."""
+
+    def progress(self) -> tuple[str, int, int | None]:
+        """
+        Get the current compilation progress.
+
+        Returns:
+            Tuple of (phase, done, total), phase is one of
+            grammar, lexer, table or done, see LR1Constraint
+        """
+        ...
+
+    def is_ready(self) -> bool:
+        """
+        Check if compilation has finished, without blocking.
+
+        Returns:
+            True if the result is available
+        """
+        ...
+
+    def result(self) -> LR1Constraint:
+        """
+        Wait for compilation to finish and get the constraint.
+        Can only be called once.
+
+        Returns:
+            LR1Constraint instance
+        """
+        ...
+
+@final
+class MultiVocabConstraint:
+    """
+    One compiled LR(1) grammar serving several vocabularies, e.g. of models
+    with different tokenizers, so the grammar is only compiled once.
+    """
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        exact: bool = False,
+        lru_cache_size: int | None = None,
+        cache_policy: str = "lru",
+        cache_hasher: str = "siphash",
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Compile the grammar without any vocabulary.
+
+        Args:
+            grammar: LR(1) grammar definition
+            lexer: lexer definition
+            exact: whether to use the exact LR(1) constraint
+            lru_cache_size: size of the cache of each vocabulary
+            cache_policy: eviction policy of the caches, see LR1Constraint
+            cache_hasher: hasher of the caches, see LR1Constraint
+            on_invalid: policy for invalid continuations, see LR1Constraint
+        """
+        ...
+
+    def add_vocabulary(self, name: str, continuations: list[bytes]) -> None:
+        """
+        Add a vocabulary sharing the compiled grammar.
+        Raises an error if a vocabulary with the name exists already.
+
+        Args:
+            name: name of the vocabulary
+            continuations: continuations of the vocabulary
+        """
+        ...
+
+    def remove_vocabulary(self, name: str) -> bool:
+        """
+        Remove a vocabulary.
+
+        Args:
+            name: name of the vocabulary
+
+        Returns:
+            True if the vocabulary existed
+        """
+        ...
+
+    def vocabularies(self) -> list[str]:
+        """
+        Get the names of all vocabularies.
+
+        Returns:
+            Names in the order the vocabularies were added
+        """
+        ...
+
+    def constraint(self, name: str) -> LR1Constraint:
+        """
+        Get a constraint in the start state for a vocabulary.
+        All constraints of a vocabulary share its cache.
+
+        Args:
+            name: name of the vocabulary
+
+        Returns:
+            LR1Constraint instance
+        """
+        ...
+
+    def get(self, name: str, prefix: bytes) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuations of a vocabulary after a prefix.
+        Raises an error if the prefix is invalid.
+
+        Args:
+            name: name of the vocabulary
+            prefix: prefix to get the continuations after
+
+        Returns:
+            Indices of the valid continuations
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage in bytes, counting the shared
+        grammar once plus the memory and cache of every vocabulary.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+@final
+class ConstraintScheduler:
+    """
+    Scheduler batching next and get calls of many sessions over one LR(1)
+    constraint on a bounded thread pool, with a bounded request queue.
+    Requests of a session run in submission order, and sessions can expire
+    after a time without requests. See AsyncConstraintScheduler for an
+    asyncio wrapper.
+    """
+
+    def __init__(
+        self,
+        constraint: LR1Constraint,
+        num_threads: int = 0,
+        batch_size: int = 64,
+        max_pending: int = 1024,
+        max_delay: float = 0.001,
+        session_ttl: float | None = None,
+        on_evict: Callable[[int], None] | None = None,
+    ) -> None:
+        """
+        Create a scheduler sharing the grammar of a constraint.
+
+        Args:
+            constraint: LR(1) constraint to schedule requests for
+            num_threads: Worker threads, 0 for one per CPU (default: 0)
+            batch_size: Most requests per batch (default: 64)
+            max_pending: Most queued requests (default: 1024)
+            max_delay: Seconds to wait for a batch to fill up (default: 0.001)
+            session_ttl: Seconds after which sessions without requests are
+                evicted, None to keep them until closed (default: None)
+            on_evict: Called with the id of every evicted session from a
+                worker thread (default: None)
+        """
+        ...
+
+    def open_session(self, prefix: bytes | None = None) -> int:
+        """
+        Open a session at the state after the prefix.
+
+        Args:
+            prefix: Optional prefix to start from
+
+        Returns:
+            Session id
+        """
+        ...
+
+    def close_session(self, session: int) -> bool:
+        """
+        Close a session, queued requests of it fail.
+
+        Args:
+            session: Session id
+
+        Returns:
+            True if the session was open
+        """
+        ...
+
+    def pending(self) -> int:
+        """
+        Get the number of queued requests.
+
+        Returns:
+            Number of queued requests
+        """
+        ...
+
+    def evict_idle(self) -> list[int]:
+        """
+        Evict sessions idle for longer than the session ttl right away,
+        instead of waiting for the next periodic sweep.
+
+        Returns:
+            Ids of the evicted sessions
+        """
+        ...
+
+    def submit_next(
+        self,
+        session: int,
+        index: int,
+        callback: Callable[[bool | None, str | None], None],
+    ) -> None:
+        """
+        Queue advancing the session by the index. Raises if the queue is full.
+        The callback is called from a worker thread with whether the index
+        was valid and None, or None and an error message.
+
+        Args:
+            session: Session id
+            index: Index / token id to advance with
+            callback: Called with the result or an error
+        """
+        ...
+
+    def submit_get(
+        self,
+        session: int,
+        callback: Callable[[tuple[npt.NDArray[np.int32], bool] | None, str | None], None],
+    ) -> None:
+        """
+        Queue getting the valid indices of the session. Raises if the queue
+        is full. The callback is called from a worker thread with the indices
+        and whether the session is in a match state and None, or None and
+        an error message.
+
+        Args:
+            session: Session id
+            callback: Called with the result or an error
+        """
+        ...
+
+@final
+class LR1Parser:
+    """LR(1) grammar parser."""
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        nfc: bool = False,
+        lowercase: bool = False,
+        inline: bool = False,
+    ) -> None:
+        """
+        Create an LR(1) parser. Inputs can be normalized before lexing,
+        e.g. for case-insensitive grammars written in lowercase; spans
+        and terminal values still refer to the original input.
+
+        Args:
+            grammar: Grammar definition string
+            lexer: Lexer definition string, may be empty if the grammar
+                only uses quoted literals, which are lexed as is
+            nfc: Apply unicode NFC normalization to inputs (default: False)
+            lowercase: Lowercase inputs (default: False)
+            inline: Inline single production rules for a smaller parse
+                table, parse trees are unchanged (default: False)
+        """
+        ...
+
+    @staticmethod
+    def from_files(
+        grammar_path: str,
+        lexer_path: str,
+        nfc: bool = False,
+        lowercase: bool = False,
+        inline: bool = False,
+    ) -> LR1Parser:
+        """
+        Create an LR(1) parser from files.
+
+        Args:
+            grammar_path: Path to the grammar file
+            lexer_path: Path to the lexer file
+            nfc: Apply unicode NFC normalization to inputs (default: False)
+            lowercase: Lowercase inputs (default: False)
+            inline: Inline single production rules for a smaller parse
+                table, parse trees are unchanged (default: False)
+
+        Returns:
+            LR1Parser instance
+        """
+        ...
+
+    def prefix_parse(
+        self,
+        input: bytes,
+        skip_empty: bool = False,
+        collapse_single: bool = False,
+    ) -> tuple[dict[str, Any], bytes]:
+        """
+        Parse a byte prefix, returning the parse tree and remaining bytes.
+
+        Args:
+            input: Input bytes to parse
+            skip_empty: Skip empty nodes in the parse tree (default: False)
+            collapse_single: Collapse single-child nodes (default: False)
+
+        Returns:
+            Tuple of (parse tree dict, remaining unparsed bytes)
+        """
+        ...
+
+    def parse(
+        self,
+        input: str | bytes,
+        skip_empty: bool = False,
+        collapse_single: bool = False,
+    ) -> dict[str, Any]:
+        """
+        Parse a complete input string or byte string.
+        If the lexer is defined with %bytes, terminal values
+        in the parse tree are bytes instead of strings.
+
+        Args:
+            input: Input string or bytes to parse
+            skip_empty: Skip empty nodes in the parse tree (default: False)
+            collapse_single: Collapse single-child nodes (default: False)
+
+        Returns:
+            Parse tree as a dict
+        """
+        ...
+
+    def to_value(
+        self,
+        input: str | bytes,
+        spans: bool = False,
+    ) -> Any | tuple[Any, dict[str, tuple[int, int]]]:
+        """
+        Parse a complete input with a JSON grammar, like the built-in one or
+        one from json_schema_to_lr1, directly into a Python value.
+
+        Args:
+            input: Input string or bytes to parse
+            spans: Also return the byte spans of the value and all nested
+                values, keyed by JSON pointer (default: False)
+
+        Returns:
+            Python value, or a tuple of the value and the spans
+        """
+        ...
+
+    def lex(self, input: str | bytes) -> list[tuple[str | None, tuple[int, int]]]:
+        """
+        Lex an input string or byte string into tokens.
+
+        Args:
+            input: Input string or bytes to lex
+
+        Returns:
+            List of (token_name, (start, end)) tuples
+        """
+        ...
+
+    def lex_with_recovery(
+        self, input: str | bytes
+    ) -> tuple[
+        list[tuple[str | None, tuple[int, int]]], list[tuple[str, tuple[int, int]]]
+    ]:
+        """
+        Lex an input string or byte string without failing on unlexable
+        input, e.g. for dirty data. Unlexable spans are skipped until a
+        token matches again and become ERROR tokens.
+
+        Args:
+            input: Input string or bytes to lex
+
+        Returns:
+            Tuple of (token_name, (start, end)) tuples and error records
+            as (kind, (start, end)) tuples, kind is no_match if no token
+            matches at the start of the span or incomplete if the input
+            ends inside a token
+        """
+        ...
+
+@final
+class LexicalConstraint:
+    """
+    Constraint that only enforces the output to be a sequence of lexer
+    terminals in any order, optionally restricted by a regular expression
+    over terminal names, e.g. "NAME (COMMA NAME)*". Ignore tokens may
+    appear anywhere and do not count as terminals.
+    """
+
+    def __init__(
+        self,
+        lexer: str,
+        continuations: Continuations,
+        terminals: str | None = None,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a lexical constraint.
+
+        Args:
+            lexer: Lexer definition in the same format as for LR1Constraint
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            terminals: Regular expression over terminal names, whitespace
+                only separates names (default: None, any sequence)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_file(
+        path: str,
+        continuations: Continuations,
+        terminals: str | None = None,
+        on_invalid: str = "sticky",
+    ) -> LexicalConstraint:
+        """
+        Create a lexical constraint from a lexer file.
+
+        Args:
+            path: Path to the lexer file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            terminals: Regular expression over terminal names, whitespace
+                only separates names (default: None, any sequence)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            LexicalConstraint instance
+        """
+        ...
+
+    def terminal_names(self) -> list[str]:
+        """
+        Get the names of the lexer terminals in lexer order.
+
+        Returns:
+            List of terminal names
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> LexicalConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned LexicalConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the lexer automata and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def lex(self, input: str | bytes) -> list[tuple[str | None, tuple[int, int]]]:
+        """
+        Lex a complete output into terminals, e.g. to extract values.
+
+        Args:
+            input: Input string or bytes to lex
+
+        Returns:
+            List of (terminal_name, (start, end)) tuples, ignore
+            tokens have no name
+
+        Raises:
+            RuntimeError: If the input cannot be lexed or its terminal
+                sequence does not match the terminal regex
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
+@final
+class EarleyConstraint:
+    """
+    Constraint based on a context free grammar in the same format as for
+    LR1Constraint, parsed with an Earley parser instead of an LR(1) table.
+    Slower, but works for grammars that are not LR(1), e.g. ambiguous
+    ones or ones with conflicts.
+    """
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create an Earley grammar constraint.
+
+        Args:
+            grammar: Grammar definition
+            lexer: Lexer definition
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_files(
+        grammar_path: str,
+        lexer_path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> EarleyConstraint:
+        """
+        Create an Earley grammar constraint from files.
+
+        Args:
+            grammar_path: Path to the grammar file
+            lexer_path: Path to the lexer file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            EarleyConstraint instance
+        """
+        ...
+
+    def expected_terminals(self) -> list[str]:
+        """
+        Get the display names of the terminals the parser accepts at the
+        position of the pending lexeme, like LR1Constraint.expected_terminals.
+
+        Returns:
+            List of terminal names
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> EarleyConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned EarleyConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the lexer automata and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
+@final
+class GLRConstraint:
+    """
+    Constraint based on a context free grammar in the same format as for
+    LR1Constraint, where conflicts in the LR(1) table are not resolved, but
+    every conflicting action is followed with its own parser stack. Accepts
+    inputs that LR1Constraint rejects because of resolved conflicts, and is
+    faster than EarleyConstraint if there are only a few conflicts.
+    """
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+        max_stacks: int = 1024,
+    ) -> None:
+        """
+        Create a GLR grammar constraint.
+
+        Args:
+            grammar: Grammar definition
+            lexer: Lexer definition
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            max_stacks: Maximum number of parser stacks kept at once, more
+                are dropped and overflowed() returns True (default: 1024)
+        """
+        ...
+
+    @staticmethod
+    def from_files(
+        grammar_path: str,
+        lexer_path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+        max_stacks: int = 1024,
+    ) -> GLRConstraint:
+        """
+        Create a GLR grammar constraint from files.
+
+        Args:
+            grammar_path: Path to the grammar file
+            lexer_path: Path to the lexer file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            max_stacks: Maximum number of parser stacks kept at once, more
+                are dropped and overflowed() returns True (default: 1024)
+
+        Returns:
+            GLRConstraint instance
+        """
+        ...
+
+    def overflowed(self) -> bool:
+        """
+        Check whether parser stacks were dropped because of max_stacks since
+        the start state. Valid continuations may then be missing from get().
+
+        Returns:
+            True if the stack limit was exceeded
+        """
+        ...
+
+    def expected_terminals(self) -> list[str]:
+        """
+        Get the display names of the terminals the parser accepts at the
+        position of the pending lexeme, like LR1Constraint.expected_terminals.
+
+        Returns:
+            List of terminal names
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> GLRConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned GLRConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the lexer automata and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def num_conflicts(self) -> int:
+        """
+        Get the number of LR(1) table entries with conflicting actions.
+
+        Returns:
+            Number of conflicts, 0 if the grammar is LR(1)
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
+@final
+class PushdownConstraint:
+    """
+    Constraint based on a context free grammar in the same format as for
+    LR1Constraint, run as a nondeterministic top-down pushdown automaton
+    that tracks the stacks of all possible derivations. Works for grammars
+    that are not LR(1), e.g. ambiguous ones or ones with conflicts, and
+    unlike EarleyConstraint its state does not grow with the input, only
+    with the nesting depth and the number of live derivations.
+    """
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a pushdown grammar constraint.
+
+        Args:
+            grammar: Grammar definition
+            lexer: Lexer definition
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_files(
+        grammar_path: str,
+        lexer_path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> PushdownConstraint:
+        """
+        Create a pushdown grammar constraint from files.
+
+        Args:
+            grammar_path: Path to the grammar file
+            lexer_path: Path to the lexer file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            PushdownConstraint instance
+        """
+        ...
+
+    def expected_terminals(self) -> list[str]:
+        """
+        Get the display names of the terminals the parser accepts at the
+        position of the pending lexeme, like LR1Constraint.expected_terminals.
+
+        Returns:
+            List of terminal names
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> PushdownConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned PushdownConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the lexer automata and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
+@final
+class PegConstraint:
+    """
+    Constraint based on a parsing expression grammar (PEG) with rules like
+    Sum <- Value (('+' / '-') Value)*. Alternatives are ordered, repetitions
+    are greedy, and & and ! are lookaheads. There is no separate lexer, the
+    first rule has to match the whole output, and left recursive rules are
+    not supported.
+    """
+
+    def __init__(
+        self,
+        grammar: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a PEG grammar constraint.
+
+        Args:
+            grammar: Grammar in PEG notation
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_file(
+        grammar_path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> PegConstraint:
+        """
+        Create a PEG grammar constraint from a file.
+
+        Args:
+            grammar_path: Path to the grammar file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            PegConstraint instance
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> PegConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned PegConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
+@final
+class SemanticConstraint:
+    """
+    Constraint based on an LR(1) grammar in the same format as for
+    LR1Constraint, with semantic checks on the text of completed
+    nonterminals, e.g. whether a date is a real calendar date. A
+    continuation is invalid if a check rejects a nonterminal it completes,
+    which happens once the terminal after the nonterminal is known.
+
+    Terminals can also be computed from the output before them instead of
+    being lexed, e.g. a field whose length is given by an earlier length
+    terminal. As soon as the parser can shift a computed terminal, the
+    following bytes belong to it, without skipping whitespace before it.
+    """
+
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        continuations: Continuations,
+        hooks: dict[str, Callable[[bytes], bool]],
+        on_invalid: str = "sticky",
+        length_prefixed: dict[str, str] | None = None,
+        computed: dict[str, Callable[[dict[str, bytes]], bytes | None]]
+        | None = None,
+    ) -> None:
+        """
+        Create a semantic grammar constraint.
+
+        Args:
+            grammar: Grammar definition
+            lexer: Lexer definition
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            hooks: Checks by rule name, called with the text of every
+                completed nonterminal of the rule, from its first to its
+                last token; results are cached per rule and text, and
+                exceptions count as rejections
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            length_prefixed: Computed terminals by name, mapped to the
+                terminal before them giving their length in bytes as a
+                decimal number
+            computed: Computed terminals by name, mapped to functions
+                getting the latest text of every terminal seen so far and
+                returning the text of the terminal, or None if it cannot
+                occur; exceptions count as None
+        """
+        ...
+
+    @staticmethod
+    def from_files(
+        grammar_path: str,
+        lexer_path: str,
+        continuations: Continuations,
+        hooks: dict[str, Callable[[bytes], bool]],
+        on_invalid: str = "sticky",
+        length_prefixed: dict[str, str] | None = None,
+        computed: dict[str, Callable[[dict[str, bytes]], bytes | None]]
+        | None = None,
+    ) -> SemanticConstraint:
+        """
+        Create a semantic grammar constraint from files.
+
+        Args:
+            grammar_path: Path to the grammar file
+            lexer_path: Path to the lexer file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            hooks: Checks by rule name, called with the text of every
+                completed nonterminal of the rule, from its first to its
+                last token; results are cached per rule and text, and
+                exceptions count as rejections
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            length_prefixed: Computed terminals by name, mapped to the
+                terminal before them giving their length in bytes as a
+                decimal number
+            computed: Computed terminals by name, mapped to functions
+                getting the latest text of every terminal seen so far and
+                returning the text of the terminal, or None if it cannot
+                occur; exceptions count as None
+
+        Returns:
+            SemanticConstraint instance
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> SemanticConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned SemanticConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the lexer automata and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def encode(self, input: bytes) -> list[int]:
+        """
+        Find continuation indices spelling out the given bytes, such that
+        the constraint stays valid after each continuation.
+        Does not change the state of the constraint.
+
+        Args:
+            input: Bytes to encode
+
+        Returns:
+            List of continuation indices
+
+        Raises:
+            RuntimeError: If the bytes cannot be encoded
+        """
+        ...
+
+@final
+class LiteralSetConstraint:
+    """
+    Constraint allowing exactly one of a set of literals, like
+    ChoiceConstraint, but the set can be changed with insert and remove at
+    any time without rebuilding anything, e.g. for product catalogs with
+    millions of entries. Changes are shared with all clones, each picks them
+    up at its next step.
+    """
+
+    def __init__(
+        self,
+        literals: list[str | bytes],
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a literal set constraint.
+
+        Args:
+            literals: Allowed literals, duplicates are ignored, may be empty
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_file(
+        path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> LiteralSetConstraint:
+        """
+        Create a literal set constraint from a file with one literal per line.
+
+        Args:
+            path: Path to the literals file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            LiteralSetConstraint instance
+        """
+        ...
+
+    def insert(self, literal: str | bytes) -> bool:
+        """
+        Add a literal to the set.
+
+        Args:
+            literal: Literal to allow
+
+        Returns:
+            True if the literal was not in the set before
+
+        Raises:
+            RuntimeError: If the grown set exceeds the memory budget, the
+                literal is not added then
+        """
+        ...
+
+    def remove(self, literal: str | bytes) -> bool:
+        """
+        Remove a literal from the set. Outputs that already started with it
+        can only be completed to other literals afterwards.
+
+        Args:
+            literal: Literal to disallow
+
+        Returns:
+            True if the literal was in the set before
+        """
+        ...
+
+    def __contains__(self, literal: str | bytes) -> bool: ...
+    def __len__(self) -> int: ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> LiteralSetConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned LiteralSetConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+@final
+class LazyRegexConstraint:
+    """
+    Constraint for a regular expression that is determinized lazily while
+    decoding instead of compiled into a dense DFA up front. Uses bounded
+    memory for huge patterns, e.g. alternations of many entity names, at
+    the cost of slower steps. Alternatives do not take priority over each
+    other and only ^ and $ are supported as assertions.
+    """
+
+    def __init__(
+        self,
+        regex: str,
+        continuations: Continuations,
+        cache_capacity: int = 65536,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a lazy regex constraint.
+
+        Args:
+            regex: Regular expression pattern, optionally with fragments
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            cache_capacity: Number of cached transitions, the cache is
+                cleared once it is full, 0 disables it (default: 65536)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    @staticmethod
+    def from_file(
+        path: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> LazyRegexConstraint:
+        """
+        Create a lazy regex constraint from a file.
+
+        Args:
+            path: Path to the regex file
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+
+        Returns:
+            LazyRegexConstraint instance
+        """
+        ...
+
+    def pattern(self) -> str:
+        """
+        Get the regular expression of the constraint.
+
+        Returns:
+            Pattern
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> LazyRegexConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned LazyRegexConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled parts and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+@final
+class Utf8Constraint:
+    """
+    Constraint for a regular expression whose output is always valid UTF-8,
+    up to a last character that is not complete yet. Continuations that
+    would produce invalid UTF-8, e.g. byte fallback tokens in the wrong
+    order, are rejected right away, and a match additionally requires the
+    last character to be complete. Useful for byte level patterns, e.g.
+    with (?-u), that would otherwise allow any byte sequence.
+    """
+
+    def __init__(
+        self,
+        regex: str,
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a UTF-8 constraint.
+
+        Args:
+            regex: Regular expression pattern, optionally with fragments
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    def is_complete(self) -> bool:
+        """
+        Check if the output so far ends with a complete character.
+
+        Returns:
+            True if no character is partially generated
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> Utf8Constraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned Utf8Constraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled parts and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+@final
+class RegexSetConstraint:
+    """
+    Constraint for several regular expressions compiled into a single
+    DFA, the output has to match one of them. Tells which patterns the
+    output can still match, e.g. to know which of several output formats
+    it has committed to.
+    """
+
+    def __init__(
+        self,
+        patterns: list[str],
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a regex set constraint.
+
+        Args:
+            patterns: Regular expressions, at least one
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    def patterns(self) -> list[str]:
+        """
+        Get the regular expressions of the set.
+
+        Returns:
+            List of patterns
+        """
+        ...
+
+    def viable(self) -> list[int]:
+        """
+        Get the indices of the patterns the output can still match.
+
+        Returns:
+            Sorted list of pattern indices
+        """
+        ...
+
+    def matched(self) -> list[int]:
+        """
+        Get the indices of the patterns the output matches.
+
+        Returns:
+            Sorted list of pattern indices, empty if the output is no match
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> RegexSetConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned RegexSetConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled parts and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+@final
+class TaggedUnionConstraint:
+    """
+    Constraint for outputs that conform to exactly one of several named
+    LR(1) grammars, e.g. to let a model decide between a tool call and a
+    final answer in a single decoding pass. Grammars are tried in order,
+    so earlier grammars win if an output conforms to more than one.
+    """
+
+    def __init__(
+        self,
+        grammars: list[tuple[str, str, str]],
+        continuations: Continuations,
+        on_invalid: str = "sticky",
+    ) -> None:
+        """
+        Create a tagged union constraint.
+
+        Args:
+            grammars: List of (name, grammar, lexer) tuples
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+        """
+        ...
+
+    def names(self) -> list[str]:
+        """
+        Get the names of the grammars in order.
+
+        Returns:
+            List of grammar names
+        """
+        ...
+
+    def reset(self, prefix: bytes | None = None) -> None:
+        """
+        Reset the constraint to the initial state, optionally with a prefix.
+
+        Args:
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> TaggedUnionConstraint:
+        """
+        Create a copy of the constraint with the current state.
+
+        Returns:
+            Cloned TaggedUnionConstraint instance
+        """
+        ...
+
+    def get(self) -> npt.NDArray[np.int32]:
+        """
+        Get the valid continuation indices for the current state,
+        the union over all grammars the output can still conform to.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
+
+        Returns:
+            True if the state is invalid
+        """
+        ...
+
+    def is_match(self) -> bool:
+        """
+        Check if the current state is a match state for any grammar.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def candidates(self) -> list[str]:
+        """
+        Get the names of the grammars the output can still conform to.
+
+        Returns:
+            List of grammar names
+        """
+        ...
+
+    def matched(self) -> str | None:
+        """
+        Get the name of the first grammar the current output conforms to.
+
+        Returns:
+            Grammar name, or None if the output is not a match
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled grammars and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
+    def parse(
+        self,
+        input: str | bytes,
+        skip_empty: bool = False,
+        collapse_single: bool = False,
+    ) -> tuple[str, dict[str, Any]]:
+        """
+        Parse a complete output with the first grammar it conforms to.
+
+        Args:
+            input: Input string or bytes to parse
+            skip_empty: Skip empty nodes in the parse tree (default: False)
+            collapse_single: Collapse single-child nodes (default: False)
+
+        Returns:
+            Tuple of grammar name and parse tree as a dict
+        """
+        ...
+
+@final
+class TemplateConstraint:
+    """
+    Constraint for literal text with holes, each filled by a regular
+    expression or an LR(1) grammar, e.g.
+    "Name: {regex:\\w+}\\nData: {grammar:json}". Literal braces are
+    written as {{ and }}.
+    """
+
+    def __init__(
+        self,
+        template: str,
+        continuations: Continuations,
+        grammars: list[tuple[str, str, str]] = [],
+        on_invalid: str = "sticky",
+    ) -> None:
         """
-        Create a regex constraint.
+        Create a template constraint.
 
         Args:
-            regex: Regular expression pattern
-            continuations: List of byte continuations (vocabulary)
+            template: Template with {regex:PATTERN} and {grammar:NAME} holes
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            grammars: List of (name, grammar, lexer) tuples for the grammar
+                holes, each grammar can be used once (default: [])
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
         """
         ...
 
-    @staticmethod
-    def from_file(path: str, continuations: list[list[int]]) -> RegexConstraint:
+    def part(self) -> int:
         """
-        Create a regex constraint from a file.
-
-        Args:
-            path: Path to a file containing the regex pattern
-            continuations: List of byte continuations (vocabulary)
+        Get the index of the part the output is in; the literals and regex
+        holes between two grammar holes form a single part.
 
         Returns:
-            RegexConstraint instance
+            Part index
         """
         ...
 
@@ -42,12 +4856,12 @@ class RegexConstraint:
         """
         ...
 
-    def clone(self) -> RegexConstraint:
+    def clone(self) -> TemplateConstraint:
         """
         Create a copy of the constraint with the current state.
 
         Returns:
-            Cloned RegexConstraint instance
+            Cloned TemplateConstraint instance
         """
         ...
 
@@ -60,6 +4874,28 @@ class RegexConstraint:
         """
         ...
 
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
     def is_invalid(self) -> bool:
         """
         Check if the current state is invalid.
@@ -78,59 +4914,128 @@ class RegexConstraint:
         """
         ...
 
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
     def next(self, index: int) -> None:
         """
         Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
 
         Args:
             index: Continuation index to advance by
         """
         ...
 
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled parts and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
 @final
-class LR1Constraint:
-    """Constraint based on an LR(1) grammar."""
+class DispatchConstraint:
+    """
+    Constraint that switches between grammars mid-output, e.g. for tool
+    calling: free text, or text matching a base regular expression, until
+    the start sentinel of a tool like <tool_call> is emitted, then output
+    of the tool's LR(1) grammar until its end sentinel like </tool_call>,
+    then text again.
+    """
 
     def __init__(
         self,
-        grammar: str,
-        lexer: str,
-        continuations: list[list[int]],
-        exact: bool = False,
-        lru_cache_size: int | None = None,
+        tools: list[tuple[str, str, str, str, str]],
+        continuations: Continuations,
+        base: str | None = None,
+        on_invalid: str = "sticky",
     ) -> None:
         """
-        Create an LR(1) grammar constraint.
+        Create a dispatch constraint.
 
         Args:
-            grammar: Grammar definition string
-            lexer: Lexer definition string
-            continuations: List of byte continuations (vocabulary)
-            exact: Use exact constraint matching (default: False)
-            lru_cache_size: Size of the LRU cache (default: 8192)
+            tools: List of (name, start sentinel, end sentinel, grammar, lexer)
+                tuples
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            base: Regular expression for the text outside of tool calls,
+                unconstrained if None (default: None)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
         """
         ...
 
-    @staticmethod
-    def from_files(
-        grammar_path: str,
-        lexer_path: str,
-        continuations: list[list[int]],
-        exact: bool = False,
-        lru_cache_size: int | None = None,
-    ) -> LR1Constraint:
+    def names(self) -> list[str]:
         """
-        Create an LR(1) grammar constraint from files.
+        Get the names of the tools.
 
-        Args:
-            grammar_path: Path to the grammar file
-            lexer_path: Path to the lexer file
-            continuations: List of byte continuations (vocabulary)
-            exact: Use exact constraint matching (default: False)
-            lru_cache_size: Size of the LRU cache (default: 8192)
+        Returns:
+            List of tool names
+        """
+        ...
+
+    def active(self) -> str | None:
+        """
+        Get the name of the tool whose grammar the output is in.
 
         Returns:
-            LR1Constraint instance
+            Tool name, None outside of tool calls
         """
         ...
 
@@ -143,12 +5048,12 @@ class LR1Constraint:
         """
         ...
 
-    def clone(self) -> LR1Constraint:
+    def clone(self) -> DispatchConstraint:
         """
         Create a copy of the constraint with the current state.
 
         Returns:
-            Cloned LR1Constraint instance
+            Cloned DispatchConstraint instance
         """
         ...
 
@@ -161,6 +5066,28 @@ class LR1Constraint:
         """
         ...
 
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
+
+        Args:
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
     def is_invalid(self) -> bool:
         """
         Check if the current state is invalid.
@@ -179,95 +5106,337 @@ class LR1Constraint:
         """
         ...
 
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            True if the text conforms to the constraint
+        """
+        ...
+
+    def check_detailed(self, text: str | bytes) -> CheckReport:
+        """
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
+
+        Args:
+            text: Text or bytes to check
+
+        Returns:
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
     def next(self, index: int) -> None:
         """
         Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
 
         Args:
             index: Continuation index to advance by
         """
         ...
 
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled parts and the current state.
+
+        Returns:
+            Number of bytes
+        """
+        ...
+
 @final
-class LR1Parser:
-    """LR(1) grammar parser."""
+class RepeatedConstraint:
+    """
+    Constraint for streams of documents conforming to an LR(1) grammar,
+    separated by matches of a separator regular expression, e.g. one JSON
+    value per line, or directly following each other. Single continuations
+    can end one document and start the next one.
+    """
 
-    def __init__(self, grammar: str, lexer: str) -> None:
+    def __init__(
+        self,
+        grammar: str,
+        lexer: str,
+        separator: str | None,
+        continuations: Continuations,
+        min: int = 1,
+        max: int | None = None,
+        on_invalid: str = "sticky",
+        literal: bool = False,
+    ) -> None:
         """
-        Create an LR(1) parser.
+        Create a repeated constraint.
 
         Args:
-            grammar: Grammar definition string
-            lexer: Lexer definition string
+            grammar: Grammar definition for a single document
+            lexer: Lexer definition for a single document
+            separator: Regular expression for the separator, literals
+                need to be escaped unless literal is set; None for documents
+                directly following each other
+            continuations: Byte continuations (vocabulary), e.g. a list or generator
+            min: Minimum number of documents (default: 1)
+            max: Maximum number of documents, unbounded if None (default: None)
+            on_invalid: What next does with an invalid continuation: sticky
+                marks the constraint invalid, raise raises an error and keeps
+                the state, reset goes back to the last match state or the
+                start state (default: sticky)
+            literal: Treat the separator as a literal string instead of
+                a regular expression (default: False)
         """
         ...
 
-    @staticmethod
-    def from_files(grammar_path: str, lexer_path: str) -> LR1Parser:
+    def reset(self, prefix: bytes | None = None) -> None:
         """
-        Create an LR(1) parser from files.
+        Reset the constraint to the initial state, optionally with a prefix.
 
         Args:
-            grammar_path: Path to the grammar file
-            lexer_path: Path to the lexer file
+            prefix: Optional byte prefix to reset to
+        """
+        ...
+
+    def clone(self) -> RepeatedConstraint:
+        """
+        Create a copy of the constraint with the current state.
 
         Returns:
-            LR1Parser instance
+            Cloned RepeatedConstraint instance
         """
         ...
 
-    def prefix_parse(
-        self,
-        input: bytes,
-        skip_empty: bool = False,
-        collapse_single: bool = False,
-    ) -> tuple[dict[str, Any], bytes]:
+    def get(self) -> npt.NDArray[np.int32]:
         """
-        Parse a byte prefix, returning the parse tree and remaining bytes.
+        Get the valid continuation indices for the current state,
+        including those that end a document and start the next one.
+
+        Returns:
+            Array of valid continuation indices
+        """
+        ...
+
+    def get_ranges(self) -> list[tuple[int, int]]:
+        """
+        Get the valid continuation indices for the current state as
+        sorted, half-open ranges of consecutive indices.
+
+        Returns:
+            List of (start, end) ranges
+        """
+        ...
+
+    def pack_mask_u32(self, out: npt.NDArray[np.uint32]) -> None:
+        """
+        Write the valid continuations for the current state as a bit packed
+        mask into a preallocated buffer, the layout used by common GPU
+        sampling kernels: continuation i is bit i % 32 of word i // 32.
 
         Args:
-            input: Input bytes to parse
-            skip_empty: Skip empty nodes in the parse tree (default: False)
-            collapse_single: Collapse single-child nodes (default: False)
+            out: Contiguous uint32 array with at least ceil(vocab size / 32)
+                words, bits of invalid continuations are cleared
+        """
+        ...
+
+    def is_invalid(self) -> bool:
+        """
+        Check if the current state is invalid.
 
         Returns:
-            Tuple of (parse tree dict, remaining unparsed bytes)
+            True if the state is invalid
         """
         ...
 
-    def parse(
-        self,
-        input: str,
-        skip_empty: bool = False,
-        collapse_single: bool = False,
-    ) -> dict[str, Any]:
+    def is_match(self) -> bool:
         """
-        Parse a complete input string.
+        Check if the current state is a match state, i.e. the output is a
+        complete stream with an allowed number of documents.
+
+        Returns:
+            True if the state is a match
+        """
+        ...
+
+    def check(self, text: str | bytes) -> bool:
+        """
+        Check if a full text conforms to the constraint, independent of
+        the continuations and the current state, e.g. to re-validate
+        final outputs.
 
         Args:
-            input: Input string to parse
-            skip_empty: Skip empty nodes in the parse tree (default: False)
-            collapse_single: Collapse single-child nodes (default: False)
+            text: Text or bytes to check
 
         Returns:
-            Parse tree as a dict
+            True if the text conforms to the constraint
         """
         ...
 
-    def lex(self, input: str) -> list[tuple[str | None, tuple[int, int]]]:
+    def check_detailed(self, text: str | bytes) -> CheckReport:
         """
-        Lex an input string into tokens.
+        Like check, but also reports whether the text can still be
+        completed and how much of it is valid.
 
         Args:
-            input: Input string to lex
+            text: Text or bytes to check
 
         Returns:
-            List of (token_name, (start, end)) tuples
+            CheckReport instance
+        """
+        ...
+
+    def classify(self, prefix: str | bytes | None = None) -> Classification:
+        """
+        Classify a prefix as a complete match, a prefix that can still be
+        extended to a match with the continuations, or invalid.
+
+        Args:
+            prefix: Prefix to classify, the current state if None (default: None)
+
+        Returns:
+            Classification of the prefix
+        """
+        ...
+
+    def last_valid_truncation(self, text: str | bytes) -> int | None:
+        """
+        Find the last point at which the text fully matches, e.g. to
+        truncate a run-on output to its last complete value.
+
+        Args:
+            text: Text to search
+
+        Returns:
+            Largest prefix length in bytes at which the text is a match,
+            None if no prefix matches
+        """
+        ...
+
+    def completed(self) -> int:
+        """
+        Get the number of completed documents in the current output, the
+        smallest one if the end of the current document is ambiguous.
+
+        Returns:
+            Number of completed documents
+        """
+        ...
+
+    def spans(self, text: str | bytes) -> list[tuple[int, int]] | None:
+        """
+        Get the byte spans of the documents in a complete output, e.g. to
+        parse them separately after generation. Ambiguous boundaries are
+        resolved in favor of longer documents.
+
+        Args:
+            text: Text or bytes to split
+
+        Returns:
+            List of (start, end) byte spans, or None if the text does not
+            conform to the constraint
+        """
+        ...
+
+    def next(self, index: int) -> None:
+        """
+        Advance the state by the chosen continuation index.
+        Invalid continuations are handled according to on_invalid.
+
+        Args:
+            index: Continuation index to advance by
+        """
+        ...
+
+    def memory_usage(self) -> int:
+        """
+        Get the approximate memory usage of the constraint in bytes,
+        including the compiled grammar and the current state.
+
+        Returns:
+            Number of bytes
         """
         ...
 
 __all__ = [
+    "CheckReport",
+    "ChoiceConstraint",
+    "Classification",
+    "ConstraintScheduler",
+    "DispatchConstraint",
+    "EarleyConstraint",
+    "Explanation",
+    "GLRConstraint",
+    "Guide",
+    "LR1Compilation",
     "LR1Constraint",
     "LR1Parser",
+    "LazyRegexConstraint",
+    "LiteralSetConstraint",
+    "LexicalConstraint",
+    "MultiVocabConstraint",
+    "PegConstraint",
+    "PushdownConstraint",
     "RegexConstraint",
+    "RegexSetConstraint",
+    "RepeatedConstraint",
+    "SemanticConstraint",
+    "TaggedUnionConstraint",
+    "TemplateConstraint",
+    "Transcript",
+    "TranscriptVerification",
+    "Utf8Constraint",
+    "abnf_to_lr1",
+    "builtin_grammar",
+    "builtin_grammars",
+    "distinguish_regex",
+    "dtd_to_lr1",
+    "ebnf_to_lr1",
+    "gbnf_to_lr1",
+    "grammar",
+    "grammar_docs",
+    "guidance_to_lr1",
+    "inline_rules",
+    "json_schema",
+    "json_schema_to_lr1",
+    "lark_to_lr1",
+    "lr1_to_guidance",
+    "memory_used",
+    "proto_to_lr1",
+    "regex",
+    "run_length_order",
+    "select",
+    "set_memory_limit",
+    "strftime_to_regex",
 ]
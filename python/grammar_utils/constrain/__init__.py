@@ -1,8 +1,42 @@
+import asyncio
+import json
 from functools import reduce
+from typing import Any, Callable
 
 import numpy as np
 
-from grammar_utils._internal import LR1Constraint, RegexConstraint  # noqa
+from grammar_utils._internal import (  # noqa
+    CheckReport,
+    ChoiceConstraint,
+    Classification,
+    ConstraintScheduler,
+    DispatchConstraint,
+    EarleyConstraint,
+    Explanation,
+    GLRConstraint,
+    LazyRegexConstraint,
+    LexicalConstraint,
+    LiteralSetConstraint,
+    LR1Constraint,
+    MultiVocabConstraint,
+    PegConstraint,
+    RegexConstraint,
+    RegexSetConstraint,
+    RepeatedConstraint,
+    SemanticConstraint,
+    TaggedUnionConstraint,
+    TemplateConstraint,
+    Transcript,
+    TranscriptVerification,
+    Utf8Constraint,
+    abnf_to_lr1,
+    distinguish_regex,
+    dtd_to_lr1,
+    ebnf_to_lr1,
+    gbnf_to_lr1,
+    json_schema_to_lr1,
+    lark_to_lr1,
+)
 from grammar_utils.grammars import load_grammar_and_lexer
 
 
@@ -11,13 +45,13 @@ def load_lr1_constraint(
     vocab: list[list[int]],
     exact: bool = False,
     lru_cache_size: int | None = None,
+    whitespace: str = "preserve",
 ) -> LR1Constraint:
     """
 
     Load a LR(1) constraint for the given name.
-    Currently supported:
-    - json
-    - sparql
+    Supported are all built-in grammars, see builtin_grammars().
+    See LR1Constraint for the whitespace policies.
 
     """
     return LR1Constraint(
@@ -25,6 +59,7 @@ def load_lr1_constraint(
         vocab,
         exact=exact,
         lru_cache_size=lru_cache_size,
+        whitespace=whitespace,
     )
 
 
@@ -49,6 +84,52 @@ def load_regex_constraint(name: str, vocab: list[list[int]]) -> RegexConstraint:
     return RegexConstraint(regex, vocab)
 
 
+def constraint_for_response_format(
+    response_format: dict[str, Any],
+    continuations: list[list[int]],
+    exact: bool = False,
+    lru_cache_size: int | None = None,
+) -> LR1Constraint | None:
+    """
+
+    Create a constraint for a response_format payload of the OpenAI API.
+    Supported are:
+    - {"type": "text"}: no constraint, None is returned
+    - {"type": "json_object"}: any JSON object
+    - {"type": "json_schema", "json_schema": {"schema": ..., "strict": ...}}:
+      JSON following the schema; if strict is not set and the schema uses
+      unsupported features, any JSON object is allowed instead
+
+    """
+    any_object = json.dumps({"type": "object"})
+    kind = response_format.get("type")
+    if kind == "text":
+        return None
+    elif kind == "json_object":
+        grammar, lexer = json_schema_to_lr1(any_object)
+    elif kind == "json_schema":
+        json_schema = response_format.get("json_schema")
+        if not isinstance(json_schema, dict):
+            raise ValueError("json_schema response format requires a json_schema")
+        schema = json.dumps(json_schema.get("schema", {"type": "object"}))
+        try:
+            grammar, lexer = json_schema_to_lr1(schema)
+        except RuntimeError:
+            if json_schema.get("strict", False):
+                raise
+            grammar, lexer = json_schema_to_lr1(any_object)
+    else:
+        raise ValueError(f"unsupported response format type: {kind}")
+
+    return LR1Constraint(
+        grammar,
+        lexer,
+        continuations,
+        exact=exact,
+        lru_cache_size=lru_cache_size,
+    )
+
+
 class Constraint:
     """
     Base class for constraints.
@@ -151,3 +232,196 @@ class OrConstraint(Constraint):
 
     def clone(self) -> "OrConstraint":
         return OrConstraint([c.clone() for c in self.constraints])
+
+
+def _resolve(future: asyncio.Future, value: Any, error: str | None) -> None:
+    if future.cancelled():
+        return
+    if error is not None:
+        future.set_exception(RuntimeError(error))
+    else:
+        future.set_result(value)
+
+
+class AsyncConstraintScheduler:
+    """
+
+    An asyncio wrapper around ConstraintScheduler, which batches the
+    next and get calls of many sessions over one LR(1) constraint on
+    a bounded number of worker threads.
+    Awaiting more than max_pending calls at once waits for earlier
+    calls to finish instead of growing the queue.
+    With a session_ttl, sessions without calls for that many seconds
+    are evicted and on_evict is called with their ids from a scheduler
+    thread.
+
+    """
+
+    def __init__(
+        self,
+        constraint: LR1Constraint,
+        num_threads: int = 0,
+        batch_size: int = 64,
+        max_pending: int = 1024,
+        max_delay: float = 0.001,
+        session_ttl: float | None = None,
+        on_evict: Callable[[int], None] | None = None,
+    ):
+        self.scheduler = ConstraintScheduler(
+            constraint,
+            num_threads=num_threads,
+            batch_size=batch_size,
+            max_pending=max_pending,
+            max_delay=max_delay,
+            session_ttl=session_ttl,
+            on_evict=on_evict,
+        )
+        self._slots = asyncio.Semaphore(max_pending)
+
+    def open_session(self, prefix: bytes | None = None) -> int:
+        """
+        Opens a session at the state after the prefix.
+        """
+        return self.scheduler.open_session(prefix)
+
+    def close_session(self, session: int) -> bool:
+        """
+        Closes a session, pending calls of it fail.
+        """
+        return self.scheduler.close_session(session)
+
+    async def _submit(self, submit: Callable[..., None], *args: Any) -> Any:
+        loop = asyncio.get_running_loop()
+        future = loop.create_future()
+
+        def done(value: Any, error: str | None) -> None:
+            # called from a scheduler thread
+            loop.call_soon_threadsafe(_resolve, future, value, error)
+
+        async with self._slots:
+            submit(*args, done)
+            return await future
+
+    async def next(self, session: int, index: int) -> bool:
+        """
+        Advances the session by the chosen index / token id and returns
+        whether it was valid, invalid ones invalidate the session.
+        """
+        return await self._submit(self.scheduler.submit_next, session, index)
+
+    async def get(self, session: int) -> tuple[np.ndarray, bool]:
+        """
+        Returns the constraint indices of the session and whether it
+        is in a match state.
+        """
+        return await self._submit(self.scheduler.submit_get, session)
+
+
+class _Row:
+    def __init__(self, constraint: Constraint, ids: list[int], start: int):
+        self.constraint = constraint
+        # all ids seen so far and where the generated part starts
+        self.ids = ids
+        self.start = start
+
+
+def _to_list(ids: Any) -> list[int]:
+    return ids.tolist() if hasattr(ids, "tolist") else list(ids)
+
+
+class GrammarLogitsProcessor:
+    """
+
+    A logits processor restricting generation to a constraint.
+    It can be used with vLLM, where it is called with the generated token ids
+    and the logits of a single sequence, and with transformers, where it is
+    called with batched input ids including the prompt and batched scores
+    (set includes_prompt=True in that case).
+
+    The continuation indices of the constraint must be the token ids of
+    the model. The EOS token is only allowed once the constraint is
+    satisfied, or if no other token is valid anymore.
+    If a row is called with token ids that do not continue the ones from
+    the previous call, it is reset, so a processor can be reused
+    across requests.
+
+    """
+
+    def __init__(
+        self,
+        constraint: Constraint,
+        eos_token_id: int,
+        includes_prompt: bool = False,
+    ):
+        self.constraint = constraint.clone()
+        self.constraint.reset()
+        self.eos_token_id = eos_token_id
+        self.includes_prompt = includes_prompt
+        self._rows: list[_Row | None] = []
+
+    def reset(self) -> None:
+        """
+        Forgets all tracked sequences.
+        """
+        self._rows = []
+
+    def __call__(self, token_ids: Any, logits: Any) -> Any:
+        """
+        Masks the logits of all tokens not allowed by the constraint in place
+        and returns them. Works with numpy arrays and torch tensors.
+        """
+        if len(logits.shape) == 1:
+            self._process(0, _to_list(token_ids), logits)
+        else:
+            for i in range(logits.shape[0]):
+                self._process(i, _to_list(token_ids[i]), logits[i])
+        return logits
+
+    def _row(self, i: int, ids: list[int]) -> _Row:
+        while len(self._rows) <= i:
+            self._rows.append(None)
+        row = self._rows[i]
+        if (
+            row is not None
+            and len(ids) > len(row.ids)
+            and ids[: len(row.ids)] == row.ids
+        ):
+            return row
+
+        # new sequence, if it continues the prompt of a tracked row
+        # (e.g. beams being reordered) only replay the generated part,
+        # otherwise everything is prompt
+        start = 0
+        if self.includes_prompt:
+            start = next(
+                (
+                    r.start
+                    for r in self._rows
+                    if r is not None
+                    and len(ids) > r.start
+                    and ids[: r.start] == r.ids[: r.start]
+                ),
+                len(ids),
+            )
+        row = _Row(self.constraint.clone(), ids[:start], start)
+        self._rows[i] = row
+        return row
+
+    def _process(self, i: int, ids: list[int], logits: Any) -> None:
+        row = self._row(i, ids)
+        for token_id in ids[len(row.ids) :]:
+            if token_id != self.eos_token_id:
+                row.constraint.next(token_id)
+        row.ids = ids
+
+        allowed = row.constraint.get()
+        if row.constraint.is_match() or len(allowed) == 0:
+            allowed = np.append(allowed, self.eos_token_id)
+
+        mask = np.ones(logits.shape[-1], dtype=bool)
+        mask[allowed[allowed < len(mask)]] = False
+        if type(logits).__module__.startswith("torch"):
+            import torch
+
+            mask = torch.from_numpy(mask).to(logits.device)
+        logits[mask] = float("-inf")
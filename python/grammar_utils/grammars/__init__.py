@@ -1,15 +1,17 @@
-from importlib import resources
+from grammar_utils._internal import (  # noqa
+    builtin_grammar,
+    builtin_grammars,
+    grammar_docs,
+    inline_rules,
+)
 
 
 def load_grammar_and_lexer(name: str) -> tuple[str, str]:
     """
 
     Read the grammar and lexer definitions for the given name.
-    Currently supported:
-    - json
-    - sparql
+    See builtin_grammars() for the supported names, e.g.
+    json, yaml, csv, xml, datetime, sql or sparql.
 
     """
-    grammar = resources.read_text(f"grammar_utils.grammars.{name}", f"{name}.y")
-    lexer = resources.read_text(f"grammar_utils.grammars.{name}", f"{name}.l")
-    return grammar, lexer
+    return builtin_grammar(name)
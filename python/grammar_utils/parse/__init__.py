@@ -1,7 +1,7 @@
 import argparse
 from pprint import pprint
 
-from grammar_utils._internal import LR1Parser  # noqa
+from grammar_utils._internal import LR1Parser, select  # noqa
 from grammar_utils.grammars import load_grammar_and_lexer
 
 
@@ -9,9 +9,7 @@ def load_lr1_parser(name: str) -> tuple[str, str]:
     """
 
     Load a LR(1) parser for the given name.
-    Currently supported:
-    - json
-    - sparql
+    Supported are all built-in grammars, see builtin_grammars().
 
     """
     return LR1Parser(*load_grammar_and_lexer(name))
@@ -0,0 +1,162 @@
+import threading
+from concurrent.futures import ThreadPoolExecutor
+
+import pytest
+
+from grammar_utils._internal import (
+    ChoiceConstraint,
+    LiteralSetConstraint,
+    LR1Constraint,
+    RegexConstraint,
+)
+
+THREADS = 8
+ROUNDS = 50
+
+# one continuation per byte, so the index of a byte is the byte itself
+VOCAB = [bytes([i]) for i in range(256)]
+
+CALC_GRAMMAR = """
+%start Expr
+%%
+Expr: Expr '+' Term | Term ;
+Term: Term '*' Factor | Factor ;
+Factor: '(' Expr ')' | 'INT' ;
+"""
+
+CALC_LEXER = """
+%%
+INT [0-9]+
+"""
+
+
+def run_threads(fn, n=THREADS):
+    # start all threads at once to make overlapping calls likely
+    barrier = threading.Barrier(n)
+
+    def run(i):
+        barrier.wait()
+        return fn(i)
+
+    with ThreadPoolExecutor(n) as pool:
+        return list(pool.map(run, range(n)))
+
+
+def advance(constraint, text):
+    states = []
+    for b in text.encode():
+        states.append((constraint.get_ranges(), constraint.is_match()))
+        constraint.next(b)
+    states.append((constraint.get_ranges(), constraint.is_match()))
+    return states
+
+
+CONSTRAINTS = {
+    "regex": (lambda: RegexConstraint(r"[a-z]+@[a-z]+\.com", VOCAB), "abc@def.com"),
+    "lr1": (lambda: LR1Constraint(CALC_GRAMMAR, CALC_LEXER, VOCAB), "(1+23)*4"),
+    "choice": (lambda: ChoiceConstraint(["alpha", "beta", "alps"], VOCAB), "alps"),
+    "literal_set": (
+        lambda: LiteralSetConstraint(["alpha", "beta", "alps"], VOCAB),
+        "alps",
+    ),
+}
+
+
+@pytest.mark.parametrize("name", CONSTRAINTS)
+def test_clones(name):
+    factory, text = CONSTRAINTS[name]
+    constraint = factory()
+    expected = advance(constraint.clone(), text)
+    assert expected[-1][1]
+
+    def run(_):
+        for _ in range(ROUNDS):
+            clone = constraint.clone()
+            assert advance(clone, text) == expected
+            clone.reset()
+            assert advance(clone, text) == expected
+        return True
+
+    assert all(run_threads(run))
+    # the original is untouched by its clones
+    assert advance(constraint, text) == expected
+
+
+def test_shared_regex_advance():
+    steps = THREADS * ROUNDS
+    constraint = RegexConstraint(f"a{{{steps}}}", VOCAB)
+
+    def run(_):
+        for _ in range(ROUNDS):
+            # readers interleaved with writers must not see torn states
+            ranges = constraint.get_ranges()
+            assert ranges in ([(ord("a"), ord("a") + 1)], [])
+            constraint.next(ord("a"))
+        return True
+
+    assert all(run_threads(run))
+    # every step is applied exactly once
+    assert constraint.is_match()
+    assert constraint.get_ranges() == []
+    assert not constraint.is_invalid()
+
+
+def test_shared_lr1_advance():
+    constraint = LR1Constraint(CALC_GRAMMAR, CALC_LEXER, VOCAB)
+    constraint.next(ord("1"))
+
+    def run(_):
+        for _ in range(ROUNDS):
+            # digits continue the current number, so any order is valid
+            constraint.next(ord("0"))
+            assert not constraint.is_invalid()
+            constraint.get_ranges()
+        return True
+
+    assert all(run_threads(run))
+    assert constraint.is_match()
+    assert not constraint.is_invalid()
+    constraint.next(ord("+"))
+    assert not constraint.is_match()
+
+
+def test_shared_reset():
+    constraint = RegexConstraint("ab", VOCAB)
+    start = constraint.get_ranges()
+
+    def run(i):
+        for _ in range(ROUNDS):
+            if i % 2 == 0:
+                constraint.reset()
+            else:
+                constraint.reset(b"a")
+            # a reset is atomic, so only the two reset states can be seen
+            assert constraint.get_ranges() in (start, [(ord("b"), ord("b") + 1)])
+        return True
+
+    assert all(run_threads(run))
+
+
+def test_literal_set_concurrent_insert():
+    constraint = LiteralSetConstraint([], VOCAB)
+
+    def run(i):
+        clone = constraint.clone()
+        for j in range(ROUNDS):
+            literal = f"item-{i}-{j}"
+            assert constraint.insert(literal)
+            # inserts are shared with all clones
+            assert literal in clone
+            clone.reset()
+            advance(clone, literal)
+            assert clone.is_match()
+        return True
+
+    assert all(run_threads(run))
+    assert len(constraint) == THREADS * ROUNDS
+
+    def remove(i):
+        return all(constraint.remove(f"item-{i}-{j}") for j in range(ROUNDS))
+
+    assert all(run_threads(remove))
+    assert len(constraint) == 0
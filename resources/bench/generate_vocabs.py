@@ -0,0 +1,109 @@
+"""
+
+Generates the benchmark vocabularies in this directory.
+
+The vocabularies have the sizes and layout of the GPT-2, T5 and Llama-3
+tokenizers (byte tokens, special tokens, number tokens) but are synthetic:
+the pieces come from the Llama-2 vocabulary in resources/test and are
+extended with deterministic merges of existing pieces. This way benchmarks
+need no downloads and results are comparable across machines.
+
+File format: number of tokens as little endian u32, then for every token
+its length as u8 followed by its bytes.
+
+The files are not checked in, the benchmark suite runs this script when
+they are missing. To run it by hand:
+    python resources/bench/generate_vocabs.py
+
+"""
+
+import json
+import struct
+from pathlib import Path
+
+DIR = Path(__file__).parent
+MAX_LEN = 24
+
+
+class Lcg:
+    def __init__(self, seed: int) -> None:
+        self.state = seed
+
+    def next(self, n: int) -> int:
+        self.state = (self.state * 6364136223846793005 + 1442695040888963407) % 2**64
+        return (self.state >> 33) % n
+
+
+def llama2_pieces() -> list[bytes]:
+    with open(DIR.parent / "test" / "continuations.json") as f:
+        vocab = json.load(f)
+    # skip <unk>, <s>, </s>, the byte tokens and <pad>
+    return [piece.encode() for piece in vocab[259:-1]]
+
+
+def extend(tokens: list[bytes], size: int, seed: int) -> list[bytes]:
+    # merges a word-like piece with a continuation piece, preferring frequent
+    # (early) pieces like bpe merges do
+    rng = Lcg(seed)
+    seen = set(tokens)
+    words = [t for t in tokens if t[:1].isalpha() or t[:1] == b" "]
+    suffixes = [t for t in tokens if t[:1].isalpha() and len(t) > 1]
+    while len(tokens) < size:
+        left = words[min(rng.next(len(words)), rng.next(len(words)))]
+        right = suffixes[min(rng.next(len(suffixes)), rng.next(len(suffixes)))]
+        token = left + right
+        if len(token) > MAX_LEN or token in seen:
+            continue
+        seen.add(token)
+        tokens.append(token)
+    return tokens
+
+
+def numbers(digits: int) -> list[bytes]:
+    # like llama-3, which splits numbers into groups of up to three digits
+    return [str(n).encode() for n in range(10**digits)]
+
+
+def unique(tokens: list[bytes]) -> list[bytes]:
+    return list(dict.fromkeys(tokens))
+
+
+def gpt2(pieces: list[bytes]) -> list[bytes]:
+    tokens = unique([bytes([b]) for b in range(256)] + pieces)
+    return extend(tokens, 50256, seed=2) + [b"<|endoftext|>"]
+
+
+def t5(pieces: list[bytes]) -> list[bytes]:
+    # sentencepiece without byte fallback, 100 sentinel tokens at the end
+    tokens = unique([b"<pad>", b"</s>", b"<unk>"] + pieces)
+    tokens = extend(tokens, 32000, seed=5)
+    return tokens + [f"<extra_id_{i}>".encode() for i in reversed(range(100))]
+
+
+def llama3(pieces: list[bytes]) -> list[bytes]:
+    tokens = unique([bytes([b]) for b in range(256)] + numbers(3) + pieces)
+    special = [b"<|begin_of_text|>", b"<|end_of_text|>"] + [
+        f"<|reserved_special_token_{i}|>".encode() for i in range(254)
+    ]
+    return extend(tokens, 128000, seed=3) + special
+
+
+def write(name: str, tokens: list[bytes]) -> None:
+    assert all(len(token) <= 255 for token in tokens)
+    with open(DIR / f"{name}.vocab", "wb") as f:
+        f.write(struct.pack("<I", len(tokens)))
+        for token in tokens:
+            f.write(struct.pack("<B", len(token)))
+            f.write(token)
+
+
+if __name__ == "__main__":
+    pieces = llama2_pieces()
+    for name, make, size in [
+        ("gpt2", gpt2, 50257),
+        ("t5", t5, 32100),
+        ("llama3", llama3, 128256),
+    ]:
+        tokens = make(pieces)
+        assert len(tokens) == size, (name, len(tokens))
+        write(name, tokens)
@@ -0,0 +1,435 @@
+use std::{error::Error, iter::Peekable, str::CharIndices};
+
+use indexmap::IndexMap;
+
+use crate::gbnf::{references, Converter, Expr};
+
+// core rules from appendix B of RFC 5234, added to a grammar
+// if they are referenced but not defined by it
+const CORE_RULES: &str = r#"
+ALPHA  = %x41-5A / %x61-7A
+BIT    = "0" / "1"
+CHAR   = %x01-7F
+CR     = %x0D
+CRLF   = CR LF
+CTL    = %x00-1F / %x7F
+DIGIT  = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB   = %x09
+LF     = %x0A
+LWSP   = *(WSP / CRLF WSP)
+OCTET  = %x00-FF
+SP     = %x20
+VCHAR  = %x21-7E
+WSP    = SP / HTAB
+"#;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Name(String),
+    // true for incremental alternatives =/
+    Define(bool),
+    // true if case sensitive
+    Literal(String, bool),
+    Range(char, char),
+    Open,
+    Close,
+    OpenOptional,
+    CloseOptional,
+    Slash,
+    Repeat(u32, Option<u32>),
+}
+
+fn number(chars: &mut Peekable<CharIndices>, radix: u32) -> Option<u32> {
+    let mut digits = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_digit(radix)) {
+        digits.push(c);
+    }
+    u32::from_str_radix(&digits, radix).ok()
+}
+
+// numeric value after the %, like x41, x41-5A or d13.10
+fn numeric_value(chars: &mut Peekable<CharIndices>, pos: usize) -> Result<Lexeme, Box<dyn Error>> {
+    let radix = match chars.next().map(|(_, c)| c.to_ascii_lowercase()) {
+        Some('x') => 16,
+        Some('d') => 10,
+        Some('b') => 2,
+        _ => return Err(format!("expected %x, %d or %b at position {pos}").into()),
+    };
+    let value = |chars: &mut Peekable<CharIndices>| {
+        number(chars, radix)
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("invalid numeric value at position {pos}"))
+    };
+    let first = value(chars)?;
+    if chars.next_if(|&(_, c)| c == '-').is_some() {
+        let last = value(chars)?;
+        if last < first {
+            return Err(format!("invalid range at position {pos}").into());
+        }
+        return Ok(Lexeme::Range(first, last));
+    }
+    let mut literal = String::from(first);
+    while chars.next_if(|&(_, c)| c == '.').is_some() {
+        literal.push(value(chars)?);
+    }
+    Ok(Lexeme::Literal(literal, true))
+}
+
+// string after the opening quote
+fn quoted(chars: &mut Peekable<CharIndices>, pos: usize) -> Result<String, Box<dyn Error>> {
+    let mut literal = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '"')) => return Ok(literal),
+            Some((_, c)) => literal.push(c),
+            None => return Err(format!("unterminated string at position {pos}").into()),
+        }
+    }
+}
+
+fn tokenize(abnf: &str) -> Result<Vec<Lexeme>, Box<dyn Error>> {
+    let mut lexemes = vec![];
+    let mut chars = abnf.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            ';' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut name = String::from(c);
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '-')
+                {
+                    name.push(c);
+                }
+                Lexeme::Name(name)
+            }
+            '=' => Lexeme::Define(chars.next_if(|&(_, c)| c == '/').is_some()),
+            '"' => Lexeme::Literal(quoted(&mut chars, pos)?, false),
+            // case sensitive and insensitive strings from RFC 7405
+            '%' if chars.next_if(|&(_, c)| matches!(c, 's' | 'S')).is_some() => {
+                if chars.next_if(|&(_, c)| c == '"').is_none() {
+                    return Err(format!("expected string after %s at position {pos}").into());
+                }
+                Lexeme::Literal(quoted(&mut chars, pos)?, true)
+            }
+            '%' if chars.next_if(|&(_, c)| matches!(c, 'i' | 'I')).is_some() => {
+                if chars.next_if(|&(_, c)| c == '"').is_none() {
+                    return Err(format!("expected string after %i at position {pos}").into());
+                }
+                Lexeme::Literal(quoted(&mut chars, pos)?, false)
+            }
+            '%' => numeric_value(&mut chars, pos)?,
+            '<' => return Err(format!("prose values are not supported at position {pos}").into()),
+            '(' => Lexeme::Open,
+            ')' => Lexeme::Close,
+            '[' => Lexeme::OpenOptional,
+            ']' => Lexeme::CloseOptional,
+            '/' => Lexeme::Slash,
+            '*' => Lexeme::Repeat(0, number(&mut chars, 10)),
+            c if c.is_ascii_digit() => {
+                let mut digits = String::from(c);
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    digits.push(c);
+                }
+                let min = digits.parse()?;
+                if chars.next_if(|&(_, c)| c == '*').is_some() {
+                    let max = number(&mut chars, 10);
+                    if max.is_some_and(|max| max < min) {
+                        return Err(format!("invalid repetition at position {pos}").into());
+                    }
+                    Lexeme::Repeat(min, max)
+                } else {
+                    Lexeme::Repeat(min, Some(min))
+                }
+            }
+            c => return Err(format!("unexpected character {c:?} at position {pos}").into()),
+        };
+        lexemes.push(lexeme);
+    }
+    Ok(lexemes)
+}
+
+// case insensitive strings match ascii letters in both cases
+fn literal(literal: String, case_sensitive: bool) -> Expr {
+    if case_sensitive || !literal.chars().any(|c| c.is_ascii_alphabetic()) {
+        return Expr::Literal(literal);
+    }
+    let mut items = vec![];
+    let mut run = String::new();
+    for c in literal.chars() {
+        if !c.is_ascii_alphabetic() {
+            run.push(c);
+            continue;
+        }
+        if !run.is_empty() {
+            items.push(Expr::Literal(std::mem::take(&mut run)));
+        }
+        let (lower, upper) = (c.to_ascii_lowercase(), c.to_ascii_uppercase());
+        items.push(Expr::Class(false, vec![(lower, lower), (upper, upper)]));
+    }
+    if !run.is_empty() {
+        items.push(Expr::Literal(run));
+    }
+    if items.len() == 1 {
+        items.pop().unwrap()
+    } else {
+        Expr::Seq(items)
+    }
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos + offset)
+    }
+
+    fn at_rule_start(&self) -> bool {
+        matches!(self.peek(0), Some(Lexeme::Name(_)))
+            && matches!(self.peek(1), Some(Lexeme::Define(_)))
+    }
+
+    fn alternatives(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut alternatives = vec![self.concatenation()?];
+        while self.peek(0) == Some(&Lexeme::Slash) {
+            self.pos += 1;
+            alternatives.push(self.concatenation()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Alt(alternatives)
+        })
+    }
+
+    fn group(&mut self, close: Lexeme) -> Result<Expr, Box<dyn Error>> {
+        self.pos += 1;
+        let inner = self.alternatives()?;
+        if self.peek(0) != Some(&close) {
+            return Err(format!("missing closing {close:?}").into());
+        }
+        Ok(inner)
+    }
+
+    fn concatenation(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut items = vec![];
+        // a rule ends where the next one starts
+        while !self.at_rule_start() {
+            let repeat = match self.peek(0) {
+                Some(&Lexeme::Repeat(min, max)) => {
+                    self.pos += 1;
+                    Some((min, max))
+                }
+                _ => None,
+            };
+            let element = match self.peek(0).cloned() {
+                None | Some(Lexeme::Slash | Lexeme::Close | Lexeme::CloseOptional)
+                    if repeat.is_none() =>
+                {
+                    break
+                }
+                Some(Lexeme::Name(name)) => Expr::Ref(name.to_lowercase()),
+                Some(Lexeme::Literal(text, case_sensitive)) => literal(text, case_sensitive),
+                Some(Lexeme::Range(first, last)) => Expr::Class(false, vec![(first, last)]),
+                Some(Lexeme::Open) => self.group(Lexeme::Close)?,
+                Some(Lexeme::OpenOptional) => {
+                    let inner = self.group(Lexeme::CloseOptional)?;
+                    Expr::Repeat(Box::new(inner), 0, Some(1))
+                }
+                Some(Lexeme::Define(_)) => return Err("unexpected =".into()),
+                _ => return Err("repetition without element".into()),
+            };
+            self.pos += 1;
+            items.push(match repeat {
+                Some((min, max)) => Expr::Repeat(Box::new(element), min, max),
+                None => element,
+            });
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+}
+
+// rules by lowercase name, rule names are case insensitive
+fn parse_rules(abnf: &str) -> Result<IndexMap<String, Expr>, Box<dyn Error>> {
+    let mut parser = Parser {
+        lexemes: tokenize(abnf)?,
+        pos: 0,
+    };
+    let mut rules = IndexMap::new();
+    while parser.pos < parser.lexemes.len() {
+        let Some(Lexeme::Name(name)) = parser.peek(0).cloned() else {
+            return Err("expected rule name".into());
+        };
+        let Some(&Lexeme::Define(incremental)) = parser.peek(1) else {
+            return Err(format!("expected = after rule name {name}").into());
+        };
+        parser.pos += 2;
+        let expr = parser.alternatives()?;
+        if parser.pos < parser.lexemes.len() && !parser.at_rule_start() {
+            return Err(
+                format!("unexpected {:?} in rule {name}", parser.lexemes[parser.pos]).into(),
+            );
+        }
+        let key = name.to_lowercase();
+        match (rules.get_mut(&key), incremental) {
+            (None, false) => {
+                rules.insert(key, expr);
+            }
+            (Some(Expr::Alt(alternatives)), true) => alternatives.push(expr),
+            (Some(existing), true) => {
+                let first = std::mem::replace(existing, Expr::Alt(vec![]));
+                *existing = Expr::Alt(vec![first, expr]);
+            }
+            (None, true) => return Err(format!("=/ for undefined rule {name}").into()),
+            (Some(_), false) => return Err(format!("duplicate rule {name}").into()),
+        }
+    }
+    Ok(rules)
+}
+
+// converts a grammar in ABNF notation (RFC 5234 and the case sensitive strings
+// of RFC 7405) into an LR(1) grammar and lexer, the first rule is the start
+// rule; core rules like ALPHA, DIGIT or CRLF are added when referenced, and
+// like for GBNF, regular rules are inlined into tokens split by longest match
+pub fn abnf_to_lr1(abnf: &str) -> Result<(String, String), Box<dyn Error>> {
+    let mut rules = parse_rules(abnf)?;
+    let start = rules.keys().next().ok_or("grammar has no rules")?.clone();
+    let core = parse_rules(CORE_RULES).expect("core rules are valid");
+    // core rules can reference other core rules
+    let mut todo: Vec<String> = rules.keys().cloned().collect();
+    while let Some(name) = todo.pop() {
+        let mut refs = vec![];
+        references(&rules[&name], &mut refs);
+        let missing: Vec<String> = refs
+            .into_iter()
+            .filter(|r| !rules.contains_key(*r) && core.contains_key(*r))
+            .map(String::from)
+            .collect();
+        for r in missing {
+            if !rules.contains_key(&r) {
+                rules.insert(r.clone(), core[&r].clone());
+                todo.push(r);
+            }
+        }
+    }
+    Converter::new(&rules)?.convert(&start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint};
+
+    fn constraint(abnf: &str) -> LR1GrammarConstraint {
+        let (grammar, lexer) = abnf_to_lr1(abnf).unwrap();
+        LR1GrammarConstraint::new(&grammar, &lexer, (0..=255).map(|b| vec![b]).collect()).unwrap()
+    }
+
+    fn assert_matches(lr1: &LR1GrammarConstraint, valid: &[&str], invalid: &[&str]) {
+        for valid in valid {
+            let state = lr1.get_state(valid.as_bytes()).unwrap();
+            assert!(lr1.is_match_state(&state), "{valid}");
+        }
+        for invalid in invalid {
+            assert!(
+                lr1.get_state(invalid.as_bytes())
+                    .is_none_or(|state| !lr1.is_match_state(&state)),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_abnf_uri() {
+        // simplified URI from RFC 3986
+        let lr1 = constraint(
+            r##"
+URI           = scheme ":" hier-part [ "?" query ] [ "#" fragment ]
+hier-part     = "//" authority path-abempty
+              / path-rootless
+scheme        = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
+authority     = host [ ":" port ]
+host          = 1*( unreserved / pct-encoded )
+port          = *DIGIT
+path-abempty  = *( "/" segment )
+path-rootless = 1*pchar *( "/" segment )
+segment       = *pchar
+pchar         = unreserved / pct-encoded / sub-delims / ":" / "@"
+query         = *( pchar / "/" / "?" )
+fragment      = *( pchar / "/" / "?" )
+pct-encoded   = "%" HEXDIG HEXDIG
+unreserved    = ALPHA / DIGIT / "-" / "." / "_" / "~"
+sub-delims    = "!" / "$" / "&" / "'" / "(" / ")" / "*" / "+" / "," / ";" / "="
+"##,
+        );
+        assert_matches(
+            &lr1,
+            &[
+                "https://example.com/a/b?x=1#top",
+                "HTTP://example.com:8080/%7Euser",
+                "mailto:user@example.com",
+                "urn:isbn:0451450523",
+            ],
+            &["1http://x", "http://exa mple.com", "http://x/%zz"],
+        );
+    }
+
+    #[test]
+    fn test_abnf_features() {
+        let lr1 = constraint(
+            r#"
+; http style header fields, the first rule is the start rule
+fields     = 1*field CRLF
+field      = name ":" *WSP value CRLF
+name       = 1*( ALPHA / "-" )
+value      = %s"on" / %s"off" / number / list
+value      =/ %i"auto"
+number     = 1*3DIGIT [ "." 2DIGIT ]
+list       = "(" [ value *( "," value ) ] ")"
+"#,
+        );
+        assert_matches(
+            &lr1,
+            &[
+                "Keep-Alive: on\r\n\r\n",
+                "a:off\r\nb: 12.50\r\n\r\n",
+                "x: (1,2)\r\n\r\n",
+                "x: (AuTo,())\r\n\r\n",
+            ],
+            &[
+                "a: ON\r\n\r\n",
+                "a: 1234\r\n\r\n",
+                "a: 1.5\r\n\r\n",
+                "a: on\n\n",
+            ],
+        );
+
+        // numeric values, concatenations and case insensitive strings
+        let lr1 = constraint("greeting = %x48.49 \" \" %d97-99 \"x\" 2*3( \"ab\" )");
+        assert_matches(
+            &lr1,
+            &["HI axabab", "HI cXaBAbab"],
+            &["hi axabab", "HI dxabab", "HI axab", "HI axabababab"],
+        );
+
+        assert!(abnf_to_lr1("").is_err());
+        assert!(abnf_to_lr1("a = b").is_err());
+        assert!(abnf_to_lr1("a = <prose>").is_err());
+        assert!(abnf_to_lr1("a = \"x\"\na = \"y\"").is_err());
+        assert!(abnf_to_lr1("a =/ \"x\"").is_err());
+        assert!(abnf_to_lr1("a = ( \"x\"").is_err());
+        assert!(abnf_to_lr1("a = %x5A-41").is_err());
+    }
+}
@@ -0,0 +1,46 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use grammar_utils::run_grammar_tests;
+
+#[derive(Parser)]
+#[command(about = "Utilities for regex and grammar constraints")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the tests.toml of grammar directories like grammars/json
+    Test {
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match args.command {
+        Command::Test { dirs } => {
+            let mut success = true;
+            for dir in dirs {
+                match run_grammar_tests(&dir) {
+                    Ok(report) => {
+                        println!("{}\n{report}", dir.display());
+                        success &= report.is_success();
+                    }
+                    Err(e) => {
+                        eprintln!("{}\nerror: {e}", dir.display());
+                        success = false;
+                    }
+                }
+            }
+            if success {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use clap::Parser;
+use grammar_utils::ConstraintServer;
+
+#[derive(Parser)]
+#[command(about = "Serve regex and grammar constraints over http")]
+struct Args {
+    #[arg(short, long, default_value = "127.0.0.1:8000")]
+    addr: String,
+
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+
+    /// Evict sessions unused for this many seconds
+    #[arg(long)]
+    session_ttl: Option<f64>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+    let mut server = ConstraintServer::new();
+    if let Some(ttl) = args.session_ttl {
+        server = server.with_session_ttl(Duration::try_from_secs_f64(ttl)?);
+    }
+    println!("serving constraints on http://{}", args.addr);
+    server.serve(&args.addr, args.threads)
+}
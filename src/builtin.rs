@@ -0,0 +1,99 @@
+// ready-made grammars for common formats, embedded from the grammars
+// directory, where each is tested by its tests.toml
+use std::error::Error;
+
+use crate::{ExactLR1GrammarConstraint, LR1GrammarConstraint, LR1GrammarParser};
+
+macro_rules! builtin_grammars {
+    ($($name:ident),+ $(,)?) => {
+        // names of all built-in grammars, in alphabetical order
+        pub const NAMES: &[&str] = &[$(stringify!($name)),+];
+
+        // grammar and lexer definition of a built-in grammar
+        pub fn grammar(name: &str) -> Option<(&'static str, &'static str)> {
+            match name {
+                $(stringify!($name) => Some((
+                    include_str!(concat!("../grammars/", stringify!($name), "/", stringify!($name), ".y")),
+                    include_str!(concat!("../grammars/", stringify!($name), "/", stringify!($name), ".l")),
+                )),)+
+                _ => None,
+            }
+        }
+
+        $(
+            pub fn $name(continuations: Vec<Vec<u8>>) -> Result<LR1GrammarConstraint, Box<dyn Error>> {
+                constraint(stringify!($name), continuations)
+            }
+        )+
+    };
+}
+
+builtin_grammars!(calc, csv, datetime, json, sparql, sql, xml, yaml);
+
+fn definition(name: &str) -> Result<(&'static str, &'static str), Box<dyn Error>> {
+    grammar(name).ok_or_else(|| {
+        format!(
+            "unknown built-in grammar {name}, expected one of {}",
+            NAMES.join(", ")
+        )
+        .into()
+    })
+}
+
+pub fn constraint(
+    name: &str,
+    continuations: Vec<Vec<u8>>,
+) -> Result<LR1GrammarConstraint, Box<dyn Error>> {
+    let (grammar, lexer) = definition(name)?;
+    LR1GrammarConstraint::new(grammar, lexer, continuations)
+}
+
+pub fn exact_constraint(
+    name: &str,
+    continuations: Vec<Vec<u8>>,
+) -> Result<ExactLR1GrammarConstraint, Box<dyn Error>> {
+    let (grammar, lexer) = definition(name)?;
+    ExactLR1GrammarConstraint::new(grammar, lexer, continuations)
+}
+
+pub fn parser(name: &str) -> Result<LR1GrammarParser, Box<dyn Error>> {
+    let (grammar, lexer) = definition(name)?;
+    LR1GrammarParser::new(grammar, lexer)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::Constraint;
+
+    #[test]
+    fn test_builtin() {
+        // every grammar directory is built in
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/grammars");
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, NAMES);
+        for name in NAMES {
+            assert!(parser(name).is_ok(), "{name}");
+        }
+        assert!(grammar("toml").is_none());
+        assert!(constraint("toml", vec![]).is_err());
+
+        let conts: Vec<_> = ["{", "}", "[", "]", "1", ",", "a"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let json = json(conts.clone()).unwrap();
+        assert!(json.check(b"[1,{}]"));
+        assert!(!json.check(b"[1,]"));
+        let state = json.get_start_state();
+        assert_eq!(json.get_valid_continuations(&state), [0, 2, 4]);
+        let csv = exact_constraint("csv", conts).unwrap();
+        assert!(csv.check(b"a,1"));
+    }
+}
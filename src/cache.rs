@@ -0,0 +1,607 @@
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{BuildHasher, Hash, Hasher, RandomState},
+    mem::{size_of, size_of_val},
+    num::NonZeroUsize,
+    str::FromStr,
+};
+
+use lru::LruCache;
+use rustc_hash::{FxBuildHasher, FxHasher};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum CachePolicy {
+    // evict the least recently used core
+    #[default]
+    Lru,
+    // evict the least frequently used core, ties are broken by age
+    Lfu,
+    // simplified 2Q, cores seen once are kept in a fifo queue and only
+    // promoted to an lru queue when they are seen again after being evicted
+    TwoQueue,
+    // never evict
+    Unbounded,
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(CachePolicy::Lru),
+            "lfu" => Ok(CachePolicy::Lfu),
+            "2q" => Ok(CachePolicy::TwoQueue),
+            "unbounded" => Ok(CachePolicy::Unbounded),
+            _ => Err(format!(
+                "unknown cache policy {s}, expected lru, lfu, 2q or unbounded"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum CacheHasher {
+    #[default]
+    Siphash,
+    Ahash,
+    Fxhash,
+}
+
+impl FromStr for CacheHasher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "siphash" => Ok(CacheHasher::Siphash),
+            "ahash" => Ok(CacheHasher::Ahash),
+            "fxhash" => Ok(CacheHasher::Fxhash),
+            _ => Err(format!(
+                "unknown cache hasher {s}, expected siphash, ahash or fxhash"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CacheOptions {
+    // maximum number of entries over all cores
+    capacity: NonZeroUsize,
+    policy: CachePolicy,
+    hasher: CacheHasher,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: NonZeroUsize::new(8192).unwrap(),
+            policy: CachePolicy::default(),
+            hasher: CacheHasher::default(),
+        }
+    }
+}
+
+impl CacheOptions {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn capacity(mut self, capacity: NonZeroUsize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub(crate) fn policy(mut self, policy: CachePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub(crate) fn hasher(mut self, hasher: CacheHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+}
+
+// hasher chosen at runtime, dispatching on every write is
+// cheap compared to the hashing itself
+#[derive(Clone)]
+pub(crate) enum CacheHashBuilder {
+    Sip(RandomState),
+    AHash(ahash::RandomState),
+    Fx(FxBuildHasher),
+}
+
+impl From<CacheHasher> for CacheHashBuilder {
+    fn from(hasher: CacheHasher) -> Self {
+        match hasher {
+            CacheHasher::Siphash => CacheHashBuilder::Sip(RandomState::new()),
+            CacheHasher::Ahash => CacheHashBuilder::AHash(ahash::RandomState::new()),
+            CacheHasher::Fxhash => CacheHashBuilder::Fx(FxBuildHasher),
+        }
+    }
+}
+
+pub(crate) enum CacheHashState {
+    Sip(DefaultHasher),
+    AHash(ahash::AHasher),
+    Fx(FxHasher),
+}
+
+impl BuildHasher for CacheHashBuilder {
+    type Hasher = CacheHashState;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        match self {
+            CacheHashBuilder::Sip(state) => CacheHashState::Sip(state.build_hasher()),
+            CacheHashBuilder::AHash(state) => CacheHashState::AHash(state.build_hasher()),
+            CacheHashBuilder::Fx(state) => CacheHashState::Fx(state.build_hasher()),
+        }
+    }
+}
+
+impl Hasher for CacheHashState {
+    fn finish(&self) -> u64 {
+        match self {
+            CacheHashState::Sip(hasher) => hasher.finish(),
+            CacheHashState::AHash(hasher) => hasher.finish(),
+            CacheHashState::Fx(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            CacheHashState::Sip(hasher) => hasher.write(bytes),
+            CacheHashState::AHash(hasher) => hasher.write(bytes),
+            CacheHashState::Fx(hasher) => hasher.write(bytes),
+        }
+    }
+
+    // forward integer writes, fxhash and ahash have fast paths for them
+    fn write_u32(&mut self, i: u32) {
+        match self {
+            CacheHashState::Sip(hasher) => hasher.write_u32(i),
+            CacheHashState::AHash(hasher) => hasher.write_u32(i),
+            CacheHashState::Fx(hasher) => hasher.write_u32(i),
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        match self {
+            CacheHashState::Sip(hasher) => hasher.write_u64(i),
+            CacheHashState::AHash(hasher) => hasher.write_u64(i),
+            CacheHashState::Fx(hasher) => hasher.write_u64(i),
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        match self {
+            CacheHashState::Sip(hasher) => hasher.write_usize(i),
+            CacheHashState::AHash(hasher) => hasher.write_usize(i),
+            CacheHashState::Fx(hasher) => hasher.write_usize(i),
+        }
+    }
+}
+
+// keeps track of the order in which cores are evicted
+enum EvictionOrder<C> {
+    Lru(LruCache<C, (), CacheHashBuilder>),
+    Lfu {
+        // frequency and insertion tick per core
+        keys: HashMap<C, (u64, u64), CacheHashBuilder>,
+        order: BTreeMap<(u64, u64), C>,
+        tick: u64,
+    },
+    TwoQueue {
+        // cores seen once, in insertion order
+        fifo: LruCache<C, (), CacheHashBuilder>,
+        // cores seen again after being evicted from the fifo queue
+        lru: LruCache<C, (), CacheHashBuilder>,
+        // recently evicted cores from the fifo queue
+        ghosts: LruCache<C, (), CacheHashBuilder>,
+    },
+    Unbounded,
+}
+
+impl<C: Hash + Eq + Clone> EvictionOrder<C> {
+    fn new(policy: CachePolicy, capacity: NonZeroUsize, hasher: &CacheHashBuilder) -> Self {
+        match policy {
+            CachePolicy::Lru => EvictionOrder::Lru(LruCache::unbounded_with_hasher(hasher.clone())),
+            CachePolicy::Lfu => EvictionOrder::Lfu {
+                keys: HashMap::with_hasher(hasher.clone()),
+                order: BTreeMap::new(),
+                tick: 0,
+            },
+            CachePolicy::TwoQueue => EvictionOrder::TwoQueue {
+                fifo: LruCache::unbounded_with_hasher(hasher.clone()),
+                lru: LruCache::unbounded_with_hasher(hasher.clone()),
+                ghosts: LruCache::with_hasher(capacity, hasher.clone()),
+            },
+            CachePolicy::Unbounded => EvictionOrder::Unbounded,
+        }
+    }
+
+    fn touch<Q>(&mut self, core: &Q)
+    where
+        C: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self {
+            EvictionOrder::Lru(lru) => {
+                lru.promote(core);
+            }
+            EvictionOrder::Lfu { keys, order, .. } => {
+                let Some((freq, tick)) = keys.get_mut(core) else {
+                    return;
+                };
+                let core = order
+                    .remove(&(*freq, *tick))
+                    .expect("core should be ordered");
+                *freq += 1;
+                order.insert((*freq, *tick), core);
+            }
+            EvictionOrder::TwoQueue { lru, .. } => {
+                lru.promote(core);
+            }
+            EvictionOrder::Unbounded => {}
+        }
+    }
+
+    fn insert(&mut self, core: C) {
+        match self {
+            EvictionOrder::Lru(lru) => {
+                lru.put(core, ());
+            }
+            EvictionOrder::Lfu { keys, order, tick } => {
+                *tick += 1;
+                keys.insert(core.clone(), (1, *tick));
+                order.insert((1, *tick), core);
+            }
+            EvictionOrder::TwoQueue { fifo, lru, ghosts } => {
+                if ghosts.pop(&core).is_some() {
+                    lru.put(core, ());
+                } else {
+                    fifo.put(core, ());
+                }
+            }
+            EvictionOrder::Unbounded => {}
+        }
+    }
+
+    fn pop(&mut self, num_cores: usize) -> Option<C> {
+        match self {
+            EvictionOrder::Lru(lru) => lru.pop_lru().map(|(core, _)| core),
+            EvictionOrder::Lfu { keys, order, .. } => {
+                let (_, core) = order.pop_first()?;
+                keys.remove(&core);
+                Some(core)
+            }
+            EvictionOrder::TwoQueue { fifo, lru, ghosts } => {
+                // keep the fifo queue at about a quarter of all cores
+                if fifo.len() > num_cores / 4 || lru.is_empty() {
+                    let (core, _) = fifo.pop_lru()?;
+                    ghosts.put(core.clone(), ());
+                    Some(core)
+                } else {
+                    lru.pop_lru().map(|(core, _)| core)
+                }
+            }
+            EvictionOrder::Unbounded => None,
+        }
+    }
+}
+
+type Suffixes<S, V> = HashMap<S, V, CacheHashBuilder>;
+
+struct Core<P, S, V> {
+    shared: P,
+    suffixes: Suffixes<S, V>,
+}
+
+// a cache with two levels of keys, a core key and a suffix key, where
+// every core holds a value shared by all its suffix entries, e.g. work
+// that only depends on the parser stack of LR states that differ in their
+// pending lexeme; the eviction policy operates on cores, so that the entries
+// sharing a core are kept or evicted together with the shared value instead
+// of pushing out entries with other cores
+pub(crate) struct TwoLevelCache<C, P, S, V> {
+    entries: HashMap<C, Core<P, S, V>, CacheHashBuilder>,
+    order: EvictionOrder<C>,
+    hasher: CacheHashBuilder,
+    len: usize,
+    capacity: NonZeroUsize,
+}
+
+impl<C: Hash + Eq + Clone, P, S: Hash + Eq, V> TwoLevelCache<C, P, S, V> {
+    pub(crate) fn new(options: CacheOptions) -> Self {
+        let hasher = CacheHashBuilder::from(options.hasher);
+        Self {
+            entries: HashMap::with_hasher(hasher.clone()),
+            order: EvictionOrder::new(options.policy, options.capacity, &hasher),
+            hasher,
+            len: 0,
+            capacity: options.capacity,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_cores(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn contains_core<QC>(&self, core: &QC) -> bool
+    where
+        C: Borrow<QC>,
+        QC: Hash + Eq + ?Sized,
+    {
+        self.entries.contains_key(core)
+    }
+
+    pub(crate) fn get<QC, QS>(&mut self, core: &QC, suffix: &QS) -> Option<&V>
+    where
+        C: Borrow<QC>,
+        S: Borrow<QS>,
+        QC: Hash + Eq + ?Sized,
+        QS: Hash + Eq + ?Sized,
+    {
+        let value = self.entries.get(core)?.suffixes.get(suffix)?;
+        self.order.touch(core);
+        Some(value)
+    }
+
+    // the value shared by all entries of the core, e.g. to compute
+    // a missing suffix entry from it
+    pub(crate) fn get_shared<QC>(&mut self, core: &QC) -> Option<&P>
+    where
+        C: Borrow<QC>,
+        QC: Hash + Eq + ?Sized,
+    {
+        let shared = &self.entries.get(core)?.shared;
+        self.order.touch(core);
+        Some(shared)
+    }
+
+    // inserts a new entry, the shared value is only kept if the core is new;
+    // evicted and replaced entries are passed to on_evict, together with
+    // their core and its shared value if the core was evicted as well
+    pub(crate) fn insert(
+        &mut self,
+        core: C,
+        shared: P,
+        suffix: S,
+        value: V,
+        mut on_evict: impl FnMut(Option<(&C, &P)>, &S, &V),
+    ) {
+        if let Some((suffix, value)) = self
+            .entries
+            .get_mut(&core)
+            .and_then(|entry| entry.suffixes.remove_entry(&suffix))
+        {
+            on_evict(None, &suffix, &value);
+            self.len -= 1;
+        }
+        // the core itself can be evicted here as well,
+        // in which case it is inserted again below
+        while self.len >= self.capacity.get() && self.pop(&mut on_evict) {}
+        if let Some(entry) = self.entries.get_mut(&core) {
+            entry.suffixes.insert(suffix, value);
+            self.order.touch(&core);
+        } else {
+            let mut suffixes = HashMap::with_hasher(self.hasher.clone());
+            suffixes.insert(suffix, value);
+            self.entries.insert(core.clone(), Core { shared, suffixes });
+            self.order.insert(core);
+        }
+        self.len += 1;
+    }
+
+    // removes a core with all its entries according to the eviction policy,
+    // returns false if there is nothing to evict
+    pub(crate) fn pop(&mut self, mut on_evict: impl FnMut(Option<(&C, &P)>, &S, &V)) -> bool {
+        let Some(core) = self.order.pop(self.entries.len()) else {
+            return false;
+        };
+        let entry = self
+            .entries
+            .remove(&core)
+            .expect("evicted core should be cached");
+        for (i, (suffix, value)) in entry.suffixes.iter().enumerate() {
+            on_evict((i == 0).then_some((&core, &entry.shared)), suffix, value);
+        }
+        self.len -= entry.suffixes.len();
+        true
+    }
+}
+
+// compact representation of a set of continuation indices, picks the
+// smallest of a sorted list, runs of consecutive indices or a bitset,
+// the original order of the indices is not preserved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CompressedIndices {
+    List(Vec<u32>),
+    // start and length of each run
+    Runs(Vec<(u32, u32)>),
+    Bitset(Vec<u64>),
+}
+
+impl CompressedIndices {
+    pub(crate) fn new(indices: &[i32], vocab_size: usize) -> Self {
+        let mut sorted: Vec<u32> = indices.iter().map(|&i| i as u32).collect();
+        sorted.sort_unstable();
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for &i in &sorted {
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == i => *len += 1,
+                _ => runs.push((i, 1)),
+            }
+        }
+        let list_size = size_of_val(sorted.as_slice());
+        let runs_size = size_of_val(runs.as_slice());
+        let bitset_size = vocab_size.div_ceil(64) * size_of::<u64>();
+        if bitset_size < list_size.min(runs_size) {
+            let mut bitset = vec![0; vocab_size.div_ceil(64)];
+            for i in sorted {
+                bitset[i as usize / 64] |= 1 << (i % 64);
+            }
+            CompressedIndices::Bitset(bitset)
+        } else if runs_size < list_size {
+            runs.shrink_to_fit();
+            CompressedIndices::Runs(runs)
+        } else {
+            sorted.shrink_to_fit();
+            CompressedIndices::List(sorted)
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            CompressedIndices::List(list) => list.len(),
+            CompressedIndices::Runs(runs) => runs.iter().map(|&(_, len)| len as usize).sum(),
+            CompressedIndices::Bitset(bitset) => {
+                bitset.iter().map(|word| word.count_ones() as usize).sum()
+            }
+        }
+    }
+
+    pub(crate) fn decompress(&self) -> Vec<i32> {
+        match self {
+            CompressedIndices::List(list) => list.iter().map(|&i| i as i32).collect(),
+            CompressedIndices::Runs(runs) => runs
+                .iter()
+                .flat_map(|&(start, len)| start as i32..(start + len) as i32)
+                .collect(),
+            CompressedIndices::Bitset(bitset) => {
+                let mut indices = Vec::with_capacity(self.len());
+                for (w, &word) in bitset.iter().enumerate() {
+                    let mut word = word;
+                    while word != 0 {
+                        indices.push((w * 64) as i32 + word.trailing_zeros() as i32);
+                        word &= word - 1;
+                    }
+                }
+                indices
+            }
+        }
+    }
+
+    // heap bytes used
+    pub(crate) fn heap_size(&self) -> usize {
+        match self {
+            CompressedIndices::List(list) => list.capacity() * size_of::<u32>(),
+            CompressedIndices::Runs(runs) => runs.capacity() * size_of::<(u32, u32)>(),
+            CompressedIndices::Bitset(bitset) => bitset.capacity() * size_of::<u64>(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(policy: CachePolicy) -> CacheOptions {
+        CacheOptions::new()
+            .capacity(NonZeroUsize::new(4).unwrap())
+            .policy(policy)
+    }
+
+    #[test]
+    fn test_two_level_cache() {
+        let mut cache = TwoLevelCache::new(options(CachePolicy::Lru));
+        let mut evicted = vec![];
+        let mut on_evict = |core: Option<(&u8, &u8)>, suffix: &u8, _: &()| {
+            evicted.push((core.map(|(core, _)| *core), *suffix));
+        };
+        cache.insert(1, 10, 1, (), &mut on_evict);
+        cache.insert(1, 11, 2, (), &mut on_evict);
+        cache.insert(2, 20, 1, (), &mut on_evict);
+        cache.insert(1, 12, 3, (), &mut on_evict);
+        assert_eq!((cache.len(), cache.num_cores()), (4, 2));
+        assert!(cache.get(&1, &2).is_some());
+        assert!(cache.get(&2, &2).is_none());
+        // the shared value of a core is the one it was created with
+        assert_eq!(cache.get_shared(&1), Some(&10));
+        assert_eq!(cache.get_shared(&3), None);
+        // core 2 is the least recently used one
+        cache.insert(1, 13, 4, (), &mut on_evict);
+        assert_eq!((cache.len(), cache.num_cores()), (4, 1));
+        assert!(!cache.contains_core(&2));
+        // replacing an entry does not evict others
+        cache.insert(1, 13, 4, (), &mut on_evict);
+        assert_eq!(cache.len(), 4);
+        // a single core exceeding the capacity is evicted as a whole,
+        // and comes back with a new shared value
+        cache.insert(1, 14, 5, (), &mut on_evict);
+        assert_eq!((cache.len(), cache.num_cores()), (1, 1));
+        assert_eq!(cache.get_shared(&1), Some(&14));
+        assert!(cache.get(&1, &5).is_some());
+        assert!(cache.pop(&mut on_evict));
+        assert!(!cache.pop(&mut on_evict));
+        assert_eq!(cache.len(), 0);
+        assert_eq!(evicted.len(), 7);
+        assert_eq!(evicted[0], (Some(2), 1));
+        assert_eq!(evicted.iter().filter(|(core, _)| core.is_some()).count(), 3);
+    }
+
+    #[test]
+    fn test_cache_policies() {
+        let fill = |policy, hasher| {
+            let mut cache = TwoLevelCache::new(options(policy).hasher(hasher));
+            for core in 0..4u8 {
+                cache.insert(core, (), 0, (), |_, _, _| {});
+            }
+            // core 0 is used often, core 1 recently
+            for _ in 0..3 {
+                cache.get(&0, &0);
+            }
+            cache.get(&1, &0);
+            let mut evicted = vec![];
+            for core in 4..6 {
+                cache.insert(core, (), 0, (), |core, _, _| {
+                    evicted.extend(core.map(|(core, _)| *core))
+                });
+            }
+            evicted
+        };
+        assert_eq!(fill(CachePolicy::Lru, CacheHasher::Siphash), vec![2, 3]);
+        assert_eq!(fill(CachePolicy::Lfu, CacheHasher::Ahash), vec![2, 3]);
+        assert_eq!(fill(CachePolicy::TwoQueue, CacheHasher::Fxhash), vec![0, 1]);
+        assert!(fill(CachePolicy::Unbounded, CacheHasher::Fxhash).is_empty());
+
+        // with 2q, cores that come back after eviction are kept longer
+        let mut cache = TwoLevelCache::new(options(CachePolicy::TwoQueue));
+        for core in [0, 1, 2, 3, 4, 0, 5, 6, 7] {
+            cache.insert(core, (), 0, (), |_, _, _| {});
+        }
+        assert!(cache.contains_core(&0));
+        assert!(!cache.contains_core(&4));
+    }
+
+    #[test]
+    fn test_compressed_indices() {
+        let vocab_size = 1000;
+        let kind = |c: &CompressedIndices| match c {
+            CompressedIndices::List(_) => "list",
+            CompressedIndices::Runs(_) => "runs",
+            CompressedIndices::Bitset(_) => "bitset",
+        };
+        let cases: Vec<(Vec<i32>, _)> = vec![
+            (vec![], "list"),
+            (vec![999, 3, 500], "list"),
+            ((100..400).chain(600..700).rev().collect(), "runs"),
+            ((0..1000).step_by(2).collect(), "bitset"),
+        ];
+        for (indices, expected) in cases {
+            let compressed = CompressedIndices::new(&indices, vocab_size);
+            assert_eq!(kind(&compressed), expected);
+            let mut sorted = indices.clone();
+            sorted.sort();
+            assert_eq!(compressed.decompress(), sorted);
+            assert_eq!(compressed.len(), indices.len());
+            assert!(compressed.heap_size() <= indices.len() * size_of::<i32>());
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use candle_core::{Result, Tensor, D};
+
+use crate::Constraint;
+
+// masks logits of any float dtype on any device with the valid continuations of
+// the state, like Constraint::apply_mask; the last dimension of the logits are
+// the continuations, and all other dimensions are masked with the same state
+pub fn apply_mask_tensor<C: Constraint + ?Sized>(
+    constraint: &C,
+    state: &C::State,
+    logits: &Tensor,
+) -> Result<Tensor> {
+    let size = logits.dim(D::Minus1)?;
+    let mut mask = vec![0u8; size];
+    for (start, end) in constraint.get_valid_ranges(state) {
+        let start = (start as usize).min(size);
+        let end = (end as usize).min(size);
+        mask[start..end].fill(1);
+    }
+    let mask = Tensor::from_vec(mask, size, logits.device())?.broadcast_as(logits.shape())?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, logits.device())?
+        .to_dtype(logits.dtype())?
+        .broadcast_as(logits.shape())?;
+    mask.where_cond(logits, &neg_inf)
+}
+
+#[cfg(test)]
+mod test {
+    use candle_core::{DType, Device};
+
+    use super::*;
+    use crate::RegularExpressionConstraint;
+
+    #[test]
+    fn test_apply_mask_tensor() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new("[a-c]", conts).unwrap();
+        let state = re.get_start_state();
+        let logits = Tensor::ones((2, 260), DType::F32, &Device::Cpu).unwrap();
+        let masked = apply_mask_tensor(&re, &state, &logits).unwrap();
+        let masked: Vec<Vec<f32>> = masked.to_vec2().unwrap();
+        for row in masked {
+            let valid: Vec<_> = (0..row.len()).filter(|&i| row[i] == 1.0).collect();
+            assert_eq!(valid, [97, 98, 99]);
+            assert_eq!(row[0], f32::NEG_INFINITY);
+        }
+
+        let logits = Tensor::zeros(99, DType::BF16, &Device::Cpu).unwrap();
+        let masked = apply_mask_tensor(&re, &state, &logits).unwrap();
+        assert_eq!(masked.dtype(), DType::BF16);
+        let masked: Vec<f32> = masked.to_dtype(DType::F32).unwrap().to_vec1().unwrap();
+        assert_eq!(masked.iter().filter(|l| l.is_finite()).count(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{error::Error, fs::File, io::read_to_string, mem::size_of, path::Path};
+
+use crate::{
+    lr1::valid_continuations_in_order,
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+// byte trie with u32 node ids and 0 as root, shared by the choice constraint
+// and the literal set, which store their nodes differently
+pub(crate) trait Trie {
+    fn step(&self, node: u32, byte: u8) -> Option<u32>;
+
+    fn walk(&self, node: u32, bytes: &[u8]) -> Option<u32> {
+        bytes
+            .iter()
+            .try_fold(node, |node, &byte| self.step(node, byte))
+    }
+
+    // continuations that can be walked from the node, in the order of
+    // optimized_prefix_order given by permutation and skips
+    fn valid_continuations(
+        &self,
+        node: u32,
+        continuations: &[Vec<u8>],
+        permutation: &[usize],
+        skips: &[usize],
+    ) -> Vec<usize> {
+        valid_continuations_in_order(continuations, permutation, skips, |cont| {
+            self.walk(node, cont).is_some()
+        })
+    }
+}
+
+// trie node, its outgoing edges are edges[offsets[node]..offsets[node + 1]]
+// sorted by byte; every node lies on the path to at least one option
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct ChoiceState(u32);
+
+impl MemoryUsage for ChoiceState {
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+// constraint that only allows one of a fixed set of strings, backed by
+// a trie instead of a regex alternation, so even tens of thousands of
+// options are built in linear time and memory
+pub struct ChoiceConstraint {
+    offsets: Vec<u32>,
+    edges: Vec<(u8, u32)>,
+    is_option: Vec<bool>,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+}
+
+impl ChoiceConstraint {
+    pub fn new(options: Vec<String>, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        if options.is_empty() {
+            return Err("choice constraint needs at least one option".into());
+        }
+        let mut options: Vec<_> = options.into_iter().map(String::into_bytes).collect();
+        options.sort();
+        options.dedup();
+
+        // in sorted order, a child shared with an earlier option is always
+        // the last child added to its node
+        let mut children: Vec<Vec<(u8, u32)>> = vec![vec![]];
+        let mut is_option = vec![false];
+        for option in &options {
+            let mut node = 0;
+            for &b in option {
+                node = match children[node].last() {
+                    Some(&(last, child)) if last == b => child as usize,
+                    _ => {
+                        let child = children.len();
+                        if child > u32::MAX as usize {
+                            return Err("too many options for a choice constraint".into());
+                        }
+                        children[node].push((b, child as u32));
+                        children.push(vec![]);
+                        is_option.push(false);
+                        child
+                    }
+                };
+            }
+            is_option[node] = true;
+        }
+
+        let mut offsets = Vec::with_capacity(children.len() + 1);
+        let mut edges = Vec::with_capacity(children.len() - 1);
+        for node in children {
+            offsets.push(edges.len() as u32);
+            edges.extend(node);
+        }
+        offsets.push(edges.len() as u32);
+
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            offsets,
+            edges,
+            is_option,
+            continuations,
+            permutation,
+            skips,
+        })
+    }
+
+    // one option per line, empty lines are options too except for a
+    // trailing one
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path.as_ref())?;
+        let content = read_to_string(file)?;
+        let options = content.lines().map(String::from).collect();
+        Self::new(options, continuations)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+}
+
+impl Trie for ChoiceConstraint {
+    fn step(&self, node: u32, byte: u8) -> Option<u32> {
+        let start = self.offsets[node as usize] as usize;
+        let end = self.offsets[node as usize + 1] as usize;
+        let edges = &self.edges[start..end];
+        let i = edges.binary_search_by_key(&byte, |&(b, _)| b).ok()?;
+        Some(edges[i].1)
+    }
+}
+
+impl MemoryUsage for ChoiceConstraint {
+    fn memory_usage(&self) -> usize {
+        self.offsets.capacity() * size_of::<u32>()
+            + self.edges.capacity() * size_of::<(u8, u32)>()
+            + self.is_option.capacity()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for ChoiceConstraint {
+    type State = ChoiceState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.walk(0, prefix).map(ChoiceState)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        ChoiceState(0)
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.is_option[state.0 as usize]
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.valid_continuations(state.0, &self.continuations, &self.permutation, &self.skips)
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+}
+
+impl ByteConstraint for ChoiceConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.walk(state.0, bytes).map(ChoiceState)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RegularExpressionConstraint;
+
+    fn conts() -> Vec<Vec<u8>> {
+        [
+            "a",
+            "b",
+            "ab",
+            "abc",
+            "ber",
+            "Berlin",
+            "lin",
+            "ü",
+            "\u{fc}nchen",
+            "M",
+        ]
+        .iter()
+        .map(|c| c.as_bytes().to_vec())
+        .collect()
+    }
+
+    #[test]
+    fn test_choice() {
+        let options = ["Berlin", "Bern", "München", "a", "abc", "a"];
+        let choice =
+            ChoiceConstraint::new(options.iter().map(|o| o.to_string()).collect(), conts())
+                .unwrap();
+        for option in options {
+            assert!(choice.check(option.as_bytes()));
+        }
+        assert!(!choice.check(b"ab"));
+        assert!(!choice.check(b"Ber"));
+        assert!(!choice.check(b"Berlins"));
+        assert!(choice.get_state(b"Bex").is_none());
+
+        let state = choice.get_start_state();
+        assert!(!choice.is_match_state(&state));
+        assert_eq!(choice.get_valid_continuations(&state), vec![0, 2, 3, 5, 9]);
+        let state = choice.get_state(b"Ber").unwrap();
+        assert_eq!(choice.get_valid_continuations(&state), vec![6]);
+        let state = choice.get_state(b"M").unwrap();
+        assert_eq!(choice.get_valid_continuations(&state), vec![7, 8]);
+        let state = choice.get_state(b"a").unwrap();
+        assert!(choice.is_match_state(&state));
+
+        // the empty option makes the start state a match
+        let choice = ChoiceConstraint::new(vec!["".to_string(), "b".to_string()], conts()).unwrap();
+        assert!(choice.is_match_state(&choice.get_start_state()));
+        assert!(ChoiceConstraint::new(vec![], conts()).is_err());
+    }
+
+    #[test]
+    fn test_choice_matches_regex() {
+        let options: Vec<String> = (0..2000)
+            .map(|i| format!("entity_{}_{}", i % 37, i * 7919 % 1000))
+            .collect();
+        let conts: Vec<Vec<u8>> = (0..=255)
+            .map(|b| vec![b])
+            .chain(["entity_", "_1", "12", "3_"].map(|c| c.as_bytes().to_vec()))
+            .collect();
+        let choice = ChoiceConstraint::new(options.clone(), conts.clone()).unwrap();
+        // alternations prefer earlier alternatives, so longer options go first
+        let mut sorted = options.clone();
+        sorted.sort_by_key(|o| std::cmp::Reverse(o.len()));
+        let pattern = sorted
+            .iter()
+            .map(|o| regex::escape(o))
+            .collect::<Vec<_>>()
+            .join("|");
+        let re = RegularExpressionConstraint::new(&pattern, conts).unwrap();
+        for option in options.iter().step_by(97) {
+            let bytes = option.as_bytes();
+            for len in 0..=bytes.len() {
+                let c = choice.get_state(&bytes[..len]).unwrap();
+                let r = re.get_state(&bytes[..len]).unwrap();
+                assert_eq!(
+                    choice.get_valid_continuations(&c),
+                    re.get_valid_continuations(&r)
+                );
+                assert_eq!(choice.is_match_state(&c), re.is_match_state(&r));
+            }
+        }
+    }
+}
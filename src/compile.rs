@@ -0,0 +1,101 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use crate::limits::CompileLimitError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilePhase {
+    Grammar,
+    Lexer,
+    Table,
+    // only reported by BackgroundCompile, once the compiled value exists
+    Done,
+}
+
+impl Display for CompilePhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phase = match self {
+            CompilePhase::Grammar => "grammar",
+            CompilePhase::Lexer => "lexer",
+            CompilePhase::Table => "table",
+            CompilePhase::Done => "done",
+        };
+        write!(f, "{phase}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileProgress {
+    pub phase: CompilePhase,
+    // number of lexer dfas built during the lexer phase; during the table
+    // phase the number of LR(0) states counted so far, which stays at their
+    // total while the table is built, and the number of LR states once the
+    // table is finished
+    pub done: usize,
+    // none if not known in advance, e.g. the number
+    // of LR states while the table is being built
+    pub total: Option<usize>,
+}
+
+impl CompileProgress {
+    pub(crate) fn new(phase: CompilePhase, done: usize, total: Option<usize>) -> Self {
+        Self { phase, done, total }
+    }
+}
+
+pub type CompileError = Box<dyn Error + Send + Sync>;
+
+// a compilation running on a separate thread, which can be polled for
+// its progress and whether it has finished without blocking
+pub struct BackgroundCompile<T> {
+    progress: Arc<Mutex<CompileProgress>>,
+    handle: JoinHandle<Result<T, CompileError>>,
+}
+
+impl<T: Send + 'static> BackgroundCompile<T> {
+    pub fn spawn<F>(compile: F) -> Self
+    where
+        F: FnOnce(&mut dyn FnMut(CompileProgress)) -> Result<T, Box<dyn Error>> + Send + 'static,
+    {
+        let progress = Arc::new(Mutex::new(CompileProgress::new(
+            CompilePhase::Grammar,
+            0,
+            None,
+        )));
+        let thread_progress = progress.clone();
+        let handle = thread::spawn(move || {
+            let mut report = |p: CompileProgress| {
+                *thread_progress.lock().expect("error locking progress") = p;
+            };
+            // compilation errors are not thread safe in general, keep
+            // limit errors structured and convert all others to strings
+            let value =
+                compile(&mut report).map_err(|e| match e.downcast::<CompileLimitError>() {
+                    Ok(e) => e as CompileError,
+                    Err(e) => e.to_string().into(),
+                })?;
+            let mut progress = thread_progress.lock().expect("error locking progress");
+            progress.phase = CompilePhase::Done;
+            Ok(value)
+        });
+        Self { progress, handle }
+    }
+
+    pub fn progress(&self) -> CompileProgress {
+        *self.progress.lock().expect("error locking progress")
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    pub fn join(self) -> Result<T, CompileError> {
+        self.handle
+            .join()
+            .unwrap_or_else(|_| Err("compilation thread panicked".into()))
+    }
+}
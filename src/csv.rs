@@ -0,0 +1,409 @@
+use std::{error::Error, str::FromStr};
+
+use itertools::Itertools;
+use regex::escape;
+
+use crate::{utils::integer_range_pattern, RegularExpressionConstraint};
+
+#[derive(Debug, Clone)]
+pub enum CsvColumn {
+    Regex(String),
+    Enum(Vec<String>),
+    Integer { min: i64, max: i64 },
+    // free text, quoted fields may contain the delimiter, line breaks and
+    // the quote character doubled, unquoted fields none of them
+    Text,
+}
+
+impl CsvColumn {
+    fn pattern(&self) -> Result<String, Box<dyn Error>> {
+        match self {
+            CsvColumn::Regex(pattern) => Ok(format!("(?:{pattern})")),
+            CsvColumn::Enum(options) => {
+                if options.is_empty() {
+                    return Err("enum column needs at least one option".into());
+                }
+                // longer options first, otherwise leftmost-first matching
+                // would cut off options that have a shorter option as prefix
+                Ok(format!(
+                    "(?:{})",
+                    options
+                        .iter()
+                        .sorted_by_key(|option| std::cmp::Reverse(option.len()))
+                        .map(|option| escape(option))
+                        .join("|")
+                ))
+            }
+            CsvColumn::Integer { min, max } => integer_range_pattern(*min, *max),
+            // depends on the quoting, see CsvConstraintBuilder::field_pattern
+            CsvColumn::Text => unreachable!("text columns have no fixed pattern"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsvQuoting {
+    // fields are never quoted
+    Never,
+    // fields may optionally be surrounded by the quote character
+    Optional,
+    // all fields must be surrounded by the quote character
+    Always,
+}
+
+impl FromStr for CsvQuoting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(CsvQuoting::Never),
+            "optional" => Ok(CsvQuoting::Optional),
+            "always" => Ok(CsvQuoting::Always),
+            _ => Err(format!(
+                "unknown csv quoting {s}, expected never, optional or always"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvConstraintBuilder {
+    columns: Vec<CsvColumn>,
+    header: Option<Vec<String>>,
+    delimiter: char,
+    quote: char,
+    quoting: CsvQuoting,
+    line_terminator: String,
+    min_rows: usize,
+    max_rows: Option<usize>,
+}
+
+impl Default for CsvConstraintBuilder {
+    fn default() -> Self {
+        Self {
+            columns: vec![],
+            header: None,
+            delimiter: ',',
+            quote: '"',
+            quoting: CsvQuoting::Never,
+            line_terminator: "\n".to_string(),
+            min_rows: 1,
+            max_rows: None,
+        }
+    }
+}
+
+impl CsvConstraintBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tsv() -> Self {
+        Self::default().delimiter('\t')
+    }
+
+    pub fn column(mut self, column: CsvColumn) -> Self {
+        self.columns.push(column);
+        self
+    }
+
+    pub fn columns(mut self, columns: impl IntoIterator<Item = CsvColumn>) -> Self {
+        self.columns.extend(columns);
+        self
+    }
+
+    // column names written as a header row before the data rows
+    pub fn header(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.header = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn quoting(mut self, quoting: CsvQuoting, quote: char) -> Self {
+        self.quoting = quoting;
+        self.quote = quote;
+        self
+    }
+
+    pub fn line_terminator(mut self, line_terminator: impl Into<String>) -> Self {
+        self.line_terminator = line_terminator.into();
+        self
+    }
+
+    pub fn rows(mut self, min: usize, max: Option<usize>) -> Self {
+        self.min_rows = min;
+        self.max_rows = max;
+        self
+    }
+
+    // characters that end an unquoted field
+    fn special_chars(&self) -> String {
+        let mut chars = vec![self.delimiter, '\r', '\n'];
+        if self.quoting != CsvQuoting::Never {
+            chars.push(self.quote);
+        }
+        chars.extend(self.line_terminator.chars());
+        chars.into_iter().unique().collect()
+    }
+
+    fn text_pattern(&self) -> String {
+        let unquoted = format!(
+            "[^{}]*",
+            self.special_chars()
+                .chars()
+                .map(|c| escape(&c.to_string()))
+                .join("")
+        );
+        let quote = escape(&self.quote.to_string());
+        let quoted = format!("{quote}(?:[^{quote}]|{quote}{quote})*{quote}");
+        // quoted first, the unquoted field can be empty and would take
+        // priority over the quoted one with leftmost first semantics
+        match self.quoting {
+            CsvQuoting::Never => unquoted,
+            CsvQuoting::Optional => format!("(?:{quoted}|{unquoted})"),
+            CsvQuoting::Always => quoted,
+        }
+    }
+
+    // a header name is matched literally, it is quoted if the quoting
+    // requires it or if it contains special characters
+    fn name_pattern(&self, name: &str) -> Result<String, Box<dyn Error>> {
+        let special = self.special_chars();
+        let needs_quotes = name.chars().any(|c| special.contains(c));
+        let quote = self.quote.to_string();
+        let quoted = escape(&format!(
+            "{quote}{}{quote}",
+            name.replace(&quote, &quote.repeat(2))
+        ));
+        Ok(match self.quoting {
+            CsvQuoting::Never if needs_quotes => {
+                return Err(format!(
+                    "column name '{name}' contains special characters and needs quoting"
+                )
+                .into())
+            }
+            CsvQuoting::Never => escape(name),
+            CsvQuoting::Optional if needs_quotes => quoted,
+            CsvQuoting::Optional => format!("(?:{}|{quoted})", escape(name)),
+            CsvQuoting::Always => quoted,
+        })
+    }
+
+    fn header_pattern(&self, names: &[String]) -> Result<String, Box<dyn Error>> {
+        if names.len() != self.columns.len() {
+            return Err(format!(
+                "csv header has {} names, but there are {} columns",
+                names.len(),
+                self.columns.len()
+            )
+            .into());
+        }
+        let names = names
+            .iter()
+            .map(|name| self.name_pattern(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(names.join(&escape(&self.delimiter.to_string())))
+    }
+
+    fn field_pattern(&self, column: &CsvColumn) -> Result<String, Box<dyn Error>> {
+        if matches!(column, CsvColumn::Text) {
+            return Ok(self.text_pattern());
+        }
+        let pattern = column.pattern()?;
+        let quote = escape(&self.quote.to_string());
+        Ok(match self.quoting {
+            CsvQuoting::Never => pattern,
+            CsvQuoting::Optional => format!("(?:{quote}{pattern}{quote}|{pattern})"),
+            CsvQuoting::Always => format!("{quote}{pattern}{quote}"),
+        })
+    }
+
+    pub(crate) fn row_pattern(&self) -> Result<String, Box<dyn Error>> {
+        if self.columns.is_empty() {
+            return Err("csv constraint needs at least one column".into());
+        }
+        let fields = self
+            .columns
+            .iter()
+            .map(|column| self.field_pattern(column))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(fields.join(&escape(&self.delimiter.to_string())))
+    }
+
+    pub fn pattern(&self) -> Result<String, Box<dyn Error>> {
+        if self.max_rows.is_some_and(|max| max < self.min_rows.max(1)) {
+            return Err(format!(
+                "invalid row bounds: min {} and max {:?}",
+                self.min_rows, self.max_rows
+            )
+            .into());
+        }
+        let row = self.row_pattern()?;
+        let terminator = escape(&self.line_terminator);
+        // rows are separated by the line terminator, a trailing
+        // terminator after the last row is allowed
+        let repeat = match self.max_rows {
+            Some(max) => format!("{{{},{}}}", self.min_rows.saturating_sub(1), max - 1),
+            None => format!("{{{},}}", self.min_rows.saturating_sub(1)),
+        };
+        let rows = format!("(?:{row})(?:{terminator}(?:{row})){repeat}(?:{terminator})?");
+        let Some(names) = &self.header else {
+            return Ok(if self.min_rows == 0 {
+                format!("(?:{rows})?")
+            } else {
+                rows
+            });
+        };
+        // the header is always there, even without any data rows
+        let header = self.header_pattern(names)?;
+        Ok(if self.min_rows == 0 {
+            format!("{header}(?:{terminator}(?:{rows})?)?")
+        } else {
+            format!("{header}{terminator}{rows}")
+        })
+    }
+
+    pub fn build(
+        &self,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<RegularExpressionConstraint, Box<dyn Error>> {
+        RegularExpressionConstraint::new(&self.pattern()?, continuations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Constraint;
+
+    fn byte_continuations() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    fn is_match(constraint: &RegularExpressionConstraint, text: &str) -> bool {
+        constraint
+            .get_state(text.as_bytes())
+            .is_some_and(|state| constraint.is_match_state(&state))
+    }
+
+    #[test]
+    fn test_csv_builder() {
+        let csv = CsvConstraintBuilder::new()
+            .column(CsvColumn::Regex("[a-z]+".to_string()))
+            .column(CsvColumn::Enum(vec![
+                "yes".to_string(),
+                "no".to_string(),
+                "none".to_string(),
+            ]))
+            .column(CsvColumn::Integer { min: 0, max: 120 })
+            .rows(1, Some(2))
+            .build(byte_continuations())
+            .unwrap();
+        assert!(is_match(&csv, "alice,yes,42"));
+        assert!(is_match(&csv, "alice,yes,42\n"));
+        assert!(is_match(&csv, "alice,yes,42\nbob,no,120"));
+        assert!(!is_match(&csv, "alice,yes,121"));
+        assert!(is_match(&csv, "alice,none,7"));
+        assert!(!is_match(&csv, "alice,maybe,1"));
+        assert!(!is_match(&csv, "alice,yes"));
+        assert!(!is_match(&csv, ""));
+        assert!(csv.get_state(b"alice,yes,42\nbob,no,120\ncarol").is_none());
+
+        let tsv = CsvConstraintBuilder::tsv()
+            .column(CsvColumn::Regex("[a-z ]+".to_string()))
+            .column(CsvColumn::Integer { min: -5, max: 5 })
+            .quoting(CsvQuoting::Always, '\'')
+            .rows(0, None)
+            .build(byte_continuations())
+            .unwrap();
+        assert!(is_match(&tsv, ""));
+        assert!(is_match(&tsv, "'a b'\t'-5'\n'c'\t'0'\n'd'\t'5'"));
+        assert!(!is_match(&tsv, "a b\t-5"));
+
+        let optional = CsvConstraintBuilder::new()
+            .column(CsvColumn::Regex("[a-z,]+".to_string()))
+            .column(CsvColumn::Regex("[a-z]+".to_string()))
+            .quoting(CsvQuoting::Optional, '"')
+            .build(byte_continuations())
+            .unwrap();
+        assert!(is_match(&optional, "\"a,b\",c"));
+        assert!(is_match(&optional, "ab,c"));
+
+        assert!(CsvConstraintBuilder::new().pattern().is_err());
+        assert!(CsvConstraintBuilder::new()
+            .column(CsvColumn::Enum(vec![]))
+            .pattern()
+            .is_err());
+        assert!(CsvConstraintBuilder::new()
+            .column(CsvColumn::Regex("a".to_string()))
+            .rows(3, Some(2))
+            .pattern()
+            .is_err());
+    }
+
+    #[test]
+    fn test_csv_header_and_text() {
+        let csv = CsvConstraintBuilder::new()
+            .column(CsvColumn::Text)
+            .column(CsvColumn::Integer { min: 0, max: 9 })
+            .header(["name", "rating, 0-9"])
+            .quoting(CsvQuoting::Optional, '"')
+            .rows(0, Some(2))
+            .build(byte_continuations())
+            .unwrap();
+        assert!(is_match(&csv, "name,\"rating, 0-9\""));
+        assert!(is_match(&csv, "\"name\",\"rating, 0-9\"\n"));
+        assert!(is_match(&csv, "name,\"rating, 0-9\"\nalice,5\n,7"));
+        assert!(is_match(
+            &csv,
+            "name,\"rating, 0-9\"\n\"a \"\"b\"\",\nc\",5"
+        ));
+        assert!(!is_match(&csv, "name,rating, 0-9"));
+        assert!(!is_match(&csv, "alice,5"));
+        assert!(!is_match(&csv, "name,\"rating, 0-9\"\na\"b,5"));
+        assert!(!is_match(&csv, "name,\"rating, 0-9\"\na,b,5"));
+        assert!(!is_match(&csv, "name,\"rating, 0-9\"\n\"a\"b\",5"));
+        assert!(!is_match(&csv, "name,\"rating, 0-9\"\na,1\nb,2\nc,3"));
+
+        // quoted text in the last column, also after an empty field
+        let last = CsvConstraintBuilder::new()
+            .column(CsvColumn::Regex("[a-z]*".to_string()))
+            .column(CsvColumn::Text)
+            .quoting(CsvQuoting::Optional, '"')
+            .rows(1, None)
+            .build(byte_continuations())
+            .unwrap();
+        assert!(is_match(&last, "a,x"));
+        assert!(is_match(&last, "a,\"x\""));
+        assert!(is_match(&last, "a,\"x, \"\"y\"\"\"\nb,"));
+        assert!(is_match(&last, "\"\",\"x\""));
+        assert!(is_match(&last, ","));
+        assert!(!is_match(&last, "a,\"x"));
+        assert!(!is_match(&last, "a,x\"y\""));
+
+        let always = CsvConstraintBuilder::tsv()
+            .column(CsvColumn::Text)
+            .header(["say \"hi\""])
+            .quoting(CsvQuoting::Always, '"')
+            .build(byte_continuations())
+            .unwrap();
+        assert!(is_match(&always, "\"say \"\"hi\"\"\"\n\"a\tb\""));
+        assert!(!is_match(&always, "\"say \"\"hi\"\"\"\na"));
+
+        let never = CsvConstraintBuilder::new()
+            .column(CsvColumn::Text)
+            .column(CsvColumn::Text)
+            .header(["a", "b"]);
+        let constraint = never.build(byte_continuations()).unwrap();
+        assert!(is_match(&constraint, "a,b\nx \"y\",z"));
+        assert!(!is_match(&constraint, "a,b\nx,y,z"));
+        assert!(never.clone().header(["a"]).pattern().is_err());
+        assert!(never.header(["a", "b,c"]).pattern().is_err());
+        assert_eq!("optional".parse(), Ok(CsvQuoting::Optional));
+        assert!("sometimes".parse::<CsvQuoting>().is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::error::Error;
+
+use itertools::Itertools;
+use regex::escape;
+
+use crate::utils::padded_range_pattern;
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+const LONG_MONTHS: &[u64] = &[1, 3, 5, 7, 8, 10, 12];
+const SHORT_MONTHS: &[u64] = &[4, 6, 9, 11];
+const ALL_MONTHS: &[u64] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+// years divisible by 4 but not by 100, or divisible by 400
+const LEAP_YEAR: &str =
+    "(?:[0-9]{2}(?:0[48]|[2468][048]|[13579][26])|(?:[02468][048]|[13579][26])00)";
+// two digit years are taken to be in 2000 to 2099, so 00 is a leap year
+const LEAP_SHORT_YEAR: &str = "(?:[02468][048]|[13579][26])";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Literal(char),
+    Year,
+    ShortYear,
+    Month,
+    MonthName { short: bool },
+    Day { pad: char },
+    DayOfYear,
+    Hour,
+    Hour12,
+    Minute,
+    Second,
+    Fraction,
+    AmPm,
+    Weekday { short: bool },
+    WeekdayNumber { sunday: u64 },
+    Offset { colon: bool },
+    TimeZone,
+}
+
+fn parse(layout: &str, fields: &mut Vec<Field>) -> Result<(), Box<dyn Error>> {
+    let mut chars = layout.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            fields.push(Field::Literal(c));
+            continue;
+        }
+        let field = match chars.next() {
+            Some('Y') => Field::Year,
+            Some('y') => Field::ShortYear,
+            Some('m') => Field::Month,
+            Some('b' | 'h') => Field::MonthName { short: true },
+            Some('B') => Field::MonthName { short: false },
+            Some('d') => Field::Day { pad: '0' },
+            Some('e') => Field::Day { pad: ' ' },
+            Some('j') => Field::DayOfYear,
+            Some('H') => Field::Hour,
+            Some('I') => Field::Hour12,
+            Some('M') => Field::Minute,
+            Some('S') => Field::Second,
+            Some('f') => Field::Fraction,
+            Some('p') => Field::AmPm,
+            Some('a') => Field::Weekday { short: true },
+            Some('A') => Field::Weekday { short: false },
+            Some('u') => Field::WeekdayNumber { sunday: 7 },
+            Some('w') => Field::WeekdayNumber { sunday: 0 },
+            Some('z') => Field::Offset { colon: false },
+            Some('Z') => Field::TimeZone,
+            Some('n') => Field::Literal('\n'),
+            Some('t') => Field::Literal('\t'),
+            Some('%') => Field::Literal('%'),
+            Some(':') if chars.next() == Some('z') => Field::Offset { colon: true },
+            Some(c @ ('F' | 'T' | 'R' | 'D')) => {
+                let expanded = match c {
+                    'F' => "%Y-%m-%d",
+                    'T' => "%H:%M:%S",
+                    'R' => "%H:%M",
+                    _ => "%m/%d/%y",
+                };
+                parse(expanded, fields)?;
+                continue;
+            }
+            Some(c) => return Err(format!("unsupported strftime directive %{c}").into()),
+            None => return Err("strftime layout ends with an incomplete directive".into()),
+        };
+        fields.push(field);
+    }
+    Ok(())
+}
+
+// the days that are valid depend on the month and year, so layouts with both
+// are split into variants that each fix the possible months, days and whether
+// the year has to be a leap year
+#[derive(Debug, Clone, Copy)]
+struct Variant {
+    months: &'static [u64],
+    days: (u64, u64),
+    days_of_year: (u64, u64),
+    leap: bool,
+}
+
+fn variants(fields: &[Field]) -> Vec<Variant> {
+    let has = |f: fn(&Field) -> bool| fields.iter().any(f);
+    let has_year = has(|f| matches!(f, Field::Year | Field::ShortYear));
+    let has_month = has(|f| matches!(f, Field::Month | Field::MonthName { .. }));
+    let has_day = has(|f| matches!(f, Field::Day { .. }));
+    let month_days = if has_month && has_day {
+        vec![
+            (LONG_MONTHS, (1, 31), false),
+            (SHORT_MONTHS, (1, 30), false),
+            (&[2][..], (1, 28), false),
+            (&[2][..], (29, 29), has_year),
+        ]
+    } else {
+        vec![(ALL_MONTHS, (1, 31), false)]
+    };
+    let days_of_year = if has(|f| *f == Field::DayOfYear) && has_year {
+        vec![((1, 365), false), ((366, 366), true)]
+    } else {
+        vec![((1, 366), false)]
+    };
+    month_days
+        .into_iter()
+        .cartesian_product(days_of_year)
+        .map(|((months, days, leap), (days_of_year, leap_day))| Variant {
+            months,
+            days,
+            days_of_year,
+            leap: leap || leap_day,
+        })
+        .collect()
+}
+
+fn names(names: &[&str], indices: impl IntoIterator<Item = usize>, short: bool) -> String {
+    let names = indices.into_iter().map(|i| {
+        let name = names[i];
+        if short {
+            &name[..3]
+        } else {
+            name
+        }
+    });
+    format!("(?:{})", names.map(escape).join("|"))
+}
+
+fn field_pattern(field: &Field, variant: &Variant) -> String {
+    match field {
+        Field::Literal(c) => escape(&c.to_string()),
+        Field::Year if variant.leap => LEAP_YEAR.to_string(),
+        Field::Year => "[0-9]{4}".to_string(),
+        Field::ShortYear if variant.leap => LEAP_SHORT_YEAR.to_string(),
+        Field::ShortYear => "[0-9]{2}".to_string(),
+        Field::Month => format!(
+            "(?:{})",
+            variant.months.iter().map(|m| format!("{m:02}")).join("|")
+        ),
+        Field::MonthName { short } => names(
+            &MONTHS,
+            variant.months.iter().map(|&m| m as usize - 1),
+            *short,
+        ),
+        Field::Day { pad } => padded_range_pattern(variant.days.0, variant.days.1, 2, *pad),
+        Field::DayOfYear => {
+            padded_range_pattern(variant.days_of_year.0, variant.days_of_year.1, 3, '0')
+        }
+        Field::Hour => padded_range_pattern(0, 23, 2, '0'),
+        Field::Hour12 => padded_range_pattern(1, 12, 2, '0'),
+        Field::Minute => padded_range_pattern(0, 59, 2, '0'),
+        // 60 for leap seconds
+        Field::Second => padded_range_pattern(0, 60, 2, '0'),
+        Field::Fraction => "[0-9]{6}".to_string(),
+        Field::AmPm => "(?:AM|PM)".to_string(),
+        Field::Weekday { short } => names(&WEEKDAYS, 0..7, *short),
+        Field::WeekdayNumber { sunday } => format!("[{}-{}]", sunday.min(&1), sunday.max(&6)),
+        Field::Offset { colon } => format!(
+            "[+-]{}{}[0-5][0-9]",
+            padded_range_pattern(0, 23, 2, '0'),
+            if *colon { ":" } else { "" }
+        ),
+        Field::TimeZone => "[A-Z]+".to_string(),
+    }
+}
+
+// converts a strftime layout like %Y-%m-%dT%H:%M:%SZ into a regular expression
+// matching exactly the valid dates and times in that layout, including leap days;
+// weekday names are not checked against the date
+pub fn strftime_to_regex(layout: &str) -> Result<String, Box<dyn Error>> {
+    let mut fields = vec![];
+    parse(layout, &mut fields)?;
+    let variants = variants(&fields);
+    let patterns: Vec<_> = variants
+        .iter()
+        .map(|variant| {
+            fields
+                .iter()
+                .map(|field| field_pattern(field, variant))
+                .join("")
+        })
+        .collect();
+    if patterns.len() == 1 {
+        return Ok(patterns.into_iter().next().unwrap());
+    }
+    Ok(format!("(?:{})", patterns.join("|")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Constraint, RegularExpressionConstraint};
+
+    fn is_leap(year: u64) -> bool {
+        year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+    }
+
+    fn days_in_month(year: u64, month: u64) -> u64 {
+        match month {
+            2 if is_leap(year) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    fn matcher(layout: &str) -> impl Fn(&str) -> bool {
+        let re = RegularExpressionConstraint::from_strftime(layout, vec![]).unwrap();
+        move |s: &str| {
+            re.get_state(s.as_bytes())
+                .is_some_and(|state| re.is_match_state(&state))
+        }
+    }
+
+    #[test]
+    fn test_strftime_dates() {
+        let is_match = matcher("%Y-%m-%d");
+        let short = matcher("%d.%m.%y");
+        for year in (1896..=2104).chain([0, 400, 1600, 1700, 9999]) {
+            for month in 0..=13 {
+                for day in 0..=32 {
+                    let valid =
+                        (1..=12).contains(&month) && day >= 1 && day <= days_in_month(year, month);
+                    let date = format!("{year:04}-{month:02}-{day:02}");
+                    assert_eq!(is_match(&date), valid, "{date}");
+                    if (2000..2100).contains(&year) {
+                        let date = format!("{day:02}.{month:02}.{:02}", year % 100);
+                        assert_eq!(short(&date), valid, "{date}");
+                    }
+                }
+            }
+        }
+        assert!(!is_match("2024-2-29"));
+        assert!(!is_match("24-02-29"));
+    }
+
+    #[test]
+    fn test_strftime_times() {
+        let is_match = matcher("%Y-%m-%dT%H:%M:%SZ");
+        assert!(is_match("2024-02-29T23:59:60Z"));
+        assert!(is_match("2023-12-31T00:00:00Z"));
+        assert!(!is_match("2023-02-29T12:00:00Z"));
+        assert!(!is_match("2023-01-01T24:00:00Z"));
+        assert!(!is_match("2023-01-01T12:60:00Z"));
+        assert!(!is_match("2023-01-01T12:00:00"));
+
+        let is_match = matcher("%a, %e %b %Y %I:%M %p %:z");
+        assert!(is_match("Thu,  1 Feb 2024 09:30 AM +01:00"));
+        assert!(is_match("Sun, 30 Apr 2023 12:00 PM -11:30"));
+        assert!(!is_match("Sun, 31 Apr 2023 12:00 PM -11:30"));
+        assert!(!is_match("Sun, 01 Apr 2023 12:00 PM -11:30"));
+        assert!(!is_match("Sun,  1 Apr 2023 00:00 PM -11:30"));
+        assert!(!is_match("Sun,  1 Apr 2023 10:00 PM +24:00"));
+
+        let is_match = matcher("%Y/%j %T.%f %%");
+        assert!(is_match("2024/366 10:00:00.000001 %"));
+        assert!(!is_match("2023/366 10:00:00.000001 %"));
+        assert!(!is_match("2023/000 10:00:00.000001 %"));
+        assert!(!is_match("2023/100 10:00:00.0001 %"));
+
+        let is_match = matcher("%B %d");
+        assert!(is_match("February 29"));
+        assert!(!is_match("February 30"));
+        assert!(!is_match("June 31"));
+        assert!(is_match("December 31"));
+
+        assert!(strftime_to_regex("%Q").is_err());
+        assert!(strftime_to_regex("%Y-%").is_err());
+        assert!(strftime_to_regex("%:y").is_err());
+    }
+}
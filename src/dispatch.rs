@@ -0,0 +1,491 @@
+use std::{collections::HashSet, error::Error};
+
+use crate::{
+    memory::MemoryUsage, state_fingerprint, Constraint, DynState, LR1GrammarConstraint,
+    RegularExpressionConstraint,
+};
+
+// the dyn byte constraint trait is not imported, its methods would be
+// ambiguous with those of the constraint traits for concrete constraints
+type Part = Box<dyn crate::DynByteConstraint>;
+
+struct Route {
+    name: String,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    constraint: Part,
+}
+
+// switches between constraints mid-output, e.g. for tool calling: free text,
+// or text under a base constraint, until the start sentinel of a route like
+// <tool_call> is emitted, then the output of the route's constraint, e.g.
+// json, until its end sentinel like </tool_call>, then text again; a start
+// sentinel always switches to its route if the text before it is a match
+// of the base constraint, an end sentinel always switches back
+pub struct DispatchConstraint {
+    base: Option<Part>,
+    routes: Vec<Route>,
+    continuations: Vec<Vec<u8>>,
+    // continuations containing the first byte of a sentinel,
+    // the only ones that can switch between text and a route
+    crossing: Vec<usize>,
+    is_crossing: Vec<bool>,
+    // memory of the parts, measured before their types are erased
+    memory: usize,
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+enum Mode {
+    // text with the state of the base constraint, none if unconstrained
+    Text(Option<DynState>),
+    // number of bytes of the start sentinel of a route seen so far
+    Opening(usize, usize),
+    Call(usize, DynState),
+    // number of bytes of the end sentinel of a route seen so far
+    Closing(usize, usize),
+}
+
+// modes the output can be in; more than one if it is ambiguous whether a
+// sentinel is being emitted, e.g. after a < in free text
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct DispatchState {
+    alternatives: Vec<Mode>,
+}
+
+impl DispatchConstraint {
+    // tools are given as (name, start sentinel, end sentinel, grammar, lexer),
+    // the base as a regular expression, unconstrained text if none
+    pub fn new(
+        tools: &[(&str, &str, &str, &str, &str)],
+        base: Option<&str>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut memory = 0;
+        let base = base
+            .map(|pattern| {
+                let re = RegularExpressionConstraint::new(pattern, continuations.clone())
+                    .map_err(|e| format!("invalid base regex {pattern}: {e}"))?;
+                memory += re.memory_usage();
+                Ok::<Part, Box<dyn Error>>(Box::new(re))
+            })
+            .transpose()?;
+        let mut routes = vec![];
+        for &(name, start, end, grammar, lexer) in tools {
+            let lr1 = LR1GrammarConstraint::new(grammar, lexer, continuations.clone())
+                .map_err(|e| format!("invalid grammar for tool {name}: {e}"))?;
+            memory += lr1.memory_usage();
+            routes.push((
+                name.to_string(),
+                start.to_string(),
+                end.to_string(),
+                Box::new(lr1) as Part,
+            ));
+        }
+        let mut dispatch = Self::from_parts(base, routes)?;
+        dispatch.memory = memory;
+        Ok(dispatch)
+    }
+
+    // routes are given as (name, start sentinel, end sentinel, constraint),
+    // all constraints need the same continuations; the memory of constraints
+    // given this way is not accounted for
+    pub fn from_parts(
+        base: Option<Box<dyn crate::DynByteConstraint>>,
+        routes: Vec<(String, String, String, Box<dyn crate::DynByteConstraint>)>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let Some((.., first)) = routes.first() else {
+            return Err("dispatch needs at least one route".into());
+        };
+        let continuations = first.continuations().to_vec();
+        if base
+            .as_ref()
+            .is_some_and(|base| base.continuations() != continuations)
+        {
+            return Err("continuations of the base differ from those of the routes".into());
+        }
+        let mut names = HashSet::new();
+        let mut firsts = [false; 256];
+        let mut parsed = vec![];
+        for (name, start, end, constraint) in routes {
+            if !names.insert(name.clone()) {
+                return Err(format!("duplicate route name {name}").into());
+            }
+            if start.is_empty() || end.is_empty() {
+                return Err(format!("sentinels of route {name} must not be empty").into());
+            }
+            if constraint.continuations() != continuations {
+                return Err(format!("continuations of route {name} differ from the others").into());
+            }
+            firsts[start.as_bytes()[0] as usize] = true;
+            firsts[end.as_bytes()[0] as usize] = true;
+            parsed.push(Route {
+                name,
+                start: start.into_bytes(),
+                end: end.into_bytes(),
+                constraint,
+            });
+        }
+        let is_crossing: Vec<_> = continuations
+            .iter()
+            .map(|cont| cont.iter().any(|&b| firsts[b as usize]))
+            .collect();
+        let crossing = is_crossing
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &crossing)| crossing.then_some(i))
+            .collect();
+        Ok(Self {
+            base,
+            routes: parsed,
+            continuations,
+            crossing,
+            is_crossing,
+            memory: 0,
+        })
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|route| route.name.as_str())
+    }
+
+    // name of the route whose constraint the output is in, none in text or
+    // while it is still open whether a start sentinel is being emitted
+    pub fn active(&self, state: &DispatchState) -> Option<&str> {
+        state.alternatives.iter().find_map(|mode| match mode {
+            Mode::Call(route, _) | Mode::Closing(route, _) => {
+                Some(self.routes[*route].name.as_str())
+            }
+            _ => None,
+        })
+    }
+
+    fn text_start(&self) -> Mode {
+        Mode::Text(self.base.as_ref().map(|base| base.get_start_state()))
+    }
+
+    fn text_match(&self, state: &Option<DynState>) -> bool {
+        match (&self.base, state) {
+            (Some(base), Some(state)) => base.is_match_state(state),
+            _ => true,
+        }
+    }
+
+    fn advance(&self, state: &DispatchState, bytes: &[u8]) -> Option<DispatchState> {
+        let mut alternatives = state.alternatives.clone();
+        for &b in bytes {
+            let mut next = vec![];
+            // modes entered by completing a sentinel with this byte
+            let mut opened = vec![];
+            let mut closed = false;
+            for mode in &alternatives {
+                match mode {
+                    Mode::Text(state) => {
+                        match (&self.base, state) {
+                            (Some(base), Some(state)) => {
+                                if let Some(state) = base.get_next_state_with_bytes(state, &[b]) {
+                                    next.push(Mode::Text(Some(state)));
+                                }
+                            }
+                            _ => next.push(Mode::Text(None)),
+                        }
+                        if self.text_match(state) {
+                            for (i, route) in self.routes.iter().enumerate() {
+                                if route.start[0] == b {
+                                    self.open(i, 1, &mut next, &mut opened);
+                                }
+                            }
+                        }
+                    }
+                    &Mode::Opening(route, pos) => {
+                        if self.routes[route].start[pos] == b {
+                            self.open(route, pos + 1, &mut next, &mut opened);
+                        }
+                    }
+                    Mode::Call(route, state) => {
+                        let route = *route;
+                        let constraint = &self.routes[route].constraint;
+                        if let Some(state) = constraint.get_next_state_with_bytes(state, &[b]) {
+                            next.push(Mode::Call(route, state));
+                        }
+                        if constraint.is_match_state(state) && self.routes[route].end[0] == b {
+                            closed |= self.close(route, 1, &mut next);
+                        }
+                    }
+                    &Mode::Closing(route, pos) => {
+                        if self.routes[route].end[pos] == b {
+                            closed |= self.close(route, pos + 1, &mut next);
+                        }
+                    }
+                }
+            }
+            // a completed sentinel cannot be part of text or a call
+            if !opened.is_empty() {
+                next.retain(|mode| !matches!(mode, Mode::Text(_)));
+            }
+            if closed {
+                next.retain(|mode| !matches!(mode, Mode::Call(..)));
+                next.push(self.text_start());
+            }
+            next.extend(opened);
+            let mut unique = Vec::with_capacity(next.len());
+            for mode in next {
+                if !unique.contains(&mode) {
+                    unique.push(mode);
+                }
+            }
+            if unique.is_empty() {
+                return None;
+            }
+            alternatives = unique;
+        }
+        // canonical order, so equal sets of alternatives are equal states
+        alternatives.sort_by_cached_key(state_fingerprint);
+        Some(DispatchState { alternatives })
+    }
+
+    fn open(&self, route: usize, pos: usize, next: &mut Vec<Mode>, opened: &mut Vec<Mode>) {
+        let r = &self.routes[route];
+        if pos == r.start.len() {
+            opened.push(Mode::Call(route, r.constraint.get_start_state()));
+        } else {
+            next.push(Mode::Opening(route, pos));
+        }
+    }
+
+    // returns whether the end sentinel is complete
+    fn close(&self, route: usize, pos: usize, next: &mut Vec<Mode>) -> bool {
+        if pos == self.routes[route].end.len() {
+            return true;
+        }
+        next.push(Mode::Closing(route, pos));
+        false
+    }
+}
+
+impl MemoryUsage for DispatchConstraint {
+    fn memory_usage(&self) -> usize {
+        self.memory
+            + self.continuations.iter().map(Vec::len).sum::<usize>()
+            + self.crossing.len() * size_of::<usize>()
+            + self.is_crossing.len()
+            + size_of::<Self>()
+    }
+}
+
+impl Constraint for DispatchConstraint {
+    type State = DispatchState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.advance(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        DispatchState {
+            alternatives: vec![self.text_start()],
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        state.alternatives.iter().any(|mode| match mode {
+            Mode::Text(state) => self.text_match(state),
+            _ => false,
+        })
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        // within a sentinel every continuation has to be checked
+        if state
+            .alternatives
+            .iter()
+            .any(|mode| matches!(mode, Mode::Opening(..) | Mode::Closing(..)))
+        {
+            return (0..self.continuations.len())
+                .filter(|&i| self.advance(state, &self.continuations[i]).is_some())
+                .collect();
+        }
+        // continuations without a sentinel byte stay in the mode of an
+        // alternative, so the constraint of that mode decides
+        let mut conts = vec![];
+        for mode in &state.alternatives {
+            match mode {
+                Mode::Text(None) => conts.extend(0..self.continuations.len()),
+                Mode::Text(Some(state)) => {
+                    if let Some(base) = &self.base {
+                        conts.extend(base.get_valid_continuations(state));
+                    }
+                }
+                Mode::Call(route, state) => conts.extend(
+                    self.routes[*route]
+                        .constraint
+                        .get_valid_continuations(state),
+                ),
+                Mode::Opening(..) | Mode::Closing(..) => unreachable!(),
+            }
+        }
+        conts.retain(|&i| !self.is_crossing[i]);
+        conts.extend(
+            self.crossing
+                .iter()
+                .copied()
+                .filter(|&i| self.advance(state, &self.continuations[i]).is_some()),
+        );
+        conts.sort_unstable();
+        conts.dedup();
+        conts
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.advance(state, self.continuations.get(continuation)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_dispatch() {
+        let conts: Vec<_> = [
+            "Hi",
+            " ",
+            "<tool_call>",
+            "{",
+            "}",
+            "\"a\"",
+            ":",
+            "1",
+            "</tool_call>",
+            "<",
+            "tool",
+            "_call>",
+            "}</tool_call>",
+            "</",
+            "x",
+            "1<",
+        ]
+        .iter()
+        .map(|c| c.as_bytes().to_vec())
+        .collect();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let grammar = fs::read_to_string(format!("{dir}/grammars/json/json.y")).unwrap();
+        let lexer = fs::read_to_string(format!("{dir}/grammars/json/json.l")).unwrap();
+        let tools = [(
+            "json",
+            "<tool_call>",
+            "</tool_call>",
+            grammar.as_str(),
+            lexer.as_str(),
+        )];
+        let dispatch = DispatchConstraint::new(&tools, None, conts.clone()).unwrap();
+        assert_eq!(dispatch.names().collect::<Vec<_>>(), ["json"]);
+
+        // free text, but a sentinel has to be followed by json
+        let state = dispatch.get_start_state();
+        assert!(dispatch.is_match_state(&state));
+        assert_eq!(
+            dispatch.get_valid_continuations(&state),
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        let state = dispatch.get_state(b"Hi <tool_call>").unwrap();
+        assert_eq!(dispatch.active(&state), Some("json"));
+        assert!(!dispatch.is_match_state(&state));
+        assert_eq!(dispatch.get_valid_continuations(&state), [1, 3, 5, 7, 15]);
+        // a split sentinel switches as well
+        let split = dispatch.get_state(b"Hi <").unwrap();
+        assert_eq!(dispatch.active(&split), None);
+        let split = dispatch.get_next_state(&split, 10).unwrap();
+        let split = dispatch.get_next_state(&split, 11).unwrap();
+        assert_eq!(split, state);
+
+        let state = dispatch.get_state(b"Hi <tool_call>{\"a\":1").unwrap();
+        assert_eq!(dispatch.get_valid_continuations(&state), [1, 4, 7, 12]);
+        let state = dispatch.get_next_state(&state, 12).unwrap();
+        assert_eq!(dispatch.active(&state), None);
+        assert!(dispatch.is_match_state(&state));
+        // and back to free text, where another call can follow
+        assert!(dispatch.check(b"Hi <tool_call>{\"a\":1}</tool_call>x<tool_call>1</tool_call>"));
+        assert!(dispatch.check(b"<tool_call> 1</tool_call>Hi"));
+        assert!(!dispatch.check(b"Hi <tool_call>{\"a\":1}"));
+        assert!(!dispatch.check(b"Hi <tool_call>x"));
+        assert!(dispatch.get_state(b"<tool_call>1 x").is_none());
+        assert!(dispatch.get_state(b"<tool_call></tool_call>").is_none());
+        // an end sentinel outside of a call is just text
+        assert!(dispatch.check(b"</tool_call>"));
+
+        // text before a call has to match the base
+        let dispatch = DispatchConstraint::new(&tools, Some("[A-Za-z ]*"), conts.clone()).unwrap();
+        let state = dispatch.get_state(b"Hi ").unwrap();
+        assert_eq!(
+            dispatch.get_valid_continuations(&state),
+            [0, 1, 2, 9, 10, 14]
+        );
+        assert!(dispatch.check(b"Hi <tool_call>1</tool_call> Hi"));
+        assert!(!dispatch.check(b"Hi 1"));
+        assert!(dispatch.get_state(b"Hi </").is_none());
+
+        assert!(DispatchConstraint::new(&[], None, conts.clone()).is_err());
+        let empty = [("json", "", "</tool_call>", grammar.as_str(), lexer.as_str())];
+        assert!(DispatchConstraint::new(&empty, None, conts.clone()).is_err());
+        let twice = [tools[0], tools[0]];
+        assert!(DispatchConstraint::new(&twice, None, conts).is_err());
+    }
+
+    #[test]
+    fn test_dispatch_routes() {
+        let conts: Vec<_> = ["a", "b", "[", "]", "(", ")", "[a]", "(b)"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let a = RegularExpressionConstraint::new("a+", conts.clone()).unwrap();
+        let b = RegularExpressionConstraint::new("b+", conts.clone()).unwrap();
+        let dispatch = DispatchConstraint::from_parts(
+            None,
+            vec![
+                (
+                    "a".to_string(),
+                    "[".to_string(),
+                    "]".to_string(),
+                    Box::new(a),
+                ),
+                (
+                    "b".to_string(),
+                    "(".to_string(),
+                    ")".to_string(),
+                    Box::new(b),
+                ),
+            ],
+        )
+        .unwrap();
+        let state = dispatch.get_state(b"ab(").unwrap();
+        assert_eq!(dispatch.active(&state), Some("b"));
+        assert_eq!(dispatch.get_valid_continuations(&state), [1]);
+        let state = dispatch.get_state(b"ab(b").unwrap();
+        assert_eq!(dispatch.get_valid_continuations(&state), [1, 5]);
+        assert!(dispatch.check(b"[a]b(b)a[aa](bb)"));
+        assert!(!dispatch.check(b"[b]"));
+        assert!(!dispatch.check(b"(a)"));
+        assert!(!dispatch.check(b"[]"));
+
+        let other = RegularExpressionConstraint::new("a", vec![b"a".to_vec()]).unwrap();
+        assert!(DispatchConstraint::from_parts(
+            Some(Box::new(other)),
+            vec![(
+                "a".to_string(),
+                "[".to_string(),
+                "]".to_string(),
+                Box::new(RegularExpressionConstraint::new("a", conts).unwrap())
+            )],
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashSet, VecDeque},
+    error::Error,
+    hash::Hash,
+};
+
+use regex_automata::util::primitives::StateID;
+
+use crate::{
+    utils::{preferred_bytes, PrefixDFA},
+    Constraint,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Distinction {
+    // shortest input matched by the left but not by the right side
+    pub left_only: Option<Vec<u8>>,
+    // shortest input matched by the right but not by the left side
+    pub right_only: Option<Vec<u8>>,
+}
+
+impl Distinction {
+    pub fn is_empty(&self) -> bool {
+        self.left_only.is_none() && self.right_only.is_none()
+    }
+
+    fn record(&mut self, left: bool, right: bool, input: impl Fn() -> Vec<u8>) {
+        if left && !right && self.left_only.is_none() {
+            self.left_only = Some(input());
+        } else if right && !left && self.right_only.is_none() {
+            self.right_only = Some(input());
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.left_only.is_some() && self.right_only.is_some()
+    }
+}
+
+fn path<T: Copy>(previous: &[(Option<usize>, T)], mut node: usize) -> Vec<T> {
+    let mut path = vec![];
+    while let (Some(parent), item) = previous[node] {
+        path.push(item);
+        node = parent;
+    }
+    path.reverse();
+    path
+}
+
+// breadth first search over the product automaton of two dfas, visit is called
+// with whether each side matches and the input leading there, and stops the
+// search by returning true
+fn search_product(
+    left: &PrefixDFA,
+    right: &PrefixDFA,
+    mut visit: impl FnMut(bool, bool, &dyn Fn() -> Vec<u8>) -> bool,
+) {
+    let step = |dfa: &PrefixDFA, state: Option<StateID>, b: u8| {
+        state
+            .and_then(|state| dfa.step(state, b))
+            .filter(|&next| dfa.is_valid(next))
+    };
+    let matches =
+        |dfa: &PrefixDFA, state: Option<StateID>| state.is_some_and(|s| dfa.is_eoi_match(s));
+
+    let preference = preferred_bytes();
+    let start = (Some(left.get_start_state()), Some(right.get_start_state()));
+    let mut nodes = vec![(None, 0)];
+    let mut seen = HashSet::from([start]);
+    let mut queue = VecDeque::from([(start, 0)]);
+    while let Some(((l, r), node)) = queue.pop_front() {
+        if visit(matches(left, l), matches(right, r), &|| path(&nodes, node)) {
+            break;
+        }
+        for &b in &preference {
+            let next = (step(left, l, b), step(right, r, b));
+            if next == (None, None) || !seen.insert(next) {
+                continue;
+            }
+            nodes.push((Some(node), b));
+            queue.push_back((next, nodes.len() - 1));
+        }
+    }
+}
+
+// shortest inputs matched by exactly one of two regular expressions, found by
+// a breadth first search over their product automaton; both sides are empty
+// if and only if the regular expressions are equivalent
+pub fn distinguish_regex(left: &str, right: &str) -> Result<Distinction, Box<dyn Error>> {
+    let left = PrefixDFA::new(left)?;
+    let right = PrefixDFA::new(right)?;
+    let mut distinction = Distinction::default();
+    search_product(&left, &right, |l, r, input| {
+        distinction.record(l, r, input);
+        distinction.is_complete()
+    });
+    Ok(distinction)
+}
+
+// shortest input matched by both regular expressions, none if they are disjoint
+pub(crate) fn regex_intersection(
+    left: &str,
+    right: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let left = PrefixDFA::with_options(left, None, true)?;
+    let right = PrefixDFA::with_options(right, None, true)?;
+    let mut common = None;
+    search_product(&left, &right, |l, r, input| {
+        if l && r {
+            common = Some(input());
+        }
+        common.is_some()
+    });
+    Ok(common)
+}
+
+// shortest inputs of at most max_len bytes that are matched by exactly one of
+// two constraints over the same continuations, e.g. two versions of a grammar;
+// the search is bounded, because the state space of a grammar can be infinite
+pub fn distinguish<L, R>(
+    left: &L,
+    right: &R,
+    continuations: &[impl AsRef<[u8]>],
+    max_len: usize,
+) -> Distinction
+where
+    L: Constraint,
+    R: Constraint,
+    L::State: Hash + Eq + Clone,
+    R::State: Hash + Eq + Clone,
+{
+    // nodes are expanded in order of input length, ties in order of discovery
+    let mut nodes: Vec<(Option<usize>, usize)> = vec![(None, 0)];
+    let mut states = vec![Some((
+        Some(left.get_start_state()),
+        Some(right.get_start_state()),
+    ))];
+    let mut heap = BinaryHeap::from([Reverse((0, 0))]);
+    let mut seen = HashSet::new();
+    let mut distinction = Distinction::default();
+    while let Some(Reverse((len, node))) = heap.pop() {
+        let (l, r) = states[node].take().expect("node is queued once");
+        if !seen.insert((l.clone(), r.clone())) {
+            continue;
+        }
+        let left_match = l.as_ref().is_some_and(|s| left.is_match_state(s));
+        let right_match = r.as_ref().is_some_and(|s| right.is_match_state(s));
+        distinction.record(left_match, right_match, || {
+            path(&nodes, node)
+                .into_iter()
+                .flat_map(|i| continuations[i].as_ref().to_vec())
+                .collect()
+        });
+        if distinction.is_complete() {
+            break;
+        }
+        let mut candidates = BTreeSet::new();
+        if let Some(l) = &l {
+            candidates.extend(left.get_valid_continuations(l));
+        }
+        if let Some(r) = &r {
+            candidates.extend(right.get_valid_continuations(r));
+        }
+        for i in candidates {
+            let next_len = len + continuations[i].as_ref().len();
+            if next_len > max_len {
+                continue;
+            }
+            let next_l = l.as_ref().and_then(|s| left.get_next_state(s, i));
+            let next_r = r.as_ref().and_then(|s| right.get_next_state(s, i));
+            // inputs only one side can still match are useless once the
+            // shortest for that side is known
+            let useful = match (&next_l, &next_r) {
+                (Some(_), Some(_)) => true,
+                (Some(_), None) => distinction.left_only.is_none(),
+                (None, Some(_)) => distinction.right_only.is_none(),
+                (None, None) => false,
+            };
+            if !useful {
+                continue;
+            }
+            nodes.push((Some(node), i));
+            states.push(Some((next_l, next_r)));
+            heap.push(Reverse((next_len, nodes.len() - 1)));
+        }
+    }
+    distinction
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LR1GrammarConstraint;
+
+    #[test]
+    fn test_distinguish_regex() {
+        let distinction = distinguish_regex("a+", "a*").unwrap();
+        assert_eq!(distinction.left_only, None);
+        assert_eq!(distinction.right_only, Some(vec![]));
+
+        let distinction = distinguish_regex("[0-9]+|x", "[0-9]{1,3}|y").unwrap();
+        assert_eq!(distinction.left_only.as_deref(), Some(&b"x"[..]));
+        assert_eq!(distinction.right_only.as_deref(), Some(&b"y"[..]));
+        let distinction = distinguish_regex("[0-9]+", "[0-9]{1,3}").unwrap();
+        assert_eq!(distinction.left_only.as_deref(), Some(&b"0000"[..]));
+
+        assert!(distinguish_regex("(a|b)*", "(a*b*)*").unwrap().is_empty());
+        assert!(distinguish_regex("(", "a").is_err());
+    }
+
+    #[test]
+    fn test_regex_intersection() {
+        assert_eq!(
+            regex_intersection("[a-z]+[0-9]", "a|ab1").unwrap(),
+            Some(b"ab1".to_vec())
+        );
+        assert_eq!(regex_intersection("[a-z]+", "[0-9]*").unwrap(), None);
+    }
+
+    #[test]
+    fn test_distinguish_lr1() {
+        let continuations: Vec<Vec<u8>> = (0..=255).map(|b| vec![b]).collect();
+        let lexer = "%%\n";
+        let nested = LR1GrammarConstraint::new(
+            "%start S\n%%\nS: '(' S ')' S | ;",
+            lexer,
+            continuations.clone(),
+        )
+        .unwrap();
+        let flat = LR1GrammarConstraint::new(
+            "%start S\n%%\nS: '(' ')' S | '[' ']' S | ;",
+            lexer,
+            continuations.clone(),
+        )
+        .unwrap();
+        let distinction = distinguish(&nested, &flat, &continuations, 8);
+        assert_eq!(distinction.left_only.as_deref(), Some(&b"(())"[..]));
+        assert_eq!(distinction.right_only.as_deref(), Some(&b"[]"[..]));
+
+        // bounded by the input length
+        let distinction = distinguish(&nested, &flat, &continuations, 3);
+        assert_eq!(distinction.left_only, None);
+        assert!(distinguish(&nested, &nested, &continuations, 8).is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+use std::{collections::HashMap, error::Error, fmt::Write};
+
+use cfgrammar::{
+    yacc::{YaccGrammar, YaccKind, YaccOriginalActionKind},
+    PIdx, RIdx, Symbol, TIdx,
+};
+use itertools::Itertools;
+use regex::{escape, Regex};
+
+use crate::{
+    lr1::{extract_token_aliases, format_yacc_error, parse_lexer, LexerSpec},
+    utils::{pattern_from_parts, PrefixDFA},
+    LR1GrammarParser,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+struct Terminal {
+    name: String,
+    alias: Option<String>,
+    // regular expression for lexer tokens, none for quoted literals
+    pattern: Option<String>,
+    example: Option<Vec<u8>>,
+}
+
+impl Terminal {
+    fn display(&self) -> String {
+        match &self.pattern {
+            Some(_) => self.name.clone(),
+            None => format!("'{}'", self.name),
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    alternatives: Vec<Vec<String>>,
+    example: Option<Vec<u8>>,
+}
+
+// human readable documentation of an LR(1) grammar and lexer, with a textual
+// syntax diagram (one line per alternative) and an example for every rule, and a
+// table of all terminals with their patterns and examples; examples are built
+// from shortest derivations, the one of the start rule is checked with the parser
+pub fn grammar_docs(
+    grammar: &str,
+    lexer: &str,
+    format: DocFormat,
+) -> Result<String, Box<dyn Error>> {
+    let parser = LR1GrammarParser::new(grammar, lexer)?;
+    let (source, aliases) = extract_token_aliases(grammar)?;
+    let yacc = YaccGrammar::new(
+        YaccKind::Original(YaccOriginalActionKind::NoAction),
+        &source,
+    )
+    .map_err(|e| {
+        format!(
+            "errors creating grammar:\n{}",
+            e.iter().map(|e| format_yacc_error(&source, e)).join("\n")
+        )
+    })?;
+    let LexerSpec {
+        fragments,
+        tokens,
+        ignore_tokens,
+        byte_mode,
+        ..
+    } = parse_lexer(lexer)?;
+    let token_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
+    let mode = |pattern: String| {
+        if byte_mode {
+            format!("(?-u:{pattern})")
+        } else {
+            pattern
+        }
+    };
+
+    let mut terminals = HashMap::new();
+    for tidx in yacc.iter_tidxs() {
+        if tidx == yacc.eof_token_idx() {
+            continue;
+        }
+        let name = yacc.token_name(tidx).ok_or("unnamed token")?;
+        let (pattern, dfa_pattern) = match tokens.get(name) {
+            Some(parts) => {
+                let pattern = pattern_from_parts(name, parts, &token_name, &fragments, &tokens)?;
+                (Some(pattern.clone()), mode(pattern))
+            }
+            None => (None, mode(escape(name))),
+        };
+        terminals.insert(
+            tidx,
+            Terminal {
+                name: name.to_string(),
+                alias: aliases.get(name).cloned(),
+                pattern,
+                example: PrefixDFA::new(&dfa_pattern)?.shortest_match(),
+            },
+        );
+    }
+    let skipped = ignore_tokens
+        .iter()
+        .map(|parts| pattern_from_parts("ignore token", parts, &token_name, &fragments, &tokens))
+        .collect::<Result<Vec<_>, _>>()?;
+    // terminals are separated by a space if the lexer skips it
+    let mut separators = vec![&b""[..]];
+    for pattern in &skipped {
+        if PrefixDFA::new(&mode(pattern.clone()))?
+            .get_state(b" ")
+            .is_some()
+        {
+            separators.insert(0, b" ");
+            break;
+        }
+    }
+
+    // the start rule comes first, the augmented start rule is skipped
+    let augmented = yacc.prod_to_rule(yacc.start_prod());
+    let &[Symbol::Rule(start)] = yacc.prod(yacc.start_prod()) else {
+        return Err("augmented start rule should derive the start rule".into());
+    };
+    let ridxs: Vec<RIdx<u32>> = [start]
+        .into_iter()
+        .chain(yacc.iter_rules().filter(|&ridx| {
+            ridx != augmented && ridx != start && Some(ridx) != yacc.implicit_rule()
+        }))
+        .collect();
+    let derivations = shortest_derivations(&yacc, &terminals);
+    let rules = ridxs
+        .iter()
+        .map(|&ridx| {
+            let alternatives = yacc
+                .rule_to_prods(ridx)
+                .iter()
+                .map(|&pidx| {
+                    yacc.prod(pidx)
+                        .iter()
+                        .map(|symbol| match symbol {
+                            Symbol::Rule(ridx) => yacc.rule_name_str(*ridx).to_string(),
+                            Symbol::Token(tidx) => terminals[tidx].display(),
+                        })
+                        .collect()
+                })
+                .collect();
+            let mut examples = separators.iter().filter_map(|sep| {
+                let mut tidxs = vec![];
+                expand(&yacc, &derivations, Symbol::Rule(ridx), &mut tidxs)?;
+                let example = tidxs
+                    .iter()
+                    .map(|tidx| terminals[tidx].example.clone())
+                    .collect::<Option<Vec<_>>>()?
+                    .join(*sep);
+                Some(example)
+            });
+            // other rules cannot be checked, as they only derive parts of an input
+            let example = if ridx == start {
+                examples.find(|example| parser.parse(example, false, false).is_ok())
+            } else {
+                examples.next()
+            };
+            Rule {
+                name: yacc.rule_name_str(ridx).to_string(),
+                alternatives,
+                example,
+            }
+        })
+        .collect::<Vec<_>>();
+    let terminals: Vec<_> = yacc
+        .iter_tidxs()
+        .filter_map(|tidx| terminals.remove(&tidx))
+        .collect();
+
+    Ok(match format {
+        DocFormat::Markdown => markdown(&rules, &terminals, &skipped),
+        DocFormat::Html => html(&rules, &terminals, &skipped),
+    })
+}
+
+// per rule the production of a shortest derivation, measured in the length of the
+// terminal examples plus one per terminal; none for rules without a derivation
+fn shortest_derivations(
+    grammar: &YaccGrammar,
+    terminals: &HashMap<TIdx<u32>, Terminal>,
+) -> Vec<Option<(usize, PIdx<u32>)>> {
+    let mut best: Vec<Option<(usize, PIdx<u32>)>> = vec![None; usize::from(grammar.rules_len())];
+    // only strict improvements are recorded, so derivations never loop
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for ridx in grammar.iter_rules() {
+            for &pidx in grammar.rule_to_prods(ridx) {
+                let cost = grammar
+                    .prod(pidx)
+                    .iter()
+                    .map(|symbol| match symbol {
+                        Symbol::Rule(ridx) => best[usize::from(*ridx)].map(|(cost, _)| cost),
+                        Symbol::Token(tidx) => terminals[tidx]
+                            .example
+                            .as_ref()
+                            .map(|example| example.len() + 1),
+                    })
+                    .sum::<Option<usize>>();
+                let Some(cost) = cost else {
+                    continue;
+                };
+                let current = &mut best[usize::from(ridx)];
+                if current.is_none_or(|(best, _)| cost < best) {
+                    *current = Some((cost, pidx));
+                    changed = true;
+                }
+            }
+        }
+    }
+    best
+}
+
+fn expand(
+    grammar: &YaccGrammar,
+    derivations: &[Option<(usize, PIdx<u32>)>],
+    symbol: Symbol<u32>,
+    tidxs: &mut Vec<TIdx<u32>>,
+) -> Option<()> {
+    match symbol {
+        Symbol::Token(tidx) => tidxs.push(tidx),
+        Symbol::Rule(ridx) => {
+            let (_, pidx) = derivations[usize::from(ridx)]?;
+            for &symbol in grammar.prod(pidx) {
+                expand(grammar, derivations, symbol, tidxs)?;
+            }
+        }
+    }
+    Some(())
+}
+
+// examples are shown as text, with control characters escaped
+fn example_text(example: &[u8]) -> String {
+    String::from_utf8_lossy(example)
+        .chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_debug().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+fn diagram(rule: &Rule) -> Vec<String> {
+    let indent = " ".repeat(rule.name.chars().count() + 1);
+    rule.alternatives
+        .iter()
+        .enumerate()
+        .map(|(i, symbols)| {
+            let symbols = if symbols.is_empty() {
+                "(empty)".to_string()
+            } else {
+                symbols.join(" ")
+            };
+            if i == 0 {
+                format!("{} ::= {symbols}", rule.name)
+            } else {
+                format!("{indent}  | {symbols}")
+            }
+        })
+        .collect()
+}
+
+// inline code in markdown, using longer backtick fences if the text contains backticks
+fn md_code(text: &str) -> String {
+    if text.is_empty() {
+        return "(empty)".to_string();
+    }
+    let ticks = "`".repeat(text.split(|c| c != '`').map(str::len).max().unwrap_or(0) + 1);
+    let pad = if text.starts_with('`') || text.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{ticks}{pad}{text}{pad}{ticks}")
+}
+
+fn md_cell(text: &str) -> String {
+    md_code(text).replace('|', "\\|")
+}
+
+fn markdown(rules: &[Rule], terminals: &[Terminal], skipped: &[String]) -> String {
+    let mut doc = String::new();
+    writeln!(doc, "# Grammar\n").unwrap();
+    writeln!(doc, "Start rule: {}\n", md_code(&rules[0].name)).unwrap();
+    writeln!(doc, "## Rules").unwrap();
+    for rule in rules {
+        writeln!(doc, "\n### {}\n", rule.name).unwrap();
+        writeln!(doc, "```\n{}\n```", diagram(rule).join("\n")).unwrap();
+        if let Some(example) = &rule.example {
+            writeln!(doc, "\nExample: {}", md_code(&example_text(example))).unwrap();
+        }
+    }
+    writeln!(doc, "\n## Terminals\n").unwrap();
+    writeln!(doc, "| Terminal | Description | Pattern | Example |").unwrap();
+    writeln!(doc, "| --- | --- | --- | --- |").unwrap();
+    for terminal in terminals {
+        writeln!(
+            doc,
+            "| {} | {} | {} | {} |",
+            md_cell(&terminal.display()),
+            terminal.alias.as_deref().unwrap_or(""),
+            terminal
+                .pattern
+                .as_deref()
+                .map_or("literal".to_string(), md_cell),
+            terminal
+                .example
+                .as_deref()
+                .map_or(String::new(), |example| md_cell(&example_text(example))),
+        )
+        .unwrap();
+    }
+    if !skipped.is_empty() {
+        writeln!(
+            doc,
+            "\nSkipped between terminals: {}",
+            skipped.iter().map(|pattern| md_code(pattern)).join(", ")
+        )
+        .unwrap();
+    }
+    doc
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html(rules: &[Rule], terminals: &[Terminal], skipped: &[String]) -> String {
+    let mut doc = String::new();
+    writeln!(doc, "<h1>Grammar</h1>").unwrap();
+    writeln!(
+        doc,
+        "<p>Start rule: <a href=\"#rule-{0}\"><code>{0}</code></a></p>",
+        html_escape(&rules[0].name)
+    )
+    .unwrap();
+    writeln!(doc, "<h2>Rules</h2>").unwrap();
+    for rule in rules {
+        let name = html_escape(&rule.name);
+        writeln!(doc, "<h3 id=\"rule-{name}\">{name}</h3>").unwrap();
+        writeln!(doc, "<pre>{}</pre>", html_escape(&diagram(rule).join("\n"))).unwrap();
+        if let Some(example) = &rule.example {
+            writeln!(
+                doc,
+                "<p>Example: <code>{}</code></p>",
+                html_escape(&example_text(example))
+            )
+            .unwrap();
+        }
+    }
+    writeln!(doc, "<h2>Terminals</h2>").unwrap();
+    writeln!(doc, "<table>").unwrap();
+    writeln!(
+        doc,
+        "<tr><th>Terminal</th><th>Description</th><th>Pattern</th><th>Example</th></tr>"
+    )
+    .unwrap();
+    for terminal in terminals {
+        writeln!(
+            doc,
+            "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td><code>{}</code></td></tr>",
+            html_escape(&terminal.display()),
+            html_escape(terminal.alias.as_deref().unwrap_or("")),
+            terminal
+                .pattern
+                .as_deref()
+                .map_or("literal".to_string(), |pattern| {
+                    format!("<code>{}</code>", html_escape(pattern))
+                }),
+            terminal
+                .example
+                .as_deref()
+                .map_or(String::new(), |example| html_escape(&example_text(example))),
+        )
+        .unwrap();
+    }
+    writeln!(doc, "</table>").unwrap();
+    if !skipped.is_empty() {
+        writeln!(
+            doc,
+            "<p>Skipped between terminals: {}</p>",
+            skipped
+                .iter()
+                .map(|pattern| format!("<code>{}</code>", html_escape(pattern)))
+                .join(", ")
+        )
+        .unwrap();
+    }
+    doc
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    const GRAMMAR: &str = r#"
+%start Query
+%token NAME "a name"
+%%
+Query: 'SELECT' Names Where ;
+Names: 'NAME' | Names ',' 'NAME' ;
+Where: | 'WHERE' 'NAME' '=' 'NUMBER' ;
+"#;
+    const LEXER: &str = r#"
+%%
+SELECT (?i)select
+WHERE where
+NAME [a-z_]+
+NUMBER [0-9]+|`[0-9]+`
+; \s+
+"#;
+
+    #[test]
+    fn test_grammar_docs_markdown() {
+        let docs = grammar_docs(GRAMMAR, LEXER, DocFormat::Markdown).unwrap();
+        assert!(docs.starts_with("# Grammar\n\nStart rule: `Query`\n"));
+        assert!(docs.contains(
+            "### Names\n\n```\nNames ::= NAME\n        | Names ',' NAME\n```\n\nExample: `a`\n"
+        ));
+        assert!(docs.contains("Where ::= (empty)\n        | WHERE NAME '=' NUMBER\n"));
+        // the start example uses the shortest alternatives and passes the parser
+        assert!(docs.contains("Example: `select a`\n"));
+        assert!(docs.contains("| `NAME` | a name | `[a-z_]+` | `a` |\n"));
+        assert!(docs.contains("| `NUMBER` |  | `` [0-9]+\\|`[0-9]+` `` | `0` |\n"));
+        assert!(docs.contains("| `','` |  | literal | `,` |\n"));
+        assert!(docs.ends_with("Skipped between terminals: `\\s+`\n"));
+    }
+
+    #[test]
+    fn test_grammar_docs_html() {
+        let docs = grammar_docs(GRAMMAR, LEXER, DocFormat::Html).unwrap();
+        assert!(docs.contains("<a href=\"#rule-Query\"><code>Query</code></a>"));
+        assert!(docs.contains("<h3 id=\"rule-Where\">Where</h3>"));
+        assert!(docs.contains("<pre>Where ::= (empty)\n        | WHERE NAME '=' NUMBER</pre>"));
+        assert!(docs.contains("<p>Example: <code>select a</code></p>"));
+
+        // examples of all bundled grammars are accepted by their parsers
+        for name in ["calc", "json", "sparql"] {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("grammars")
+                .join(name);
+            let grammar = fs::read_to_string(dir.join(format!("{name}.y"))).unwrap();
+            let lexer = fs::read_to_string(dir.join(format!("{name}.l"))).unwrap();
+            let docs = grammar_docs(&grammar, &lexer, DocFormat::Markdown).unwrap();
+            let start = docs.split("## Rules").nth(1).unwrap();
+            let example = start
+                .split("\n## ")
+                .next()
+                .unwrap()
+                .split("### ")
+                .nth(1)
+                .unwrap();
+            assert!(example.contains("Example: "), "{name}: {example}");
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    any::Any,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use crate::{ByteConstraint, Constraint};
+
+trait AnyState: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn AnyState>;
+
+    fn eq_dyn(&self, other: &dyn AnyState) -> bool;
+
+    fn hash_dyn(&self, state: &mut dyn Hasher);
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T> AnyState for T
+where
+    T: Any + Clone + Eq + Hash + Send + Sync,
+{
+    fn clone_box(&self) -> Box<dyn AnyState> {
+        Box::new(self.clone())
+    }
+
+    fn eq_dyn(&self, other: &dyn AnyState) -> bool {
+        other
+            .as_any()
+            .downcast_ref::<T>()
+            .is_some_and(|other| self == other)
+    }
+
+    fn hash_dyn(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// type erased state of a dyn constraint, states of different
+// constraint types never compare equal
+pub struct DynState(Box<dyn AnyState>);
+
+impl DynState {
+    pub fn new<T>(state: T) -> Self
+    where
+        T: Any + Clone + Eq + Hash + Send + Sync,
+    {
+        Self(Box::new(state))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.as_any().downcast_ref()
+    }
+}
+
+impl Clone for DynState {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+impl PartialEq for DynState {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_dyn(other.0.as_ref())
+    }
+}
+
+impl Eq for DynState {}
+
+impl Hash for DynState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_any().type_id().hash(state);
+        self.0.hash_dyn(state);
+    }
+}
+
+impl fmt::Debug for DynState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DynState").finish_non_exhaustive()
+    }
+}
+
+// object safe counterpart of the constraint trait, implemented for every
+// constraint with a cloneable, hashable state, so constraints of different
+// types can be stored and dispatched uniformly as Box<dyn DynConstraint>;
+// passing a state of another constraint type panics
+pub trait DynConstraint: Send + Sync {
+    fn get_state(&self, prefix: &[u8]) -> Option<DynState>;
+
+    fn get_start_state(&self) -> DynState;
+
+    fn is_match_state(&self, state: &DynState) -> bool;
+
+    fn get_valid_continuations(&self, state: &DynState) -> Vec<usize>;
+
+    fn get_valid_ranges(&self, state: &DynState) -> Vec<(u32, u32)>;
+
+    fn get_next_state(&self, state: &DynState, continuation: usize) -> Option<DynState>;
+
+    fn has_same_continuations(&self, state: &DynState, next: &DynState) -> bool;
+
+    fn get_approximate_continuations(&self, state: &DynState) -> Vec<usize>;
+}
+
+fn downcast<S: Any>(state: &DynState) -> &S {
+    state
+        .downcast_ref()
+        .expect("state does not belong to this constraint")
+}
+
+impl<C> DynConstraint for C
+where
+    C: Constraint + Send + Sync,
+    C::State: Any + Clone + Eq + Hash + Send + Sync,
+{
+    fn get_state(&self, prefix: &[u8]) -> Option<DynState> {
+        Constraint::get_state(self, prefix).map(DynState::new)
+    }
+
+    fn get_start_state(&self) -> DynState {
+        DynState::new(Constraint::get_start_state(self))
+    }
+
+    fn is_match_state(&self, state: &DynState) -> bool {
+        Constraint::is_match_state(self, downcast(state))
+    }
+
+    fn get_valid_continuations(&self, state: &DynState) -> Vec<usize> {
+        Constraint::get_valid_continuations(self, downcast(state))
+    }
+
+    fn get_valid_ranges(&self, state: &DynState) -> Vec<(u32, u32)> {
+        Constraint::get_valid_ranges(self, downcast(state))
+    }
+
+    fn get_next_state(&self, state: &DynState, continuation: usize) -> Option<DynState> {
+        Constraint::get_next_state(self, downcast(state), continuation).map(DynState::new)
+    }
+
+    fn has_same_continuations(&self, state: &DynState, next: &DynState) -> bool {
+        Constraint::has_same_continuations(self, downcast(state), downcast(next))
+    }
+
+    fn get_approximate_continuations(&self, state: &DynState) -> Vec<usize> {
+        Constraint::get_approximate_continuations(self, downcast(state))
+    }
+}
+
+// object safe version of the byte constraint trait, for combinators that
+// need to split continuations between their constraints
+pub trait DynByteConstraint: DynConstraint {
+    fn continuations(&self) -> &[Vec<u8>];
+
+    fn get_next_state_with_bytes(&self, state: &DynState, bytes: &[u8]) -> Option<DynState>;
+}
+
+impl<C> DynByteConstraint for C
+where
+    C: ByteConstraint + Send + Sync,
+    C::State: Any + Clone + Eq + Hash + Send + Sync,
+{
+    fn continuations(&self) -> &[Vec<u8>] {
+        ByteConstraint::continuations(self)
+    }
+
+    fn get_next_state_with_bytes(&self, state: &DynState, bytes: &[u8]) -> Option<DynState> {
+        ByteConstraint::get_next_state_with_bytes(self, downcast(state), bytes).map(DynState::new)
+    }
+}
+
+// trait objects of both dyn traits are constraints themselves
+macro_rules! dyn_constraint {
+    ($dyn:ty) => {
+        impl Constraint for $dyn {
+            type State = DynState;
+
+            fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+                DynConstraint::get_state(self, prefix)
+            }
+
+            fn get_start_state(&self) -> Self::State {
+                DynConstraint::get_start_state(self)
+            }
+
+            fn is_match_state(&self, state: &Self::State) -> bool {
+                DynConstraint::is_match_state(self, state)
+            }
+
+            fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+                DynConstraint::get_valid_continuations(self, state)
+            }
+
+            fn get_valid_ranges(&self, state: &Self::State) -> Vec<(u32, u32)> {
+                DynConstraint::get_valid_ranges(self, state)
+            }
+
+            fn get_next_state(
+                &self,
+                state: &Self::State,
+                continuation: usize,
+            ) -> Option<Self::State> {
+                DynConstraint::get_next_state(self, state, continuation)
+            }
+
+            fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+                DynConstraint::has_same_continuations(self, state, next)
+            }
+
+            fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+                DynConstraint::get_approximate_continuations(self, state)
+            }
+        }
+    };
+}
+
+dyn_constraint!(dyn DynConstraint + '_);
+dyn_constraint!(dyn DynByteConstraint + '_);
+
+impl ByteConstraint for dyn DynByteConstraint + '_ {
+    fn continuations(&self) -> &[Vec<u8>] {
+        DynByteConstraint::continuations(self)
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        DynByteConstraint::get_next_state_with_bytes(self, state, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{encode_with_constraint, LR1GrammarConstraint, RegularExpressionConstraint};
+
+    #[test]
+    fn test_dyn_constraints() {
+        let conts: Vec<_> = ["a", "b", "(", ")", "ab"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let grammar = r#"
+%start S
+%%
+S: 'LPAREN' S 'RPAREN' | 'A' ;
+"#;
+        let lexer = r#"
+%%
+LPAREN \(
+RPAREN \)
+A a
+"#;
+        let constraints: Vec<Box<dyn DynConstraint>> = vec![
+            Box::new(RegularExpressionConstraint::new("ab+", conts.clone()).unwrap()),
+            Box::new(LR1GrammarConstraint::new(grammar, lexer, conts.clone()).unwrap()),
+        ];
+
+        let states: Vec<_> = constraints.iter().map(|c| c.get_start_state()).collect();
+        assert_eq!(constraints[0].get_valid_continuations(&states[0]), [0, 4]);
+        assert_eq!(constraints[1].get_valid_continuations(&states[1]), [0, 2]);
+        assert_ne!(states[0], states[1]);
+        assert_eq!(states[0], states[0].clone());
+        assert!(states[0].downcast_ref::<crate::LR1State>().is_none());
+
+        let next = constraints[0].get_next_state(&states[0], 4).unwrap();
+        assert!(constraints[0].is_match_state(&next));
+        assert_eq!(constraints[0].get_valid_ranges(&next), [(1, 2)]);
+        assert_eq!(constraints[0].get_state(b"ab"), Some(next));
+
+        let state = constraints[1].get_state(b"((a").unwrap();
+        assert!(!constraints[1].is_match_state(&state));
+        assert_eq!(constraints[1].get_valid_continuations(&state), [3]);
+        assert!(constraints[1].get_next_state(&state, 0).is_none());
+
+        // dyn constraints can be used wherever a constraint is expected
+        let byte_constraint: Box<dyn DynByteConstraint> =
+            Box::new(LR1GrammarConstraint::new(grammar, lexer, conts.clone()).unwrap());
+        assert_eq!(
+            encode_with_constraint(byte_constraint.as_ref(), b"(a)").unwrap(),
+            [2, 0, 3]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "state does not belong to this constraint")]
+    fn test_dyn_state_mismatch() {
+        let conts = vec![b"a".to_vec()];
+        let a: Box<dyn DynConstraint> =
+            Box::new(RegularExpressionConstraint::new("a", conts.clone()).unwrap());
+        let b: Box<dyn DynConstraint> = Box::new(
+            LR1GrammarConstraint::new("%start S\n%%\nS: 'A' ;", "%%\nA a", conts).unwrap(),
+        );
+        b.get_valid_continuations(&a.get_start_state());
+    }
+}
@@ -0,0 +1,489 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::read_to_string,
+    mem::size_of,
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+use cfgrammar::{
+    yacc::{YaccGrammar, YaccKind, YaccOriginalActionKind},
+    PIdx, Symbol, TIdx,
+};
+use regex_automata::util::primitives::StateID;
+use rustc_hash::FxHasher;
+
+use crate::{
+    limits::CompileLimits,
+    lr1::{
+        advance_lexer, eoi_terminals, initial_prefix_matches, is_valid_remainder,
+        load_grammar_and_pdfas, valid_continuations_in_order, Matching, PdfaList, TokenNames,
+    },
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+// production with the position of the dot and the set it was predicted in,
+// none if that is the set containing the item
+#[derive(Debug, Clone)]
+struct Item {
+    prod: PIdx<u32>,
+    dot: usize,
+    origin: Option<Arc<EarleySet>>,
+}
+
+impl Item {
+    fn key(&self) -> (PIdx<u32>, usize, usize) {
+        let origin = self.origin.as_ref().map_or(0, |o| Arc::as_ptr(o) as usize);
+        (self.prod, self.dot, origin)
+    }
+}
+
+impl PartialEq for Item {
+    fn eq(&self, other: &Self) -> bool {
+        self.prod == other.prod
+            && self.dot == other.dot
+            && match (&self.origin, &other.origin) {
+                (None, None) => true,
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b) || a == b,
+                _ => false,
+            }
+    }
+}
+
+impl Eq for Item {}
+
+// the items after a number of tokens; sets are shared between states and
+// reference the sets their items were predicted in, so the chart of a state
+// is kept alive only as far as it is still needed
+#[derive(Debug)]
+struct EarleySet {
+    items: Vec<Item>,
+    // structural hash, so comparing sets of different states is cheap
+    hash: u64,
+}
+
+impl EarleySet {
+    fn new(items: Vec<Item>) -> Self {
+        let mut hasher = FxHasher::default();
+        for item in &items {
+            item.prod.hash(&mut hasher);
+            item.dot.hash(&mut hasher);
+            item.origin.as_ref().map(|o| o.hash).hash(&mut hasher);
+        }
+        Self {
+            items,
+            hash: hasher.finish(),
+        }
+    }
+}
+
+impl PartialEq for EarleySet {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.items == other.items
+    }
+}
+
+impl Eq for EarleySet {}
+
+impl Hash for EarleySet {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EarleyState {
+    set: Arc<EarleySet>,
+    matching: Matching,
+}
+
+impl MemoryUsage for EarleyState {
+    fn memory_usage(&self) -> usize {
+        // only the current set, earlier ones are shared with other states
+        size_of::<Self>()
+            + self.set.items.capacity() * size_of::<Item>()
+            + self.matching.capacity() * size_of::<(usize, StateID)>()
+    }
+}
+
+// constraint for grammars that are not LR(1), e.g. ambiguous ones or ones with
+// conflicts, using the same grammar and lexer format; instead of a parser stack
+// every state keeps the Earley items of the pending position, which is slower
+// than a table lookup but works for every context free grammar
+pub struct EarleyGrammarConstraint {
+    grammar: YaccGrammar<u32>,
+    pdfas: PdfaList,
+    nullable: Vec<bool>,
+    start: Arc<EarleySet>,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+    token_names: TokenNames,
+}
+
+impl EarleyGrammarConstraint {
+    pub fn new(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_limits(grammar, tokens, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (grammar, pdfas, _, token_names) = load_grammar_and_pdfas(
+            grammar,
+            YaccKind::Original(YaccOriginalActionKind::NoAction),
+            tokens,
+            limits,
+            Instant::now(),
+            &mut |_| {},
+        )?;
+        let firsts = grammar.firsts();
+        let nullable = grammar
+            .iter_rules()
+            .map(|ridx| firsts.is_epsilon_set(ridx))
+            .collect();
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        let mut constraint = Self {
+            grammar,
+            pdfas,
+            nullable,
+            start: Arc::new(EarleySet::new(vec![])),
+            continuations,
+            permutation,
+            skips,
+            token_names,
+        };
+        constraint.start = constraint.closure(vec![Item {
+            prod: constraint.grammar.start_prod(),
+            dot: 0,
+            origin: None,
+        }]);
+        Ok(constraint)
+    }
+
+    pub fn from_files(
+        grammar_path: impl AsRef<Path>,
+        tokens_path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(grammar_path.as_ref())?;
+        let grammar = read_to_string(file)?;
+        let file = File::open(tokens_path.as_ref())?;
+        let tokens = read_to_string(file)?;
+        Self::new(&grammar, &tokens, continuations)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // display names of the terminals the parser accepts next, including
+    // the one currently being lexed; uses %token aliases if given
+    pub fn expected_terminals(&self, state: &EarleyState) -> Vec<&str> {
+        self.grammar
+            .iter_tidxs()
+            .filter(|&tidx| {
+                if tidx == self.grammar.eof_token_idx() {
+                    self.accepts(&state.set)
+                } else {
+                    self.expects(&state.set, tidx)
+                }
+            })
+            .map(|tidx| self.token_names[usize::from(tidx)].as_str())
+            .collect()
+    }
+
+    fn next_symbol(&self, item: &Item) -> Option<Symbol<u32>> {
+        self.grammar.prod(item.prod).get(item.dot).copied()
+    }
+
+    // adds predicted and completed items to the kernel items; nullable rules
+    // are skipped right away when predicted, so completions never need to look
+    // at the set that is being built (Aycock and Horspool)
+    fn closure(&self, kernel: Vec<Item>) -> Arc<EarleySet> {
+        let mut items = vec![];
+        let mut seen = HashSet::new();
+        let mut add = |item: Item, items: &mut Vec<Item>| {
+            if seen.insert(item.key()) {
+                items.push(item);
+            }
+        };
+        for item in kernel {
+            add(item, &mut items);
+        }
+        let mut i = 0;
+        while i < items.len() {
+            let item = items[i].clone();
+            i += 1;
+            match self.next_symbol(&item) {
+                Some(Symbol::Rule(ridx)) => {
+                    for &prod in self.grammar.rule_to_prods(ridx) {
+                        let predicted = Item {
+                            prod,
+                            dot: 0,
+                            origin: None,
+                        };
+                        add(predicted, &mut items);
+                    }
+                    if self.nullable[usize::from(ridx)] {
+                        let skipped = Item {
+                            dot: item.dot + 1,
+                            ..item
+                        };
+                        add(skipped, &mut items);
+                    }
+                }
+                Some(Symbol::Token(_)) => {}
+                None => {
+                    let Some(origin) = &item.origin else {
+                        continue;
+                    };
+                    let lhs = Symbol::Rule(self.grammar.prod_to_rule(item.prod));
+                    for waiting in &origin.items {
+                        if self.next_symbol(waiting) != Some(lhs) {
+                            continue;
+                        }
+                        let completed = Item {
+                            prod: waiting.prod,
+                            dot: waiting.dot + 1,
+                            origin: waiting.origin.clone().or_else(|| Some(origin.clone())),
+                        };
+                        add(completed, &mut items);
+                    }
+                }
+            }
+        }
+        Arc::new(EarleySet::new(items))
+    }
+
+    fn expects(&self, set: &EarleySet, tidx: TIdx<u32>) -> bool {
+        set.items
+            .iter()
+            .any(|item| self.next_symbol(item) == Some(Symbol::Token(tidx)))
+    }
+
+    fn accepts(&self, set: &EarleySet) -> bool {
+        let start = self.grammar.start_prod();
+        let len = self.grammar.prod(start).len();
+        set.items
+            .iter()
+            .any(|item| item.prod == start && item.dot == len)
+    }
+
+    fn scan(&self, set: &Arc<EarleySet>, tidx: TIdx<u32>) -> Option<Arc<EarleySet>> {
+        let kernel: Vec<_> = set
+            .items
+            .iter()
+            .filter(|item| self.next_symbol(item) == Some(Symbol::Token(tidx)))
+            .map(|item| Item {
+                prod: item.prod,
+                dot: item.dot + 1,
+                origin: item.origin.clone().or_else(|| Some(set.clone())),
+            })
+            .collect();
+        if kernel.is_empty() {
+            None
+        } else {
+            Some(self.closure(kernel))
+        }
+    }
+
+    fn is_valid_matching(&self, set: &EarleySet, matching: &Matching) -> bool {
+        is_valid_remainder(
+            &self.pdfas,
+            matching,
+            self.grammar.eof_token_idx(),
+            |tidx| self.expects(set, tidx),
+        )
+    }
+}
+
+impl MemoryUsage for EarleyGrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        let pdfas: usize = self.pdfas.iter().map(|(pdfa, _)| pdfa.memory_usage()).sum();
+        pdfas
+            + self.nullable.len()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for EarleyGrammarConstraint {
+    type State = EarleyState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.get_next_state_with_bytes(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        EarleyState {
+            set: self.start.clone(),
+            matching: initial_prefix_matches(&self.pdfas),
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        eoi_terminals(&self.pdfas, &state.matching).any(|tidx| {
+            self.scan(&state.set, tidx)
+                .is_some_and(|set| self.accepts(&set))
+        })
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        valid_continuations_in_order(
+            &self.continuations,
+            &self.permutation,
+            &self.skips,
+            |cont| self.get_next_state_with_bytes(state, cont).is_some(),
+        )
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+}
+
+impl ByteConstraint for EarleyGrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        let (set, matching) = advance_lexer(
+            bytes,
+            &self.pdfas,
+            &state.matching,
+            state.set.clone(),
+            |set, tidx| self.scan(&set, tidx),
+        )?;
+        if !self.is_valid_matching(&set, &matching) {
+            return None;
+        }
+        Some(EarleyState { set, matching })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LR1GrammarConstraint;
+
+    fn conts() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    #[test]
+    fn test_earley_ambiguous() {
+        // ambiguous and with shift reduce conflicts, so not LR(1)
+        let grammar = "%start E\n%%\nE: E '+' E | E '*' E | '(' E ')' | 'INT' ;";
+        let lexer = "%%\nINT [0-9]+\n; [\\x20]+";
+        let earley = EarleyGrammarConstraint::new(grammar, lexer, conts()).unwrap();
+        assert!(earley.check(b"1 + 2 * (3 + 4)"));
+        assert!(earley.check(b"12"));
+        assert!(!earley.check(b"1 +"));
+        assert!(!earley.check(b"1 + 2 "));
+        assert!(earley.get_state(b"1 + (").is_some());
+        assert!(earley.get_state(b"1 + )").is_none());
+        assert!(earley.get_state(b"1 2").is_none());
+
+        let state = earley.get_state(b"(1").unwrap();
+        let valid = earley.get_valid_continuations(&state);
+        let expected: Vec<usize> = b" )*+0123456789".iter().map(|&b| b as usize).collect();
+        assert_eq!(valid, expected);
+        let mut terminals = earley.expected_terminals(&state);
+        terminals.sort();
+        // terminals at the position of the pending lexeme 1, like for LR(1)
+        assert_eq!(terminals, ["'('", "INT"]);
+
+        // states reached by different prefixes compare equal
+        assert!(earley.states_equal(b"1 + 2", b"1+2"));
+        assert!(!earley.states_equal(b"(1", b"1"));
+    }
+
+    #[test]
+    fn test_earley_palindromes() {
+        // the middle of a palindrome cannot be found with bounded lookahead, so
+        // the LR(1) table resolves the conflicts and misses some palindromes
+        let grammar = "%start S\n%%\nS: 'a' S 'a' | 'b' S 'b' | 'a' | 'b' | ;";
+        let earley = EarleyGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        let lr1 = LR1GrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        for text in ["aa", "aba", "abba", "baab", "abaaba"] {
+            assert!(earley.check(text.as_bytes()));
+        }
+        assert!(!lr1.check(b"abba"));
+        assert!(!earley.check(b"ab"));
+        assert!(earley.get_state(b"abb").is_some());
+    }
+
+    #[test]
+    fn test_earley_nullable_and_lookahead() {
+        // needs unbounded lookahead to decide between the alternatives,
+        // and has nullable rules in between
+        let grammar = "
+%start S
+%%
+S: A 'x' | B 'y' ;
+A: 'a' A | Opt ;
+B: 'a' B | Opt ;
+Opt: 'b' | ;
+";
+        let earley = EarleyGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        assert!(earley.check(b"aaax"));
+        assert!(earley.check(b"aaaby"));
+        assert!(earley.check(b"x"));
+        assert!(!earley.check(b"aab"));
+        assert!(earley.get_state(b"aabx").is_some());
+        assert!(earley.get_state(b"aaba").is_none());
+        assert_eq!(
+            earley.get_valid_continuations(&earley.get_state(b"aa").unwrap()),
+            [b'a' as usize, b'b' as usize, b'x' as usize, b'y' as usize]
+        );
+    }
+
+    #[test]
+    fn test_earley_json() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let earley = EarleyGrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let lr1 = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let text = br#"{"a": [1, -2.5e3, {"b": null}], "c": "d"}"#;
+        for len in 0..=text.len() {
+            let prefix = &text[..len];
+            let (Some(e), Some(l)) = (earley.get_state(prefix), lr1.get_state(prefix)) else {
+                panic!("invalid prefix {}", String::from_utf8_lossy(prefix));
+            };
+            assert_eq!(
+                earley.get_valid_continuations(&e),
+                lr1.get_valid_continuations(&l)
+            );
+            assert_eq!(earley.is_match_state(&e), lr1.is_match_state(&l));
+        }
+    }
+}
@@ -0,0 +1,556 @@
+use std::{error::Error, iter::Peekable, str::CharIndices};
+
+use indexmap::IndexMap;
+
+use crate::gbnf::{Converter, Expr};
+
+// exceptions are parsed into references to this prefix and the index of the
+// exception, which are replaced by character classes once all rules are known
+const EXCEPTION: &str = "\0except";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Name(String),
+    Define,
+    End,
+    Literal(String),
+    Class(bool, Vec<(char, char)>),
+    Number(u32),
+    Open,
+    Close,
+    OpenOptional,
+    CloseOptional,
+    OpenRepeat,
+    CloseRepeat,
+    Bar,
+    Comma,
+    Minus,
+    Star,
+    Repeat(u32, Option<u32>),
+}
+
+fn hex_char(chars: &mut Peekable<CharIndices>, pos: usize) -> Result<char, Box<dyn Error>> {
+    if chars.next().map(|(_, c)| c) != Some('x') {
+        return Err(format!("expected #x at position {pos}").into());
+    }
+    let mut digits = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+        digits.push(c);
+    }
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("invalid character #x{digits} at position {pos}").into())
+}
+
+fn skip_comment(
+    chars: &mut Peekable<CharIndices>,
+    close: char,
+    pos: usize,
+) -> Result<(), Box<dyn Error>> {
+    while let Some((_, c)) = chars.next() {
+        if c == '*' && chars.next_if(|&(_, c)| c == close).is_some() {
+            return Ok(());
+        }
+    }
+    Err(format!("unterminated comment at position {pos}").into())
+}
+
+fn tokenize(ebnf: &str, w3c: bool) -> Result<Vec<Lexeme>, Box<dyn Error>> {
+    let mut lexemes = vec![];
+    let mut chars = ebnf.char_indices().peekable();
+    let is_name_char = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    while let Some((pos, c)) = chars.next() {
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            '/' if w3c && chars.next_if(|&(_, c)| c == '*').is_some() => {
+                skip_comment(&mut chars, '/', pos)?;
+                continue;
+            }
+            '(' if !w3c && chars.next_if(|&(_, c)| c == '*').is_some() => {
+                skip_comment(&mut chars, ')', pos)?;
+                continue;
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut name = String::from(c);
+                loop {
+                    while let Some((_, c)) = chars.next_if(|(_, c)| is_name_char(c)) {
+                        name.push(c);
+                    }
+                    // iso names can contain spaces, because items of a
+                    // sequence are separated by commas
+                    let mut lookahead = chars.clone();
+                    while lookahead.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+                    if w3c || !lookahead.peek().is_some_and(|(_, c)| is_name_char(c)) {
+                        break;
+                    }
+                    chars = lookahead;
+                    name.push(' ');
+                }
+                Lexeme::Name(name)
+            }
+            c if c.is_ascii_digit() && !w3c => {
+                let mut digits = String::from(c);
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    digits.push(c);
+                }
+                Lexeme::Number(digits.parse()?)
+            }
+            ':' if w3c => {
+                if chars.next().map(|(_, c)| c) != Some(':')
+                    || chars.next().map(|(_, c)| c) != Some('=')
+                {
+                    return Err(format!("expected ::= at position {pos}").into());
+                }
+                Lexeme::Define
+            }
+            '=' if !w3c => Lexeme::Define,
+            ';' | '.' if !w3c => Lexeme::End,
+            '"' | '\'' => {
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, q)) if q == c => break,
+                        Some((_, c)) => literal.push(c),
+                        None => return Err(format!("unterminated string at position {pos}").into()),
+                    }
+                }
+                Lexeme::Literal(literal)
+            }
+            '#' if w3c => Lexeme::Literal(hex_char(&mut chars, pos)?.to_string()),
+            '[' if w3c => {
+                let negated = chars.next_if(|&(_, c)| c == '^').is_some();
+                let mut ranges = vec![];
+                loop {
+                    let start = match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((pos, '#')) => hex_char(&mut chars, pos)?,
+                        Some((_, c)) => c,
+                        None => return Err(format!("unterminated class at position {pos}").into()),
+                    };
+                    let mut end = start;
+                    let mut lookahead = chars.clone();
+                    if lookahead.next().is_some_and(|(_, c)| c == '-')
+                        && lookahead.peek().is_some_and(|&(_, c)| c != ']')
+                    {
+                        chars.next();
+                        end = match chars.next() {
+                            Some((pos, '#')) => hex_char(&mut chars, pos)?,
+                            Some((_, c)) => c,
+                            None => unreachable!("checked by lookahead"),
+                        };
+                        if end < start {
+                            return Err(format!("invalid range {start}-{end} in class").into());
+                        }
+                    }
+                    ranges.push((start, end));
+                }
+                Lexeme::Class(negated, ranges)
+            }
+            '[' => Lexeme::OpenOptional,
+            ']' if !w3c => Lexeme::CloseOptional,
+            '{' if !w3c => Lexeme::OpenRepeat,
+            '}' if !w3c => Lexeme::CloseRepeat,
+            '(' => Lexeme::Open,
+            ')' => Lexeme::Close,
+            '|' => Lexeme::Bar,
+            ',' if !w3c => Lexeme::Comma,
+            '-' => Lexeme::Minus,
+            '*' if w3c => Lexeme::Repeat(0, None),
+            '*' => Lexeme::Star,
+            '+' if w3c => Lexeme::Repeat(1, None),
+            '?' if w3c => Lexeme::Repeat(0, Some(1)),
+            '?' => {
+                return Err(
+                    format!("special sequences are not supported, at position {pos}").into(),
+                )
+            }
+            c => return Err(format!("unexpected character {c:?} at position {pos}").into()),
+        };
+        lexemes.push(lexeme);
+    }
+    Ok(lexemes)
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+    w3c: bool,
+    exceptions: Vec<(Expr, Expr)>,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos + offset)
+    }
+
+    fn expect(&mut self, lexeme: Lexeme, context: &str) -> Result<(), Box<dyn Error>> {
+        match self.peek(0) {
+            Some(next) if *next == lexeme => {
+                self.pos += 1;
+                Ok(())
+            }
+            next => Err(format!("expected {lexeme:?} {context}, got {next:?}").into()),
+        }
+    }
+
+    // only w3c rules end where the next one starts, iso rules end with ;
+    fn at_rule_start(&self) -> bool {
+        self.w3c
+            && matches!(self.peek(0), Some(Lexeme::Name(_)))
+            && self.peek(1) == Some(&Lexeme::Define)
+    }
+
+    fn alternatives(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek(0) == Some(&Lexeme::Bar) {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Alt(alternatives)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut items = vec![];
+        loop {
+            if self.at_rule_start()
+                || matches!(
+                    self.peek(0),
+                    None | Some(
+                        Lexeme::Bar
+                            | Lexeme::Close
+                            | Lexeme::CloseOptional
+                            | Lexeme::CloseRepeat
+                            | Lexeme::End
+                    )
+                )
+            {
+                break;
+            }
+            if !self.w3c && !items.is_empty() {
+                self.expect(Lexeme::Comma, "between items of a sequence")?;
+            }
+            items.push(self.exception()?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+
+    fn exception(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let item = self.term()?;
+        if self.peek(0) != Some(&Lexeme::Minus) {
+            return Ok(item);
+        }
+        self.pos += 1;
+        let except = self.term()?;
+        self.exceptions.push((item, except));
+        Ok(Expr::Ref(format!(
+            "{EXCEPTION}{}",
+            self.exceptions.len() - 1
+        )))
+    }
+
+    fn term(&mut self) -> Result<Expr, Box<dyn Error>> {
+        if let Some(&Lexeme::Number(n)) = self.peek(0) {
+            self.pos += 1;
+            self.expect(Lexeme::Star, "after repetition count")?;
+            return Ok(Expr::Repeat(Box::new(self.primary()?), n, Some(n)));
+        }
+        let mut item = self.primary()?;
+        while let Some(&Lexeme::Repeat(min, max)) = self.peek(0) {
+            self.pos += 1;
+            item = Expr::Repeat(Box::new(item), min, max);
+        }
+        Ok(item)
+    }
+
+    fn primary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let lexeme = self.peek(0).cloned().ok_or("unexpected end of grammar")?;
+        self.pos += 1;
+        Ok(match lexeme {
+            Lexeme::Name(name) => Expr::Ref(name),
+            Lexeme::Literal(literal) => Expr::Literal(literal),
+            Lexeme::Class(negated, ranges) => Expr::Class(negated, ranges),
+            Lexeme::Open => self.group(Lexeme::Close)?,
+            Lexeme::OpenOptional => {
+                Expr::Repeat(Box::new(self.group(Lexeme::CloseOptional)?), 0, Some(1))
+            }
+            Lexeme::OpenRepeat => Expr::Repeat(Box::new(self.group(Lexeme::CloseRepeat)?), 0, None),
+            lexeme => return Err(format!("unexpected {lexeme:?}").into()),
+        })
+    }
+
+    fn group(&mut self, close: Lexeme) -> Result<Expr, Box<dyn Error>> {
+        let inner = self.alternatives()?;
+        self.expect(close, "at the end of a group")?;
+        Ok(inner)
+    }
+}
+
+fn normalize(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = vec![];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn complement(ranges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut complement = vec![];
+    let mut next = 0;
+    for &(start, end) in ranges {
+        if start > next {
+            complement.push((next, start - 1));
+        }
+        next = end + 1;
+    }
+    if next <= char::MAX as u32 {
+        complement.push((next, char::MAX as u32));
+    }
+    complement
+}
+
+fn difference(ranges: &[(u32, u32)], except: &[(u32, u32)]) -> Vec<(char, char)> {
+    let surrogate = |c: u32| (0xD800..=0xDFFF).contains(&c);
+    let mut difference = vec![];
+    for &(start, end) in ranges {
+        for (other_start, other_end) in complement(except) {
+            let mut start = start.max(other_start);
+            let mut end = end.min(other_end);
+            if surrogate(start) {
+                start = 0xE000;
+            }
+            if surrogate(end) {
+                end = 0xD7FF;
+            }
+            if start <= end {
+                difference.push((char::from_u32(start).unwrap(), char::from_u32(end).unwrap()));
+            }
+        }
+    }
+    difference
+}
+
+// sorted ranges of the characters an expression matches, None if it can
+// also match something else than a single character
+fn char_set(
+    expr: &Expr,
+    rules: &IndexMap<String, Expr>,
+    classes: &[Expr],
+    visiting: &mut Vec<String>,
+) -> Option<Vec<(u32, u32)>> {
+    let ranges = match expr {
+        Expr::Literal(literal) => {
+            let mut chars = literal.chars();
+            let c = chars.next()? as u32;
+            if chars.next().is_some() {
+                return None;
+            }
+            vec![(c, c)]
+        }
+        Expr::Class(negated, ranges) => {
+            let ranges = normalize(
+                ranges
+                    .iter()
+                    .map(|&(start, end)| (start as u32, end as u32))
+                    .collect(),
+            );
+            if *negated {
+                complement(&ranges)
+            } else {
+                ranges
+            }
+        }
+        Expr::Any => vec![(0, char::MAX as u32)],
+        Expr::Ref(name) => match name.strip_prefix(EXCEPTION) {
+            Some(idx) => char_set(
+                &classes[idx.parse::<usize>().ok()?],
+                rules,
+                classes,
+                visiting,
+            )?,
+            None if visiting.contains(name) => return None,
+            None => {
+                visiting.push(name.clone());
+                let ranges = char_set(rules.get(name)?, rules, classes, visiting);
+                visiting.pop();
+                ranges?
+            }
+        },
+        Expr::Alt(items) => items
+            .iter()
+            .map(|e| char_set(e, rules, classes, visiting))
+            .collect::<Option<Vec<_>>>()?
+            .concat(),
+        Expr::Seq(items) if items.len() == 1 => char_set(&items[0], rules, classes, visiting)?,
+        Expr::Seq(_) | Expr::Repeat(..) => return None,
+    };
+    Some(normalize(ranges))
+}
+
+fn replace_exceptions(expr: &mut Expr, classes: &[Expr]) {
+    match expr {
+        Expr::Ref(name) => {
+            if let Some(idx) = name.strip_prefix(EXCEPTION) {
+                let idx: usize = idx.parse().expect("exception index");
+                *expr = classes[idx].clone();
+            }
+        }
+        Expr::Seq(items) | Expr::Alt(items) => items
+            .iter_mut()
+            .for_each(|e| replace_exceptions(e, classes)),
+        Expr::Repeat(inner, ..) => replace_exceptions(inner, classes),
+        Expr::Literal(_) | Expr::Class(..) | Expr::Any => {}
+    }
+}
+
+fn parse_ebnf(ebnf: &str) -> Result<(String, IndexMap<String, Expr>), Box<dyn Error>> {
+    // w3c rules are defined with ::=, iso rules with =
+    let w3c = ebnf.contains("::=");
+    let mut parser = Parser {
+        lexemes: tokenize(ebnf, w3c)?,
+        pos: 0,
+        w3c,
+        exceptions: vec![],
+    };
+    let mut rules = IndexMap::new();
+    while parser.pos < parser.lexemes.len() {
+        let Some(Lexeme::Name(name)) = parser.peek(0).cloned() else {
+            return Err("expected rule name".into());
+        };
+        parser.pos += 1;
+        parser.expect(Lexeme::Define, &format!("after rule name {name}"))?;
+        let expr = parser.alternatives()?;
+        if !w3c {
+            parser.expect(Lexeme::End, &format!("at the end of rule {name}"))?;
+        } else if parser.pos < parser.lexemes.len() && !parser.at_rule_start() {
+            return Err(
+                format!("unexpected {:?} in rule {name}", parser.lexemes[parser.pos]).into(),
+            );
+        }
+        if rules.insert(name.clone(), expr).is_some() {
+            return Err(format!("duplicate rule {name}").into());
+        }
+    }
+    let start = rules.keys().next().ok_or("grammar has no rules")?.clone();
+
+    // nested exceptions come first, so they are resolved before they are used
+    let mut classes = vec![];
+    for (item, except) in &parser.exceptions {
+        let (Some(ranges), Some(except)) = (
+            char_set(item, &rules, &classes, &mut vec![]),
+            char_set(except, &rules, &classes, &mut vec![]),
+        ) else {
+            return Err("exceptions are only supported between sets of single characters".into());
+        };
+        let ranges = difference(&ranges, &except);
+        if ranges.is_empty() {
+            return Err("exception excludes all characters".into());
+        }
+        classes.push(Expr::Class(false, ranges));
+    }
+    for expr in rules.values_mut() {
+        replace_exceptions(expr, &classes);
+    }
+    Ok((start, rules))
+}
+
+// converts a grammar in ISO (name = a, b | c ;) or W3C (Name ::= a b | c)
+// EBNF notation into an LR(1) grammar and lexer, the notation is detected by
+// the ::= of W3C rules and the first rule is the start rule; like for GBNF,
+// regular rules are inlined into tokens that are split by longest match
+pub fn ebnf_to_lr1(ebnf: &str) -> Result<(String, String), Box<dyn Error>> {
+    let (start, rules) = parse_ebnf(ebnf)?;
+    Converter::new(&rules)?.convert(&start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint};
+
+    fn constraint(ebnf: &str) -> LR1GrammarConstraint {
+        let (grammar, lexer) = ebnf_to_lr1(ebnf).unwrap();
+        LR1GrammarConstraint::new(&grammar, &lexer, (0..=255).map(|b| vec![b]).collect()).unwrap()
+    }
+
+    fn assert_matches(lr1: &LR1GrammarConstraint, valid: &[&str], invalid: &[&str]) {
+        for valid in valid {
+            let state = lr1.get_state(valid.as_bytes()).unwrap();
+            assert!(lr1.is_match_state(&state), "{valid}");
+        }
+        for invalid in invalid {
+            assert!(lr1.get_state(invalid.as_bytes()).is_none(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_ebnf_iso() {
+        let lr1 = constraint(
+            r#"
+(* arithmetic, the first rule is the start rule *)
+expression = term, { ( "+" | "-" ), term };
+term = factor, { ( "*" | '/' ), factor };
+factor = number | "(", expression, ")" | "x", 2 * digit;
+number = [ "." ], digit excluding zero, { digit } | "0";
+digit = "0" | digit excluding zero;
+digit excluding zero = "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9";
+"#,
+        );
+        assert_matches(
+            &lr1,
+            &["1", "0", "(1+20)*3", ".5/x01-(((7)))", "x99"],
+            &["01", "1++", "x1+", "(1))", "x123"],
+        );
+
+        assert!(ebnf_to_lr1("a = \"x\"").is_err());
+        assert!(ebnf_to_lr1("a = \"x\" \"y\";").is_err());
+        assert!(ebnf_to_lr1("a = ? letters ?;").is_err());
+        assert!(ebnf_to_lr1("a = b;").is_err());
+        assert!(ebnf_to_lr1("a = [ \"x\";").is_err());
+    }
+
+    #[test]
+    fn test_ebnf_w3c() {
+        let lr1 = constraint(
+            r#"
+/* a tiny subset of xml */
+element ::= '<' Name (S Attribute)* S? '>' content '</' Name S? '>'
+content ::= (CharData | element)*
+Attribute ::= Name Eq '"' ([^<&"] | Reference)* '"'
+Eq ::= S? '=' S?
+Reference ::= '&' Name ';'
+Name ::= NameStartChar (NameStartChar | [0-9])*
+NameStartChar ::= [a-zA-Z_:] | [#xC0-#xD6]
+CharData ::= (Char - [<&])+
+Char ::= #x9 | #xA | #xD | [#x20-#xD7FF] | [#xE000-#x10FFFF]
+S ::= (#x20 | #x9 | #xD | #xA)+
+"#,
+        );
+        assert_matches(
+            &lr1,
+            &[
+                "<a></a>",
+                "<a x = \"1&amp;\">hi <b>\u{e4}</b>!</a >",
+                "<a><b></b><c></c></a>",
+            ],
+            &["<a>x<y</a>", "<a x=1></a>", "<a>&</a>", "<1></1>"],
+        );
+
+        assert!(ebnf_to_lr1("a ::= \"ab\" - \"a\"").is_err());
+        assert!(ebnf_to_lr1("a ::= [ab] - (\"a\" | #x62)").is_err());
+        assert!(ebnf_to_lr1("a ::= (\"b\"").is_err());
+        assert!(ebnf_to_lr1("a ::= \"b\"\na ::= \"c\"").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::{error::Error, fmt::Display};
+
+use crate::ByteConstraint;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeError {
+    // furthest byte position up to which the input could be encoded
+    pub position: usize,
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to encode input with constraint, got stuck at byte position {}",
+            self.position
+        )
+    }
+}
+
+impl Error for EncodeError {}
+
+struct Frame<S> {
+    position: usize,
+    state: S,
+    // sorted by length, longest last
+    candidates: Vec<usize>,
+}
+
+// finds a sequence of continuation indices of the constraint spelling out the
+// given bytes, such that the constraint stays valid after every continuation;
+// longer continuations are tried first, so the result is similar to greedy tokenization
+pub fn encode_with_constraint<C: ByteConstraint + ?Sized>(
+    constraint: &C,
+    bytes: &[u8],
+) -> Result<Vec<usize>, EncodeError> {
+    let continuations = constraint.continuations();
+    let candidates = |state: &C::State, position: usize| {
+        let rest = &bytes[position..];
+        let mut candidates: Vec<_> = constraint
+            .get_valid_continuations(state)
+            .into_iter()
+            .filter(|&c| {
+                let cont = &continuations[c];
+                !cont.is_empty() && rest.starts_with(cont)
+            })
+            .collect();
+        candidates.sort_by_key(|&c| continuations[c].len());
+        candidates
+    };
+    let state = constraint.get_start_state();
+    let mut stack = vec![Frame {
+        position: 0,
+        candidates: candidates(&state, 0),
+        state,
+    }];
+    // the constraint state is determined by the bytes seen so far, so a
+    // position from which encoding failed once will always fail
+    let mut failed = vec![false; bytes.len() + 1];
+    let mut furthest = 0;
+    let mut path = vec![];
+    while let Some(frame) = stack.last_mut() {
+        if frame.position == bytes.len() {
+            return Ok(path);
+        }
+        let Some(c) = frame.candidates.pop() else {
+            failed[frame.position] = true;
+            stack.pop();
+            path.pop();
+            continue;
+        };
+        let position = frame.position + continuations[c].len();
+        if failed[position] {
+            continue;
+        }
+        let Some(state) = constraint.get_next_state(&frame.state, c) else {
+            continue;
+        };
+        furthest = furthest.max(position);
+        path.push(c);
+        stack.push(Frame {
+            position,
+            candidates: candidates(&state, position),
+            state,
+        });
+    }
+    Err(EncodeError { position: furthest })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RegularExpressionConstraint;
+
+    #[test]
+    fn test_encode_with_constraint() {
+        // no continuation for a single c
+        let continuations: Vec<_> = ["a", "b", "ab", "bc", "ca"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let re = RegularExpressionConstraint::new("(ab)+c", continuations).unwrap();
+        assert_eq!(encode_with_constraint(&re, b""), Ok(vec![]));
+        // longest first would end with a single c, needs backtracking
+        assert_eq!(encode_with_constraint(&re, b"abc"), Ok(vec![0, 3]));
+        assert_eq!(encode_with_constraint(&re, b"ababc"), Ok(vec![2, 0, 3]));
+        assert_eq!(
+            encode_with_constraint(&re, b"abca"),
+            Err(EncodeError { position: 3 })
+        );
+        assert_eq!(
+            encode_with_constraint(&re, b"abd"),
+            Err(EncodeError { position: 2 })
+        );
+    }
+}
@@ -0,0 +1,741 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Write,
+    iter::Peekable,
+    str::CharIndices,
+};
+
+use indexmap::IndexMap;
+
+use crate::utils::lexer_pattern;
+
+#[derive(Debug, Clone)]
+pub(crate) enum Expr {
+    Literal(String),
+    Class(bool, Vec<(char, char)>),
+    Any,
+    Ref(String),
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Repeat(Box<Expr>, u32, Option<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Name(String),
+    Define,
+    Literal(String),
+    Class(bool, Vec<(char, char)>),
+    Any,
+    Open,
+    Close,
+    Bar,
+    Repeat(u32, Option<u32>),
+}
+
+pub(crate) fn escaped_char(chars: &mut Peekable<CharIndices>) -> Result<char, Box<dyn Error>> {
+    let (pos, c) = chars.next().ok_or("unexpected end of grammar after \\")?;
+    let hex = |chars: &mut Peekable<CharIndices>, n: usize| -> Result<char, Box<dyn Error>> {
+        let digits: String = (0..n)
+            .filter_map(|_| chars.next().map(|(_, c)| c))
+            .collect();
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .filter(|_| digits.len() == n)
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("invalid escape \\{c}{digits} at position {pos}").into())
+    };
+    Ok(match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'x' => hex(chars, 2)?,
+        'u' => hex(chars, 4)?,
+        'U' => hex(chars, 8)?,
+        c => c,
+    })
+}
+
+fn number(chars: &mut Peekable<CharIndices>) -> Option<u32> {
+    let mut digits = String::new();
+    while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit()) {
+        digits.push(c);
+        chars.next();
+    }
+    digits.parse().ok()
+}
+
+fn tokenize(gbnf: &str) -> Result<Vec<Lexeme>, Box<dyn Error>> {
+    let mut lexemes = vec![];
+    let mut chars = gbnf.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            '#' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            c if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') => {
+                let mut name = String::from(c);
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+                {
+                    name.push(c);
+                }
+                Lexeme::Name(name)
+            }
+            ':' => {
+                if chars.next().map(|(_, c)| c) != Some(':')
+                    || chars.next().map(|(_, c)| c) != Some('=')
+                {
+                    return Err(format!("expected ::= at position {pos}").into());
+                }
+                Lexeme::Define
+            }
+            '"' => {
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => literal.push(escaped_char(&mut chars)?),
+                        Some((_, c)) => literal.push(c),
+                        None => return Err(format!("unterminated string at position {pos}").into()),
+                    }
+                }
+                Lexeme::Literal(literal)
+            }
+            '[' => {
+                let negated = chars.next_if(|&(_, c)| c == '^').is_some();
+                let mut ranges = vec![];
+                loop {
+                    let start = match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((_, '\\')) => escaped_char(&mut chars)?,
+                        Some((_, c)) => c,
+                        None => return Err(format!("unterminated class at position {pos}").into()),
+                    };
+                    let mut end = start;
+                    let mut lookahead = chars.clone();
+                    if lookahead.next().is_some_and(|(_, c)| c == '-')
+                        && lookahead.peek().is_some_and(|&(_, c)| c != ']')
+                    {
+                        chars.next();
+                        end = match chars.next() {
+                            Some((_, '\\')) => escaped_char(&mut chars)?,
+                            Some((_, c)) => c,
+                            None => unreachable!("checked by lookahead"),
+                        };
+                        if end < start {
+                            return Err(format!("invalid range {start}-{end} in class").into());
+                        }
+                    }
+                    ranges.push((start, end));
+                }
+                Lexeme::Class(negated, ranges)
+            }
+            '.' => Lexeme::Any,
+            '(' => Lexeme::Open,
+            ')' => Lexeme::Close,
+            '|' => Lexeme::Bar,
+            '*' => Lexeme::Repeat(0, None),
+            '+' => Lexeme::Repeat(1, None),
+            '?' => Lexeme::Repeat(0, Some(1)),
+            '{' => {
+                let min = number(&mut chars).ok_or(format!("expected number at position {pos}"))?;
+                let max = if chars.next_if(|&(_, c)| c == ',').is_some() {
+                    number(&mut chars)
+                } else {
+                    Some(min)
+                };
+                if chars.next().map(|(_, c)| c) != Some('}') || max.is_some_and(|max| max < min) {
+                    return Err(format!("invalid repetition at position {pos}").into());
+                }
+                Lexeme::Repeat(min, max)
+            }
+            c => return Err(format!("unexpected character {c:?} at position {pos}").into()),
+        };
+        lexemes.push(lexeme);
+    }
+    Ok(lexemes)
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos + offset)
+    }
+
+    fn at_rule_start(&self) -> bool {
+        matches!(self.peek(0), Some(Lexeme::Name(_))) && self.peek(1) == Some(&Lexeme::Define)
+    }
+
+    fn alternatives(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek(0) == Some(&Lexeme::Bar) {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Alt(alternatives)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut items = vec![];
+        // a rule ends where the next one starts
+        while !self.at_rule_start() {
+            let item = match self.peek(0).cloned() {
+                None | Some(Lexeme::Bar | Lexeme::Close) => break,
+                Some(Lexeme::Name(name)) => Expr::Ref(name),
+                Some(Lexeme::Literal(literal)) => Expr::Literal(literal),
+                Some(Lexeme::Class(negated, ranges)) => Expr::Class(negated, ranges),
+                Some(Lexeme::Any) => Expr::Any,
+                Some(Lexeme::Open) => {
+                    self.pos += 1;
+                    let inner = self.alternatives()?;
+                    if self.peek(0) != Some(&Lexeme::Close) {
+                        return Err("missing closing parenthesis".into());
+                    }
+                    inner
+                }
+                Some(Lexeme::Define) => return Err("unexpected ::=".into()),
+                Some(Lexeme::Repeat(..)) => {
+                    return Err("repetition operator without preceding item".into())
+                }
+            };
+            self.pos += 1;
+            let mut item = item;
+            while let Some(&Lexeme::Repeat(min, max)) = self.peek(0) {
+                self.pos += 1;
+                item = Expr::Repeat(Box::new(item), min, max);
+            }
+            items.push(item);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+}
+
+fn parse_gbnf(gbnf: &str) -> Result<IndexMap<String, Expr>, Box<dyn Error>> {
+    let mut parser = Parser {
+        lexemes: tokenize(gbnf)?,
+        pos: 0,
+    };
+    let mut rules = IndexMap::new();
+    while parser.pos < parser.lexemes.len() {
+        let Some(Lexeme::Name(name)) = parser.peek(0).cloned() else {
+            return Err("expected rule name".into());
+        };
+        if !parser.at_rule_start() {
+            return Err(format!("expected ::= after rule name {name}").into());
+        }
+        parser.pos += 2;
+        let expr = parser.alternatives()?;
+        if parser.pos < parser.lexemes.len() && !parser.at_rule_start() {
+            return Err(
+                format!("unexpected {:?} in rule {name}", parser.lexemes[parser.pos]).into(),
+            );
+        }
+        if rules.insert(name.clone(), expr).is_some() {
+            return Err(format!("duplicate rule {name}").into());
+        }
+    }
+    Ok(rules)
+}
+
+pub(crate) fn references<'e>(expr: &'e Expr, refs: &mut Vec<&'e str>) {
+    match expr {
+        Expr::Ref(name) => refs.push(name),
+        Expr::Seq(items) | Expr::Alt(items) => items.iter().for_each(|e| references(e, refs)),
+        Expr::Repeat(inner, ..) => references(inner, refs),
+        Expr::Literal(_) | Expr::Class(..) | Expr::Any => {}
+    }
+}
+
+pub(crate) fn escape_char(c: char) -> String {
+    if c.is_ascii_alphanumeric() {
+        c.to_string()
+    } else {
+        // lowercase hex, so the escape is never mistaken for a {FRAGMENT}
+        format!("\\x{{{:x}}}", c as u32)
+    }
+}
+
+pub(crate) fn repetition(min: u32, max: Option<u32>) -> String {
+    match (min, max) {
+        (0, None) => "*".to_string(),
+        (1, None) => "+".to_string(),
+        (0, Some(1)) => "?".to_string(),
+        (min, None) => format!("{{{min},}}"),
+        (min, Some(max)) if min == max => format!("{{{min}}}"),
+        (min, Some(max)) => format!("{{{min},{max}}}"),
+    }
+}
+
+pub(crate) struct Converter<'a> {
+    rules: &'a IndexMap<String, Expr>,
+    // rules that only reference other regular rules, without recursion;
+    // they are inlined into the token patterns
+    regular: HashSet<&'a str>,
+    names: HashMap<&'a str, String>,
+    tokens: Vec<(String, String)>,
+    symbols: HashMap<String, String>,
+    optional: HashMap<String, String>,
+    output: Vec<(String, Vec<Vec<String>>)>,
+    helpers: HashMap<String, usize>,
+}
+
+impl<'a> Converter<'a> {
+    pub(crate) fn new(rules: &'a IndexMap<String, Expr>) -> Result<Self, Box<dyn Error>> {
+        let mut refs = HashMap::new();
+        for (name, expr) in rules {
+            let mut rule_refs = vec![];
+            references(expr, &mut rule_refs);
+            if let Some(missing) = rule_refs.iter().find(|r| !rules.contains_key(**r)) {
+                return Err(format!("rule {missing} referenced in {name} is not defined").into());
+            }
+            refs.insert(name.as_str(), rule_refs);
+        }
+
+        // a rule is regular if all rules reachable from it are non-recursive
+        fn is_regular<'a>(
+            name: &'a str,
+            refs: &HashMap<&'a str, Vec<&'a str>>,
+            visiting: &mut Vec<&'a str>,
+            known: &mut HashMap<&'a str, bool>,
+        ) -> bool {
+            if let Some(&regular) = known.get(name) {
+                return regular;
+            } else if visiting.contains(&name) {
+                return false;
+            }
+            visiting.push(name);
+            let regular = refs[name]
+                .iter()
+                .all(|r| is_regular(r, refs, visiting, known));
+            visiting.pop();
+            known.insert(name, regular);
+            regular
+        }
+        let mut known = HashMap::new();
+        let regular = rules
+            .keys()
+            .filter(|name| is_regular(name, &refs, &mut vec![], &mut known))
+            .map(String::as_str)
+            .collect();
+
+        let mut names = HashMap::new();
+        let mut taken = HashSet::new();
+        for name in rules.keys() {
+            let mut sanitized = name.replace(['-', ' '], "_");
+            if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                sanitized.insert_str(0, "r_");
+            }
+            while !taken.insert(sanitized.clone()) {
+                sanitized.push('_');
+            }
+            names.insert(name.as_str(), sanitized);
+        }
+
+        Ok(Self {
+            rules,
+            regular,
+            names,
+            tokens: vec![],
+            symbols: HashMap::new(),
+            optional: HashMap::new(),
+            output: vec![],
+            helpers: HashMap::new(),
+        })
+    }
+
+    fn is_regular(&self, expr: &Expr) -> bool {
+        let mut refs = vec![];
+        references(expr, &mut refs);
+        refs.iter().all(|r| self.regular.contains(r))
+    }
+
+    fn nullable(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Literal(literal) => literal.is_empty(),
+            Expr::Class(..) | Expr::Any => false,
+            Expr::Ref(name) => self.nullable(&self.rules[name]),
+            Expr::Seq(items) => items.iter().all(|e| self.nullable(e)),
+            Expr::Alt(items) => items.iter().any(|e| self.nullable(e)),
+            Expr::Repeat(inner, min, _) => *min == 0 || self.nullable(inner),
+        }
+    }
+
+    fn regex(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Literal(literal) => literal.chars().map(escape_char).collect(),
+            Expr::Class(negated, ranges) => {
+                let mut class = String::from(if *negated { "[^" } else { "[" });
+                for &(start, end) in ranges {
+                    class.push_str(&escape_char(start));
+                    if end != start {
+                        class.push('-');
+                        class.push_str(&escape_char(end));
+                    }
+                }
+                class.push(']');
+                class
+            }
+            Expr::Any => "(?s:.)".to_string(),
+            Expr::Ref(name) => format!("(?:{})", self.regex(&self.rules[name])),
+            Expr::Seq(items) => items
+                .iter()
+                .map(|e| format!("(?:{})", self.regex(e)))
+                .collect(),
+            Expr::Alt(items) => items
+                .iter()
+                .map(|e| self.regex(e))
+                .collect::<Vec<_>>()
+                .join("|"),
+            Expr::Repeat(inner, min, max) => {
+                format!("(?:{}){}", self.regex(inner), repetition(*min, *max))
+            }
+        }
+    }
+
+    // regex for the non-empty matches of a regular expression,
+    // None if it only matches the empty string
+    fn non_empty(&self, expr: &Expr) -> Option<String> {
+        if !self.nullable(expr) {
+            return Some(self.regex(expr));
+        }
+        match expr {
+            Expr::Literal(_) => None,
+            Expr::Ref(name) => self.non_empty(&self.rules[name]),
+            Expr::Seq(items) => {
+                let alternatives: Vec<_> = (0..items.len())
+                    .filter_map(|i| {
+                        let first = self.non_empty(&items[i])?;
+                        let rest: String = items[i + 1..]
+                            .iter()
+                            .map(|e| format!("(?:{})", self.regex(e)))
+                            .collect();
+                        Some(format!("(?:{first}){rest}"))
+                    })
+                    .collect();
+                (!alternatives.is_empty()).then(|| alternatives.join("|"))
+            }
+            Expr::Alt(items) => {
+                let alternatives: Vec<_> = items.iter().filter_map(|e| self.non_empty(e)).collect();
+                (!alternatives.is_empty()).then(|| alternatives.join("|"))
+            }
+            // a nullable repetition is equivalent to one of inner{0,max}
+            Expr::Repeat(inner, _, max) => {
+                if *max == Some(0) {
+                    return None;
+                }
+                let first = self.non_empty(inner)?;
+                Some(format!(
+                    "(?:{first})(?:{}){}",
+                    self.regex(inner),
+                    repetition(0, max.map(|max| max - 1))
+                ))
+            }
+            Expr::Class(..) | Expr::Any => unreachable!("never nullable"),
+        }
+    }
+
+    fn token(&mut self, pattern: String, run: &[&Expr]) -> String {
+        if let Some(symbol) = self.symbols.get(&pattern) {
+            return symbol.clone();
+        }
+        let identifier = |s: &str| {
+            let mut chars = s.chars();
+            chars.next().is_some_and(|c| c.is_ascii_uppercase())
+                && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        };
+        let symbol = match run {
+            // literals without whitespace or quotes are used directly like
+            // in handwritten grammars, unless they look like token names
+            [Expr::Literal(literal)]
+                if !identifier(literal)
+                    && !literal.chars().any(|c| {
+                        c.is_whitespace() || c.is_control() || matches!(c, '\'' | '"' | '\\')
+                    }) =>
+            {
+                format!("'{literal}'")
+            }
+            _ => {
+                let base = match run {
+                    [Expr::Ref(name)] => self.names[name.as_str()].to_uppercase(),
+                    _ => "T".to_string(),
+                };
+                let mut name = base.clone();
+                let mut i = 0;
+                while name == "T" || self.tokens.iter().any(|(n, _)| *n == name) {
+                    name = format!("{base}{i}");
+                    i += 1;
+                }
+                self.tokens.push((name.clone(), pattern.clone()));
+                format!("'{name}'")
+            }
+        };
+        self.symbols.insert(pattern, symbol.clone());
+        symbol
+    }
+
+    fn flush(&mut self, run: &mut Vec<&Expr>, symbols: &mut Vec<String>) {
+        if run.is_empty() {
+            return;
+        }
+        let expr = Expr::Seq(run.iter().map(|&e| e.clone()).collect());
+        if !self.nullable(&expr) {
+            let pattern = self.regex(&expr);
+            symbols.push(self.token(pattern, run));
+        } else if let Some(pattern) = self.non_empty(&expr) {
+            // tokens cannot be empty, so the empty match becomes an empty rule
+            let token = self.token(pattern, run);
+            let optional = match self.optional.get(&token) {
+                Some(optional) => optional.clone(),
+                None => {
+                    let name = self.helper("opt");
+                    self.output
+                        .push((name.clone(), vec![vec![], vec![token.clone()]]));
+                    self.optional.insert(token, name.clone());
+                    name
+                }
+            };
+            symbols.push(optional);
+        }
+        run.clear();
+    }
+
+    fn helper(&mut self, owner: &str) -> String {
+        let count = self.helpers.entry(owner.to_string()).or_default();
+        *count += 1;
+        format!("{owner}__{count}")
+    }
+
+    fn sequence(&mut self, expr: &'a Expr, owner: &str) -> Vec<String> {
+        let items = match expr {
+            Expr::Seq(items) => items.iter().collect(),
+            expr => vec![expr],
+        };
+        let mut symbols = vec![];
+        let mut run = vec![];
+        for item in items {
+            if self.is_regular(item) {
+                run.push(item);
+                continue;
+            }
+            self.flush(&mut run, &mut symbols);
+            symbols.push(self.symbol(item, owner));
+        }
+        self.flush(&mut run, &mut symbols);
+        symbols
+    }
+
+    fn alternatives(&mut self, expr: &'a Expr, owner: &str) -> Vec<Vec<String>> {
+        match expr {
+            Expr::Alt(items) => items.iter().map(|e| self.sequence(e, owner)).collect(),
+            expr => vec![self.sequence(expr, owner)],
+        }
+    }
+
+    // symbol for a non-regular expression
+    fn symbol(&mut self, expr: &'a Expr, owner: &str) -> String {
+        match expr {
+            Expr::Ref(name) => self.names[name.as_str()].clone(),
+            Expr::Repeat(inner, min, max) => {
+                let inner = self.symbol(inner, owner);
+                let name = self.helper(owner);
+                let alternatives = match max {
+                    Some(max) => (*min..=*max)
+                        .map(|n| vec![inner.clone(); n as usize])
+                        .collect(),
+                    None => {
+                        let star = self.helper(owner);
+                        self.output.push((
+                            star.clone(),
+                            vec![vec![], vec![star.clone(), inner.clone()]],
+                        ));
+                        let mut alternative = vec![inner; *min as usize];
+                        alternative.push(star);
+                        vec![alternative]
+                    }
+                };
+                self.output.push((name.clone(), alternatives));
+                name
+            }
+            expr => {
+                let name = self.helper(owner);
+                let alternatives = self.alternatives(expr, owner);
+                self.output.push((name.clone(), alternatives));
+                name
+            }
+        }
+    }
+
+    pub(crate) fn convert(mut self, start: &str) -> Result<(String, String), Box<dyn Error>> {
+        let start = self
+            .rules
+            .get_key_value(start)
+            .ok_or_else(|| format!("grammar has no {start} rule"))?
+            .0
+            .as_str();
+        let mut todo = vec![start];
+        let mut seen: HashSet<&str> = todo.iter().copied().collect();
+        while let Some(name) = todo.pop() {
+            let owner = self.names[name].clone();
+            let expr = &self.rules[name];
+            let alternatives = if name == start || !self.regular.contains(name) {
+                self.alternatives(expr, &owner)
+            } else {
+                continue;
+            };
+            self.output.push((owner, alternatives));
+            let mut refs = vec![];
+            references(expr, &mut refs);
+            for r in refs {
+                if !self.regular.contains(r) && seen.insert(r) {
+                    todo.push(r);
+                }
+            }
+        }
+
+        let start = self.names[start].clone();
+        let mut grammar = format!("%start {start}\n\n%%\n");
+        // start first, the other rules in order of creation
+        let start_idx = self.output.iter().position(|(n, _)| *n == start).unwrap();
+        let start_rule = self.output.remove(start_idx);
+        for (name, alternatives) in std::iter::once(&start_rule).chain(&self.output) {
+            let alternatives: Vec<_> = alternatives.iter().map(|a| a.join(" ")).collect();
+            write!(
+                grammar,
+                "\n{name}\n    : {}\n    ;\n",
+                alternatives.join("\n    | ")
+            )?;
+        }
+
+        // gbnf and ebnf alternatives are unordered
+        let mut lexer = String::from("%longest\n\n%%\n\n");
+        for (name, pattern) in &self.tokens {
+            writeln!(lexer, "{name} {}", lexer_pattern(pattern))?;
+        }
+        Ok((grammar, lexer))
+    }
+}
+
+// converts a grammar in the GBNF format of llama.cpp into an LR(1) grammar and
+// lexer; rules that are regular are inlined into tokens, and consecutive
+// regular items of a sequence are merged into a single token, which works as
+// long as the lexer can split the input into these tokens by longest match
+pub fn gbnf_to_lr1(gbnf: &str) -> Result<(String, String), Box<dyn Error>> {
+    let rules = parse_gbnf(gbnf)?;
+    Converter::new(&rules)?.convert("root")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint};
+
+    fn byte_continuations() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    fn constraint(gbnf: &str) -> LR1GrammarConstraint {
+        let (grammar, lexer) = gbnf_to_lr1(gbnf).unwrap();
+        LR1GrammarConstraint::new(&grammar, &lexer, byte_continuations()).unwrap()
+    }
+
+    #[test]
+    fn test_gbnf_json() {
+        // json.gbnf from llama.cpp
+        let lr1 = constraint(
+            r#"
+root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4}) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+# Optional space: by convention, applied in transitions between tokens
+ws ::= | " " | "\n" [ \t]{0,20}
+"#,
+        );
+        for valid in [
+            &b"{}"[..],
+            b"{\"a\": [1, -2.5e3, true, null], \"b\": {\"c\": \"\\u00e4\"}}",
+            b"{\n  \"a\":\"b\"\n} ",
+        ] {
+            let state = lr1.get_state(valid).unwrap();
+            assert!(
+                lr1.is_match_state(&state),
+                "{}",
+                String::from_utf8_lossy(valid)
+            );
+        }
+        assert!(!lr1.is_match_state(&lr1.get_state(b"{\"a\": [1, 2").unwrap()));
+        for invalid in [&b"[1]"[..], b"{\"a\" 1}", b"{\"a\": 01}", b"{,}"] {
+            assert!(lr1.get_state(invalid).is_none());
+        }
+    }
+
+    #[test]
+    fn test_gbnf_features() {
+        let lr1 = constraint(
+            r#"
+# comma separated list of words or nested lists
+root ::= list-item{1,3} "."
+list-item ::= ( word | "(" root-list ")" ) sep?
+root-list ::= list-item+
+word ::= [a-zA-Z_]+ | "\u00e4"
+sep ::= "," " "*
+"#,
+        );
+        for valid in [&b"a."[..], b"a, b,c.", "(\u{e4}, x)y.".as_bytes()] {
+            let state = lr1.get_state(valid).unwrap();
+            assert!(
+                lr1.is_match_state(&state),
+                "{}",
+                String::from_utf8_lossy(valid)
+            );
+        }
+        assert!(lr1.get_state(b"a b c d.").is_none());
+        assert!(lr1.get_state(b"a, 1").is_none());
+
+        // any character and escapes
+        let lr1 = constraint(r#"root ::= "<" . "\x3e" [\]-]"#);
+        assert!(lr1.is_match_state(&lr1.get_state(b"<\n>]").unwrap()));
+        assert!(lr1.is_match_state(&lr1.get_state(b"<x>-").unwrap()));
+        assert!(lr1.get_state(b"<x>a").is_none());
+
+        assert!(gbnf_to_lr1("start ::= \"a\"").is_err());
+        assert!(gbnf_to_lr1("root ::= a").is_err());
+        assert!(gbnf_to_lr1("root ::= (\"a\"").is_err());
+        assert!(gbnf_to_lr1("root ::= \"a\"\nroot ::= \"b\"").is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::read_to_string,
+    mem::size_of,
+    path::Path,
+    time::Instant,
+};
+
+use cfgrammar::{
+    yacc::{YaccGrammar, YaccKind, YaccOriginalActionKind},
+    PIdx, TIdx,
+};
+use lrtable::{Action, StIdx, StateTable};
+use regex_automata::util::primitives::StateID;
+
+use crate::{
+    limits::CompileLimits,
+    lr1::{
+        advance_lexer, build_table, eoi_terminals, grammar_memory_usage, initial_prefix_matches,
+        is_valid_remainder, load_grammar_and_pdfas, valid_continuations_in_order, Matching,
+        PdfaList, TokenNames,
+    },
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+// default upper bound on the parser stacks kept alive at once; only reached
+// by highly ambiguous grammars, use the Earley constraint for those
+pub const DEFAULT_MAX_STACKS: usize = 1024;
+
+type Stack = Vec<StIdx<u32>>;
+
+// reduces dropped from the table when resolving conflicts,
+// by state and lookahead terminal
+type Conflicts = HashMap<(StIdx<u32>, TIdx<u32>), Vec<PIdx<u32>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GLRState {
+    // sorted and without duplicates, so equal states compare equal
+    stacks: Vec<Stack>,
+    matching: Matching,
+    overflowed: bool,
+}
+
+impl GLRState {
+    // whether stacks were dropped to stay within the stack limit since the
+    // start state; continuations of such a state, and of all states after it,
+    // are only those of the stacks that were kept, so valid ones may be missing
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+impl MemoryUsage for GLRState {
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self
+                .stacks
+                .iter()
+                .map(|stack| size_of::<Stack>() + stack.capacity() * size_of::<StIdx<u32>>())
+                .sum::<usize>()
+            + self.matching.capacity() * size_of::<(usize, StateID)>()
+    }
+}
+
+// constraint for grammars with shift reduce or reduce reduce conflicts; the
+// LR(1) constraints resolve those like Yacc, in favor of the shift or the
+// earlier production, and so reject some valid inputs, here every conflicting
+// action is followed with its own parser stack instead, and a continuation
+// is valid if any stack accepts it
+pub struct GLRGrammarConstraint {
+    grammar: YaccGrammar<u32>,
+    table: StateTable<u32>,
+    num_states: usize,
+    conflicts: Conflicts,
+    pdfas: PdfaList,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+    token_names: TokenNames,
+    max_stacks: usize,
+}
+
+impl GLRGrammarConstraint {
+    pub fn new(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_limits(grammar, tokens, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let (grammar, pdfas, _, token_names) = load_grammar_and_pdfas(
+            grammar,
+            YaccKind::Original(YaccOriginalActionKind::NoAction),
+            tokens,
+            limits,
+            start,
+            &mut |_| {},
+        )?;
+        let (grammar, table, num_states) = build_table(grammar, limits, start, &mut |_| {})?;
+        let mut conflicts = Conflicts::new();
+        if let Some(resolved) = table.conflicts() {
+            for &(tidx, pidx, stidx) in resolved.sr_conflicts() {
+                conflicts.entry((stidx, tidx)).or_default().push(pidx);
+            }
+            for &(tidx, _, pidx, stidx) in resolved.rr_conflicts() {
+                conflicts.entry((stidx, tidx)).or_default().push(pidx);
+            }
+        }
+        for pidxs in conflicts.values_mut() {
+            pidxs.sort();
+            pidxs.dedup();
+        }
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            grammar,
+            table,
+            num_states,
+            conflicts,
+            pdfas,
+            continuations,
+            permutation,
+            skips,
+            token_names,
+            max_stacks: DEFAULT_MAX_STACKS,
+        })
+    }
+
+    pub fn from_files(
+        grammar_path: impl AsRef<Path>,
+        tokens_path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(grammar_path.as_ref())?;
+        let grammar = read_to_string(file)?;
+        let file = File::open(tokens_path.as_ref())?;
+        let tokens = read_to_string(file)?;
+        Self::new(&grammar, &tokens, continuations)
+    }
+
+    // keeps at most max_stacks parser stacks alive at once, more are dropped
+    // and the state is marked as overflowed, see GLRState::overflowed
+    pub fn with_max_stacks(mut self, max_stacks: usize) -> Self {
+        self.max_stacks = max_stacks.max(1);
+        self
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // number of table entries with conflicting actions, zero if
+    // the grammar is LR(1) and the LR(1) constraints suffice
+    pub fn num_conflicts(&self) -> usize {
+        self.conflicts.len()
+    }
+
+    // display names of the terminals any parser stack accepts next, including
+    // the one currently being lexed; uses %token aliases if given
+    pub fn expected_terminals(&self, state: &GLRState) -> Vec<&str> {
+        self.grammar
+            .iter_tidxs()
+            .filter(|&tidx| {
+                if tidx == self.grammar.eof_token_idx() {
+                    self.accepts(&state.stacks)
+                } else {
+                    self.expects(&state.stacks, tidx)
+                }
+            })
+            .map(|tidx| self.token_names[usize::from(tidx)].as_str())
+            .collect()
+    }
+
+    fn actions(
+        &self,
+        stidx: StIdx<u32>,
+        tidx: TIdx<u32>,
+    ) -> impl Iterator<Item = Action<u32>> + '_ {
+        let conflicts = self
+            .conflicts
+            .get(&(stidx, tidx))
+            .map_or(&[][..], |pidxs| pidxs.as_slice());
+        std::iter::once(self.table.action(stidx, tidx))
+            .chain(conflicts.iter().map(|&pidx| Action::Reduce(pidx)))
+    }
+
+    // all stacks after shifting the terminal, following every reduce before it,
+    // and whether any of them accepts; sets overflowed if reduces were dropped
+    fn step(
+        &self,
+        stack: &Stack,
+        tidx: TIdx<u32>,
+        shifted: &mut Vec<Stack>,
+        overflowed: &mut bool,
+    ) -> bool {
+        let mut accept = false;
+        let mut seen = HashSet::new();
+        let mut pending = vec![stack.clone()];
+        while let Some(stack) = pending.pop() {
+            let Some(&stidx) = stack.last() else {
+                continue;
+            };
+            for action in self.actions(stidx, tidx) {
+                match action {
+                    Action::Shift(next) => {
+                        let mut next_stack = stack.clone();
+                        next_stack.push(next);
+                        shifted.push(next_stack);
+                    }
+                    Action::Reduce(pidx) => {
+                        let len = self.grammar.prod(pidx).len();
+                        if len >= stack.len() {
+                            continue;
+                        }
+                        let mut next_stack = stack[..stack.len() - len].to_vec();
+                        let ridx = self.grammar.prod_to_rule(pidx);
+                        let Some(next) = self.table.goto(*next_stack.last().unwrap(), ridx) else {
+                            continue;
+                        };
+                        next_stack.push(next);
+                        if seen.contains(&next_stack) {
+                            continue;
+                        } else if seen.len() >= self.max_stacks {
+                            *overflowed = true;
+                        } else {
+                            seen.insert(next_stack.clone());
+                            pending.push(next_stack);
+                        }
+                    }
+                    Action::Accept => accept = true,
+                    Action::Error => {}
+                }
+            }
+        }
+        accept
+    }
+
+    fn shift(
+        &self,
+        stacks: &[Stack],
+        tidx: TIdx<u32>,
+        overflowed: &mut bool,
+    ) -> Option<Vec<Stack>> {
+        let mut shifted = vec![];
+        for stack in stacks {
+            self.step(stack, tidx, &mut shifted, overflowed);
+        }
+        shifted.sort();
+        shifted.dedup();
+        if shifted.len() > self.max_stacks {
+            shifted.truncate(self.max_stacks);
+            *overflowed = true;
+        }
+        (!shifted.is_empty()).then_some(shifted)
+    }
+
+    // the stacks are not changed, so dropped reduces need no flag here
+    fn expects(&self, stacks: &[Stack], tidx: TIdx<u32>) -> bool {
+        stacks.iter().any(|stack| {
+            let mut shifted = vec![];
+            self.step(stack, tidx, &mut shifted, &mut false);
+            !shifted.is_empty()
+        })
+    }
+
+    fn accepts(&self, stacks: &[Stack]) -> bool {
+        let eof = self.grammar.eof_token_idx();
+        stacks
+            .iter()
+            .any(|stack| self.step(stack, eof, &mut vec![], &mut false))
+    }
+
+    fn is_valid_matching(&self, stacks: &[Stack], matching: &Matching) -> bool {
+        is_valid_remainder(
+            &self.pdfas,
+            matching,
+            self.grammar.eof_token_idx(),
+            |tidx| self.expects(stacks, tidx),
+        )
+    }
+}
+
+impl MemoryUsage for GLRGrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        grammar_memory_usage(&self.grammar, self.num_states, &self.pdfas)
+            + self
+                .conflicts
+                .values()
+                .map(|pidxs| {
+                    size_of::<((StIdx<u32>, TIdx<u32>), Vec<PIdx<u32>>)>()
+                        + pidxs.capacity() * size_of::<PIdx<u32>>()
+                })
+                .sum::<usize>()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for GLRGrammarConstraint {
+    type State = GLRState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.get_next_state_with_bytes(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        GLRState {
+            stacks: vec![vec![self.table.start_state()]],
+            matching: initial_prefix_matches(&self.pdfas),
+            overflowed: false,
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        eoi_terminals(&self.pdfas, &state.matching).any(|tidx| {
+            self.shift(&state.stacks, tidx, &mut false)
+                .is_some_and(|stacks| self.accepts(&stacks))
+        })
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        valid_continuations_in_order(
+            &self.continuations,
+            &self.permutation,
+            &self.skips,
+            |cont| self.get_next_state_with_bytes(state, cont).is_some(),
+        )
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+}
+
+impl ByteConstraint for GLRGrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        let mut overflowed = state.overflowed;
+        let (stacks, matching) = advance_lexer(
+            bytes,
+            &self.pdfas,
+            &state.matching,
+            state.stacks.clone(),
+            |stacks, tidx| self.shift(&stacks, tidx, &mut overflowed),
+        )?;
+        if !self.is_valid_matching(&stacks, &matching) {
+            return None;
+        }
+        Some(GLRState {
+            stacks,
+            matching,
+            overflowed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EarleyGrammarConstraint, LR1GrammarConstraint};
+
+    fn conts() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    #[test]
+    fn test_glr_palindromes() {
+        // the LR(1) table resolves the shift reduce conflicts in favor of
+        // the shift and so misses palindromes of even length
+        let grammar = "%start S\n%%\nS: 'a' S 'a' | 'b' S 'b' | 'a' | 'b' | ;";
+        let glr = GLRGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        let lr1 = LR1GrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        assert!(glr.num_conflicts() > 0);
+        for text in ["a", "aa", "aba", "abba", "baab", "abaaba"] {
+            assert!(glr.check(text.as_bytes()), "{text}");
+        }
+        assert!(!lr1.check(b"abba"));
+        assert!(!glr.check(b"ab"));
+        assert!(!glr.check(b"abbaa"));
+        let state = glr.get_state(b"abb").unwrap();
+        assert_eq!(
+            glr.get_valid_continuations(&state),
+            [b'a' as usize, b'b' as usize]
+        );
+        assert_eq!(glr.expected_terminals(&state), ["'a'", "'b'"]);
+        let state = glr.get_state(b"abba").unwrap();
+        assert!(glr.is_match_state(&state));
+        assert!(!state.overflowed());
+    }
+
+    #[test]
+    fn test_glr_max_stacks() {
+        // every position could be the middle of the palindrome, so the
+        // number of stacks grows with the length of the input
+        let grammar = "%start S\n%%\nS: 'a' S 'a' | 'b' S 'b' | 'a' | 'b' | ;";
+        let glr = GLRGrammarConstraint::new(grammar, "%%\n", conts())
+            .unwrap()
+            .with_max_stacks(2);
+        let state = glr.get_state(b"a").unwrap();
+        assert!(!state.overflowed());
+        let state = glr.get_state(b"aaaa").unwrap();
+        assert!(state.overflowed());
+        // the overflow sticks to all following states
+        let next = glr.get_next_state(&state, b'a' as usize).unwrap();
+        assert!(next.overflowed());
+        // stacks needed for longer palindromes were dropped
+        assert!(!glr.check(b"aaaaaa"));
+
+        let glr = GLRGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        let state = glr.get_state(b"aaaaaa").unwrap();
+        assert!(!state.overflowed());
+        assert!(glr.is_match_state(&state));
+    }
+
+    #[test]
+    fn test_glr_reduce_reduce() {
+        // A and B both derive 'x' followed by 'y', the LR(1) table
+        // always reduces to the earlier A and rejects "xyz"
+        let grammar = "
+%start S
+%%
+S: A 'y' | B 'y' 'z' | B 'w' ;
+A: 'x' ;
+B: 'x' ;
+";
+        let glr = GLRGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        let lr1 = LR1GrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        let earley = EarleyGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        assert!(glr.num_conflicts() > 0);
+        assert!(!lr1.check(b"xyz"));
+        for text in ["x", "xy", "xyz", "xw", "xz", "xyy", "y"] {
+            let text = text.as_bytes();
+            assert_eq!(glr.check(text), earley.check(text));
+            assert_eq!(
+                glr.get_state(text).is_some(),
+                earley.get_state(text).is_some()
+            );
+            if let (Some(g), Some(e)) = (glr.get_state(text), earley.get_state(text)) {
+                assert_eq!(
+                    glr.get_valid_continuations(&g),
+                    earley.get_valid_continuations(&e)
+                );
+            }
+        }
+        // both parses are still alive after "xy"
+        let state = glr.get_state(b"xy").unwrap();
+        assert!(glr.is_match_state(&state));
+        assert_eq!(glr.get_valid_continuations(&state), [b'z' as usize]);
+        assert!(glr.states_equal(b"xy", b"xy"));
+        assert!(!glr.states_equal(b"x", b"xy"));
+    }
+
+    #[test]
+    fn test_glr_json() {
+        // without conflicts it behaves like the LR(1) constraint
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let glr = GLRGrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let lr1 = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        assert_eq!(glr.num_conflicts(), 0);
+        let text = br#"{"a": [1, -2.5e3, {"b": null}], "c": "d"}"#;
+        for len in 0..=text.len() {
+            let prefix = &text[..len];
+            let (Some(g), Some(l)) = (glr.get_state(prefix), lr1.get_state(prefix)) else {
+                panic!("invalid prefix {}", String::from_utf8_lossy(prefix));
+            };
+            assert_eq!(
+                glr.get_valid_continuations(&g),
+                lr1.get_valid_continuations(&l)
+            );
+            assert_eq!(glr.is_match_state(&g), lr1.is_match_state(&l));
+        }
+    }
+}
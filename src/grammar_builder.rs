@@ -0,0 +1,293 @@
+use std::{collections::HashSet, error::Error, fmt::Write};
+
+use indexmap::IndexMap;
+use regex::{escape, Regex};
+
+use crate::{utils::lexer_pattern, LR1GrammarConstraint, LR1GrammarParser};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GrammarSymbol {
+    Rule(String),
+    Token(String),
+    // fixed text, which gets its own token
+    Literal(String),
+}
+
+impl GrammarSymbol {
+    pub fn rule(name: impl Into<String>) -> Self {
+        Self::Rule(name.into())
+    }
+
+    pub fn token(name: impl Into<String>) -> Self {
+        Self::Token(name.into())
+    }
+
+    pub fn literal(text: impl Into<String>) -> Self {
+        Self::Literal(text.into())
+    }
+}
+
+// builds the grammar and lexer definitions of an LR(1) grammar in code,
+// e.g. for grammars generated at runtime, taking care of quoting, escaping
+// and the order of tokens; literals get their own tokens, which come before
+// all other tokens in the lexer, so keywords win over identifiers
+#[derive(Debug, Clone, Default)]
+pub struct GrammarBuilder {
+    start: Option<String>,
+    rules: IndexMap<String, Vec<Vec<GrammarSymbol>>>,
+    fragments: Vec<(String, String)>,
+    tokens: Vec<(String, String)>,
+    ignore: Vec<String>,
+}
+
+impl GrammarBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the first rule is the start rule unless set otherwise
+    pub fn start(mut self, rule: impl Into<String>) -> Self {
+        self.start = Some(rule.into());
+        self
+    }
+
+    // adds alternatives to a rule, an empty alternative matches the empty
+    // string, e.g. rule("list", [vec![], vec![rule("list"), token("ITEM")]])
+    pub fn rule(
+        mut self,
+        name: impl Into<String>,
+        alternatives: impl IntoIterator<Item = impl IntoIterator<Item = GrammarSymbol>>,
+    ) -> Self {
+        self.rules
+            .entry(name.into())
+            .or_default()
+            .extend(alternatives.into_iter().map(|a| a.into_iter().collect()));
+        self
+    }
+
+    // a regular expression that tokens can refer to as {NAME}
+    pub fn fragment(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.fragments.push((name.into(), pattern.into()));
+        self
+    }
+
+    // tokens are tried in the order they are added, the earlier one
+    // wins if two tokens match the same text
+    pub fn token(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.tokens.push((name.into(), pattern.into()));
+        self
+    }
+
+    // text skipped between tokens, e.g. whitespace or comments
+    pub fn ignore(mut self, pattern: impl Into<String>) -> Self {
+        self.ignore.push(pattern.into());
+        self
+    }
+
+    // grammar and lexer definitions in the usual .y and .l formats
+    pub fn build(&self) -> Result<(String, String), Box<dyn Error>> {
+        let rule_name = Regex::new("^[A-Za-z_][A-Za-z0-9_]*$")?;
+        let token_name = Regex::new("^[A-Z][A-Z0-9_]*$")?;
+        let Some(first) = self.rules.keys().next() else {
+            return Err("grammar needs at least one rule".into());
+        };
+        let start = self.start.as_ref().unwrap_or(first);
+        if !self.rules.contains_key(start) {
+            return Err(format!("start rule {start} is not defined").into());
+        }
+        if let Some(name) = self.rules.keys().find(|name| !rule_name.is_match(name)) {
+            return Err(format!("invalid rule name {name}").into());
+        }
+        let mut names = HashSet::new();
+        for (name, _) in self.fragments.iter().chain(&self.tokens) {
+            if !token_name.is_match(name) {
+                return Err(format!(
+                    "invalid token or fragment name {name}, expected uppercase letters, digits and _"
+                )
+                .into());
+            } else if !names.insert(name.as_str()) {
+                return Err(format!("duplicate token or fragment {name}").into());
+            }
+        }
+        let tokens: HashSet<_> = self.tokens.iter().map(|(name, _)| name.as_str()).collect();
+
+        // literal tokens are named LIT0, LIT1, ... skipping taken names
+        let mut literals: IndexMap<&str, String> = IndexMap::new();
+        let mut next = 0;
+        let mut grammar = format!("%start {start}\n\n%%\n");
+        for (name, alternatives) in &self.rules {
+            if alternatives.is_empty() {
+                return Err(format!("rule {name} has no alternatives").into());
+            }
+            let mut bodies = vec![];
+            for alternative in alternatives {
+                let mut body = vec![];
+                for symbol in alternative {
+                    body.push(match symbol {
+                        GrammarSymbol::Rule(rule) if self.rules.contains_key(rule) => rule.clone(),
+                        GrammarSymbol::Rule(rule) => {
+                            return Err(format!("rule {rule} used in {name} is not defined").into())
+                        }
+                        GrammarSymbol::Token(token) if tokens.contains(token.as_str()) => {
+                            format!("'{token}'")
+                        }
+                        GrammarSymbol::Token(token) => {
+                            return Err(
+                                format!("token {token} used in {name} is not defined").into()
+                            )
+                        }
+                        GrammarSymbol::Literal(text) if text.is_empty() => {
+                            return Err(format!("empty literal in {name}").into())
+                        }
+                        GrammarSymbol::Literal(text) => {
+                            if !literals.contains_key(text.as_str()) {
+                                let mut token = format!("LIT{next}");
+                                while names.contains(token.as_str()) {
+                                    next += 1;
+                                    token = format!("LIT{next}");
+                                }
+                                next += 1;
+                                literals.insert(text, token);
+                            }
+                            format!("'{}'", literals[text.as_str()])
+                        }
+                    });
+                }
+                bodies.push(body.join(" "));
+            }
+            write!(
+                grammar,
+                "\n{name}\n    : {}\n    ;\n",
+                bodies.join("\n    | ")
+            )?;
+        }
+
+        let mut lexer = String::new();
+        for (name, pattern) in &self.fragments {
+            writeln!(lexer, "{name} {}", lexer_pattern(pattern))?;
+        }
+        lexer.push_str("\n%%\n\n");
+        for (text, token) in &literals {
+            writeln!(lexer, "{token} {}", lexer_pattern(&escape(text)))?;
+        }
+        for (name, pattern) in &self.tokens {
+            writeln!(lexer, "{name} {}", lexer_pattern(pattern))?;
+        }
+        for pattern in &self.ignore {
+            writeln!(lexer, "; {}", lexer_pattern(pattern))?;
+        }
+        Ok((grammar, lexer))
+    }
+
+    pub fn constraint(
+        &self,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<LR1GrammarConstraint, Box<dyn Error>> {
+        let (grammar, lexer) = self.build()?;
+        LR1GrammarConstraint::new(&grammar, &lexer, continuations)
+    }
+
+    pub fn parser(&self) -> Result<LR1GrammarParser, Box<dyn Error>> {
+        let (grammar, lexer) = self.build()?;
+        LR1GrammarParser::new(&grammar, &lexer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Constraint;
+
+    use GrammarSymbol as S;
+
+    fn calc() -> GrammarBuilder {
+        GrammarBuilder::new()
+            .rule(
+                "stmt",
+                [
+                    vec![
+                        S::literal("let"),
+                        S::token("ID"),
+                        S::literal("="),
+                        S::rule("expr"),
+                    ],
+                    vec![S::rule("expr")],
+                ],
+            )
+            .rule(
+                "expr",
+                [
+                    vec![S::rule("expr"), S::literal("+"), S::rule("term")],
+                    vec![S::rule("term")],
+                ],
+            )
+            .rule(
+                "term",
+                [
+                    vec![S::token("NUM")],
+                    vec![S::token("ID")],
+                    vec![S::literal("("), S::rule("expr"), S::literal(")")],
+                ],
+            )
+            .fragment("DIGIT", "[0-9]")
+            .token("NUM", "{DIGIT}+")
+            .token("ID", "[a-z]+")
+            .ignore("[ \n]+")
+    }
+
+    #[test]
+    fn test_grammar_builder() {
+        let (grammar, lexer) = calc().build().unwrap();
+        assert!(grammar.starts_with("%start stmt\n"));
+        // literals are escaped and come first, so let is a keyword
+        assert!(lexer.contains("LIT2 (?:\\+)\n"));
+        assert!(lexer.find("LIT0").unwrap() < lexer.find("ID").unwrap());
+        // whitespace in patterns does not split them
+        assert!(lexer.ends_with("; (?:[\\x{20}\\x{a}]+)\n"));
+
+        let conts = (0..=255).map(|b| vec![b]).collect();
+        let constraint = calc().constraint(conts).unwrap();
+        assert!(constraint.check(b"let x = (1 + y) + 23"));
+        assert!(constraint.check(b"x+1"));
+        assert!(!constraint.check(b"let let = 1"));
+        assert!(!constraint.check(b"1 +"));
+
+        let parser = calc().parser().unwrap();
+        let parse = parser.parse(b"let a = 1", true, true).unwrap();
+        assert_eq!(parse.name(), "stmt");
+
+        // a different start rule and an empty alternative
+        let conts = (0..=255).map(|b| vec![b]).collect();
+        let list = GrammarBuilder::new()
+            .rule("item", [[S::literal("'")]])
+            .rule("list", [vec![], vec![S::rule("list"), S::rule("item")]])
+            .start("list")
+            .constraint(conts)
+            .unwrap();
+        assert!(list.check(b"'"));
+        assert!(list.check(b"'''"));
+    }
+
+    #[test]
+    fn test_grammar_builder_errors() {
+        assert!(GrammarBuilder::new().build().is_err());
+        assert!(calc().start("missing").build().is_err());
+        assert!(calc().token("ID", "x").build().is_err());
+        assert!(calc().token("lower", "x").build().is_err());
+        assert!(calc().rule("bad-name", [[S::token("ID")]]).build().is_err());
+        assert!(calc()
+            .rule("expr", [[S::token("MISSING")]])
+            .build()
+            .is_err());
+        assert!(calc().rule("expr", [[S::rule("missing")]]).build().is_err());
+        assert!(calc().rule("expr", [[S::literal("")]]).build().is_err());
+        assert!(calc()
+            .rule("empty", [] as [Vec<GrammarSymbol>; 0])
+            .build()
+            .is_err());
+        // a token named like a literal token does not clash with it
+        let (grammar, lexer) = calc().token("LIT0", "x").build().unwrap();
+        assert!(grammar.contains("'LIT1'"));
+        assert!(lexer.contains("LIT1 (?:let)\n"));
+    }
+}
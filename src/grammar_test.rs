@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::{Constraint, LR1GrammarConstraint, LR1GrammarParser};
+
+// test cases of a grammar, usually read from a tests.toml next to it:
+//
+// valid = ["{}", "[1, 2]"]
+// invalid = ["[1 2]"]
+//
+// [[parse]]
+// input = "[1]"
+// skeleton = "(json (value (arr '[' (arr_plus (value NUMBER)) ']')))"
+//
+// [[prefix]]
+// prefix = "[1,"
+// expected = ["','", "']'"]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrammarTests {
+    // inputs the grammar has to accept
+    #[serde(default)]
+    pub valid: Vec<String>,
+    // inputs the grammar has to reject
+    #[serde(default)]
+    pub invalid: Vec<String>,
+    #[serde(default, rename = "parse")]
+    pub parses: Vec<ParseCase>,
+    #[serde(default, rename = "prefix")]
+    pub prefixes: Vec<PrefixCase>,
+}
+
+// input with its expected parse tree, see LR1Parse::skeleton for the format
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParseCase {
+    pub input: String,
+    pub skeleton: String,
+}
+
+// valid prefix with the display names of the terminals the parser accepts at
+// its end, including the one currently being lexed, see expected_terminals
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixCase {
+    pub prefix: String,
+    pub expected: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarTestFailure {
+    pub case: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GrammarTestReport {
+    pub passed: usize,
+    pub failures: Vec<GrammarTestFailure>,
+}
+
+impl GrammarTestReport {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn check(&mut self, case: impl FnOnce() -> String, result: Result<(), String>) {
+        match result {
+            Ok(()) => self.passed += 1,
+            Err(message) => self.failures.push(GrammarTestFailure {
+                case: case(),
+                message,
+            }),
+        }
+    }
+}
+
+impl Display for GrammarTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for failure in &self.failures {
+            writeln!(f, "FAIL {}: {}", failure.case, failure.message)?;
+        }
+        write!(f, "{} passed, {} failed", self.passed, self.failures.len())
+    }
+}
+
+// skeleton with single spaces between nodes, so expected
+// skeletons can be spread over multiple lines
+fn normalize_skeleton(skeleton: &str) -> String {
+    let mut parts = vec![];
+    let mut chars = skeleton.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' => parts.push(c.to_string()),
+            '\'' => {
+                let mut part = String::from(c);
+                for c in chars.by_ref() {
+                    part.push(c);
+                    if c == '\'' {
+                        break;
+                    }
+                }
+                parts.push(part);
+            }
+            c => {
+                let mut part = String::from(c);
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, '(' | ')' | '\''))
+                {
+                    part.push(c);
+                }
+                parts.push(part);
+            }
+        }
+    }
+    let mut normalized = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 && part != ")" && parts[i - 1] != "(" {
+            normalized.push(' ');
+        }
+        normalized.push_str(part);
+    }
+    normalized
+}
+
+impl GrammarTests {
+    pub fn from_toml(toml: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn run(&self, grammar: &str, lexer: &str) -> Result<GrammarTestReport, Box<dyn Error>> {
+        let parser = LR1GrammarParser::new(grammar, lexer)?;
+        // only used for prefixes, so it needs no continuations
+        let constraint = LR1GrammarConstraint::new(grammar, lexer, vec![])?;
+        let mut report = GrammarTestReport::default();
+        for input in &self.valid {
+            let result = parser
+                .parse(input, true, false)
+                .map(|_| ())
+                .map_err(|e| format!("rejected: {e}"));
+            report.check(|| format!("valid {input:?}"), result);
+        }
+        for input in &self.invalid {
+            let result = match parser.parse(input, true, false) {
+                Ok(_) => Err("accepted".to_string()),
+                Err(_) => Ok(()),
+            };
+            report.check(|| format!("invalid {input:?}"), result);
+        }
+        for case in &self.parses {
+            let result = match parser.parse(&case.input, true, false) {
+                Ok(parse) => {
+                    let expected = normalize_skeleton(&case.skeleton);
+                    let actual = parse.skeleton();
+                    if actual == expected {
+                        Ok(())
+                    } else {
+                        Err(format!("expected {expected}, got {actual}"))
+                    }
+                }
+                Err(e) => Err(format!("rejected: {e}")),
+            };
+            report.check(|| format!("parse {:?}", case.input), result);
+        }
+        for case in &self.prefixes {
+            let result = match constraint.get_state(case.prefix.as_bytes()) {
+                Some(state) => {
+                    let expected: BTreeSet<_> = case.expected.iter().map(String::as_str).collect();
+                    let actual: BTreeSet<_> =
+                        constraint.expected_terminals(&state).into_iter().collect();
+                    if actual == expected {
+                        Ok(())
+                    } else {
+                        Err(format!("expected {expected:?}, got {actual:?}"))
+                    }
+                }
+                None => Err("invalid prefix".to_string()),
+            };
+            report.check(|| format!("prefix {:?}", case.prefix), result);
+        }
+        Ok(report)
+    }
+}
+
+// runs the tests.toml of a grammar directory <name> with <name>.y and <name>.l,
+// and checks that the files in examples/ are valid prefixes, like the benchmarks
+// expect them to be
+pub fn run_grammar_tests(dir: impl AsRef<Path>) -> Result<GrammarTestReport, Box<dyn Error>> {
+    let dir = dir.as_ref();
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("invalid grammar directory {}", dir.display()))?;
+    let read = |file: &str| {
+        fs::read_to_string(dir.join(file))
+            .map_err(|e| format!("failed to read {}: {e}", dir.join(file).display()))
+    };
+    let grammar = read(&format!("{name}.y"))?;
+    let lexer = read(&format!("{name}.l"))?;
+    let tests = GrammarTests::from_toml(&read("tests.toml")?)?;
+    let mut report = tests.run(&grammar, &lexer)?;
+
+    let examples = dir.join("examples");
+    if examples.is_dir() {
+        let constraint = LR1GrammarConstraint::new(&grammar, &lexer, vec![])?;
+        let mut paths: Vec<_> = fs::read_dir(examples)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        paths.sort();
+        for path in paths {
+            let example = fs::read_to_string(&path)?;
+            let result =
+                match constraint.get_state(example.trim_end_matches(['\r', '\n']).as_bytes()) {
+                    Some(_) => Ok(()),
+                    None => Err("invalid prefix".to_string()),
+                };
+            report.check(|| format!("example {}", path.display()), result);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_grammar_tests() {
+        let grammar = "%start Expr\n%%\nExpr: Expr '+' Term | Term ;\nTerm: '(' Expr ')' | 'INT' ;";
+        let lexer = "%%\nINT [0-9]+\n; [\\x20]+";
+        let tests = GrammarTests::from_toml(
+            r#"
+valid = ["1", "(1 + 2) + 3"]
+invalid = ["1 +", "1 + 2", "()"]
+
+[[parse]]
+input = "1+2"
+skeleton = """
+(Expr
+  (Expr (Term INT))
+  '+' (Term INT))
+"""
+
+[[parse]]
+input = "(1)"
+skeleton = "(Expr (Term INT))"
+
+[[prefix]]
+prefix = "(1+"
+expected = ["')'", "'+'"]
+
+[[prefix]]
+prefix = "1 1"
+expected = []
+"#,
+        )
+        .unwrap();
+        let report = tests.run(grammar, lexer).unwrap();
+        assert_eq!(report.passed, 6);
+        let cases: Vec<_> = report.failures.iter().map(|f| f.case.as_str()).collect();
+        assert_eq!(
+            cases,
+            ["invalid \"1 + 2\"", "parse \"(1)\"", "prefix \"1 1\""]
+        );
+        assert!(report.to_string().ends_with("6 passed, 3 failed"));
+
+        assert!(GrammarTests::from_toml("valid = [1]").is_err());
+        assert!(GrammarTests::from_toml("vaild = []").is_err());
+    }
+
+    #[test]
+    fn test_grammar_dirs() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("grammars");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.join("tests.toml").exists() {
+                let report = run_grammar_tests(&path).unwrap();
+                assert!(report.is_success(), "{}:\n{report}", path.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::{collections::HashMap, error::Error, fmt::Write};
+
+use cfgrammar::{
+    yacc::{YaccGrammar, YaccKind, YaccOriginalActionKind},
+    RIdx, Symbol, TIdx,
+};
+use itertools::Itertools;
+use regex::{escape, Regex};
+use serde_json::{json, Value};
+
+use crate::{
+    lr1::{extract_token_aliases, format_yacc_error, parse_lexer, LexerSpec},
+    utils::{lexer_pattern, pattern_from_parts},
+};
+
+// nodes and values in the guidance json format are externally tagged,
+// i.e. objects with a single key naming the variant
+fn variant(value: &Value) -> Result<(&str, &Value), Box<dyn Error>> {
+    match value {
+        Value::Object(obj) if obj.len() == 1 => {
+            let (kind, body) = obj.iter().next().unwrap();
+            Ok((kind, body))
+        }
+        Value::String(kind) => Ok((kind, &Value::Null)),
+        _ => Err(format!("expected a single variant, got {value}").into()),
+    }
+}
+
+fn index(value: &Value, len: usize, what: &str) -> Result<usize, Box<dyn Error>> {
+    value
+        .as_u64()
+        .map(|i| i as usize)
+        .filter(|&i| i < len)
+        .ok_or_else(|| format!("invalid {what} id {value}").into())
+}
+
+fn regex_node(rx_nodes: &[Value], id: usize) -> Result<String, Box<dyn Error>> {
+    let (kind, body) = variant(&rx_nodes[id])?;
+    let child = |value: &Value| regex_node(rx_nodes, index(value, rx_nodes.len(), "regex node")?);
+    let children = |value: &Value| -> Result<Vec<String>, Box<dyn Error>> {
+        value
+            .as_array()
+            .ok_or_else(|| format!("expected regex node ids for {kind}"))?
+            .iter()
+            .map(child)
+            .collect()
+    };
+    Ok(match kind {
+        "EmptyString" => String::new(),
+        "Regex" => format!("(?:{})", body.as_str().ok_or("expected string for Regex")?),
+        "Literal" => escape(body.as_str().ok_or("expected string for Literal")?),
+        "Byte" => format!(
+            "(?-u:\\x{:02X})",
+            body.as_u64().filter(|&b| b < 256).ok_or("invalid Byte")?
+        ),
+        "ByteSet" => {
+            // 256 bit mask as 8 u32 words
+            let words = body.as_array().ok_or("expected words for ByteSet")?;
+            let mut class = String::new();
+            for b in 0..256usize {
+                let word = words.get(b / 32).and_then(Value::as_u64).unwrap_or(0);
+                if word & (1 << (b % 32)) != 0 {
+                    write!(class, "\\x{b:02X}")?;
+                }
+            }
+            format!("(?-u:[{class}])")
+        }
+        "Concat" => children(body)?
+            .into_iter()
+            .map(|c| format!("(?:{c})"))
+            .collect(),
+        "Or" => format!("(?:{})", children(body)?.join("|")),
+        "Repeat" => {
+            let args = body
+                .as_array()
+                .filter(|a| a.len() == 3)
+                .ok_or("invalid Repeat")?;
+            let min = args[1].as_u64().ok_or("invalid Repeat minimum")?;
+            let max = args[2]
+                .as_u64()
+                .map(|max| max.to_string())
+                .unwrap_or_default();
+            format!("(?:{}){{{min},{max}}}", child(&args[0])?)
+        }
+        _ => return Err(format!("unsupported regex node {kind}").into()),
+    })
+}
+
+fn regex_spec(spec: &Value, rx_nodes: &[Value]) -> Result<String, Box<dyn Error>> {
+    match spec {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(_) => regex_node(rx_nodes, index(spec, rx_nodes.len(), "regex node")?),
+        _ => Err(format!("unsupported regex spec {spec}").into()),
+    }
+}
+
+// converts a grammar in the json format of guidance / llguidance into an
+// LR(1) grammar and lexer; supported are grammars with String, Join, Select,
+// Lexeme and Gen (without stop regex) nodes, node 0 is the start node
+pub fn guidance_to_lr1(json: &str) -> Result<(String, String), Box<dyn Error>> {
+    let value: Value = serde_json::from_str(json)?;
+    let grammar = match value.get("grammars").and_then(Value::as_array) {
+        Some(grammars) if grammars.len() == 1 => &grammars[0],
+        Some(_) => return Err("only a single grammar is supported".into()),
+        None => &value,
+    };
+    for key in ["lark_grammar", "json_schema"] {
+        if grammar.get(key).is_some_and(|v| !v.is_null()) {
+            return Err(format!("grammars given as {key} are not supported").into());
+        }
+    }
+    let nodes = grammar
+        .get("nodes")
+        .and_then(Value::as_array)
+        .filter(|nodes| !nodes.is_empty())
+        .ok_or("grammar has no nodes")?;
+    let rx_nodes = grammar
+        .get("rx_nodes")
+        .and_then(Value::as_array)
+        .map_or(&[][..], Vec::as_slice);
+
+    let identifier = Regex::new("^[A-Z][A-Z0-9_]*$")?;
+    let mut tokens = vec![];
+    let mut literals: HashMap<&str, String> = HashMap::new();
+    let mut symbols = vec![];
+    for (i, node) in nodes.iter().enumerate() {
+        let (kind, body) = variant(node)?;
+        let symbol = match kind {
+            "String" => {
+                let literal = body["literal"]
+                    .as_str()
+                    .ok_or("expected literal for String")?;
+                if literal.is_empty() {
+                    format!("n{i}")
+                } else if let Some(symbol) = literals.get(literal) {
+                    symbol.clone()
+                } else {
+                    // literals without whitespace or quotes are used directly like
+                    // in handwritten grammars, unless they look like token names
+                    let symbol = if identifier.is_match(literal)
+                        || literal
+                            .chars()
+                            .any(|c| c.is_whitespace() || matches!(c, '\'' | '"' | '\\'))
+                    {
+                        let name = format!("LIT{}", literals.len());
+                        tokens.push((name.clone(), escape(literal)));
+                        format!("'{name}'")
+                    } else {
+                        format!("'{literal}'")
+                    };
+                    literals.insert(literal, symbol.clone());
+                    symbol
+                }
+            }
+            "Lexeme" => {
+                if body["json_string"].as_bool().unwrap_or(false) {
+                    return Err("lexemes with json_string are not supported".into());
+                }
+                tokens.push((format!("LEX{i}"), regex_spec(&body["rx"], rx_nodes)?));
+                format!("'LEX{i}'")
+            }
+            "Gen" => {
+                let stop = match &body["stop_rx"] {
+                    Value::Null => String::new(),
+                    stop => regex_spec(stop, rx_nodes)?,
+                };
+                if !stop.is_empty() {
+                    return Err("gen nodes with a stop regex are not supported".into());
+                }
+                tokens.push((format!("LEX{i}"), regex_spec(&body["body_rx"], rx_nodes)?));
+                format!("'LEX{i}'")
+            }
+            "Join" | "Select" => format!("n{i}"),
+            _ => return Err(format!("unsupported node {kind}").into()),
+        };
+        symbols.push(symbol);
+    }
+
+    let mut rules = String::from("%start n0\n\n%%\n");
+    let symbol = |value: &Value| -> Result<&str, Box<dyn Error>> {
+        Ok(&symbols[index(value, nodes.len(), "node")?])
+    };
+    for (i, node) in nodes.iter().enumerate() {
+        let (kind, body) = variant(node)?;
+        let alternatives = match kind {
+            "Join" => vec![body["sequence"]
+                .as_array()
+                .ok_or("expected sequence for Join")?
+                .iter()
+                .map(symbol)
+                .collect::<Result<Vec<_>, _>>()?
+                .join(" ")],
+            "Select" => {
+                let among = body["among"]
+                    .as_array()
+                    .ok_or("expected among for Select")?;
+                if among.is_empty() {
+                    return Err(format!("select node {i} has no alternatives").into());
+                }
+                among
+                    .iter()
+                    .map(|value| symbol(value).map(str::to_string))
+                    .collect::<Result<_, _>>()?
+            }
+            _ if symbols[i] == format!("n{i}") => vec![String::new()],
+            // the start node needs to be a rule
+            _ if i == 0 => vec![symbols[0].clone()],
+            _ => continue,
+        };
+        write!(
+            rules,
+            "\nn{i}\n    : {}\n    ;\n",
+            alternatives.join("\n    | ")
+        )?;
+    }
+
+    let mut lexer = String::from("%%\n\n");
+    for (name, pattern) in &tokens {
+        writeln!(lexer, "{name} {}", lexer_pattern(pattern))?;
+    }
+    if let Some(skip) = grammar.get("greedy_skip_rx").filter(|v| !v.is_null()) {
+        let skip = regex_spec(skip, rx_nodes)?;
+        if !skip.is_empty() {
+            writeln!(lexer, "; {}", lexer_pattern(&skip))?;
+        }
+    }
+    Ok((rules, lexer))
+}
+
+// converts an LR(1) grammar and lexer into the json format of guidance / llguidance;
+// rules become Join and Select nodes, tokens become Lexeme nodes and tokens only
+// used in the grammar become String nodes, ignore tokens are merged into the skip regex
+pub fn lr1_to_guidance(grammar: &str, lexer: &str) -> Result<String, Box<dyn Error>> {
+    // token aliases have no equivalent in guidance grammars
+    let (source, _) = extract_token_aliases(grammar)?;
+    let grammar = YaccGrammar::new(
+        YaccKind::Original(YaccOriginalActionKind::NoAction),
+        &source,
+    )
+    .map_err(|e| {
+        format!(
+            "errors creating grammar:\n{}",
+            e.iter().map(|e| format_yacc_error(&source, e)).join("\n")
+        )
+    })?;
+    let LexerSpec {
+        fragments,
+        tokens,
+        ignore_tokens,
+        byte_mode,
+        ..
+    } = parse_lexer(lexer)?;
+    let token_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
+    let pattern = |name: &str, parts| -> Result<String, Box<dyn Error>> {
+        let pattern = pattern_from_parts(name, parts, &token_name, &fragments, &tokens)?;
+        Ok(if byte_mode {
+            format!("(?-u:{pattern})")
+        } else {
+            pattern
+        })
+    };
+
+    // the start rule gets node 0, the augmented start rule is skipped
+    let augmented = grammar.prod_to_rule(grammar.start_prod());
+    let rules: Vec<RIdx<u32>> = [grammar.start_rule_idx()]
+        .into_iter()
+        .chain(grammar.iter_rules().filter(|&ridx| {
+            ridx != augmented
+                && ridx != grammar.start_rule_idx()
+                && Some(ridx) != grammar.implicit_rule()
+        }))
+        .collect();
+    let rule_ids: HashMap<_, _> = rules.iter().enumerate().map(|(i, &r)| (r, i)).collect();
+    let mut nodes = vec![Value::Null; rules.len()];
+    let mut token_ids: HashMap<TIdx<u32>, usize> = HashMap::new();
+    let mut node_id = |nodes: &mut Vec<Value>, symbol: &Symbol<u32>| match *symbol {
+        Symbol::Rule(ridx) => Ok(rule_ids[&ridx]),
+        Symbol::Token(tidx) => {
+            if let Some(&id) = token_ids.get(&tidx) {
+                return Ok(id);
+            }
+            let name = grammar.token_name(tidx).ok_or("unnamed token")?;
+            let node = if let Some(parts) = tokens.get(name) {
+                json!({ "Lexeme": { "rx": pattern(name, parts)? } })
+            } else if fragments.contains_key(name) {
+                return Err(format!("token {name} is only defined as fragment").into());
+            } else {
+                json!({ "String": { "literal": name } })
+            };
+            nodes.push(node);
+            token_ids.insert(tidx, nodes.len() - 1);
+            Ok::<_, Box<dyn Error>>(nodes.len() - 1)
+        }
+    };
+    for (i, &ridx) in rules.iter().enumerate() {
+        let mut sequences = vec![];
+        for &pidx in grammar.rule_to_prods(ridx) {
+            sequences.push(
+                grammar
+                    .prod(pidx)
+                    .iter()
+                    .map(|symbol| node_id(&mut nodes, symbol))
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+        let sequence_node = |sequence: Vec<usize>| {
+            if sequence.is_empty() {
+                json!({ "String": { "literal": "" } })
+            } else {
+                json!({ "Join": { "sequence": sequence } })
+            }
+        };
+        nodes[i] = if sequences.len() == 1 {
+            sequence_node(sequences.pop().unwrap())
+        } else {
+            // alternatives of a single symbol are selected directly
+            let mut among = vec![];
+            for sequence in sequences {
+                if let [id] = sequence[..] {
+                    among.push(id);
+                } else {
+                    nodes.push(sequence_node(sequence));
+                    among.push(nodes.len() - 1);
+                }
+            }
+            json!({ "Select": { "among": among } })
+        };
+    }
+
+    let skip = ignore_tokens
+        .iter()
+        .map(|parts| pattern("ignore token", parts).map(|p| format!("(?:{p})")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut grammar = json!({ "nodes": nodes, "rx_nodes": [] });
+    if !skip.is_empty() {
+        grammar["greedy_skip_rx"] = Value::String(skip.join("|"));
+    }
+    Ok(serde_json::to_string_pretty(
+        &json!({ "grammars": [grammar] }),
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint};
+
+    fn byte_continuations() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    #[test]
+    fn test_guidance_to_lr1() {
+        // "name: " followed by a capitalized word, repeated with ", " in between
+        let json = r#"{"grammars": [{
+            "nodes": [
+                {"Join": {"sequence": [1, 2]}},
+                {"Select": {"among": [3, 4]}},
+                {"String": {"literal": ""}},
+                {"Join": {"sequence": [5]}},
+                {"Join": {"sequence": [1, 6, 5]}},
+                {"Join": {"sequence": [7, 8]}},
+                {"String": {"literal": ", "}},
+                {"String": {"literal": "name:"}},
+                {"Lexeme": {"rx": 0}}
+            ],
+            "rx_nodes": [
+                {"Concat": [1, 2]},
+                {"Regex": "[A-Z]"},
+                {"Repeat": [3, 1, null]},
+                {"ByteSet": [0, 0, 0, 134217726, 0, 0, 0, 0]}
+            ],
+            "greedy_skip_rx": "[ ]+"
+        }]}"#;
+        let (grammar, lexer) = guidance_to_lr1(json).unwrap();
+        let lr1 = LR1GrammarConstraint::new(&grammar, &lexer, byte_continuations()).unwrap();
+        let state = lr1.get_state(b"name: Alice, name:Bob").unwrap();
+        assert!(lr1.is_match_state(&state));
+        let state = lr1.get_state(b"name: Alice,").unwrap();
+        assert!(!lr1.is_match_state(&state));
+        assert!(lr1.get_state(b"name: alice").is_none());
+        assert!(lr1.get_state(b"name: A,").is_none());
+
+        assert!(guidance_to_lr1(r#"{"grammars": [{"lark_grammar": "start: 'a'"}]}"#).is_err());
+        assert!(guidance_to_lr1(
+            r#"{"nodes": [{"Gen": {"body_rx": "[a-z]+", "stop_rx": "\\n"}}]}"#
+        )
+        .is_err());
+        let (grammar, lexer) =
+            guidance_to_lr1(r#"{"nodes": [{"Gen": {"body_rx": "[a-z]+"}}]}"#).unwrap();
+        let lr1 = LR1GrammarConstraint::new(&grammar, &lexer, byte_continuations()).unwrap();
+        assert!(lr1.is_match_state(&lr1.get_state(b"abc").unwrap()));
+    }
+
+    #[test]
+    fn test_lr1_to_guidance() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("grammars/json");
+        let grammar = fs::read_to_string(dir.join("json.y")).unwrap();
+        let lexer = fs::read_to_string(dir.join("json.l")).unwrap();
+        let json = lr1_to_guidance(&grammar, &lexer).unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert!(value["grammars"][0]["greedy_skip_rx"].is_string());
+
+        // round trip through the guidance format
+        let (grammar2, lexer2) = guidance_to_lr1(&json).unwrap();
+        let original = LR1GrammarConstraint::new(&grammar, &lexer, byte_continuations()).unwrap();
+        let converted =
+            LR1GrammarConstraint::new(&grammar2, &lexer2, byte_continuations()).unwrap();
+        for input in [
+            &b"{\"a\": [1, 2.5e3, true, null], \"b\": {}}"[..],
+            b"[\"x\\n\", -1]",
+            b"{\"a\" 1}",
+            b"[1, 2",
+        ] {
+            let (a, b) = (original.get_state(input), converted.get_state(input));
+            assert_eq!(a.is_some(), b.is_some());
+            if let (Some(a), Some(b)) = (a, b) {
+                assert_eq!(original.is_match_state(&a), converted.is_match_state(&b));
+                assert_eq!(
+                    original.get_valid_continuations(&a),
+                    converted.get_valid_continuations(&b)
+                );
+            }
+        }
+    }
+}
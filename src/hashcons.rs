@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use rustc_hash::FxBuildHasher;
+
+const NUM_SHARDS: usize = 64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// nodes by the hash of their value
+type Shard<T> = Mutex<HashMap<u64, Vec<Weak<Node<T>>>>>;
+
+// table of all live hash consed values of a type, sharded to keep
+// lock contention low when many threads create states at once
+pub(crate) struct Interner<T: Intern> {
+    shards: [Shard<T>; NUM_SHARDS],
+}
+
+impl<T: Intern> Default for Interner<T> {
+    fn default() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Mutex::default()),
+        }
+    }
+}
+
+impl<T: Intern> Interner<T> {
+    fn shard(&self, hash: u64) -> &Shard<T> {
+        &self.shards[hash as usize % NUM_SHARDS]
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().values().map(Vec::len).sum::<usize>())
+            .sum()
+    }
+}
+
+// types with a global interner, see HashConsed
+pub(crate) trait Intern: Hash + Eq + Sized + 'static {
+    fn interner() -> &'static Interner<Self>;
+}
+
+struct Node<T: Intern> {
+    id: u64,
+    hash: u64,
+    value: T,
+}
+
+impl<T: Intern> Drop for Node<T> {
+    fn drop(&mut self) {
+        // the value might have been interned again in the meantime,
+        // so only dead entries are removed
+        let mut shard = T::interner().shard(self.hash).lock().unwrap();
+        if let Some(nodes) = shard.get_mut(&self.hash) {
+            nodes.retain(|node| node.strong_count() > 0);
+            if nodes.is_empty() {
+                shard.remove(&self.hash);
+            }
+        }
+    }
+}
+
+// an immutable, reference counted value that is unique among all live
+// values of its type, so cloning is O(1), equality only looks at a small
+// integer id and hashing at the hash of the value computed when interning,
+// which keeps hashes stable across runs; ids are never reused
+pub(crate) struct HashConsed<T: Intern>(Arc<Node<T>>);
+
+impl<T: Intern> HashConsed<T> {
+    pub(crate) fn new(value: T) -> Self {
+        let hash = FxBuildHasher.hash_one(&value);
+        // upgraded nodes must be dropped after the shard is unlocked, a
+        // node whose last handle is dropped locks the shard to remove itself
+        let mut upgraded = vec![];
+        let mut shard = T::interner().shard(hash).lock().unwrap();
+        let nodes = shard.entry(hash).or_default();
+        for node in nodes.iter().filter_map(Weak::upgrade) {
+            if node.value == value {
+                drop(shard);
+                return Self(node);
+            }
+            upgraded.push(node);
+        }
+        let node = Arc::new(Node {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            hash,
+            value,
+        });
+        nodes.retain(|node| node.strong_count() > 0);
+        nodes.push(Arc::downgrade(&node));
+        Self(node)
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    // number of handles to the same value, e.g. states of different beams
+    pub(crate) fn shared(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+}
+
+impl<T: Intern> Clone for HashConsed<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Intern> Deref for HashConsed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0.value
+    }
+}
+
+impl<T: Intern> PartialEq for HashConsed<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl<T: Intern> Eq for HashConsed<T> {}
+
+impl<T: Intern> Hash for HashConsed<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash.hash(state);
+    }
+}
+
+impl<T: Intern + Default> Default for HashConsed<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Intern + fmt::Debug> fmt::Debug for HashConsed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.value.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::LazyLock;
+
+    use super::*;
+
+    impl Intern for Vec<u8> {
+        fn interner() -> &'static Interner<Self> {
+            static INTERNER: LazyLock<Interner<Vec<u8>>> = LazyLock::new(Interner::default);
+            &INTERNER
+        }
+    }
+
+    #[test]
+    fn test_hash_consing() {
+        let a = HashConsed::new(b"hash".to_vec());
+        let b = HashConsed::new(b"hash".to_vec());
+        let c = HashConsed::new(b"consed".to_vec());
+        assert_eq!(a, b);
+        assert_eq!(a.id(), b.id());
+        assert_ne!(a, c);
+        assert_eq!(a.shared(), 2);
+        assert_eq!(*c, b"consed");
+
+        // dropped values are removed and get a new id when interned again
+        let id = c.id();
+        drop(c);
+        let c = HashConsed::new(b"consed".to_vec());
+        assert_ne!(c.id(), id);
+
+        let handles: Vec<_> = std::thread::scope(|s| {
+            (0..8)
+                .map(|_| s.spawn(|| HashConsed::new(b"threads".to_vec())))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        assert!(handles.iter().all(|handle| handle == &handles[0]));
+    }
+}
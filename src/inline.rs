@@ -0,0 +1,408 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use cfgrammar::yacc::{
+    ast::{ASTWithValidityInfo, GrammarAST, Symbol},
+    YaccKind, YaccOriginalActionKind,
+};
+use itertools::Itertools;
+use regex::Regex;
+
+use crate::{
+    lr1::{extract_token_aliases, format_yacc_error},
+    LR1Parse,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Sym {
+    Rule(String),
+    Token(String),
+}
+
+impl Sym {
+    fn matches(&self, parse: &LR1Parse<'_>) -> bool {
+        match (self, parse) {
+            (Sym::Rule(name), LR1Parse::NonTerminal(other, _) | LR1Parse::Empty(other)) => {
+                name == other
+            }
+            (Sym::Token(name), LR1Parse::Terminal(other, ..)) => name == other,
+            _ => false,
+        }
+    }
+}
+
+// where the symbols of a production of the inlined grammar came from,
+// either directly from the production or from an inlined rule
+#[derive(Debug, Clone)]
+enum Slot {
+    Child,
+    Rule(String, Vec<Slot>),
+}
+
+// symbols of an inlined production together with their slots
+type Production = (Vec<Sym>, Vec<Slot>);
+
+// origin of the productions of an inlined grammar, used to restore the inlined
+// rules in parse trees, so they look as if parsed with the original grammar
+#[derive(Debug, Clone, Default)]
+pub struct InlineProvenance {
+    // per rule the productions containing inlined rules
+    productions: HashMap<String, Vec<Production>>,
+}
+
+impl InlineProvenance {
+    fn fill<'a>(
+        slots: &'a [Slot],
+        children: &mut impl Iterator<Item = LR1Parse<'a>>,
+    ) -> Vec<LR1Parse<'a>> {
+        slots
+            .iter()
+            .map(|slot| match slot {
+                Slot::Child => children.next().expect("child for every slot"),
+                Slot::Rule(name, slots) => {
+                    let nodes = Self::fill(slots, children);
+                    if nodes.is_empty() {
+                        LR1Parse::Empty(name)
+                    } else {
+                        LR1Parse::NonTerminal(name, nodes)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // the tree has to be unfiltered, i.e. parsed without skipping empty
+    // nodes or collapsing single children
+    pub fn restore<'a>(&'a self, parse: LR1Parse<'a>) -> LR1Parse<'a> {
+        let (name, children) = match parse {
+            LR1Parse::NonTerminal(name, children) => (
+                name,
+                children
+                    .into_iter()
+                    .map(|child| self.restore(child))
+                    .collect(),
+            ),
+            LR1Parse::Empty(name) => (name, vec![]),
+            terminal => return terminal,
+        };
+        let slots = self.productions.get(name).and_then(|productions| {
+            productions.iter().find_map(|(symbols, slots)| {
+                (symbols.len() == children.len()
+                    && symbols.iter().zip(&children).all(|(s, c)| s.matches(c)))
+                .then_some(slots)
+            })
+        });
+        let children = match slots {
+            Some(slots) => Self::fill(slots, &mut children.into_iter()),
+            None => children,
+        };
+        if children.is_empty() {
+            LR1Parse::Empty(name)
+        } else {
+            LR1Parse::NonTerminal(name, children)
+        }
+    }
+}
+
+pub struct InlinedGrammar {
+    grammar: String,
+    inlined: Vec<String>,
+    provenance: InlineProvenance,
+}
+
+impl InlinedGrammar {
+    pub fn grammar(&self) -> &str {
+        &self.grammar
+    }
+
+    // names of the inlined rules, in grammar order
+    pub fn inlined(&self) -> &[String] {
+        &self.inlined
+    }
+
+    pub fn into_parts(self) -> (String, InlineProvenance) {
+        (self.grammar, self.provenance)
+    }
+}
+
+fn single_body<'a>(ast: &'a GrammarAST, name: &str) -> &'a [Symbol] {
+    let rule = &ast.rules[name];
+    &ast.prods[rule.pidxs[0]].symbols
+}
+
+// whether target is reachable from the body of rule through candidates only
+fn reaches(ast: &GrammarAST, candidates: &HashSet<&str>, rule: &str, target: &str) -> bool {
+    let mut stack = vec![rule];
+    let mut seen = HashSet::new();
+    while let Some(rule) = stack.pop() {
+        for symbol in single_body(ast, rule) {
+            let Symbol::Rule(name, _) = symbol else {
+                continue;
+            };
+            if name == target {
+                return true;
+            }
+            if candidates.contains(name.as_str()) && seen.insert(name.as_str()) {
+                stack.push(name);
+            }
+        }
+    }
+    false
+}
+
+fn expand(
+    ast: &GrammarAST,
+    candidates: &HashSet<&str>,
+    symbol: &Symbol,
+    symbols: &mut Vec<Sym>,
+    slots: &mut Vec<Slot>,
+) {
+    match symbol {
+        Symbol::Rule(name, _) if candidates.contains(name.as_str()) => {
+            let mut inner = vec![];
+            for symbol in single_body(ast, name) {
+                expand(ast, candidates, symbol, symbols, &mut inner);
+            }
+            slots.push(Slot::Rule(name.clone(), inner));
+        }
+        Symbol::Rule(name, _) => {
+            symbols.push(Sym::Rule(name.clone()));
+            slots.push(Slot::Child);
+        }
+        Symbol::Token(name, _) => {
+            symbols.push(Sym::Token(name.clone()));
+            slots.push(Slot::Child);
+        }
+    }
+}
+
+fn quote(token: &str) -> String {
+    if token.contains('\'') {
+        format!("\"{token}\"")
+    } else {
+        format!("'{token}'")
+    }
+}
+
+// inlines rules with a single production that are not recursive, i.e. replaces
+// them by their production everywhere, which gives smaller LR tables and flatter
+// parse trees; the start rule and rules with precedence are kept, and declarations
+// before %% stay as they are
+pub fn inline_rules(grammar: &str) -> Result<InlinedGrammar, Box<dyn Error>> {
+    let (source, _) = extract_token_aliases(grammar)?;
+    let ast = ASTWithValidityInfo::new(
+        YaccKind::Original(YaccOriginalActionKind::GenericParseTree),
+        &source,
+    );
+    if !ast.is_valid() {
+        return Err(format!(
+            "errors creating grammar:\n{}",
+            ast.errors()
+                .iter()
+                .map(|e| format_yacc_error(grammar, e))
+                .join("\n")
+        )
+        .into());
+    }
+    let ast = ast.ast();
+    if ast.prods.iter().any(|prod| prod.action.is_some()) || ast.programs.is_some() {
+        return Err("grammars with actions or programs cannot be inlined".into());
+    }
+    let start = match &ast.start {
+        Some((start, _)) => start.as_str(),
+        None => ast.rules.keys().next().ok_or("grammar without rules")?,
+    };
+
+    let mut candidates: HashSet<&str> = ast
+        .rules
+        .values()
+        .filter(|rule| {
+            rule.name.0 != start
+                && rule.actiont.is_none()
+                && rule.pidxs.len() == 1
+                && ast.prods[rule.pidxs[0]].precedence.is_none()
+        })
+        .map(|rule| rule.name.0.as_str())
+        .collect();
+    // a rule reaching itself through inlined rules would expand forever,
+    // keeping one rule of such a cycle is enough
+    for name in ast.rules.keys() {
+        if candidates.contains(name.as_str()) && reaches(ast, &candidates, name, name) {
+            candidates.remove(name.as_str());
+        }
+    }
+
+    let declarations = Regex::new("(?m)^%%")?
+        .find(grammar)
+        .ok_or("grammar without rules section")?;
+    let mut output = grammar[..declarations.end()].to_string();
+    output.push_str("\n\n");
+    let mut provenance = InlineProvenance::default();
+    for rule in ast.rules.values() {
+        let name = &rule.name.0;
+        if candidates.contains(name.as_str()) {
+            continue;
+        }
+        output.push_str(name);
+        output.push('\n');
+        for (i, &pidx) in rule.pidxs.iter().enumerate() {
+            let prod = &ast.prods[pidx];
+            let mut symbols = vec![];
+            let mut slots = vec![];
+            for symbol in &prod.symbols {
+                expand(ast, &candidates, symbol, &mut symbols, &mut slots);
+            }
+            let text = symbols
+                .iter()
+                .map(|symbol| match symbol {
+                    Sym::Rule(name) => name.clone(),
+                    Sym::Token(name) => quote(name),
+                })
+                .chain(
+                    prod.precedence
+                        .iter()
+                        .map(|prec| format!("%prec {}", quote(prec))),
+                )
+                .join(" ");
+            output.push_str(&format!("    {} {text}\n", if i == 0 { ':' } else { '|' }));
+            if slots.iter().any(|slot| matches!(slot, Slot::Rule(..))) {
+                provenance
+                    .productions
+                    .entry(name.clone())
+                    .or_default()
+                    .push((symbols, slots));
+            }
+        }
+        output.push_str("    ;\n\n");
+    }
+
+    let inlined = ast
+        .rules
+        .keys()
+        .filter(|name| candidates.contains(name.as_str()))
+        .cloned()
+        .collect();
+    Ok(InlinedGrammar {
+        grammar: output,
+        inlined,
+        provenance,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use cfgrammar::yacc::YaccGrammar;
+
+    use super::*;
+    use crate::{
+        json_schema_to_lr1, lr1::build_table, CompileLimits, Constraint, LR1GrammarConstraint,
+        LR1GrammarParser,
+    };
+
+    fn states(grammar: &str) -> usize {
+        let grammar = YaccGrammar::new(
+            YaccKind::Original(YaccOriginalActionKind::GenericParseTree),
+            grammar,
+        )
+        .unwrap();
+        build_table(
+            grammar,
+            &CompileLimits::default(),
+            Instant::now(),
+            &mut |_| {},
+        )
+        .unwrap()
+        .2
+    }
+
+    #[test]
+    fn test_inline_json_schema() {
+        let (grammar, lexer) = json_schema_to_lr1(
+            r#"{
+                "type": "object",
+                "properties": {"name": {"type": "string"}, "tags": {"type": "array"}},
+                "required": ["name"]
+            }"#,
+        )
+        .unwrap();
+        let inlined = inline_rules(&grammar).unwrap();
+        assert!(!inlined.inlined().is_empty());
+        assert!(states(inlined.grammar()) < states(&grammar));
+
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let original = LR1GrammarConstraint::new(&grammar, &lexer, conts.clone()).unwrap();
+        let inlined = LR1GrammarConstraint::new(inlined.grammar(), &lexer, conts).unwrap();
+        for text in [
+            r#"{"name": "x"}"#,
+            r#"{"name": "x", "tags": [1, {"a": null}]}"#,
+            r#"{"tags": []}"#,
+            r#"{"name": 1}"#,
+        ] {
+            for len in 0..=text.len() {
+                let prefix = &text.as_bytes()[..len];
+                let a = original.get_state(prefix);
+                let b = inlined.get_state(prefix);
+                assert_eq!(a.is_some(), b.is_some());
+                if let (Some(a), Some(b)) = (a, b) {
+                    assert_eq!(
+                        original.get_valid_continuations(&a),
+                        inlined.get_valid_continuations(&b)
+                    );
+                    assert_eq!(original.is_match_state(&a), inlined.is_match_state(&b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_inline_provenance() {
+        let grammar = r#"
+%start S
+%token NUM "number"
+%%
+S: S ',' item | item ;
+item: '(' pair ')' | NUM ;
+pair: key ':' value ;
+key: NUM ;
+value: S | empty ;
+empty: ;
+"#;
+        let lexer = "%%\nNUM [0-9]+\n; \\s+\n";
+        let inlined = inline_rules(grammar).unwrap();
+        assert_eq!(inlined.inlined(), ["pair", "key", "empty"]);
+        // the alias declaration is kept
+        assert!(inlined.grammar().contains(r#"%token NUM "number""#));
+
+        let original = LR1GrammarParser::new(grammar, lexer).unwrap();
+        let (grammar, provenance) = inlined.into_parts();
+        let flat = LR1GrammarParser::new(&grammar, lexer).unwrap();
+        let restored = LR1GrammarParser::new(&grammar, lexer)
+            .unwrap()
+            .with_provenance(provenance);
+        for text in ["1", "(1: 2, (3:)), 4", "(1:)"] {
+            let tree = original.parse(text, false, false).unwrap();
+            // only texts with pairs go through inlined rules
+            let flat_len = flat.parse(text, false, false).unwrap().skeleton().len();
+            assert_eq!(flat_len < tree.skeleton().len(), text.contains(':'));
+            assert_eq!(restored.parse(text, false, false).unwrap(), tree);
+            assert_eq!(
+                restored.parse(text, true, true).unwrap(),
+                original.parse(text, true, true).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_inline_cycle() {
+        let grammar = "%start S\n%%\nS: A | 'q' ;\nA: B 'x' ;\nB: A 'y' ;\n";
+        let inlined = inline_rules(grammar).unwrap();
+        assert_eq!(inlined.inlined(), ["B"]);
+        assert!(inlined.grammar().contains("A\n    : A 'y' 'x'\n"));
+        assert!(inline_rules("%start S\n%%\nS: 'a' { $1 } ;\n").is_err());
+        assert!(inline_rules("%start S\n%%\nS: T ;\n").is_err());
+    }
+}
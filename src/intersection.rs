@@ -0,0 +1,170 @@
+use std::error::Error;
+
+use crate::{Constraint, DynState};
+
+// intersection of arbitrary constraints over the same continuations, e.g. an
+// lr(1) grammar together with a regex limiting the length; a continuation is
+// valid if it is valid for all of the constraints, which does not guarantee
+// that the constraints still have a common match after it
+pub struct IntersectionConstraint {
+    constraints: Vec<Box<dyn crate::DynConstraint>>,
+}
+
+// one state per constraint
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct IntersectionState {
+    states: Vec<DynState>,
+}
+
+impl IntersectionState {
+    pub fn states(&self) -> &[DynState] {
+        &self.states
+    }
+}
+
+// keeps the elements of the sorted continuations that are also in other
+fn retain_sorted(continuations: &mut Vec<usize>, other: &[usize]) {
+    let mut j = 0;
+    continuations.retain(|&cont| {
+        while j < other.len() && other[j] < cont {
+            j += 1;
+        }
+        j < other.len() && other[j] == cont
+    });
+}
+
+impl IntersectionConstraint {
+    pub fn new(constraints: Vec<Box<dyn crate::DynConstraint>>) -> Result<Self, Box<dyn Error>> {
+        if constraints.is_empty() {
+            return Err("intersection needs at least one constraint".into());
+        }
+        Ok(Self { constraints })
+    }
+
+    pub fn constraints(&self) -> &[Box<dyn crate::DynConstraint>] {
+        &self.constraints
+    }
+
+    fn intersect(
+        &self,
+        state: &IntersectionState,
+        continuations: impl Fn(&dyn crate::DynConstraint, &DynState) -> Vec<usize>,
+    ) -> Vec<usize> {
+        let mut members = self.constraints.iter().zip(&state.states);
+        let (constraint, state) = members.next().expect("at least one constraint");
+        let mut conts = continuations(constraint.as_ref(), state);
+        for (constraint, state) in members {
+            // no need to ask the remaining constraints
+            if conts.is_empty() {
+                break;
+            }
+            retain_sorted(&mut conts, &continuations(constraint.as_ref(), state));
+        }
+        conts
+    }
+}
+
+impl Constraint for IntersectionConstraint {
+    type State = IntersectionState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        let states = self
+            .constraints
+            .iter()
+            .map(|c| c.get_state(prefix))
+            .collect::<Option<_>>()?;
+        Some(IntersectionState { states })
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        IntersectionState {
+            states: self
+                .constraints
+                .iter()
+                .map(|c| c.get_start_state())
+                .collect(),
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.constraints
+            .iter()
+            .zip(&state.states)
+            .all(|(constraint, state)| constraint.is_match_state(state))
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        self.constraints
+            .iter()
+            .zip(state.states.iter().zip(&next.states))
+            .all(|(constraint, (state, next))| constraint.has_same_continuations(state, next))
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.intersect(state, |constraint, state| {
+            constraint.get_valid_continuations(state)
+        })
+    }
+
+    fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.intersect(state, |constraint, state| {
+            constraint.get_approximate_continuations(state)
+        })
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let states = self
+            .constraints
+            .iter()
+            .zip(&state.states)
+            .map(|(constraint, state)| constraint.get_next_state(state, continuation))
+            .collect::<Option<_>>()?;
+        Some(IntersectionState { states })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{LR1GrammarConstraint, RegularExpressionConstraint};
+
+    #[test]
+    fn test_intersection() {
+        let conts: Vec<_> = ["[", "]", "1", ",", "12", "[1", ", ", "a"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let json = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts.clone(),
+        )
+        .unwrap();
+        // json of at most six bytes without whitespace
+        let short = RegularExpressionConstraint::new(r"[^\s]{0,6}", conts).unwrap();
+        let both = IntersectionConstraint::new(vec![Box::new(json), Box::new(short)]).unwrap();
+
+        let state = both.get_start_state();
+        assert!(!both.is_match_state(&state));
+        assert_eq!(both.get_valid_continuations(&state), [0, 2, 4, 5]);
+
+        let state = both.get_state(b"[1,12").unwrap();
+        assert_eq!(both.get_valid_continuations(&state), [1, 2, 3]);
+        assert!(both.get_next_state(&state, 6).is_none());
+        let state = both.get_next_state(&state, 1).unwrap();
+        assert!(both.is_match_state(&state));
+        assert!(both.get_valid_continuations(&state).is_empty());
+        assert_eq!(state.states().len(), 2);
+
+        assert!(both.check(b"[1,12]"));
+        assert!(!both.check(b"[1, 12]"));
+        assert!(!both.check(b"[1,123]"));
+        assert!(both.get_state(b"a").is_none());
+
+        let approximate = both.get_approximate_continuations(&both.get_start_state());
+        assert!([0, 2, 4, 5].iter().all(|cont| approximate.contains(cont)));
+
+        assert!(IntersectionConstraint::new(vec![]).is_err());
+    }
+}
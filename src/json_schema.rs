@@ -0,0 +1,1010 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fmt::Write,
+};
+
+use indexmap::IndexMap;
+use regex::{escape, Regex};
+use regex_syntax::{
+    hir::{
+        Class, ClassBytes, ClassBytesRange, ClassUnicode, ClassUnicodeRange, Hir, HirKind, Literal,
+        Look, Repetition,
+    },
+    ParserBuilder,
+};
+use serde_json::Value;
+
+use crate::{
+    distinguish::regex_intersection,
+    inline_rules,
+    lr1::LR1State,
+    utils::{integer_range_pattern, lexer_pattern},
+    ByteConstraint, CompileLimits, Constraint, LR1GrammarConstraint, MemoryUsage,
+};
+
+// keywords that either are annotations or are handled below, all others would
+// restrict the generated json in ways that are not enforced, so they are rejected
+const KEYWORDS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "$defs",
+    "definitions",
+    "$ref",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "type",
+    "enum",
+    "const",
+    "anyOf",
+    "oneOf",
+    "allOf",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "pattern",
+    "minimum",
+    "maximum",
+];
+
+// inclusive integer bounds, none if unbounded; i128 so that interval
+// boundaries one past an i64 bound do not overflow
+type Bounds = (Option<i128>, Option<i128>);
+
+// zero or more characters that can appear unescaped within a json string
+fn any_string_content() -> Repetition {
+    Repetition {
+        min: 0,
+        max: None,
+        greedy: true,
+        sub: Box::new(Hir::class(Class::Unicode(ClassUnicode::new([
+            ClassUnicodeRange::new(' ', '!'),
+            ClassUnicodeRange::new('#', '['),
+            ClassUnicodeRange::new(']', char::MAX),
+        ])))),
+    }
+}
+
+// restricts a pattern to characters that can appear unescaped within a json
+// string, e.g. . does not match quotes and backslashes anymore
+fn string_content(hir: &Hir, pattern: &str) -> Result<Hir, Box<dyn Error>> {
+    let Repetition { sub: allowed, .. } = any_string_content();
+    Ok(match hir.kind() {
+        HirKind::Empty => Hir::empty(),
+        HirKind::Literal(Literal(bytes)) => {
+            if let Some(&b) = bytes.iter().find(|&&b| b < 0x20 || b == b'"' || b == b'\\') {
+                return Err(format!(
+                    "pattern {pattern} requires {:?}, which has to be escaped in json strings",
+                    char::from(b)
+                )
+                .into());
+            }
+            hir.clone()
+        }
+        HirKind::Class(Class::Unicode(class)) => {
+            let HirKind::Class(Class::Unicode(allowed)) = allowed.kind() else {
+                unreachable!("string content is a unicode class")
+            };
+            let mut class = class.clone();
+            class.intersect(allowed);
+            Hir::class(Class::Unicode(class))
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let mut class = class.clone();
+            class.intersect(&ClassBytes::new([
+                ClassBytesRange::new(b' ', b'!'),
+                ClassBytesRange::new(b'#', b'['),
+                ClassBytesRange::new(b']', u8::MAX),
+            ]));
+            Hir::class(Class::Bytes(class))
+        }
+        HirKind::Look(_) => {
+            return Err(format!(
+                "pattern {pattern} has assertions other than a leading ^ or trailing $"
+            )
+            .into())
+        }
+        HirKind::Repetition(repetition) => Hir::repetition(Repetition {
+            sub: Box::new(string_content(&repetition.sub, pattern)?),
+            ..repetition.clone()
+        }),
+        HirKind::Capture(capture) => string_content(&capture.sub, pattern)?,
+        HirKind::Concat(hirs) => Hir::concat(
+            hirs.iter()
+                .map(|h| string_content(h, pattern))
+                .collect::<Result<_, _>>()?,
+        ),
+        HirKind::Alternation(hirs) => Hir::alternation(
+            hirs.iter()
+                .map(|h| string_content(h, pattern))
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+fn contains((lo, hi): Bounds, (inner_lo, inner_hi): Bounds) -> bool {
+    lo.is_none_or(|lo| inner_lo.is_some_and(|inner| inner >= lo))
+        && hi.is_none_or(|hi| inner_hi.is_some_and(|inner| inner <= hi))
+}
+
+// generic json for schemas without restrictions, same as grammars/json/json.y
+const ANY_RULES: &str = "
+json_value
+    : json_string
+    | json_number
+    | json_obj
+    | json_arr
+    | 'true'
+    | 'false'
+    | 'null'
+    ;
+
+json_obj
+    : '{' '}'
+    | '{' json_members '}'
+    ;
+
+json_members
+    : json_pair
+    | json_members ',' json_pair
+    ;
+
+json_pair
+    : json_string ':' json_value
+    ;
+
+json_arr
+    : '[' ']'
+    | '[' json_elements ']'
+    ;
+
+json_elements
+    : json_value
+    | json_elements ',' json_value
+    ;
+";
+
+const LEXER_FRAGMENTS: &str = r#"HEX [0-9a-fA-F]
+UNICODE u{HEX}{4}
+ESC '\' ({UNICODE}|["\\/bfnrt])
+SAFECODEPOINT [^\x00-\x1F"\\]
+INT 0|([1-9][0-9]*)
+EXP [Ee][+-]?[0-9]+
+WS [\x20\t\n\r]+
+"#;
+
+// the lexer does not know which tokens the parser expects, so literal strings
+// and numbers are put before the pattern and the generic STRING, INTEGER and
+// NUMBER tokens to win ties, and are also accepted wherever a pattern they match
+// or a generic string or number is expected; the same holds for pattern tokens
+// and generic strings, and patterns must not overlap with each other
+struct Builder<'a> {
+    root: &'a Value,
+    // rule names and their alternatives
+    rules: Vec<(String, Vec<String>)>,
+    refs: HashMap<&'a str, String>,
+    // serialized json strings and numbers and their token names
+    literals: IndexMap<String, String>,
+    // regular expressions for the content of pattern strings
+    patterns: Vec<String>,
+    // bounds of integers with a minimum or maximum
+    ranges: Vec<Bounds>,
+    tokens: BTreeSet<&'static str>,
+    any: bool,
+}
+
+impl<'a> Builder<'a> {
+    fn rule(&mut self, mut alternatives: Vec<String>) -> String {
+        // duplicate alternatives, e.g. from enums, would be conflicts
+        let mut seen = BTreeSet::new();
+        alternatives.retain(|alternative| seen.insert(alternative.clone()));
+        let name = format!("s{}", self.rules.len());
+        self.rules.push((name.clone(), alternatives));
+        name
+    }
+
+    fn token(&mut self, name: &'static str) -> String {
+        self.tokens.insert(name);
+        format!("json_{}", name.to_lowercase())
+    }
+
+    fn literal(&mut self, value: &Value) -> String {
+        match value {
+            Value::Null | Value::Bool(_) => format!("'{value}'"),
+            Value::String(_) | Value::Number(_) => {
+                let len = self.literals.len();
+                let name = self
+                    .literals
+                    .entry(value.to_string())
+                    .or_insert_with(|| format!("LIT{len}"));
+                format!("'{name}'")
+            }
+            Value::Array(values) => {
+                let values: Vec<_> = values.iter().map(|v| self.literal(v)).collect();
+                format!("'[' {} ']'", values.join(" ',' "))
+            }
+            Value::Object(obj) => {
+                let members: Vec<_> = obj
+                    .iter()
+                    .map(|(key, v)| {
+                        let key = self.literal(&Value::String(key.clone()));
+                        format!("{key} ':' {}", self.literal(v))
+                    })
+                    .collect();
+                format!("'{{' {} '}}'", members.join(" ',' "))
+            }
+        }
+    }
+
+    // json schema patterns are unanchored, so unless they start with ^ or end
+    // with $, other string content can come before or after them; they are
+    // matched against the raw string content without resolving escapes
+    fn pattern(&mut self, pattern: &str) -> Result<String, Box<dyn Error>> {
+        let hir = ParserBuilder::new().build().parse(pattern)?;
+        let mut parts = match hir.kind() {
+            HirKind::Concat(hirs) => hirs.clone(),
+            _ => vec![hir],
+        };
+        let start = parts
+            .first()
+            .is_some_and(|h| h.kind() == &HirKind::Look(Look::Start));
+        if start {
+            parts.remove(0);
+        }
+        let end = parts
+            .last()
+            .is_some_and(|h| h.kind() == &HirKind::Look(Look::End));
+        if end {
+            parts.pop();
+        }
+        let mut content = vec![];
+        if !start {
+            content.push(Hir::repetition(any_string_content()));
+        }
+        for part in &parts {
+            content.push(string_content(part, pattern)?);
+        }
+        if !end {
+            content.push(Hir::repetition(any_string_content()));
+        }
+        let content = Hir::concat(content).to_string();
+
+        if let Some(i) = self.patterns.iter().position(|p| p == &content) {
+            return Ok(format!("json_pat{i}"));
+        }
+        for other in &self.patterns {
+            if let Some(example) = regex_intersection(&content, other)? {
+                return Err(format!(
+                    "pattern {pattern} overlaps with another pattern, e.g. both match {:?}",
+                    String::from_utf8_lossy(&example)
+                )
+                .into());
+            }
+        }
+        self.patterns.push(content);
+        Ok(format!("json_pat{}", self.patterns.len() - 1))
+    }
+
+    fn range(&mut self, bounds: Bounds) -> Result<String, Box<dyn Error>> {
+        if let (Some(lo), Some(hi)) = bounds {
+            if lo > hi {
+                return Err(format!("minimum {lo} is larger than maximum {hi}").into());
+            }
+        }
+        let i = self
+            .ranges
+            .iter()
+            .position(|&r| r == bounds)
+            .unwrap_or_else(|| {
+                self.ranges.push(bounds);
+                self.ranges.len() - 1
+            });
+        Ok(format!("json_range{i}"))
+    }
+
+    // the lexer cannot know which range the parser expects, so ranges are split
+    // into disjoint intervals, each with its own token, and a range accepts the
+    // tokens of all intervals within it
+    fn intervals(&self) -> Vec<Bounds> {
+        let cuts: BTreeSet<i128> = self
+            .ranges
+            .iter()
+            .flat_map(|&(lo, hi)| [lo, hi.map(|hi| hi + 1)])
+            .flatten()
+            .collect();
+        let cuts: Vec<_> = cuts.into_iter().collect();
+        let mut intervals = vec![];
+        if let (Some(&first), Some(&last)) = (cuts.first(), cuts.last()) {
+            intervals.push((None, Some(first - 1)));
+            intervals.extend(cuts.windows(2).map(|w| (Some(w[0]), Some(w[1] - 1))));
+            intervals.push((Some(last), None));
+        }
+        // integers beyond i64 are not supported
+        intervals.retain(|&interval| {
+            interval.0.is_none_or(|lo| lo <= i64::MAX.into())
+                && self.ranges.iter().any(|&r| contains(r, interval))
+        });
+        intervals
+    }
+
+    fn any(&mut self) -> String {
+        self.any = true;
+        self.tokens.extend(["STRING", "NUMBER"]);
+        "json_value".to_string()
+    }
+
+    // rules for pattern strings and generic strings and numbers, including
+    // all literals and pattern strings the lexer might produce instead
+    fn token_rules(&self) -> Vec<(String, Vec<String>)> {
+        let literals = |integer_only: bool, string: bool| {
+            self.literals
+                .iter()
+                .filter(move |(json, _)| {
+                    let is_string = json.starts_with('"');
+                    is_string == string && (!integer_only || !json.contains(['.', 'e', 'E']))
+                })
+                .map(|(_, name)| format!("'{name}'"))
+        };
+        let intervals = self.intervals();
+        let interval_tokens = || (0..intervals.len()).map(|i| format!("'INT{i}'"));
+        let mut rules = vec![];
+        for (i, &range) in self.ranges.iter().enumerate() {
+            let mut alternatives: Vec<_> = intervals
+                .iter()
+                .enumerate()
+                .filter(|&(_, &interval)| contains(range, interval))
+                .map(|(j, _)| format!("'INT{j}'"))
+                .collect();
+            alternatives.extend(
+                self.literals
+                    .iter()
+                    .filter(|(json, _)| {
+                        json.parse::<i128>()
+                            .is_ok_and(|n| contains(range, (Some(n), Some(n))))
+                    })
+                    .map(|(_, name)| format!("'{name}'")),
+            );
+            rules.push((format!("json_range{i}"), alternatives));
+        }
+        for (i, pattern) in self.patterns.iter().enumerate() {
+            let regex =
+                Regex::new(&format!("^(?:{pattern})$")).expect("pattern was checked before");
+            let mut alternatives = vec![format!("'PAT{i}'")];
+            alternatives.extend(
+                self.literals
+                    .iter()
+                    .filter(|(json, _)| {
+                        json.starts_with('"') && regex.is_match(&json[1..json.len() - 1])
+                    })
+                    .map(|(_, name)| format!("'{name}'")),
+            );
+            rules.push((format!("json_pat{i}"), alternatives));
+        }
+        rules.extend(self.tokens.iter().map(|&token| {
+            let mut alternatives = vec![format!("'{token}'")];
+            match token {
+                "STRING" => {
+                    alternatives.extend((0..self.patterns.len()).map(|i| format!("'PAT{i}'")));
+                    alternatives.extend(literals(false, true));
+                }
+                "INTEGER" => {
+                    alternatives.extend(interval_tokens());
+                    alternatives.extend(literals(true, false));
+                }
+                _ => {
+                    if self.tokens.contains("INTEGER") {
+                        alternatives.push("'INTEGER'".to_string());
+                    }
+                    alternatives.extend(interval_tokens());
+                    alternatives.extend(literals(false, false));
+                }
+            }
+            (format!("json_{}", token.to_lowercase()), alternatives)
+        }));
+        rules
+    }
+
+    fn reference(&mut self, reference: &'a str) -> Result<String, Box<dyn Error>> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| format!("unsupported or invalid reference {reference}"))?;
+        // reserve the rule before descending, references can be recursive
+        let idx = self.rules.len();
+        let name = self.rule(vec![]);
+        self.refs.insert(reference, name.clone());
+        let symbol = self.symbol(target)?;
+        self.rules[idx].1 = vec![symbol];
+        Ok(name)
+    }
+
+    fn symbol(&mut self, schema: &'a Value) -> Result<String, Box<dyn Error>> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok(self.any()),
+            Value::Bool(false) => return Err("schema false cannot be satisfied".into()),
+            Value::Object(obj) => obj,
+            _ => return Err(format!("invalid schema {schema}").into()),
+        };
+        if let Some(keyword) = obj.keys().find(|k| !KEYWORDS.contains(&k.as_str())) {
+            return Err(format!("unsupported schema keyword {keyword}").into());
+        }
+
+        if let Some(reference) = obj.get("$ref") {
+            return self.reference(reference.as_str().ok_or("expected string for $ref")?);
+        } else if let Some(values) = obj.get("enum") {
+            let values = values.as_array().ok_or("expected array for enum")?;
+            let alternatives = values.iter().map(|v| self.literal(v)).collect();
+            return Ok(self.rule(alternatives));
+        } else if let Some(value) = obj.get("const") {
+            let alternative = self.literal(value);
+            return Ok(self.rule(vec![alternative]));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = obj.get(key) {
+                let schemas = schemas
+                    .as_array()
+                    .filter(|s| !s.is_empty())
+                    .ok_or_else(|| format!("expected non-empty array for {key}"))?;
+                let alternatives = schemas
+                    .iter()
+                    .map(|s| self.symbol(s))
+                    .collect::<Result<_, _>>()?;
+                return Ok(self.rule(alternatives));
+            }
+        }
+        if let Some(schemas) = obj.get("allOf") {
+            return match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => self.symbol(schema),
+                _ => Err("allOf is only supported with a single schema".into()),
+            };
+        }
+
+        let types = match obj.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(types)) => types
+                .iter()
+                .map(|t| t.as_str().ok_or("expected string types"))
+                .collect::<Result<_, _>>()?,
+            Some(t) => return Err(format!("invalid type {t}").into()),
+            None if obj.contains_key("properties") => vec!["object"],
+            None if obj.contains_key("items") => vec!["array"],
+            None if obj.contains_key("pattern") => vec!["string"],
+            None => return Ok(self.any()),
+        };
+        let mut alternatives = vec![];
+        for t in types {
+            alternatives.extend(self.typed(t, obj)?);
+        }
+        Ok(self.rule(alternatives))
+    }
+
+    fn typed(
+        &mut self,
+        t: &str,
+        obj: &'a serde_json::Map<String, Value>,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(match t {
+            "string" => match obj.get("pattern") {
+                Some(Value::String(pattern)) => vec![self.pattern(pattern)?],
+                Some(_) => return Err("expected string for pattern".into()),
+                None => vec![self.token("STRING")],
+            },
+            "number" if obj.contains_key("minimum") || obj.contains_key("maximum") => {
+                return Err("minimum and maximum are only supported for integers".into())
+            }
+            "number" => vec![self.token("NUMBER")],
+            "integer" => {
+                let bound = |key| {
+                    obj.get(key)
+                        .map(|v: &Value| {
+                            v.as_i64()
+                                .map(i128::from)
+                                .ok_or_else(|| format!("expected 64-bit integer for {key}"))
+                        })
+                        .transpose()
+                };
+                match (bound("minimum")?, bound("maximum")?) {
+                    (None, None) => vec![self.token("INTEGER")],
+                    bounds => vec![self.range(bounds)?],
+                }
+            }
+            "boolean" => vec!["'true'".to_string(), "'false'".to_string()],
+            "null" => vec!["'null'".to_string()],
+            "array" => {
+                let item = match obj.get("items") {
+                    Some(items) => self.symbol(items)?,
+                    None => self.any(),
+                };
+                let list = format!("s{}", self.rules.len());
+                self.rule(vec![item.clone(), format!("{list} ',' {item}")]);
+                vec!["'[' ']'".to_string(), format!("'[' {list} ']'")]
+            }
+            "object" => self.object(obj)?,
+            _ => return Err(format!("unsupported type {t}").into()),
+        })
+    }
+
+    fn object(
+        &mut self,
+        obj: &'a serde_json::Map<String, Value>,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let properties = obj
+            .get("properties")
+            .map(|p| p.as_object().ok_or("expected object for properties"))
+            .transpose()?
+            .filter(|p| !p.is_empty());
+        let additional = obj.get("additionalProperties");
+        let Some(properties) = properties else {
+            return Ok(match additional {
+                Some(Value::Bool(false)) => vec!["'{' '}'".to_string()],
+                None | Some(Value::Bool(true)) => {
+                    self.any();
+                    vec!["json_obj".to_string()]
+                }
+                Some(_) => return Err("schemas for additionalProperties are not supported".into()),
+            });
+        };
+        if !matches!(additional, None | Some(Value::Bool(false))) {
+            return Err("additionalProperties next to properties are not supported".into());
+        }
+        let required: BTreeSet<&str> = obj
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        // properties are generated in the order of the schema, optional ones can be
+        // skipped; first and rest are the rules for the members from i on without
+        // and with a leading comma, empty if there are no more members
+        let members = properties
+            .iter()
+            .map(|(key, schema)| {
+                let key = self.literal(&Value::String(key.clone()));
+                Ok(format!("{key} ':' {}", self.symbol(schema)?))
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        let (mut first, mut rest) = (String::new(), String::new());
+        for (i, (key, member)) in properties.keys().zip(&members).enumerate().rev() {
+            let optional = !required.contains(key.as_str());
+            let next_rest = rest;
+            if i > 0 {
+                let mut alternatives = vec![format!("',' {member} {next_rest}")];
+                if optional {
+                    alternatives.push(next_rest.clone());
+                }
+                rest = self.rule(alternatives);
+            } else {
+                rest = String::new();
+            }
+            let mut alternatives = vec![format!("{member} {next_rest}")];
+            if optional {
+                alternatives.push(first);
+            }
+            first = self.rule(alternatives);
+        }
+        Ok(vec![format!("'{{' {first} '}}'")])
+    }
+}
+
+// converts a json schema into an LR(1) grammar and lexer for json documents
+// following the schema; supported is roughly the subset of the strict mode of
+// structured outputs, i.e. objects generate their properties in schema order
+// and never generate additional properties if properties are given
+pub fn json_schema_to_lr1(schema: &str) -> Result<(String, String), Box<dyn Error>> {
+    let root: Value = serde_json::from_str(schema)?;
+    let mut builder = Builder {
+        root: &root,
+        rules: vec![],
+        refs: HashMap::new(),
+        literals: IndexMap::new(),
+        patterns: vec![],
+        ranges: vec![],
+        tokens: BTreeSet::new(),
+        any: false,
+    };
+    let start = builder.symbol(&root)?;
+
+    let mut grammar = format!("%start {start}\n\n%%\n");
+    for (name, alternatives) in builder.rules.iter().chain(&builder.token_rules()) {
+        let alternatives: Vec<_> = alternatives.iter().map(|a| a.trim()).collect();
+        write!(
+            grammar,
+            "\n{name}\n    : {}\n    ;\n",
+            alternatives.join("\n    | ")
+        )?;
+    }
+    if builder.any {
+        grammar.push_str(ANY_RULES);
+    }
+
+    let mut lexer = format!("{LEXER_FRAGMENTS}\n%%\n\n");
+    for (json, name) in &builder.literals {
+        writeln!(lexer, "{name} {}", lexer_pattern(&escape(json)))?;
+    }
+    for (i, pattern) in builder.patterns.iter().enumerate() {
+        writeln!(lexer, "PAT{i} '\"' {} '\"'", lexer_pattern(pattern))?;
+    }
+    for (i, &bounds) in builder.intervals().iter().enumerate() {
+        let (lo, hi) = (
+            bounds.0.unwrap_or(i64::MIN.into()),
+            bounds.1.unwrap_or(i64::MAX.into()),
+        );
+        writeln!(
+            lexer,
+            "INT{i} {}",
+            integer_range_pattern(lo as i64, hi as i64)?
+        )?;
+    }
+    // integers before numbers, so they are preferred when both are used
+    for token in &builder.tokens {
+        let pattern = match *token {
+            "STRING" => r#"'"' ({ESC}|{SAFECODEPOINT})* '"'"#,
+            "NUMBER" => "-?{INT}( '.' [0-9]+)?{EXP}?",
+            "INTEGER" => "-?{INT}",
+            _ => unreachable!("unknown token {token}"),
+        };
+        writeln!(lexer, "{token} {pattern}")?;
+    }
+    lexer.push_str("; {WS}\n");
+    Ok((grammar, lexer))
+}
+
+// constraint for json documents following a json schema, compiled into an LR(1)
+// grammar constraint via json_schema_to_lr1
+pub struct JsonSchemaConstraint {
+    inner: LR1GrammarConstraint,
+}
+
+impl JsonSchemaConstraint {
+    pub fn new(schema: &str, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        Self::with_limits(schema, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        schema: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (grammar, lexer) = json_schema_to_lr1(schema)?;
+        // generated grammars have many single production rules
+        let grammar = inline_rules(&grammar)?;
+        let inner =
+            LR1GrammarConstraint::with_limits(grammar.grammar(), &lexer, continuations, limits)?;
+        Ok(Self { inner })
+    }
+
+    pub fn inner(&self) -> &LR1GrammarConstraint {
+        &self.inner
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        self.inner.continuations()
+    }
+}
+
+// numeric formats schemars adds to integers and numbers, they only restate
+// the type and bounds and are dropped before compiling
+#[cfg(feature = "schemars")]
+const NUMERIC_FORMATS: &[&str] = &[
+    "int", "int8", "int16", "int32", "int64", "int128", "uint", "uint8", "uint16", "uint32",
+    "uint64", "uint128", "float", "double",
+];
+
+#[cfg(feature = "schemars")]
+fn strip_numeric_formats(value: &mut Value) {
+    match value {
+        Value::Object(obj) => {
+            if obj
+                .get("format")
+                .and_then(Value::as_str)
+                .is_some_and(|format| NUMERIC_FORMATS.contains(&format))
+            {
+                obj.remove("format");
+            }
+            obj.values_mut().for_each(strip_numeric_formats);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_numeric_formats),
+        _ => {}
+    }
+}
+
+// constraint for json documents that deserialize into the given type, derived
+// from the json schema schemars generates for it
+#[cfg(feature = "schemars")]
+pub fn constraint_for<T: schemars::JsonSchema>(
+    continuations: Vec<Vec<u8>>,
+) -> Result<JsonSchemaConstraint, Box<dyn Error>> {
+    let mut schema = schemars::schema_for!(T).to_value();
+    strip_numeric_formats(&mut schema);
+    JsonSchemaConstraint::new(&serde_json::to_string(&schema)?, continuations)
+}
+
+impl MemoryUsage for JsonSchemaConstraint {
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage()
+    }
+}
+
+impl Constraint for JsonSchemaConstraint {
+    type State = LR1State;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.inner.get_state(prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        self.inner.get_start_state()
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.inner.is_match_state(state)
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.inner.get_valid_continuations(state)
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.inner.get_next_state(state, continuation)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        self.inner.has_same_continuations(state, next)
+    }
+
+    fn last_valid_truncation(&self, bytes: &[u8]) -> Option<usize> {
+        self.inner.last_valid_truncation(bytes)
+    }
+}
+
+impl ByteConstraint for JsonSchemaConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        self.inner.continuations()
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.inner.get_next_state_with_bytes(state, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint};
+
+    fn constraint(schema: &str) -> LR1GrammarConstraint {
+        let (grammar, lexer) = json_schema_to_lr1(schema).unwrap();
+        LR1GrammarConstraint::new(&grammar, &lexer, (0..=255).map(|b| vec![b]).collect()).unwrap()
+    }
+
+    fn is_match(constraint: &LR1GrammarConstraint, input: &str) -> bool {
+        constraint
+            .get_state(input.as_bytes())
+            .is_some_and(|state| constraint.is_match_state(&state))
+    }
+
+    #[test]
+    fn test_json_schema_object() {
+        let c = constraint(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "age": {"type": ["integer", "null"]},
+                    "tags": {"type": "array", "items": {"enum": ["a", "b", 1]}},
+                    "extra": {"type": "boolean"}
+                },
+                "required": ["name", "tags"],
+                "additionalProperties": false
+            }"#,
+        );
+        assert!(is_match(&c, r#"{"name": "x", "age": 3, "tags": []}"#));
+        assert!(is_match(
+            &c,
+            r#"{"name": "x", "tags": ["a", 1], "extra": true}"#
+        ));
+        assert!(is_match(&c, r#"{ "name":"x" , "age":null,"tags":["b"] }"#));
+        // missing required, wrong order, wrong types, additional properties
+        assert!(!is_match(&c, r#"{"name": "x"}"#));
+        assert!(!is_match(&c, r#"{"tags": [], "name": "x"}"#));
+        assert!(!is_match(&c, r#"{"name": "x", "age": 3.5, "tags": []}"#));
+        assert!(!is_match(&c, r#"{"name": "x", "tags": ["c"]}"#));
+        assert!(!is_match(&c, r#"{"name": "x", "tags": [], "other": 1}"#));
+        assert!(c.get_state(br#"{"name": "x", "tags": [], "#).is_some());
+        assert!(c.get_state(br#"{"name": "x", ,"#).is_none());
+        // string values can be equal to keys or enum values
+        assert!(is_match(&c, r#"{"name": "name", "tags": ["a"]}"#));
+        assert!(is_match(&c, r#"{"name": "a", "age": 1, "tags": [1]}"#));
+        assert!(!is_match(&c, r#"{"name": "x", "age": 1.0, "tags": []}"#));
+
+        let c = constraint(
+            r#"{"type": "object", "properties": {
+                "version": {"const": {"major": 1, "tags": ["x"]}},
+                "value": {"type": ["number", "integer"]}
+            }, "required": ["version", "value"]}"#,
+        );
+        assert!(is_match(
+            &c,
+            r#"{"version": {"major": 1, "tags": ["x"]}, "value": 1}"#
+        ));
+        assert!(is_match(
+            &c,
+            r#"{"version": {"major": 1, "tags": ["x"]}, "value": 1.5}"#
+        ));
+        assert!(!is_match(
+            &c,
+            r#"{"version": {"major": 2, "tags": ["x"]}, "value": 1}"#
+        ));
+    }
+
+    #[test]
+    fn test_json_schema_refs() {
+        let c = constraint(
+            r##"{
+                "$defs": {
+                    "node": {
+                        "type": "object",
+                        "properties": {
+                            "value": {"type": "number"},
+                            "children": {"type": "array", "items": {"$ref": "#/$defs/node"}}
+                        },
+                        "required": ["value", "children"]
+                    }
+                },
+                "anyOf": [{"$ref": "#/$defs/node"}, {"const": "leaf"}]
+            }"##,
+        );
+        assert!(is_match(&c, r#""leaf""#));
+        assert!(is_match(
+            &c,
+            r#"{"value": 1, "children": [{"value": -2.5e3, "children": []}]}"#
+        ));
+        assert!(!is_match(&c, r#"{"value": 1, "children": ["leaf"]}"#));
+    }
+
+    #[test]
+    fn test_json_schema_pattern() {
+        let continuations: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let c = JsonSchemaConstraint::new(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "id": {"type": "string", "pattern": "^[a-z]{2}-[0-9]+$"},
+                    "name": {"type": "string"},
+                    "kind": {"enum": ["ab-1", "other"]},
+                    "note": {"type": "string", "pattern": "^<.*>$"}
+                },
+                "required": ["id", "name", "note"]
+            }"#,
+            continuations,
+        )
+        .unwrap();
+        let check = |input: &str| c.check(input.as_bytes());
+        assert!(check(r#"{"id": "ab-12", "name": "n", "note": "<>"}"#));
+        // the enum value matches the id pattern, and pattern strings are strings
+        assert!(check(r#"{"id": "ab-1", "name": "ab-1", "note": "<a>"}"#));
+        assert!(check(r#"{"id": "ab-1", "name": "<b>", "note": "<c>"}"#));
+        assert!(!check(r#"{"id": "ab-", "name": "n", "note": "<>"}"#));
+        assert!(!check(r#"{"id": "abc-1", "name": "n", "note": "<>"}"#));
+        assert!(!check(r#"{"id": "ab-1", "name": "n", "note": "<>!"}"#));
+        // . does not match quotes, so the string cannot end early
+        assert!(!check(r#"{"id": "ab-1", "name": "n", "note": "<">"}"#));
+        assert!(c.get_state(br#"{"id": "ab-"#).is_some());
+        assert!(c.get_state(br#"{"id": "ab1"#).is_none());
+
+        // unanchored patterns can be surrounded by other content
+        let c = constraint(r#"{"pattern": "x.?y"}"#);
+        assert!(is_match(&c, r#""xy""#));
+        assert!(is_match(&c, r#""a xzy!""#));
+        assert!(!is_match(&c, r#""x--y""#));
+
+        // quotes need escapes, and overlapping patterns are ambiguous for the lexer
+        assert!(json_schema_to_lr1(r#"{"pattern": "a\"b"}"#).is_err());
+        assert!(json_schema_to_lr1(r#"{"pattern": "a\\bb"}"#).is_err());
+        assert!(json_schema_to_lr1(
+            r#"{"anyOf": [{"pattern": "^[0-9]+$"}, {"pattern": "^[0-5]$"}]}"#
+        )
+        .is_err());
+        assert!(json_schema_to_lr1(
+            r#"{"anyOf": [{"pattern": "^[0-9]+$"}, {"pattern": "^[a-z]+$"}]}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_json_schema_any() {
+        let c = constraint(r#"{"type": "object"}"#);
+        assert!(is_match(&c, r#"{"a": [1, {"b": null}], "c": "d"}"#));
+        assert!(!is_match(&c, "[1]"));
+        let c = constraint(r#"{"type": "array", "description": "anything"}"#);
+        assert!(is_match(&c, r#"[1, "a", {}]"#));
+
+        assert!(json_schema_to_lr1(r#"{"type": "string", "format": "date"}"#).is_err());
+        assert!(json_schema_to_lr1(r##"{"$ref": "#/$defs/missing"}"##).is_err());
+        assert!(json_schema_to_lr1(
+            r#"{"type": "object", "properties": {"a": {}}, "additionalProperties": true}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_json_schema_integer_range() {
+        let c = constraint(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "age": {"type": "integer", "minimum": 0, "maximum": 150},
+                    "delta": {"type": "integer", "minimum": -10},
+                    "count": {"type": "integer"},
+                    "level": {"enum": [3, 200]},
+                    "code": {"type": "integer", "minimum": 100, "maximum": 199}
+                },
+                "required": ["age", "delta", "count", "level", "code"]
+            }"#,
+        );
+        let doc = |age: i64, delta: i64, level: i64, code: i64| {
+            format!(
+                r#"{{"age": {age}, "delta": {delta}, "count": 5, "level": {level}, "code": {code}}}"#
+            )
+        };
+        assert!(is_match(&c, &doc(0, -10, 3, 100)));
+        assert!(is_match(&c, &doc(150, 1000000, 200, 199)));
+        assert!(is_match(&c, &doc(42, 0, 3, 150)));
+        assert!(!is_match(&c, &doc(151, 0, 3, 150)));
+        assert!(!is_match(&c, &doc(-1, 0, 3, 150)));
+        assert!(!is_match(&c, &doc(42, -11, 3, 150)));
+        assert!(!is_match(&c, &doc(42, 0, 4, 150)));
+        assert!(!is_match(&c, &doc(42, 0, 3, 99)));
+
+        assert!(json_schema_to_lr1(r#"{"type": "integer", "minimum": 5, "maximum": 4}"#).is_err());
+        assert!(json_schema_to_lr1(r#"{"type": "number", "minimum": 0}"#).is_err());
+        assert!(json_schema_to_lr1(r#"{"type": "integer", "minimum": 0.5}"#).is_err());
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn test_constraint_for() {
+        #[allow(dead_code)]
+        #[derive(schemars::JsonSchema)]
+        enum Mood {
+            Happy,
+            Sad,
+        }
+
+        #[allow(dead_code)]
+        #[derive(schemars::JsonSchema)]
+        struct Response {
+            name: String,
+            age: u8,
+            score: f64,
+            mood: Mood,
+            tags: Vec<String>,
+            nickname: Option<String>,
+        }
+
+        let c = constraint_for::<Response>((0..=255).map(|b| vec![b]).collect()).unwrap();
+        let is_match = |input: &str| {
+            c.get_state(input.as_bytes())
+                .is_some_and(|state| c.is_match_state(&state))
+        };
+        assert!(is_match(
+            r#"{"name": "a", "age": 30, "score": 1.5, "mood": "Happy", "tags": [], "nickname": null}"#
+        ));
+        assert!(is_match(
+            r#"{"name": "a", "age": 255, "score": -2, "mood": "Sad", "tags": ["x"], "nickname": "b"}"#
+        ));
+        assert!(!is_match(
+            r#"{"name": "a", "age": 256, "score": 1, "mood": "Sad", "tags": [], "nickname": null}"#
+        ));
+        assert!(!is_match(
+            r#"{"name": "a", "age": 3, "score": 1, "mood": "Angry", "tags": [], "nickname": null}"#
+        ));
+    }
+}
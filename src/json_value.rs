@@ -0,0 +1,247 @@
+use std::{collections::BTreeMap, error::Error};
+
+use serde_json::{Map, Value};
+
+use crate::{lr1::Span, LR1Parse};
+
+// byte spans of all values in a json document, keyed by json pointer
+pub type JsonSpans = BTreeMap<String, (usize, usize)>;
+
+// builds json values from the terminals of a parse instead of its rules, so
+// it works for every grammar that lexes json into its usual tokens, like
+// grammars/json or the grammars from json_schema_to_lr1
+struct JsonBuilder<'p> {
+    tokens: Vec<(&'p [u8], Span)>,
+    pos: usize,
+    spans: Option<JsonSpans>,
+}
+
+fn terminals<'p>(parse: &'p LR1Parse<'_>, tokens: &mut Vec<(&'p [u8], Span)>) {
+    match parse {
+        LR1Parse::Empty(..) => {}
+        LR1Parse::Terminal(_, span, value) => tokens.push((value, *span)),
+        LR1Parse::NonTerminal(_, children) => {
+            for child in children {
+                terminals(child, tokens);
+            }
+        }
+    }
+}
+
+// escape a key as json pointer reference token, see rfc 6901
+fn pointer_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+impl JsonBuilder<'_> {
+    fn error(&self, expected: &str) -> Box<dyn Error> {
+        match self.tokens.get(self.pos) {
+            Some((value, (start, _))) => format!(
+                "expected {expected} at byte {start}, but got {:?}",
+                String::from_utf8_lossy(value)
+            )
+            .into(),
+            None => format!("expected {expected}, but got end of input").into(),
+        }
+    }
+
+    fn peek(&self) -> Option<&[u8]> {
+        self.tokens.get(self.pos).map(|&(value, _)| value)
+    }
+
+    fn expect(&mut self, token: &[u8]) -> Result<Span, Box<dyn Error>> {
+        match self.tokens.get(self.pos) {
+            Some(&(value, span)) if value == token => {
+                self.pos += 1;
+                Ok(span)
+            }
+            _ => Err(self.error(&format!("{:?}", String::from_utf8_lossy(token)))),
+        }
+    }
+
+    fn value(&mut self, pointer: &mut String) -> Result<Value, Box<dyn Error>> {
+        let Some(&(token, (start, mut end))) = self.tokens.get(self.pos) else {
+            return Err(self.error("a json value"));
+        };
+        let value = match token {
+            b"{" => {
+                self.pos += 1;
+                let mut map = Map::new();
+                while self.peek() != Some(b"}") {
+                    if !map.is_empty() {
+                        self.expect(b",")?;
+                    }
+                    let key = match self.tokens.get(self.pos) {
+                        Some((key, _)) => match serde_json::from_slice(key) {
+                            Ok(Value::String(key)) => key,
+                            _ => return Err(self.error("a json string key")),
+                        },
+                        None => return Err(self.error("a json string key")),
+                    };
+                    self.pos += 1;
+                    self.expect(b":")?;
+                    let len = pointer.len();
+                    pointer.push('/');
+                    pointer.push_str(&pointer_token(&key));
+                    let value = self.value(pointer)?;
+                    pointer.truncate(len);
+                    map.insert(key, value);
+                }
+                end = self.expect(b"}")?.1;
+                Value::Object(map)
+            }
+            b"[" => {
+                self.pos += 1;
+                let mut values = vec![];
+                while self.peek() != Some(b"]") {
+                    if !values.is_empty() {
+                        self.expect(b",")?;
+                    }
+                    let len = pointer.len();
+                    pointer.push_str(&format!("/{}", values.len()));
+                    values.push(self.value(pointer)?);
+                    pointer.truncate(len);
+                }
+                end = self.expect(b"]")?.1;
+                Value::Array(values)
+            }
+            token => {
+                // strings, numbers and literals are single tokens
+                let value = match serde_json::from_slice(token) {
+                    Ok(Value::Object(_) | Value::Array(_)) | Err(_) => {
+                        return Err(self.error("a json value"));
+                    }
+                    Ok(value) => value,
+                };
+                self.pos += 1;
+                value
+            }
+        };
+        if let Some(spans) = &mut self.spans {
+            spans.insert(pointer.clone(), (start, end));
+        }
+        Ok(value)
+    }
+}
+
+fn to_value(
+    parse: &LR1Parse<'_>,
+    spans: Option<JsonSpans>,
+) -> Result<(Value, Option<JsonSpans>), Box<dyn Error>> {
+    let mut tokens = vec![];
+    terminals(parse, &mut tokens);
+    let mut builder = JsonBuilder {
+        tokens,
+        pos: 0,
+        spans,
+    };
+    let value = builder.value(&mut String::new())?;
+    if builder.pos < builder.tokens.len() {
+        return Err(builder.error("end of input"));
+    }
+    Ok((value, builder.spans))
+}
+
+impl LR1Parse<'_> {
+    // json value of a parse of a json grammar
+    pub fn to_value(&self) -> Result<Value, Box<dyn Error>> {
+        to_value(self, None).map(|(value, _)| value)
+    }
+
+    // json value together with the byte spans of it and all nested values
+    pub fn to_value_with_spans(&self) -> Result<(Value, JsonSpans), Box<dyn Error>> {
+        to_value(self, Some(JsonSpans::new()))
+            .map(|(value, spans)| (value, spans.unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{fs, path::PathBuf};
+
+    use serde_json::json;
+
+    use crate::{json_schema_to_lr1, LR1GrammarParser};
+
+    fn load_json_parser() -> LR1GrammarParser {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("grammars/json");
+        let grammar = fs::read_to_string(dir.join("json.y")).unwrap();
+        let lexer = fs::read_to_string(dir.join("json.l")).unwrap();
+        LR1GrammarParser::new(&grammar, &lexer).unwrap()
+    }
+
+    #[test]
+    fn test_to_value() {
+        let parser = load_json_parser();
+        let input = r#"{"a/b": [1, -2.5e3, "x\nä"], "c~": {"d": null, "e": true}, "f": {}}"#;
+        // pruning does not change the value
+        for (skip_empty, collapse_single) in [(false, false), (true, true)] {
+            let parse = parser.parse(input, skip_empty, collapse_single).unwrap();
+            assert_eq!(
+                parse.to_value().unwrap(),
+                serde_json::from_str::<Value>(input).unwrap()
+            );
+        }
+
+        let parse = parser.parse(input, false, false).unwrap();
+        let (value, spans) = parse.to_value_with_spans().unwrap();
+        assert_eq!(value["c~"]["e"], json!(true));
+        // keys keep the order of the input
+        let keys: Vec<_> = value.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["a/b", "c~", "f"]);
+        assert_eq!(spans[""], (0, input.len()));
+        for (pointer, expected) in [
+            ("/a~1b", r#"[1, -2.5e3, "x\nä"]"#),
+            ("/a~1b/1", "-2.5e3"),
+            ("/a~1b/2", r#""x\nä""#),
+            ("/c~0/d", "null"),
+            ("/f", "{}"),
+        ] {
+            let (start, end) = spans[pointer];
+            assert_eq!(&input[start..end], expected);
+            assert!(value.pointer(pointer).is_some());
+        }
+        assert_eq!(spans.len(), 9);
+
+        let parse = parser.parse("[]", false, false).unwrap();
+        assert_eq!(parse.to_value().unwrap(), json!([]));
+    }
+
+    #[test]
+    fn test_to_value_schema_grammar() {
+        let (grammar, lexer) = json_schema_to_lr1(
+            r#"{"type": "object", "properties": {"name": {"type": "string"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}}},
+                "required": ["name", "tags"]}"#,
+        )
+        .unwrap();
+        let parser = LR1GrammarParser::new(&grammar, &lexer).unwrap();
+        let input = r#"{"name": "x", "tags": ["b", "a"]}"#;
+        let parse = parser.parse(input, true, true).unwrap();
+        assert_eq!(
+            parse.to_value().unwrap(),
+            json!({"name": "x", "tags": ["b", "a"]})
+        );
+    }
+
+    #[test]
+    fn test_to_value_errors() {
+        let parser =
+            LR1GrammarParser::new("%start S\n%%\nS: 'A' | 'A' 'A' ;", "%%\nA [a-z]+\n; \\s+")
+                .unwrap();
+        let parse = parser.parse("true", false, false).unwrap();
+        assert_eq!(parse.to_value().unwrap(), json!(true));
+        assert!(parser
+            .parse("abc", false, false)
+            .unwrap()
+            .to_value()
+            .is_err());
+        assert!(parser
+            .parse("true false", false, false)
+            .unwrap()
+            .to_value()
+            .is_err());
+    }
+}
@@ -0,0 +1,907 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Write,
+    iter::Peekable,
+    str::CharIndices,
+};
+
+use indexmap::IndexMap;
+
+use crate::{
+    gbnf::{escape_char, escaped_char, repetition},
+    utils::lexer_pattern,
+};
+
+// terminals of the common module of lark, with patterns the
+// regex engine supports, e.g. without lookbehind
+const COMMON: &str = r##"
+DIGIT: "0".."9"
+HEXDIGIT: "a".."f" | "A".."F" | DIGIT
+INT: DIGIT+
+SIGNED_INT: ["+" | "-"] INT
+DECIMAL: INT "." INT? | "." INT
+_EXP: ("e" | "E") SIGNED_INT
+FLOAT: INT _EXP | DECIMAL _EXP?
+SIGNED_FLOAT: ["+" | "-"] FLOAT
+NUMBER: FLOAT | INT
+SIGNED_NUMBER: ["+" | "-"] NUMBER
+ESCAPED_STRING: "\"" /([^"\\]|\\.)*/ "\""
+LCASE_LETTER: "a".."z"
+UCASE_LETTER: "A".."Z"
+LETTER: UCASE_LETTER | LCASE_LETTER
+WORD: LETTER+
+CNAME: ("_" | LETTER) ("_" | LETTER | DIGIT)*
+WS_INLINE: (" " | /\t/)+
+WS: /[ \t\f\r\n]/+
+CR: /\r/
+LF: /\n/
+NEWLINE: (CR? LF)+
+SH_COMMENT: /#[^\n]*/
+CPP_COMMENT: /\/\/[^\n]*/
+C_COMMENT: /\/\*([^*]|\*+[^*\/])*\*+\//
+SQL_COMMENT: /--[^\n]*/
+"##;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(String, bool),
+    Pattern(String, String),
+    Range(char, char),
+    Ref(String),
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Repeat(Box<Expr>, u32, Option<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Name(String),
+    Directive(String),
+    Literal(String, bool),
+    Pattern(String, String),
+    Number(u32),
+    Priority(i32),
+    Colon,
+    Bar,
+    Open,
+    Close,
+    OpenOptional,
+    CloseOptional,
+    Optional,
+    Keep,
+    Star,
+    Plus,
+    Tilde,
+    Range,
+    Arrow,
+}
+
+fn is_terminal(name: &str) -> bool {
+    name.trim_start_matches('_')
+        .starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+fn tokenize(lark: &str) -> Result<Vec<Lexeme>, Box<dyn Error>> {
+    let mut lexemes = vec![];
+    let mut chars = lark.char_indices().peekable();
+    let is_name_char = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    let number = |chars: &mut Peekable<CharIndices>, mut digits: String| -> Result<u32, _> {
+        while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        digits.parse()
+    };
+    while let Some((pos, c)) = chars.next() {
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            '/' if chars.next_if(|&(_, c)| c == '/').is_some() => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            '%' => {
+                let mut directive = String::from(c);
+                while let Some((_, c)) = chars.next_if(|&(_, c)| c != '\n') {
+                    directive.push(c);
+                }
+                if let Some(comment) = directive.find("//") {
+                    directive.truncate(comment);
+                }
+                Lexeme::Directive(directive.trim().to_string())
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::from(c);
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_name_char(c)) {
+                    name.push(c);
+                }
+                Lexeme::Name(name)
+            }
+            '"' => {
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => literal.push(escaped_char(&mut chars)?),
+                        Some((_, c)) => literal.push(c),
+                        None => return Err(format!("unterminated string at position {pos}").into()),
+                    }
+                }
+                let mut lookahead = chars.clone();
+                let insensitive = lookahead.next().is_some_and(|(_, c)| c == 'i')
+                    && !lookahead.peek().is_some_and(|(_, c)| is_name_char(c));
+                if insensitive {
+                    chars.next();
+                }
+                Lexeme::Literal(literal, insensitive)
+            }
+            '/' => {
+                let mut pattern = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '/')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, '/')) => pattern.push('/'),
+                            Some((_, c)) => {
+                                pattern.push('\\');
+                                pattern.push(c);
+                            }
+                            None => break,
+                        },
+                        Some((_, '\n')) | None => {
+                            return Err(format!("unterminated regex at position {pos}").into())
+                        }
+                        Some((_, c)) => pattern.push(c),
+                    }
+                }
+                let mut flags = String::new();
+                while let Some((_, c)) = chars.next_if(|&(_, c)| "imslux".contains(c)) {
+                    flags.push(c);
+                }
+                Lexeme::Pattern(pattern, flags)
+            }
+            '.' if chars.next_if(|&(_, c)| c == '.').is_some() => Lexeme::Range,
+            '.' => {
+                let negative = chars.next_if(|&(_, c)| c == '-').is_some();
+                let priority = number(&mut chars, String::new())
+                    .map_err(|_| format!("invalid priority at position {pos}"))?
+                    as i32;
+                Lexeme::Priority(if negative { -priority } else { priority })
+            }
+            c if c.is_ascii_digit() => Lexeme::Number(number(&mut chars, String::from(c))?),
+            '-' if chars.next_if(|&(_, c)| c == '>').is_some() => Lexeme::Arrow,
+            ':' => Lexeme::Colon,
+            '|' => Lexeme::Bar,
+            '(' => Lexeme::Open,
+            ')' => Lexeme::Close,
+            '[' => Lexeme::OpenOptional,
+            ']' => Lexeme::CloseOptional,
+            '?' => Lexeme::Optional,
+            '!' => Lexeme::Keep,
+            '*' => Lexeme::Star,
+            '+' => Lexeme::Plus,
+            '~' => Lexeme::Tilde,
+            '{' => return Err(format!("templates are not supported, at position {pos}").into()),
+            c => return Err(format!("unexpected character {c:?} at position {pos}").into()),
+        };
+        lexemes.push(lexeme);
+    }
+    Ok(lexemes)
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos + offset)
+    }
+
+    // whether a rule or terminal definition starts here,
+    // e.g. ?expr: or NUMBER.2:
+    fn at_definition(&self) -> bool {
+        let mut offset = 0;
+        if matches!(self.peek(0), Some(Lexeme::Optional | Lexeme::Keep)) {
+            offset += 1;
+        }
+        if !matches!(self.peek(offset), Some(Lexeme::Name(_))) {
+            return false;
+        }
+        offset += 1;
+        if matches!(self.peek(offset), Some(Lexeme::Priority(_))) {
+            offset += 1;
+        }
+        self.peek(offset) == Some(&Lexeme::Colon)
+    }
+
+    fn at_sequence_end(&self) -> bool {
+        self.at_definition()
+            || matches!(
+                self.peek(0),
+                None | Some(
+                    Lexeme::Bar
+                        | Lexeme::Close
+                        | Lexeme::CloseOptional
+                        | Lexeme::Arrow
+                        | Lexeme::Directive(_)
+                )
+            )
+    }
+
+    fn expect(&mut self, lexeme: Lexeme) -> Result<(), Box<dyn Error>> {
+        match self.peek(0) {
+            Some(next) if *next == lexeme => {
+                self.pos += 1;
+                Ok(())
+            }
+            next => Err(format!("expected {lexeme:?}, got {next:?}").into()),
+        }
+    }
+
+    fn alternatives(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut alternatives = vec![];
+        loop {
+            alternatives.push(self.sequence()?);
+            // aliases only name alternatives in the parse tree
+            if self.peek(0) == Some(&Lexeme::Arrow) {
+                let Some(Lexeme::Name(_)) = self.peek(1) else {
+                    return Err("expected alias name after ->".into());
+                };
+                self.pos += 2;
+            }
+            if self.peek(0) != Some(&Lexeme::Bar) {
+                break;
+            }
+            self.pos += 1;
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Alt(alternatives)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut items = vec![];
+        while !self.at_sequence_end() {
+            items.push(self.term()?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+
+    fn term(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut item = self.primary()?;
+        loop {
+            let (min, max) = match self.peek(0) {
+                // a ? can also start the next definition
+                Some(Lexeme::Optional) if !self.at_definition() => (0, Some(1)),
+                Some(Lexeme::Star) => (0, None),
+                Some(Lexeme::Plus) => (1, None),
+                Some(Lexeme::Tilde) => {
+                    self.pos += 1;
+                    let Some(&Lexeme::Number(min)) = self.peek(0) else {
+                        return Err("expected number after ~".into());
+                    };
+                    let mut max = min;
+                    if self.peek(1) == Some(&Lexeme::Range) {
+                        let Some(&Lexeme::Number(n)) = self.peek(2) else {
+                            return Err("expected number after ..".into());
+                        };
+                        max = n;
+                        self.pos += 2;
+                    }
+                    if max < min {
+                        return Err(format!("invalid repetition ~ {min}..{max}").into());
+                    }
+                    (min, Some(max))
+                }
+                _ => break,
+            };
+            self.pos += 1;
+            item = Expr::Repeat(Box::new(item), min, max);
+        }
+        Ok(item)
+    }
+
+    fn primary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let lexeme = self.peek(0).cloned().ok_or("unexpected end of grammar")?;
+        self.pos += 1;
+        Ok(match lexeme {
+            Lexeme::Name(name) => Expr::Ref(name),
+            Lexeme::Literal(start, _) if self.peek(0) == Some(&Lexeme::Range) => {
+                let Some(Lexeme::Literal(end, _)) = self.peek(1).cloned() else {
+                    return Err("expected string after ..".into());
+                };
+                self.pos += 2;
+                let single = |s: &str| {
+                    let mut chars = s.chars();
+                    chars.next().filter(|_| chars.next().is_none())
+                };
+                match (single(&start), single(&end)) {
+                    (Some(start), Some(end)) if start <= end => Expr::Range(start, end),
+                    _ => return Err(format!("invalid range {start:?}..{end:?}").into()),
+                }
+            }
+            Lexeme::Literal(literal, insensitive) => Expr::Literal(literal, insensitive),
+            Lexeme::Pattern(pattern, flags) => Expr::Pattern(pattern, flags),
+            Lexeme::Open => {
+                let inner = self.alternatives()?;
+                self.expect(Lexeme::Close)?;
+                inner
+            }
+            Lexeme::OpenOptional => {
+                let inner = self.alternatives()?;
+                self.expect(Lexeme::CloseOptional)?;
+                Expr::Repeat(Box::new(inner), 0, Some(1))
+            }
+            lexeme => return Err(format!("unexpected {lexeme:?}").into()),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Definitions {
+    rules: IndexMap<String, Expr>,
+    terminals: IndexMap<String, (Expr, i32)>,
+    ignore: Vec<Expr>,
+}
+
+// replaces references to other terminals by their definitions,
+// so imported terminals do not depend on the module they come from
+fn inline(expr: &Expr, terminals: &IndexMap<String, (Expr, i32)>) -> Result<Expr, Box<dyn Error>> {
+    Ok(match expr {
+        Expr::Ref(name) => {
+            let (expr, _) = terminals
+                .get(name)
+                .ok_or_else(|| format!("terminal {name} not found"))?;
+            inline(expr, terminals)?
+        }
+        Expr::Seq(items) => Expr::Seq(
+            items
+                .iter()
+                .map(|e| inline(e, terminals))
+                .collect::<Result<_, _>>()?,
+        ),
+        Expr::Alt(items) => Expr::Alt(
+            items
+                .iter()
+                .map(|e| inline(e, terminals))
+                .collect::<Result<_, _>>()?,
+        ),
+        Expr::Repeat(inner, min, max) => {
+            Expr::Repeat(Box::new(inline(inner, terminals)?), *min, *max)
+        }
+        expr => expr.clone(),
+    })
+}
+
+fn import(args: &str, defs: &mut Definitions) -> Result<(), Box<dyn Error>> {
+    // %import common.NAME, %import common.NAME -> ALIAS or %import common (A, B)
+    let imports: Vec<(&str, &str, &str)> = match args.split_once('(') {
+        Some((module, names)) => names
+            .trim_end()
+            .strip_suffix(')')
+            .ok_or("expected ) at the end of import")?
+            .split(',')
+            .map(|name| (module.trim(), name.trim(), name.trim()))
+            .collect(),
+        None => {
+            let (path, alias) = args.split_once("->").unwrap_or((args, ""));
+            let (module, name) = path
+                .trim()
+                .rsplit_once('.')
+                .ok_or_else(|| format!("invalid import {args}"))?;
+            let alias = alias.trim();
+            vec![(module, name, if alias.is_empty() { name } else { alias })]
+        }
+    };
+    let common = parse_definitions(COMMON)?;
+    for (module, name, alias) in imports {
+        if module != "common" {
+            return Err(format!("cannot import from {module}, only common is available").into());
+        }
+        let (expr, priority) = common
+            .terminals
+            .get(name)
+            .ok_or_else(|| format!("terminal {name} not found in common"))?;
+        let expr = inline(expr, &common.terminals)?;
+        if defs
+            .terminals
+            .insert(alias.to_string(), (expr, *priority))
+            .is_some()
+        {
+            return Err(format!("duplicate terminal {alias}").into());
+        }
+    }
+    Ok(())
+}
+
+fn parse_definitions(lark: &str) -> Result<Definitions, Box<dyn Error>> {
+    let mut parser = Parser {
+        lexemes: tokenize(lark)?,
+        pos: 0,
+    };
+    let mut defs = Definitions::default();
+    while let Some(lexeme) = parser.peek(0).cloned() {
+        if let Lexeme::Directive(directive) = lexeme {
+            parser.pos += 1;
+            let (name, args) = directive
+                .split_once(char::is_whitespace)
+                .unwrap_or((&directive, ""));
+            match name {
+                "%import" => import(args, &mut defs)?,
+                "%ignore" => {
+                    let mut parser = Parser {
+                        lexemes: tokenize(args)?,
+                        pos: 0,
+                    };
+                    defs.ignore.push(parser.alternatives()?);
+                    if parser.pos < parser.lexemes.len() {
+                        return Err(format!(
+                            "unexpected {:?} in %ignore",
+                            parser.lexemes[parser.pos]
+                        )
+                        .into());
+                    }
+                }
+                name => return Err(format!("unsupported directive {name}").into()),
+            }
+            continue;
+        }
+        if !parser.at_definition() {
+            return Err(format!("expected rule or terminal definition, got {lexeme:?}").into());
+        }
+        // modifiers and priorities of rules only shape the parse tree
+        if matches!(lexeme, Lexeme::Optional | Lexeme::Keep) {
+            parser.pos += 1;
+        }
+        let Some(Lexeme::Name(name)) = parser.peek(0).cloned() else {
+            unreachable!("checked by at_definition");
+        };
+        parser.pos += 1;
+        let mut priority = 0;
+        if let Some(&Lexeme::Priority(p)) = parser.peek(0) {
+            priority = p;
+            parser.pos += 1;
+        }
+        parser.pos += 1;
+        let expr = parser.alternatives()?;
+        if let Some(next) = parser
+            .peek(0)
+            .filter(|next| !matches!(next, Lexeme::Directive(_)) && !parser.at_definition())
+        {
+            return Err(format!("unexpected {next:?} in {name}").into());
+        }
+        let duplicate = if is_terminal(&name) {
+            defs.terminals
+                .insert(name.clone(), (expr, priority))
+                .is_some()
+        } else {
+            defs.rules.insert(name.clone(), expr).is_some()
+        };
+        if duplicate {
+            return Err(format!("duplicate definition of {name}").into());
+        }
+    }
+    Ok(defs)
+}
+
+struct Token {
+    name: String,
+    pattern: String,
+    priority: i32,
+    string: bool,
+    // case sensitive literal, shown in error messages
+    literal: Option<String>,
+}
+
+struct Lowering<'a> {
+    defs: &'a Definitions,
+    rule_names: HashMap<&'a str, String>,
+    terminal_names: HashMap<&'a str, String>,
+    taken: HashSet<String>,
+    tokens: Vec<Token>,
+    symbols: HashMap<String, String>,
+    output: Vec<(String, Vec<Vec<String>>)>,
+    helpers: HashMap<String, usize>,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(defs: &'a Definitions) -> Self {
+        let unique = |mut name: String, taken: &mut HashSet<String>| {
+            while !taken.insert(name.clone()) {
+                name.push('_');
+            }
+            name
+        };
+        let mut taken = HashSet::new();
+        let mut rule_names = HashMap::new();
+        for name in defs.rules.keys() {
+            let mut sanitized = name.clone();
+            if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                sanitized.insert(0, 'r');
+            }
+            rule_names.insert(name.as_str(), unique(sanitized, &mut taken));
+        }
+        // token names are separate from rule names
+        let mut taken = HashSet::new();
+        let mut terminal_names = HashMap::new();
+        for name in defs.terminals.keys() {
+            let mut sanitized = name.trim_start_matches('_').to_string();
+            if !sanitized.starts_with(|c: char| c.is_ascii_uppercase()) {
+                sanitized.insert(0, 'T');
+            }
+            terminal_names.insert(name.as_str(), unique(sanitized, &mut taken));
+        }
+        Self {
+            defs,
+            rule_names,
+            terminal_names,
+            taken,
+            tokens: vec![],
+            symbols: HashMap::new(),
+            output: vec![],
+            helpers: HashMap::new(),
+        }
+    }
+
+    fn regex(&self, expr: &Expr, visiting: &mut Vec<&'a str>) -> Result<String, Box<dyn Error>> {
+        Ok(match expr {
+            Expr::Literal(literal, insensitive) => {
+                let escaped: String = literal.chars().map(escape_char).collect();
+                if *insensitive {
+                    format!("(?i:{escaped})")
+                } else {
+                    escaped
+                }
+            }
+            Expr::Pattern(pattern, flags) => {
+                // unicode and lark specific flags have no equivalent
+                let flags: String = flags.chars().filter(|c| "imsx".contains(*c)).collect();
+                format!("(?{flags}:{pattern})")
+            }
+            Expr::Range(start, end) => format!("[{}-{}]", escape_char(*start), escape_char(*end)),
+            Expr::Ref(name) => {
+                let (name, (expr, _)) =
+                    self.defs.terminals.get_key_value(name).ok_or_else(|| {
+                        format!("terminal {name} not defined, or refers to a rule")
+                    })?;
+                if visiting.contains(&name.as_str()) {
+                    return Err(format!("terminal {name} is recursive").into());
+                }
+                visiting.push(name);
+                let regex = self.regex(expr, visiting)?;
+                visiting.pop();
+                format!("(?:{regex})")
+            }
+            Expr::Seq(items) => items
+                .iter()
+                .map(|e| Ok(format!("(?:{})", self.regex(e, visiting)?)))
+                .collect::<Result<_, Box<dyn Error>>>()?,
+            Expr::Alt(items) => items
+                .iter()
+                .map(|e| self.regex(e, visiting))
+                .collect::<Result<Vec<_>, _>>()?
+                .join("|"),
+            Expr::Repeat(inner, min, max) => {
+                format!(
+                    "(?:{}){}",
+                    self.regex(inner, visiting)?,
+                    repetition(*min, *max)
+                )
+            }
+        })
+    }
+
+    fn token(
+        &mut self,
+        key: String,
+        name: impl FnOnce(&mut HashSet<String>) -> String,
+        expr: &Expr,
+        priority: i32,
+    ) -> Result<String, Box<dyn Error>> {
+        if let Some(symbol) = self.symbols.get(&key) {
+            return Ok(symbol.clone());
+        }
+        let pattern = self.regex(expr, &mut vec![])?;
+        let name = name(&mut self.taken);
+        let literal = match expr {
+            Expr::Literal(literal, false) => Some(literal.clone()),
+            _ => None,
+        };
+        self.tokens.push(Token {
+            name: name.clone(),
+            pattern,
+            priority,
+            string: matches!(expr, Expr::Literal(..)),
+            literal,
+        });
+        let symbol = format!("'{name}'");
+        self.symbols.insert(key, symbol.clone());
+        Ok(symbol)
+    }
+
+    fn terminal(&mut self, name: &str) -> Result<String, Box<dyn Error>> {
+        let (name, (expr, priority)) = self
+            .defs
+            .terminals
+            .get_key_value(name)
+            .ok_or_else(|| format!("terminal {name} not defined"))?;
+        let sanitized = self.terminal_names[name.as_str()].clone();
+        self.token(format!("terminal {name}"), |_| sanitized, expr, *priority)
+    }
+
+    // anonymous terminal, named after a keyword like lark does
+    fn anonymous(&mut self, expr: &Expr) -> Result<String, Box<dyn Error>> {
+        if let Some((name, _)) = self.defs.terminals.iter().find(|(_, (e, _))| e == expr) {
+            return self.terminal(name);
+        }
+        let base = match expr {
+            Expr::Literal(literal, _)
+                if literal.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && literal
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                literal.to_uppercase()
+            }
+            _ => "ANON".to_string(),
+        };
+        let name = |taken: &mut HashSet<String>| {
+            let mut name = base.clone();
+            let mut i = 0;
+            while name == "ANON" || !taken.insert(name.clone()) {
+                name = format!("{base}_{i}");
+                i += 1;
+            }
+            name
+        };
+        self.token(format!("{expr:?}"), name, expr, 0)
+    }
+
+    fn helper(&mut self, owner: &str) -> String {
+        let count = self.helpers.entry(owner.to_string()).or_default();
+        *count += 1;
+        format!("{owner}__{count}")
+    }
+
+    fn sequence(&mut self, expr: &Expr, owner: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        match expr {
+            Expr::Seq(items) => {
+                let mut symbols = vec![];
+                for item in items {
+                    symbols.extend(self.sequence(item, owner)?);
+                }
+                Ok(symbols)
+            }
+            expr => Ok(vec![self.symbol(expr, owner)?]),
+        }
+    }
+
+    fn alternatives(
+        &mut self,
+        expr: &Expr,
+        owner: &str,
+    ) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+        match expr {
+            Expr::Alt(items) => items.iter().map(|e| self.sequence(e, owner)).collect(),
+            expr => Ok(vec![self.sequence(expr, owner)?]),
+        }
+    }
+
+    fn symbol(&mut self, expr: &Expr, owner: &str) -> Result<String, Box<dyn Error>> {
+        match expr {
+            Expr::Ref(name) if is_terminal(name) => self.terminal(name),
+            Expr::Ref(name) => {
+                self.rule_names.get(name.as_str()).cloned().ok_or_else(|| {
+                    format!("rule {name} referenced in {owner} is not defined").into()
+                })
+            }
+            Expr::Literal(..) | Expr::Pattern(..) | Expr::Range(..) => self.anonymous(expr),
+            Expr::Repeat(inner, min, max) => {
+                let inner = self.symbol(inner, owner)?;
+                let name = self.helper(owner);
+                let alternatives = match max {
+                    Some(max) => (*min..=*max)
+                        .map(|n| vec![inner.clone(); n as usize])
+                        .collect(),
+                    None => {
+                        let star = self.helper(owner);
+                        self.output.push((
+                            star.clone(),
+                            vec![vec![], vec![star.clone(), inner.clone()]],
+                        ));
+                        let mut alternative = vec![inner; *min as usize];
+                        alternative.push(star);
+                        vec![alternative]
+                    }
+                };
+                self.output.push((name.clone(), alternatives));
+                Ok(name)
+            }
+            expr => {
+                let name = self.helper(owner);
+                let alternatives = self.alternatives(expr, owner)?;
+                self.output.push((name.clone(), alternatives));
+                Ok(name)
+            }
+        }
+    }
+
+    fn lower(mut self) -> Result<(String, String), Box<dyn Error>> {
+        if !self.defs.rules.contains_key("start") {
+            return Err("grammar has no start rule".into());
+        }
+        let mut todo = vec!["start"];
+        let mut seen: HashSet<&str> = todo.iter().copied().collect();
+        while let Some(name) = todo.pop() {
+            let expr = &self.defs.rules[name];
+            let owner = self.rule_names[name].clone();
+            let alternatives = self.alternatives(expr, &owner)?;
+            self.output.push((owner, alternatives));
+            let mut stack = vec![expr];
+            while let Some(expr) = stack.pop() {
+                match expr {
+                    Expr::Ref(r) if !is_terminal(r) => {
+                        let (r, _) = self.defs.rules.get_key_value(r).expect("checked above");
+                        if seen.insert(r) {
+                            todo.push(r);
+                        }
+                    }
+                    Expr::Seq(items) | Expr::Alt(items) => stack.extend(items),
+                    Expr::Repeat(inner, ..) => stack.push(inner),
+                    _ => {}
+                }
+            }
+        }
+        let ignore = self
+            .defs
+            .ignore
+            .iter()
+            .map(|e| self.regex(e, &mut vec![]))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let start = self.rule_names["start"].clone();
+        let mut grammar = format!("%start {start}\n");
+        for token in &self.tokens {
+            if let Some(literal) = token
+                .literal
+                .as_ref()
+                .filter(|l| !l.is_empty() && !l.chars().any(|c| c.is_control() || c == '"'))
+            {
+                writeln!(grammar, "%token {} \"'{literal}'\"", token.name)?;
+            }
+        }
+        grammar.push_str("\n%%\n");
+        let start_idx = self.output.iter().position(|(n, _)| *n == start).unwrap();
+        let start_rule = self.output.remove(start_idx);
+        for (name, alternatives) in std::iter::once(&start_rule).chain(&self.output) {
+            let alternatives: Vec<_> = alternatives.iter().map(|a| a.join(" ")).collect();
+            write!(
+                grammar,
+                "\n{name}\n    : {}\n    ;\n",
+                alternatives.join("\n    | ")
+            )?;
+        }
+
+        // the lexer takes the first of equally long matches, so like in lark
+        // higher priorities come first, then literals before patterns
+        self.tokens
+            .sort_by_key(|token| (-token.priority, !token.string));
+        let mut lexer = String::from("%%\n\n");
+        for token in &self.tokens {
+            writeln!(lexer, "{} {}", token.name, lexer_pattern(&token.pattern))?;
+        }
+        for pattern in ignore {
+            writeln!(lexer, "; {}", lexer_pattern(&pattern))?;
+        }
+        Ok((grammar, lexer))
+    }
+}
+
+// converts a lark grammar with rules, terminals, %import of the common
+// terminals and %ignore into an LR(1) grammar and lexer; tree shaping like
+// aliases, ?rule and !rule is dropped, and unlike the contextual lexer of lark
+// the lexer always takes the longest match of all terminals
+pub fn lark_to_lr1(lark: &str) -> Result<(String, String), Box<dyn Error>> {
+    let defs = parse_definitions(lark)?;
+    Lowering::new(&defs).lower()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint};
+
+    fn constraint(lark: &str) -> LR1GrammarConstraint {
+        let (grammar, lexer) = lark_to_lr1(lark).unwrap();
+        LR1GrammarConstraint::new(&grammar, &lexer, (0..=255).map(|b| vec![b]).collect()).unwrap()
+    }
+
+    fn assert_matches(lr1: &LR1GrammarConstraint, valid: &[&str], invalid: &[&str]) {
+        for valid in valid {
+            let state = lr1.get_state(valid.as_bytes()).unwrap();
+            assert!(lr1.is_match_state(&state), "{valid}");
+        }
+        for invalid in invalid {
+            assert!(lr1.get_state(invalid.as_bytes()).is_none(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_lark_json() {
+        // json grammar from the lark documentation
+        let lr1 = constraint(
+            r#"
+?start: value
+
+?value: object
+      | array
+      | string
+      | SIGNED_NUMBER      -> number
+      | "true"             -> true
+      | "false"            -> false
+      | "null"             -> null
+
+array  : "[" [value ("," value)*] "]"
+object : "{" [pair ("," pair)*] "}"
+pair   : string ":" value
+
+string : ESCAPED_STRING
+
+%import common.ESCAPED_STRING
+%import common.SIGNED_NUMBER
+%import common.WS
+%ignore WS
+"#,
+        );
+        assert_matches(
+            &lr1,
+            &[
+                "{}",
+                "{\"a\": [1, -2.5e3, true, null], \"b\": {\"c\": \"\\\"\"}}",
+                " [\n]",
+            ],
+            &["[1 2]", "{\"a\" 1}", "[truee]", "{,}"],
+        );
+    }
+
+    #[test]
+    fn test_lark_features() {
+        let lr1 = constraint(
+            r#"
+start: _stmt+
+_stmt: "if" NAME ":" NAME ";"? | "print"i NAME~1..2 ";" | assign
+assign: NAME "=" (NUMBER | HEX) ";"
+NAME: /[a-z]+/
+NUMBER.2: DIGIT+
+HEX: "0x" ("0".."9" | "a".."f")~1..4
+%import common (DIGIT, WS_INLINE) // only the inline whitespace
+%ignore WS_INLINE
+"#,
+        );
+        assert_matches(
+            &lr1,
+            &[
+                "if x: y;",
+                "iffy = 3;",
+                "PRINT a b; x=0xff;",
+                "print a;",
+                "if x:y",
+            ],
+            &["if = 3;", "print a b c;", "x = 0x12345;", "x = 3;\n"],
+        );
+
+        assert!(lark_to_lr1("begin: \"a\"").is_err());
+        assert!(lark_to_lr1("start: a").is_err());
+        assert!(lark_to_lr1("start: A\nA: B\nB: A").is_err());
+        assert!(lark_to_lr1("start: \"a\"\n%import other.A").is_err());
+        assert!(lark_to_lr1("start: \"a\"\n%declare A").is_err());
+        assert!(lark_to_lr1("start: (\"a\"").is_err());
+    }
+}
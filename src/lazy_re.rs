@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::read_to_string,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use regex_automata::{
+    nfa::thompson::{self, State, WhichCaptures, NFA},
+    util::{look::Look, primitives::StateID, syntax},
+};
+use rustc_hash::FxHashSet;
+
+use crate::{
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint, RegularExpressionConstraint,
+};
+
+// the nfa states a lazy regex constraint can be in after some input,
+// only states that consume bytes, match, or wait for the end of the input
+// are kept, and only if a match is still reachable from them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LazyRegexState(Arc<[StateID]>);
+
+// a regular expression constraint that simulates the nfa of the pattern and
+// determinizes it lazily, step by step, instead of compiling a dense dfa up
+// front; huge patterns, e.g. alternations of many entity names, only need
+// memory for the nfa and a bounded cache of transitions, at the cost of
+// slower steps whenever a transition is not cached; unlike the dense
+// constraint, alternatives do not take priority over each other, so ab|abc
+// also accepts abc
+pub struct LazyRegexConstraint {
+    pattern: String,
+    nfa: NFA,
+    // nfa states from which a match state is reachable
+    live: Vec<bool>,
+    start: LazyRegexState,
+    cache: Mutex<HashMap<(LazyRegexState, u8), Option<LazyRegexState>>>,
+    cache_capacity: usize,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+}
+
+const DEFAULT_CACHE_CAPACITY: usize = 1 << 16;
+
+impl LazyRegexConstraint {
+    pub fn new(content: &str, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        Self::with_cache_capacity(content, continuations, DEFAULT_CACHE_CAPACITY)
+    }
+
+    // the cache holds at most cache_capacity transitions and is cleared
+    // once it is full, a capacity of 0 disables it
+    pub fn with_cache_capacity(
+        content: &str,
+        continuations: Vec<Vec<u8>>,
+        cache_capacity: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pattern = RegularExpressionConstraint::parse(content)?;
+        // same syntax as the dense regex constraint, see PrefixDFA
+        let nfa = NFA::compiler()
+            .syntax(syntax::Config::new().utf8(false))
+            .configure(
+                thompson::Config::new()
+                    .utf8(false)
+                    .which_captures(WhichCaptures::None),
+            )
+            .build(&pattern)?;
+
+        let num_states = nfa.states().len();
+        let mut predecessors = vec![vec![]; num_states];
+        let mut live = vec![false; num_states];
+        for (id, state) in nfa.states().iter().enumerate() {
+            let successors: Vec<StateID> = match state {
+                State::ByteRange { trans } => vec![trans.next],
+                State::Sparse(sparse) => sparse.transitions.iter().map(|t| t.next).collect(),
+                State::Dense(dense) => dense.transitions.to_vec(),
+                State::Look { look, next } => match look {
+                    Look::Start | Look::End => vec![*next],
+                    look => {
+                        return Err(format!(
+                            "unsupported assertion {look:?}, \
+                            only ^ and $ are supported by the lazy regex constraint"
+                        )
+                        .into())
+                    }
+                },
+                State::Union { alternates } => alternates.to_vec(),
+                State::BinaryUnion { alt1, alt2 } => vec![*alt1, *alt2],
+                State::Capture { next, .. } => vec![*next],
+                State::Fail => vec![],
+                State::Match { .. } => {
+                    live[id] = true;
+                    vec![]
+                }
+            };
+            for next in successors {
+                predecessors[next.as_usize()].push(id);
+            }
+        }
+        // a match at the start of the input only, e.g. ^a^b, is not
+        // told apart here, such states are dropped later when stepping
+        let mut stack: Vec<_> = (0..num_states).filter(|&id| live[id]).collect();
+        while let Some(id) = stack.pop() {
+            for &prev in &predecessors[id] {
+                if !live[prev] {
+                    live[prev] = true;
+                    stack.push(prev);
+                }
+            }
+        }
+
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        let mut constraint = Self {
+            pattern,
+            nfa,
+            live,
+            start: LazyRegexState(Arc::new([])),
+            cache: Mutex::new(HashMap::new()),
+            cache_capacity,
+            continuations,
+            permutation,
+            skips,
+        };
+        constraint.start = constraint.closure(&[constraint.nfa.start_anchored()], true, false);
+        Ok(constraint)
+    }
+
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path.as_ref())?;
+        let content = read_to_string(file)?;
+        Self::new(&content, continuations)
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // number of currently cached transitions
+    pub fn cached_transitions(&self) -> usize {
+        self.cache.lock().expect("cache lock poisoned").len()
+    }
+
+    // follows all transitions that do not consume a byte, keeping the
+    // states that do, match states, and $ if not at the end of the input
+    fn closure(&self, seeds: &[StateID], at_start: bool, at_end: bool) -> LazyRegexState {
+        let mut seen = FxHashSet::default();
+        let mut stack = seeds.to_vec();
+        let mut states = vec![];
+        while let Some(id) = stack.pop() {
+            if !self.live[id.as_usize()] || !seen.insert(id) {
+                continue;
+            }
+            match self.nfa.state(id) {
+                State::ByteRange { .. } | State::Sparse(_) | State::Dense(_) => states.push(id),
+                State::Match { .. } => states.push(id),
+                State::Look { look, next } => match look {
+                    Look::Start if at_start => stack.push(*next),
+                    Look::End if at_end => stack.push(*next),
+                    Look::End => states.push(id),
+                    _ => {}
+                },
+                State::Union { alternates } => stack.extend(alternates.iter().rev()),
+                State::BinaryUnion { alt1, alt2 } => stack.extend([*alt2, *alt1]),
+                State::Capture { next, .. } => stack.push(*next),
+                State::Fail => {}
+            }
+        }
+        states.sort_unstable();
+        LazyRegexState(states.into())
+    }
+
+    fn step_uncached(&self, state: &LazyRegexState, byte: u8) -> Option<LazyRegexState> {
+        let seeds: Vec<_> = state
+            .0
+            .iter()
+            .filter_map(|&id| match self.nfa.state(id) {
+                State::ByteRange { trans } => trans.matches_byte(byte).then_some(trans.next),
+                State::Sparse(sparse) => sparse.matches_byte(byte),
+                State::Dense(dense) => dense.matches_byte(byte),
+                _ => None,
+            })
+            .collect();
+        let next = self.closure(&seeds, false, false);
+        (!next.0.is_empty()).then_some(next)
+    }
+
+    fn step(&self, state: &LazyRegexState, byte: u8) -> Option<LazyRegexState> {
+        if self.cache_capacity == 0 {
+            return self.step_uncached(state, byte);
+        }
+        let key = (state.clone(), byte);
+        if let Some(next) = self.cache.lock().expect("cache lock poisoned").get(&key) {
+            return next.clone();
+        }
+        let next = self.step_uncached(state, byte);
+        let mut cache = self.cache.lock().expect("cache lock poisoned");
+        if cache.len() >= self.cache_capacity {
+            cache.clear();
+        }
+        cache.insert(key, next.clone());
+        next
+    }
+
+    fn drive(&self, state: &LazyRegexState, bytes: &[u8]) -> Option<LazyRegexState> {
+        let mut state = state.clone();
+        for &b in bytes {
+            state = self.step(&state, b)?;
+        }
+        Some(state)
+    }
+}
+
+impl MemoryUsage for LazyRegexConstraint {
+    fn memory_usage(&self) -> usize {
+        // the cache is not included, it grows up to its capacity
+        self.pattern.capacity()
+            + self.nfa.memory_usage()
+            + self.live.capacity()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.capacity() + self.skips.capacity()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for LazyRegexConstraint {
+    type State = LazyRegexState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.drive(&self.start, prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        self.start.clone()
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.closure(&state.0, false, true)
+            .0
+            .iter()
+            .any(|&id| matches!(self.nfa.state(id), State::Match { .. }))
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mut valid = vec![];
+        let mut i = 0;
+        while let Some(&idx) = self.permutation.get(i) {
+            if self.drive(state, &self.continuations[idx]).is_some() {
+                valid.push(idx);
+                i += 1;
+            } else {
+                // continuations extending an invalid one are invalid as well
+                i += self.skips[i] + 1;
+            }
+        }
+        valid.sort_unstable();
+        valid
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.drive(state, self.continuations.get(continuation)?)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+}
+
+impl ByteConstraint for LazyRegexConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.drive(state, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lazy_re() {
+        let conts: Vec<_> = [
+            "a", "b", "ab", "abc", "c", "1", "12", "@", ".", "com", "x", " ", "\n",
+        ]
+        .iter()
+        .map(|c| c.as_bytes().to_vec())
+        .collect();
+        let inputs: [&[u8]; 8] = [b"", b"a", b"ab", b"abc", b"a1", b"ab@c", b"ab@c.", b"xx"];
+        for pattern in [
+            r"a(b|c)*",
+            r"[a-z]+@[a-z]+\.(com|org)",
+            r"(ab|c)+1?",
+            r"^ab.*",
+            r"x{2,3}|\d+",
+            r"(?i)AB",
+        ] {
+            let dense = RegularExpressionConstraint::new(pattern, conts.clone()).unwrap();
+            for capacity in [0, 1, 1024] {
+                let lazy =
+                    LazyRegexConstraint::with_cache_capacity(pattern, conts.clone(), capacity)
+                        .unwrap();
+                assert!(lazy.cached_transitions() <= capacity);
+                for input in inputs {
+                    let (Some(d), Some(l)) = (dense.get_state(input), lazy.get_state(input)) else {
+                        assert_eq!(
+                            dense.get_state(input).is_some(),
+                            lazy.get_state(input).is_some()
+                        );
+                        continue;
+                    };
+                    assert_eq!(dense.is_match_state(&d), lazy.is_match_state(&l));
+                    assert_eq!(
+                        dense.get_valid_continuations(&d),
+                        lazy.get_valid_continuations(&l),
+                        "{pattern} after {input:?}"
+                    );
+                }
+            }
+        }
+
+        // many alternatives, e.g. entity names
+        let names: Vec<_> = (0..5000).map(|i| format!("entity{i}")).collect();
+        let lazy = LazyRegexConstraint::new(&names.join("|"), conts).unwrap();
+        assert!(lazy.check(b"entity4711"));
+        assert!(!lazy.check(b"entity"));
+        assert!(lazy.get_state(b"entity50000").is_none());
+
+        let lazy = LazyRegexConstraint::new("ab|abc", vec![]).unwrap();
+        assert!(lazy.check(b"ab"));
+        assert!(lazy.check(b"abc"));
+
+        // $ only matches at the end of the input
+        let lazy = LazyRegexConstraint::new("a$|ab", vec![b"a".to_vec(), b"b".to_vec()]).unwrap();
+        let state = lazy.get_state(b"a").unwrap();
+        assert!(lazy.is_match_state(&state));
+        assert_eq!(lazy.get_valid_continuations(&state), [1]);
+
+        assert!(LazyRegexConstraint::new(r"\bword", vec![]).is_err());
+        assert!(LazyRegexConstraint::new("(", vec![]).is_err());
+    }
+}
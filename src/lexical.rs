@@ -0,0 +1,422 @@
+use std::{error::Error, fs::File, io::read_to_string, mem::size_of, path::Path};
+
+use cfgrammar::TIdx;
+use regex::{escape, Regex};
+use regex_automata::util::primitives::StateID;
+
+use crate::{
+    limits::CompileLimits,
+    lr1::{
+        build_pdfa, initial_prefix_matches, lexer, parse_lexer, prefix_lexer_with, LexerSpec,
+        Matching, PdfaList,
+    },
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::{optimized_prefix_order, pattern_from_parts, PrefixDFA},
+    ByteConstraint, Constraint, TokenAndSpan,
+};
+
+// terminals are mapped to characters from the private use area,
+// so a regex over terminal names becomes a regular regex over characters
+const TERMINAL_CHAR_OFFSET: u32 = 0xE000;
+const MAX_TERMINALS: usize = 0x1900;
+
+fn terminal_char(terminal: usize) -> char {
+    char::from_u32(TERMINAL_CHAR_OFFSET + terminal as u32).expect("valid terminal char")
+}
+
+fn terminal_pattern(pattern: &str, names: &[&str]) -> Result<String, Box<dyn Error>> {
+    // escape sequences are kept as is, e.g. \S is no terminal named S
+    let name_or_escape = Regex::new(r"\\.|[A-Z][A-Z0-9_]*")?;
+    let mut translated = String::new();
+    let mut last = 0;
+    for m in name_or_escape.find_iter(pattern) {
+        translated.push_str(&pattern[last..m.start()]);
+        last = m.end();
+        if m.as_str().starts_with('\\') {
+            translated.push_str(m.as_str());
+            continue;
+        }
+        let terminal = names
+            .iter()
+            .position(|&name| name == m.as_str())
+            .ok_or_else(|| format!("unknown terminal {} in terminal regex", m.as_str()))?;
+        translated.push_str(&escape(&terminal_char(terminal).to_string()));
+    }
+    translated.push_str(&pattern[last..]);
+    // whitespace only separates terminal names
+    Ok(format!("(?x:{translated})"))
+}
+
+// constraint that only enforces the output to be a sequence of lexer
+// terminals, optionally restricted by a regex over terminal names,
+// e.g. NAME (COMMA NAME)*; ignore tokens may appear anywhere
+pub struct LexicalConstraint {
+    names: Vec<String>,
+    pdfas: PdfaList,
+    terminals: PrefixDFA,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Default)]
+pub struct LexicalState {
+    matching: Matching,
+    terminals: StateID,
+}
+
+impl LexicalConstraint {
+    pub fn new(
+        lexer: &str,
+        terminals: Option<&str>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_limits(lexer, terminals, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        lexer: &str,
+        terminals: Option<&str>,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let LexerSpec {
+            fragments,
+            tokens,
+            ignore_tokens,
+            byte_mode,
+            all_matches,
+            ..
+        } = parse_lexer(lexer)?;
+        if tokens.len() > MAX_TERMINALS {
+            return Err(format!("lexer has more than {MAX_TERMINALS} terminals").into());
+        }
+        let token_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
+        let mode = |pattern: String| {
+            if byte_mode {
+                format!("(?-u:{pattern})")
+            } else {
+                pattern
+            }
+        };
+
+        // terminals are identified by their index in the lexer
+        let mut pdfas = vec![];
+        let named = tokens.iter().map(|(name, parts)| (*name, parts, true));
+        let ignored = ignore_tokens
+            .iter()
+            .map(|parts| ("ignore token", parts, false));
+        for (name, parts, is_terminal) in named.chain(ignored) {
+            let pattern = mode(pattern_from_parts(
+                name,
+                parts,
+                &token_name,
+                &fragments,
+                &tokens,
+            )?);
+            let pdfa = build_pdfa(name, &pattern, all_matches, limits)?;
+            if pdfa.is_eoi_match(pdfa.get_start_state()) {
+                return Err(
+                    format!("token pattern {pattern} for {name} matches empty string").into(),
+                );
+            }
+            let tidx = if is_terminal {
+                Some(TIdx(pdfas.len() as u32))
+            } else {
+                None
+            };
+            pdfas.push((pdfa, tidx));
+        }
+
+        let names: Vec<_> = tokens.keys().copied().collect();
+        let pattern = match terminals {
+            Some(pattern) => terminal_pattern(pattern, &names)?,
+            None => format!(
+                "[{}-{}]*",
+                escape(&terminal_char(0).to_string()),
+                escape(&terminal_char(names.len().max(1) - 1).to_string())
+            ),
+        };
+        // the terminal regex is compiled into a dfa over terminals,
+        // which is stepped whenever the lexer completes a terminal
+        let terminals = build_pdfa("terminal regex", &pattern, false, limits)
+            .map_err(|e| format!("invalid terminal regex: {e}"))?;
+
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            names: names.into_iter().map(String::from).collect(),
+            pdfas,
+            terminals,
+            continuations,
+            permutation,
+            skips,
+        })
+    }
+
+    pub fn from_file(
+        lexer_path: impl AsRef<Path>,
+        terminals: Option<&str>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(lexer_path.as_ref())?;
+        let lexer = read_to_string(file)?;
+        Self::new(&lexer, terminals, continuations)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // names of the lexer terminals, in lexer order
+    pub fn terminal_names(&self) -> &[String] {
+        &self.names
+    }
+
+    // lexes a complete output into terminals, ignore tokens have no name;
+    // fails if the terminal sequence does not match the terminal regex
+    pub fn lex(&self, text: impl AsRef<[u8]>) -> Result<Vec<TokenAndSpan<'_>>, Box<dyn Error>> {
+        let (tokens, spans) = lexer(text, &self.pdfas)?;
+        let accepted = tokens
+            .iter()
+            .try_fold(self.terminals.get_start_state(), |state, &tidx| {
+                self.next_terminal_state(state, tidx)
+            })
+            .is_some_and(|state| self.terminals.is_eoi_match(state));
+        if !accepted {
+            return Err("terminal sequence does not match the terminal regex".into());
+        }
+        Ok(tokens
+            .into_iter()
+            .zip(spans)
+            .map(|(tidx, span)| {
+                (
+                    tidx.map(|tidx| self.names[usize::from(tidx)].as_str()),
+                    span,
+                )
+            })
+            .collect())
+    }
+
+    fn next_terminal_state(&self, state: StateID, tidx: Option<TIdx<u32>>) -> Option<StateID> {
+        let Some(tidx) = tidx else {
+            // ignore tokens do not count as terminals
+            return Some(state);
+        };
+        let mut buf = [0; 4];
+        let c = terminal_char(usize::from(tidx)).encode_utf8(&mut buf);
+        self.terminals.drive(state, c.as_bytes())
+    }
+
+    fn next_state(
+        &self,
+        matching: Matching,
+        terminals: StateID,
+        bytes: &[u8],
+    ) -> Option<LexicalState> {
+        let (tokens, _, matching, _) = prefix_lexer_with(bytes, &self.pdfas, matching).ok()?;
+        let terminals = tokens.into_iter().try_fold(terminals, |state, tidx| {
+            self.next_terminal_state(state, tidx)
+        })?;
+        // at least one of the terminals still matching has to be allowed next
+        if !matching.iter().any(|&(pidx, _)| {
+            self.next_terminal_state(terminals, self.pdfas[pidx].1)
+                .is_some()
+        }) {
+            return None;
+        }
+        Some(LexicalState {
+            matching,
+            terminals,
+        })
+    }
+}
+
+impl MemoryUsage for LexicalConstraint {
+    fn memory_usage(&self) -> usize {
+        self.pdfas
+            .iter()
+            .map(|(pdfa, _)| pdfa.memory_usage())
+            .sum::<usize>()
+            + self.terminals.memory_usage()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for LexicalConstraint {
+    type State = LexicalState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.next_state(
+            initial_prefix_matches(&self.pdfas),
+            self.terminals.get_start_state(),
+            prefix,
+        )
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        self.get_state(b"").expect("should not happen")
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        if state.matching == initial_prefix_matches(&self.pdfas) {
+            // nothing lexed since the last terminal
+            return self.terminals.is_eoi_match(state.terminals);
+        }
+        state.matching.iter().any(|&(pidx, pdfa_state)| {
+            let (pdfa, tidx) = &self.pdfas[pidx];
+            pdfa.is_eoi_match(pdfa_state)
+                && self
+                    .next_terminal_state(state.terminals, *tidx)
+                    .is_some_and(|terminals| self.terminals.is_eoi_match(terminals))
+        })
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mut conts = vec![];
+        let mut i = 0;
+        while i < self.permutation.len() {
+            let skip = self.skips[i];
+            let j = self.permutation[i];
+            i += 1;
+            if self
+                .next_state(
+                    state.matching.clone(),
+                    state.terminals,
+                    &self.continuations[j],
+                )
+                .is_none()
+            {
+                // continuations with an invalid prefix are invalid as well
+                i += skip;
+                continue;
+            }
+            conts.push(j);
+        }
+        conts.sort();
+        conts
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+}
+
+impl ByteConstraint for LexicalConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.next_state(state.matching.clone(), state.terminals, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LEXER: &str = r#"
+%%
+NAME [a-z]+
+NUMBER [0-9]+
+COMMA ,
+; \s+
+"#;
+
+    fn constraint(terminals: Option<&str>, continuations: &[&str]) -> LexicalConstraint {
+        LexicalConstraint::new(
+            LEXER,
+            terminals,
+            continuations
+                .iter()
+                .map(|c| c.as_bytes().to_vec())
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lexical() {
+        let conts = ["ab", "12", ",", " ", "!", "c d"];
+        let lexical = constraint(None, &conts);
+        assert_eq!(lexical.terminal_names(), ["NAME", "NUMBER", "COMMA"]);
+        let state = lexical.get_start_state();
+        assert!(lexical.is_match_state(&state));
+        assert_eq!(lexical.get_valid_continuations(&state), vec![0, 1, 2, 3, 5]);
+
+        // any order of terminals is fine, garbage is not
+        let state = lexical.get_state(b",, 12ab ,").unwrap();
+        assert!(lexical.is_match_state(&state));
+        assert!(lexical.get_next_state(&state, 4).is_none());
+        assert!(lexical.get_state(b"ab!").is_none());
+    }
+
+    #[test]
+    fn test_lexical_terminals() {
+        let conts = ["ab", "12", ",", " ", "c d"];
+        let lexical = constraint(Some("NAME (COMMA NAME)*"), &conts);
+        let state = lexical.get_start_state();
+        assert!(!lexical.is_match_state(&state));
+        assert_eq!(lexical.get_valid_continuations(&state), vec![0, 3]);
+
+        let state = lexical.get_next_state(&state, 0).unwrap();
+        assert!(lexical.is_match_state(&state));
+        // ab12 would lex as NAME NUMBER, c d as NAME NAME
+        assert_eq!(lexical.get_valid_continuations(&state), vec![0, 2, 3]);
+
+        let state = lexical.get_state(b"ab ,").unwrap();
+        assert!(!lexical.is_match_state(&state));
+        assert_eq!(lexical.get_valid_continuations(&state), vec![0, 3]);
+        assert!(lexical.get_state(b"ab, cd").is_some());
+        assert!(lexical.get_state(b"ab cd").is_none());
+        assert!(lexical.get_state(b"12").is_none());
+
+        assert!(LexicalConstraint::new(LEXER, Some("NAME SEMICOLON"), vec![]).is_err());
+    }
+
+    #[test]
+    fn test_lexical_key_value() {
+        let lexer = r#"
+%%
+KEY [a-z_]+
+COLON :
+VALUE \x20[^\n]*
+NEWLINE \n
+"#;
+        let conts: Vec<_> = ["name", ":", " foo bar", "\n", "x"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let lexical =
+            LexicalConstraint::new(lexer, Some("(KEY COLON VALUE NEWLINE)+"), conts).unwrap();
+        let state = lexical.get_state(b"name: foo\nkey:").unwrap();
+        assert!(!lexical.is_match_state(&state));
+        assert_eq!(lexical.get_valid_continuations(&state), vec![2]);
+        let state = lexical.get_state(b"name: foo\nkey: bar\n").unwrap();
+        assert!(lexical.is_match_state(&state));
+        assert!(lexical.get_state(b"name: foo\n:").is_none());
+
+        let terminals = lexical.lex("name: foo\nkey: bar\n").unwrap();
+        assert_eq!(
+            terminals,
+            vec![
+                (Some("KEY"), (0, 4)),
+                (Some("COLON"), (4, 5)),
+                (Some("VALUE"), (5, 9)),
+                (Some("NEWLINE"), (9, 10)),
+                (Some("KEY"), (10, 13)),
+                (Some("COLON"), (13, 14)),
+                (Some("VALUE"), (14, 18)),
+                (Some("NEWLINE"), (18, 19)),
+            ]
+        );
+        assert!(lexical.lex("name: foo").is_err());
+        assert!(lexical.lex("name: foo\n!").is_err());
+    }
+}
@@ -1,14 +1,145 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    hash::Hash,
+};
+
+use rand::Rng;
+use utils::{index_ranges, pack_indices_u32, preferred_bytes};
+
+mod abnf;
+pub mod builtin;
+mod cache;
+#[cfg(feature = "candle")]
+mod candle;
+mod choice;
+mod compile;
+mod csv;
+mod datetime;
+mod dispatch;
+mod distinguish;
+mod docs;
+mod dynamic;
+mod earley;
+mod ebnf;
+mod encode;
+mod gbnf;
+mod glr;
+mod grammar_builder;
+mod grammar_test;
+mod guidance;
+mod hashcons;
+mod inline;
+mod intersection;
+mod json_schema;
+mod json_value;
+mod lark;
+mod lazy_re;
+mod lexical;
+mod limits;
+mod literal_set;
 mod lr1;
+mod memory;
+mod multi_vocab;
+mod peg;
+mod proto;
+mod pushdown;
 mod py;
+mod query;
 mod re;
+mod regex_set;
+mod repeated;
+mod scheduler;
+mod semantic;
+mod sequence;
+#[cfg(feature = "server")]
+mod server;
+mod template;
+mod transcript;
+mod union;
+mod unroll;
+mod utf8;
 mod utils;
+mod xml;
 
-pub use re::RegularExpressionConstraint;
+pub use abnf::abnf_to_lr1;
+#[cfg(feature = "candle")]
+pub use candle::apply_mask_tensor;
+pub use choice::{ChoiceConstraint, ChoiceState};
+pub use compile::{BackgroundCompile, CompileError, CompilePhase, CompileProgress};
+pub use csv::{CsvColumn, CsvConstraintBuilder, CsvQuoting};
+pub use datetime::strftime_to_regex;
+pub use dispatch::{DispatchConstraint, DispatchState};
+pub use distinguish::{distinguish, distinguish_regex, Distinction};
+pub use docs::{grammar_docs, DocFormat};
+pub use dynamic::{DynByteConstraint, DynConstraint, DynState};
+pub use earley::{EarleyGrammarConstraint, EarleyState};
+pub use ebnf::ebnf_to_lr1;
+pub use encode::{encode_with_constraint, EncodeError};
+pub use gbnf::gbnf_to_lr1;
+pub use glr::{GLRGrammarConstraint, GLRState, DEFAULT_MAX_STACKS};
+pub use grammar_builder::{GrammarBuilder, GrammarSymbol};
+pub use grammar_test::{
+    run_grammar_tests, GrammarTestFailure, GrammarTestReport, GrammarTests, ParseCase, PrefixCase,
+};
+pub use guidance::{guidance_to_lr1, lr1_to_guidance};
+pub use inline::{inline_rules, InlineProvenance, InlinedGrammar};
+pub use intersection::{IntersectionConstraint, IntersectionState};
+#[cfg(feature = "schemars")]
+pub use json_schema::constraint_for;
+pub use json_schema::{json_schema_to_lr1, JsonSchemaConstraint};
+pub use json_value::JsonSpans;
+pub use lark::lark_to_lr1;
+pub use lazy_re::{LazyRegexConstraint, LazyRegexState};
+pub use lexical::{LexicalConstraint, LexicalState};
+pub use limits::{CompileLimitError, CompileLimits};
+pub use literal_set::{LiteralSetConstraint, LiteralSetState};
+pub use memory::{
+    Evictable, MemoryBudget, MemoryBudgetExceeded, MemoryPolicy, MemoryReservation, MemoryUsage,
+};
+pub use multi_vocab::{MultiVocabConstraint, WithContinuations};
+pub use peg::{PegGrammarConstraint, PegState};
+pub use proto::{proto_to_lr1, ProtoFormat};
+pub use pushdown::{PushdownGrammarConstraint, PushdownState};
+pub use py::{InvalidPolicy, PyConstraintCore};
+pub use query::{ParseQuery, QueryNode};
+pub use re::{RegexFlags, RegularExpressionConstraint};
 pub use regex_automata::util::primitives::StateID as RegularExpressionState;
+pub use regex_set::RegexSetConstraint;
+pub use repeated::{RepeatedConstraint, RepeatedState};
+pub use scheduler::{
+    ConstraintScheduler, Mask, ScheduledRequest, ScheduledResponse, SchedulerError,
+    SchedulerOptions, SessionId, Ticket,
+};
+pub use semantic::{
+    ComputedText, LengthPrefixed, SemanticGrammarConstraint, SemanticHook, SemanticState,
+    TerminalContext, TerminalMachine,
+};
+pub use sequence::{SequenceConstraint, SequenceState};
+#[cfg(feature = "server")]
+pub use server::ConstraintServer;
+pub use template::TemplateConstraint;
+pub use transcript::{Transcript, TranscriptRecorder, TranscriptStep, TranscriptVerification};
+pub use union::{TaggedUnionConstraint, TaggedUnionState, UnionConstraint, UnionState};
+pub use unroll::UnrollOverflow;
+pub use utf8::{Utf8Constraint, Utf8State};
+pub use utils::{normalize, run_length_order, state_fingerprint, Normalization, OffsetMap};
+pub use xml::dtd_to_lr1;
+
+#[doc(hidden)]
+pub mod __private {
+    // used by the py_constraint macro, so downstream crates
+    // do not need to depend on anyhow and numpy themselves
+    pub use anyhow;
+    pub use numpy;
+
+    pub use crate::py::{CheckReport, Classification, TextOrBytes};
+}
 
 pub use lr1::{
-    ExactLR1GrammarConstraint, LR1GrammarConstraint, LR1GrammarParser, LR1NextState, LR1Parse,
-    LR1State, TokenAndSpan,
+    ExactLR1GrammarConstraint, Explanation, LR1GrammarConstraint, LR1GrammarParser, LR1NextState,
+    LR1Parse, LR1State, LexError, LexErrorKind, Rejection, TokenAndSpan, WhitespacePolicy,
+    LEX_ERROR_TOKEN,
 };
 
 pub trait Constraint {
@@ -22,5 +153,369 @@ pub trait Constraint {
 
     fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize>;
 
+    // same as get_valid_continuations, but written into out, which is cleared
+    // first, so its allocation can be reused across decoding steps
+    fn get_valid_continuations_into(&self, state: &Self::State, out: &mut Vec<usize>) {
+        out.clear();
+        out.extend(self.get_valid_continuations(state));
+    }
+
+    // valid continuations as sorted, half-open ranges of consecutive indices,
+    // see run_length_order for a vocabulary order that keeps these few
+    fn get_valid_ranges(&self, state: &Self::State) -> Vec<(u32, u32)> {
+        index_ranges(self.get_valid_continuations(state))
+    }
+
+    // bit packed mask of the valid continuations, continuation i is bit i % 32 of
+    // word i / 32; out needs at least one bit per continuation, rounded up to
+    // whole words, and bits of invalid continuations are cleared
+    fn pack_mask_u32(&self, state: &Self::State, out: &mut [u32]) {
+        pack_indices_u32(
+            self.get_valid_ranges(state)
+                .into_iter()
+                .flat_map(|(start, end)| start as usize..end as usize),
+            out,
+        );
+    }
+
+    // sets the logits of all invalid continuations to neg_inf, where logit i
+    // belongs to continuation i; logits beyond the continuations, e.g. from a
+    // padded vocabulary, are always set, see apply_mask_tensor for candle
+    fn apply_mask(&self, state: &Self::State, logits: &mut [f32], neg_inf: f32) {
+        let mut invalid_from = 0;
+        for (start, end) in self.get_valid_ranges(state) {
+            let start = (start as usize).min(logits.len());
+            logits[invalid_from..start].fill(neg_inf);
+            invalid_from = (end as usize).min(logits.len());
+        }
+        logits[invalid_from..].fill(neg_inf);
+    }
+
     fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State>;
+
+    // whether two byte prefixes reach the same state, so everything depending only
+    // on the state (e.g. future valid continuations) can be shared between them;
+    // false if one of the prefixes is invalid
+    fn states_equal(&self, a: &[u8], b: &[u8]) -> bool
+    where
+        Self::State: PartialEq,
+    {
+        match (self.get_state(a), self.get_state(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    // whether next, reached from state by a single continuation, is known to have
+    // the same valid continuations and match status as state without recomputing them;
+    // a cheap check, the built-in constraints only detect steps that leave the
+    // state unchanged and do not compare the continuations of different states;
+    // false negatives are allowed, so the default never claims so
+    fn has_same_continuations(&self, _state: &Self::State, _next: &Self::State) -> bool {
+        false
+    }
+
+    // valid continuations of next, reusing those of the state it was reached from
+    // if has_same_continuations holds and recomputing them otherwise; there is no
+    // delta between the continuations of different states
+    fn get_next_valid_continuations(
+        &self,
+        state: &Self::State,
+        valid: &[usize],
+        next: &Self::State,
+    ) -> Vec<usize> {
+        if self.has_same_continuations(state, next) {
+            valid.to_vec()
+        } else {
+            self.get_valid_continuations(next)
+        }
+    }
+
+    // cheap superset of the valid continuations, e.g. to pick candidates from
+    // before checking just those with refine_continuations; exact by default
+    fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.get_valid_continuations(state)
+    }
+
+    // the candidates that are valid in the state, in their given order; each is
+    // checked on its own, so for a few candidates, e.g. the top k of a sampler,
+    // this is much cheaper than computing all valid continuations
+    fn refine_continuations(&self, state: &Self::State, candidates: &[usize]) -> Vec<usize> {
+        candidates
+            .iter()
+            .copied()
+            .filter(|&continuation| self.get_next_state(state, continuation).is_some())
+            .collect()
+    }
+
+    fn classify(&self, prefix: &[u8]) -> Classification {
+        self.get_state(prefix)
+            .map_or(Classification::Invalid, |state| self.classify_state(&state))
+    }
+
+    // prefixes without valid continuations are invalid unless they match,
+    // even though they might be completed with other continuations
+    fn classify_state(&self, state: &Self::State) -> Classification {
+        if self.is_match_state(state) {
+            Classification::Valid
+        } else if self.get_valid_continuations(state).is_empty() {
+            Classification::Invalid
+        } else {
+            Classification::ValidPrefix
+        }
+    }
+
+    // largest prefix length of the bytes at which the constraint is in a match
+    // state, e.g. to truncate run-on outputs to the last complete value; the
+    // default is quadratic in the number of bytes, constraints that can step
+    // through bytes one at a time should override it
+    fn last_valid_truncation(&self, bytes: &[u8]) -> Option<usize> {
+        let valid_up_to = self.check_detailed(bytes).valid_up_to;
+        (0..=valid_up_to).rev().find(|&len| {
+            self.get_state(&bytes[..len])
+                .is_some_and(|state| self.is_match_state(&state))
+        })
+    }
+
+    // whether the full text conforms to the constraint, independent of the
+    // continuations, e.g. to re-validate final outputs of constrained decoding
+    fn check(&self, text: &[u8]) -> bool {
+        self.get_state(text)
+            .is_some_and(|state| self.is_match_state(&state))
+    }
+
+    fn check_detailed(&self, text: &[u8]) -> CheckReport {
+        let Some(state) = self.get_state(text) else {
+            // valid prefixes are prefix closed, so the longest one
+            // can be found with a binary search
+            let (mut lower, mut upper) = (0, text.len());
+            while lower + 1 < upper {
+                let mid = lower + (upper - lower) / 2;
+                if self.get_state(&text[..mid]).is_some() {
+                    lower = mid;
+                } else {
+                    upper = mid;
+                }
+            }
+            return CheckReport {
+                is_match: false,
+                is_prefix: false,
+                valid_up_to: lower,
+            };
+        };
+        CheckReport {
+            is_match: self.is_match_state(&state),
+            is_prefix: true,
+            valid_up_to: text.len(),
+        }
+    }
+}
+
+// constraints that can be driven by arbitrary bytes instead of whole continuations,
+// so they can be combined with other constraints within a single continuation
+pub trait ByteConstraint: Constraint {
+    fn continuations(&self) -> &[Vec<u8>];
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State>;
+
+    // shortest bytes of at most max_len that drive the state to a match state,
+    // preferring readable bytes among completions of equal length, e.g. to close
+    // brackets and quotes when generation hits a length limit; the search is
+    // bounded, because the state space of a grammar can be infinite
+    fn minimal_completion(&self, state: &Self::State, max_len: usize) -> Option<Vec<u8>>
+    where
+        Self::State: Clone + Hash + Eq,
+    {
+        let preference = preferred_bytes();
+        // breadth first search, remembering the previous node and byte
+        let mut nodes = vec![(state.clone(), None)];
+        let mut seen = HashSet::from([state.clone()]);
+        let mut depth_end = 1;
+        let mut len = 0;
+        let mut i = 0;
+        while i < nodes.len() {
+            if i == depth_end {
+                len += 1;
+                depth_end = nodes.len();
+            }
+            if self.is_match_state(&nodes[i].0) {
+                let mut bytes = vec![];
+                let mut current = i;
+                while let Some((prev, b)) = nodes[current].1 {
+                    bytes.push(b);
+                    current = prev;
+                }
+                bytes.reverse();
+                return Some(bytes);
+            }
+            if len < max_len {
+                for &b in &preference {
+                    let Some(next) = self.get_next_state_with_bytes(&nodes[i].0, &[b]) else {
+                        continue;
+                    };
+                    if seen.insert(next.clone()) {
+                        nodes.push((next, Some((i, b))));
+                    }
+                }
+            }
+            i += 1;
+        }
+        None
+    }
+
+    // random output of at most max_len bytes accepted by the constraint, e.g. for
+    // synthetic data or test fixtures; every step picks uniformly between stopping
+    // at a match and the distinct next states, and then a byte leading there, so
+    // alternatives are sampled evenly regardless of how many bytes they accept;
+    // only steps after which a match is reachable within the budget are taken
+    fn sample(&self, rng: &mut impl Rng, max_len: usize) -> Option<Vec<u8>>
+    where
+        Self::State: Clone + Hash + Eq,
+    {
+        let mut state = self.get_start_state();
+        let mut output = vec![];
+        let mut lengths = CompletionLengths::new(self);
+        lengths.get(&state, max_len)?;
+        loop {
+            let budget = max_len - output.len();
+            let mut groups: Vec<(Self::State, Vec<u8>)> = vec![];
+            if budget > 0 {
+                for b in 0..=255 {
+                    let Some(next) = self.get_next_state_with_bytes(&state, &[b]) else {
+                        continue;
+                    };
+                    if let Some((_, bytes)) = groups.iter_mut().find(|(s, _)| s == &next) {
+                        bytes.push(b);
+                    } else if lengths.get(&next, budget - 1).is_some() {
+                        groups.push((next, vec![b]));
+                    }
+                }
+            }
+            let is_match = self.is_match_state(&state);
+            let choice = rng.random_range(0..groups.len() + is_match as usize);
+            if choice == groups.len() {
+                return Some(output);
+            }
+            let (next, bytes) = groups.swap_remove(choice);
+            output.push(bytes[rng.random_range(0..bytes.len())]);
+            state = next;
+        }
+    }
+
+    // up to max_count distinct outputs of at most max_len bytes that complete the
+    // state to a match, shortest first and readable bytes first among those of
+    // equal length, e.g. for autocompletion or to see what a grammar allows next;
+    // only prefixes that can still be completed within max_len are extended, so
+    // the work grows with the number of completions, not with the search space
+    fn enumerate_completions(
+        &self,
+        state: &Self::State,
+        max_count: usize,
+        max_len: usize,
+    ) -> Vec<Vec<u8>>
+    where
+        Self::State: Clone + Hash + Eq,
+    {
+        let preference = preferred_bytes();
+        let mut lengths = CompletionLengths::new(self);
+        let mut completions = vec![];
+        let Some(len) = lengths.get(state, max_len) else {
+            return completions;
+        };
+        // best first by the length of the shortest completion through a node,
+        // which is exact, so completions come out in order of their length
+        let mut nodes = vec![(state.clone(), vec![])];
+        let mut heap = BinaryHeap::from([Reverse((len, 0))]);
+        while let Some(Reverse((_, node))) = heap.pop() {
+            if completions.len() >= max_count {
+                break;
+            }
+            let (state, bytes) = nodes[node].clone();
+            if self.is_match_state(&state) {
+                completions.push(bytes.clone());
+            }
+            let budget = max_len - bytes.len();
+            if budget == 0 {
+                continue;
+            }
+            for &b in &preference {
+                let Some(next) = self.get_next_state_with_bytes(&state, &[b]) else {
+                    continue;
+                };
+                let Some(len) = lengths.get(&next, budget - 1) else {
+                    continue;
+                };
+                let mut next_bytes = bytes.clone();
+                next_bytes.push(b);
+                heap.push(Reverse((next_bytes.len() + len, nodes.len())));
+                nodes.push((next, next_bytes));
+            }
+        }
+        completions
+    }
+
+    // dense mask with one entry per continuation, true for the valid ones,
+    // e.g. for logits processors that want a mask of vocabulary size
+    fn get_valid_continuations_mask(&self, state: &Self::State) -> Vec<bool> {
+        let mut mask = vec![false; self.continuations().len()];
+        for i in self.get_valid_continuations(state) {
+            mask[i] = true;
+        }
+        mask
+    }
+}
+
+// per state the length of its shortest completion, or the largest budget
+// within which it has none, see sample and enumerate_completions
+struct CompletionLengths<'a, C: ByteConstraint + ?Sized> {
+    constraint: &'a C,
+    known: HashMap<C::State, Result<usize, usize>>,
+}
+
+impl<'a, C> CompletionLengths<'a, C>
+where
+    C: ByteConstraint + ?Sized,
+    C::State: Clone + Hash + Eq,
+{
+    fn new(constraint: &'a C) -> Self {
+        Self {
+            constraint,
+            known: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, state: &C::State, budget: usize) -> Option<usize> {
+        match self.known.get(state) {
+            Some(Ok(len)) => return (*len <= budget).then_some(*len),
+            Some(Err(tried)) if budget <= *tried => return None,
+            _ => {}
+        }
+        let completion = self.constraint.minimal_completion(state, budget);
+        let result = completion.as_ref().map(Vec::len).ok_or(budget);
+        self.known.insert(state.clone(), result);
+        result.ok()
+    }
+}
+
+// validity of a prefix with respect to a constraint and its continuations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Classification {
+    // complete match, possibly with further valid continuations
+    Valid,
+    // no match yet, but can be extended with the continuations
+    ValidPrefix,
+    // can never become a match with the continuations
+    Invalid,
+}
+
+// result of checking a full text against a constraint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckReport {
+    // whether the text conforms to the constraint
+    pub is_match: bool,
+    // whether the text can still be completed to conform to the constraint
+    pub is_prefix: bool,
+    // length in bytes of the longest prefix of the text that can be completed,
+    // equal to the length of the text if it is a valid prefix itself
+    pub valid_up_to: usize,
 }
@@ -0,0 +1,382 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use regex_syntax::{
+    hir::{Class, Hir, HirKind, Literal},
+    ParserBuilder,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileLimits {
+    pub max_rules: Option<usize>,
+    // checked against the LR(0) states before the table is built,
+    // and against the states of the table afterwards
+    pub max_states: Option<usize>,
+    pub max_lexer_dfa_states: Option<usize>,
+    // largest count of a repetition following an unbounded repetition over
+    // overlapping bytes, like n in [ab]*a[ab]{n}, whose lexer dfas grow
+    // exponentially in n; also rejects nested unbounded repetitions over
+    // overlapping bytes, like (a+b?)+
+    pub max_lexer_repetition: Option<u32>,
+    // checked between compilation phases and while counting the LR states;
+    // the LR table itself is built on a worker thread that is abandoned at
+    // the deadline, with at most one such worker per core running at a time;
+    // other phases (e.g. a single lexer dfa) are not interrupted
+    pub max_compile_time: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileLimitError {
+    TooManyRules {
+        rules: usize,
+        limit: usize,
+    },
+    TooManyStates {
+        states: usize,
+        limit: usize,
+    },
+    LexerDFATooLarge {
+        token: String,
+        states: Option<usize>,
+        limit: usize,
+    },
+    RiskyRepetition {
+        token: String,
+        repetition: String,
+        unbounded: String,
+        count: u32,
+        limit: u32,
+    },
+    NestedRepetition {
+        token: String,
+        inner: String,
+        outer: String,
+    },
+    Timeout {
+        phase: &'static str,
+        elapsed: Duration,
+        limit: Duration,
+    },
+}
+
+impl Display for CompileLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileLimitError::TooManyRules { rules, limit } => {
+                write!(
+                    f,
+                    "grammar has {rules} rules, but at most {limit} are allowed"
+                )
+            }
+            CompileLimitError::TooManyStates { states, limit } => {
+                write!(
+                    f,
+                    "LR table has at least {states} states, but at most {limit} are allowed"
+                )
+            }
+            CompileLimitError::LexerDFATooLarge {
+                token,
+                states: Some(states),
+                limit,
+            } => write!(
+                f,
+                "lexer DFA for {token} has {states} states, but at most {limit} are allowed"
+            ),
+            CompileLimitError::LexerDFATooLarge {
+                token,
+                states: None,
+                limit,
+            } => write!(
+                f,
+                "lexer DFA for {token} exceeds the limit of {limit} states during construction"
+            ),
+            CompileLimitError::RiskyRepetition {
+                token,
+                repetition,
+                unbounded,
+                count,
+                limit,
+            } => write!(
+                f,
+                "lexer rule for {token} repeats {repetition} {count} times after {unbounded}, \
+                but at most {limit} are allowed there because the lexer DFA can grow \
+                exponentially with the count; lower the count, bound {unbounded} or make \
+                their bytes disjoint"
+            ),
+            CompileLimitError::NestedRepetition {
+                token,
+                inner,
+                outer,
+            } => write!(
+                f,
+                "lexer rule for {token} nests the unbounded repetition {inner} in {outer} \
+                over overlapping bytes; flatten it (e.g. (a+)+ to a+) or make the bytes \
+                of {inner} disjoint from what follows it"
+            ),
+            CompileLimitError::Timeout {
+                phase,
+                elapsed,
+                limit,
+            } => write!(
+                f,
+                "compilation took {:.2}s after {phase}, but at most {:.2}s are allowed",
+                elapsed.as_secs_f64(),
+                limit.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl Error for CompileLimitError {}
+
+impl CompileLimits {
+    pub(crate) fn check_rules(&self, rules: usize) -> Result<(), CompileLimitError> {
+        match self.max_rules {
+            Some(limit) if rules > limit => Err(CompileLimitError::TooManyRules { rules, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_states(&self, states: usize) -> Result<(), CompileLimitError> {
+        match self.max_states {
+            Some(limit) if states > limit => {
+                Err(CompileLimitError::TooManyStates { states, limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // checks the pattern of a lexer rule for repetitions that blow up its dfa,
+    // before the dfa is built; patterns that fail to parse are left to the dfa
+    // construction to report
+    pub(crate) fn check_repetitions(
+        &self,
+        token: &str,
+        pattern: &str,
+    ) -> Result<(), CompileLimitError> {
+        let Some(limit) = self.max_lexer_repetition else {
+            return Ok(());
+        };
+        let Ok(hir) = ParserBuilder::new().utf8(false).build().parse(pattern) else {
+            return Ok(());
+        };
+        RepetitionChecker { token, limit }.check(&hir, &[], Bytes::default(), None)
+    }
+
+    pub(crate) fn check_time(
+        &self,
+        start: Instant,
+        phase: &'static str,
+    ) -> Result<(), CompileLimitError> {
+        let elapsed = start.elapsed();
+        match self.max_compile_time {
+            Some(limit) if elapsed > limit => Err(CompileLimitError::Timeout {
+                phase,
+                elapsed,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// set of bytes, non-ascii characters of unicode classes are approximated
+// by all non-ascii bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Bytes([u64; 4]);
+
+impl Bytes {
+    fn insert_range(&mut self, start: u8, end: u8) {
+        for b in start..=end {
+            self.0[usize::from(b / 64)] |= 1 << (b % 64);
+        }
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+
+    fn overlaps(&self, other: &Self) -> bool {
+        self.0.iter().zip(&other.0).any(|(a, b)| a & b != 0)
+    }
+
+    fn of_class(class: &Class) -> Self {
+        let mut bytes = Self::default();
+        match class {
+            Class::Bytes(class) => {
+                for range in class.iter() {
+                    bytes.insert_range(range.start(), range.end());
+                }
+            }
+            Class::Unicode(class) => {
+                for range in class.iter() {
+                    let (start, end) = (u32::from(range.start()), u32::from(range.end()));
+                    if start < 0x80 {
+                        bytes.insert_range(start as u8, end.min(0x7F) as u8);
+                    }
+                    if end >= 0x80 {
+                        bytes.insert_range(0x80, 0xFF);
+                    }
+                }
+            }
+        }
+        bytes
+    }
+
+    // all bytes the hir can consume
+    fn of(hir: &Hir) -> Self {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => Self::default(),
+            HirKind::Literal(Literal(lit)) => {
+                let mut bytes = Self::default();
+                for &b in lit.iter() {
+                    bytes.insert_range(b, b);
+                }
+                bytes
+            }
+            HirKind::Class(class) => Self::of_class(class),
+            HirKind::Repetition(rep) => Self::of(&rep.sub),
+            HirKind::Capture(cap) => Self::of(&cap.sub),
+            HirKind::Concat(hirs) | HirKind::Alternation(hirs) => hirs
+                .iter()
+                .fold(Self::default(), |bytes, hir| bytes.union(Self::of(hir))),
+        }
+    }
+
+    // bytes the hir can start with
+    fn first(hir: &Hir) -> Self {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => Self::default(),
+            HirKind::Literal(Literal(lit)) => {
+                let mut bytes = Self::default();
+                if let Some(&b) = lit.first() {
+                    bytes.insert_range(b, b);
+                }
+                bytes
+            }
+            HirKind::Class(class) => Self::of_class(class),
+            HirKind::Repetition(rep) if rep.max == Some(0) => Self::default(),
+            HirKind::Repetition(rep) => Self::first(&rep.sub),
+            HirKind::Capture(cap) => Self::first(&cap.sub),
+            HirKind::Alternation(hirs) => hirs
+                .iter()
+                .fold(Self::default(), |bytes, hir| bytes.union(Self::first(hir))),
+            HirKind::Concat(hirs) => Self::first_of_concat(hirs),
+        }
+    }
+
+    fn first_of_concat(hirs: &[Hir]) -> Self {
+        let mut bytes = Self::default();
+        for hir in hirs {
+            bytes = bytes.union(Self::first(hir));
+            if !is_nullable(hir) {
+                break;
+            }
+        }
+        bytes
+    }
+}
+
+fn is_nullable(hir: &Hir) -> bool {
+    hir.properties().minimum_len() == Some(0)
+}
+
+fn is_unbounded(hir: &Hir) -> bool {
+    matches!(hir.kind(), HirKind::Repetition(rep) if rep.max.is_none())
+}
+
+// unbounded repetitions within the hir
+fn unbounded_repetitions<'h>(hir: &'h Hir, reps: &mut Vec<(&'h Hir, Bytes)>) {
+    match hir.kind() {
+        HirKind::Repetition(rep) => {
+            if rep.max.is_none() {
+                reps.push((hir, Bytes::of(&rep.sub)));
+            }
+            unbounded_repetitions(&rep.sub, reps);
+        }
+        HirKind::Capture(cap) => unbounded_repetitions(&cap.sub, reps),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => {
+            hirs.iter().for_each(|hir| unbounded_repetitions(hir, reps))
+        }
+        _ => {}
+    }
+}
+
+struct RepetitionChecker<'a> {
+    token: &'a str,
+    limit: u32,
+}
+
+impl RepetitionChecker<'_> {
+    // preceding are the unbounded repetitions before the hir that can still
+    // be active, follow the bytes that can follow the hir within the innermost
+    // enclosing unbounded repetition outer, including its next iteration
+    fn check(
+        &self,
+        hir: &Hir,
+        preceding: &[(&Hir, Bytes)],
+        follow: Bytes,
+        outer: Option<&Hir>,
+    ) -> Result<(), CompileLimitError> {
+        match hir.kind() {
+            HirKind::Repetition(rep) => {
+                let count = rep.max.unwrap_or(rep.min);
+                let bytes = Bytes::of(&rep.sub);
+                if count > self.limit {
+                    if let Some((unbounded, _)) = preceding
+                        .iter()
+                        .find(|(_, unbounded)| unbounded.overlaps(&bytes))
+                    {
+                        return Err(CompileLimitError::RiskyRepetition {
+                            token: self.token.to_string(),
+                            repetition: rep.sub.to_string(),
+                            unbounded: unbounded.to_string(),
+                            count,
+                            limit: self.limit,
+                        });
+                    }
+                }
+                if rep.max.is_some() {
+                    return self.check(&rep.sub, preceding, follow, outer);
+                }
+                let first = Bytes::first(&rep.sub);
+                if let Some(outer) = outer {
+                    if first.overlaps(&follow) {
+                        return Err(CompileLimitError::NestedRepetition {
+                            token: self.token.to_string(),
+                            inner: hir.to_string(),
+                            outer: outer.to_string(),
+                        });
+                    }
+                }
+                self.check(&rep.sub, preceding, first.union(follow), Some(hir))
+            }
+            HirKind::Capture(cap) => self.check(&cap.sub, preceding, follow, outer),
+            HirKind::Alternation(hirs) => hirs
+                .iter()
+                .try_for_each(|hir| self.check(hir, preceding, follow, outer)),
+            HirKind::Concat(hirs) => {
+                let mut preceding = preceding.to_vec();
+                for (i, hir) in hirs.iter().enumerate() {
+                    let rest = &hirs[i + 1..];
+                    let mut hir_follow = Bytes::first_of_concat(rest);
+                    if rest.iter().all(is_nullable) {
+                        hir_follow = hir_follow.union(follow);
+                    }
+                    self.check(hir, &preceding, hir_follow, outer)?;
+                    // a required part over other bytes ends preceding repetitions
+                    if !is_nullable(hir) && !is_unbounded(hir) {
+                        let bytes = Bytes::of(hir);
+                        preceding.retain(|(_, unbounded)| unbounded.overlaps(&bytes));
+                    }
+                    unbounded_repetitions(hir, &mut preceding);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
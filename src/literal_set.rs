@@ -0,0 +1,314 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::read_to_string,
+    mem::size_of,
+    path::Path,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+    choice::Trie,
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+// trie node, always stands for the same byte prefix, even after all
+// literals with it were removed, so states stay meaningful across updates
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct LiteralSetState(u32);
+
+impl MemoryUsage for LiteralSetState {
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    // sorted by byte
+    children: Vec<(u8, u32)>,
+    // number of literals starting with the prefix of the node
+    literals: usize,
+    is_literal: bool,
+}
+
+// trie that grows with inserts, unlike the one of the choice constraint
+struct MutableTrie {
+    nodes: Vec<Node>,
+}
+
+impl Trie for MutableTrie {
+    // only follows edges to nodes that still lead to a literal
+    fn step(&self, node: u32, byte: u8) -> Option<u32> {
+        let children = &self.nodes[node as usize].children;
+        let i = children.binary_search_by_key(&byte, |&(b, _)| b).ok()?;
+        let child = children[i].1;
+        (self.nodes[child as usize].literals > 0).then_some(child)
+    }
+}
+
+impl MutableTrie {
+    fn contains(&self, literal: &[u8]) -> bool {
+        self.walk(0, literal)
+            .is_some_and(|node| self.nodes[node as usize].is_literal)
+    }
+
+    // number of bytes of the literal that already have a node,
+    // including nodes of removed literals
+    fn existing_prefix_len(&self, literal: &[u8]) -> usize {
+        let mut node = 0;
+        for (len, &b) in literal.iter().enumerate() {
+            let children = &self.nodes[node].children;
+            match children.binary_search_by_key(&b, |&(b, _)| b) {
+                Ok(i) => node = children[i].1 as usize,
+                Err(_) => return len,
+            }
+        }
+        literal.len()
+    }
+
+    fn insert(&mut self, literal: &[u8]) -> Result<bool, Box<dyn Error>> {
+        if self.contains(literal) {
+            return Ok(false);
+        }
+        // checked up front, so a failed insert leaves the trie unchanged
+        let new_nodes = literal.len() - self.existing_prefix_len(literal);
+        if self.nodes.len() + new_nodes > u32::MAX as usize {
+            return Err("too many trie nodes for a literal set".into());
+        }
+        let mut node = 0;
+        self.nodes[0].literals += 1;
+        for &b in literal {
+            let num_nodes = self.nodes.len();
+            let children = &mut self.nodes[node].children;
+            node = match children.binary_search_by_key(&b, |&(b, _)| b) {
+                Ok(i) => children[i].1 as usize,
+                Err(i) => {
+                    // nodes are never freed, nodes of removed literals are
+                    // reused when a literal with their prefix is inserted again
+                    children.insert(i, (b, num_nodes as u32));
+                    self.nodes.push(Node::default());
+                    num_nodes
+                }
+            };
+            self.nodes[node].literals += 1;
+        }
+        self.nodes[node].is_literal = true;
+        Ok(true)
+    }
+
+    fn remove(&mut self, literal: &[u8]) -> bool {
+        if !self.contains(literal) {
+            return false;
+        }
+        let mut node = 0;
+        self.nodes[0].literals -= 1;
+        for &b in literal {
+            let children = &self.nodes[node].children;
+            let i = children
+                .binary_search_by_key(&b, |&(b, _)| b)
+                .expect("literal should be in the trie");
+            node = children[i].1 as usize;
+            self.nodes[node].literals -= 1;
+        }
+        self.nodes[node].is_literal = false;
+        true
+    }
+}
+
+// constraint that only allows one of a set of literals, like the choice
+// constraint, but the set can be changed with insert and remove at any
+// time without rebuilding anything, e.g. for product catalogs with millions
+// of entries; updates are visible to all states immediately, a state whose
+// literals were all removed has no valid continuations anymore
+pub struct LiteralSetConstraint {
+    trie: RwLock<MutableTrie>,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+}
+
+impl LiteralSetConstraint {
+    // the set may be empty and filled later
+    pub fn new<L: AsRef<[u8]>>(
+        literals: impl IntoIterator<Item = L>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut trie = MutableTrie {
+            nodes: vec![Node::default()],
+        };
+        for literal in literals {
+            trie.insert(literal.as_ref())?;
+        }
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            trie: RwLock::new(trie),
+            continuations,
+            permutation,
+            skips,
+        })
+    }
+
+    // one literal per line, like for the choice constraint
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path.as_ref())?;
+        let content = read_to_string(file)?;
+        Self::new(content.lines(), continuations)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, MutableTrie> {
+        self.trie.read().expect("literal set lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, MutableTrie> {
+        self.trie.write().expect("literal set lock poisoned")
+    }
+
+    // whether the literal was not in the set before, fails without
+    // changing the set if the trie would outgrow its u32 node ids
+    pub fn insert(&self, literal: impl AsRef<[u8]>) -> Result<bool, Box<dyn Error>> {
+        self.write().insert(literal.as_ref())
+    }
+
+    // whether the literal was in the set before
+    pub fn remove(&self, literal: impl AsRef<[u8]>) -> bool {
+        self.write().remove(literal.as_ref())
+    }
+
+    pub fn contains(&self, literal: impl AsRef<[u8]>) -> bool {
+        self.read().contains(literal.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().nodes[0].literals
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl MemoryUsage for LiteralSetConstraint {
+    fn memory_usage(&self) -> usize {
+        let trie = self.read();
+        trie.nodes.capacity() * size_of::<Node>()
+            + trie
+                .nodes
+                .iter()
+                .map(|node| node.children.capacity() * size_of::<(u8, u32)>())
+                .sum::<usize>()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for LiteralSetConstraint {
+    type State = LiteralSetState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.read().walk(0, prefix).map(LiteralSetState)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        LiteralSetState(0)
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.read().nodes[state.0 as usize].is_literal
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.read().valid_continuations(
+            state.0,
+            &self.continuations,
+            &self.permutation,
+            &self.skips,
+        )
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+}
+
+impl ByteConstraint for LiteralSetConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.read().walk(state.0, bytes).map(LiteralSetState)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ChoiceConstraint;
+
+    #[test]
+    fn test_literal_set() {
+        let conts: Vec<Vec<u8>> = ["a", "b", "ab", "abc", "ber", "Berlin", "lin", "M", "n"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let options = ["Berlin", "Bern", "München", "a", "abc"];
+        let set = LiteralSetConstraint::new(options, conts.clone()).unwrap();
+        let choice = ChoiceConstraint::new(
+            options.iter().map(|o| o.to_string()).collect(),
+            conts.clone(),
+        )
+        .unwrap();
+        assert_eq!(set.len(), 5);
+        for prefix in [&b""[..], b"a", b"Ber", b"M", b"ab"] {
+            let s = set.get_state(prefix).unwrap();
+            let c = choice.get_state(prefix).unwrap();
+            assert_eq!(
+                set.get_valid_continuations(&s),
+                choice.get_valid_continuations(&c)
+            );
+            assert_eq!(set.is_match_state(&s), choice.is_match_state(&c));
+        }
+
+        // updates are visible to existing states
+        let state = set.get_state(b"Ber").unwrap();
+        assert!(set.remove("Berlin"));
+        assert!(!set.remove("Berlin"));
+        assert_eq!(set.get_valid_continuations(&state), [8]);
+        assert!(set.remove("Bern"));
+        assert!(set.get_valid_continuations(&state).is_empty());
+        assert!(set.get_state(b"B").is_none());
+        assert!(set.insert("Berlin").unwrap());
+        assert!(!set.insert("Berlin").unwrap());
+        assert_eq!(set.get_valid_continuations(&state), [6]);
+        assert_eq!(set.len(), 4);
+
+        // removing a literal keeps the ones it is a prefix of
+        assert!(set.remove("a"));
+        assert!(!set.check(b"a"));
+        assert!(set.check(b"abc"));
+        assert!(set.contains("abc"));
+        assert!(!set.contains("ab"));
+
+        // empty sets allow nothing until filled
+        let set = LiteralSetConstraint::new(Vec::<String>::new(), conts).unwrap();
+        assert!(set.is_empty());
+        assert!(set
+            .get_valid_continuations(&set.get_start_state())
+            .is_empty());
+        assert!(set.insert("").unwrap());
+        assert!(set.is_match_state(&set.get_start_state()));
+        assert!(set.insert("Mn").unwrap());
+        assert!(set.check(b"Mn"));
+    }
+}
@@ -1,23 +1,49 @@
-use std::{collections::HashMap, error::Error, fs::File, io::read_to_string, path::Path};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::read_to_string,
+    mem::size_of,
+    panic,
+    path::Path,
+    str::FromStr,
+    sync::{
+        mpsc::{channel, RecvTimeoutError},
+        Arc, Condvar, LazyLock, Mutex,
+    },
+    thread,
+    time::Instant,
+};
 
 use cfgrammar::{
     yacc::{YaccGrammar, YaccGrammarError, YaccKind, YaccOriginalActionKind},
-    Spanned, TIdx,
+    PIdx, Spanned, Symbol, TIdx,
 };
 use indexmap::IndexMap;
 use itertools::{Either, Itertools};
 use lrtable::{Action, Minimiser, StIdx, StateTable};
 use regex::{escape, Regex};
 use regex_automata::util::primitives::StateID;
+use rustc_hash::FxHasher;
 
 use crate::{
-    utils::{extract_parts, optimized_prefix_order, pattern_from_parts, PrefixDFA, PrefixMatch},
-    Constraint,
+    compile::{CompilePhase, CompileProgress},
+    hashcons::{HashConsed, Intern, Interner},
+    limits::{CompileLimitError, CompileLimits},
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::{
+        extract_parts, normalize, optimized_prefix_order, pattern_from_parts, Normalization,
+        OffsetMap, Part, PrefixDFA, PrefixMatch,
+    },
+    ByteConstraint, Constraint, InlineProvenance,
 };
 
-type PdfaList = Vec<(PrefixDFA, Option<TIdx<u32>>)>;
+pub(crate) type PdfaList = Vec<(PrefixDFA, Option<TIdx<u32>>)>;
 
-fn format_yacc_error(grammar: &str, e: &YaccGrammarError) -> String {
+pub(crate) fn format_yacc_error(grammar: &str, e: &YaccGrammarError) -> String {
     format!(
         "{} at {}",
         e,
@@ -35,29 +61,537 @@ fn format_yacc_error(grammar: &str, e: &YaccGrammarError) -> String {
     )
 }
 
-fn load_grammar_and_pdfas(
+// %token NAME "alias" declarations give terminals friendly names for error
+// messages, e.g. "expected ')' or ','"; cfgrammar would treat the alias as another
+// token, so it is blanked out, which keeps spans in grammar errors valid
+pub(crate) fn extract_token_aliases(
     grammar: &str,
-    grammar_kind: YaccKind,
-    lexer: &str,
-) -> Result<(YaccGrammar, PdfaList), Box<dyn Error>> {
-    let grammar = YaccGrammar::new(grammar_kind, grammar).map_err(|e| {
-        format!(
-            "errors creating grammar:\n{}",
-            e.iter().map(|e| format_yacc_error(grammar, e)).join("\n")
-        )
+) -> Result<(String, HashMap<String, String>), Box<dyn Error>> {
+    let declarations = Regex::new("(?m)^%%")?
+        .find(grammar)
+        .map_or(grammar, |m| &grammar[..m.start()]);
+    let directive = Regex::new(r"(?m)^%token\b.*$")?;
+    let part = Regex::new(r#"'[^']*'|"[^"]*"|[^\s'"]+"#)?;
+    let mut source = grammar.as_bytes().to_vec();
+    let mut aliases = HashMap::new();
+    for line in directive.find_iter(declarations) {
+        let mut name = None;
+        for m in part.find_iter(line.as_str()).skip(1) {
+            let text = m.as_str();
+            match name.take() {
+                Some(name) if text.starts_with('"') => {
+                    let alias = &text[1..text.len() - 1];
+                    if aliases.insert(name, alias.to_string()).is_some() {
+                        return Err(format!("duplicate alias for token {name}").into());
+                    }
+                    source[line.start() + m.start()..line.start() + m.end()].fill(b' ');
+                }
+                _ if text.starts_with('\'') || text.starts_with('"') => {}
+                _ => name = Some(text),
+            }
+        }
+    }
+    let aliases = aliases
+        .into_iter()
+        .map(|(name, alias)| (name.to_string(), alias))
+        .collect();
+    Ok((String::from_utf8(source)?, aliases))
+}
+
+fn format_expected(expected: &[&str]) -> String {
+    match expected {
+        [] => "nothing".to_string(),
+        [single] => single.to_string(),
+        [init @ .., last] => format!("{} or {last}", init.join(", ")),
+    }
+}
+
+// why a continuation is invalid in a state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    // no lexer rule matches the continuation from this byte offset on
+    NoLexerMatch {
+        offset: usize,
+    },
+    // exact constraints complete at most one token per continuation
+    TooManyTokens {
+        tokens: usize,
+    },
+    // the parser does not accept a completed terminal
+    UnexpectedTerminal {
+        terminal: String,
+        expected: Vec<String>,
+    },
+    // the pending lexeme can only become terminals the parser does not accept
+    UnexpectedLexeme {
+        candidates: Vec<String>,
+        expected: Vec<String>,
+    },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let expected = |expected: &[String]| {
+            format_expected(&expected.iter().map(String::as_str).collect_vec())
+        };
+        match self {
+            Rejection::NoLexerMatch { offset } => {
+                write!(f, "no lexer rule matches from byte {offset} on")
+            }
+            Rejection::TooManyTokens { tokens } => write!(
+                f,
+                "completes {tokens} tokens, but exact constraints allow at most one"
+            ),
+            Rejection::UnexpectedTerminal {
+                terminal,
+                expected: e,
+            } => {
+                write!(f, "unexpected {terminal}, expected {}", expected(e))
+            }
+            Rejection::UnexpectedLexeme {
+                candidates,
+                expected: e,
+            } => write!(
+                f,
+                "pending lexeme can only become {}, expected {}",
+                expected(candidates),
+                expected(e)
+            ),
+        }
+    }
+}
+
+// how the lexer and parser process a continuation in a state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub continuation: usize,
+    // whether the continuation continues a lexeme pending in the state
+    pub continues_lexeme: bool,
+    // tokens the continuation completes with their byte spans in it, by
+    // terminal display name, none for skipped input like whitespace; a
+    // lexeme pending in the state that ends right away has an empty span
+    pub completed: Vec<(Option<String>, (usize, usize))>,
+    // terminals the lexeme pending after the continuation can still become
+    pub pending: Vec<String>,
+    // none if the continuation is valid
+    pub rejection: Option<Rejection>,
+}
+
+impl Explanation {
+    pub fn is_valid(&self) -> bool {
+        self.rejection.is_none()
+    }
+}
+
+// replays what get_next_state does for the continuation, step by step
+#[allow(clippy::too_many_arguments)]
+fn explain(
+    grammar: &YaccGrammar,
+    table: &StateTable<u32>,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    token_names: &[String],
+    state: &LR1State,
+    continuation: usize,
+    bytes: &[u8],
+    max_tokens: Option<usize>,
+) -> Explanation {
+    let name = |tidx: TIdx<u32>| token_names[usize::from(tidx)].clone();
+    let expected = |stack: &[StIdx<u32>]| {
+        expected_terminals(grammar, table, token_names, stack)
+            .into_iter()
+            .map(String::from)
+            .collect_vec()
+    };
+    let mut explanation = Explanation {
+        continuation,
+        continues_lexeme: *state.matching != initial_prefix_matches(pdfas),
+        completed: vec![],
+        pending: vec![],
+        rejection: None,
+    };
+
+    let mut matching = state.matching.to_vec();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match find_token_or_matching(&bytes[i..], &matching, pdfas) {
+            Some(TokenOrMatching::Token(token, len)) => {
+                tokens.push((token, (i, i + len)));
+                i += len;
+                matching = initial_prefix_matches(pdfas);
+            }
+            Some(TokenOrMatching::Matching(next)) => {
+                matching = next;
+                break;
+            }
+            None => {
+                explanation.completed = tokens
+                    .into_iter()
+                    .map(|(token, span)| (token.map(name), span))
+                    .collect();
+                explanation.rejection = Some(Rejection::NoLexerMatch { offset: i });
+                return explanation;
+            }
+        }
+    }
+    explanation.completed = tokens
+        .iter()
+        .map(|&(token, span)| (token.map(name), span))
+        .collect();
+    explanation.pending = matching
+        .iter()
+        .filter_map(|&(pidx, _)| pdfas[pidx].1)
+        .unique()
+        .map(name)
+        .collect();
+    if max_tokens.is_some_and(|max| tokens.len() > max) {
+        explanation.rejection = Some(Rejection::TooManyTokens {
+            tokens: tokens.len(),
+        });
+        return explanation;
+    }
+
+    let mut stack = state.stack.to_vec();
+    for tidx in tokens.into_iter().filter_map(|(token, _)| token) {
+        stack = match shift_reduce(grammar, table, &stack, tidx) {
+            LR1Action::Stack(stack) => stack,
+            LR1Action::ShiftReduce(keep, stidx) => {
+                stack.truncate(keep);
+                stack.push(stidx);
+                stack
+            }
+            LR1Action::Accept | LR1Action::Error => {
+                explanation.rejection = Some(Rejection::UnexpectedTerminal {
+                    terminal: name(tidx),
+                    expected: expected(&stack),
+                });
+                return explanation;
+            }
+        };
+    }
+    if !is_valid_matching(matching.iter().copied(), grammar, table, pdfas, &stack) {
+        explanation.rejection = Some(Rejection::UnexpectedLexeme {
+            candidates: explanation.pending.clone(),
+            expected: expected(&stack),
+        });
+    }
+    explanation
+}
+
+// a dfa state has at most 512 transitions (stride) of 4 bytes each,
+// plus some slack for start states and match information
+const MAX_BYTES_PER_DFA_STATE: usize = 2048;
+const DFA_SIZE_SLACK: usize = 1 << 20;
+
+pub(crate) fn build_pdfa(
+    name: &str,
+    pattern: &str,
+    all_matches: bool,
+    limits: &CompileLimits,
+) -> Result<PrefixDFA, Box<dyn Error>> {
+    limits.check_repetitions(name, pattern)?;
+    let Some(limit) = limits.max_lexer_dfa_states else {
+        return Ok(PrefixDFA::with_options(pattern, None, all_matches)?);
+    };
+    let size_limit = limit
+        .saturating_mul(MAX_BYTES_PER_DFA_STATE)
+        .saturating_add(DFA_SIZE_SLACK);
+    let pdfa = PrefixDFA::with_options(pattern, Some(size_limit), all_matches).map_err(|e| {
+        if e.is_size_limit_exceeded() {
+            CompileLimitError::LexerDFATooLarge {
+                token: name.to_string(),
+                states: None,
+                limit,
+            }
+            .into()
+        } else {
+            e as Box<dyn Error>
+        }
     })?;
+    let states = pdfa.num_states();
+    if states > limit {
+        return Err(CompileLimitError::LexerDFATooLarge {
+            token: name.to_string(),
+            states: Some(states),
+            limit,
+        }
+        .into());
+    }
+    Ok(pdfa)
+}
 
-    // get token patterns and corresponding pdfas
-    let token_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
+// number of LR(0) states of the grammar, a lower bound on the states of its
+// LR table, since every state of the table adds lookaheads to one of them;
+// cheap compared to the table, so the state limit and the deadline are
+// checked while counting and stop huge grammars before the table is built;
+// reports the states counted so far as progress of the table phase
+fn count_lr0_states(
+    grammar: &YaccGrammar,
+    limits: &CompileLimits,
+    start: Instant,
+    progress: &mut dyn FnMut(CompileProgress),
+) -> Result<usize, CompileLimitError> {
+    // production and position of the dot in it
+    type Item = (PIdx<u32>, usize);
+    let closure = |kernel: &[Item]| {
+        let mut items = kernel.to_vec();
+        let mut rules = HashSet::new();
+        let mut i = 0;
+        while let Some(&(pidx, dot)) = items.get(i) {
+            i += 1;
+            if let Some(&Symbol::Rule(ridx)) = grammar.prod(pidx).get(dot) {
+                if rules.insert(ridx) {
+                    items.extend(grammar.rule_to_prods(ridx).iter().map(|&p| (p, 0)));
+                }
+            }
+        }
+        items
+    };
+    let kernel = vec![(grammar.start_prod(), 0)];
+    let mut states = HashSet::from([kernel.clone()]);
+    let mut pending = vec![kernel];
+    while let Some(kernel) = pending.pop() {
+        let mut gotos: HashMap<Symbol<u32>, Vec<Item>> = HashMap::new();
+        for (pidx, dot) in closure(&kernel) {
+            if let Some(&symbol) = grammar.prod(pidx).get(dot) {
+                gotos.entry(symbol).or_default().push((pidx, dot + 1));
+            }
+        }
+        for mut next in gotos.into_values() {
+            next.sort();
+            if states.insert(next.clone()) {
+                limits.check_states(states.len())?;
+                pending.push(next);
+            }
+        }
+        limits.check_time(start, "counting the LR states")?;
+        progress(CompileProgress::new(
+            CompilePhase::Table,
+            states.len(),
+            None,
+        ));
+    }
+    Ok(states.len())
+}
+
+// table workers abandoned at the deadline keep running until their table is
+// built, since lrtable cannot be interrupted; to bound the cpu they burn, at
+// most one worker per core runs at a time, and compilations wait for a free
+// one until their deadline
+struct TableWorkers {
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+static TABLE_WORKERS: TableWorkers = TableWorkers::new();
+
+struct TableWorker<'a>(&'a TableWorkers);
+
+impl Drop for TableWorker<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().expect("error locking table workers") -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+impl TableWorkers {
+    const fn new() -> Self {
+        Self {
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    // none if no worker finished before the deadline
+    fn acquire(&self, max: usize, deadline: Instant) -> Option<TableWorker<'_>> {
+        let mut running = self.running.lock().expect("error locking table workers");
+        while *running >= max {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            running = self
+                .finished
+                .wait_timeout(running, timeout)
+                .expect("error locking table workers")
+                .0;
+        }
+        *running += 1;
+        Some(TableWorker(self))
+    }
+}
+
+// with a compile time limit the table is built on a worker thread, and the
+// build is abandoned once the deadline passes; the worker cannot be
+// interrupted and still runs to the end in the background
+fn build_table_with_deadline(
+    grammar: YaccGrammar,
+    limits: &CompileLimits,
+    start: Instant,
+) -> Result<(YaccGrammar, StateTable<u32>, usize), Box<dyn Error>> {
+    let Some(limit) = limits.max_compile_time else {
+        let (graph, table) = lrtable::from_yacc(&grammar, Minimiser::Pager)?;
+        return Ok((grammar, table, usize::from(graph.all_states_len())));
+    };
+    let max_workers = thread::available_parallelism().map_or(1, usize::from);
+    let Some(worker) = TABLE_WORKERS.acquire(max_workers, start + limit) else {
+        return Err(CompileLimitError::Timeout {
+            phase: "waiting for a table worker",
+            elapsed: start.elapsed(),
+            limit,
+        }
+        .into());
+    };
+    let (tx, rx) = channel();
+    let handle = thread::spawn(move || {
+        // released when the table is built, even after the deadline
+        let _worker = worker;
+        let built = lrtable::from_yacc(&grammar, Minimiser::Pager);
+        // the receiver is gone if the deadline passed
+        let _ = tx.send((grammar, built));
+    });
+    match rx.recv_timeout(limit.saturating_sub(start.elapsed())) {
+        Ok((grammar, built)) => {
+            let (graph, table) = built?;
+            Ok((grammar, table, usize::from(graph.all_states_len())))
+        }
+        Err(RecvTimeoutError::Timeout) => Err(CompileLimitError::Timeout {
+            phase: "building the LR table",
+            elapsed: start.elapsed(),
+            limit,
+        }
+        .into()),
+        Err(RecvTimeoutError::Disconnected) => {
+            panic::resume_unwind(handle.join().expect_err("worker should have panicked"))
+        }
+    }
+}
+
+// the state limit and the compile time limit are enforced while building
+// the table, see count_lr0_states and build_table_with_deadline
+pub(crate) fn build_table(
+    grammar: YaccGrammar,
+    limits: &CompileLimits,
+    start: Instant,
+    progress: &mut dyn FnMut(CompileProgress),
+) -> Result<(YaccGrammar, StateTable<u32>, usize), Box<dyn Error>> {
+    progress(CompileProgress::new(CompilePhase::Table, 0, None));
+    count_lr0_states(&grammar, limits, start, progress)?;
+    let (grammar, table, num_states) = build_table_with_deadline(grammar, limits, start)?;
+    limits.check_states(num_states)?;
+    limits.check_time(start, "building the LR table")?;
+    progress(CompileProgress::new(
+        CompilePhase::Table,
+        num_states,
+        Some(num_states),
+    ));
+    Ok((grammar, table, num_states))
+}
+
+pub(crate) struct LexerSpec<'a> {
+    pub(crate) fragments: HashMap<&'a str, Vec<Part>>,
+    // use index map to preserve order
+    pub(crate) tokens: IndexMap<&'a str, Vec<Part>>,
+    pub(crate) ignore_tokens: Vec<Vec<Part>>,
+    pub(crate) byte_mode: bool,
+    pub(crate) all_matches: bool,
+    // tokens of the tokenizer some terminals are bound to, in the order of
+    // their placeholders, see special_token_placeholder
+    pub(crate) special_tokens: Vec<SpecialToken>,
+}
+
+// a terminal that is a single token of the tokenizer, e.g. <tool_call>,
+// instead of a pattern over bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SpecialToken {
+    Text(String),
+    Id(usize),
+}
+
+// special tokens are lexed from placeholders that cannot occur in utf8 text,
+// the LR(1) constraints replace the continuations of the special tokens
+// with them, so no other continuation can produce a special token terminal
+fn special_token_placeholder(idx: usize) -> Vec<u8> {
+    let mut placeholder = vec![0xFF];
+    placeholder.extend(idx.to_string().into_bytes());
+    placeholder.push(0xFE);
+    placeholder
+}
+
+// continuations with those of the special tokens replaced by their placeholders;
+// special tokens must be in the vocabulary unless there are no continuations yet,
+// e.g. for a grammar compiled for several vocabularies
+pub(crate) fn resolve_special_tokens(
+    special_tokens: &[SpecialToken],
+    mut continuations: Vec<Vec<u8>>,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    if special_tokens.is_empty() || continuations.is_empty() {
+        return Ok(continuations);
+    }
+    let mut indices = vec![];
+    for (idx, token) in special_tokens.iter().enumerate() {
+        let matching: Vec<_> = match token {
+            SpecialToken::Text(text) => continuations
+                .iter()
+                .positions(|cont| cont == text.as_bytes())
+                .collect(),
+            SpecialToken::Id(id) if *id < continuations.len() => vec![*id],
+            SpecialToken::Id(_) => vec![],
+        };
+        if matching.is_empty() {
+            return Err(match token {
+                SpecialToken::Text(text) => {
+                    format!("special token {text} not found in continuations")
+                }
+                SpecialToken::Id(id) => format!(
+                    "special token id {id} out of range for {} continuations",
+                    continuations.len()
+                ),
+            }
+            .into());
+        }
+        indices.extend(matching.into_iter().map(|cont| (cont, idx)));
+    }
+    if !indices.iter().map(|(cont, _)| cont).all_unique() {
+        return Err("continuation bound to more than one special token".into());
+    }
+    for (cont, idx) in indices {
+        continuations[cont] = special_token_placeholder(idx);
+    }
+    Ok(continuations)
+}
+
+pub(crate) fn parse_lexer(lexer: &str) -> Result<LexerSpec<'_>, Box<dyn Error>> {
+    if lexer.trim().is_empty() {
+        // no lexer rules, e.g. for grammars that only use quoted literals,
+        // which are added to the lexer automatically
+        return Ok(LexerSpec {
+            fragments: HashMap::new(),
+            tokens: IndexMap::new(),
+            ignore_tokens: vec![],
+            byte_mode: false,
+            all_matches: false,
+            special_tokens: vec![],
+        });
+    }
     let fragment_token_regex = Regex::new(r"(?Rm)^([A-Z][A-Z0-9_]*|;)\s+(.+)$")?;
     let sep = Regex::new("(?Rm)^%%$")?;
     let m = sep.find(lexer).ok_or("line with %% not found")?;
 
     // parse fragements
     let mut fragments = HashMap::new();
+    let mut byte_mode = false;
+    let mut all_matches = false;
     for line in lexer[..m.start()].lines() {
         if line.is_empty() || line.trim_start().starts_with("//") {
             continue;
+        } else if line.trim() == "%bytes" {
+            // all patterns match raw bytes instead of unicode characters,
+            // e.g. . matches any byte except \n and \xFF the byte 0xFF
+            byte_mode = true;
+            continue;
+        } else if line.trim() == "%longest" {
+            // alternatives in patterns do not take priority over each other,
+            // e.g. a|ab matches ab, lazy repetitions behave like greedy ones
+            all_matches = true;
+            continue;
         }
         let cap = fragment_token_regex
             .captures(line)
@@ -74,9 +608,9 @@ fn load_grammar_and_pdfas(
     }
 
     // parse tokens / terminals
-    // use index map to preserve order
     let mut tokens = IndexMap::new();
     let mut ignore_tokens = vec![];
+    let mut special_tokens = vec![];
     for line in lexer[m.end()..].lines() {
         if line.is_empty() || line.trim_start().starts_with("//") {
             continue;
@@ -86,7 +620,31 @@ fn load_grammar_and_pdfas(
             .ok_or(format!("invalid token line: {line}"))?;
         let name = cap.get(1).unwrap().as_str();
         let pattern = cap.get(2).unwrap().as_str();
-        let parts = extract_parts(pattern);
+        let special = if let Some(text) = pattern.strip_prefix("%token_id ") {
+            let id = text
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid token id {text} for {name}"))?;
+            Some(SpecialToken::Id(id))
+        } else {
+            pattern
+                .strip_prefix("%token ")
+                .map(|text| SpecialToken::Text(text.trim().to_string()))
+        };
+        let parts = if let Some(special) = special {
+            if name == ";" {
+                return Err("ignore tokens cannot be special tokens".into());
+            }
+            let placeholder = special_token_placeholder(special_tokens.len());
+            special_tokens.push(special);
+            let pattern = placeholder
+                .iter()
+                .map(|b| format!(r"(?-u:\x{b:02X})"))
+                .join("");
+            vec![Part::Regex(pattern)]
+        } else {
+            extract_parts(pattern)
+        };
         if parts.is_empty() {
             return Err(format!("invalid token pattern {pattern} for {name}").into());
         }
@@ -97,27 +655,114 @@ fn load_grammar_and_pdfas(
         if !ignore_tokens.is_empty() {
             return Err("ignore tokens must be at the end of the lexer file".into());
         }
-        if grammar.token_idx(name).is_none() {
-            eprintln!("token {name} not used in grammar, skipping...");
-        };
         if tokens.insert(name, parts).is_some() {
             return Err(format!("duplicate token {name}").into());
         };
     }
 
-    // build pdfas from fragments and tokens
-    let mut pdfas = vec![];
-    for (name, parts) in tokens.iter() {
-        let pattern = pattern_from_parts(name, parts, &token_name, &fragments, &tokens)?;
-        let pdfa = PrefixDFA::new(&pattern)?;
-        if pdfa.is_eoi_match(pdfa.get_start_state()) {
-            return Err(format!("token pattern {pattern} for {name} matches empty string").into());
+    Ok(LexerSpec {
+        fragments,
+        tokens,
+        ignore_tokens,
+        byte_mode,
+        all_matches,
+        special_tokens,
+    })
+}
+
+// display names of all grammar tokens, indexed by token index
+pub(crate) type TokenNames = Vec<String>;
+
+// how whitespace between tokens is lexed, e.g. to force compact or
+// canonical output without rewriting the lexer of a grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhitespacePolicy {
+    // the ignore tokens of the lexer as written
+    #[default]
+    Preserve,
+    // no whitespace between tokens, ignore tokens are dropped
+    Forbid,
+    // at most a single space before each token, ignore tokens are dropped
+    SingleSpace,
+    // any amount of spaces, tabs and newlines between tokens,
+    // replacing the ignore tokens of the lexer
+    FreeForm,
+}
+
+impl FromStr for WhitespacePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(WhitespacePolicy::Preserve),
+            "forbid" => Ok(WhitespacePolicy::Forbid),
+            "single_space" => Ok(WhitespacePolicy::SingleSpace),
+            "free_form" => Ok(WhitespacePolicy::FreeForm),
+            _ => Err(format!(
+                "unknown whitespace policy {s}, expected preserve, forbid, single_space or free_form"
+            )),
+        }
+    }
+}
+
+pub(crate) fn load_grammar_and_pdfas(
+    grammar: &str,
+    grammar_kind: YaccKind,
+    lexer: &str,
+    limits: &CompileLimits,
+    start: Instant,
+    progress: &mut dyn FnMut(CompileProgress),
+) -> Result<(YaccGrammar, PdfaList, bool, TokenNames), Box<dyn Error>> {
+    load_grammar_and_pdfas_with(
+        grammar,
+        grammar_kind,
+        lexer,
+        WhitespacePolicy::Preserve,
+        limits,
+        start,
+        progress,
+    )
+}
+
+fn load_grammar_and_pdfas_with(
+    grammar: &str,
+    grammar_kind: YaccKind,
+    lexer: &str,
+    whitespace: WhitespacePolicy,
+    limits: &CompileLimits,
+    start: Instant,
+    progress: &mut dyn FnMut(CompileProgress),
+) -> Result<(YaccGrammar, PdfaList, bool, TokenNames), Box<dyn Error>> {
+    progress(CompileProgress::new(CompilePhase::Grammar, 0, None));
+    let (source, aliases) = extract_token_aliases(grammar)?;
+    let grammar = YaccGrammar::new(grammar_kind, &source).map_err(|e| {
+        format!(
+            "errors creating grammar:\n{}",
+            e.iter().map(|e| format_yacc_error(grammar, e)).join("\n")
+        )
+    })?;
+    limits.check_rules(usize::from(grammar.rules_len()))?;
+    limits.check_time(start, "parsing the grammar")?;
+
+    let LexerSpec {
+        fragments,
+        tokens,
+        ignore_tokens,
+        byte_mode,
+        all_matches,
+        ..
+    } = parse_lexer(lexer)?;
+    for name in tokens.keys() {
+        if grammar.token_idx(name).is_none() {
+            eprintln!("token {name} not used in grammar, skipping...");
         };
-        pdfas.push((pdfa, grammar.token_idx(name)));
     }
+    let token_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
 
-    // add all unseen tokens from grammar as literal tokens to lexer
-    for token in grammar
+    // all unseen tokens from grammar are added as literal tokens to lexer,
+    // after the lexer tokens, so lexer tokens win ties of equal length;
+    // between literals longest match decides, e.g. <= over <
+    let unseen_tokens: Vec<_> = grammar
         .iter_tidxs()
         .filter_map(|tidx| {
             if tidx == grammar.eof_token_idx() {
@@ -126,40 +771,223 @@ fn load_grammar_and_pdfas(
             grammar.token_name(tidx)
         })
         .filter(|name| !fragments.contains_key(name) && !tokens.contains_key(name))
-    {
+        .collect();
+
+    let mut ignore_patterns = vec![];
+    match whitespace {
+        WhitespacePolicy::Preserve => {
+            for parts in &ignore_tokens {
+                ignore_patterns.push(pattern_from_parts(
+                    "ignore token",
+                    parts,
+                    &token_name,
+                    &fragments,
+                    &tokens,
+                )?);
+            }
+        }
+        WhitespacePolicy::Forbid | WhitespacePolicy::SingleSpace => {}
+        WhitespacePolicy::FreeForm => ignore_patterns.push(r"[ \t\r\n]+".to_string()),
+    }
+
+    // build pdfas from fragments and tokens
+    let total = tokens.len() + unseen_tokens.len() + ignore_patterns.len();
+    let mut report = |done| progress(CompileProgress::new(CompilePhase::Lexer, done, Some(total)));
+    report(0);
+    let mut pdfas = vec![];
+    let mode = |pattern: String| {
+        // the space is part of the following token instead of an ignore token,
+        // so there cannot be two in a row
+        let pattern = if whitespace == WhitespacePolicy::SingleSpace {
+            format!("(?: )?(?:{pattern})")
+        } else {
+            pattern
+        };
+        if byte_mode {
+            format!("(?-u:{pattern})")
+        } else {
+            pattern
+        }
+    };
+    for (name, parts) in tokens.iter() {
+        let pattern = mode(pattern_from_parts(
+            name,
+            parts,
+            &token_name,
+            &fragments,
+            &tokens,
+        )?);
+        let pdfa = build_pdfa(name, &pattern, all_matches, limits)?;
+        if pdfa.is_eoi_match(pdfa.get_start_state()) {
+            return Err(format!("token pattern {pattern} for {name} matches empty string").into());
+        };
+        pdfas.push((pdfa, grammar.token_idx(name)));
+        limits.check_time(start, "building the lexer")?;
+        report(pdfas.len());
+    }
+
+    for &token in &unseen_tokens {
         let tidx = grammar
             .token_idx(token)
             .ok_or(format!("token {token} not found in grammar"))?;
-        let pdfa = PrefixDFA::new(&escape(token))?;
+        let pdfa = build_pdfa(token, &mode(escape(token)), false, limits)?;
         pdfas.push((pdfa, Some(tidx)));
+        report(pdfas.len());
     }
 
     // add ignore pdfas at the end
-    for parts in &ignore_tokens {
-        let pattern = pattern_from_parts("ignore token", parts, &token_name, &fragments, &tokens)?;
-        let pdfa = PrefixDFA::new(&pattern)?;
+    for pattern in ignore_patterns {
+        let pattern = mode(pattern);
+        let pdfa = build_pdfa("ignore token", &pattern, all_matches, limits)?;
         if pdfa.is_eoi_match(pdfa.get_start_state()) {
             return Err(
                 format!("token pattern {pattern} for ignore token matches empty string").into(),
             );
         };
         pdfas.push((pdfa, None));
+        limits.check_time(start, "building the lexer")?;
+        report(pdfas.len());
+    }
+
+    // aliases first, then literals quoted as they appear in the input
+    let token_names = grammar
+        .iter_tidxs()
+        .map(|tidx| {
+            let Some(name) = grammar.token_name(tidx) else {
+                return "end of input".to_string();
+            };
+            if let Some(alias) = aliases.get(name) {
+                alias.clone()
+            } else if unseen_tokens.contains(&name) {
+                format!("'{name}'")
+            } else {
+                name.to_string()
+            }
+        })
+        .collect();
+
+    Ok((grammar, pdfas, byte_mode, token_names))
+}
+
+fn expected_tidxs<'g>(
+    grammar: &'g YaccGrammar,
+    table: &'g StateTable<u32>,
+    stack: &'g [StIdx<u32>],
+) -> impl Iterator<Item = TIdx<u32>> + 'g {
+    grammar.iter_tidxs().filter(move |&tidx| {
+        let action = shift_reduce(grammar, table, stack, tidx);
+        if tidx == grammar.eof_token_idx() {
+            action.is_accept()
+        } else {
+            !action.is_error()
+        }
+    })
+}
+
+fn expected_terminals<'a>(
+    grammar: &YaccGrammar,
+    table: &StateTable<u32>,
+    token_names: &'a [String],
+    stack: &[StIdx<u32>],
+) -> Vec<&'a str> {
+    expected_tidxs(grammar, table, stack)
+        .map(|tidx| token_names[usize::from(tidx)].as_str())
+        .collect()
+}
+
+// groups the valid continuations of the start state by the terminal they
+// commit to, that is the first one they complete or the only expected one
+// they can still start; continuations that only lex skippable input or could
+// still start several terminals do not commit and are left out
+fn openers<'a>(
+    constraint: &impl Constraint<State = LR1State>,
+    grammar: &YaccGrammar,
+    table: &StateTable<u32>,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    continuations: &[Vec<u8>],
+    token_names: &'a [String],
+) -> BTreeMap<&'a str, Vec<usize>> {
+    let start = constraint.get_start_state();
+    let expected: HashSet<_> = expected_tidxs(grammar, table, &start.stack).collect();
+    let mut openers: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for i in constraint.get_valid_continuations(&start) {
+        let Ok((tokens, _, matching, _)) =
+            prefix_lexer_with(&continuations[i], pdfas, start.matching.to_vec())
+        else {
+            continue;
+        };
+        let tidx = match tokens.into_iter().flatten().next() {
+            Some(tidx) => tidx,
+            None => {
+                let mut candidates = matching
+                    .iter()
+                    .filter_map(|&(pidx, _)| pdfas[pidx].1)
+                    .filter(|tidx| expected.contains(tidx))
+                    .unique();
+                match (candidates.next(), candidates.next()) {
+                    (Some(tidx), None) => tidx,
+                    _ => continue,
+                }
+            }
+        };
+        openers
+            .entry(token_names[usize::from(tidx)].as_str())
+            .or_default()
+            .push(i);
+    }
+    for indices in openers.values_mut() {
+        indices.sort();
+    }
+    openers
+}
+
+// hash of everything a compiled grammar consists of, that is the grammar, the
+// serialized lr table and the token dfas; tables and dfas are built
+// deterministically, so this is stable across runs, processes and threads
+fn compiled_fingerprint(
+    grammar: &YaccGrammar,
+    table: &StateTable<u32>,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+) -> FxHasher {
+    let mut hasher = FxHasher::default();
+    serde_json::to_vec(grammar)
+        .expect("grammar should be serializable")
+        .hash(&mut hasher);
+    serde_json::to_vec(table)
+        .expect("state table should be serializable")
+        .hash(&mut hasher);
+    for (pdfa, tidx) in pdfas {
+        pdfa.to_bytes().hash(&mut hasher);
+        tidx.map(usize::from).hash(&mut hasher);
     }
+    hasher
+}
 
-    Ok((grammar, pdfas))
+pub(crate) fn grammar_memory_usage(
+    grammar: &YaccGrammar,
+    num_states: usize,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+) -> usize {
+    // the state table stores actions per state and token and gotos
+    // per state and rule, it does so sparsely, so this is an upper bound
+    let table = num_states
+        * (usize::from(grammar.tokens_len()) + usize::from(grammar.rules_len()))
+        * size_of::<u32>();
+    let pdfas: usize = pdfas.iter().map(|(pdfa, _)| pdfa.memory_usage()).sum();
+    table + pdfas
 }
 
-type Tokens = Vec<Option<TIdx<u32>>>;
-type Span = (usize, usize);
-type Spans = Vec<Span>;
-type Matching = Vec<(usize, StateID)>;
+pub(crate) type Tokens = Vec<Option<TIdx<u32>>>;
+pub(crate) type Span = (usize, usize);
+pub(crate) type Spans = Vec<Span>;
+pub(crate) type Matching = Vec<(usize, StateID)>;
 
-enum TokenOrMatching {
+pub(crate) enum TokenOrMatching {
     Token(Option<TIdx<u32>>, usize),
     Matching(Matching),
 }
 
-fn find_token_or_matching(
+pub(crate) fn find_token_or_matching(
     prefix: &[u8],
     matching: &Matching,
     pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
@@ -192,10 +1020,10 @@ fn find_token_or_matching(
     }
 }
 
-type PrefixLexerOutput = (Tokens, Spans, Matching, Span);
+pub(crate) type PrefixLexerOutput = (Tokens, Spans, Matching, Span);
 
 #[inline]
-fn prefix_lexer_with(
+pub(crate) fn prefix_lexer_with(
     continuation: &[u8],
     pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
     mut prefix_matches: Matching,
@@ -241,20 +1069,89 @@ fn initial_prefix_match_iter(
 }
 
 #[inline]
-fn initial_prefix_matches(pdfas: &[(PrefixDFA, Option<TIdx<u32>>)]) -> Matching {
+pub(crate) fn initial_prefix_matches(pdfas: &[(PrefixDFA, Option<TIdx<u32>>)]) -> Matching {
     initial_prefix_match_iter(pdfas).collect()
 }
 
-fn prefix_lexer(
-    prefix: impl AsRef<[u8]>,
-    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
-) -> Result<PrefixLexerOutput, Box<dyn Error>> {
-    // initially all pdfas are in the potential prefix matches, the start state
+// the following helpers are shared by the constraints for grammars that are
+// not LR(1), which use the lexer of the LR(1) constraints with their own parser
+
+// indices of the valid continuations in the order of optimized_prefix_order,
+// continuations starting with an invalid one are skipped without checking
+pub(crate) fn valid_continuations_in_order(
+    continuations: &[Vec<u8>],
+    permutation: &[usize],
+    skips: &[usize],
+    mut is_valid: impl FnMut(&[u8]) -> bool,
+) -> Vec<usize> {
+    let mut conts = vec![];
+    let mut i = 0;
+    while i < permutation.len() {
+        let skip = skips[i];
+        let j = permutation[i];
+        i += 1;
+        if is_valid(&continuations[j]) {
+            conts.push(j);
+        } else {
+            // continuations starting with an invalid one are invalid too
+            i += skip;
+        }
+    }
+    conts.sort();
+    conts
+}
+
+// lexes the bytes following the pending lexeme and shifts every completed
+// terminal into the parse; none if the lexer or the parser rejects them
+pub(crate) fn advance_lexer<P>(
+    bytes: &[u8],
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    matching: &Matching,
+    parse: P,
+    shift: impl FnMut(P, TIdx<u32>) -> Option<P>,
+) -> Option<(P, Matching)> {
+    let (tokens, _, matching, _) = prefix_lexer_with(bytes, pdfas, matching.clone()).ok()?;
+    let parse = tokens.into_iter().flatten().try_fold(parse, shift)?;
+    Some((parse, matching))
+}
+
+// the pending lexeme has to be skippable or the prefix of a terminal other
+// than the end of input that the parser expects
+pub(crate) fn is_valid_remainder(
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    matching: &Matching,
+    eof: TIdx<u32>,
+    mut expects: impl FnMut(TIdx<u32>) -> bool,
+) -> bool {
+    matching.iter().any(|&(pidx, _)| match pdfas[pidx].1 {
+        Some(tidx) => tidx != eof && expects(tidx),
+        None => true,
+    })
+}
+
+// terminals the pending lexeme completes if the input ends here
+pub(crate) fn eoi_terminals<'a>(
+    pdfas: &'a [(PrefixDFA, Option<TIdx<u32>>)],
+    matching: &'a Matching,
+) -> impl Iterator<Item = TIdx<u32>> + 'a {
+    matching
+        .iter()
+        .filter_map(|&(pidx, pdfa_state)| match &pdfas[pidx] {
+            (pdfa, Some(tidx)) if pdfa.is_eoi_match(pdfa_state) => Some(*tidx),
+            _ => None,
+        })
+}
+
+fn prefix_lexer(
+    prefix: impl AsRef<[u8]>,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+) -> Result<PrefixLexerOutput, Box<dyn Error>> {
+    // initially all pdfas are in the potential prefix matches, the start state
     let prefix_matches = initial_prefix_matches(pdfas);
     prefix_lexer_with(prefix.as_ref(), pdfas, prefix_matches)
 }
 
-fn lexer(
+pub(crate) fn lexer(
     text: impl AsRef<[u8]>,
     pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
 ) -> Result<(Tokens, Spans), Box<dyn Error>> {
@@ -285,10 +1182,96 @@ fn lexer(
     Ok((tokens, spans))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexErrorKind {
+    // no token matches at the start of the span
+    NoMatch,
+    // the input ends inside a token
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub span: Span,
+}
+
+// name of the tokens covering unlexable spans when lexing with recovery
+pub const LEX_ERROR_TOKEN: &str = "ERROR";
+
+type RecoveredTokens = Vec<Result<Option<TIdx<u32>>, LexErrorKind>>;
+
+fn close_lex_error(
+    error_start: &mut Option<usize>,
+    tokens: &mut RecoveredTokens,
+    spans: &mut Spans,
+    end: usize,
+) {
+    if let Some(start) = error_start.take() {
+        tokens.push(Err(LexErrorKind::NoMatch));
+        spans.push((start, end));
+    }
+}
+
+// like lexer, but unlexable input is skipped until a token matches again,
+// in units of characters unless in byte mode, and reported as errors
+fn lexer_with_recovery(
+    text: &[u8],
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    byte_mode: bool,
+) -> (RecoveredTokens, Spans) {
+    let mut tokens = vec![];
+    let mut spans = vec![];
+    let mut error_start = None;
+    let initial = initial_prefix_matches(pdfas);
+    let mut i = 0;
+    while i < text.len() {
+        match find_token_or_matching(&text[i..], &initial, pdfas) {
+            Some(TokenOrMatching::Token(token, len)) => {
+                close_lex_error(&mut error_start, &mut tokens, &mut spans, i);
+                tokens.push(Ok(token));
+                spans.push((i, i + len));
+                i += len;
+            }
+            Some(TokenOrMatching::Matching(matching)) => {
+                close_lex_error(&mut error_start, &mut tokens, &mut spans, i);
+                let token = matching.iter().find_map(|&(pidx, state)| {
+                    let (pdfa, token) = &pdfas[pidx];
+                    pdfa.is_eoi_match(state).then_some(*token)
+                });
+                tokens.push(token.ok_or(LexErrorKind::Incomplete));
+                spans.push((i, text.len()));
+                i = text.len();
+            }
+            None => {
+                error_start.get_or_insert(i);
+                i += if byte_mode {
+                    1
+                } else {
+                    // skip a whole utf8 character, invalid bytes one by one
+                    match text[i] {
+                        0xC0..=0xDF => 2,
+                        0xE0..=0xEF => 3,
+                        0xF0..=0xF7 => 4,
+                        _ => 1,
+                    }
+                }
+                .min(text.len() - i);
+            }
+        }
+    }
+    close_lex_error(&mut error_start, &mut tokens, &mut spans, text.len());
+    (tokens, spans)
+}
+
 pub struct LR1GrammarParser {
     grammar: YaccGrammar<u32>,
     table: StateTable<u32>,
     pdfas: Vec<(PrefixDFA, Option<TIdx<u32>>)>,
+    byte_mode: bool,
+    token_names: TokenNames,
+    normalization: Normalization,
+    provenance: Option<InlineProvenance>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -378,25 +1361,139 @@ impl LR1Parse<'_> {
         }
         pretty_parse(self, 0, skip_empty, collapse_single)
     }
+
+    // full parse tree in a stable format for snapshot tests: one node per line,
+    // indented by depth, terminals with their span and ascii escaped value
+    pub fn to_canonical_string(&self) -> String {
+        fn canonical(parse: &LR1Parse<'_>, depth: usize, s: &mut String) {
+            let indent = depth * 2;
+            match parse {
+                LR1Parse::Empty(name) => {
+                    s.push_str(&format!("{:indent$}{name} (empty)\n", ""));
+                }
+                LR1Parse::Terminal(name, (start, end), value) => {
+                    s.push_str(&format!(
+                        "{:indent$}{name} {start}..{end} \"{}\"\n",
+                        "",
+                        value.escape_ascii()
+                    ));
+                }
+                LR1Parse::NonTerminal(name, children) => {
+                    s.push_str(&format!("{:indent$}{name}\n", ""));
+                    for child in children {
+                        canonical(child, depth + 1, s);
+                    }
+                }
+            }
+        }
+        let mut s = String::new();
+        canonical(self, 0, &mut s);
+        s
+    }
+
+    // tree structure without values and empty nodes, e.g. for grammar tests;
+    // terminals that are not identifiers are quoted:
+    // (json (value (arr '[' (arr_plus (value NUMBER)) ']')))
+    pub fn skeleton(&self) -> String {
+        match self {
+            LR1Parse::Empty(..) => String::new(),
+            LR1Parse::Terminal(name, ..)
+                if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                name.to_string()
+            }
+            LR1Parse::Terminal(name, ..) => format!("'{name}'"),
+            LR1Parse::NonTerminal(name, children) => {
+                let mut s = format!("({name}");
+                for child in children.iter().map(|c| c.skeleton()) {
+                    if !child.is_empty() {
+                        s.push(' ');
+                        s.push_str(&child);
+                    }
+                }
+                s.push(')');
+                s
+            }
+        }
+    }
 }
 
 pub type TokenAndSpan<'a> = (Option<&'a str>, Span);
 
 impl LR1GrammarParser {
     pub fn new(grammar: &str, tokens: &str) -> Result<Self, Box<dyn Error>> {
-        let (grammar, pdfas) = load_grammar_and_pdfas(
+        Self::with_limits(grammar, tokens, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        tokens: &str,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile(grammar, tokens, limits, |_| {})
+    }
+
+    pub fn compile(
+        grammar: &str,
+        tokens: &str,
+        limits: &CompileLimits,
+        mut progress: impl FnMut(CompileProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let (grammar, pdfas, byte_mode, token_names) = load_grammar_and_pdfas(
             grammar,
             YaccKind::Original(YaccOriginalActionKind::GenericParseTree),
             tokens,
+            limits,
+            start,
+            &mut progress,
         )?;
-        let (_, table) = lrtable::from_yacc(&grammar, Minimiser::Pager)?;
+        let (grammar, table, _) = build_table(grammar, limits, start, &mut progress)?;
         Ok(Self {
             grammar,
             table,
             pdfas,
+            byte_mode,
+            token_names,
+            normalization: Normalization::default(),
+            provenance: None,
         })
     }
 
+    // restores the rules inlined into the grammar in parse trees,
+    // see inline_rules
+    pub fn with_provenance(mut self, provenance: InlineProvenance) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    fn restore<'a>(&'a self, tree: LR1Parse<'a>) -> LR1Parse<'a> {
+        match &self.provenance {
+            Some(provenance) => provenance.restore(tree),
+            None => tree,
+        }
+    }
+
+    // normalizes inputs before lexing, spans in lexing and parsing results
+    // as well as terminal values still refer to the original input
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn normalization(&self) -> Normalization {
+        self.normalization
+    }
+
+    // input as seen by the lexer, with the map back to the input if normalized
+    fn normalize_input<'i>(&self, input: &'i [u8]) -> (Cow<'i, [u8]>, Option<OffsetMap>) {
+        if self.normalization.is_identity() {
+            return (Cow::Borrowed(input), None);
+        }
+        let (normalized, map) = normalize(input, self.normalization);
+        (Cow::Owned(normalized), Some(map))
+    }
+
     pub fn from_files(
         grammar_path: impl AsRef<Path>,
         tokens_path: impl AsRef<Path>,
@@ -408,21 +1505,68 @@ impl LR1GrammarParser {
         Self::new(&grammar, &tokens)
     }
 
-    pub fn lex(&self, text: &str) -> Result<Vec<TokenAndSpan<'_>>, Box<dyn Error>> {
+    // whether the lexer was defined with %bytes, in which case
+    // terminal values are not necessarily valid utf8
+    pub fn byte_mode(&self) -> bool {
+        self.byte_mode
+    }
+
+    // stable across runs, processes and thread counts, e.g. for caching artifacts
+    pub fn fingerprint(&self) -> u64 {
+        compiled_fingerprint(&self.grammar, &self.table, &self.pdfas).finish()
+    }
+
+    pub fn lex(&self, text: impl AsRef<[u8]>) -> Result<Vec<TokenAndSpan<'_>>, Box<dyn Error>> {
+        let (text, map) = self.normalize_input(text.as_ref());
         let (tokens, spans) = lexer(text, &self.pdfas)?;
         Ok(tokens
             .into_iter()
             .zip(spans)
-            .map(|(tidx, span)| (tidx.and_then(|tidx| self.grammar.token_name(tidx)), span))
+            .map(|(tidx, span)| {
+                (
+                    tidx.and_then(|tidx| self.grammar.token_name(tidx)),
+                    original_span(map.as_ref(), span),
+                )
+            })
             .collect())
     }
 
+    // error-tolerant lexing for dirty inputs, unlexable spans become
+    // LEX_ERROR_TOKEN tokens and are additionally returned as errors
+    pub fn lex_with_recovery(
+        &self,
+        text: impl AsRef<[u8]>,
+    ) -> (Vec<TokenAndSpan<'_>>, Vec<LexError>) {
+        let (text, map) = self.normalize_input(text.as_ref());
+        let (tokens, spans) = lexer_with_recovery(&text, &self.pdfas, self.byte_mode);
+        let mut errors = vec![];
+        let tokens = tokens
+            .into_iter()
+            .zip(spans)
+            .map(|(token, span)| (token, original_span(map.as_ref(), span)))
+            .map(|(token, span)| match token {
+                Ok(tidx) => (tidx.and_then(|tidx| self.grammar.token_name(tidx)), span),
+                Err(kind) => {
+                    errors.push(LexError { kind, span });
+                    (Some(LEX_ERROR_TOKEN), span)
+                }
+            })
+            .collect();
+        (tokens, errors)
+    }
+
     pub fn prefix_lex(&self, prefix: &[u8]) -> Result<Vec<TokenAndSpan<'_>>, Box<dyn Error>> {
-        let (tokens, spans, ..) = prefix_lexer(prefix, &self.pdfas)?;
+        let (prefix, map) = self.normalize_input(prefix);
+        let (tokens, spans, ..) = prefix_lexer(&prefix, &self.pdfas)?;
         Ok(tokens
             .into_iter()
             .zip(spans)
-            .map(|(tidx, span)| (tidx.and_then(|tidx| self.grammar.token_name(tidx)), span))
+            .map(|(tidx, span)| {
+                (
+                    tidx.and_then(|tidx| self.grammar.token_name(tidx)),
+                    original_span(map.as_ref(), span),
+                )
+            })
             .collect())
     }
 
@@ -433,13 +1577,15 @@ impl LR1GrammarParser {
         is_prefix: bool,
     ) -> Result<LR1Parse<'_>, Box<dyn Error>> {
         let input = input.as_ref();
+        let (lexed, map) = self.normalize_input(input);
         let (tokens, spans) = if is_prefix {
-            let (tokens, spans, ..) = prefix_lexer(input, &self.pdfas)?;
+            let (tokens, spans, ..) = prefix_lexer(&lexed, &self.pdfas)?;
             (tokens, spans)
         } else {
-            lexer(input, &self.pdfas)?
+            lexer(&lexed, &self.pdfas)?
         };
 
+        // from here on spans refer to the original input
         let mut tokens: Vec<_> = tokens
             .into_iter()
             .zip(spans)
@@ -448,7 +1594,7 @@ impl LR1GrammarParser {
                     (
                         tidx,
                         self.grammar.token_name(tidx).unwrap_or("UNKOWN"),
-                        span,
+                        original_span(map.as_ref(), span),
                     )
                 })
             })
@@ -512,10 +1658,14 @@ impl LR1GrammarParser {
                 }
                 Action::Error => {
                     let (t_start, t_end) = span;
+                    let expected =
+                        expected_terminals(&self.grammar, &self.table, &self.token_names, &pstack);
                     return Err(format!(
-                        "parse error at position {t_start} for token {t_name} with content '{}' \
-                        (the input most likely does not follow the grammar)",
-                        String::from_utf8_lossy(&input[t_start..t_end])
+                        "parse error at position {t_start}: unexpected {} with content '{}', \
+                        expected {} (the input most likely does not follow the grammar)",
+                        self.token_names[usize::from(la_tidx)],
+                        String::from_utf8_lossy(&input[t_start..t_end]),
+                        format_expected(&expected)
                     )
                     .into());
                 }
@@ -566,7 +1716,7 @@ impl LR1GrammarParser {
     ) -> Result<(LR1Parse<'_>, &'p [u8]), Box<dyn Error>> {
         let tree = self
             .parse_tree(prefix, true)
-            .map(|tree| Self::filter_parse(tree, skip_empty, collapse_single))?;
+            .map(|tree| Self::filter_parse(self.restore(tree), skip_empty, collapse_single))?;
         fn find_end(parse: &LR1Parse<'_>, end: usize) -> usize {
             match parse {
                 LR1Parse::Empty(..) => end,
@@ -583,22 +1733,98 @@ impl LR1GrammarParser {
 
     pub fn parse(
         &self,
-        text: &str,
+        text: impl AsRef<[u8]>,
         skip_empty: bool,
         collapse_single: bool,
     ) -> Result<LR1Parse<'_>, Box<dyn Error>> {
         self.parse_tree(text, false)
-            .map(|tree| Self::filter_parse(tree, skip_empty, collapse_single))
+            .map(|tree| Self::filter_parse(self.restore(tree), skip_empty, collapse_single))
+    }
+}
+
+fn original_span(map: Option<&OffsetMap>, span: Span) -> Span {
+    map.map_or(span, |map| map.original_span(span))
+}
+
+// continuations the lexer alone accepts from a lexer state, independent of the
+// parser stack, so one mask serves all states sharing the pending lexeme; a
+// superset of the valid continuations, filled lazily and cleared when full
+#[derive(Default)]
+struct LexerMasks(Mutex<HashMap<HashConsed<Matching>, Arc<Vec<usize>>>>);
+
+const LEXER_MASKS_CAPACITY: usize = 1024;
+
+impl LexerMasks {
+    fn get(
+        &self,
+        matching: &HashConsed<Matching>,
+        compute: impl FnOnce() -> Vec<usize>,
+    ) -> Arc<Vec<usize>> {
+        if let Some(mask) = self.0.lock().expect("lexer masks poisoned").get(matching) {
+            return mask.clone();
+        }
+        let mask = Arc::new(compute());
+        let mut masks = self.0.lock().expect("lexer masks poisoned");
+        if masks.len() >= LEXER_MASKS_CAPACITY {
+            masks.clear();
+        }
+        masks.insert(matching.clone(), mask.clone());
+        mask
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0
+            .lock()
+            .expect("lexer masks poisoned")
+            .iter()
+            .map(|(matching, mask)| {
+                matching.len() * size_of::<(usize, StateID)>()
+                    + mask.capacity() * size_of::<usize>()
+            })
+            .sum()
     }
 }
 
+// continuations the lexer can consume from the matching pdfas, with at most
+// max_tokens completed tokens; the permutation and skips are from
+// optimized_prefix_order, so a continuation the lexer rejects rules out all
+// continuations it is a prefix of
+fn lexer_continuations(
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    continuations: &[Vec<u8>],
+    permutation: &[usize],
+    skips: &[usize],
+    matching: &Matching,
+    max_tokens: usize,
+) -> Vec<usize> {
+    let mut conts = vec![];
+    let mut i = 0;
+    while i < permutation.len() {
+        let skip = skips[i];
+        let j = permutation[i];
+        i += 1;
+        match prefix_lexer_with(&continuations[j], pdfas, matching.clone()) {
+            Ok((tokens, ..)) if tokens.len() <= max_tokens => conts.push(j),
+            Ok(_) => {}
+            Err(_) => i += skip,
+        }
+    }
+    conts.sort();
+    conts
+}
+
 pub struct ExactLR1GrammarConstraint {
-    pub(crate) grammar: YaccGrammar<u32>,
-    table: StateTable<u32>,
-    pdfas: Vec<(PrefixDFA, Option<TIdx<u32>>)>,
+    // grammar, table and lexer are shared between vocabularies, see with_continuations
+    pub(crate) grammar: Arc<YaccGrammar<u32>>,
+    table: Arc<StateTable<u32>>,
+    num_states: usize,
+    pdfas: Arc<Vec<(PrefixDFA, Option<TIdx<u32>>)>>,
     continuations: Vec<Vec<u8>>,
     permutation: Vec<usize>,
     skips: Vec<usize>,
+    token_names: Arc<TokenNames>,
+    special_tokens: Arc<Vec<SpecialToken>>,
+    lexer_masks: LexerMasks,
 }
 
 #[derive(Debug)]
@@ -681,31 +1907,6 @@ fn shift_reduce(
     LR1Action::ShiftReduce(stack_end + 1, stidx)
 }
 
-#[inline]
-fn partition_matching(
-    matching: impl IntoIterator<Item = (usize, StateID)>,
-    grammar: &YaccGrammar,
-    table: &StateTable<u32>,
-    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
-    stack: &[StIdx<u32>],
-) -> (Vec<usize>, Vec<usize>) {
-    // parition matching into valid and invalid
-    matching.into_iter().partition_map(|(pidx, _)| {
-        let (_, tidx) = &pdfas[pidx];
-        if let Some(&tidx) = tidx.as_ref() {
-            if tidx != grammar.eof_token_idx()
-                && !shift_reduce(grammar, table, stack, tidx).is_error()
-            {
-                Either::Left(pidx)
-            } else {
-                Either::Right(pidx)
-            }
-        } else {
-            Either::Left(pidx)
-        }
-    })
-}
-
 fn is_valid_matching(
     matching: impl IntoIterator<Item = (usize, StateID)>,
     grammar: &YaccGrammar,
@@ -810,26 +2011,223 @@ fn is_match_state(
     })
 }
 
+// what the parser stack alone decides about the lexer dfas: whether the
+// parser takes the terminal of a dfa next, and whether the output can end
+// right after it; shared by all states with the same stack, e.g. beams that
+// only differ in their pending lexeme
+#[derive(Debug, Clone)]
+pub(crate) struct StackLexemes {
+    valid: Vec<bool>,
+    accepting: Vec<bool>,
+}
+
+impl StackLexemes {
+    fn new(
+        grammar: &YaccGrammar,
+        table: &StateTable<u32>,
+        pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+        stack: &[StIdx<u32>],
+    ) -> Self {
+        let (valid, accepting) = pdfas
+            .iter()
+            .map(|(_, tidx)| {
+                // skippable lexemes never change the stack
+                let Some(&tidx) = tidx.as_ref() else {
+                    return (true, false);
+                };
+                let action = shift_reduce(grammar, table, stack, tidx);
+                let valid = tidx != grammar.eof_token_idx() && !action.is_error();
+                let accepting = match action {
+                    LR1Action::Stack(stack) => is_accept_state(grammar, table, &stack),
+                    LR1Action::ShiftReduce(keep, stidx) => {
+                        let mut next_stack = stack[..keep].to_vec();
+                        next_stack.push(stidx);
+                        is_accept_state(grammar, table, &next_stack)
+                    }
+                    _ => false,
+                };
+                (valid, accepting)
+            })
+            .unzip();
+        Self { valid, accepting }
+    }
+
+    // same as is_valid_matching with the stack
+    fn is_valid_matching(&self, matching: impl IntoIterator<Item = (usize, StateID)>) -> bool {
+        matching.into_iter().any(|(pidx, _)| self.valid[pidx])
+    }
+
+    // same as is_match_state with a state on the stack
+    fn is_match(&self, pdfas: &[(PrefixDFA, Option<TIdx<u32>>)], matching: &Matching) -> bool {
+        matching.iter().any(|&(pidx, pdfa_state)| {
+            self.accepting[pidx] && pdfas[pidx].0.is_eoi_match(pdfa_state)
+        })
+    }
+
+    pub(crate) fn heap_size(&self) -> usize {
+        self.valid.capacity() + self.accepting.capacity()
+    }
+}
+
+// state after driving the state with the bytes, which may contain any number of tokens
+fn next_state_with_bytes(
+    grammar: &YaccGrammar,
+    table: &StateTable<u32>,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    state: &LR1State,
+    bytes: &[u8],
+) -> Option<LR1State> {
+    let (tokens, _, matching, _) = prefix_lexer_with(bytes, pdfas, state.matching.to_vec()).ok()?;
+    let Drive::Stack(stack) = drive(grammar, table, state.stack.to_vec(), &tokens) else {
+        return None;
+    };
+    if !is_valid_matching(matching.iter().copied(), grammar, table, pdfas, &stack) {
+        return None;
+    }
+    Some(LR1State::new(stack, matching))
+}
+
+// largest prefix length of the bytes ending in a match state,
+// stepping through the bytes one at a time
+fn last_match_position(
+    grammar: &YaccGrammar,
+    table: &StateTable<u32>,
+    pdfas: &[(PrefixDFA, Option<TIdx<u32>>)],
+    bytes: &[u8],
+) -> Option<usize> {
+    let mut state = LR1State::new(vec![table.start_state()], initial_prefix_matches(pdfas));
+    let mut last = is_match_state(grammar, table, pdfas, &state).then_some(0);
+    for (i, b) in bytes.iter().enumerate() {
+        let Some(next) = next_state_with_bytes(grammar, table, pdfas, &state, &[*b]) else {
+            break;
+        };
+        state = next;
+        if is_match_state(grammar, table, pdfas, &state) {
+            last = Some(i + 1);
+        }
+    }
+    last
+}
+
 impl ExactLR1GrammarConstraint {
     pub fn new(
         grammar: &str,
         lexer: &str,
         continuations: Vec<Vec<u8>>,
     ) -> Result<Self, Box<dyn Error>> {
-        let (grammar, pdfas) = load_grammar_and_pdfas(
+        Self::with_limits(grammar, lexer, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        lexer: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile(grammar, lexer, continuations, limits, |_| {})
+    }
+
+    // whitespace between tokens follows the policy instead of the
+    // ignore tokens of the lexer
+    pub fn with_whitespace(
+        grammar: &str,
+        lexer: &str,
+        continuations: Vec<Vec<u8>>,
+        whitespace: WhitespacePolicy,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile_with_whitespace(
+            grammar,
+            lexer,
+            continuations,
+            whitespace,
+            &CompileLimits::default(),
+            |_| {},
+        )
+    }
+
+    pub fn compile(
+        grammar: &str,
+        lexer: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+        progress: impl FnMut(CompileProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile_with_whitespace(
+            grammar,
+            lexer,
+            continuations,
+            WhitespacePolicy::Preserve,
+            limits,
+            progress,
+        )
+    }
+
+    pub fn compile_with_whitespace(
+        grammar: &str,
+        lexer: &str,
+        continuations: Vec<Vec<u8>>,
+        whitespace: WhitespacePolicy,
+        limits: &CompileLimits,
+        mut progress: impl FnMut(CompileProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let (grammar, pdfas, _, token_names) = load_grammar_and_pdfas_with(
             grammar,
             YaccKind::Original(YaccOriginalActionKind::NoAction),
             lexer,
+            whitespace,
+            limits,
+            start,
+            &mut progress,
         )?;
-        let (_, table) = lrtable::from_yacc(&grammar, Minimiser::Pager)?;
+        let special_tokens = parse_lexer(lexer)?.special_tokens;
+        let continuations = resolve_special_tokens(&special_tokens, continuations)?;
+        let (grammar, table, num_states) = build_table(grammar, limits, start, &mut progress)?;
         let (permutation, skips) = optimized_prefix_order(&continuations);
         Ok(Self {
             continuations,
-            grammar,
-            pdfas,
-            table,
+            grammar: Arc::new(grammar),
+            pdfas: Arc::new(pdfas),
+            table: Arc::new(table),
+            num_states,
+            permutation,
+            skips,
+            token_names: Arc::new(token_names),
+            special_tokens: Arc::new(special_tokens),
+            lexer_masks: LexerMasks::default(),
+        })
+    }
+
+    // memory of the compiled grammar, table and lexer, which is shared with
+    // all constraints created by with_continuations, so it should be counted
+    // once for all of them
+    pub fn shared_memory_usage(&self) -> usize {
+        grammar_memory_usage(&self.grammar, self.num_states, &self.pdfas)
+    }
+
+    // memory used for the continuations of this constraint only
+    pub fn vocabulary_memory_usage(&self) -> usize {
+        continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+            + self.lexer_masks.memory_usage()
+    }
+
+    // the same grammar with other continuations, e.g. the vocabulary of another
+    // tokenizer; shares the compiled grammar, table and lexer instead of rebuilding them
+    pub fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        let continuations = resolve_special_tokens(&self.special_tokens, continuations)?;
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            continuations,
+            grammar: self.grammar.clone(),
+            pdfas: self.pdfas.clone(),
+            table: self.table.clone(),
+            num_states: self.num_states,
             permutation,
             skips,
+            token_names: self.token_names.clone(),
+            special_tokens: self.special_tokens.clone(),
+            lexer_masks: LexerMasks::default(),
         })
     }
 
@@ -845,27 +2243,125 @@ impl ExactLR1GrammarConstraint {
         Self::new(&grammar, &tokens, continuations)
     }
 
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // stable across runs, processes and thread counts, e.g. for caching artifacts
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = compiled_fingerprint(&self.grammar, &self.table, &self.pdfas);
+        self.continuations.hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn only_skippable_matching(&self, state: &LR1State) -> bool {
         only_skippable_matching(&state.matching, &self.pdfas)
     }
-}
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Default)]
-pub struct LR1State {
-    stack: Vec<StIdx<u32>>,
-    matching: Matching,
-}
+    // display names of the terminals the parser accepts next, including
+    // the one currently being lexed; uses %token aliases if given
+    pub fn expected_terminals(&self, state: &LR1State) -> Vec<&str> {
+        expected_terminals(&self.grammar, &self.table, &self.token_names, &state.stack)
+    }
 
-impl LR1State {
-    #[allow(dead_code)]
-    pub fn next(&mut self, state: LR1NextState) {
-        if let Some((keep, stidx, ..)) = state.action {
-            self.stack.truncate(keep);
-            self.stack.extend(stidx);
-        }
-        self.matching = state.matching;
+    // why the continuation is valid or invalid in the state, none if there
+    // is no such continuation
+    pub fn explain(&self, state: &LR1State, continuation: usize) -> Option<Explanation> {
+        let bytes = self.continuations.get(continuation)?;
+        Some(explain(
+            &self.grammar,
+            &self.table,
+            &self.pdfas,
+            &self.token_names,
+            state,
+            continuation,
+            bytes,
+            Some(1),
+        ))
     }
-}
+
+    // opening continuations grouped by the terminal they commit to, e.g. to
+    // bias generation towards one top-level branch of the grammar
+    pub fn openers(&self) -> BTreeMap<&str, Vec<usize>> {
+        openers(
+            self,
+            &self.grammar,
+            &self.table,
+            &self.pdfas,
+            &self.continuations,
+            &self.token_names,
+        )
+    }
+}
+
+impl Intern for Vec<StIdx<u32>> {
+    fn interner() -> &'static Interner<Self> {
+        static STACKS: LazyLock<Interner<Vec<StIdx<u32>>>> = LazyLock::new(Interner::default);
+        &STACKS
+    }
+}
+
+impl Intern for Matching {
+    fn interner() -> &'static Interner<Self> {
+        static MATCHINGS: LazyLock<Interner<Matching>> = LazyLock::new(Interner::default);
+        &MATCHINGS
+    }
+}
+
+pub(crate) type LR1Stack = HashConsed<Vec<StIdx<u32>>>;
+pub(crate) type LR1Matching = HashConsed<Matching>;
+
+// parser stack and lexer remainder are hash consed, so states are cloned
+// in O(1), e.g. for beams, and compared and hashed by their ids
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Default)]
+pub struct LR1State {
+    stack: LR1Stack,
+    matching: LR1Matching,
+}
+
+impl LR1State {
+    fn new(stack: Vec<StIdx<u32>>, matching: Matching) -> Self {
+        Self {
+            stack: HashConsed::new(stack),
+            matching: HashConsed::new(matching),
+        }
+    }
+
+    // the LR core state, i.e. the parser stack, and the lexer remainder,
+    // i.e. the states of all lexer dfas still matching the pending lexeme
+    pub(crate) fn split(&self) -> (&LR1Stack, &LR1Matching) {
+        (&self.stack, &self.matching)
+    }
+
+    pub(crate) fn into_split(self) -> (LR1Stack, LR1Matching) {
+        (self.stack, self.matching)
+    }
+
+    // whether both states have the same parser stack, e.g. after
+    // a continuation that only advanced over skippable input
+    pub(crate) fn same_stack(&self, other: &Self) -> bool {
+        self.stack == other.stack
+    }
+
+    #[allow(dead_code)]
+    pub fn next(&mut self, state: LR1NextState) {
+        if let Some((keep, stidx, ..)) = state.action {
+            let mut stack = self.stack[..keep].to_vec();
+            stack.extend(stidx);
+            self.stack = HashConsed::new(stack);
+        }
+        self.matching = HashConsed::new(state.matching);
+    }
+}
+
+impl MemoryUsage for LR1State {
+    // shared stacks and lexer remainders are split evenly among their states
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.stack.len() * size_of::<StIdx<u32>>() / self.stack.shared()
+            + self.matching.len() * size_of::<(usize, StateID)>() / self.matching.shared()
+    }
+}
 
 #[derive(Clone, Default)]
 pub struct LR1NextState {
@@ -873,6 +2369,136 @@ pub struct LR1NextState {
     matching: Matching,
 }
 
+impl MemoryUsage for ExactLR1GrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        self.shared_memory_usage() + self.vocabulary_memory_usage()
+    }
+}
+
+impl ExactLR1GrammarConstraint {
+    // whether a single continuation is valid, like get_valid_continuations
+    pub(crate) fn is_valid_continuation_index(
+        &self,
+        state: &LR1State,
+        continuation: usize,
+    ) -> bool {
+        let lexemes = self.stack_lexemes(state);
+        let next = self.completed_stack(state);
+        self.continuations
+            .get(continuation)
+            .is_some_and(|cont| self.is_valid_continuation(state, &lexemes, next.as_deref(), cont))
+    }
+
+    // the part of checking continuations that only depends on the parser
+    // stack of the state, e.g. to share it between states with the same stack
+    pub(crate) fn stack_lexemes(&self, state: &LR1State) -> StackLexemes {
+        StackLexemes::new(&self.grammar, &self.table, &self.pdfas, &state.stack)
+    }
+
+    // like get_valid_continuations, with the lexemes of the state's stack
+    pub(crate) fn get_valid_continuations_with(
+        &self,
+        state: &LR1State,
+        lexemes: &StackLexemes,
+        conts: &mut Vec<usize>,
+    ) {
+        conts.clear();
+        let next = self.completed_stack(state);
+
+        // now check all continuations
+        let mut i = 0;
+        while i < self.permutation.len() {
+            let skip = self.skips[i];
+            let j = self.permutation[i];
+            i += 1;
+            if self.is_valid_continuation(state, lexemes, next.as_deref(), &self.continuations[j]) {
+                conts.push(j);
+            } else {
+                i += skip;
+            }
+        }
+        conts.sort();
+    }
+
+    // like is_match_state, with the lexemes of the state's stack
+    pub(crate) fn is_match_state_with(&self, state: &LR1State, lexemes: &StackLexemes) -> bool {
+        lexemes.is_match(&self.pdfas, &state.matching)
+    }
+
+    // parser stack after the pending lexeme is completed, none if no
+    // lexer dfa is in a match state or the parser rejects the terminal
+    fn completed_stack(&self, state: &LR1State) -> Option<Vec<StIdx<u32>>> {
+        state.matching.iter().find_map(|(pidx, pdfa_state)| {
+            let (pdfa, tidx) = &self.pdfas[*pidx];
+            if !pdfa.is_eoi_match(*pdfa_state) {
+                return None;
+            }
+            let next_stack = if let Some(&tidx) = tidx.as_ref() {
+                match shift_reduce(&self.grammar, &self.table, &state.stack, tidx) {
+                    LR1Action::Stack(stack) => stack,
+                    LR1Action::ShiftReduce(keep, stidx) => {
+                        let mut next_stack = state.stack[..keep].to_vec();
+                        next_stack.push(stidx);
+                        next_stack
+                    }
+                    _ => return None,
+                }
+            } else {
+                state.stack.to_vec()
+            };
+            Some(next_stack)
+        })
+    }
+
+    // whether the continuation either extends the pending lexeme, or completes
+    // it and is a prefix of the next one; lexemes are the ones of the state's
+    // stack and next is the completed stack
+    fn is_valid_continuation(
+        &self,
+        state: &LR1State,
+        lexemes: &StackLexemes,
+        next: Option<&[StIdx<u32>]>,
+        cont: &[u8],
+    ) -> bool {
+        let (pdfa_matching, mut not_matching): (Vec<_>, Vec<_>) =
+            state.matching.iter().partition_map(|&(pidx, pdfa_state)| {
+                let (pdfa, _) = &self.pdfas[pidx];
+                if let Some(state) = pdfa.drive(pdfa_state, cont) {
+                    Either::Left((pidx, state))
+                } else {
+                    Either::Right(pidx)
+                }
+            });
+        let (matching_but_invalid, still_matching): (Vec<_>, Vec<_>) = pdfa_matching
+            .into_iter()
+            .map(|(pidx, _)| pidx)
+            .partition(|&pidx| !lexemes.valid[pidx]);
+        if !still_matching.is_empty() {
+            return true;
+        }
+        let Some(next_stack) = next else {
+            return false;
+        };
+        not_matching.extend(matching_but_invalid);
+        is_valid_matching(
+            self.pdfas
+                .iter()
+                .enumerate()
+                .filter_map(|(pidx, (pdfa, _))| {
+                    if not_matching.binary_search(&pidx).is_ok() {
+                        return None;
+                    }
+                    pdfa.drive(pdfa.get_start_state(), cont)
+                        .map(|state| (pidx, state))
+                }),
+            &self.grammar,
+            &self.table,
+            &self.pdfas,
+            next_stack,
+        )
+    }
+}
+
 impl Constraint for ExactLR1GrammarConstraint {
     type State = LR1State;
 
@@ -895,7 +2521,7 @@ impl Constraint for ExactLR1GrammarConstraint {
         ) {
             return None;
         }
-        Some(Self::State { stack, matching })
+        Some(Self::State::new(stack, matching))
     }
 
     fn get_start_state(&self) -> Self::State {
@@ -906,89 +2532,63 @@ impl Constraint for ExactLR1GrammarConstraint {
         is_match_state(&self.grammar, &self.table, &self.pdfas, state)
     }
 
-    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
-        let mut conts = vec![];
-
-        let next = state.matching.iter().find_map(|(pidx, pdfa_state)| {
-            let (pdfa, tidx) = &self.pdfas[*pidx];
-            if !pdfa.is_eoi_match(*pdfa_state) {
-                return None;
-            }
-            let next_stack = if let Some(&tidx) = tidx.as_ref() {
-                match shift_reduce(&self.grammar, &self.table, &state.stack, tidx) {
-                    LR1Action::Stack(stack) => stack,
-                    LR1Action::ShiftReduce(keep, stidx) => {
-                        let mut next_stack = state.stack[..keep].to_vec();
-                        next_stack.push(stidx);
-                        next_stack
-                    }
-                    _ => return None,
-                }
-            } else {
-                state.stack.clone()
-            };
-            Some(next_stack)
-        });
+    fn last_valid_truncation(&self, bytes: &[u8]) -> Option<usize> {
+        last_match_position(&self.grammar, &self.table, &self.pdfas, bytes)
+    }
 
-        // now check all continuations
-        let mut i = 0;
-        while i < self.permutation.len() {
-            let skip = self.skips[i];
-            let j = self.permutation[i];
-            let cont = &self.continuations[j];
-            i += 1;
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        // only an unchanged state, i.e. the same parser stack and the same lexer
+        // dfa states, e.g. inside a string terminal; different states are not
+        // compared by their continuations, even if they only differ in the lexer
+        state == next
+    }
 
-            let (pdfa_matching, mut not_matching): (Vec<_>, Vec<_>) =
-                state.matching.iter().partition_map(|&(pidx, pdfa_state)| {
-                    let (pdfa, _) = &self.pdfas[pidx];
-                    if let Some(state) = pdfa.drive(pdfa_state, cont) {
-                        Either::Left((pidx, state))
-                    } else {
-                        Either::Right(pidx)
-                    }
-                });
-            let (still_matching, matching_but_invalid) = partition_matching(
-                pdfa_matching.clone(),
-                &self.grammar,
-                &self.table,
+    fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+        // continuations complete at most the pending lexeme
+        let mask = self.lexer_masks.get(&state.matching, || {
+            lexer_continuations(
                 &self.pdfas,
-                &state.stack,
-            );
-            if !still_matching.is_empty() {
-                conts.push(j);
-                continue;
-            } else if let Some(next_stack) = &next {
-                not_matching.extend(matching_but_invalid);
-                if is_valid_matching(
-                    self.pdfas
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(pidx, (pdfa, _))| {
-                            if not_matching.binary_search(&pidx).is_ok() {
-                                return None;
-                            }
-                            pdfa.drive(pdfa.get_start_state(), cont)
-                                .map(|state| (pidx, state))
-                        }),
-                    &self.grammar,
-                    &self.table,
-                    &self.pdfas,
-                    next_stack,
-                ) {
-                    conts.push(j);
-                    continue;
-                }
-            }
-            i += skip;
-        }
-        conts.sort();
+                &self.continuations,
+                &self.permutation,
+                &self.skips,
+                &state.matching,
+                1,
+            )
+        });
+        mask.to_vec()
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mut conts = vec![];
+        self.get_valid_continuations_into(state, &mut conts);
         conts
     }
 
+    fn get_valid_continuations_into(&self, state: &Self::State, conts: &mut Vec<usize>) {
+        let lexemes = self.stack_lexemes(state);
+        self.get_valid_continuations_with(state, &lexemes, conts);
+    }
+
+    // same check as get_valid_continuations, which is stricter than
+    // get_next_state, e.g. for ignored input before the first lexeme
+    fn refine_continuations(&self, state: &Self::State, candidates: &[usize]) -> Vec<usize> {
+        let lexemes = self.stack_lexemes(state);
+        let next = self.completed_stack(state);
+        candidates
+            .iter()
+            .copied()
+            .filter(|&j| {
+                self.continuations.get(j).is_some_and(|cont| {
+                    self.is_valid_continuation(state, &lexemes, next.as_deref(), cont)
+                })
+            })
+            .collect()
+    }
+
     fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
         let cont = self.continuations.get(continuation)?;
         let (tokens, _, next_matching, _) =
-            prefix_lexer_with(cont, &self.pdfas, state.matching.clone()).ok()?;
+            prefix_lexer_with(cont, &self.pdfas, state.matching.to_vec()).ok()?;
         // should never happen in exact lr1 grammar constraint
         if tokens.len() > 1 {
             None
@@ -1002,9 +2602,10 @@ impl Constraint for ExactLR1GrammarConstraint {
             ) {
                 return None;
             }
+            // the parser stack is shared with the previous state
             Some(Self::State {
                 stack: state.stack.clone(),
-                matching: next_matching,
+                matching: HashConsed::new(next_matching),
             })
         } else {
             let next_stack =
@@ -1026,21 +2627,23 @@ impl Constraint for ExactLR1GrammarConstraint {
             ) {
                 return None;
             }
-            Some(Self::State {
-                stack: next_stack,
-                matching: next_matching,
-            })
+            Some(Self::State::new(next_stack, next_matching))
         }
     }
 }
 
 pub struct LR1GrammarConstraint {
-    grammar: YaccGrammar<u32>,
-    table: StateTable<u32>,
-    pdfas: Vec<(PrefixDFA, Option<TIdx<u32>>)>,
+    // grammar, table and lexer are shared between vocabularies, see with_continuations
+    grammar: Arc<YaccGrammar<u32>>,
+    table: Arc<StateTable<u32>>,
+    num_states: usize,
+    pdfas: Arc<Vec<(PrefixDFA, Option<TIdx<u32>>)>>,
     continuations: Vec<Vec<u8>>,
     permutation: Vec<usize>,
     skips: Vec<usize>,
+    token_names: Arc<TokenNames>,
+    special_tokens: Arc<Vec<SpecialToken>>,
+    lexer_masks: LexerMasks,
 }
 
 impl LR1GrammarConstraint {
@@ -1049,20 +2652,119 @@ impl LR1GrammarConstraint {
         tokens: &str,
         continuations: Vec<Vec<u8>>,
     ) -> Result<Self, Box<dyn Error>> {
-        let (grammar, pdfas) = load_grammar_and_pdfas(
+        Self::with_limits(grammar, tokens, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile(grammar, tokens, continuations, limits, |_| {})
+    }
+
+    // whitespace between tokens follows the policy instead of the
+    // ignore tokens of the lexer
+    pub fn with_whitespace(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        whitespace: WhitespacePolicy,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile_with_whitespace(
+            grammar,
+            tokens,
+            continuations,
+            whitespace,
+            &CompileLimits::default(),
+            |_| {},
+        )
+    }
+
+    pub fn compile(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+        progress: impl FnMut(CompileProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile_with_whitespace(
+            grammar,
+            tokens,
+            continuations,
+            WhitespacePolicy::Preserve,
+            limits,
+            progress,
+        )
+    }
+
+    pub fn compile_with_whitespace(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        whitespace: WhitespacePolicy,
+        limits: &CompileLimits,
+        mut progress: impl FnMut(CompileProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let (grammar, pdfas, _, token_names) = load_grammar_and_pdfas_with(
             grammar,
             YaccKind::Original(YaccOriginalActionKind::NoAction),
             tokens,
+            whitespace,
+            limits,
+            start,
+            &mut progress,
         )?;
-        let (_, table) = lrtable::from_yacc(&grammar, Minimiser::Pager)?;
+        let special_tokens = parse_lexer(tokens)?.special_tokens;
+        let continuations = resolve_special_tokens(&special_tokens, continuations)?;
+        let (grammar, table, num_states) = build_table(grammar, limits, start, &mut progress)?;
         let (permutation, skips) = optimized_prefix_order(&continuations);
         Ok(Self {
             continuations,
-            grammar,
-            pdfas,
-            table,
+            grammar: Arc::new(grammar),
+            pdfas: Arc::new(pdfas),
+            table: Arc::new(table),
+            num_states,
+            permutation,
+            skips,
+            token_names: Arc::new(token_names),
+            special_tokens: Arc::new(special_tokens),
+            lexer_masks: LexerMasks::default(),
+        })
+    }
+
+    // memory of the compiled grammar, table and lexer, which is shared with
+    // all constraints created by with_continuations, so it should be counted
+    // once for all of them
+    pub fn shared_memory_usage(&self) -> usize {
+        grammar_memory_usage(&self.grammar, self.num_states, &self.pdfas)
+    }
+
+    // memory used for the continuations of this constraint only
+    pub fn vocabulary_memory_usage(&self) -> usize {
+        continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+            + self.lexer_masks.memory_usage()
+    }
+
+    // the same grammar with other continuations, e.g. the vocabulary of another
+    // tokenizer; shares the compiled grammar, table and lexer instead of rebuilding them
+    pub fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        let continuations = resolve_special_tokens(&self.special_tokens, continuations)?;
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            continuations,
+            grammar: self.grammar.clone(),
+            pdfas: self.pdfas.clone(),
+            table: self.table.clone(),
+            num_states: self.num_states,
             permutation,
             skips,
+            token_names: self.token_names.clone(),
+            special_tokens: self.special_tokens.clone(),
+            lexer_masks: LexerMasks::default(),
         })
     }
 
@@ -1078,9 +2780,121 @@ impl LR1GrammarConstraint {
         Self::new(&grammar, &tokens, continuations)
     }
 
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // stable across runs, processes and thread counts, e.g. for caching artifacts
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = compiled_fingerprint(&self.grammar, &self.table, &self.pdfas);
+        self.continuations.hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn only_skippable_matching(&self, state: &LR1State) -> bool {
         only_skippable_matching(&state.matching, &self.pdfas)
     }
+
+    // the part of checking continuations that only depends on the parser
+    // stack of the state, e.g. to share it between states with the same stack
+    pub(crate) fn stack_lexemes(&self, state: &LR1State) -> StackLexemes {
+        StackLexemes::new(&self.grammar, &self.table, &self.pdfas, &state.stack)
+    }
+
+    // like get_valid_continuations, with the lexemes of the state's stack
+    pub(crate) fn get_valid_continuations_with(
+        &self,
+        state: &LR1State,
+        lexemes: &StackLexemes,
+        conts: &mut Vec<usize>,
+    ) {
+        conts.clear();
+
+        // now check all continuations
+        let mut i = 0;
+        while i < self.permutation.len() {
+            let skip = self.skips[i];
+            let j = self.permutation[i];
+            let cont = &self.continuations[j];
+            i += 1;
+
+            let Ok((tokens, _, next_matching, _)) =
+                prefix_lexer_with(cont, &self.pdfas, state.matching.to_vec())
+            else {
+                i += skip;
+                continue;
+            };
+            let valid = if tokens.iter().all(Option::is_none) {
+                // no terminal completed, so the stack is unchanged
+                lexemes.is_valid_matching(next_matching.iter().copied())
+            } else if let Drive::Stack(next_stack) =
+                drive(&self.grammar, &self.table, state.stack.to_vec(), &tokens)
+            {
+                is_valid_matching(
+                    next_matching.iter().copied(),
+                    &self.grammar,
+                    &self.table,
+                    &self.pdfas,
+                    &next_stack,
+                )
+            } else {
+                false
+            };
+            if !valid {
+                i += skip;
+                continue;
+            }
+
+            conts.push(j);
+        }
+        conts.sort();
+    }
+
+    // like is_match_state, with the lexemes of the state's stack
+    pub(crate) fn is_match_state_with(&self, state: &LR1State, lexemes: &StackLexemes) -> bool {
+        lexemes.is_match(&self.pdfas, &state.matching)
+    }
+
+    // display names of the terminals the parser accepts next, including
+    // the one currently being lexed; uses %token aliases if given
+    pub fn expected_terminals(&self, state: &LR1State) -> Vec<&str> {
+        expected_terminals(&self.grammar, &self.table, &self.token_names, &state.stack)
+    }
+
+    // why the continuation is valid or invalid in the state, none if there
+    // is no such continuation
+    pub fn explain(&self, state: &LR1State, continuation: usize) -> Option<Explanation> {
+        let bytes = self.continuations.get(continuation)?;
+        Some(explain(
+            &self.grammar,
+            &self.table,
+            &self.pdfas,
+            &self.token_names,
+            state,
+            continuation,
+            bytes,
+            None,
+        ))
+    }
+
+    // opening continuations grouped by the terminal they commit to, e.g. to
+    // bias generation towards one top-level branch of the grammar
+    pub fn openers(&self) -> BTreeMap<&str, Vec<usize>> {
+        openers(
+            self,
+            &self.grammar,
+            &self.table,
+            &self.pdfas,
+            &self.continuations,
+            &self.token_names,
+        )
+    }
+}
+
+impl MemoryUsage for LR1GrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        self.shared_memory_usage() + self.vocabulary_memory_usage()
+    }
 }
 
 impl Constraint for LR1GrammarConstraint {
@@ -1105,7 +2919,7 @@ impl Constraint for LR1GrammarConstraint {
         ) {
             return None;
         }
-        Some(Self::State { stack, matching })
+        Some(Self::State::new(stack, matching))
     }
 
     fn get_start_state(&self) -> Self::State {
@@ -1116,68 +2930,65 @@ impl Constraint for LR1GrammarConstraint {
         is_match_state(&self.grammar, &self.table, &self.pdfas, state)
     }
 
-    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
-        let mut conts = vec![];
-
-        // now check all continuations
-        let mut i = 0;
-        while i < self.permutation.len() {
-            let skip = self.skips[i];
-            let j = self.permutation[i];
-            let cont = &self.continuations[j];
-            i += 1;
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        // only an unchanged state, i.e. the same parser stack and the same lexer
+        // dfa states, e.g. inside a string terminal; different states are not
+        // compared by their continuations, even if they only differ in the lexer
+        state == next
+    }
 
-            let Ok((tokens, _, next_matching, _)) =
-                prefix_lexer_with(cont, &self.pdfas, state.matching.clone())
-            else {
-                i += skip;
-                continue;
-            };
-            let Drive::Stack(next_stack) =
-                drive(&self.grammar, &self.table, state.stack.clone(), &tokens)
-            else {
-                i += skip;
-                continue;
-            };
-            if !is_valid_matching(
-                next_matching.iter().copied(),
-                &self.grammar,
-                &self.table,
+    fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mask = self.lexer_masks.get(&state.matching, || {
+            lexer_continuations(
                 &self.pdfas,
-                &next_stack,
-            ) {
-                i += skip;
-                continue;
-            }
+                &self.continuations,
+                &self.permutation,
+                &self.skips,
+                &state.matching,
+                usize::MAX,
+            )
+        });
+        mask.to_vec()
+    }
 
-            conts.push(j);
-        }
-        conts.sort();
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mut conts = vec![];
+        self.get_valid_continuations_into(state, &mut conts);
         conts
     }
 
+    fn get_valid_continuations_into(&self, state: &Self::State, conts: &mut Vec<usize>) {
+        let lexemes = self.stack_lexemes(state);
+        self.get_valid_continuations_with(state, &lexemes, conts);
+    }
+
     fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
         let cont = &self.continuations.get(continuation)?;
-        let (tokens, _, next_matching, _) =
-            prefix_lexer_with(cont, &self.pdfas, state.matching.clone()).ok()?;
-        let Drive::Stack(next_stack) =
-            drive(&self.grammar, &self.table, state.stack.clone(), &tokens)
-        else {
-            return None;
-        };
-        if !is_valid_matching(
-            next_matching.iter().copied(),
-            &self.grammar,
-            &self.table,
-            &self.pdfas,
-            &next_stack,
-        ) {
-            return None;
-        }
-        Some(Self::State {
-            stack: next_stack,
-            matching: next_matching,
-        })
+        next_state_with_bytes(&self.grammar, &self.table, &self.pdfas, state, cont)
+    }
+
+    fn last_valid_truncation(&self, bytes: &[u8]) -> Option<usize> {
+        last_match_position(&self.grammar, &self.table, &self.pdfas, bytes)
+    }
+}
+
+impl ByteConstraint for ExactLR1GrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        next_state_with_bytes(&self.grammar, &self.table, &self.pdfas, state, bytes)
+    }
+}
+
+impl ByteConstraint for LR1GrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        next_state_with_bytes(&self.grammar, &self.table, &self.pdfas, state, bytes)
     }
 }
 
@@ -1186,7 +2997,16 @@ mod test {
     use itertools::Itertools;
 
     use super::*;
-    use std::{collections::HashMap, fs, path::PathBuf};
+    use crate::{state_fingerprint, BackgroundCompile, Classification, Normalization};
+    use rand::{rngs::StdRng, SeedableRng};
+    use rayon::{prelude::*, ThreadPoolBuilder};
+    use std::{
+        collections::{HashMap, HashSet},
+        fs,
+        path::PathBuf,
+        thread,
+        time::Duration,
+    };
 
     fn load_continuations() -> Vec<Vec<u8>> {
         let dir = env!("CARGO_MANIFEST_DIR");
@@ -1522,41 +3342,432 @@ mod test {
     }
 
     #[test]
-    fn test_lrk_parser() {
-        let (grammar, lexer, examples) = load_lrk_grammar("calc");
-        let lrk = LR1GrammarParser::from_files(grammar, lexer).unwrap();
-        for example in examples {
-            lrk.prefix_parse(example.as_bytes(), false, false).unwrap();
-        }
+    fn test_deterministic_compilation() {
+        let (grammar, lexer, examples) = load_lrk_grammar("json");
+        let grammar = fs::read_to_string(grammar).unwrap();
+        let lexer = fs::read_to_string(lexer).unwrap();
+        let conts = load_continuations()[..5000].to_vec();
+
+        // compile on several threads at once, each with differently seeded hash maps
+        let (fingerprints, constraints): (Vec<_>, Vec<_>) = thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let parser = LR1GrammarParser::new(&grammar, &lexer).unwrap();
+                        let exact = ExactLR1GrammarConstraint::new(&grammar, &lexer, conts.clone())
+                            .unwrap();
+                        let constraint =
+                            LR1GrammarConstraint::new(&grammar, &lexer, conts.clone()).unwrap();
+                        (
+                            (
+                                parser.fingerprint(),
+                                exact.fingerprint(),
+                                constraint.fingerprint(),
+                            ),
+                            constraint,
+                        )
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).unzip()
+        });
+        assert!(fingerprints.iter().all_equal());
+        let constraint = &constraints[0];
+        let other = LR1GrammarConstraint::new(&grammar, &lexer, conts[..100].to_vec()).unwrap();
+        assert_ne!(other.fingerprint(), constraint.fingerprint());
+
+        // batched valid continuations are identical for any number of threads
+        let prefixes: Vec<_> = examples
+            .iter()
+            .flat_map(|example| {
+                (0..example.len())
+                    .step_by(7)
+                    .filter(|&i| example.is_char_boundary(i))
+                    .map(|i| &example.as_bytes()[..i])
+            })
+            .collect();
+        let valid = |c: &LR1GrammarConstraint, prefix: &[u8]| {
+            c.get_state(prefix)
+                .map(|state| (c.get_valid_continuations(&state), c.is_match_state(&state)))
+        };
+        let expected: Vec<_> = prefixes.iter().map(|p| valid(constraint, p)).collect();
+        assert!(expected.iter().any(Option::is_some));
+        for threads in [1, 2, 8] {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let actual: Vec<_> = pool.install(|| {
+                prefixes
+                    .par_iter()
+                    .enumerate()
+                    .map(|(i, p)| valid(&constraints[i % constraints.len()], p))
+                    .collect()
+            });
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_lrk_check() {
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let exact = ExactLR1GrammarConstraint::from_files(&grammar, &lexer, vec![]).unwrap();
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, vec![]).unwrap();
+        for text in [
+            &b"{\"a\": [1, 2]}"[..],
+            b"{\"a\": [1, 2]",
+            b"{\"a\": [1, 2]}}",
+            b"{\"a\" 1}",
+        ] {
+            assert_eq!(exact.check_detailed(text), lrk.check_detailed(text));
+        }
+        assert!(lrk.check(b"{\"a\": [1, 2]}"));
+        let report = lrk.check_detailed(b"{\"a\": [1, 2]");
+        assert!(!report.is_match && report.is_prefix);
+        let report = lrk.check_detailed(b"{\"a\": [1, 2]}}");
+        assert!(!report.is_match && !report.is_prefix);
+        assert_eq!(report.valid_up_to, 13);
+        assert_eq!(lrk.check_detailed(b"{\"a\" 1}").valid_up_to, 5);
+
+        let conts: Vec<_> = ["{", "}", "\"a\"", ":", "1"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+        assert_eq!(lrk.classify(b"{\"a\": 1}"), Classification::Valid);
+        assert_eq!(lrk.classify(b"{\"a\": 1"), Classification::ValidPrefix);
+        assert_eq!(lrk.classify(b"{\"a\": 1}}"), Classification::Invalid);
+        // true could be completed, but not with the continuations
+        assert_eq!(lrk.classify(b"{\"a\": t"), Classification::Invalid);
+    }
+
+    #[test]
+    fn test_lrk_last_valid_truncation() {
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let exact = ExactLR1GrammarConstraint::from_files(&grammar, &lexer, vec![]).unwrap();
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, vec![]).unwrap();
+        for (text, expected) in [
+            (&b"{\"a\": 1}{\"b\""[..], Some(8)),
+            (b"{\"a\": [1, 2]}} and more", Some(13)),
+            (b"{\"a\": 12", None),
+            (b"1, 2", Some(1)),
+            (b"", None),
+        ] {
+            assert_eq!(lrk.last_valid_truncation(text), expected);
+            assert_eq!(exact.last_valid_truncation(text), expected);
+            // same as the quadratic default
+            let dynamic: &dyn crate::DynConstraint = &lrk;
+            assert_eq!(dynamic.last_valid_truncation(text), expected);
+        }
+    }
+
+    #[test]
+    fn test_lrk_parser_normalization() {
+        let grammar = r#"
+%start Query
+%%
+Query: 'SELECT' 'NAME' ;
+"#;
+        let lexer = r#"
+%%
+SELECT select
+NAME [a-zäöü]+
+; \s+
+"#;
+        let parser = LR1GrammarParser::new(grammar, lexer).unwrap();
+        assert!(parser.parse("SELECT Äpfel", false, false).is_err());
+
+        let parser = parser.with_normalization(Normalization {
+            nfc: true,
+            lowercase: true,
+        });
+        // decomposed A with diaeresis, 3 bytes that normalize to 2
+        let input = "SELECT  A\u{308}pfel";
+        let parse = parser.parse(input, false, false).unwrap();
+        let LR1Parse::NonTerminal(_, children) = &parse else {
+            panic!("expected non-terminal");
+        };
+        assert_eq!(
+            children[0],
+            LR1Parse::Terminal("SELECT", (0, 6), b"SELECT".to_vec())
+        );
+        assert_eq!(
+            children[1],
+            LR1Parse::Terminal("NAME", (8, 15), "A\u{308}pfel".as_bytes().to_vec())
+        );
+        assert_eq!(
+            parser.lex(input).unwrap(),
+            [
+                (Some("SELECT"), (0, 6)),
+                (None, (6, 8)),
+                (Some("NAME"), (8, 15))
+            ]
+        );
+
+        let (_, rest) = parser
+            .prefix_parse(b"SeLeCt \xc3\x84PFEL rest", false, false)
+            .unwrap();
+        assert_eq!(rest, b" rest");
+        let err = parser.parse("SELECT SELECT", false, false).unwrap_err();
+        assert!(err.to_string().contains("position 7"));
+    }
+
+    #[test]
+    fn test_lrk_parser() {
+        let (grammar, lexer, examples) = load_lrk_grammar("calc");
+        let lrk = LR1GrammarParser::from_files(grammar, lexer).unwrap();
+        for example in examples {
+            lrk.prefix_parse(example.as_bytes(), false, false).unwrap();
+        }
+
+        let (grammar, lexer, examples) = load_lrk_grammar("sparql");
+        let lrk = LR1GrammarParser::from_files(grammar, lexer).unwrap();
+        for example in examples {
+            lrk.prefix_parse(example.as_bytes(), false, false).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_snapshots() {
+        for name in ["calc", "json", "sparql"] {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("grammars")
+                .join(name);
+            let parser = LR1GrammarParser::from_files(
+                dir.join(format!("{name}.y")),
+                dir.join(format!("{name}.l")),
+            )
+            .unwrap();
+            let mut examples: Vec<_> = fs::read_dir(dir.join("examples"))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            examples.sort();
+            for example in examples {
+                // examples may be prefixes only, some even end within a token,
+                // so the snapshot also covers the unparsed rest and errors
+                let text = fs::read(&example).unwrap();
+                let snapshot = match parser.prefix_parse(&text, false, false) {
+                    Ok((parse, rest)) => format!(
+                        "{}rest: \"{}\"",
+                        parse.to_canonical_string(),
+                        rest.escape_ascii()
+                    ),
+                    Err(e) => format!("error: {e}"),
+                };
+                let stem = example.file_stem().unwrap().to_string_lossy();
+                insta::assert_snapshot!(format!("{name}_{stem}"), snapshot);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_partial_match() {
+        let (grammar, lexer, _) = load_lrk_grammar("sparql");
+        let lrk = LR1GrammarParser::from_files(grammar, lexer).unwrap();
+        // valid SPARQL should parse fine
+        assert!(lrk
+            .parse("SELECT ?x WHERE { ?x ?y ?z }", false, false)
+            .is_ok());
+        // trailing content that is prefix of a string token should fail
+        let err = lrk
+            .parse("SELECT ?x WHERE { ?x ?y ?z } 'unclosed", false, false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("trailing content from position 29: 'unclosed"),
+            "unexpected error message: {err}"
+        );
+        // prefix_parse should still accept partial input
+        assert!(lrk
+            .prefix_parse(b"SELECT ?x WHERE { ?x ?y ?z } 'unclosed", false, false)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_grammar_literals_only() {
+        let grammar = r"
+%start Query
+%%
+Query: 'select' '(' Columns ')' | 'select' '(' Columns ')' 'where' Cond ;
+Columns: 'x' | 'y' | Columns ',' 'x' | Columns ',' 'y' ;
+Cond: 'x' Cmp 'y' ;
+Cmp: '<' | '<=' | '=' ;
+";
+        // no lexer needed, all tokens are literals
+        let lrk = LR1GrammarParser::new(grammar, "").unwrap();
+        let parse = lrk.parse("select(x,y)wherex<=y", true, true).unwrap();
+        assert_eq!(parse.name(), "Query");
+        let tokens = lrk.lex("x<=y").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (Some("x"), (0, 1)),
+                (Some("<="), (1, 3)),
+                (Some("y"), (3, 4))
+            ]
+        );
+        assert!(lrk.parse("select(x)where", false, false).is_err());
+
+        let conts: Vec<_> = ["select", "(", "x", ")", "<", "<=", "where"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let constraint = LR1GrammarConstraint::new(grammar, "\n", conts).unwrap();
+        let state = constraint.get_state(b"select(x)wherex").unwrap();
+        assert_eq!(constraint.get_valid_continuations(&state), vec![4, 5]);
+        let state = constraint.get_state(b"select(x)wherex<").unwrap();
+        assert!(!constraint.is_match_state(&state));
+
+        // a non-empty lexer still needs the %% separator
+        assert!(LR1GrammarParser::new(grammar, "X x").is_err());
+    }
+
+    #[test]
+    fn test_lex_with_recovery() {
+        let grammar = "%start Words\n%%\nWords: 'WORD' | Words 'WORD' ;";
+        let lexer = "%%\nWORD [a-zé]+\n; \\s+\n; \"<!--\" ([^-]|-[^-])* \"-->\"";
+        let lrk = LR1GrammarParser::new(grammar, lexer).unwrap();
+        let (tokens, errors) = lrk.lex_with_recovery("ab 1é2 c");
+        assert_eq!(
+            tokens,
+            vec![
+                (Some("WORD"), (0, 2)),
+                (None, (2, 3)),
+                (Some("ERROR"), (3, 4)),
+                (Some("WORD"), (4, 6)),
+                (Some("ERROR"), (6, 7)),
+                (None, (7, 8)),
+                (Some("WORD"), (8, 9)),
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec![
+                LexError {
+                    kind: LexErrorKind::NoMatch,
+                    span: (3, 4)
+                },
+                LexError {
+                    kind: LexErrorKind::NoMatch,
+                    span: (6, 7)
+                }
+            ]
+        );
+        // consecutive bad characters form a single error,
+        // multi-byte characters are not split
+        let (tokens, errors) = lrk.lex_with_recovery("a€€!b");
+        assert_eq!(
+            tokens,
+            vec![
+                (Some("WORD"), (0, 1)),
+                (Some("ERROR"), (1, 8)),
+                (Some("WORD"), (8, 9))
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        // input ending inside a token
+        let (tokens, errors) = lrk.lex_with_recovery("a <!-- b");
+        assert_eq!(tokens.last(), Some(&(Some("ERROR"), (2, 8))));
+        assert_eq!(
+            errors,
+            vec![LexError {
+                kind: LexErrorKind::Incomplete,
+                span: (2, 8)
+            }]
+        );
+        assert_eq!(lrk.lex_with_recovery(""), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_token_aliases() {
+        let grammar = r#"
+%token NAME "identifier" NUMBER
+%start Call
+%%
+Call: 'NAME' '(' Args ')' ;
+Args: Arg | Args ',' Arg ;
+Arg: 'NAME' | 'NUMBER' ;
+"#;
+        let lexer = "%%\nNAME [a-z]+\nNUMBER [0-9]+\n; \\s+";
+        let lrk = LR1GrammarParser::new(grammar, lexer).unwrap();
+        assert!(lrk.parse("f(a, 1)", false, false).is_ok());
+        let err = lrk.parse("f(a b)", false, false).unwrap_err().to_string();
+        assert!(
+            err.contains("position 4: unexpected identifier with content 'b', expected ')' or ','"),
+            "unexpected error message: {err}"
+        );
+        let err = lrk.parse("f(", false, false).unwrap_err().to_string();
+        assert!(
+            err.contains("unexpected end of input with content '', expected identifier or NUMBER"),
+            "unexpected error message: {err}"
+        );
 
-        let (grammar, lexer, examples) = load_lrk_grammar("sparql");
-        let lrk = LR1GrammarParser::from_files(grammar, lexer).unwrap();
-        for example in examples {
-            lrk.prefix_parse(example.as_bytes(), false, false).unwrap();
-        }
+        let constraint = LR1GrammarConstraint::new(grammar, lexer, vec![]).unwrap();
+        let state = constraint.get_state(b"f(a ").unwrap();
+        assert_eq!(constraint.expected_terminals(&state), vec!["')'", "','"]);
+        let state = constraint.get_state(b"f(a) ").unwrap();
+        assert_eq!(constraint.expected_terminals(&state), vec!["end of input"]);
+
+        // aliases are removed before parsing the grammar
+        let (source, aliases) = extract_token_aliases(grammar).unwrap();
+        assert_eq!(source.len(), grammar.len());
+        assert!(!source.contains("identifier"));
+        assert_eq!(
+            aliases,
+            HashMap::from([("NAME".to_string(), "identifier".to_string())])
+        );
     }
 
     #[test]
-    fn test_parse_rejects_partial_match() {
-        let (grammar, lexer, _) = load_lrk_grammar("sparql");
-        let lrk = LR1GrammarParser::from_files(grammar, lexer).unwrap();
-        // valid SPARQL should parse fine
-        assert!(lrk
-            .parse("SELECT ?x WHERE { ?x ?y ?z }", false, false)
-            .is_ok());
-        // trailing content that is prefix of a string token should fail
-        let err = lrk
-            .parse("SELECT ?x WHERE { ?x ?y ?z } 'unclosed", false, false)
-            .unwrap_err()
-            .to_string();
-        assert!(
-            err.contains("trailing content from position 29: 'unclosed"),
-            "unexpected error message: {err}"
+    fn test_byte_mode() {
+        // binary blocks with a magic header and a terminator
+        let grammar =
+            "%start Blocks\n%%\nBlocks: Blocks Block | Block;\nBlock: 'MAGIC' 'DATA' 'END';";
+        let lexer = r"%bytes
+%%
+MAGIC \xCA\xFE
+DATA [\x80-\xFF]{2}
+END \x00\x00";
+        let parser = LR1GrammarParser::new(grammar, lexer).unwrap();
+        assert!(parser.byte_mode());
+        let input = b"\xCA\xFE\xFF\x80\x00\x00\xCA\xFE\x81\x82\x00\x00";
+        let tokens = parser.lex(input).unwrap();
+        assert_eq!(tokens.len(), 6);
+        assert_eq!(tokens[1], (Some("DATA"), (2, 4)));
+        let tree = parser.parse(input, true, true).unwrap();
+        assert_eq!(tree.name(), "Blocks");
+        assert!(parser.parse(&input[..11], false, false).is_err());
+
+        let continuations = (0..=255).map(|b| vec![b]).collect();
+        let constraint = LR1GrammarConstraint::new(grammar, lexer, continuations).unwrap();
+        let state = constraint.get_state(&input[..3]).unwrap();
+        assert_eq!(
+            constraint.get_valid_continuations(&state),
+            (0x80..=0xFF).collect::<Vec<_>>()
         );
-        // prefix_parse should still accept partial input
-        assert!(lrk
-            .prefix_parse(b"SELECT ?x WHERE { ?x ?y ?z } 'unclosed", false, false)
-            .is_ok());
+        let state = constraint.get_state(&input[..6]).unwrap();
+        assert!(constraint.is_match_state(&state));
+        assert_eq!(constraint.get_valid_continuations(&state), vec![0xCA]);
+
+        // without %bytes, \xFF is the unicode character U+00FF
+        let parser = LR1GrammarParser::new(grammar, &lexer.replace("%bytes\n", "")).unwrap();
+        assert!(!parser.byte_mode());
+        assert!(parser.parse(input, false, false).is_err());
+    }
+
+    #[test]
+    fn test_longest() {
+        let grammar = "%start S\n%%\nS: 'NUM' 'END';";
+        let lexer = "%longest\n%%\nNUM [0-9]|[1-9][0-9]+\nEND (|;)!";
+        let parser = LR1GrammarParser::new(grammar, lexer).unwrap();
+        assert!(parser.parse(b"12;!", false, false).is_ok());
+        assert!(parser.parse(b"1!", false, false).is_ok());
+
+        // by default the first alternative wins
+        let parser = LR1GrammarParser::new(grammar, &lexer.replace("%longest\n", "")).unwrap();
+        assert!(parser.parse(b"12;!", false, false).is_err());
     }
 
     fn drive_with_tokens(
@@ -1663,6 +3874,221 @@ mod test {
             );
         }
     }
+    #[test]
+    fn test_compile_limits() {
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let grammar = fs::read_to_string(grammar).unwrap();
+        let lexer = fs::read_to_string(lexer).unwrap();
+        let compile = |limits: CompileLimits| {
+            LR1GrammarConstraint::with_limits(&grammar, &lexer, vec![], &limits)
+                .err()
+                .and_then(|e| e.downcast_ref::<CompileLimitError>().cloned())
+        };
+        assert_eq!(compile(CompileLimits::default()), None);
+        assert!(matches!(
+            compile(CompileLimits {
+                max_rules: Some(3),
+                ..Default::default()
+            }),
+            Some(CompileLimitError::TooManyRules { limit: 3, .. })
+        ));
+        // stops counting right after the limit, before the table is built
+        assert_eq!(
+            compile(CompileLimits {
+                max_states: Some(5),
+                ..Default::default()
+            }),
+            Some(CompileLimitError::TooManyStates {
+                states: 6,
+                limit: 5
+            })
+        );
+        // the LR(0) states are a lower bound on the states of the table
+        let yacc = YaccGrammar::new(
+            YaccKind::Original(YaccOriginalActionKind::NoAction),
+            &grammar,
+        )
+        .unwrap();
+        let lr0 = count_lr0_states(
+            &yacc,
+            &CompileLimits::default(),
+            Instant::now(),
+            &mut |_| {},
+        )
+        .unwrap();
+        let (_, _, states) =
+            build_table(yacc, &CompileLimits::default(), Instant::now(), &mut |_| {}).unwrap();
+        assert!(lr0 > 5 && lr0 <= states);
+        assert!(matches!(
+            compile(CompileLimits {
+                max_lexer_dfa_states: Some(5),
+                ..Default::default()
+            }),
+            Some(CompileLimitError::LexerDFATooLarge { token, .. }) if token == "STRING"
+        ));
+        assert!(matches!(
+            compile(CompileLimits {
+                max_compile_time: Some(Duration::ZERO),
+                ..Default::default()
+            }),
+            Some(CompileLimitError::Timeout { .. })
+        ));
+        assert_eq!(
+            compile(CompileLimits {
+                max_rules: Some(100),
+                max_states: Some(1000),
+                max_lexer_dfa_states: Some(1000),
+                max_lexer_repetition: Some(8),
+                max_compile_time: Some(Duration::from_secs(60)),
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_table_workers() {
+        let workers = TableWorkers::new();
+        let first = workers.acquire(1, Instant::now()).unwrap();
+        // the deadline passes while the only worker is running
+        assert!(workers
+            .acquire(1, Instant::now() + Duration::from_millis(20))
+            .is_none());
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                drop(first);
+            });
+            assert!(workers
+                .acquire(1, Instant::now() + Duration::from_secs(10))
+                .is_some());
+        });
+        assert_eq!(*workers.running.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_lexer_repetition_limits() {
+        let compile = |lexer: &str| {
+            LR1GrammarConstraint::with_limits(
+                "%start S\n%%\nS: 'A';",
+                &format!("%%\nA {lexer}"),
+                vec![],
+                &CompileLimits {
+                    max_lexer_repetition: Some(8),
+                    ..Default::default()
+                },
+            )
+            .err()
+            .map(|e| e.downcast::<CompileLimitError>().map(|e| *e).unwrap())
+        };
+        let err = compile("[ab]*a[ab]{30}").unwrap();
+        assert!(matches!(
+            &err,
+            CompileLimitError::RiskyRepetition { token, count: 30, limit: 8, .. } if token == "A"
+        ));
+        assert!(err
+            .to_string()
+            .contains("repeats [ab] 30 times after [ab]*"));
+        assert!(matches!(
+            compile(".*x.{9,}"),
+            Some(CompileLimitError::RiskyRepetition { count: 9, .. })
+        ));
+        assert!(matches!(
+            compile("(a+b?)+"),
+            Some(CompileLimitError::NestedRepetition { token, .. }) if token == "A"
+        ));
+        assert!(matches!(
+            compile("(x[a-z]*)*"),
+            Some(CompileLimitError::NestedRepetition { .. })
+        ));
+        for lexer in [
+            "[ab]*a[ab]{8}",
+            "[ab]*c[ab]{30}",
+            "\"[^\"]*\"[a-z]{30}",
+            "[a-z]*[0-9]{30}",
+            "([a-z]+\\x20)*[a-z]+",
+            "(a+b)+",
+        ] {
+            assert_eq!(compile(lexer), None, "{lexer}");
+        }
+        // no false positives for the bundled grammars
+        for name in ["calc", "json", "sparql"] {
+            let (grammar, lexer, _) = load_lrk_grammar(name);
+            let grammar = fs::read_to_string(grammar).unwrap();
+            let lexer = fs::read_to_string(lexer).unwrap();
+            let limits = CompileLimits {
+                max_lexer_repetition: Some(8),
+                ..Default::default()
+            };
+            assert!(LR1GrammarParser::with_limits(&grammar, &lexer, &limits).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_compile_progress() {
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let grammar = fs::read_to_string(grammar).unwrap();
+        let lexer = fs::read_to_string(lexer).unwrap();
+        let mut reports = vec![];
+        LR1GrammarConstraint::compile(&grammar, &lexer, vec![], &CompileLimits::default(), |p| {
+            reports.push(p)
+        })
+        .unwrap();
+        assert_eq!(reports[0].phase, CompilePhase::Grammar);
+        let lexer_reports: Vec<_> = reports
+            .iter()
+            .filter(|p| p.phase == CompilePhase::Lexer)
+            .collect();
+        let total = lexer_reports[0].total.unwrap();
+        assert_eq!(lexer_reports.len(), total + 1);
+        assert!(lexer_reports.iter().enumerate().all(|(i, p)| p.done == i));
+        // the LR(0) states while counting them, then all states of the table
+        let table_reports: Vec<_> = reports
+            .iter()
+            .filter(|p| p.phase == CompilePhase::Table)
+            .collect();
+        assert!(table_reports.len() > 2);
+        assert!(table_reports.windows(2).all(|w| w[0].done <= w[1].done));
+        let last = reports.last().unwrap();
+        assert_eq!(last.phase, CompilePhase::Table);
+        assert_eq!(last.total, Some(last.done));
+        assert!(last.done > 0);
+        // only a background compile reports done, once the constraint exists
+        assert!(reports.iter().all(|p| p.phase != CompilePhase::Done));
+
+        let background = BackgroundCompile::spawn(move |progress| {
+            LR1GrammarConstraint::compile(
+                &grammar,
+                &lexer,
+                vec![],
+                &CompileLimits::default(),
+                progress,
+            )
+        });
+        while !background.is_ready() {
+            std::thread::yield_now();
+        }
+        assert_eq!(
+            background.progress(),
+            CompileProgress::new(CompilePhase::Done, last.done, last.total)
+        );
+        assert!(background.join().is_ok());
+
+        let background = BackgroundCompile::spawn(|progress| {
+            LR1GrammarConstraint::compile(
+                "%start S\n%%\nS: 'a';",
+                "%%\n",
+                vec![],
+                &CompileLimits {
+                    max_rules: Some(1),
+                    ..Default::default()
+                },
+                progress,
+            )
+        });
+        let err = background.join().err().unwrap();
+        assert!(err.downcast_ref::<CompileLimitError>().is_some());
+    }
+
     #[test]
     fn test_lrk_constraint() {
         let conts = load_continuations();
@@ -1725,4 +4151,541 @@ mod test {
                 .collect_vec()
         );
     }
+
+    #[test]
+    fn test_lrk_constraint_concurrent() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+        assert_send_sync(&lrk);
+        assert_send_sync(&exact);
+
+        let inputs: [&[u8]; 3] = [
+            b"{\"id\": [1, 2.5]}",
+            b"[{\"a\": null}]",
+            b"{\"b\": {\"c\": \"x\"}}",
+        ];
+        // shared constraints give the same results for many concurrent
+        // callers as for a single one
+        fn walk<C: Constraint>(constraint: &C, input: &[u8]) -> Vec<(Vec<usize>, bool)> {
+            (0..=input.len())
+                .step_by(3)
+                .map(|i| {
+                    let state = constraint.get_state(&input[..i]).unwrap();
+                    (
+                        constraint.get_valid_continuations(&state),
+                        constraint.is_match_state(&state),
+                    )
+                })
+                .collect()
+        }
+        let expected: Vec<_> = inputs
+            .iter()
+            .map(|input| (walk(&lrk, input), walk(&exact, input)))
+            .collect();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let (lrk, exact, inputs, expected) = (&lrk, &exact, &inputs, &expected);
+                s.spawn(move || {
+                    for i in 0..inputs.len() {
+                        let j = (i + t) % inputs.len();
+                        assert_eq!(walk(lrk, inputs[j]), expected[j].0);
+                        assert_eq!(walk(exact, inputs[j]), expected[j].1);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_openers() {
+        let conts = load_continuations();
+        let index = |s: &str| conts.iter().position(|c| c == s.as_bytes()).unwrap();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts.clone()).unwrap();
+        let openers = lrk.openers();
+        assert_eq!(
+            openers.keys().copied().collect_vec(),
+            ["'['", "'false'", "'null'", "'true'", "'{'", "NUMBER", "STRING"]
+        );
+        for (name, cont) in [
+            ("'{'", "{"),
+            ("'['", "["),
+            ("'true'", "t"),
+            ("STRING", "\""),
+            ("NUMBER", "1"),
+        ] {
+            assert!(openers[name].contains(&index(cont)), "{name} {cont}");
+        }
+        // the exact constraint also checks the rest of a continuation, so
+        // its groups are subsets
+        for (name, indices) in exact.openers() {
+            assert!(indices.iter().all(|i| openers[name].contains(i)));
+        }
+        assert!(!exact.openers()["'{'"].contains(&index("{{")));
+        // whitespace does not commit to a terminal
+        assert!(openers
+            .values()
+            .all(|indices| !indices.contains(&index(" "))));
+        // groups are disjoint and only contain valid continuations
+        let start = lrk.get_start_state();
+        let valid = lrk.get_valid_continuations(&start);
+        let mut all = openers.values().flatten().collect_vec();
+        let len = all.len();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), len);
+        assert!(all.iter().all(|i| valid.contains(i)));
+        for indices in openers.values() {
+            assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let conts: Vec<_> = ["{", "]}", "\"a", " ", "@", "1}", "1,", "{}}", "]"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(&grammar, &lexer, conts).unwrap();
+        let name = |name: &str| Some(name.to_string());
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect_vec();
+
+        // the opening brace is still pending, so the continuation ends it
+        let state = lrk.get_state(b"{").unwrap();
+        let explanation = lrk.explain(&state, 2).unwrap();
+        assert!(explanation.is_valid());
+        assert!(explanation.continues_lexeme);
+        assert_eq!(explanation.completed, [(name("'{'"), (0, 0))]);
+        assert_eq!(explanation.pending, ["STRING"]);
+        // a closing bracket is lexed fine, but the parser expects a key
+        assert_eq!(
+            lrk.explain(&state, 1).unwrap().rejection,
+            Some(Rejection::UnexpectedTerminal {
+                terminal: "']'".to_string(),
+                expected: names(&["'}'", "STRING"])
+            })
+        );
+        let explanation = lrk.explain(&state, 8).unwrap();
+        assert_eq!(explanation.pending, ["']'"]);
+        assert_eq!(
+            explanation.rejection,
+            Some(Rejection::UnexpectedLexeme {
+                candidates: names(&["']'"]),
+                expected: names(&["'}'", "STRING"])
+            })
+        );
+        assert_eq!(
+            lrk.explain(&state, 4).unwrap().rejection,
+            Some(Rejection::NoLexerMatch { offset: 0 })
+        );
+        assert!(lrk.explain(&state, 9).is_none());
+
+        // a pending number is completed by the closing brace
+        let state = lrk.get_state(b"{\"a\": 1").unwrap();
+        let explanation = lrk.explain(&state, 5).unwrap();
+        assert!(explanation.is_valid());
+        assert!(explanation.continues_lexeme);
+        assert_eq!(explanation.completed, [(name("NUMBER"), (0, 1))]);
+        assert_eq!(explanation.pending, ["'}'"]);
+        // exact constraints complete at most one token per continuation
+        let state = exact.get_state(b"{\"a\": 1").unwrap();
+        assert!(exact.explain(&state, 5).unwrap().is_valid());
+        let state = exact.get_state(b"[").unwrap();
+        let explanation = exact.explain(&state, 7).unwrap();
+        assert_eq!(
+            explanation.completed,
+            [
+                (name("'['"), (0, 0)),
+                (name("'{'"), (0, 1)),
+                (name("'}'"), (1, 2))
+            ]
+        );
+        assert_eq!(
+            explanation.rejection,
+            Some(Rejection::TooManyTokens { tokens: 3 })
+        );
+        assert_eq!(
+            explanation.rejection.unwrap().to_string(),
+            "completes 3 tokens, but exact constraints allow at most one"
+        );
+
+        // explanations agree with the constraints
+        let conts = load_continuations();
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts.clone()).unwrap();
+        for prefix in [&b""[..], b"{\"a", b"[1, tr", b"{\"a\": [1] "] {
+            let state = lrk.get_state(prefix).unwrap();
+            let exact_state = exact.get_state(prefix).unwrap();
+            for i in 0..conts.len() {
+                assert_eq!(
+                    lrk.explain(&state, i).unwrap().is_valid(),
+                    lrk.get_next_state(&state, i).is_some()
+                );
+                assert_eq!(
+                    exact.explain(&exact_state, i).unwrap().is_valid(),
+                    exact.get_next_state(&exact_state, i).is_some()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_continuations() {
+        let conts = load_continuations();
+        let c = conts.iter().position(|c| c == b"c").unwrap();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+
+        // inside a string the lexer dfa loops, so the state does not change
+        let state = lrk.get_state(b"{\"ab").unwrap();
+        let valid = lrk.get_valid_continuations(&state);
+        let next = lrk.get_next_state(&state, c).unwrap();
+        assert!(lrk.has_same_continuations(&state, &next));
+        assert_eq!(
+            lrk.get_next_valid_continuations(&state, &valid, &next),
+            valid
+        );
+
+        let state = exact.get_state(b"{\"ab").unwrap();
+        let next = exact.get_next_state(&state, c).unwrap();
+        assert!(exact.has_same_continuations(&state, &next));
+
+        // a new token starts after the opening brace
+        let state = lrk.get_state(b"{").unwrap();
+        let valid = lrk.get_valid_continuations(&state);
+        let next = lrk.get_state(b"{\"").unwrap();
+        assert!(!lrk.has_same_continuations(&state, &next));
+        assert_eq!(
+            lrk.get_next_valid_continuations(&state, &valid, &next),
+            lrk.get_valid_continuations(&next)
+        );
+    }
+
+    #[test]
+    fn test_approximate_continuations() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+
+        fn check(constraint: &impl Constraint<State = LR1State>, prefix: &[u8]) -> Vec<usize> {
+            let state = constraint.get_state(prefix).unwrap();
+            let valid = constraint.get_valid_continuations(&state);
+            let approximate = constraint.get_approximate_continuations(&state);
+            assert!(approximate.len() >= valid.len());
+            // refining the superset gives back exactly the valid continuations
+            let refined = constraint.refine_continuations(&state, &approximate);
+            assert_eq!(
+                refined,
+                valid,
+                "prefix {:?}",
+                String::from_utf8_lossy(prefix)
+            );
+            approximate
+        }
+        for prefix in [
+            &b""[..],
+            b"{",
+            b"{\"ab",
+            b"{\"ab\"",
+            b"{\"ab\": ",
+            b"{\"ab\": 1",
+            b"{\"ab\": [tr",
+            b"[1, 2.5e",
+        ] {
+            let lrk_approximate = check(&lrk, prefix);
+            let exact_approximate = check(&exact, prefix);
+            assert!(exact_approximate.len() <= lrk_approximate.len());
+        }
+
+        // the lexer alone does not know that a value has to follow
+        let state = exact.get_state(b"{\"ab\": ").unwrap();
+        let approximate = exact.get_approximate_continuations(&state);
+        assert!(approximate.len() > exact.get_valid_continuations(&state).len());
+        // inside a string the superset is served from the cache for every
+        // parser stack
+        let state = exact.get_state(b"[\"ab").unwrap();
+        let other = exact.get_state(b"{\"cd").unwrap();
+        assert_eq!(
+            exact.get_approximate_continuations(&state),
+            exact.get_approximate_continuations(&other)
+        );
+        let candidates = [usize::MAX, approximate[0]];
+        assert!(exact
+            .refine_continuations(&state, &candidates)
+            .iter()
+            .all(|&c| c == approximate[0]));
+    }
+
+    #[test]
+    fn test_valid_continuations_into() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+
+        // the buffer is reused across calls and cleared before each one
+        let mut buffer = vec![usize::MAX; 3];
+        for prefix in [&b""[..], b"{\"ab\": ", b"[1, 2.5e", b"{\"ab"] {
+            let state = lrk.get_state(prefix).unwrap();
+            lrk.get_valid_continuations_into(&state, &mut buffer);
+            assert_eq!(buffer, lrk.get_valid_continuations(&state));
+            let state = exact.get_state(prefix).unwrap();
+            exact.get_valid_continuations_into(&state, &mut buffer);
+            assert_eq!(buffer, exact.get_valid_continuations(&state));
+        }
+    }
+
+    #[test]
+    fn test_stack_lexemes() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+
+        // the lexemes of one state serve all states with the same stack,
+        // no matter their pending lexeme
+        for (base, prefixes) in [
+            (
+                &b""[..],
+                &[&b"1"[..], b"12.5e", b"\"ab", b"tr", b"true", b"  "][..],
+            ),
+            (
+                b"[1, ",
+                &[b"[1, 2", b"[1, 2.5", b"[1, \"a", b"[1, nul", b"[1,  \n"],
+            ),
+        ] {
+            let state = lrk.get_state(base).unwrap();
+            let lexemes = lrk.stack_lexemes(&state);
+            let mut matches = 0;
+            let mut conts = vec![];
+            for prefix in prefixes {
+                let next = lrk.get_state(prefix).unwrap();
+                assert!(next.same_stack(&state));
+                lrk.get_valid_continuations_with(&next, &lexemes, &mut conts);
+                assert_eq!(conts, lrk.get_valid_continuations(&next));
+                let is_match = lrk.is_match_state_with(&next, &lexemes);
+                assert_eq!(is_match, lrk.is_match_state(&next));
+                matches += is_match as usize;
+            }
+            assert_eq!(matches > 0, base.is_empty());
+
+            let state = exact.get_state(base).unwrap();
+            let lexemes = exact.stack_lexemes(&state);
+            for prefix in prefixes {
+                let next = exact.get_state(prefix).unwrap();
+                exact.get_valid_continuations_with(&next, &lexemes, &mut conts);
+                assert_eq!(conts, exact.get_valid_continuations(&next));
+                assert_eq!(
+                    exact.is_match_state_with(&next, &lexemes),
+                    exact.is_match_state(&next)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_minimal_completion() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(&grammar, &lexer, conts.clone()).unwrap();
+        let exact = ExactLR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+        for (prefix, completion) in [
+            (&br#"{"a": [1, {"b": "x"#[..], &br#""}]}"#[..]),
+            (b"[tr", b"ue]"),
+            (b"[1, 2.5e", b"0]"),
+            (b"{\"ab\": ", b"0}"),
+            (b"0", b""),
+        ] {
+            let state = lrk.get_state(prefix).unwrap();
+            assert_eq!(lrk.minimal_completion(&state, 16).unwrap(), completion);
+            let state = exact.get_state(prefix).unwrap();
+            assert_eq!(exact.minimal_completion(&state, 16).unwrap(), completion);
+            let completed = [prefix, completion].concat();
+            assert!(lrk.check(&completed));
+        }
+        // the search is bounded
+        let state = lrk.get_state(b"[[[[").unwrap();
+        assert!(lrk.minimal_completion(&state, 3).is_none());
+        assert_eq!(lrk.minimal_completion(&state, 4).unwrap(), b"]]]]");
+    }
+
+    #[test]
+    fn test_sample() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+        let mut rng = StdRng::seed_from_u64(22);
+        let mut samples = HashSet::new();
+        for _ in 0..20 {
+            let sample = lrk.sample(&mut rng, 12).unwrap();
+            assert!(sample.len() <= 12);
+            assert!(lrk.check(&sample), "{}", String::from_utf8_lossy(&sample));
+            samples.insert(sample);
+        }
+        assert!(samples.len() > 10);
+    }
+
+    #[test]
+    fn test_enumerate_completions() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+        let state = lrk.get_state(b"[1, tr").unwrap();
+        assert_eq!(lrk.enumerate_completions(&state, 1, 8), [b"ue]".to_vec()]);
+        let state = lrk.get_state(b"{\"a\": ").unwrap();
+        let completions = lrk.enumerate_completions(&state, 20, 8);
+        assert_eq!(completions.len(), 20);
+        assert_eq!(completions[0], b"0}");
+        for completion in &completions {
+            assert!(lrk.check(&[&b"{\"a\": "[..], completion].concat()));
+        }
+        assert_eq!(completions.iter().collect::<HashSet<_>>().len(), 20);
+    }
+
+    #[test]
+    fn test_states_equal() {
+        let conts = load_continuations();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+        assert!(lrk.states_equal(b"{\"a\": 1, \"b", b"{\"xyz\": [1], \"cd"));
+        assert!(lrk.states_equal(b"{ \"a\"", b"{\"b\""));
+        assert!(!lrk.states_equal(b"{\"a\": 1", b"{\"a\": \"1"));
+        assert!(!lrk.states_equal(b"}", b"}"));
+        let a = lrk.get_state(b"[1, 2, ").unwrap();
+        let b = lrk.get_state(b"[\"x\", ").unwrap();
+        assert_eq!(state_fingerprint(&a), state_fingerprint(&b));
+        let c = lrk.get_state(b"[1, 2").unwrap();
+        assert_ne!(state_fingerprint(&a), state_fingerprint(&c));
+    }
+
+    #[test]
+    fn test_hash_consed_states() {
+        let conts = load_continuations();
+        let c = conts.iter().position(|c| c == b"c").unwrap();
+        let (grammar, lexer, _) = load_lrk_grammar("json");
+        let lrk = LR1GrammarConstraint::from_files(grammar, lexer, conts).unwrap();
+
+        // equal states reached on different paths share their stack and lexer remainder
+        let a = lrk.get_state(b"{\"ab").unwrap();
+        let b = lrk.get_state(b"{ \"ab").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.split().0.id(), b.split().0.id());
+        assert_eq!(a.split().1.id(), b.split().1.id());
+        let clone = a.clone();
+        assert_eq!(clone.split().0.shared(), a.split().0.shared());
+
+        // staying within a lexeme keeps the parser stack
+        let next = lrk.get_next_state(&a, c).unwrap();
+        assert_eq!(next.split().0.id(), a.split().0.id());
+        assert_eq!(next, a);
+        let other = lrk.get_state(b"[\"ab").unwrap();
+        assert_ne!(other, a);
+        assert_eq!(other.split().1, a.split().1);
+
+        // skipped whitespace does not change the parser stack
+        let state = lrk.get_state(b"[1, ").unwrap();
+        assert!(state.same_stack(&lrk.get_state(b"[1,   \n").unwrap()));
+        assert!(!state.same_stack(&lrk.get_state(b"[1, 2,").unwrap()));
+    }
+
+    #[test]
+    fn test_whitespace_policy() {
+        let (grammar, lexer) = crate::builtin::grammar("json").unwrap();
+        let conts: Vec<_> = ["{", "}", "\"a\"", ":", "1", " ", "  ", "\n"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let constraint = |policy: &str| {
+            LR1GrammarConstraint::with_whitespace(
+                grammar,
+                lexer,
+                conts.clone(),
+                policy.parse().unwrap(),
+            )
+            .unwrap()
+        };
+
+        let preserve = constraint("preserve");
+        assert!(preserve.check(b"{ \"a\" :\n  1 }"));
+
+        let forbid = constraint("forbid");
+        assert!(forbid.check(b"{\"a\":1}"));
+        assert!(!forbid.check(b"{\"a\": 1}"));
+        let state = forbid.get_state(b"{\"a\":").unwrap();
+        assert_eq!(forbid.get_valid_continuations(&state), [0, 2, 4]);
+
+        let single = constraint("single_space");
+        assert!(single.check(b"{\"a\": 1}"));
+        assert!(single.check(b"{ \"a\" : 1 }"));
+        assert!(!single.check(b"{\"a\":  1}"));
+        assert!(!single.check(b"{\"a\":\n1}"));
+        let state = single.get_state(b"{\"a\":").unwrap();
+        assert_eq!(single.get_valid_continuations(&state), [0, 2, 4, 5]);
+        let state = single.get_state(b"{\"a\": ").unwrap();
+        assert_eq!(single.get_valid_continuations(&state), [0, 2, 4]);
+
+        let free = constraint("free_form");
+        assert!(free.check(b"{\t\"a\" :\n\n 1 }"));
+        let exact = ExactLR1GrammarConstraint::with_whitespace(
+            grammar,
+            lexer,
+            conts.clone(),
+            WhitespacePolicy::Forbid,
+        )
+        .unwrap();
+        assert!(!exact.check(b"{\"a\": 1}"));
+
+        assert!("compact".parse::<WhitespacePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let grammar = "%start Call\n%token TEXT TOOL_CALL END\n%%\nCall: TEXT TOOL_CALL TEXT END ;";
+        let lexer = "%%\nTOOL_CALL %token <tool_call>\nEND %token_id 4\nTEXT [a-z<>_/]+";
+        let conts: Vec<_> = ["a", "<tool_call>", "<", "tool_call>", "</tool_call>", "b"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        fn check<C: Constraint>(lrk: &C) {
+            // special tokens are only produced by their own continuations
+            let state = lrk.get_start_state();
+            assert_eq!(lrk.get_valid_continuations(&state), [0, 2, 3, 5]);
+            let state = lrk.get_next_state(&state, 0).unwrap();
+            assert_eq!(lrk.get_valid_continuations(&state), [0, 1, 2, 3, 5]);
+            let spelled = lrk.get_next_state(&state, 2).unwrap();
+            let spelled = lrk.get_next_state(&spelled, 3).unwrap();
+            assert_eq!(lrk.get_valid_continuations(&spelled), [0, 1, 2, 3, 5]);
+            let state = lrk.get_next_state(&state, 1).unwrap();
+            let state = lrk.get_next_state(&state, 5).unwrap();
+            assert!(!lrk.is_match_state(&state));
+            let state = lrk.get_next_state(&state, 4).unwrap();
+            assert!(lrk.is_match_state(&state));
+            assert!(!lrk.check(b"a<tool_call>b</tool_call>"));
+        }
+        check(&LR1GrammarConstraint::new(grammar, lexer, conts.clone()).unwrap());
+        check(&ExactLR1GrammarConstraint::new(grammar, lexer, conts.clone()).unwrap());
+
+        // special tokens have to be in the vocabulary, each continuation
+        // can only be bound to one of them
+        let missing = conts[..4].to_vec();
+        assert!(LR1GrammarConstraint::new(grammar, lexer, missing).is_err());
+        let twice = "%%\nTOOL_CALL %token <tool_call>\nEND %token_id 1\nTEXT [a-z]+";
+        assert!(LR1GrammarConstraint::new(grammar, twice, conts.clone()).is_err());
+        let lrk = LR1GrammarConstraint::new(grammar, lexer, vec![]).unwrap();
+        assert!(lrk.with_continuations(conts[..4].to_vec()).is_err());
+        let lrk = lrk.with_continuations(conts).unwrap();
+        assert_eq!(
+            lrk.get_valid_continuations(&lrk.get_start_state()),
+            [0, 2, 3, 5]
+        );
+    }
 }
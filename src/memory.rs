@@ -0,0 +1,267 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, Weak,
+    },
+};
+
+pub trait MemoryUsage {
+    // approximate number of heap and inline bytes used
+    fn memory_usage(&self) -> usize;
+}
+
+pub trait Evictable: Send + Sync {
+    // try to free at least the given number of bytes,
+    // returns the number of bytes actually freed
+    fn evict(&self, bytes: usize) -> usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    // fail allocations that would exceed the budget
+    Deny,
+    // evict from registered caches first, fail only if that is not enough
+    Evict,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryBudgetExceeded {
+    pub requested: usize,
+    pub used: usize,
+    pub limit: usize,
+}
+
+impl Display for MemoryBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "memory budget exceeded: requested {} bytes with {} of {} bytes in use",
+            self.requested, self.used, self.limit
+        )
+    }
+}
+
+impl Error for MemoryBudgetExceeded {}
+
+pub struct MemoryBudget {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    evict: AtomicBool,
+    evictables: Mutex<Vec<Weak<dyn Evictable>>>,
+}
+
+static GLOBAL_BUDGET: MemoryBudget = MemoryBudget::new();
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBudget {
+    pub const fn new() -> Self {
+        Self {
+            limit: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+            evict: AtomicBool::new(false),
+            evictables: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn global() -> &'static Self {
+        &GLOBAL_BUDGET
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::SeqCst) {
+            usize::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set_policy(&self, policy: MemoryPolicy) {
+        self.evict
+            .store(policy == MemoryPolicy::Evict, Ordering::SeqCst);
+    }
+
+    pub fn policy(&self) -> MemoryPolicy {
+        if self.evict.load(Ordering::SeqCst) {
+            MemoryPolicy::Evict
+        } else {
+            MemoryPolicy::Deny
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn register(&self, evictable: Weak<dyn Evictable>) {
+        let mut evictables = self.evictables.lock().expect("error locking evictables");
+        evictables.retain(|e| e.strong_count() > 0);
+        evictables.push(evictable);
+    }
+
+    pub fn reserve(&self, bytes: usize) -> Result<MemoryReservation<'_>, MemoryBudgetExceeded> {
+        let mut reservation = MemoryReservation {
+            budget: self,
+            bytes: 0,
+        };
+        reservation.grow(bytes)?;
+        Ok(reservation)
+    }
+
+    fn try_add(&self, bytes: usize) -> bool {
+        let limit = self.limit.load(Ordering::SeqCst);
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .is_ok()
+    }
+
+    fn acquire(&self, bytes: usize) -> Result<(), MemoryBudgetExceeded> {
+        if self.try_add(bytes) {
+            return Ok(());
+        }
+        if self.policy() == MemoryPolicy::Evict {
+            // upgrade first, so evictables are not called
+            // while holding the registry lock
+            let evictables: Vec<_> = self
+                .evictables
+                .lock()
+                .expect("error locking evictables")
+                .iter()
+                .filter_map(Weak::upgrade)
+                .collect();
+            for evictable in evictables {
+                let missing = self
+                    .used()
+                    .saturating_add(bytes)
+                    .saturating_sub(self.limit.load(Ordering::SeqCst));
+                evictable.evict(missing);
+                if self.try_add(bytes) {
+                    return Ok(());
+                }
+            }
+        }
+        Err(MemoryBudgetExceeded {
+            requested: bytes,
+            used: self.used(),
+            limit: self.limit.load(Ordering::SeqCst),
+        })
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+pub struct MemoryReservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl MemoryReservation<'_> {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn grow(&mut self, bytes: usize) -> Result<(), MemoryBudgetExceeded> {
+        self.budget.acquire(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    pub fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.budget.release(bytes);
+        self.bytes -= bytes;
+    }
+}
+
+impl Drop for MemoryReservation<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+pub(crate) fn continuations_memory_usage(continuations: &[Vec<u8>]) -> usize {
+    continuations
+        .iter()
+        .map(|c| c.capacity() + size_of::<Vec<u8>>())
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+
+    struct Cache(Mutex<MemoryReservation<'static>>);
+
+    impl Evictable for Cache {
+        fn evict(&self, bytes: usize) -> usize {
+            let mut reservation = self.0.lock().unwrap();
+            let freed = bytes.min(reservation.bytes());
+            reservation.shrink(freed);
+            freed
+        }
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new();
+        budget.set_limit(Some(100));
+        let a = budget.reserve(60).unwrap();
+        assert_eq!(budget.used(), 60);
+        let err = budget.reserve(50).err().unwrap();
+        assert_eq!(
+            err,
+            MemoryBudgetExceeded {
+                requested: 50,
+                used: 60,
+                limit: 100
+            }
+        );
+        let mut b = budget.reserve(40).unwrap();
+        assert_eq!(budget.used(), 100);
+        b.shrink(30);
+        assert_eq!(budget.used(), 70);
+        assert!(b.grow(31).is_err());
+        drop(a);
+        drop(b);
+        assert_eq!(budget.used(), 0);
+        budget.set_limit(None);
+        assert!(budget.reserve(usize::MAX / 2).is_ok());
+    }
+
+    #[test]
+    fn test_memory_budget_evict() {
+        static BUDGET: MemoryBudget = MemoryBudget::new();
+        BUDGET.set_limit(Some(100));
+        let cache = Arc::new(Cache(Mutex::new(BUDGET.reserve(80).unwrap())));
+        let weak = Arc::downgrade(&cache);
+        BUDGET.register(weak);
+        // deny by default
+        assert!(BUDGET.reserve(50).is_err());
+        BUDGET.set_policy(MemoryPolicy::Evict);
+        let reservation = BUDGET.reserve(50).unwrap();
+        assert_eq!(cache.0.lock().unwrap().bytes(), 50);
+        assert_eq!(BUDGET.used(), 100);
+        // cannot evict more than the cache holds
+        assert!(BUDGET.reserve(60).is_err());
+        drop(reservation);
+        drop(cache);
+        assert_eq!(BUDGET.used(), 0);
+    }
+}
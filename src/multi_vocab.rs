@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use indexmap::IndexMap;
+use lru::LruCache;
+
+use crate::{
+    memory::MemoryUsage, ByteConstraint, Constraint, ExactLR1GrammarConstraint,
+    LR1GrammarConstraint,
+};
+
+const DEFAULT_CACHE_SIZE: usize = 65536;
+
+// constraints whose compiled grammar can be reused with other continuations
+pub trait WithContinuations: ByteConstraint + Sized {
+    fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>>;
+
+    // memory shared by all constraints created with with_continuations
+    fn shared_memory_usage(&self) -> usize;
+
+    // memory used by the continuations of a single constraint
+    fn vocabulary_memory_usage(&self) -> usize;
+
+    // whether a single continuation is in get_valid_continuations
+    fn is_valid_continuation(&self, state: &Self::State, continuation: usize) -> bool;
+}
+
+impl WithContinuations for LR1GrammarConstraint {
+    fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        LR1GrammarConstraint::with_continuations(self, continuations)
+    }
+
+    fn shared_memory_usage(&self) -> usize {
+        LR1GrammarConstraint::shared_memory_usage(self)
+    }
+
+    fn vocabulary_memory_usage(&self) -> usize {
+        LR1GrammarConstraint::vocabulary_memory_usage(self)
+    }
+
+    fn is_valid_continuation(&self, state: &Self::State, continuation: usize) -> bool {
+        self.get_next_state(state, continuation).is_some()
+    }
+}
+
+impl WithContinuations for ExactLR1GrammarConstraint {
+    fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        ExactLR1GrammarConstraint::with_continuations(self, continuations)
+    }
+
+    fn shared_memory_usage(&self) -> usize {
+        ExactLR1GrammarConstraint::shared_memory_usage(self)
+    }
+
+    fn vocabulary_memory_usage(&self) -> usize {
+        ExactLR1GrammarConstraint::vocabulary_memory_usage(self)
+    }
+
+    // continuations complete at most the pending lexeme, so an existing
+    // next state is not enough
+    fn is_valid_continuation(&self, state: &Self::State, continuation: usize) -> bool {
+        self.is_valid_continuation_index(state, continuation)
+    }
+}
+
+// transitions of a state, by interned continuation
+type TransitionCache<S> = LruCache<S, HashMap<u32, Option<S>>>;
+
+// which interned continuations are valid in a state, for those checked so far
+#[derive(Default)]
+struct SharedMask {
+    checked: Vec<u64>,
+    valid: Vec<u64>,
+}
+
+impl SharedMask {
+    fn get(&self, id: u32) -> Option<bool> {
+        let (word, bit) = (id as usize / 64, id % 64);
+        if self.checked.get(word)? >> bit & 1 == 0 {
+            return None;
+        }
+        Some(self.valid[word] >> bit & 1 == 1)
+    }
+
+    fn set(&mut self, id: u32, valid: bool) {
+        let (word, bit) = (id as usize / 64, id % 64);
+        if word >= self.checked.len() {
+            self.checked.resize(word + 1, 0);
+            self.valid.resize(word + 1, 0);
+        }
+        self.checked[word] |= 1 << bit;
+        if valid {
+            self.valid[word] |= 1 << bit;
+        }
+    }
+}
+
+struct Vocabulary<C> {
+    constraint: Arc<C>,
+    // interned id of every continuation
+    ids: Arc<[u32]>,
+}
+
+impl<C> Clone for Vocabulary<C> {
+    fn clone(&self) -> Self {
+        Self {
+            constraint: self.constraint.clone(),
+            ids: self.ids.clone(),
+        }
+    }
+}
+
+// one compiled grammar serving several vocabularies at once, e.g. models
+// with different tokenizers; states do not depend on the vocabulary, and the
+// continuations of all vocabularies are interned by their bytes, so matches,
+// transitions, and which continuations are valid in a state are cached once
+// and shared between all vocabularies containing the same continuation
+pub struct MultiVocabConstraint<C: WithContinuations> {
+    grammar: C,
+    vocabularies: RwLock<IndexMap<String, Vocabulary<C>>>,
+    // ids are never reused, also not after removing a vocabulary
+    interned: RwLock<HashMap<Vec<u8>, u32>>,
+    matches: Mutex<LruCache<C::State, bool>>,
+    transitions: Mutex<TransitionCache<C::State>>,
+    masks: Mutex<LruCache<C::State, SharedMask>>,
+}
+
+impl<C> MultiVocabConstraint<C>
+where
+    C: WithContinuations,
+    C::State: Hash + Eq + Clone,
+{
+    // the continuations of the given constraint are ignored, only its
+    // grammar is used
+    pub fn new(grammar: C) -> Self {
+        Self::with_cache_size(grammar, DEFAULT_CACHE_SIZE)
+    }
+
+    // the cache size is the number of states cached, masks take up to a bit
+    // per interned continuation, so only cache_size / 64 states keep them
+    pub fn with_cache_size(grammar: C, cache_size: usize) -> Self {
+        let size = |size| NonZeroUsize::new(size).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            grammar,
+            vocabularies: RwLock::default(),
+            interned: RwLock::default(),
+            matches: Mutex::new(LruCache::new(size(cache_size))),
+            transitions: Mutex::new(LruCache::new(size(cache_size))),
+            masks: Mutex::new(LruCache::new(size(cache_size / 64))),
+        }
+    }
+
+    pub fn add_vocabulary(
+        &self,
+        name: &str,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Arc<C>, Box<dyn Error>> {
+        let mut vocabularies = self.vocabularies.write().unwrap();
+        if vocabularies.contains_key(name) {
+            return Err(format!("vocabulary {name} already exists").into());
+        }
+        let constraint = Arc::new(self.grammar.with_continuations(continuations)?);
+        // interned by the continuations of the constraint, in which special
+        // tokens are already replaced, see with_continuations
+        let mut interned = self.interned.write().unwrap();
+        let ids = constraint
+            .continuations()
+            .iter()
+            .map(|cont| {
+                let next =
+                    u32::try_from(interned.len()).map_err(|_| "too many distinct continuations")?;
+                Ok(*interned.entry(cont.clone()).or_insert(next))
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        vocabularies.insert(
+            name.to_string(),
+            Vocabulary {
+                constraint: constraint.clone(),
+                ids,
+            },
+        );
+        Ok(constraint)
+    }
+
+    pub fn remove_vocabulary(&self, name: &str) -> bool {
+        self.vocabularies
+            .write()
+            .unwrap()
+            .shift_remove(name)
+            .is_some()
+    }
+
+    // the constraint for a vocabulary, sharing the compiled grammar
+    pub fn vocabulary(&self, name: &str) -> Result<Arc<C>, Box<dyn Error>> {
+        self.entry(name).map(|vocabulary| vocabulary.constraint)
+    }
+
+    fn entry(&self, name: &str) -> Result<Vocabulary<C>, Box<dyn Error>> {
+        self.vocabularies
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown vocabulary {name}").into())
+    }
+
+    // names of all vocabularies in the order they were added
+    pub fn vocabularies(&self) -> Vec<String> {
+        self.vocabularies.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn get_state(&self, prefix: &[u8]) -> Option<C::State> {
+        self.grammar.get_state(prefix)
+    }
+
+    pub fn get_start_state(&self) -> C::State {
+        self.grammar.get_start_state()
+    }
+
+    pub fn is_match_state(&self, state: &C::State) -> bool {
+        if let Some(&is_match) = self.matches.lock().unwrap().get(state) {
+            return is_match;
+        }
+        let is_match = self.grammar.is_match_state(state);
+        self.matches.lock().unwrap().put(state.clone(), is_match);
+        is_match
+    }
+
+    // continuations already checked for the state, e.g. by another vocabulary,
+    // are looked up; the others are computed and recorded for all vocabularies
+    pub fn get_valid_continuations(
+        &self,
+        name: &str,
+        state: &C::State,
+    ) -> Result<Vec<usize>, Box<dyn Error>> {
+        let Vocabulary { constraint, ids } = self.entry(name)?;
+        let mut known: Vec<_> = match self.masks.lock().unwrap().get(state) {
+            Some(mask) => ids.iter().map(|&id| mask.get(id)).collect(),
+            None => vec![None; ids.len()],
+        };
+        let unknown: Vec<_> = (0..ids.len()).filter(|&i| known[i].is_none()).collect();
+        if unknown.len() * 2 > ids.len() {
+            // mostly unknown, a full pass over the vocabulary shares the work
+            // for common prefixes and is cheaper than checking one by one
+            known.fill(Some(false));
+            for i in constraint.get_valid_continuations(state) {
+                known[i] = Some(true);
+            }
+        } else {
+            for &i in &unknown {
+                known[i] = Some(constraint.is_valid_continuation(state, i));
+            }
+        }
+        if !unknown.is_empty() {
+            let mut masks = self.masks.lock().unwrap();
+            let mask = masks.get_or_insert_mut(state.clone(), SharedMask::default);
+            for (&id, valid) in ids.iter().zip(&known) {
+                mask.set(id, valid.unwrap_or_default());
+            }
+        }
+        Ok((0..ids.len()).filter(|&i| known[i] == Some(true)).collect())
+    }
+
+    pub fn get_next_state(
+        &self,
+        name: &str,
+        state: &C::State,
+        continuation: usize,
+    ) -> Result<Option<C::State>, Box<dyn Error>> {
+        let Vocabulary { constraint, ids } = self.entry(name)?;
+        let (Some(bytes), Some(&id)) = (
+            constraint.continuations().get(continuation),
+            ids.get(continuation),
+        ) else {
+            return Err(format!("continuation {continuation} out of range for {name}").into());
+        };
+        Ok(self.transition(state, id, bytes))
+    }
+
+    // transitions only depend on the bytes, so they are cached across
+    // vocabularies; bytes that are no continuation of any vocabulary are
+    // not cached
+    pub fn get_next_state_with_bytes(&self, state: &C::State, bytes: &[u8]) -> Option<C::State> {
+        let id = self.interned.read().unwrap().get(bytes).copied();
+        match id {
+            Some(id) => self.transition(state, id, bytes),
+            None => self.grammar.get_next_state_with_bytes(state, bytes),
+        }
+    }
+
+    fn transition(&self, state: &C::State, id: u32, bytes: &[u8]) -> Option<C::State> {
+        if let Some(next) = self
+            .transitions
+            .lock()
+            .unwrap()
+            .get(state)
+            .and_then(|transitions| transitions.get(&id))
+        {
+            return next.clone();
+        }
+        let next = self.grammar.get_next_state_with_bytes(state, bytes);
+        self.transitions
+            .lock()
+            .unwrap()
+            .get_or_insert_mut(state.clone(), HashMap::new)
+            .insert(id, next.clone());
+        next
+    }
+}
+
+impl<C: WithContinuations> MemoryUsage for MultiVocabConstraint<C> {
+    // the compiled grammar is counted once, plus the continuations of
+    // every vocabulary; the caches are not included, they are bounded
+    fn memory_usage(&self) -> usize {
+        self.grammar.shared_memory_usage()
+            + self.grammar.vocabulary_memory_usage()
+            + self
+                .vocabularies
+                .read()
+                .unwrap()
+                .values()
+                .map(|vocabulary| vocabulary.constraint.vocabulary_memory_usage())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Constraint;
+
+    fn conts(conts: &[&str]) -> Vec<Vec<u8>> {
+        conts.iter().map(|c| c.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_multi_vocab() {
+        let (grammar, lexer) = crate::builtin::grammar("json").unwrap();
+        let json = LR1GrammarConstraint::new(grammar, lexer, vec![]).unwrap();
+        let multi = MultiVocabConstraint::new(json);
+        let a = multi
+            .add_vocabulary("a", conts(&["{", "}", "[", "]", "1", ","]))
+            .unwrap();
+        multi
+            .add_vocabulary("b", conts(&["[1", "]", ",", "{}", "x"]))
+            .unwrap();
+        assert!(multi.add_vocabulary("a", vec![]).is_err());
+        assert_eq!(multi.vocabularies(), ["a", "b"]);
+
+        let state = multi.get_start_state();
+        assert_eq!(
+            multi.get_valid_continuations("a", &state).unwrap(),
+            [0, 2, 4]
+        );
+        assert_eq!(multi.get_valid_continuations("b", &state).unwrap(), [0, 3]);
+        assert!(multi.get_valid_continuations("c", &state).is_err());
+
+        // the same bytes lead to the same state in both vocabularies
+        let next_a = multi.get_next_state("a", &state, 2).unwrap().unwrap();
+        let next_a = a.get_next_state(&next_a, 4).unwrap();
+        let next_b = multi.get_next_state("b", &state, 0).unwrap().unwrap();
+        assert_eq!(next_a, next_b);
+        assert_eq!(multi.get_state(b"[1"), Some(next_b.clone()));
+        assert_eq!(multi.get_valid_continuations("b", &next_b).unwrap(), [1, 2]);
+        let done = multi.get_next_state("b", &next_b, 1).unwrap().unwrap();
+        assert!(multi.is_match_state(&done));
+        assert!(!multi.is_match_state(&next_b));
+        assert!(multi.get_next_state("b", &next_b, 5).is_err());
+
+        // the shared grammar is counted once, however many clones exist
+        let usage = multi.memory_usage();
+        let vocabularies = a.vocabulary_memory_usage()
+            + multi.vocabulary("b").unwrap().vocabulary_memory_usage()
+            + multi.grammar.vocabulary_memory_usage();
+        assert_eq!(usage, a.shared_memory_usage() + vocabularies);
+        let a_usage = a.memory_usage();
+        let clones: Vec<_> = (0..3)
+            .map(|_| a.with_continuations(conts(&["1"])).unwrap())
+            .collect();
+        assert_eq!(a.memory_usage(), a_usage);
+        drop(clones);
+        assert_eq!(multi.memory_usage(), usage);
+        assert_eq!(a.memory_usage(), a_usage);
+        assert_eq!(
+            a.memory_usage(),
+            a.shared_memory_usage() + a.vocabulary_memory_usage()
+        );
+
+        assert!(multi.remove_vocabulary("a"));
+        assert!(!multi.remove_vocabulary("a"));
+        assert_eq!(multi.vocabularies(), ["b"]);
+        assert_eq!(multi.memory_usage(), usage - a.vocabulary_memory_usage());
+    }
+
+    #[test]
+    fn test_multi_vocab_shared_masks() {
+        fn check<C>(json: C)
+        where
+            C: WithContinuations,
+            C::State: Hash + Eq + Clone,
+        {
+            // overlapping vocabularies, so masks are partly known from the other one
+            let a = conts(&["{", "}", "\"", "a", ":", " ", "1", ",", "[", "]", "true"]);
+            let b = conts(&["{\"", "}", "\"", "a", ":", " ", "12", ",", "[", "]", "tr"]);
+            let c = conts(&[
+                "{", "}", "\"", "a", ":", " ", "1", ",", "[", "]", "true", "x",
+            ]);
+            let multi = MultiVocabConstraint::new(json);
+            for (name, conts) in [("a", a), ("b", b), ("c", c)] {
+                multi.add_vocabulary(name, conts).unwrap();
+            }
+            for prefix in [
+                &b""[..],
+                b"{",
+                b"{\"a",
+                b"{\"a\": ",
+                b"{\"a\": [1, ",
+                b"{\"a\": t",
+                b"[true]",
+            ] {
+                let state = multi.get_state(prefix).unwrap();
+                for name in ["a", "b", "c", "a", "c"] {
+                    let vocabulary = multi.vocabulary(name).unwrap();
+                    assert_eq!(
+                        multi.get_valid_continuations(name, &state).unwrap(),
+                        vocabulary.get_valid_continuations(&state),
+                        "{name} after {prefix:?}"
+                    );
+                }
+            }
+            // bytes of no vocabulary are not cached, but still valid
+            let state = multi.get_state(b"[").unwrap();
+            assert!(multi.get_next_state_with_bytes(&state, b"12]").is_some());
+            assert!(multi.get_next_state_with_bytes(&state, b"}").is_none());
+        }
+        let (grammar, lexer) = crate::builtin::grammar("json").unwrap();
+        check(LR1GrammarConstraint::new(grammar, lexer, vec![]).unwrap());
+        check(ExactLR1GrammarConstraint::new(grammar, lexer, vec![]).unwrap());
+    }
+}
@@ -0,0 +1,854 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::read_to_string,
+    iter::Peekable,
+    mem::size_of,
+    path::Path,
+    str::CharIndices,
+    sync::Arc,
+};
+
+use indexmap::IndexMap;
+
+use crate::{
+    gbnf::escaped_char,
+    lr1::valid_continuations_in_order,
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Vec<u8>),
+    Class(bool, Vec<(char, char)>),
+    Any,
+    Ref(usize),
+    Seq(Vec<Expr>),
+    // ordered choice, later alternatives are only tried if earlier ones fail
+    Choice(Vec<Expr>),
+    // greedy repetition without backtracking
+    Star(Box<Expr>),
+    And(Box<Expr>),
+    Not(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Name(String),
+    Arrow,
+    Literal(String),
+    Class(bool, Vec<(char, char)>),
+    Dot,
+    Open,
+    Close,
+    Slash,
+    And,
+    Not,
+    Question,
+    Star,
+    Plus,
+}
+
+fn tokenize(peg: &str) -> Result<Vec<Lexeme>, Box<dyn Error>> {
+    let mut lexemes = vec![];
+    let mut chars = peg.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            '#' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::from(c);
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    name.push(c);
+                }
+                Lexeme::Name(name)
+            }
+            '<' if chars.next_if(|&(_, c)| c == '-').is_some() => Lexeme::Arrow,
+            '\'' | '"' => {
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => literal.push(escaped_char(&mut chars)?),
+                        Some((_, q)) if q == c => break,
+                        Some((_, c)) => literal.push(c),
+                        None => return Err(format!("unterminated string at position {pos}").into()),
+                    }
+                }
+                Lexeme::Literal(literal)
+            }
+            '[' => class(&mut chars, pos)?,
+            '.' => Lexeme::Dot,
+            '(' => Lexeme::Open,
+            ')' => Lexeme::Close,
+            '/' => Lexeme::Slash,
+            '&' => Lexeme::And,
+            '!' => Lexeme::Not,
+            '?' => Lexeme::Question,
+            '*' => Lexeme::Star,
+            '+' => Lexeme::Plus,
+            c => return Err(format!("unexpected character {c:?} at position {pos}").into()),
+        };
+        lexemes.push(lexeme);
+    }
+    Ok(lexemes)
+}
+
+// character class after the opening [
+fn class(chars: &mut Peekable<CharIndices>, pos: usize) -> Result<Lexeme, Box<dyn Error>> {
+    let negated = chars.next_if(|&(_, c)| c == '^').is_some();
+    let mut ranges = vec![];
+    let next_char = |chars: &mut Peekable<CharIndices>| match chars.next() {
+        Some((_, '\\')) => escaped_char(chars).map(Some),
+        Some((_, ']')) => Ok(None),
+        Some((_, c)) => Ok(Some(c)),
+        None => Err(format!("unterminated character class at position {pos}").into()),
+    };
+    while let Some(first) = next_char(chars)? {
+        if chars.peek().is_some_and(|&(_, c)| c == '-')
+            && chars.clone().nth(1).is_some_and(|(_, c)| c != ']')
+        {
+            chars.next();
+            let last = next_char(chars)?.ok_or("unterminated range")?;
+            if last < first {
+                return Err(format!("invalid range {first}-{last} at position {pos}").into());
+            }
+            ranges.push((first, last));
+        } else {
+            ranges.push((first, first));
+        }
+    }
+    Ok(Lexeme::Class(negated, ranges))
+}
+
+struct Parser<'a> {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+    names: &'a IndexMap<String, usize>,
+}
+
+impl Parser<'_> {
+    fn peek(&self, offset: usize) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos + offset)
+    }
+
+    fn at_rule_start(&self) -> bool {
+        matches!(self.peek(0), Some(Lexeme::Name(_))) && self.peek(1) == Some(&Lexeme::Arrow)
+    }
+
+    fn choice(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek(0) == Some(&Lexeme::Slash) {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Choice(alternatives)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut items = vec![];
+        while !self.at_rule_start()
+            && !matches!(self.peek(0), None | Some(Lexeme::Slash | Lexeme::Close))
+        {
+            items.push(self.prefix()?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+
+    fn prefix(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let predicate = match self.peek(0) {
+            Some(Lexeme::And) => Some(true),
+            Some(Lexeme::Not) => Some(false),
+            _ => None,
+        };
+        if predicate.is_some() {
+            self.pos += 1;
+        }
+        let expr = self.suffix()?;
+        Ok(match predicate {
+            Some(true) => Expr::And(Box::new(expr)),
+            Some(false) => Expr::Not(Box::new(expr)),
+            None => expr,
+        })
+    }
+
+    fn suffix(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut expr = self.primary()?;
+        loop {
+            expr = match self.peek(0) {
+                Some(Lexeme::Question) => Expr::Choice(vec![expr, Expr::Seq(vec![])]),
+                Some(Lexeme::Star) => Expr::Star(Box::new(expr)),
+                Some(Lexeme::Plus) => Expr::Seq(vec![expr.clone(), Expr::Star(Box::new(expr))]),
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let lexeme = self.peek(0).cloned().ok_or("unexpected end of grammar")?;
+        self.pos += 1;
+        Ok(match lexeme {
+            Lexeme::Name(name) => Expr::Ref(
+                *self
+                    .names
+                    .get(&name)
+                    .ok_or_else(|| format!("undefined rule {name}"))?,
+            ),
+            Lexeme::Literal(literal) => Expr::Literal(literal.into_bytes()),
+            Lexeme::Class(negated, ranges) => Expr::Class(negated, ranges),
+            Lexeme::Dot => Expr::Any,
+            Lexeme::Open => {
+                let expr = self.choice()?;
+                if self.peek(0) != Some(&Lexeme::Close) {
+                    return Err("missing closing parenthesis".into());
+                }
+                self.pos += 1;
+                expr
+            }
+            lexeme => return Err(format!("unexpected {lexeme:?}").into()),
+        })
+    }
+}
+
+fn parse(peg: &str) -> Result<(Vec<String>, Vec<Expr>), Box<dyn Error>> {
+    let lexemes = tokenize(peg)?;
+    let mut names = IndexMap::new();
+    for window in lexemes.windows(2) {
+        if let [Lexeme::Name(name), Lexeme::Arrow] = window {
+            let idx = names.len();
+            if names.insert(name.clone(), idx).is_some() {
+                return Err(format!("duplicate rule {name}").into());
+            }
+        }
+    }
+    let mut parser = Parser {
+        lexemes,
+        pos: 0,
+        names: &names,
+    };
+    let mut rules = vec![];
+    while parser.pos < parser.lexemes.len() {
+        if !parser.at_rule_start() {
+            return Err(format!("expected rule, got {:?}", parser.lexemes[parser.pos]).into());
+        }
+        parser.pos += 2;
+        rules.push(parser.choice()?);
+    }
+    if rules.is_empty() {
+        return Err("grammar has no rules".into());
+    }
+    Ok((names.into_keys().collect(), rules))
+}
+
+fn nullable(expr: &Expr, rules: &[bool]) -> bool {
+    match expr {
+        Expr::Literal(literal) => literal.is_empty(),
+        Expr::Class(..) | Expr::Any => false,
+        Expr::Ref(idx) => rules[*idx],
+        Expr::Seq(items) => items.iter().all(|item| nullable(item, rules)),
+        Expr::Choice(alternatives) => alternatives.iter().any(|alt| nullable(alt, rules)),
+        Expr::Star(_) | Expr::And(_) | Expr::Not(_) => true,
+    }
+}
+
+// rules called at the position an expression starts at
+fn left_refs(expr: &Expr, nullable_rules: &[bool], refs: &mut Vec<usize>) {
+    match expr {
+        Expr::Ref(idx) => refs.push(*idx),
+        Expr::Seq(items) => {
+            for item in items {
+                left_refs(item, nullable_rules, refs);
+                if !nullable(item, nullable_rules) {
+                    break;
+                }
+            }
+        }
+        Expr::Choice(alternatives) => {
+            for alt in alternatives {
+                left_refs(alt, nullable_rules, refs);
+            }
+        }
+        Expr::Star(inner) | Expr::And(inner) | Expr::Not(inner) => {
+            left_refs(inner, nullable_rules, refs)
+        }
+        Expr::Literal(_) | Expr::Class(..) | Expr::Any => {}
+    }
+}
+
+fn empty_repetition(expr: &Expr, nullable_rules: &[bool]) -> bool {
+    match expr {
+        Expr::Star(inner) => {
+            nullable(inner, nullable_rules) || empty_repetition(inner, nullable_rules)
+        }
+        Expr::Seq(items) | Expr::Choice(items) => items
+            .iter()
+            .any(|item| empty_repetition(item, nullable_rules)),
+        Expr::And(inner) | Expr::Not(inner) => empty_repetition(inner, nullable_rules),
+        Expr::Literal(_) | Expr::Class(..) | Expr::Any | Expr::Ref(_) => false,
+    }
+}
+
+// rejects grammars a packrat parser would loop on
+fn check_well_formed(names: &[String], rules: &[Expr]) -> Result<(), Box<dyn Error>> {
+    let mut nullable_rules = vec![false; rules.len()];
+    loop {
+        let next: Vec<_> = rules
+            .iter()
+            .map(|rule| nullable(rule, &nullable_rules))
+            .collect();
+        if next == nullable_rules {
+            break;
+        }
+        nullable_rules = next;
+    }
+    for (name, rule) in names.iter().zip(rules) {
+        if empty_repetition(rule, &nullable_rules) {
+            return Err(
+                format!("rule {name} repeats an expression that matches the empty string").into(),
+            );
+        }
+    }
+    let left: Vec<_> = rules
+        .iter()
+        .map(|rule| {
+            let mut refs = vec![];
+            left_refs(rule, &nullable_rules, &mut refs);
+            refs
+        })
+        .collect();
+    for start in 0..rules.len() {
+        let mut stack = left[start].clone();
+        let mut seen = vec![false; rules.len()];
+        while let Some(idx) = stack.pop() {
+            if idx == start {
+                return Err(format!("rule {} is left recursive", names[start]).into());
+            }
+            if !std::mem::replace(&mut seen[idx], true) {
+                stack.extend(&left[idx]);
+            }
+        }
+    }
+    Ok(())
+}
+
+// possible results of an expression at a position over all extensions of
+// the input; an over-approximation once the end of the input is reached
+#[derive(Debug, Clone, Default)]
+struct Outcome {
+    // end positions of successful matches
+    ends: Vec<usize>,
+    // may match beyond the end of the input
+    open: bool,
+    fails: bool,
+    // depends on the end of the input, so it cannot be reused for longer inputs
+    touched: bool,
+}
+
+impl Outcome {
+    fn matched(end: usize) -> Self {
+        Self {
+            ends: vec![end],
+            ..Default::default()
+        }
+    }
+
+    fn failed() -> Self {
+        Self {
+            fails: true,
+            ..Default::default()
+        }
+    }
+}
+
+type Memo = HashMap<(usize, usize), Outcome>;
+
+enum Decoded {
+    Char(char, usize),
+    Invalid,
+    End,
+    // end of input within a character, with the bounds of its code point
+    Incomplete(u32, u32),
+}
+
+fn decode(input: &[u8]) -> Decoded {
+    let Some(&first) = input.first() else {
+        return Decoded::End;
+    };
+    let (len, bits) = match first {
+        0x00..=0x7f => (1, first),
+        0xc2..=0xdf => (2, first & 0x1f),
+        0xe0..=0xef => (3, first & 0x0f),
+        0xf0..=0xf4 => (4, first & 0x07),
+        _ => return Decoded::Invalid,
+    };
+    if input.len() < len {
+        if input[1..].iter().any(|&b| b & 0xc0 != 0x80) {
+            return Decoded::Invalid;
+        }
+        let prefix = input[1..]
+            .iter()
+            .fold(bits as u32, |cp, &b| cp << 6 | (b & 0x3f) as u32);
+        let missing = 6 * (len - input.len()) as u32;
+        // without overlong encodings and beyond the last code point
+        let low = (prefix << missing).max([0, 0x80, 0x800, 0x10000][len - 1]);
+        let high = ((prefix << missing) | ((1 << missing) - 1)).min(0x10ffff);
+        return if low <= high {
+            Decoded::Incomplete(low, high)
+        } else {
+            Decoded::Invalid
+        };
+    }
+    match std::str::from_utf8(&input[..len]) {
+        Ok(s) => Decoded::Char(s.chars().next().unwrap(), len),
+        Err(_) => Decoded::Invalid,
+    }
+}
+
+// packrat evaluation; with an incomplete input, terminals that run into the
+// end of the input may both match and fail, and results that do not depend
+// on the end are kept in the state to be reused for longer inputs
+struct Evaluator<'a> {
+    rules: &'a [Expr],
+    input: &'a [u8],
+    complete: bool,
+    stable: &'a Memo,
+    memo: Memo,
+}
+
+impl Evaluator<'_> {
+    fn end_of_input(&self) -> Outcome {
+        Outcome {
+            ends: vec![],
+            open: !self.complete,
+            fails: true,
+            touched: true,
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr, pos: usize) -> Outcome {
+        match expr {
+            Expr::Literal(literal) => {
+                let rest = &self.input[pos..];
+                if rest.starts_with(literal) {
+                    Outcome::matched(pos + literal.len())
+                } else if literal.starts_with(rest) {
+                    self.end_of_input()
+                } else {
+                    Outcome::failed()
+                }
+            }
+            // any also matches a single byte of invalid UTF-8, so !. is only
+            // true at the end of the input
+            Expr::Any => match decode(&self.input[pos..]) {
+                Decoded::Char(_, len) => Outcome::matched(pos + len),
+                Decoded::Invalid => Outcome::matched(pos + 1),
+                Decoded::End => self.end_of_input(),
+                Decoded::Incomplete(..) if self.complete => Outcome::matched(pos + 1),
+                Decoded::Incomplete(..) => Outcome {
+                    open: true,
+                    touched: true,
+                    ..Default::default()
+                },
+            },
+            Expr::Class(negated, ranges) => match decode(&self.input[pos..]) {
+                Decoded::Char(c, len) => {
+                    if ranges.iter().any(|&(first, last)| first <= c && c <= last) != *negated {
+                        Outcome::matched(pos + len)
+                    } else {
+                        Outcome::failed()
+                    }
+                }
+                Decoded::Invalid => Outcome::failed(),
+                Decoded::End => self.end_of_input(),
+                Decoded::Incomplete(..) if self.complete => Outcome::failed(),
+                Decoded::Incomplete(low, high) => {
+                    let overlaps = ranges
+                        .iter()
+                        .any(|&(first, last)| first as u32 <= high && last as u32 >= low);
+                    let covers = ranges
+                        .iter()
+                        .any(|&(first, last)| first as u32 <= low && last as u32 >= high);
+                    let (can_match, can_fail) = if *negated {
+                        (!covers, overlaps)
+                    } else {
+                        (overlaps, !covers)
+                    };
+                    Outcome {
+                        ends: vec![],
+                        open: can_match,
+                        fails: can_fail,
+                        touched: true,
+                    }
+                }
+            },
+            Expr::Ref(idx) => {
+                let key = (*idx, pos);
+                if let Some(outcome) = self.stable.get(&key).or_else(|| self.memo.get(&key)) {
+                    return outcome.clone();
+                }
+                let outcome = self.eval(&self.rules[*idx], pos);
+                self.memo.insert(key, outcome.clone());
+                outcome
+            }
+            Expr::Seq(items) => {
+                let mut outcome = Outcome::default();
+                let mut positions = vec![pos];
+                for item in items {
+                    let mut next = vec![];
+                    for &pos in &positions {
+                        let item = self.eval(item, pos);
+                        outcome.open |= item.open;
+                        outcome.fails |= item.fails;
+                        outcome.touched |= item.touched;
+                        next.extend(item.ends);
+                    }
+                    next.sort();
+                    next.dedup();
+                    positions = next;
+                    if positions.is_empty() {
+                        break;
+                    }
+                }
+                outcome.ends = positions;
+                outcome
+            }
+            Expr::Choice(alternatives) => {
+                let mut outcome = Outcome::failed();
+                for alternative in alternatives {
+                    let alternative = self.eval(alternative, pos);
+                    outcome.ends.extend(alternative.ends);
+                    outcome.open |= alternative.open;
+                    outcome.touched |= alternative.touched;
+                    if !alternative.fails {
+                        outcome.fails = false;
+                        break;
+                    }
+                }
+                outcome.ends.sort();
+                outcome.ends.dedup();
+                outcome
+            }
+            Expr::Star(inner) => {
+                let mut outcome = Outcome::default();
+                let mut stack = vec![pos];
+                let mut seen = vec![pos];
+                while let Some(pos) = stack.pop() {
+                    let inner = self.eval(inner, pos);
+                    if inner.fails {
+                        outcome.ends.push(pos);
+                    }
+                    outcome.open |= inner.open;
+                    outcome.touched |= inner.touched;
+                    for end in inner.ends {
+                        if !seen.contains(&end) {
+                            seen.push(end);
+                            stack.push(end);
+                        }
+                    }
+                }
+                outcome.ends.sort();
+                outcome
+            }
+            Expr::And(inner) | Expr::Not(inner) => {
+                let inner = self.eval(inner, pos);
+                let can_match = !inner.ends.is_empty() || inner.open;
+                let (matches, fails) = match expr {
+                    Expr::And(_) => (can_match, inner.fails),
+                    _ => (inner.fails, can_match),
+                };
+                Outcome {
+                    ends: if matches { vec![pos] } else { vec![] },
+                    open: false,
+                    fails,
+                    touched: inner.touched,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PegState {
+    input: Vec<u8>,
+    memo: Arc<Memo>,
+}
+
+// the memo only caches results for the input
+impl PartialEq for PegState {
+    fn eq(&self, other: &Self) -> bool {
+        self.input == other.input
+    }
+}
+
+impl Eq for PegState {}
+
+impl Hash for PegState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.input.hash(state);
+    }
+}
+
+impl MemoryUsage for PegState {
+    fn memory_usage(&self) -> usize {
+        // the memo is shared with the states before
+        size_of::<Self>() + self.input.capacity()
+    }
+}
+
+// constraint for parsing expression grammars, where alternatives are ordered
+// and repetitions are greedy; there is no separate lexer, literals and
+// classes match the UTF-8 encoded output directly, and the first rule has
+// to match the whole output
+pub struct PegGrammarConstraint {
+    rules: Vec<Expr>,
+    // start rule followed by the end of the input
+    top: Expr,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+}
+
+impl PegGrammarConstraint {
+    pub fn new(grammar: &str, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        let (names, rules) = parse(grammar)?;
+        check_well_formed(&names, &rules)?;
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            rules,
+            top: Expr::Seq(vec![Expr::Ref(0), Expr::Not(Box::new(Expr::Any))]),
+            continuations,
+            permutation,
+            skips,
+        })
+    }
+
+    pub fn from_file(
+        grammar_path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(grammar_path.as_ref())?;
+        let grammar = read_to_string(file)?;
+        Self::new(&grammar, continuations)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn evaluator<'a>(&'a self, input: &'a [u8], complete: bool, stable: &'a Memo) -> Evaluator<'a> {
+        Evaluator {
+            rules: &self.rules,
+            input,
+            complete,
+            stable,
+            memo: Memo::default(),
+        }
+    }
+
+    fn is_viable(outcome: &Outcome) -> bool {
+        !outcome.ends.is_empty() || outcome.open
+    }
+}
+
+impl MemoryUsage for PegGrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for PegGrammarConstraint {
+    type State = PegState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.get_next_state_with_bytes(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        PegState {
+            input: vec![],
+            memo: Arc::default(),
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        let mut evaluator = self.evaluator(&state.input, true, &state.memo);
+        !evaluator.eval(&self.top, 0).ends.is_empty()
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mut input = state.input.clone();
+        let len = input.len();
+        valid_continuations_in_order(
+            &self.continuations,
+            &self.permutation,
+            &self.skips,
+            |cont| {
+                input.truncate(len);
+                input.extend_from_slice(cont);
+                let mut evaluator = self.evaluator(&input, false, &state.memo);
+                Self::is_viable(&evaluator.eval(&self.top, 0))
+            },
+        )
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+}
+
+impl ByteConstraint for PegGrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        let mut input = state.input.clone();
+        input.extend_from_slice(bytes);
+        let mut evaluator = self.evaluator(&input, false, &state.memo);
+        if !Self::is_viable(&evaluator.eval(&self.top, 0)) {
+            return None;
+        }
+        let mut stable = evaluator.memo;
+        stable.retain(|_, outcome| !outcome.touched);
+        let memo = if stable.is_empty() {
+            state.memo.clone()
+        } else {
+            let mut memo = state.memo.as_ref().clone();
+            memo.extend(stable);
+            Arc::new(memo)
+        };
+        Some(PegState { input, memo })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conts() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    #[test]
+    fn test_peg_ordered_choice() {
+        // the first alternative wins, even if the rest fails afterwards
+        let peg = PegGrammarConstraint::new("S <- ('a' / 'ab') 'c'?", conts()).unwrap();
+        assert!(peg.check(b"a"));
+        assert!(peg.check(b"ac"));
+        assert!(peg.get_state(b"ab").is_none());
+
+        // keywords are not identifiers, without a separate lexer
+        let grammar = r#"
+# statements separated by semicolons
+Program   <- Statement (';' Spacing Statement)*
+Statement <- Keyword / Ident
+Keyword   <- ('if' / 'else' / 'while') ![a-z]
+Ident     <- [a-z]+
+Spacing   <- [ \t\n]*
+"#;
+        let peg = PegGrammarConstraint::new(grammar, conts()).unwrap();
+        assert!(peg.check(b"if; iffy;\n\twhile"));
+        assert!(peg.check(b"elsewhere"));
+        assert!(!peg.check(b"if;"));
+        assert!(peg.get_state(b"if ").is_none());
+        let state = peg.get_state(b"while").unwrap();
+        let valid = peg.get_valid_continuations(&state);
+        let expected: Vec<usize> = b";abcdefghijklmnopqrstuvwxyz"
+            .iter()
+            .map(|&b| b as usize)
+            .collect();
+        assert_eq!(valid, expected);
+
+        // greedy repetition never gives back input
+        let peg = PegGrammarConstraint::new("S <- 'a'* 'a'", conts()).unwrap();
+        assert!(!peg.check(b"aaa"));
+    }
+
+    #[test]
+    fn test_peg_arithmetic() {
+        let grammar = r#"
+Expr    <- Spacing Sum
+Sum     <- Product (('+' / '-') Spacing Product)*
+Product <- Value (('*' / '/') Spacing Value)*
+Value   <- [0-9]+ Spacing / '(' Spacing Sum ')' Spacing
+Spacing <- ' '*
+"#;
+        let peg = PegGrammarConstraint::new(grammar, conts()).unwrap();
+        assert!(peg.check(b"1 + 2 * (3 - 4)"));
+        assert!(peg.check(b" (1)/2 "));
+        assert!(!peg.check(b"1 +"));
+        assert!(peg.get_state(b"1 + (").is_some());
+        assert!(peg.get_state(b"1 + )").is_none());
+        assert!(peg.get_state(b"1 2").is_none());
+
+        // incremental states agree with states from the full prefix
+        let text = b"(12 + 3) * 45";
+        let mut state = peg.get_start_state();
+        for len in 1..=text.len() {
+            state = peg.get_next_state(&state, text[len - 1] as usize).unwrap();
+            let full = peg.get_state(&text[..len]).unwrap();
+            assert_eq!(state, full);
+            assert_eq!(
+                peg.get_valid_continuations(&state),
+                peg.get_valid_continuations(&full)
+            );
+            assert_eq!(peg.is_match_state(&state), peg.is_match_state(&full));
+        }
+        assert!(peg.is_match_state(&state));
+        assert!(!state.memo.is_empty());
+    }
+
+    #[test]
+    fn test_peg_predicates_and_utf8() {
+        // lookahead past the end of the input keeps the prefix valid
+        let peg = PegGrammarConstraint::new("S <- &('ab' 'c') . . 'c' / 'abd'", conts()).unwrap();
+        assert!(peg.check(b"abc"));
+        assert!(peg.check(b"abd"));
+        assert!(peg.get_state(b"ab").is_some());
+        assert!(peg.get_state(b"abe").is_none());
+
+        let peg = PegGrammarConstraint::new("S <- [ä-ö]+ !.", conts()).unwrap();
+        assert!(peg.check("äöö".as_bytes()));
+        assert!(!peg.check("äü".as_bytes()));
+        // the first byte of a multi byte character
+        let state = peg.get_state(&"ä".as_bytes()[..1]).unwrap();
+        assert!(!peg.is_match_state(&state));
+        let valid = peg.get_valid_continuations(&state);
+        assert_eq!(valid, (0xa4..=0xb6usize).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_peg_errors() {
+        assert!(PegGrammarConstraint::new("", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- A", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- S 'a' / 'a'", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- A 'a'\nA <- 'b'? S", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- ('a'?)*", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- 'a", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- ('a'", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- 'a'\nS <- 'b'", conts()).is_err());
+        assert!(PegGrammarConstraint::new("S <- [z-a]", conts()).is_err());
+        // right recursion is fine
+        let peg = PegGrammarConstraint::new("S <- 'a' S / 'b'", conts()).unwrap();
+        assert!(peg.check(b"aab"));
+    }
+}
@@ -0,0 +1,952 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fmt::Write,
+    str::FromStr,
+};
+
+use indexmap::IndexMap;
+use itertools::Itertools;
+use regex::escape;
+use serde_json::Value;
+
+use crate::utils::{integer_range_pattern, lexer_pattern, unsigned_range_pattern};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtoFormat {
+    // protobuf text format, e.g. name: "x" tags { key: "a" value: 1 }
+    #[default]
+    Text,
+    // canonical json mapping, e.g. {"name": "x", "tags": {"a": 1}}
+    Json,
+}
+
+impl FromStr for ProtoFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ProtoFormat::Text),
+            "json" => Ok(ProtoFormat::Json),
+            _ => Err(format!("unknown proto format {s}, expected text or json")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    // proto3 fields without label and optional fields can be omitted
+    Optional,
+    Required,
+    Repeated,
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    json_name: String,
+    ty: String,
+    // key and value type of map fields
+    map: Option<(String, String)>,
+    label: Label,
+}
+
+#[derive(Debug, Clone)]
+enum Item {
+    Field(Field),
+    // at most one of the fields is set
+    OneOf(Vec<Field>),
+}
+
+// messages and enums by their full name, e.g. pkg.Outer.Inner
+#[derive(Debug, Default)]
+struct Proto {
+    package: String,
+    messages: HashMap<String, Vec<Item>>,
+    enums: HashMap<String, Vec<String>>,
+}
+
+impl Proto {
+    // looks up a type name from within a scope like protoc does, innermost scope first
+    fn resolve(&self, scope: &str, name: &str) -> Option<String> {
+        let exists = |full: &str| self.messages.contains_key(full) || self.enums.contains_key(full);
+        if let Some(full) = name.strip_prefix('.') {
+            return exists(full).then(|| full.to_string());
+        }
+        let mut scope = scope;
+        loop {
+            let candidate = if scope.is_empty() {
+                name.to_string()
+            } else {
+                format!("{scope}.{name}")
+            };
+            if exists(&candidate) {
+                return Some(candidate);
+            } else if scope.is_empty() {
+                return None;
+            }
+            scope = scope.rfind('.').map_or("", |i| &scope[..i]);
+        }
+    }
+}
+
+fn tokenize(proto: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let chars: Vec<char> = proto.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if chars[i..].starts_with(&['/', '/']) {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if chars[i..].starts_with(&['/', '*']) {
+            i += 2;
+            while i < chars.len() && !chars[i..].starts_with(&['*', '/']) {
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err("unterminated block comment".into());
+            }
+            i += 2;
+            continue;
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            if i >= chars.len() {
+                return Err("unterminated string literal".into());
+            }
+            i += 1;
+        } else if c.is_ascii_alphabetic()
+            || c == '_'
+            || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_alphabetic()))
+        {
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "_.".contains(chars[i])) {
+                i += 1;
+            }
+        } else if c.is_ascii_digit() || c == '-' {
+            i += 1;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || ("+-".contains(chars[i]) && "eE".contains(chars[i - 1])))
+            {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+        tokens.push(chars[start..i].iter().collect());
+    }
+    Ok(tokens)
+}
+
+// lowerCamelCase json name of a field, e.g. first_name becomes firstName
+fn json_name(name: &str) -> String {
+    let mut json = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            json.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            json.push(c);
+        }
+    }
+    json
+}
+
+fn unquote(literal: &str) -> String {
+    literal.trim_matches(['"', '\'']).to_string()
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+    proto3: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String, Box<dyn Error>> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or("unexpected end of proto definition")?
+            .clone();
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Box<dyn Error>> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("expected {expected}, but got {token}").into());
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, Box<dyn Error>> {
+        let token = self.next()?;
+        if !token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.') {
+            return Err(format!("expected name, but got {token}").into());
+        }
+        Ok(token)
+    }
+
+    // skips a statement we do not need, e.g. option or reserved, up to its semicolon
+    fn skip_statement(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut depth = 0usize;
+        loop {
+            match self.next()?.as_str() {
+                "{" => depth += 1,
+                "}" => depth = depth.saturating_sub(1),
+                ";" if depth == 0 => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    // skips a block like a service definition including its braces
+    fn skip_block(&mut self) -> Result<(), Box<dyn Error>> {
+        while self.next()? != "{" {}
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()?.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, proto: &mut Proto) -> Result<(), Box<dyn Error>> {
+        while let Some(token) = self.peek() {
+            match token {
+                "syntax" | "edition" => {
+                    self.next()?;
+                    self.expect("=")?;
+                    // editions have no required fields either
+                    self.proto3 = unquote(&self.next()?) != "proto2";
+                    self.expect(";")?;
+                }
+                "package" => {
+                    self.next()?;
+                    proto.package = self.name()?;
+                    self.expect(";")?;
+                }
+                "import" | "option" => self.skip_statement()?,
+                "service" => self.skip_block()?,
+                "message" | "enum" => {
+                    let scope = proto.package.clone();
+                    self.definition(&scope, proto)?;
+                }
+                ";" => self.pos += 1,
+                "extend" => return Err("extensions are not supported".into()),
+                _ => return Err(format!("unexpected {token} in proto definition").into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn definition(&mut self, scope: &str, proto: &mut Proto) -> Result<(), Box<dyn Error>> {
+        let kind = self.next()?;
+        let name = self.name()?;
+        let full = if scope.is_empty() {
+            name
+        } else {
+            format!("{scope}.{name}")
+        };
+        self.expect("{")?;
+        if kind == "enum" {
+            let values = self.enum_body()?;
+            proto.enums.insert(full, values);
+        } else {
+            let items = self.message_body(&full, proto)?;
+            proto.messages.insert(full, items);
+        }
+        Ok(())
+    }
+
+    fn enum_body(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut values = vec![];
+        loop {
+            match self.peek().ok_or("unexpected end of enum")? {
+                "}" => break,
+                ";" => self.pos += 1,
+                "option" | "reserved" => self.skip_statement()?,
+                _ => {
+                    values.push(self.name()?);
+                    self.skip_statement()?;
+                }
+            }
+        }
+        self.next()?;
+        if values.is_empty() {
+            return Err("enum without values".into());
+        }
+        Ok(values)
+    }
+
+    fn message_body(
+        &mut self,
+        scope: &str,
+        proto: &mut Proto,
+    ) -> Result<Vec<Item>, Box<dyn Error>> {
+        let mut items = vec![];
+        loop {
+            match self.peek().ok_or("unexpected end of message")? {
+                "}" => break,
+                ";" => self.pos += 1,
+                "message" | "enum" => self.definition(scope, proto)?,
+                "option" | "reserved" | "extensions" => self.skip_statement()?,
+                "oneof" => {
+                    self.next()?;
+                    self.name()?;
+                    self.expect("{")?;
+                    let mut fields = vec![];
+                    loop {
+                        match self.peek().ok_or("unexpected end of oneof")? {
+                            "}" => break,
+                            ";" => self.pos += 1,
+                            "option" => self.skip_statement()?,
+                            _ => fields.push(self.field(Label::Required)?),
+                        }
+                    }
+                    self.next()?;
+                    items.push(Item::OneOf(fields));
+                }
+                "extend" => return Err("extensions are not supported".into()),
+                _ => {
+                    let label = match self.peek() {
+                        Some("optional") => Some(Label::Optional),
+                        Some("required") if self.proto3 => {
+                            return Err("required fields are not allowed in proto3".into())
+                        }
+                        Some("required") => Some(Label::Required),
+                        Some("repeated") => Some(Label::Repeated),
+                        _ => None,
+                    };
+                    if label.is_some() {
+                        self.next()?;
+                    }
+                    items.push(Item::Field(self.field(label.unwrap_or(Label::Optional))?));
+                }
+            }
+        }
+        self.next()?;
+        Ok(items)
+    }
+
+    fn field(&mut self, label: Label) -> Result<Field, Box<dyn Error>> {
+        let mut ty = self.name()?;
+        let mut map = None;
+        if ty == "group" {
+            return Err("groups are not supported".into());
+        } else if ty == "map" {
+            self.expect("<")?;
+            let key = self.name()?;
+            self.expect(",")?;
+            let value = self.name()?;
+            self.expect(">")?;
+            ty = format!("map<{key}, {value}>");
+            map = Some((key, value));
+        }
+        let name = self.name()?;
+        self.expect("=")?;
+        self.next()?;
+        let mut json = json_name(&name);
+        if self.peek() == Some("[") {
+            self.next()?;
+            while self.peek() != Some("]") {
+                if self.next()? == "json_name" {
+                    self.expect("=")?;
+                    json = unquote(&self.next()?);
+                }
+            }
+            self.next()?;
+        }
+        self.expect(";")?;
+        // map fields are repeated entries
+        let label = if map.is_some() {
+            Label::Repeated
+        } else {
+            label
+        };
+        Ok(Field {
+            name,
+            json_name: json,
+            ty,
+            map,
+            label,
+        })
+    }
+}
+
+fn parse(proto: &str) -> Result<Proto, Box<dyn Error>> {
+    let mut parser = Parser {
+        tokens: tokenize(proto)?,
+        pos: 0,
+        // protoc assumes proto2 without a syntax statement
+        proto3: false,
+    };
+    let mut parsed = Proto::default();
+    parser.file(&mut parsed)?;
+    Ok(parsed)
+}
+
+// disjoint integer intervals, split at the bounds of the 32 and 64 bit integer
+// types; the lexer cannot know which type the parser expects, so every interval
+// gets its own token and an integer type accepts the tokens of its intervals
+const INTERVALS: [(i128, i128); 6] = [
+    (i64::MIN as i128, i32::MIN as i128 - 1),
+    (i32::MIN as i128, -1),
+    (0, i32::MAX as i128),
+    (i32::MAX as i128 + 1, u32::MAX as i128),
+    (u32::MAX as i128 + 1, i64::MAX as i128),
+    (i64::MAX as i128 + 1, u64::MAX as i128),
+];
+
+fn interval_pattern((lo, hi): (i128, i128)) -> Result<String, Box<dyn Error>> {
+    if hi > i64::MAX.into() {
+        Ok(format!(
+            "(?:{})",
+            unsigned_range_pattern(lo as u64, hi as u64)
+                .into_iter()
+                .rev()
+                .join("|")
+        ))
+    } else {
+        integer_range_pattern(lo as i64, hi as i64)
+    }
+}
+
+const LEXER_FRAGMENTS: &str = r#"HEX [0-9a-fA-F]
+UNICODE u{HEX}{4}
+ESC '\' ({UNICODE}|["\\/bfnrt])
+SAFECODEPOINT [^\x00-\x1F"\\]
+TEXTESC '\' [^\n]
+TEXTCHAR [^"\\\n]
+INT 0|([1-9][0-9]*)
+EXP [Ee][+-]?[0-9]+
+WS [\x20\t\n\r]+
+"#;
+
+struct Builder<'a> {
+    proto: &'a Proto,
+    format: ProtoFormat,
+    // rule names and their alternatives
+    rules: Vec<(String, Vec<String>)>,
+    messages: HashMap<String, String>,
+    scalars: HashMap<String, String>,
+    // serialized json strings and their token names
+    literals: IndexMap<String, String>,
+    // indices of the intervals used unquoted and quoted, 64 bit integers
+    // are quoted in json
+    ints: BTreeSet<usize>,
+    quoted_ints: BTreeSet<usize>,
+    tokens: BTreeSet<&'static str>,
+}
+
+impl<'a> Builder<'a> {
+    fn rule(&mut self, mut alternatives: Vec<String>) -> String {
+        let mut seen = BTreeSet::new();
+        alternatives.retain(|alternative| seen.insert(alternative.clone()));
+        let name = format!("s{}", self.rules.len());
+        self.rules.push((name.clone(), alternatives));
+        name
+    }
+
+    fn token(&mut self, name: &'static str) -> String {
+        self.tokens.insert(name);
+        format!("proto_{}", name.to_lowercase())
+    }
+
+    // a json string, e.g. a key or enum value, in text format a literal token
+    fn literal(&mut self, text: &str) -> String {
+        if self.format == ProtoFormat::Text {
+            return format!("'{text}'");
+        }
+        let json = Value::String(text.to_string()).to_string();
+        let len = self.literals.len();
+        let name = self
+            .literals
+            .entry(json)
+            .or_insert_with(|| format!("PROTO_LIT{len}"));
+        format!("'{name}'")
+    }
+
+    fn integer(&mut self, intervals: &[usize], quoted: bool) -> Vec<String> {
+        let (used, prefix) = if quoted {
+            (&mut self.quoted_ints, "PROTO_QINT")
+        } else {
+            (&mut self.ints, "PROTO_INT")
+        };
+        used.extend(intervals);
+        intervals.iter().map(|i| format!("'{prefix}{i}'")).collect()
+    }
+
+    fn scalar(&mut self, ty: &str, key: bool) -> Option<String> {
+        let cache_key = format!("{ty}{}", if key { " key" } else { "" });
+        if let Some(name) = self.scalars.get(&cache_key) {
+            return Some(name.clone());
+        }
+        let json = self.format == ProtoFormat::Json;
+        // map keys are always strings in json
+        let quoted = json && key;
+        let alternatives = match ty {
+            "double" | "float" => vec![self.token("FLOAT")],
+            "int32" | "sint32" | "sfixed32" => self.integer(&[1, 2], quoted),
+            "uint32" | "fixed32" => self.integer(&[2, 3], quoted),
+            "int64" | "sint64" | "sfixed64" => self.integer(&[0, 1, 2, 3, 4], json),
+            "uint64" | "fixed64" => self.integer(&[2, 3, 4, 5], json),
+            "bool" if quoted => vec![self.literal("true"), self.literal("false")],
+            "bool" => vec!["'true'".to_string(), "'false'".to_string()],
+            "string" | "bytes" => vec![self.token("STRING")],
+            _ => return None,
+        };
+        let name = self.rule(alternatives);
+        self.scalars.insert(cache_key, name.clone());
+        Some(name)
+    }
+
+    fn value(&mut self, scope: &str, ty: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(name) = self.scalar(ty, false) {
+            return Ok(name);
+        }
+        let full = self
+            .proto
+            .resolve(scope, ty)
+            .ok_or_else(|| format!("unknown type {ty} in {scope}"))?;
+        if let Some(values) = self.proto.enums.get(&full) {
+            let alternatives = values.iter().map(|v| self.literal(v)).collect();
+            return Ok(self.rule(alternatives));
+        }
+        self.message(&full)
+    }
+
+    fn message(&mut self, full: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(name) = self.messages.get(full) {
+            return Ok(name.clone());
+        }
+        // reserve the rule before descending, messages can be recursive
+        let idx = self.rules.len();
+        let name = self.rule(vec![]);
+        self.messages.insert(full.to_string(), name.clone());
+        let body = match self.format {
+            ProtoFormat::Text => self.text_body(full)?,
+            ProtoFormat::Json => self.json_body(full)?,
+        };
+        self.rules[idx].1 = vec![format!("'{{' {body} '}}'")];
+        Ok(name)
+    }
+
+    fn map_key(&mut self, ty: &str) -> Result<String, Box<dyn Error>> {
+        match ty {
+            "double" | "float" | "bytes" => Err(format!("invalid map key type {ty}").into()),
+            _ => self
+                .scalar(ty, true)
+                .ok_or_else(|| format!("invalid map key type {ty}").into()),
+        }
+    }
+
+    fn items(&self, full: &str) -> &'a [Item] {
+        let proto: &'a Proto = self.proto;
+        &proto.messages[full]
+    }
+
+    // in text format fields are written in declaration order without separators,
+    // repeated fields and map entries as consecutive fields with the same name
+    fn text_member(&mut self, scope: &str, field: &Field) -> Result<String, Box<dyn Error>> {
+        let name = &field.name;
+        let (value, is_message) = match &field.map {
+            Some((key, value)) => {
+                let key = self.map_key(key)?;
+                let value = self.value(scope, value)?;
+                let entry = self.rule(vec![format!(
+                    "'{{' 'key' ':' {key} 'value' ':' {value} '}}'"
+                )]);
+                (entry, true)
+            }
+            None => {
+                let value = self.value(scope, &field.ty)?;
+                let is_message = self
+                    .proto
+                    .resolve(scope, &field.ty)
+                    .is_some_and(|full| self.proto.messages.contains_key(&full));
+                (value, is_message)
+            }
+        };
+        // the colon is optional before messages
+        let mut alternatives = vec![format!("'{name}' ':' {value}")];
+        if is_message {
+            alternatives.push(format!("'{name}' {value}"));
+        }
+        Ok(self.rule(alternatives))
+    }
+
+    fn text_body(&mut self, full: &str) -> Result<String, Box<dyn Error>> {
+        let mut next = String::new();
+        for item in self.items(full).iter().rev() {
+            let alternatives = match item {
+                Item::Field(field) => {
+                    let member = self.text_member(full, field)?;
+                    match field.label {
+                        Label::Required => vec![format!("{member} {next}")],
+                        Label::Optional => vec![format!("{member} {next}"), next],
+                        Label::Repeated => {
+                            let list = format!("s{}", self.rules.len());
+                            self.rule(vec![String::new(), format!("{list} {member}")]);
+                            vec![format!("{list} {next}")]
+                        }
+                    }
+                }
+                Item::OneOf(fields) => {
+                    let mut alternatives = fields
+                        .iter()
+                        .map(|field| Ok(format!("{} {next}", self.text_member(full, field)?)))
+                        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+                    alternatives.push(next);
+                    alternatives
+                }
+            };
+            next = self.rule(alternatives);
+        }
+        Ok(next)
+    }
+
+    // in json repeated fields are arrays and maps objects with string keys
+    fn json_member(&mut self, scope: &str, field: &Field) -> Result<String, Box<dyn Error>> {
+        let key = self.literal(&field.json_name);
+        let value = match (&field.map, field.label) {
+            (Some((key, value)), _) => {
+                let key = self.map_key(key)?;
+                let value = self.value(scope, value)?;
+                let list = format!("s{}", self.rules.len());
+                self.rule(vec![
+                    format!("{key} ':' {value}"),
+                    format!("{list} ',' {key} ':' {value}"),
+                ]);
+                self.rule(vec!["'{' '}'".to_string(), format!("'{{' {list} '}}'")])
+            }
+            (None, Label::Repeated) => {
+                let item = self.value(scope, &field.ty)?;
+                let list = format!("s{}", self.rules.len());
+                self.rule(vec![item.clone(), format!("{list} ',' {item}")]);
+                self.rule(vec!["'[' ']'".to_string(), format!("'[' {list} ']'")])
+            }
+            (None, _) => self.value(scope, &field.ty)?,
+        };
+        Ok(format!("{key} ':' {value}"))
+    }
+
+    // same as for objects of json schemas, first and rest are the rules for
+    // the members from i on without and with a leading comma
+    fn json_body(&mut self, full: &str) -> Result<String, Box<dyn Error>> {
+        let mut positions = vec![];
+        for item in self.items(full) {
+            positions.push(match item {
+                Item::Field(field) => (
+                    vec![self.json_member(full, field)?],
+                    field.label != Label::Required,
+                ),
+                Item::OneOf(fields) => (
+                    fields
+                        .iter()
+                        .map(|field| self.json_member(full, field))
+                        .collect::<Result<_, _>>()?,
+                    true,
+                ),
+            });
+        }
+        let (mut first, mut rest) = (String::new(), String::new());
+        for (i, (members, optional)) in positions.into_iter().enumerate().rev() {
+            let next_rest = rest;
+            if i > 0 {
+                let mut alternatives: Vec<_> = members
+                    .iter()
+                    .map(|member| format!("',' {member} {next_rest}"))
+                    .collect();
+                if optional {
+                    alternatives.push(next_rest.clone());
+                }
+                rest = self.rule(alternatives);
+            } else {
+                rest = String::new();
+            }
+            let mut alternatives: Vec<_> = members
+                .iter()
+                .map(|member| format!("{member} {next_rest}"))
+                .collect();
+            if optional {
+                alternatives.push(first);
+            }
+            first = self.rule(alternatives);
+        }
+        Ok(first)
+    }
+
+    // rules for generic floats and strings, including all integers and
+    // literals the lexer might produce instead
+    fn token_rules(&self) -> Vec<(String, Vec<String>)> {
+        self.tokens
+            .iter()
+            .map(|&token| {
+                let mut alternatives = vec![format!("'PROTO_{token}'")];
+                match token {
+                    "FLOAT" => {
+                        alternatives.extend(self.ints.iter().map(|i| format!("'PROTO_INT{i}'")))
+                    }
+                    _ => {
+                        alternatives
+                            .extend(self.quoted_ints.iter().map(|i| format!("'PROTO_QINT{i}'")));
+                        alternatives.extend(self.literals.values().map(|name| format!("'{name}'")));
+                    }
+                }
+                (format!("proto_{}", token.to_lowercase()), alternatives)
+            })
+            .collect()
+    }
+}
+
+// converts a message of a .proto definition into an LR(1) grammar and lexer for
+// that message in protobuf text format or the canonical json mapping; fields are
+// generated in declaration order, required fields must be set, at most one field
+// of a oneof is set and 64 bit integers are quoted in json; imports, groups and
+// extensions are not supported, map keys in json and bytes are not validated
+pub fn proto_to_lr1(
+    proto: &str,
+    message: &str,
+    format: ProtoFormat,
+) -> Result<(String, String), Box<dyn Error>> {
+    let parsed = parse(proto)?;
+    let full = parsed
+        .resolve(&parsed.package, message)
+        .filter(|full| parsed.messages.contains_key(full))
+        .ok_or_else(|| format!("message {message} not found"))?;
+    let mut builder = Builder {
+        proto: &parsed,
+        format,
+        rules: vec![],
+        messages: HashMap::new(),
+        scalars: HashMap::new(),
+        literals: IndexMap::new(),
+        ints: BTreeSet::new(),
+        quoted_ints: BTreeSet::new(),
+        tokens: BTreeSet::new(),
+    };
+    // the top level message of text format has no braces
+    let start = match format {
+        ProtoFormat::Text => {
+            let body = builder.text_body(&full)?;
+            builder.rule(vec![body])
+        }
+        ProtoFormat::Json => builder.message(&full)?,
+    };
+
+    let mut grammar = format!("%start {start}\n\n%%\n");
+    for (name, alternatives) in builder.rules.iter().chain(&builder.token_rules()) {
+        let alternatives: Vec<_> = alternatives.iter().map(|a| a.trim()).collect();
+        write!(
+            grammar,
+            "\n{name}\n    : {}\n    ;\n",
+            alternatives.join("\n    | ")
+        )?;
+    }
+
+    // literals and quoted integers before generic strings, integers before
+    // floats, so they win ties and are accepted wherever those are expected
+    let mut lexer = format!("{LEXER_FRAGMENTS}\n%%\n\n");
+    for (json, name) in &builder.literals {
+        writeln!(lexer, "{name} {}", lexer_pattern(&escape(json)))?;
+    }
+    for &i in &builder.quoted_ints {
+        writeln!(
+            lexer,
+            "PROTO_QINT{i} '\"' {} '\"'",
+            interval_pattern(INTERVALS[i])?
+        )?;
+    }
+    for &i in &builder.ints {
+        writeln!(lexer, "PROTO_INT{i} {}", interval_pattern(INTERVALS[i])?)?;
+    }
+    for token in &builder.tokens {
+        let pattern = match (*token, format) {
+            ("FLOAT", _) => "-?{INT}( '.' [0-9]+)?{EXP}?",
+            ("STRING", ProtoFormat::Text) => r#"'"' ({TEXTESC}|{TEXTCHAR})* '"'"#,
+            ("STRING", ProtoFormat::Json) => r#"'"' ({ESC}|{SAFECODEPOINT})* '"'"#,
+            _ => unreachable!("unknown token {token}"),
+        };
+        writeln!(lexer, "PROTO_{token} {pattern}")?;
+    }
+    lexer.push_str("; {WS}\n");
+    Ok((grammar, lexer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{inline_rules, Constraint, LR1GrammarConstraint};
+
+    const PROTO: &str = r#"
+        syntax = "proto3";
+        package example.v1;
+
+        import "other.proto";
+        option java_package = "com.example";
+
+        // a person
+        message Person {
+            string name = 1;
+            int32 age = 2 [deprecated = true];
+            repeated string emails = 3;
+            Kind kind = 4;
+            oneof contact {
+                string phone = 5;
+                Address address = 6;
+            }
+            map<string, int64> scores = 7;
+            optional uint64 user_id = 8 [json_name = "uid"];
+            reserved 9, 10;
+
+            message Address {
+                string city = 1;
+                /* nested
+                   recursion */
+                repeated Address parts = 2;
+            }
+        }
+
+        enum Kind {
+            KIND_UNSPECIFIED = 0;
+            KIND_HUMAN = 1;
+        }
+
+        service People {
+            rpc Get (Person) returns (Person) {}
+        }
+    "#;
+
+    fn constraint(proto: &str, message: &str, format: ProtoFormat) -> LR1GrammarConstraint {
+        let (grammar, lexer) = proto_to_lr1(proto, message, format).unwrap();
+        let grammar = inline_rules(&grammar).unwrap();
+        LR1GrammarConstraint::new(
+            grammar.grammar(),
+            &lexer,
+            (0..=255).map(|b| vec![b]).collect(),
+        )
+        .unwrap()
+    }
+
+    fn is_match(constraint: &LR1GrammarConstraint, input: &str) -> bool {
+        constraint
+            .get_state(input.as_bytes())
+            .is_some_and(|state| constraint.is_match_state(&state))
+    }
+
+    #[test]
+    fn test_proto_text_format() {
+        let c = constraint(PROTO, "Person", ProtoFormat::Text);
+        assert!(is_match(&c, r#"name: "Ada" age: 36 kind: KIND_HUMAN"#));
+        assert!(is_match(
+            &c,
+            r#"name: "A \"B\"" emails: "a@b.c" emails: "d@e.f"
+               phone: "123"
+               scores { key: "x" value: -9223372036854775808 }
+               scores: { key: "y" value: 1 }
+               user_id: 18446744073709551615"#
+        ));
+        assert!(is_match(
+            &c,
+            r#"address { city: "Berlin" parts { city: "Mitte" } }"#
+        ));
+        // wrong order, two fields of a oneof, out of range, unknown names
+        assert!(!is_match(&c, r#"age: 36 name: "Ada""#));
+        assert!(!is_match(&c, r#"phone: "1" address { }"#));
+        assert!(!is_match(&c, "age: 2147483648"));
+        assert!(!is_match(&c, "user_id: -1"));
+        assert!(!is_match(&c, "kind: KIND_ROBOT"));
+        assert!(!is_match(&c, r#"name: "Ada" name: "Bob""#));
+        assert!(c.get_state(b"address { city: ").is_some());
+        assert!(!is_match(&c, "address { city: \"x\""));
+
+        let c = constraint(PROTO, "Person.Address", ProtoFormat::Text);
+        assert!(is_match(&c, r#"city: "x" parts { } parts { }"#));
+    }
+
+    #[test]
+    fn test_proto_json_format() {
+        let c = constraint(PROTO, "example.v1.Person", ProtoFormat::Json);
+        assert!(is_match(&c, "{}"));
+        assert!(is_match(
+            &c,
+            r#"{"name": "Ada", "age": 36, "emails": ["a@b.c"], "kind": "KIND_HUMAN"}"#
+        ));
+        assert!(is_match(
+            &c,
+            r#"{"address": {"city": "Berlin", "parts": [{"parts": []}]}, "scores": {"x": "-1", "name": "2"}, "uid": "7"}"#
+        ));
+        // string values can be equal to keys, enum values or quoted integers
+        assert!(is_match(&c, r#"{"name": "KIND_HUMAN", "phone": "42"}"#));
+        assert!(!is_match(&c, r#"{"phone": "1", "address": {}}"#));
+        assert!(!is_match(&c, r#"{"userId": "7"}"#));
+        assert!(!is_match(&c, r#"{"uid": 7}"#));
+        assert!(!is_match(&c, r#"{"age": "36"}"#));
+        assert!(!is_match(&c, r#"{"kind": "KIND_ROBOT"}"#));
+        assert!(!is_match(&c, r#"{"scores": {"x": 1}}"#));
+    }
+
+    #[test]
+    fn test_proto2() {
+        let proto = r#"
+            syntax = "proto2";
+            message Point {
+                required double x = 1;
+                required double y = 2;
+                optional string label = 3;
+                map<int32, bool> flags = 4;
+            }
+        "#;
+        let c = constraint(proto, "Point", ProtoFormat::Text);
+        assert!(is_match(&c, "x: 1.5 y: -2"));
+        assert!(is_match(&c, "x: 1e3 y: 0 flags { key: -1 value: true }"));
+        assert!(!is_match(&c, "x: 1.5"));
+        assert!(!is_match(&c, "y: 1 x: 2"));
+        let c = constraint(proto, "Point", ProtoFormat::Json);
+        assert!(is_match(&c, r#"{"x": 1, "y": 2.5, "label": "p"}"#));
+        assert!(is_match(&c, r#"{"x": 1, "y": 2, "flags": {"-5": true}}"#));
+        assert!(!is_match(&c, r#"{"x": 1, "y": 2, "flags": {"a": true}}"#));
+        assert!(!is_match(&c, r#"{"y": 2}"#));
+
+        assert!(proto_to_lr1(proto, "Line", ProtoFormat::Text).is_err());
+        assert!(proto_to_lr1(
+            "syntax = \"proto3\"; message A { required int32 a = 1; }",
+            "A",
+            ProtoFormat::Text
+        )
+        .is_err());
+        assert!(proto_to_lr1("message A { B b = 1; }", "A", ProtoFormat::Text).is_err());
+        assert!(proto_to_lr1(
+            "message A { map<float, int32> m = 1; }",
+            "A",
+            ProtoFormat::Json
+        )
+        .is_err());
+        assert_eq!("json".parse(), Ok(ProtoFormat::Json));
+        assert!("binary".parse::<ProtoFormat>().is_err());
+    }
+}
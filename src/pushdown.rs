@@ -0,0 +1,554 @@
+use std::{
+    collections::HashSet, error::Error, fs::File, io::read_to_string, mem::size_of, path::Path,
+    time::Instant,
+};
+
+use cfgrammar::{
+    yacc::{YaccKind, YaccOriginalActionKind},
+    Symbol, TIdx,
+};
+use regex_automata::util::primitives::StateID;
+
+use crate::{
+    limits::CompileLimits,
+    lr1::{
+        advance_lexer, eoi_terminals, initial_prefix_matches, is_valid_remainder,
+        load_grammar_and_pdfas, valid_continuations_in_order, Matching, PdfaList, TokenNames,
+    },
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+// upper bound on the stacks kept alive at once; only reached by
+// highly ambiguous grammars, use the Earley constraint for those
+const MAX_STACKS: usize = 1024;
+
+// upper bound on the productions after removing empty productions and left
+// recursion, both of which can blow up the grammar exponentially
+const MAX_PRODUCTIONS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Sym {
+    Token(TIdx<u32>),
+    Rule(usize),
+}
+
+// symbols still to be matched, the next one last
+type Stack = Vec<Sym>;
+
+type Rules = Vec<Vec<Vec<Sym>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PushdownState {
+    // sorted and without duplicates, so equal states compare equal; every
+    // stack has a terminal on top or is empty if the input is complete
+    stacks: Vec<Stack>,
+    matching: Matching,
+}
+
+impl MemoryUsage for PushdownState {
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self
+                .stacks
+                .iter()
+                .map(|stack| size_of::<Stack>() + stack.capacity() * size_of::<Sym>())
+                .sum::<usize>()
+            + self.matching.capacity() * size_of::<(usize, StateID)>()
+    }
+}
+
+fn num_productions(rules: &Rules) -> Result<usize, Box<dyn Error>> {
+    let productions = rules.iter().map(Vec::len).sum();
+    if productions > MAX_PRODUCTIONS {
+        return Err(format!(
+            "grammar has {productions} productions after removing empty productions and \
+            left recursion, but at most {MAX_PRODUCTIONS} are allowed"
+        )
+        .into());
+    }
+    Ok(productions)
+}
+
+fn nullable(rules: &Rules) -> Vec<bool> {
+    let mut nullable = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (ridx, prods) in rules.iter().enumerate() {
+            if nullable[ridx] {
+                continue;
+            }
+            nullable[ridx] = prods.iter().any(|prod| {
+                prod.iter()
+                    .all(|sym| matches!(sym, Sym::Rule(r) if nullable[*r]))
+            });
+            changed |= nullable[ridx];
+        }
+    }
+    nullable
+}
+
+// drops the productions using rules that derive no terminal string
+fn remove_unproductive(rules: &mut Rules) {
+    let mut productive = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (ridx, prods) in rules.iter().enumerate() {
+            if productive[ridx] {
+                continue;
+            }
+            productive[ridx] = prods.iter().any(|prod| {
+                prod.iter()
+                    .all(|sym| !matches!(sym, Sym::Rule(r) if !productive[*r]))
+            });
+            changed |= productive[ridx];
+        }
+    }
+    for prods in rules.iter_mut() {
+        prods.retain(|prod| {
+            prod.iter()
+                .all(|sym| !matches!(sym, Sym::Rule(r) if !productive[*r]))
+        });
+    }
+}
+
+// every production is replaced by its variants with any subset of the nullable
+// rules left out, and empty productions are dropped; this also removes hidden
+// left recursion like A: B A 'x' with a nullable B
+fn remove_empty(rules: &mut Rules) -> Result<(), Box<dyn Error>> {
+    let nullable = nullable(rules);
+    for prods in rules.iter_mut() {
+        let mut variants = vec![];
+        for prod in prods.iter() {
+            let mut partial: Vec<Vec<Sym>> = vec![vec![]];
+            for &sym in prod {
+                let omit = matches!(sym, Sym::Rule(r) if nullable[r]);
+                let mut next = Vec::with_capacity(partial.len() * 2);
+                for variant in partial {
+                    if omit {
+                        next.push(variant.clone());
+                    }
+                    let mut variant = variant;
+                    variant.push(sym);
+                    next.push(variant);
+                }
+                if next.len() > MAX_PRODUCTIONS {
+                    return Err("too many productions when removing empty productions".into());
+                }
+                partial = next;
+            }
+            variants.extend(partial.into_iter().filter(|variant| !variant.is_empty()));
+        }
+        variants.sort();
+        variants.dedup();
+        *prods = variants;
+    }
+    num_productions(rules)?;
+    Ok(())
+}
+
+// removes left recursion from a grammar without empty productions (Paull):
+// afterwards every production of a rule starts with a terminal or a rule with a
+// higher index, so expanding the top of a stack always ends with a terminal
+fn remove_left_recursion(rules: &mut Rules) -> Result<(), Box<dyn Error>> {
+    let num_rules = rules.len();
+    for i in 0..num_rules {
+        for j in 0..i {
+            let prods = std::mem::take(&mut rules[i]);
+            let mut substituted = vec![];
+            for prod in prods {
+                if prod[0] != Sym::Rule(j) {
+                    substituted.push(prod);
+                    continue;
+                }
+                for prefix in &rules[j] {
+                    let mut prod_j = prefix.clone();
+                    prod_j.extend_from_slice(&prod[1..]);
+                    substituted.push(prod_j);
+                }
+            }
+            rules[i] = substituted;
+            num_productions(rules)?;
+        }
+        // A: A alpha | beta becomes A: beta | beta A' and A': alpha | alpha A',
+        // unit productions A: A are dropped
+        let (recursive, mut rest): (Vec<_>, Vec<_>) = std::mem::take(&mut rules[i])
+            .into_iter()
+            .filter(|prod| prod.len() > 1 || prod[0] != Sym::Rule(i))
+            .partition(|prod| prod[0] == Sym::Rule(i));
+        if !recursive.is_empty() {
+            let tail = rules.len();
+            let mut tails = vec![];
+            for prod in recursive {
+                let alpha = prod[1..].to_vec();
+                let mut repeated = alpha.clone();
+                repeated.push(Sym::Rule(tail));
+                tails.extend([alpha, repeated]);
+            }
+            let continued: Vec<_> = rest
+                .iter()
+                .map(|beta| {
+                    let mut beta = beta.clone();
+                    beta.push(Sym::Rule(tail));
+                    beta
+                })
+                .collect();
+            rest.extend(continued);
+            rules.push(tails);
+        }
+        rules[i] = rest;
+    }
+    remove_unproductive(rules);
+    num_productions(rules)?;
+    Ok(())
+}
+
+// constraint for grammars that are not LR(1) when no parse tree is needed,
+// using the same grammar and lexer format; every state keeps the set of
+// stacks of a nondeterministic top-down pushdown automaton, i.e. the symbols
+// each possible derivation still has to match, and a continuation is valid
+// if any stack accepts it; unlike the Earley constraint the state does not
+// grow with the input, only with the nesting depth and the ambiguity
+pub struct PushdownGrammarConstraint {
+    rules: Rules,
+    start: Vec<Stack>,
+    pdfas: PdfaList,
+    eof: TIdx<u32>,
+    tidxs: Vec<TIdx<u32>>,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+    token_names: TokenNames,
+}
+
+impl PushdownGrammarConstraint {
+    pub fn new(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_limits(grammar, tokens, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let (grammar, pdfas, _, token_names) = load_grammar_and_pdfas(
+            grammar,
+            YaccKind::Original(YaccOriginalActionKind::NoAction),
+            tokens,
+            limits,
+            start,
+            &mut |_| {},
+        )?;
+        let mut rules: Rules = grammar
+            .iter_rules()
+            .map(|ridx| {
+                grammar
+                    .rule_to_prods(ridx)
+                    .iter()
+                    .map(|&pidx| {
+                        grammar
+                            .prod(pidx)
+                            .iter()
+                            .map(|sym| match *sym {
+                                Symbol::Rule(ridx) => Sym::Rule(usize::from(ridx)),
+                                Symbol::Token(tidx) => Sym::Token(tidx),
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+        let start_rule = usize::from(grammar.prod_to_rule(grammar.start_prod()));
+        let empty = nullable(&rules)[start_rule];
+        remove_empty(&mut rules)?;
+        remove_left_recursion(&mut rules)?;
+        limits.check_rules(rules.len())?;
+        limits.check_time(start, "removing left recursion")?;
+
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        let mut constraint = Self {
+            rules,
+            start: vec![],
+            pdfas,
+            eof: grammar.eof_token_idx(),
+            tidxs: grammar.iter_tidxs().collect(),
+            continuations,
+            permutation,
+            skips,
+            token_names,
+        };
+        let mut stacks = constraint.expand(vec![vec![Sym::Rule(start_rule)]]);
+        if empty {
+            stacks.push(vec![]);
+            stacks.sort();
+            stacks.dedup();
+        }
+        constraint.start = stacks;
+        Ok(constraint)
+    }
+
+    pub fn from_files(
+        grammar_path: impl AsRef<Path>,
+        tokens_path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(grammar_path.as_ref())?;
+        let grammar = read_to_string(file)?;
+        let file = File::open(tokens_path.as_ref())?;
+        let tokens = read_to_string(file)?;
+        Self::new(&grammar, &tokens, continuations)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // display names of the terminals any stack accepts next, including
+    // the one currently being lexed; uses %token aliases if given
+    pub fn expected_terminals(&self, state: &PushdownState) -> Vec<&str> {
+        self.tidxs
+            .iter()
+            .filter(|&&tidx| {
+                if tidx == self.eof {
+                    self.accepts(&state.stacks)
+                } else {
+                    self.expects(&state.stacks, tidx)
+                }
+            })
+            .map(|&tidx| self.token_names[usize::from(tidx)].as_str())
+            .collect()
+    }
+
+    // replaces rules on top of the stacks by their productions until
+    // there is a terminal on top; terminates because there is no left recursion
+    fn expand(&self, mut pending: Vec<Stack>) -> Vec<Stack> {
+        let mut expanded = vec![];
+        let mut seen = HashSet::new();
+        while let Some(stack) = pending.pop() {
+            let Some(&Sym::Rule(ridx)) = stack.last() else {
+                expanded.push(stack);
+                continue;
+            };
+            for prod in &self.rules[ridx] {
+                let mut next = stack[..stack.len() - 1].to_vec();
+                next.extend(prod.iter().rev());
+                if seen.len() < MAX_STACKS && seen.insert(next.clone()) {
+                    pending.push(next);
+                }
+            }
+        }
+        expanded.sort();
+        expanded.dedup();
+        expanded.truncate(MAX_STACKS);
+        expanded
+    }
+
+    fn shift(&self, stacks: &[Stack], tidx: TIdx<u32>) -> Option<Vec<Stack>> {
+        let matched: Vec<_> = stacks
+            .iter()
+            .filter(|stack| stack.last() == Some(&Sym::Token(tidx)))
+            .map(|stack| stack[..stack.len() - 1].to_vec())
+            .collect();
+        if matched.is_empty() {
+            return None;
+        }
+        Some(self.expand(matched))
+    }
+
+    fn expects(&self, stacks: &[Stack], tidx: TIdx<u32>) -> bool {
+        stacks
+            .iter()
+            .any(|stack| stack.last() == Some(&Sym::Token(tidx)))
+    }
+
+    fn accepts(&self, stacks: &[Stack]) -> bool {
+        stacks.iter().any(Vec::is_empty)
+    }
+
+    fn is_valid_matching(&self, stacks: &[Stack], matching: &Matching) -> bool {
+        is_valid_remainder(&self.pdfas, matching, self.eof, |tidx| {
+            self.expects(stacks, tidx)
+        })
+    }
+}
+
+impl MemoryUsage for PushdownGrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        let pdfas: usize = self.pdfas.iter().map(|(pdfa, _)| pdfa.memory_usage()).sum();
+        let rules: usize = self
+            .rules
+            .iter()
+            .flatten()
+            .map(|prod| size_of::<Vec<Sym>>() + prod.capacity() * size_of::<Sym>())
+            .sum();
+        pdfas
+            + rules
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for PushdownGrammarConstraint {
+    type State = PushdownState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.get_next_state_with_bytes(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        PushdownState {
+            stacks: self.start.clone(),
+            matching: initial_prefix_matches(&self.pdfas),
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        eoi_terminals(&self.pdfas, &state.matching).any(|tidx| {
+            self.shift(&state.stacks, tidx)
+                .is_some_and(|stacks| self.accepts(&stacks))
+        })
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        valid_continuations_in_order(
+            &self.continuations,
+            &self.permutation,
+            &self.skips,
+            |cont| self.get_next_state_with_bytes(state, cont).is_some(),
+        )
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+}
+
+impl ByteConstraint for PushdownGrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        let (stacks, matching) = advance_lexer(
+            bytes,
+            &self.pdfas,
+            &state.matching,
+            state.stacks.clone(),
+            |stacks, tidx| self.shift(&stacks, tidx),
+        )?;
+        if !self.is_valid_matching(&stacks, &matching) {
+            return None;
+        }
+        Some(PushdownState { stacks, matching })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EarleyGrammarConstraint, LR1GrammarConstraint};
+
+    fn conts() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    #[test]
+    fn test_pushdown_palindromes() {
+        let grammar = "%start S\n%%\nS: 'a' S 'a' | 'b' S 'b' | 'a' | 'b' | ;";
+        let pda = PushdownGrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        let lr1 = LR1GrammarConstraint::new(grammar, "%%\n", conts()).unwrap();
+        for text in ["a", "aa", "aba", "abba", "baab", "abaaba"] {
+            assert!(pda.check(text.as_bytes()), "{text}");
+        }
+        assert!(!lr1.check(b"abba"));
+        assert!(!pda.check(b"ab"));
+        assert!(!pda.check(b"abbaa"));
+        let state = pda.get_state(b"abb").unwrap();
+        assert_eq!(
+            pda.get_valid_continuations(&state),
+            [b'a' as usize, b'b' as usize]
+        );
+        assert_eq!(pda.expected_terminals(&state), ["'a'", "'b'"]);
+        // states reached by different prefixes compare equal
+        assert!(pda.states_equal(b"ab", b"ab"));
+        assert!(!pda.states_equal(b"a", b"b"));
+    }
+
+    #[test]
+    fn test_pushdown_left_recursion() {
+        // ambiguous, left recursive and with a nullable rule in front of
+        // the recursion, compared against the Earley constraint
+        let grammar = "
+%start E
+%%
+E: E '+' E | E '*' E | Sign '(' E ')' | 'INT' | L ;
+L: L ',' 'x' | Opt 'x' ;
+Opt: | '!' ;
+Sign: '-' | ;
+";
+        let lexer = "%%\nINT [0-9]+\n; [\\x20]+";
+        let pda = PushdownGrammarConstraint::new(grammar, lexer, conts()).unwrap();
+        let earley = EarleyGrammarConstraint::new(grammar, lexer, conts()).unwrap();
+        assert!(pda.check(b"1 + 2 * -(3 + 4)"));
+        assert!(pda.check(b"x, x + !x"));
+        assert!(!pda.check(b"1 +"));
+        assert!(pda.get_state(b"1 2").is_none());
+        for text in [
+            "", "1", "1 +", "(1", "-(1)*", "x,", "!x,x", "x,!x", "1 + x, x",
+        ] {
+            let text = text.as_bytes();
+            assert_eq!(pda.check(text), earley.check(text));
+            let (p, e) = (pda.get_state(text), earley.get_state(text));
+            assert_eq!(p.is_some(), e.is_some());
+            if let (Some(p), Some(e)) = (p, e) {
+                assert_eq!(
+                    pda.get_valid_continuations(&p),
+                    earley.get_valid_continuations(&e)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pushdown_json() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let pda = PushdownGrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let lr1 = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let text = br#"{"a": [1, -2.5e3, {"b": null}], "c": "d"}"#;
+        for len in 0..=text.len() {
+            let prefix = &text[..len];
+            let (Some(p), Some(l)) = (pda.get_state(prefix), lr1.get_state(prefix)) else {
+                panic!("invalid prefix {}", String::from_utf8_lossy(prefix));
+            };
+            assert_eq!(
+                pda.get_valid_continuations(&p),
+                lr1.get_valid_continuations(&l)
+            );
+            assert_eq!(pda.is_match_state(&p), lr1.is_match_state(&l));
+        }
+    }
+}
@@ -1,39 +1,219 @@
 use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs,
+    hash::Hash,
+    mem::{size_of, size_of_val},
     num::NonZeroUsize,
-    sync::{mpsc::channel, Arc, Mutex},
+    str::FromStr,
+    sync::{mpsc::channel, Arc, Mutex, TryLockError, Weak},
+    time::Duration,
 };
 
 use anyhow::anyhow;
+use indexmap::IndexMap;
 use lru::LruCache;
-use numpy::{ndarray::Array1, IntoPyArray, PyArray1};
+use numpy::{ndarray::Array1, IntoPyArray, PyArray1, PyArrayMethods};
 use pyo3::{
     prelude::*,
-    types::{PyDict, PyList},
+    types::{PyBool, PyBytes, PyDict, PyList},
 };
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::spawn_fifo;
 use regex_automata::util::primitives::StateID;
+use serde_json::Value;
 
 use crate::{
-    Constraint, ExactLR1GrammarConstraint, LR1GrammarConstraint, LR1GrammarParser, LR1Parse,
-    LR1State, RegularExpressionConstraint, TokenAndSpan,
+    abnf_to_lr1, builtin,
+    cache::{CacheOptions, CompressedIndices, TwoLevelCache},
+    distinguish, distinguish_regex, dtd_to_lr1, ebnf_to_lr1, encode_with_constraint, gbnf_to_lr1,
+    grammar_docs, guidance_to_lr1, inline_rules, json_schema_to_lr1, lark_to_lr1,
+    lr1::{LR1Matching, LR1Stack, StackLexemes},
+    lr1_to_guidance, proto_to_lr1, run_length_order, state_fingerprint, strftime_to_regex,
+    utils::{index_ranges, pack_indices_u32},
+    BackgroundCompile, ByteConstraint, CheckReport as Report, ChoiceConstraint as Choice,
+    CompileLimits, CompilePhase, CompileProgress, ComputedText, Constraint,
+    ConstraintScheduler as Scheduler, CsvColumn, CsvConstraintBuilder, CsvQuoting,
+    DispatchConstraint as Dispatch, Distinction, DocFormat, EarleyGrammarConstraint, EncodeError,
+    Evictable, ExactLR1GrammarConstraint, GLRGrammarConstraint, JsonSchemaConstraint,
+    LR1GrammarConstraint, LR1GrammarParser, LR1Parse, LR1State, LazyRegexConstraint as LazyRegex,
+    LengthPrefixed, LexErrorKind, LexicalConstraint as Lexical, LiteralSetConstraint as LiteralSet,
+    MemoryBudget, MemoryPolicy, MemoryReservation, MemoryUsage, MultiVocabConstraint as MultiVocab,
+    Normalization, ParseQuery, PegGrammarConstraint, ProtoFormat, PushdownGrammarConstraint,
+    QueryNode, RegexFlags, RegexSetConstraint as RegexSet, RegularExpressionConstraint, Rejection,
+    RepeatedConstraint as Repeated, ScheduledRequest, ScheduledResponse, SchedulerOptions,
+    SemanticGrammarConstraint, SessionId, TaggedUnionConstraint as TaggedUnion,
+    TemplateConstraint as Template, TerminalContext, TokenAndSpan,
+    Transcript as RecordedTranscript, UnrollOverflow, Utf8Constraint as Utf8, WhitespacePolicy,
+    WithContinuations, DEFAULT_MAX_STACKS,
 };
 
+// runs f on the locked value; waiting for the lock, e.g. while a background
+// next call still holds it, neither blocks other python threads nor, on free
+// threaded builds, the garbage collector
+fn with_lock<T: Send, R: Send>(
+    py: Python<'_>,
+    mutex: &Mutex<T>,
+    f: impl FnOnce(&mut T) -> R + Send,
+) -> anyhow::Result<R> {
+    match mutex.try_lock() {
+        Ok(mut guard) => Ok(f(&mut guard)),
+        Err(TryLockError::WouldBlock) => py.detach(|| {
+            mutex
+                .lock()
+                .map(|mut guard| f(&mut guard))
+                .map_err(|_| anyhow!("error locking inner state"))
+        }),
+        Err(TryLockError::Poisoned(_)) => Err(anyhow!("error locking inner state")),
+    }
+}
+
+// what next does with a continuation that is invalid in the current state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidPolicy {
+    // mark the constraint as invalid until it is reset
+    #[default]
+    Sticky,
+    // raise an error and keep the current state
+    Raise,
+    // go back to the last state that was a match, or the start state if there was none
+    Reset,
+}
+
+impl FromStr for InvalidPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sticky" => Ok(Self::Sticky),
+            "raise" => Ok(Self::Raise),
+            "reset" => Ok(Self::Reset),
+            _ => Err(format!(
+                "unknown invalid policy {s}, expected sticky, raise or reset"
+            )),
+        }
+    }
+}
+
+impl InvalidPolicy {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        s.parse().map_err(|e: String| anyhow!(e))
+    }
+
+    // match states are only remembered if they might be needed
+    fn remember<S: Clone>(self, last_match: &mut Option<S>, state: &S, is_match: bool) {
+        if self == Self::Reset && is_match {
+            *last_match = Some(state.clone());
+        }
+    }
+}
+
+// what happens once a generation stalled, see StallWatchdog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum StallPolicy {
+    // stop the generation if the state is a match, otherwise mark the constraint invalid
+    #[default]
+    Stop,
+    // only report the stall with is_stalled
+    Report,
+}
+
+impl FromStr for StallPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "report" => Ok(Self::Report),
+            _ => Err(format!("unknown stall policy {s}, expected stop or report")),
+        }
+    }
+}
+
+// detects generations that only advance over skippable input like whitespace,
+// i.e. the parser stack does not change for a number of consecutive steps,
+// a common livelock of models that keep emitting whitespace
+#[derive(Debug, Clone, Copy)]
+struct StallWatchdog {
+    steps: usize,
+    policy: StallPolicy,
+}
+
+impl StallWatchdog {
+    fn parse(steps: Option<usize>, on_stall: &str) -> anyhow::Result<Option<Self>> {
+        let policy = on_stall.parse().map_err(|e: String| anyhow!(e))?;
+        match steps {
+            Some(0) => Err(anyhow!("stall steps must be positive")),
+            Some(steps) => Ok(Some(Self { steps, policy })),
+            None => Ok(None),
+        }
+    }
+}
+
+// spawns the transition of next on the thread pool and waits until it holds the lock
+// on the inner state, under the raise policy also until the continuation is checked
+fn spawn_next(
+    py: Python<'_>,
+    index: usize,
+    policy: InvalidPolicy,
+    transition: impl FnOnce(&dyn Fn(bool)) + Send + 'static,
+) -> anyhow::Result<()> {
+    let (tx, rx) = channel();
+    spawn_fifo(move || {
+        transition(&|valid| tx.send(valid).expect("failed to send on channel"));
+    });
+    // otherwise some unexpected behavior could occurr; detached,
+    // because the pool might still be busy with other callers
+    let valid = py.detach(move || rx.recv())?;
+    if !valid && policy == InvalidPolicy::Raise {
+        return Err(anyhow!(
+            "continuation {index} is invalid in the current state"
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 struct RegexInner {
     state: StateID,
     indices: Array1<i32>,
     is_match: bool,
     is_invalid: bool,
+    last_match: Option<StateID>,
+}
+
+// packs sorted indices into a preallocated numpy buffer, see Constraint::pack_mask_u32
+fn pack_into(indices: &[i32], out: &Bound<'_, PyArray1<u32>>) -> anyhow::Result<()> {
+    let mut out = out.try_readwrite()?;
+    let words = out.as_slice_mut()?;
+    if let Some(&last) = indices.last() {
+        if last as usize >= words.len() * 32 {
+            return Err(anyhow!(
+                "mask buffer of {} words is too small for continuation {last}",
+                words.len()
+            ));
+        }
+    }
+    pack_indices_u32(indices.iter().map(|&i| i as usize), words);
+    Ok(())
 }
 
-#[pyclass]
+#[pyclass(frozen)]
 struct RegexConstraint {
     constraint: Arc<RegularExpressionConstraint>,
     inner: Arc<Mutex<RegexInner>>,
+    memory: Arc<MemoryReservation<'static>>,
+    on_invalid: InvalidPolicy,
 }
 
 impl RegexConstraint {
-    fn init(constraint: RegularExpressionConstraint) -> Self {
+    fn init(
+        constraint: RegularExpressionConstraint,
+        on_invalid: InvalidPolicy,
+    ) -> anyhow::Result<Self> {
+        let memory = MemoryBudget::global().reserve(constraint.memory_usage())?;
         let state = constraint.get_start_state();
         let indices = constraint
             .get_valid_continuations(&state)
@@ -41,24 +221,86 @@ impl RegexConstraint {
             .map(|v| v as i32)
             .collect();
         let is_match = constraint.is_match_state(&state);
-        Self {
+        let mut last_match = None;
+        on_invalid.remember(&mut last_match, &state, is_match);
+        Ok(Self {
             constraint: Arc::new(constraint),
             inner: Arc::new(Mutex::new(RegexInner {
                 state,
                 indices,
                 is_match,
                 is_invalid: false,
+                last_match,
             })),
-        }
+            memory: Arc::new(memory),
+            on_invalid,
+        })
+    }
+}
+
+// seeded for reproducible samples, e.g. for test fixtures
+fn sample_rng(seed: Option<u64>) -> StdRng {
+    seed.map_or_else(StdRng::from_os_rng, StdRng::seed_from_u64)
+}
+
+fn sort_if(re: RegularExpressionConstraint, sorted: bool) -> RegularExpressionConstraint {
+    if sorted {
+        re.with_sorted_continuations()
+    } else {
+        re
+    }
+}
+
+fn precompute_if(
+    re: RegularExpressionConstraint,
+    max_states: Option<usize>,
+) -> Result<RegularExpressionConstraint, Box<dyn Error>> {
+    match max_states {
+        Some(max_states) => re.with_precomputed_continuations(max_states),
+        None => Ok(re),
     }
 }
 
 #[pymethods]
 impl RegexConstraint {
     #[new]
-    fn new(regex: &str, continuations: Vec<Vec<u8>>) -> anyhow::Result<Self> {
-        RegularExpressionConstraint::new(regex, continuations)
-            .map(Self::init)
+    #[pyo3(signature = (
+        regex,
+        continuations,
+        sorted_continuations = false,
+        on_invalid = "sticky",
+        case_insensitive = false,
+        unicode = true,
+        dot_matches_new_line = false,
+        unanchored_start = false,
+        unanchored_end = false,
+        precompute_max_states = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        regex: &str,
+        continuations: PyContinuations,
+        sorted_continuations: bool,
+        on_invalid: &str,
+        case_insensitive: bool,
+        unicode: bool,
+        dot_matches_new_line: bool,
+        unanchored_start: bool,
+        unanchored_end: bool,
+        precompute_max_states: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let flags = RegexFlags {
+            case_insensitive,
+            unicode,
+            dot_matches_new_line,
+            unanchored_start,
+            unanchored_end,
+        };
+        RegularExpressionConstraint::with_flags(regex, flags, continuations)
+            .map(|re| sort_if(re, sorted_continuations))
+            .and_then(|re| precompute_if(re, precompute_max_states))
             .map_err(|e| {
                 anyhow!(
                     "failed to create regular expression constraint from regex '{}': {}",
@@ -66,12 +308,142 @@ impl RegexConstraint {
                     e
                 )
             })
+            .and_then(|re| Self::init(re, on_invalid))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (regex, continuations, sorted_continuations = false, on_invalid = "sticky"))]
+    fn complement(
+        regex: &str,
+        continuations: PyContinuations,
+        sorted_continuations: bool,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        RegularExpressionConstraint::complement(regex, continuations)
+            .map(|re| sort_if(re, sorted_continuations))
+            .map_err(|e| {
+                anyhow!(
+                    "failed to create complement constraint from regex '{}': {}",
+                    regex,
+                    e
+                )
+            })
+            .and_then(|re| Self::init(re, on_invalid))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (layout, continuations, sorted_continuations = false, on_invalid = "sticky"))]
+    fn from_strftime(
+        layout: &str,
+        continuations: PyContinuations,
+        sorted_continuations: bool,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        RegularExpressionConstraint::from_strftime(layout, continuations)
+            .map(|re| sort_if(re, sorted_continuations))
+            .map_err(|e| {
+                anyhow!(
+                    "failed to create regular expression constraint from strftime layout '{}': {}",
+                    layout,
+                    e
+                )
+            })
+            .and_then(|re| Self::init(re, on_invalid))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        columns,
+        continuations,
+        header = true,
+        delimiter = ',',
+        quoting = "never",
+        quote = '"',
+        line_terminator = "\n",
+        min_rows = 1,
+        max_rows = None,
+        sorted_continuations = false,
+        on_invalid = "sticky",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_csv(
+        columns: Vec<(String, Option<PyCsvColumn>)>,
+        continuations: PyContinuations,
+        header: bool,
+        delimiter: char,
+        quoting: &str,
+        quote: char,
+        line_terminator: &str,
+        min_rows: usize,
+        max_rows: Option<usize>,
+        sorted_continuations: bool,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let quoting: CsvQuoting = quoting.parse().map_err(|e: String| anyhow!(e))?;
+        let (names, columns): (Vec<_>, Vec<_>) = columns
+            .into_iter()
+            .map(|(name, column)| (name, column.map_or(CsvColumn::Text, Into::into)))
+            .unzip();
+        let mut builder = CsvConstraintBuilder::new()
+            .columns(columns)
+            .delimiter(delimiter)
+            .quoting(quoting, quote)
+            .line_terminator(line_terminator)
+            .rows(min_rows, max_rows);
+        if header {
+            builder = builder.header(names);
+        }
+        builder
+            .build(continuations)
+            .map(|re| sort_if(re, sorted_continuations))
+            .map_err(|e| anyhow!("failed to create csv constraint: {e}"))
+            .and_then(|re| Self::init(re, on_invalid))
     }
 
     #[staticmethod]
-    fn from_file(path: &str, continuations: Vec<Vec<u8>>) -> anyhow::Result<Self> {
-        RegularExpressionConstraint::from_file(path, continuations)
-            .map(Self::init)
+    #[pyo3(signature = (
+        path,
+        continuations,
+        sorted_continuations = false,
+        on_invalid = "sticky",
+        case_insensitive = false,
+        unicode = true,
+        dot_matches_new_line = false,
+        unanchored_start = false,
+        unanchored_end = false,
+        precompute_max_states = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_file(
+        path: &str,
+        continuations: PyContinuations,
+        sorted_continuations: bool,
+        on_invalid: &str,
+        case_insensitive: bool,
+        unicode: bool,
+        dot_matches_new_line: bool,
+        unanchored_start: bool,
+        unanchored_end: bool,
+        precompute_max_states: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let flags = RegexFlags {
+            case_insensitive,
+            unicode,
+            dot_matches_new_line,
+            unanchored_start,
+            unanchored_end,
+        };
+        RegularExpressionConstraint::from_file_with_flags(path, flags, continuations)
+            .map(|re| sort_if(re, sorted_continuations))
+            .and_then(|re| precompute_if(re, precompute_max_states))
             .map_err(|e| {
                 anyhow!(
                     "failed to create regular expression constraint from file '{}': {}",
@@ -79,83 +451,302 @@ impl RegexConstraint {
                     e
                 )
             })
+            .and_then(|re| Self::init(re, on_invalid))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (regexes, continuations, sorted_continuations = false, on_invalid = "sticky"))]
+    fn new_batch(
+        py: Python<'_>,
+        regexes: Vec<String>,
+        continuations: PyContinuations,
+        sorted_continuations: bool,
+        on_invalid: &str,
+    ) -> anyhow::Result<Vec<Self>> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        py.detach(|| {
+            RegularExpressionConstraint::new_batch(&regexes, continuations)
+                .map_err(|e| anyhow!("failed to create regular expression constraints: {}", e))
+        })?
+        .into_iter()
+        .map(|re| Self::init(sort_if(re, sorted_continuations), on_invalid))
+        .collect()
+    }
+
+    fn serialize<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.constraint.to_bytes())
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (data, on_invalid = "sticky"))]
+    fn deserialize(py: Python<'_>, data: &[u8], on_invalid: &str) -> anyhow::Result<Self> {
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        py.detach(|| {
+            RegularExpressionConstraint::from_bytes(data)
+                .map_err(|e| anyhow!("failed to deserialize regular expression constraint: {}", e))
+        })
+        .and_then(|re| Self::init(re, on_invalid))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        continuations,
+        depth,
+        overflow = "reject",
+        sorted_continuations = false,
+        on_invalid = "sticky",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn unrolled(
+        py: Python<'_>,
+        grammar: &str,
+        lexer: &str,
+        continuations: PyContinuations,
+        depth: usize,
+        overflow: &str,
+        sorted_continuations: bool,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let overflow: UnrollOverflow = overflow.parse().map_err(|e: String| anyhow!(e))?;
+        py.detach(|| {
+            RegularExpressionConstraint::unrolled(grammar, lexer, depth, overflow, continuations)
+                .map_err(|e| anyhow!("failed to unroll grammar into a regular expression: {e}"))
+        })
+        .and_then(|re| Self::init(sort_if(re, sorted_continuations), on_invalid))
     }
 
     #[pyo3(signature = (prefix = None))]
-    fn reset(&self, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
+    fn reset(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
         let Some(state) = self.constraint.get_state(&prefix.unwrap_or_default()) else {
             return Err(anyhow!("failed to reset to given prefix"));
         };
-        self.inner
-            .lock()
-            .map(|mut inner| {
-                inner.state = state;
-                inner.indices = self
-                    .constraint
-                    .get_valid_continuations(&inner.state)
-                    .into_iter()
-                    .map(|v| v as i32)
-                    .collect();
-                inner.is_match = self.constraint.is_match_state(&inner.state);
-                inner.is_invalid = false;
-            })
-            .map_err(|_| anyhow!("error locking inner state"))
+        with_lock(py, &self.inner, |inner| {
+            inner.state = state;
+            inner.indices = self
+                .constraint
+                .get_valid_continuations(&inner.state)
+                .into_iter()
+                .map(|v| v as i32)
+                .collect();
+            inner.is_match = self.constraint.is_match_state(&inner.state);
+            inner.is_invalid = false;
+            inner.last_match = None;
+            self.on_invalid
+                .remember(&mut inner.last_match, &inner.state, inner.is_match);
+        })
     }
 
-    fn clone(&self) -> anyhow::Result<Self> {
-        self.inner
-            .lock()
-            .map(|inner| Self {
-                constraint: self.constraint.clone(),
-                inner: Arc::new(Mutex::new(inner.clone())),
-            })
-            .map_err(|_| anyhow!("error locking inner state"))
+    fn clone(&self, py: Python<'_>) -> anyhow::Result<Self> {
+        with_lock(py, &self.inner, |inner| Self {
+            constraint: self.constraint.clone(),
+            inner: Arc::new(Mutex::new(inner.clone())),
+            memory: self.memory.clone(),
+            on_invalid: self.on_invalid,
+        })
     }
 
     fn get<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<i32>>> {
-        self.inner
-            .lock()
-            .map(|inner| inner.indices.clone().into_pyarray(py))
-            .map_err(|_| anyhow!("error locking inner state"))
+        with_lock(py, &self.inner, |inner| inner.indices.clone())
+            .map(|indices| indices.into_pyarray(py))
     }
 
-    fn is_invalid(&self) -> anyhow::Result<bool> {
-        self.inner
-            .lock()
-            .map(|inner| inner.is_invalid || (inner.indices.is_empty() && !inner.is_match))
-            .map_err(|_| anyhow!("error locking inner state"))
+    fn get_ranges(&self, py: Python<'_>) -> anyhow::Result<Vec<(u32, u32)>> {
+        with_lock(py, &self.inner, |inner| {
+            index_ranges(inner.indices.iter().map(|&i| i as usize))
+        })
     }
 
-    fn is_match(&self) -> anyhow::Result<bool> {
-        self.inner
-            .lock()
-            .map(|inner| inner.is_match)
-            .map_err(|_| anyhow!("error locking inner state"))
+    fn pack_mask_u32(&self, py: Python<'_>, out: &Bound<'_, PyArray1<u32>>) -> anyhow::Result<()> {
+        let indices = with_lock(py, &self.inner, |inner| inner.indices.clone())?;
+        pack_into(indices.as_slice().unwrap_or_default(), out)
+    }
+
+    fn get_mask<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<bool>>> {
+        let mut mask = Array1::from_elem(self.constraint.continuations().len(), false);
+        with_lock(py, &self.inner, |inner| {
+            for &index in &inner.indices {
+                mask[index as usize] = true;
+            }
+        })?;
+        Ok(mask.into_pyarray(py))
+    }
+
+    #[pyo3(signature = (max_len = 64))]
+    fn minimal_completion<'py>(
+        &self,
+        py: Python<'py>,
+        max_len: usize,
+    ) -> anyhow::Result<Option<Bound<'py, PyBytes>>> {
+        let state = with_lock(py, &self.inner, |inner| inner.state)?;
+        let completion = self.constraint.minimal_completion(&state, max_len);
+        Ok(completion.map(|bytes| PyBytes::new(py, &bytes)))
+    }
+
+    #[pyo3(signature = (max_count = 10, max_len = 16))]
+    fn enumerate_completions<'py>(
+        &self,
+        py: Python<'py>,
+        max_count: usize,
+        max_len: usize,
+    ) -> anyhow::Result<Vec<Bound<'py, PyBytes>>> {
+        let state = with_lock(py, &self.inner, |inner| inner.state)?;
+        let completions = py.detach(|| {
+            self.constraint
+                .enumerate_completions(&state, max_count, max_len)
+        });
+        Ok(completions
+            .iter()
+            .map(|bytes| PyBytes::new(py, bytes))
+            .collect())
     }
 
-    fn next(&self, index: usize) -> anyhow::Result<()> {
+    #[pyo3(signature = (max_len = 64, seed = None))]
+    fn sample<'py>(
+        &self,
+        py: Python<'py>,
+        max_len: usize,
+        seed: Option<u64>,
+    ) -> Option<Bound<'py, PyBytes>> {
+        let sample = py.detach(|| self.constraint.sample(&mut sample_rng(seed), max_len));
+        sample.map(|bytes| PyBytes::new(py, &bytes))
+    }
+
+    fn is_invalid(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| {
+            inner.is_invalid || (inner.indices.is_empty() && !inner.is_match)
+        })
+    }
+
+    fn is_match(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| inner.is_match)
+    }
+
+    fn states_equal(&self, a: &[u8], b: &[u8]) -> bool {
+        self.constraint.states_equal(a, b)
+    }
+
+    fn check(&self, text: TextOrBytes) -> bool {
+        self.constraint.check(text.as_ref())
+    }
+
+    fn check_detailed(&self, text: TextOrBytes) -> CheckReport {
+        self.constraint.check_detailed(text.as_ref()).into()
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn classify(
+        &self,
+        py: Python<'_>,
+        prefix: Option<TextOrBytes>,
+    ) -> anyhow::Result<Classification> {
+        if let Some(prefix) = prefix {
+            return Ok(self.constraint.classify(prefix.as_ref()).into());
+        }
+        with_lock(py, &self.inner, |inner| {
+            classify_current(inner.is_invalid, inner.is_match, &inner.indices)
+        })
+    }
+
+    fn last_valid_truncation(&self, text: TextOrBytes) -> Option<usize> {
+        self.constraint.last_valid_truncation(text.as_ref())
+    }
+
+    #[pyo3(signature = (token_ids, prefix = None))]
+    fn record_transcript(
+        &self,
+        py: Python<'_>,
+        token_ids: Vec<usize>,
+        prefix: Option<Vec<u8>>,
+    ) -> Transcript {
+        let prefix = prefix.unwrap_or_default();
+        py.detach(|| {
+            Transcript(RecordedTranscript::record(
+                self.constraint.as_ref(),
+                &prefix,
+                &token_ids,
+            ))
+        })
+    }
+
+    fn verify_transcript(&self, py: Python<'_>, transcript: &Transcript) -> TranscriptVerification {
+        py.detach(|| transcript.0.verify(self.constraint.as_ref()).into())
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn fingerprint(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<u64> {
+        let state = match prefix {
+            Some(prefix) => self
+                .constraint
+                .get_state(&prefix)
+                .ok_or_else(|| anyhow!("invalid prefix"))?,
+            None => with_lock(py, &self.inner, |inner| inner.state)?,
+        };
+        Ok(state_fingerprint(&state))
+    }
+
+    fn next(&self, py: Python<'_>, index: usize) -> anyhow::Result<()> {
         let inner = self.inner.clone();
         let constraint = self.constraint.clone();
-        let (tx, rx) = channel();
-        spawn_fifo(move || {
+        let policy = self.on_invalid;
+        spawn_next(py, index, policy, move |signal| {
             let mut inner = inner.lock().expect("error locking inner state");
-            tx.send(()).expect("failed to send on channel");
-            let Some(next_state) = constraint.get_next_state(&inner.state, index) else {
-                inner.is_invalid = true;
-                return;
+            if policy != InvalidPolicy::Raise {
+                signal(true);
+            }
+            let next_state = constraint.get_next_state(&inner.state, index);
+            if policy == InvalidPolicy::Raise {
+                signal(next_state.is_some());
+            }
+            let next_state = match (next_state, policy) {
+                (Some(next_state), _) => next_state,
+                (None, InvalidPolicy::Reset) => inner
+                    .last_match
+                    .unwrap_or_else(|| constraint.get_start_state()),
+                (None, InvalidPolicy::Sticky) => {
+                    inner.is_invalid = true;
+                    return;
+                }
+                (None, InvalidPolicy::Raise) => return,
             };
-            inner.state = next_state;
-            inner.indices = constraint
-                .get_valid_continuations(&inner.state)
+            if constraint.has_same_continuations(&inner.state, &next_state) {
+                return;
+            }
+            let indices = constraint
+                .get_valid_continuations(&next_state)
                 .into_iter()
                 .map(|v| v as i32)
                 .collect();
-            inner.is_match = constraint.is_match_state(&inner.state);
-        });
-        // wait until spawned thread signals that is has locked
-        // the inner state, otherwise some unexpected behavior could occurr
-        rx.recv()?;
-        Ok(())
+            let is_match = constraint.is_match_state(&next_state);
+            policy.remember(&mut inner.last_match, &next_state, is_match);
+            (inner.indices, inner.is_match, inner.state) = (indices, is_match, next_state);
+        })
+    }
+
+    fn memory_usage(&self, py: Python<'_>) -> anyhow::Result<usize> {
+        with_lock(py, &self.inner, |inner| {
+            self.memory.bytes() + inner.indices.len() * size_of::<i32>()
+        })
+    }
+
+    fn encode(&self, input: &[u8]) -> anyhow::Result<Vec<usize>> {
+        Ok(encode_with_constraint(self.constraint.as_ref(), input)?)
+    }
+
+    fn segment<'py>(&self, py: Python<'py>, output: &[u8]) -> anyhow::Result<Bound<'py, PyDict>> {
+        let segments = self
+            .constraint
+            .segment(output)
+            .ok_or_else(|| anyhow!("output does not match the regular expression"))?;
+        let dict = PyDict::new(py);
+        for (name, span) in segments {
+            dict.set_item(name, span)?;
+        }
+        Ok(dict)
     }
 }
 
@@ -170,18 +761,182 @@ struct LR1Inner {
     indices: Array1<i32>,
     is_match: bool,
     is_invalid: bool,
+    last_match: Option<LR1State>,
+    // consecutive steps that only advanced over skippable input
+    idle_steps: usize,
+}
+
+// valid continuations are stored compressed, permissive states
+// would otherwise take up most of the cache memory
+type LR1CacheEntry = (CompressedIndices, bool);
+
+struct LR1ConstraintCache {
+    // keyed by LR core state and lexer remainder, both hash consed, so keys
+    // hash and compare as integers; states of different beams that only
+    // differ in their pending lexeme share a core, and with it the parser
+    // part of computing their continuations
+    entries: TwoLevelCache<LR1Stack, Arc<StackLexemes>, LR1Matching, LR1CacheEntry>,
+    vocab_size: usize,
+    reservation: MemoryReservation<'static>,
+}
+
+// hash consed keys are counted in full, the cache keeps them alive
+fn cache_core_size(core: &LR1Stack, lexemes: &StackLexemes) -> usize {
+    size_of_val(core.as_slice())
+        + size_of::<LR1Stack>()
+        + lexemes.heap_size()
+        + size_of::<StackLexemes>()
+        + size_of::<HashMap<(), ()>>()
+}
+
+fn cache_entry_size(suffix: &LR1Matching, (indices, _): &LR1CacheEntry) -> usize {
+    size_of_val(suffix.as_slice())
+        + size_of::<LR1Matching>()
+        + indices.heap_size()
+        + size_of::<LR1CacheEntry>()
+}
+
+fn evicted_size(
+    core: Option<(&LR1Stack, &Arc<StackLexemes>)>,
+    suffix: &LR1Matching,
+    value: &LR1CacheEntry,
+) -> usize {
+    core.map(|(core, lexemes)| cache_core_size(core, lexemes))
+        .unwrap_or_default()
+        + cache_entry_size(suffix, value)
+}
+
+impl LR1ConstraintCache {
+    fn new(options: CacheOptions, vocab_size: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            entries: TwoLevelCache::new(options),
+            vocab_size,
+            reservation: MemoryBudget::global().reserve(0)?,
+        })
+    }
+
+    // the cached entry of the state, or else the lexemes of its
+    // stack if another state with the same stack is cached
+    fn get(&mut self, state: &LR1State) -> Result<(Array1<i32>, bool), Option<Arc<StackLexemes>>> {
+        let (core, suffix) = state.split();
+        if let Some((indices, is_match)) = self.entries.get(core, suffix) {
+            return Ok((Array1::from_vec(indices.decompress()), *is_match));
+        }
+        Err(self.entries.get_shared(core).cloned())
+    }
+
+    fn put(
+        &mut self,
+        state: LR1State,
+        lexemes: Arc<StackLexemes>,
+        (indices, is_match): (Array1<i32>, bool),
+    ) {
+        let (core, suffix) = state.into_split();
+        let indices = CompressedIndices::new(
+            indices.as_slice().expect("indices should be contiguous"),
+            self.vocab_size,
+        );
+        let value = (indices, is_match);
+        let mut size = cache_entry_size(&suffix, &value);
+        if !self.entries.contains_core(&core) {
+            size += cache_core_size(&core, &lexemes);
+        }
+        while self.reservation.grow(size).is_err() {
+            // budget is exhausted, make room by evicting our own entries
+            // if the policy allows it, otherwise skip caching
+            if MemoryBudget::global().policy() == MemoryPolicy::Deny {
+                return;
+            }
+            let mut freed = 0;
+            if !self
+                .entries
+                .pop(|core, suffix, value| freed += evicted_size(core, suffix, value))
+            {
+                return;
+            }
+            self.reservation.shrink(freed);
+            if !self.entries.contains_core(&core) {
+                size = cache_entry_size(&suffix, &value) + cache_core_size(&core, &lexemes);
+            }
+        }
+        let mut freed = 0;
+        self.entries
+            .insert(core, lexemes, suffix, value, |core, suffix, value| {
+                freed += evicted_size(core, suffix, value)
+            });
+        self.reservation.shrink(freed);
+    }
+
+    fn evict(&mut self, bytes: usize) -> usize {
+        let mut freed = 0;
+        while freed < bytes
+            && self
+                .entries
+                .pop(|core, suffix, value| freed += evicted_size(core, suffix, value))
+        {}
+        self.reservation.shrink(freed);
+        freed
+    }
 }
 
-type LR1ConstraintCache = LruCache<LR1State, (Array1<i32>, bool)>;
+impl Evictable for Mutex<LR1ConstraintCache> {
+    fn evict(&self, bytes: usize) -> usize {
+        // skip caches that are currently in use, e.g. the one
+        // that triggered the eviction
+        self.try_lock()
+            .map(|mut cache| cache.evict(bytes))
+            .unwrap_or(0)
+    }
+}
 
-#[pyclass]
+#[pyclass(frozen)]
 struct LR1Constraint {
     constraint: Arc<LR1Type>,
     inner: Arc<Mutex<LR1Inner>>,
     cache: Arc<Mutex<LR1ConstraintCache>>,
+    memory: Arc<MemoryReservation<'static>>,
+    on_invalid: InvalidPolicy,
+    stall: Option<StallWatchdog>,
 }
 
 impl LR1Type {
+    fn compile(
+        grammar: &str,
+        lexer: &str,
+        continuations: Vec<Vec<u8>>,
+        exact: bool,
+        whitespace: WhitespacePolicy,
+        progress: impl FnMut(CompileProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        let limits = CompileLimits::default();
+        Ok(if exact {
+            LR1Type::Exact(ExactLR1GrammarConstraint::compile_with_whitespace(
+                grammar,
+                lexer,
+                continuations,
+                whitespace,
+                &limits,
+                progress,
+            )?)
+        } else {
+            LR1Type::Regular(LR1GrammarConstraint::compile_with_whitespace(
+                grammar,
+                lexer,
+                continuations,
+                whitespace,
+                &limits,
+                progress,
+            )?)
+        })
+    }
+
+    fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        Ok(match self {
+            LR1Type::Exact(inner) => LR1Type::Exact(inner.with_continuations(continuations)?),
+            LR1Type::Regular(inner) => LR1Type::Regular(inner.with_continuations(continuations)?),
+        })
+    }
+
     fn get_state(&self, prefix: &[u8]) -> Option<LR1State> {
         match self {
             LR1Type::Exact(inner) => inner.get_state(prefix),
@@ -196,14 +951,27 @@ impl LR1Type {
         }
     }
 
-    fn get_valid_continuations(&self, state: &LR1State) -> Array1<i32> {
+    fn stack_lexemes(&self, state: &LR1State) -> StackLexemes {
         match self {
-            LR1Type::Exact(inner) => inner.get_valid_continuations(state),
-            LR1Type::Regular(inner) => inner.get_valid_continuations(state),
+            LR1Type::Exact(inner) => inner.stack_lexemes(state),
+            LR1Type::Regular(inner) => inner.stack_lexemes(state),
         }
-        .into_iter()
-        .map(|v| v as i32)
-        .collect()
+    }
+
+    // continuations and match status of the state, with the lexemes of its stack
+    fn continuations_with(&self, state: &LR1State, lexemes: &StackLexemes) -> (Array1<i32>, bool) {
+        let mut conts = vec![];
+        let is_match = match self {
+            LR1Type::Exact(inner) => {
+                inner.get_valid_continuations_with(state, lexemes, &mut conts);
+                inner.is_match_state_with(state, lexemes)
+            }
+            LR1Type::Regular(inner) => {
+                inner.get_valid_continuations_with(state, lexemes, &mut conts);
+                inner.is_match_state_with(state, lexemes)
+            }
+        };
+        (conts.into_iter().map(|v| v as i32).collect(), is_match)
     }
 
     fn get_next_state(&self, state: &LR1State, continuation: usize) -> Option<LR1State> {
@@ -220,261 +988,2857 @@ impl LR1Type {
         }
     }
 
-    fn only_skippable_matching(&self, state: &LR1State) -> bool {
+    fn has_same_continuations(&self, state: &LR1State, next: &LR1State) -> bool {
         match self {
-            LR1Type::Exact(inner) => inner.only_skippable_matching(state),
-            LR1Type::Regular(inner) => inner.only_skippable_matching(state),
+            LR1Type::Exact(inner) => inner.has_same_continuations(state, next),
+            LR1Type::Regular(inner) => inner.has_same_continuations(state, next),
         }
     }
-}
 
-impl LR1Constraint {
-    fn init(constraint: LR1Type, lru_cache_size: Option<usize>) -> Self {
-        let state = constraint.get_start_state();
-        let indices = constraint.get_valid_continuations(&state);
-        let is_match = constraint.is_match_state(&state);
-        // get cache size from env variable TEXT_UTILS_LR1_CACHE_SIZE
-        let cache_size = lru_cache_size
-            .and_then(NonZeroUsize::new)
-            .unwrap_or(NonZeroUsize::new(8192).unwrap());
-        let mut cache = LruCache::new(cache_size);
-        cache.put(state.clone(), (indices.clone(), is_match));
-        Self {
-            constraint: Arc::new(constraint),
-            inner: Arc::new(Mutex::new(LR1Inner {
-                state,
-                indices,
-                is_match,
-                is_invalid: false,
-            })),
-            cache: Arc::new(Mutex::new(cache)),
+    fn encode(&self, input: &[u8]) -> Result<Vec<usize>, EncodeError> {
+        match self {
+            LR1Type::Exact(inner) => encode_with_constraint(inner, input),
+            LR1Type::Regular(inner) => encode_with_constraint(inner, input),
         }
     }
-}
 
-#[pymethods]
-impl LR1Constraint {
-    #[new]
-    #[pyo3(signature = (grammar, lexer, continuations, exact=false, lru_cache_size=None))]
-    fn new(
-        grammar: &str,
-        lexer: &str,
-        continuations: Vec<Vec<u8>>,
-        exact: bool,
-        lru_cache_size: Option<usize>,
-    ) -> anyhow::Result<Self> {
-        let constraint = if exact {
-            LR1Type::Exact(
-                ExactLR1GrammarConstraint::new(grammar, lexer, continuations)
-                    .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?,
-            )
-        } else {
-            LR1Type::Regular(
-                LR1GrammarConstraint::new(grammar, lexer, continuations)
-                    .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?,
-            )
-        };
-        Ok(Self::init(constraint, lru_cache_size))
-    }
+    fn continuations(&self) -> &[Vec<u8>] {
+        match self {
+            LR1Type::Exact(inner) => inner.continuations(),
+            LR1Type::Regular(inner) => inner.continuations(),
+        }
+    }
+
+    fn check_detailed(&self, text: &[u8]) -> Report {
+        match self {
+            LR1Type::Exact(inner) => inner.check_detailed(text),
+            LR1Type::Regular(inner) => inner.check_detailed(text),
+        }
+    }
+
+    fn classify(&self, prefix: &[u8]) -> crate::Classification {
+        match self {
+            LR1Type::Exact(inner) => inner.classify(prefix),
+            LR1Type::Regular(inner) => inner.classify(prefix),
+        }
+    }
+
+    fn last_valid_truncation(&self, bytes: &[u8]) -> Option<usize> {
+        match self {
+            LR1Type::Exact(inner) => inner.last_valid_truncation(bytes),
+            LR1Type::Regular(inner) => inner.last_valid_truncation(bytes),
+        }
+    }
+
+    fn minimal_completion(&self, state: &LR1State, max_len: usize) -> Option<Vec<u8>> {
+        match self {
+            LR1Type::Exact(inner) => inner.minimal_completion(state, max_len),
+            LR1Type::Regular(inner) => inner.minimal_completion(state, max_len),
+        }
+    }
+
+    fn enumerate_completions(
+        &self,
+        state: &LR1State,
+        max_count: usize,
+        max_len: usize,
+    ) -> Vec<Vec<u8>> {
+        match self {
+            LR1Type::Exact(inner) => inner.enumerate_completions(state, max_count, max_len),
+            LR1Type::Regular(inner) => inner.enumerate_completions(state, max_count, max_len),
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng, max_len: usize) -> Option<Vec<u8>> {
+        match self {
+            LR1Type::Exact(inner) => inner.sample(rng, max_len),
+            LR1Type::Regular(inner) => inner.sample(rng, max_len),
+        }
+    }
+
+    fn only_skippable_matching(&self, state: &LR1State) -> bool {
+        match self {
+            LR1Type::Exact(inner) => inner.only_skippable_matching(state),
+            LR1Type::Regular(inner) => inner.only_skippable_matching(state),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        match self {
+            LR1Type::Exact(inner) => inner.memory_usage(),
+            LR1Type::Regular(inner) => inner.memory_usage(),
+        }
+    }
+
+    fn vocabulary_memory_usage(&self) -> usize {
+        match self {
+            LR1Type::Exact(inner) => inner.vocabulary_memory_usage(),
+            LR1Type::Regular(inner) => inner.vocabulary_memory_usage(),
+        }
+    }
+
+    fn expected_terminals(&self, state: &LR1State) -> Vec<String> {
+        let expected = match self {
+            LR1Type::Exact(inner) => inner.expected_terminals(state),
+            LR1Type::Regular(inner) => inner.expected_terminals(state),
+        };
+        expected.into_iter().map(String::from).collect()
+    }
+
+    fn explain(&self, state: &LR1State, continuation: usize) -> Option<crate::Explanation> {
+        match self {
+            LR1Type::Exact(inner) => inner.explain(state, continuation),
+            LR1Type::Regular(inner) => inner.explain(state, continuation),
+        }
+    }
+
+    fn openers(&self) -> BTreeMap<String, Vec<usize>> {
+        let openers = match self {
+            LR1Type::Exact(inner) => inner.openers(),
+            LR1Type::Regular(inner) => inner.openers(),
+        };
+        openers
+            .into_iter()
+            .map(|(name, indices)| (name.to_string(), indices))
+            .collect()
+    }
+}
+
+impl LR1Constraint {
+    fn cache_options(
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+    ) -> anyhow::Result<CacheOptions> {
+        let mut options = CacheOptions::new()
+            .policy(cache_policy.parse().map_err(|e: String| anyhow!(e))?)
+            .hasher(cache_hasher.parse().map_err(|e: String| anyhow!(e))?);
+        if let Some(capacity) = lru_cache_size.and_then(NonZeroUsize::new) {
+            options = options.capacity(capacity);
+        }
+        Ok(options)
+    }
+
+    fn init(
+        constraint: LR1Type,
+        cache_options: CacheOptions,
+        on_invalid: InvalidPolicy,
+        stall: Option<StallWatchdog>,
+    ) -> anyhow::Result<Self> {
+        let memory = MemoryBudget::global().reserve(constraint.memory_usage())?;
+        Self::init_with_memory(
+            Arc::new(constraint),
+            memory,
+            cache_options,
+            on_invalid,
+            stall,
+        )
+    }
+
+    // like init, but with the memory of the constraint already reserved,
+    // e.g. only the vocabulary part if the grammar is reserved elsewhere
+    fn init_with_memory(
+        constraint: Arc<LR1Type>,
+        memory: MemoryReservation<'static>,
+        cache_options: CacheOptions,
+        on_invalid: InvalidPolicy,
+        stall: Option<StallWatchdog>,
+    ) -> anyhow::Result<Self> {
+        let state = constraint.get_start_state();
+        let cache = Arc::new(Mutex::new(LR1ConstraintCache::new(
+            cache_options,
+            constraint.continuations().len(),
+        )?));
+        let (indices, is_match) = Self::continuations(&constraint, &cache, &state);
+        let evictable: Weak<dyn Evictable> = Arc::downgrade(&cache) as _;
+        MemoryBudget::global().register(evictable);
+        let mut last_match = None;
+        on_invalid.remember(&mut last_match, &state, is_match);
+        Ok(Self {
+            constraint,
+            inner: Arc::new(Mutex::new(LR1Inner {
+                state,
+                indices,
+                is_match,
+                is_invalid: false,
+                last_match,
+                idle_steps: 0,
+            })),
+            cache,
+            memory: Arc::new(memory),
+            on_invalid,
+            stall,
+        })
+    }
+
+    fn is_stalled(&self, inner: &LR1Inner) -> bool {
+        self.stall
+            .is_some_and(|stall| inner.idle_steps >= stall.steps)
+    }
+
+    fn stops_stalled(&self, inner: &LR1Inner) -> bool {
+        self.is_stalled(inner)
+            && self
+                .stall
+                .is_some_and(|stall| stall.policy == StallPolicy::Stop)
+    }
+
+    // whether the generation should end, either because only skippable
+    // input can follow a match or because it stalled in a match state
+    fn should_stop(&self, inner: &LR1Inner) -> bool {
+        inner.is_match
+            && (self.constraint.only_skippable_matching(&inner.state) || self.stops_stalled(inner))
+    }
+
+    // the cache is only locked for lookups and insertions, so that clones
+    // sharing it can compute continuations of different states in parallel
+    fn continuations(
+        constraint: &LR1Type,
+        cache: &Mutex<LR1ConstraintCache>,
+        state: &LR1State,
+    ) -> (Array1<i32>, bool) {
+        let lexemes = match cache.lock().expect("error locking cache").get(state) {
+            Ok(entry) => return entry,
+            Err(lexemes) => lexemes,
+        };
+        let lexemes = lexemes.unwrap_or_else(|| Arc::new(constraint.stack_lexemes(state)));
+        let (indices, is_match) = constraint.continuations_with(state, &lexemes);
+        cache.lock().expect("error locking cache").put(
+            state.clone(),
+            lexemes,
+            (indices.clone(), is_match),
+        );
+        (indices, is_match)
+    }
+}
+
+#[pymethods]
+impl LR1Constraint {
+    #[new]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        continuations,
+        exact=false,
+        lru_cache_size=None,
+        progress=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+        stall_steps=None,
+        on_stall="stop",
+        whitespace="preserve",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        continuations: PyContinuations,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        progress: Option<Bound<'_, PyAny>>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+        stall_steps: Option<usize>,
+        on_stall: &str,
+        whitespace: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let cache_options = Self::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let stall = StallWatchdog::parse(stall_steps, on_stall)?;
+        let whitespace: WhitespacePolicy = whitespace.parse().map_err(|e: String| anyhow!(e))?;
+        // stop calling the progress callback after its first error,
+        // and raise that error once compilation is done
+        let mut callback_error = None;
+        let last = Cell::new(CompileProgress::new(CompilePhase::Grammar, 0, None));
+        let mut report = |p: CompileProgress| {
+            last.set(p);
+            let Some(progress) = progress.as_ref() else {
+                return;
+            };
+            if callback_error.is_none() {
+                callback_error = progress.call1((p.phase.to_string(), p.done, p.total)).err();
+            }
+        };
+        let constraint = LR1Type::compile(
+            grammar,
+            lexer,
+            continuations,
+            exact,
+            whitespace,
+            &mut report,
+        )
+        .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?;
+        // like a background compile, done is only reported once the constraint exists
+        report(CompileProgress::new(
+            CompilePhase::Done,
+            last.get().done,
+            last.get().total,
+        ));
+        if let Some(e) = callback_error {
+            return Err(e.into());
+        }
+        Self::init(constraint, cache_options, on_invalid, stall)
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        continuations,
+        exact=false,
+        lru_cache_size=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+        stall_steps=None,
+        on_stall="stop",
+        whitespace="preserve",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn compile_in_background(
+        grammar: String,
+        lexer: String,
+        continuations: PyContinuations,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+        stall_steps: Option<usize>,
+        on_stall: &str,
+        whitespace: &str,
+    ) -> anyhow::Result<LR1Compilation> {
+        let continuations = continuations.0;
+        let cache_options = Self::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let stall = StallWatchdog::parse(stall_steps, on_stall)?;
+        let whitespace: WhitespacePolicy = whitespace.parse().map_err(|e: String| anyhow!(e))?;
+        let compile = BackgroundCompile::spawn(move |progress| {
+            LR1Type::compile(&grammar, &lexer, continuations, exact, whitespace, progress)
+        });
+        Ok(LR1Compilation {
+            compile: Mutex::new(Some(compile)),
+            cache_options,
+            on_invalid,
+            stall,
+        })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        grammar_path,
+        lexer_path,
+        continuations,
+        exact=false,
+        lru_cache_size=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+        stall_steps=None,
+        on_stall="stop",
+        whitespace="preserve",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_files(
+        grammar_path: &str,
+        lexer_path: &str,
+        continuations: PyContinuations,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+        stall_steps: Option<usize>,
+        on_stall: &str,
+        whitespace: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let cache_options = Self::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let stall = StallWatchdog::parse(stall_steps, on_stall)?;
+        let whitespace: WhitespacePolicy = whitespace.parse().map_err(|e: String| anyhow!(e))?;
+        let grammar = fs::read_to_string(grammar_path)?;
+        let lexer = fs::read_to_string(lexer_path)?;
+        let constraint =
+            LR1Type::compile(&grammar, &lexer, continuations, exact, whitespace, |_| {})
+                .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?;
+        Self::init(constraint, cache_options, on_invalid, stall)
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        schema,
+        continuations,
+        exact=false,
+        lru_cache_size=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+        stall_steps=None,
+        on_stall="stop",
+        whitespace="preserve",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_json_schema(
+        schema: &str,
+        continuations: PyContinuations,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+        stall_steps: Option<usize>,
+        on_stall: &str,
+        whitespace: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let cache_options = Self::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let stall = StallWatchdog::parse(stall_steps, on_stall)?;
+        let whitespace: WhitespacePolicy = whitespace.parse().map_err(|e: String| anyhow!(e))?;
+        let constraint = json_schema_to_lr1(schema)
+            .and_then(|(grammar, lexer)| {
+                let grammar = inline_rules(&grammar)?;
+                LR1Type::compile(
+                    grammar.grammar(),
+                    &lexer,
+                    continuations,
+                    exact,
+                    whitespace,
+                    |_| {},
+                )
+            })
+            .map_err(|e| anyhow!("failed to create json schema constraint: {}", e))?;
+        Self::init(constraint, cache_options, on_invalid, stall)
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        proto,
+        message,
+        continuations,
+        format="text",
+        exact=false,
+        lru_cache_size=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+        stall_steps=None,
+        on_stall="stop",
+        whitespace="preserve",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_proto(
+        proto: &str,
+        message: &str,
+        continuations: PyContinuations,
+        format: &str,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+        stall_steps: Option<usize>,
+        on_stall: &str,
+        whitespace: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let format: ProtoFormat = format.parse().map_err(|e: String| anyhow!(e))?;
+        let cache_options = Self::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let stall = StallWatchdog::parse(stall_steps, on_stall)?;
+        let whitespace: WhitespacePolicy = whitespace.parse().map_err(|e: String| anyhow!(e))?;
+        let constraint = proto_to_lr1(proto, message, format)
+            .and_then(|(grammar, lexer)| {
+                let grammar = inline_rules(&grammar)?;
+                LR1Type::compile(
+                    grammar.grammar(),
+                    &lexer,
+                    continuations,
+                    exact,
+                    whitespace,
+                    |_| {},
+                )
+            })
+            .map_err(|e| anyhow!("failed to create proto constraint: {}", e))?;
+        Self::init(constraint, cache_options, on_invalid, stall)
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        dtd,
+        root,
+        continuations,
+        exact=false,
+        lru_cache_size=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+        stall_steps=None,
+        on_stall="stop",
+        whitespace="preserve",
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_dtd(
+        dtd: &str,
+        root: &str,
+        continuations: PyContinuations,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+        stall_steps: Option<usize>,
+        on_stall: &str,
+        whitespace: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let cache_options = Self::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let stall = StallWatchdog::parse(stall_steps, on_stall)?;
+        let whitespace: WhitespacePolicy = whitespace.parse().map_err(|e: String| anyhow!(e))?;
+        let constraint = dtd_to_lr1(dtd, root)
+            .and_then(|(grammar, lexer)| {
+                let grammar = inline_rules(&grammar)?;
+                LR1Type::compile(
+                    grammar.grammar(),
+                    &lexer,
+                    continuations,
+                    exact,
+                    whitespace,
+                    |_| {},
+                )
+            })
+            .map_err(|e| anyhow!("failed to create dtd constraint: {}", e))?;
+        Self::init(constraint, cache_options, on_invalid, stall)
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn reset(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
+        let Some(state) = self.constraint.get_state(&prefix.unwrap_or_default()) else {
+            return Err(anyhow!("failed to reset to given prefix"));
+        };
+        let (indices, is_match) =
+            py.detach(|| Self::continuations(&self.constraint, &self.cache, &state));
+        let mut last_match = None;
+        self.on_invalid.remember(&mut last_match, &state, is_match);
+        with_lock(py, &self.inner, |inner| {
+            *inner = LR1Inner {
+                state,
+                indices,
+                is_match,
+                is_invalid: false,
+                last_match,
+                idle_steps: 0,
+            }
+        })
+    }
+
+    fn clone(&self, py: Python<'_>) -> anyhow::Result<Self> {
+        with_lock(py, &self.inner, |inner| Self {
+            constraint: self.constraint.clone(),
+            inner: Arc::new(Mutex::new(inner.clone())),
+            cache: self.cache.clone(),
+            memory: self.memory.clone(),
+            on_invalid: self.on_invalid,
+            stall: self.stall,
+        })
+    }
+
+    fn get<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<i32>>> {
+        with_lock(py, &self.inner, |inner| {
+            if self.should_stop(inner) {
+                // should stop, return empty indices
+                vec![].into()
+            } else {
+                inner.indices.clone()
+            }
+        })
+        .map(|indices| indices.into_pyarray(py))
+    }
+
+    fn get_ranges(&self, py: Python<'_>) -> anyhow::Result<Vec<(u32, u32)>> {
+        with_lock(py, &self.inner, |inner| {
+            if self.should_stop(inner) {
+                vec![]
+            } else {
+                index_ranges(inner.indices.iter().map(|&i| i as usize))
+            }
+        })
+    }
+
+    fn pack_mask_u32(&self, py: Python<'_>, out: &Bound<'_, PyArray1<u32>>) -> anyhow::Result<()> {
+        let indices = with_lock(py, &self.inner, |inner| {
+            if self.should_stop(inner) {
+                vec![].into()
+            } else {
+                inner.indices.clone()
+            }
+        })?;
+        pack_into(indices.as_slice().unwrap_or_default(), out)
+    }
+
+    fn is_invalid(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| {
+            inner.is_invalid
+                || (inner.indices.is_empty() && !inner.is_match)
+                || (!inner.is_match && self.stops_stalled(inner))
+        })
+    }
+
+    #[pyo3(name = "is_stalled")]
+    fn py_is_stalled(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| self.is_stalled(inner))
+    }
+
+    fn is_match(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| inner.is_match)
+    }
+
+    fn expected_terminals(&self, py: Python<'_>) -> anyhow::Result<Vec<String>> {
+        with_lock(py, &self.inner, |inner| {
+            self.constraint.expected_terminals(&inner.state)
+        })
+    }
+
+    #[pyo3(signature = (max_len = 64))]
+    fn minimal_completion<'py>(
+        &self,
+        py: Python<'py>,
+        max_len: usize,
+    ) -> anyhow::Result<Option<Bound<'py, PyBytes>>> {
+        let state = with_lock(py, &self.inner, |inner| inner.state.clone())?;
+        let completion = py.detach(|| self.constraint.minimal_completion(&state, max_len));
+        Ok(completion.map(|bytes| PyBytes::new(py, &bytes)))
+    }
+
+    #[pyo3(signature = (max_count = 10, max_len = 16))]
+    fn enumerate_completions<'py>(
+        &self,
+        py: Python<'py>,
+        max_count: usize,
+        max_len: usize,
+    ) -> anyhow::Result<Vec<Bound<'py, PyBytes>>> {
+        let state = with_lock(py, &self.inner, |inner| inner.state.clone())?;
+        let completions = py.detach(|| {
+            self.constraint
+                .enumerate_completions(&state, max_count, max_len)
+        });
+        Ok(completions
+            .iter()
+            .map(|bytes| PyBytes::new(py, bytes))
+            .collect())
+    }
+
+    #[pyo3(signature = (max_len = 64, seed = None))]
+    fn sample<'py>(
+        &self,
+        py: Python<'py>,
+        max_len: usize,
+        seed: Option<u64>,
+    ) -> Option<Bound<'py, PyBytes>> {
+        let sample = py.detach(|| self.constraint.sample(&mut sample_rng(seed), max_len));
+        sample.map(|bytes| PyBytes::new(py, &bytes))
+    }
+
+    #[pyo3(signature = (index, prefix = None))]
+    fn explain(
+        &self,
+        py: Python<'_>,
+        index: usize,
+        prefix: Option<Vec<u8>>,
+    ) -> anyhow::Result<Explanation> {
+        let state = match prefix {
+            Some(prefix) => self
+                .constraint
+                .get_state(&prefix)
+                .ok_or_else(|| anyhow!("invalid prefix"))?,
+            None => with_lock(py, &self.inner, |inner| inner.state.clone())?,
+        };
+        self.constraint
+            .explain(&state, index)
+            .map(Explanation::from)
+            .ok_or_else(|| anyhow!("continuation index {index} out of range"))
+    }
+
+    fn openers(&self, py: Python<'_>) -> BTreeMap<String, Vec<usize>> {
+        py.detach(|| self.constraint.openers())
+    }
+
+    #[pyo3(signature = (other, max_len = 16))]
+    fn distinguish<'py>(
+        &self,
+        py: Python<'py>,
+        other: &LR1Constraint,
+        max_len: usize,
+    ) -> anyhow::Result<PyDistinction<'py>> {
+        let continuations = self.constraint.continuations();
+        if continuations != other.constraint.continuations() {
+            return Err(anyhow!("constraints have different continuations"));
+        }
+        let distinction = py.detach(|| {
+            distinguish(
+                &SharedLR1(self.constraint.clone()),
+                &SharedLR1(other.constraint.clone()),
+                continuations,
+                max_len,
+            )
+        });
+        Ok(py_distinction(py, distinction))
+    }
+
+    fn states_equal(&self, a: &[u8], b: &[u8]) -> bool {
+        match (self.constraint.get_state(a), self.constraint.get_state(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn check(&self, text: TextOrBytes) -> bool {
+        self.constraint
+            .get_state(text.as_ref())
+            .is_some_and(|state| self.constraint.is_match_state(&state))
+    }
+
+    fn check_detailed(&self, text: TextOrBytes) -> CheckReport {
+        self.constraint.check_detailed(text.as_ref()).into()
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn classify(
+        &self,
+        py: Python<'_>,
+        prefix: Option<TextOrBytes>,
+    ) -> anyhow::Result<Classification> {
+        if let Some(prefix) = prefix {
+            return Ok(self.constraint.classify(prefix.as_ref()).into());
+        }
+        with_lock(py, &self.inner, |inner| {
+            classify_current(inner.is_invalid, inner.is_match, &inner.indices)
+        })
+    }
+
+    fn last_valid_truncation(&self, text: TextOrBytes) -> Option<usize> {
+        self.constraint.last_valid_truncation(text.as_ref())
+    }
+
+    #[pyo3(signature = (token_ids, prefix = None))]
+    fn record_transcript(
+        &self,
+        py: Python<'_>,
+        token_ids: Vec<usize>,
+        prefix: Option<Vec<u8>>,
+    ) -> Transcript {
+        let prefix = prefix.unwrap_or_default();
+        py.detach(|| {
+            Transcript(RecordedTranscript::record(
+                &SharedLR1(self.constraint.clone()),
+                &prefix,
+                &token_ids,
+            ))
+        })
+    }
+
+    fn verify_transcript(&self, py: Python<'_>, transcript: &Transcript) -> TranscriptVerification {
+        py.detach(|| {
+            transcript
+                .0
+                .verify(&SharedLR1(self.constraint.clone()))
+                .into()
+        })
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn fingerprint(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<u64> {
+        let state = match prefix {
+            Some(prefix) => self
+                .constraint
+                .get_state(&prefix)
+                .ok_or_else(|| anyhow!("invalid prefix"))?,
+            None => with_lock(py, &self.inner, |inner| inner.state.clone())?,
+        };
+        Ok(state_fingerprint(&state))
+    }
+
+    fn encode(&self, input: &[u8]) -> anyhow::Result<Vec<usize>> {
+        Ok(self.constraint.encode(input)?)
+    }
+
+    fn memory_usage(&self, py: Python<'_>) -> anyhow::Result<usize> {
+        let cached = self
+            .cache
+            .lock()
+            .map_err(|_| anyhow!("error locking cache"))?
+            .reservation
+            .bytes();
+        with_lock(py, &self.inner, |inner| {
+            self.memory.bytes()
+                + cached
+                + inner.state.memory_usage()
+                + inner.indices.len() * size_of::<i32>()
+        })
+    }
+
+    fn next(&self, py: Python<'_>, index: usize) -> anyhow::Result<()> {
+        let inner = self.inner.clone();
+        let constraint = self.constraint.clone();
+        let cache = self.cache.clone();
+        let policy = self.on_invalid;
+        let watch = self.stall.is_some();
+        spawn_next(py, index, policy, move |signal| {
+            let mut inner = inner.lock().expect("error locking inner state");
+            if policy != InvalidPolicy::Raise {
+                signal(true);
+            }
+            let next_state = constraint.get_next_state(&inner.state, index);
+            if policy == InvalidPolicy::Raise {
+                signal(next_state.is_some());
+            }
+            let (next_state, idle) = match (next_state, policy) {
+                (Some(next_state), _) => {
+                    let idle = watch
+                        && next_state.same_stack(&inner.state)
+                        && constraint.only_skippable_matching(&next_state);
+                    (next_state, idle)
+                }
+                (None, InvalidPolicy::Reset) => (
+                    inner
+                        .last_match
+                        .clone()
+                        .unwrap_or_else(|| constraint.get_start_state()),
+                    false,
+                ),
+                (None, InvalidPolicy::Sticky) => {
+                    inner.is_invalid = true;
+                    return;
+                }
+                (None, InvalidPolicy::Raise) => return,
+            };
+            inner.idle_steps = if idle { inner.idle_steps + 1 } else { 0 };
+            if constraint.has_same_continuations(&inner.state, &next_state) {
+                return;
+            }
+            let (indices, is_match) =
+                LR1Constraint::continuations(&constraint, &cache, &next_state);
+            policy.remember(&mut inner.last_match, &next_state, is_match);
+            (inner.indices, inner.is_match, inner.state) = (indices, is_match, next_state);
+        })
+    }
+}
+
+#[pyclass(frozen)]
+struct LR1Compilation {
+    compile: Mutex<Option<BackgroundCompile<LR1Type>>>,
+    cache_options: CacheOptions,
+    on_invalid: InvalidPolicy,
+    stall: Option<StallWatchdog>,
+}
+
+#[pymethods]
+impl LR1Compilation {
+    fn progress(&self) -> anyhow::Result<(String, usize, Option<usize>)> {
+        let compile = self
+            .compile
+            .lock()
+            .map_err(|_| anyhow!("error locking compilation"))?;
+        let progress = compile
+            .as_ref()
+            .ok_or_else(|| anyhow!("compilation result was already retrieved"))?
+            .progress();
+        Ok((progress.phase.to_string(), progress.done, progress.total))
+    }
+
+    fn is_ready(&self) -> anyhow::Result<bool> {
+        let compile = self
+            .compile
+            .lock()
+            .map_err(|_| anyhow!("error locking compilation"))?;
+        Ok(compile.as_ref().is_none_or(|c| c.is_ready()))
+    }
+
+    fn result(&self, py: Python<'_>) -> anyhow::Result<LR1Constraint> {
+        let compile = self
+            .compile
+            .lock()
+            .map_err(|_| anyhow!("error locking compilation"))?
+            .take()
+            .ok_or_else(|| anyhow!("compilation result was already retrieved"))?;
+        let constraint = py
+            .detach(|| compile.join())
+            .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?;
+        LR1Constraint::init(constraint, self.cache_options, self.on_invalid, self.stall)
+    }
+}
+
+// one compiled LR(1) grammar serving several vocabularies, e.g. of models
+// with different tokenizers; each vocabulary gets its own constraint
+// sharing the grammar, and all constraints of a vocabulary share a cache;
+// get uses the caches of the grammar shared by all vocabularies instead;
+// the grammar is reserved once, each vocabulary only reserves its own memory
+#[pyclass(frozen)]
+struct MultiVocabConstraint {
+    shared: MultiVocab<LR1Type>,
+    memory: MemoryReservation<'static>,
+    vocabularies: Mutex<IndexMap<String, LR1Constraint>>,
+    cache_options: CacheOptions,
+    on_invalid: InvalidPolicy,
+}
+
+impl MultiVocabConstraint {
+    fn with_vocabulary<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&LR1Constraint) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let vocabularies = self
+            .vocabularies
+            .lock()
+            .map_err(|_| anyhow!("error locking vocabularies"))?;
+        let constraint = vocabularies
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown vocabulary {name}"))?;
+        f(constraint)
+    }
+}
+
+#[pymethods]
+impl MultiVocabConstraint {
+    #[new]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        exact=false,
+        lru_cache_size=None,
+        cache_policy="lru",
+        cache_hasher="siphash",
+        on_invalid="sticky",
+    ))]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        exact: bool,
+        lru_cache_size: Option<usize>,
+        cache_policy: &str,
+        cache_hasher: &str,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let cache_options =
+            LR1Constraint::cache_options(lru_cache_size, cache_policy, cache_hasher)?;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let grammar = LR1Type::compile(
+            grammar,
+            lexer,
+            vec![],
+            exact,
+            WhitespacePolicy::Preserve,
+            |_| {},
+        )
+        .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?;
+        let memory = MemoryBudget::global().reserve(grammar.memory_usage())?;
+        Ok(Self {
+            shared: MultiVocab::new(grammar),
+            memory,
+            vocabularies: Mutex::default(),
+            cache_options,
+            on_invalid,
+        })
+    }
+
+    fn add_vocabulary(&self, name: &str, continuations: PyContinuations) -> anyhow::Result<()> {
+        let mut vocabularies = self
+            .vocabularies
+            .lock()
+            .map_err(|_| anyhow!("error locking vocabularies"))?;
+        if vocabularies.contains_key(name) {
+            return Err(anyhow!("vocabulary {name} already exists"));
+        }
+        let constraint = self
+            .shared
+            .add_vocabulary(name, continuations.0)
+            .map_err(|e| anyhow!("failed to add vocabulary {name}: {e}"))?;
+        let init = || {
+            let memory = MemoryBudget::global().reserve(constraint.vocabulary_memory_usage())?;
+            LR1Constraint::init_with_memory(
+                constraint.clone(),
+                memory,
+                self.cache_options,
+                self.on_invalid,
+                None,
+            )
+        };
+        let constraint = init().inspect_err(|_| {
+            self.shared.remove_vocabulary(name);
+        })?;
+        vocabularies.insert(name.to_string(), constraint);
+        Ok(())
+    }
+
+    fn remove_vocabulary(&self, name: &str) -> anyhow::Result<bool> {
+        let mut vocabularies = self
+            .vocabularies
+            .lock()
+            .map_err(|_| anyhow!("error locking vocabularies"))?;
+        self.shared.remove_vocabulary(name);
+        Ok(vocabularies.shift_remove(name).is_some())
+    }
+
+    fn vocabularies(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .vocabularies
+            .lock()
+            .map_err(|_| anyhow!("error locking vocabularies"))?
+            .keys()
+            .cloned()
+            .collect())
+    }
+
+    fn constraint(&self, py: Python<'_>, name: &str) -> anyhow::Result<LR1Constraint> {
+        self.with_vocabulary(name, |constraint| constraint.clone(py))
+    }
+
+    // the grammar once, plus the memory of every vocabulary and its cache
+    fn memory_usage(&self, py: Python<'_>) -> anyhow::Result<usize> {
+        let vocabularies = self
+            .vocabularies
+            .lock()
+            .map_err(|_| anyhow!("error locking vocabularies"))?;
+        vocabularies
+            .values()
+            .try_fold(self.memory.bytes(), |total, constraint| {
+                Ok(total + constraint.memory_usage(py)?)
+            })
+    }
+
+    fn get<'py>(
+        &self,
+        py: Python<'py>,
+        name: &str,
+        prefix: &[u8],
+    ) -> anyhow::Result<Bound<'py, PyArray1<i32>>> {
+        // continuations already checked for another vocabulary are reused
+        let indices = py.detach(|| {
+            let state = self
+                .shared
+                .get_state(prefix)
+                .ok_or_else(|| anyhow!("prefix is not valid for the grammar"))?;
+            self.shared
+                .get_valid_continuations(name, &state)
+                .map_err(|e| anyhow!("{e}"))
+        })?;
+        Ok(indices
+            .into_iter()
+            .map(|i| i as i32)
+            .collect::<Array1<i32>>()
+            .into_pyarray(py))
+    }
+}
+
+// lets a MultiVocab share the caches of the grammar between vocabularies,
+// independent of the LR(1) constraint type
+impl Constraint for LR1Type {
+    type State = LR1State;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<LR1State> {
+        LR1Type::get_state(self, prefix)
+    }
+
+    fn get_start_state(&self) -> LR1State {
+        LR1Type::get_start_state(self)
+    }
+
+    fn is_match_state(&self, state: &LR1State) -> bool {
+        LR1Type::is_match_state(self, state)
+    }
+
+    fn get_valid_continuations(&self, state: &LR1State) -> Vec<usize> {
+        match self {
+            LR1Type::Exact(inner) => inner.get_valid_continuations(state),
+            LR1Type::Regular(inner) => inner.get_valid_continuations(state),
+        }
+    }
+
+    fn get_next_state(&self, state: &LR1State, continuation: usize) -> Option<LR1State> {
+        LR1Type::get_next_state(self, state, continuation)
+    }
+}
+
+impl ByteConstraint for LR1Type {
+    fn continuations(&self) -> &[Vec<u8>] {
+        LR1Type::continuations(self)
+    }
+
+    fn get_next_state_with_bytes(&self, state: &LR1State, bytes: &[u8]) -> Option<LR1State> {
+        match self {
+            LR1Type::Exact(inner) => inner.get_next_state_with_bytes(state, bytes),
+            LR1Type::Regular(inner) => inner.get_next_state_with_bytes(state, bytes),
+        }
+    }
+}
+
+impl WithContinuations for LR1Type {
+    fn with_continuations(&self, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        LR1Type::with_continuations(self, continuations)
+    }
+
+    fn shared_memory_usage(&self) -> usize {
+        match self {
+            LR1Type::Exact(inner) => inner.shared_memory_usage(),
+            LR1Type::Regular(inner) => inner.shared_memory_usage(),
+        }
+    }
+
+    fn vocabulary_memory_usage(&self) -> usize {
+        LR1Type::vocabulary_memory_usage(self)
+    }
+
+    fn is_valid_continuation(&self, state: &LR1State, continuation: usize) -> bool {
+        match self {
+            LR1Type::Exact(inner) => {
+                WithContinuations::is_valid_continuation(inner, state, continuation)
+            }
+            LR1Type::Regular(inner) => {
+                WithContinuations::is_valid_continuation(inner, state, continuation)
+            }
+        }
+    }
+}
+
+// shares the constraint of an LR1Constraint, e.g. with a scheduler
+struct SharedLR1(Arc<LR1Type>);
+
+impl Constraint for SharedLR1 {
+    type State = LR1State;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<LR1State> {
+        self.0.get_state(prefix)
+    }
+
+    fn get_start_state(&self) -> LR1State {
+        self.0.get_start_state()
+    }
+
+    fn is_match_state(&self, state: &LR1State) -> bool {
+        self.0.is_match_state(state)
+    }
+
+    fn get_valid_continuations(&self, state: &LR1State) -> Vec<usize> {
+        match self.0.as_ref() {
+            LR1Type::Exact(inner) => inner.get_valid_continuations(state),
+            LR1Type::Regular(inner) => inner.get_valid_continuations(state),
+        }
+    }
+
+    fn get_next_state(&self, state: &LR1State, continuation: usize) -> Option<LR1State> {
+        self.0.get_next_state(state, continuation)
+    }
+}
+
+impl ByteConstraint for SharedLR1 {
+    fn continuations(&self) -> &[Vec<u8>] {
+        self.0.continuations()
+    }
+
+    fn get_next_state_with_bytes(&self, state: &LR1State, bytes: &[u8]) -> Option<LR1State> {
+        match self.0.as_ref() {
+            LR1Type::Exact(inner) => inner.get_next_state_with_bytes(state, bytes),
+            LR1Type::Regular(inner) => inner.get_next_state_with_bytes(state, bytes),
+        }
+    }
+}
+
+#[pyclass(frozen)]
+struct ConstraintScheduler(Option<Scheduler<SharedLR1>>);
+
+impl Drop for ConstraintScheduler {
+    // dropping waits for queued requests, whose callbacks need the gil
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.0.take() {
+            Python::attach(|py| py.detach(move || drop(scheduler)));
+        }
+    }
+}
+
+impl ConstraintScheduler {
+    fn scheduler(&self) -> &Scheduler<SharedLR1> {
+        self.0.as_ref().expect("scheduler is only taken on drop")
+    }
+
+    fn submit(&self, request: ScheduledRequest, callback: Py<PyAny>) -> anyhow::Result<()> {
+        // called with the result and none, or none and an error message
+        self.scheduler()
+            .try_submit(request, move |response| {
+                Python::attach(|py| {
+                    let args = match response {
+                        Ok(ScheduledResponse::Advance(valid)) => (
+                            valid.into_pyobject(py)?.to_owned().into_any().unbind(),
+                            py.None(),
+                        ),
+                        Ok(ScheduledResponse::Mask(mask)) => {
+                            let indices: Array1<i32> =
+                                mask.continuations.into_iter().map(|v| v as i32).collect();
+                            let value = (indices.into_pyarray(py), mask.is_match);
+                            (value.into_pyobject(py)?.into_any().unbind(), py.None())
+                        }
+                        Err(e) => (
+                            py.None(),
+                            e.to_string().into_pyobject(py)?.into_any().unbind(),
+                        ),
+                    };
+                    callback.call1(py, args).map(|_| ())
+                })
+                .unwrap_or_else(|e| Python::attach(|py| e.write_unraisable(py, None)))
+            })
+            .map_err(|e| anyhow!("failed to schedule request: {e}"))
+    }
+}
+
+#[pymethods]
+impl ConstraintScheduler {
+    #[new]
+    #[pyo3(signature = (
+        constraint,
+        num_threads=0,
+        batch_size=64,
+        max_pending=1024,
+        max_delay=0.001,
+        session_ttl=None,
+        on_evict=None,
+    ))]
+    fn new(
+        constraint: &LR1Constraint,
+        num_threads: usize,
+        batch_size: usize,
+        max_pending: usize,
+        max_delay: f64,
+        session_ttl: Option<f64>,
+        on_evict: Option<Py<PyAny>>,
+    ) -> anyhow::Result<Self> {
+        let options = SchedulerOptions {
+            num_threads,
+            batch_size,
+            max_pending,
+            max_delay: Duration::try_from_secs_f64(max_delay)?,
+            session_ttl: session_ttl.map(Duration::try_from_secs_f64).transpose()?,
+        };
+        let scheduler = Scheduler::new(SharedLR1(constraint.constraint.clone()), options)
+            .map_err(|e| anyhow!("failed to create constraint scheduler: {e}"))?;
+        if let Some(on_evict) = on_evict {
+            scheduler.on_evict(move |session| {
+                Python::attach(|py| {
+                    if let Err(e) = on_evict.call1(py, (session,)) {
+                        e.write_unraisable(py, None);
+                    }
+                })
+            });
+        }
+        Ok(Self(Some(scheduler)))
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn open_session(&self, prefix: Option<Vec<u8>>) -> anyhow::Result<SessionId> {
+        self.scheduler()
+            .open_session(&prefix.unwrap_or_default())
+            .ok_or_else(|| anyhow!("failed to open session at given prefix"))
+    }
+
+    fn close_session(&self, session: SessionId) -> bool {
+        self.scheduler().close_session(session)
+    }
+
+    fn pending(&self) -> usize {
+        self.scheduler().pending()
+    }
+
+    fn evict_idle(&self, py: Python<'_>) -> Vec<SessionId> {
+        // the dispatcher might wait for the gil while sweeping
+        py.detach(|| self.scheduler().evict_idle())
+    }
+
+    fn submit_next(
+        &self,
+        session: SessionId,
+        index: usize,
+        callback: Py<PyAny>,
+    ) -> anyhow::Result<()> {
+        self.submit(ScheduledRequest::Advance(session, index), callback)
+    }
+
+    fn submit_get(&self, session: SessionId, callback: Py<PyAny>) -> anyhow::Result<()> {
+        self.submit(ScheduledRequest::Mask(session), callback)
+    }
+}
+
+// continuations given as any iterable of bytes or lists of byte values, e.g. a
+// generator, or as a callable returning one; they are converted one at a time,
+// so the vocabulary never has to exist as a python list next to the rust copy
+pub struct PyContinuations(Vec<Vec<u8>>);
+
+impl FromPyObject<'_, '_> for PyContinuations {
+    type Error = PyErr;
+
+    fn extract(obj: Borrowed<'_, '_, PyAny>) -> PyResult<Self> {
+        let iterable = if obj.is_callable() {
+            obj.call0()?
+        } else {
+            obj.to_owned()
+        };
+        // generators have no length
+        let mut continuations = Vec::with_capacity(iterable.len().unwrap_or_default());
+        for item in iterable.try_iter()? {
+            continuations.push(item?.extract()?);
+        }
+        continuations.shrink_to_fit();
+        Ok(Self(continuations))
+    }
+}
+
+#[derive(FromPyObject)]
+pub enum TextOrBytes {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl AsRef<[u8]> for TextOrBytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            TextOrBytes::Text(text) => text.as_bytes(),
+            TextOrBytes::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+// csv column types, a regex pattern, a list of allowed values or an
+// inclusive integer range; free text columns are given as None
+#[derive(FromPyObject)]
+pub enum PyCsvColumn {
+    Regex(String),
+    Enum(Vec<String>),
+    Integer((i64, i64)),
+}
+
+impl From<PyCsvColumn> for CsvColumn {
+    fn from(column: PyCsvColumn) -> Self {
+        match column {
+            PyCsvColumn::Regex(pattern) => CsvColumn::Regex(pattern),
+            PyCsvColumn::Enum(options) => CsvColumn::Enum(options),
+            PyCsvColumn::Integer((min, max)) => CsvColumn::Integer { min, max },
+        }
+    }
+}
+
+#[pyclass(frozen, get_all)]
+pub struct CheckReport {
+    is_match: bool,
+    is_prefix: bool,
+    valid_up_to: usize,
+}
+
+impl From<Report> for CheckReport {
+    fn from(report: Report) -> Self {
+        Self {
+            is_match: report.is_match,
+            is_prefix: report.is_prefix,
+            valid_up_to: report.valid_up_to,
+        }
+    }
+}
+
+#[pymethods]
+impl CheckReport {
+    fn __repr__(&self) -> String {
+        format!(
+            "CheckReport(is_match={}, is_prefix={}, valid_up_to={})",
+            if self.is_match { "True" } else { "False" },
+            if self.is_prefix { "True" } else { "False" },
+            self.valid_up_to
+        )
+    }
+}
+
+#[pyclass(frozen, get_all)]
+pub struct Explanation {
+    index: usize,
+    is_valid: bool,
+    continues_lexeme: bool,
+    completed: Vec<(Option<String>, (usize, usize))>,
+    pending: Vec<String>,
+    // kind of the rejection, e.g. unexpected_terminal, none if valid
+    rejection: Option<&'static str>,
+    // human readable rejection reason, none if valid
+    reason: Option<String>,
+    // byte offset in the continuation for no_lexer_match rejections
+    offset: Option<usize>,
+    // rejected terminal or candidates of the rejected lexeme
+    rejected: Vec<String>,
+    // terminals the parser would have accepted instead
+    expected: Vec<String>,
+}
+
+impl From<crate::Explanation> for Explanation {
+    fn from(explanation: crate::Explanation) -> Self {
+        let reason = explanation.rejection.as_ref().map(|r| r.to_string());
+        let (rejection, offset, rejected, expected) = match explanation.rejection {
+            None => (None, None, vec![], vec![]),
+            Some(Rejection::NoLexerMatch { offset }) => {
+                (Some("no_lexer_match"), Some(offset), vec![], vec![])
+            }
+            Some(Rejection::TooManyTokens { .. }) => {
+                (Some("too_many_tokens"), None, vec![], vec![])
+            }
+            Some(Rejection::UnexpectedTerminal { terminal, expected }) => {
+                (Some("unexpected_terminal"), None, vec![terminal], expected)
+            }
+            Some(Rejection::UnexpectedLexeme {
+                candidates,
+                expected,
+            }) => (Some("unexpected_lexeme"), None, candidates, expected),
+        };
+        Self {
+            index: explanation.continuation,
+            is_valid: rejection.is_none(),
+            continues_lexeme: explanation.continues_lexeme,
+            completed: explanation.completed,
+            pending: explanation.pending,
+            rejection,
+            reason,
+            offset,
+            rejected,
+            expected,
+        }
+    }
+}
+
+#[pymethods]
+impl Explanation {
+    fn __repr__(&self) -> String {
+        format!(
+            "Explanation(index={}, is_valid={}, completed={:?}, pending={:?}, reason={:?})",
+            self.index,
+            if self.is_valid { "True" } else { "False" },
+            self.completed
+                .iter()
+                .map(|(name, _)| name.as_deref().unwrap_or("<skip>"))
+                .collect::<Vec<_>>(),
+            self.pending,
+            self.reason.as_deref().unwrap_or("")
+        )
+    }
+}
+
+#[pyclass(frozen)]
+struct Transcript(RecordedTranscript);
+
+#[pymethods]
+impl Transcript {
+    #[staticmethod]
+    fn from_json(json: &str) -> anyhow::Result<Self> {
+        Ok(Self(
+            RecordedTranscript::from_json(json).map_err(|e| anyhow!("invalid transcript: {e}"))?,
+        ))
+    }
+
+    #[staticmethod]
+    fn from_msgpack(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self(
+            RecordedTranscript::from_msgpack(bytes)
+                .map_err(|e| anyhow!("invalid transcript: {e}"))?,
+        ))
+    }
+
+    fn to_json(&self) -> String {
+        self.0.to_json()
+    }
+
+    fn to_msgpack<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.to_msgpack())
+    }
+
+    fn prefix<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.prefix)
+    }
+
+    fn output<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.output())
+    }
+
+    fn token_ids(&self) -> Vec<usize> {
+        self.0.tokens()
+    }
+
+    fn fingerprints(&self) -> Vec<Option<u64>> {
+        self.0.steps.iter().map(|step| step.fingerprint).collect()
+    }
+
+    fn forced(&self) -> Vec<bool> {
+        self.0.steps.iter().map(|step| step.forced).collect()
+    }
+
+    fn is_match(&self) -> bool {
+        self.0.is_match
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.steps.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Transcript(steps={}, is_match={})",
+            self.0.steps.len(),
+            if self.0.is_match { "True" } else { "False" }
+        )
+    }
+}
+
+#[pyclass(frozen, get_all)]
+pub struct TranscriptVerification {
+    steps: usize,
+    valid_steps: usize,
+    is_match: bool,
+    is_valid: bool,
+    is_unchanged: bool,
+    vocabulary_mismatch: Option<usize>,
+    fingerprint_mismatches: Vec<usize>,
+    forced_mismatches: Vec<usize>,
+}
+
+impl From<crate::TranscriptVerification> for TranscriptVerification {
+    fn from(verification: crate::TranscriptVerification) -> Self {
+        Self {
+            steps: verification.steps,
+            valid_steps: verification.valid_steps,
+            is_match: verification.is_match,
+            is_valid: verification.is_valid(),
+            is_unchanged: verification.is_unchanged(),
+            vocabulary_mismatch: verification.vocabulary_mismatch,
+            fingerprint_mismatches: verification.fingerprint_mismatches,
+            forced_mismatches: verification.forced_mismatches,
+        }
+    }
+}
+
+#[pymethods]
+impl TranscriptVerification {
+    fn __repr__(&self) -> String {
+        format!(
+            "TranscriptVerification(is_valid={}, is_unchanged={}, valid_steps={}/{})",
+            if self.is_valid { "True" } else { "False" },
+            if self.is_unchanged { "True" } else { "False" },
+            self.valid_steps,
+            self.steps
+        )
+    }
+}
+
+#[pyclass(eq, eq_int, frozen, skip_from_py_object)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Classification {
+    Valid,
+    ValidPrefix,
+    Invalid,
+}
+
+impl From<crate::Classification> for Classification {
+    fn from(classification: crate::Classification) -> Self {
+        match classification {
+            crate::Classification::Valid => Classification::Valid,
+            crate::Classification::ValidPrefix => Classification::ValidPrefix,
+            crate::Classification::Invalid => Classification::Invalid,
+        }
+    }
+}
+
+// classification of the current state from its cached continuations,
+// same as Constraint::classify_state
+fn classify_current(is_invalid: bool, is_match: bool, indices: &Array1<i32>) -> Classification {
+    if is_invalid {
+        Classification::Invalid
+    } else if is_match {
+        Classification::Valid
+    } else if indices.is_empty() {
+        Classification::Invalid
+    } else {
+        Classification::ValidPrefix
+    }
+}
+
+#[pyclass(frozen)]
+pub struct LR1Parser {
+    inner: LR1GrammarParser,
+}
+
+impl LR1Parser {
+    // with inlining, parse trees still contain the inlined rules
+    fn build(grammar: &str, lexer: &str, inline: bool) -> Result<LR1GrammarParser, Box<dyn Error>> {
+        if !inline {
+            return LR1GrammarParser::new(grammar, lexer);
+        }
+        let (grammar, provenance) = inline_rules(grammar)?.into_parts();
+        Ok(LR1GrammarParser::new(&grammar, lexer)?.with_provenance(provenance))
+    }
+}
+
+#[pymethods]
+impl LR1Parser {
+    #[new]
+    #[pyo3(signature = (grammar, lexer, nfc = false, lowercase = false, inline = false))]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        nfc: bool,
+        lowercase: bool,
+        inline: bool,
+    ) -> anyhow::Result<Self> {
+        let inner = Self::build(grammar, lexer, inline).map_err(|e| {
+            anyhow!(
+                "failed to create LR(1) grammar parser from grammar {} and lexer {}: {}",
+                grammar,
+                lexer,
+                e
+            )
+        })?;
+        Ok(Self {
+            inner: inner.with_normalization(Normalization { nfc, lowercase }),
+        })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (grammar_path, lexer_path, nfc = false, lowercase = false, inline = false))]
+    fn from_files(
+        grammar_path: &str,
+        lexer_path: &str,
+        nfc: bool,
+        lowercase: bool,
+        inline: bool,
+    ) -> anyhow::Result<Self> {
+        let inner = fs::read_to_string(grammar_path)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|grammar| {
+                let lexer = fs::read_to_string(lexer_path)?;
+                Self::build(&grammar, &lexer, inline)
+            })
+            .map_err(|e| {
+                anyhow!(
+                    "failed to create LR(1) grammar parser from files {} and {}: {}",
+                    grammar_path,
+                    lexer_path,
+                    e
+                )
+            })?;
+        Ok(Self {
+            inner: inner.with_normalization(Normalization { nfc, lowercase }),
+        })
+    }
+
+    #[pyo3(signature = (input, skip_empty = false, collapse_single = false))]
+    fn prefix_parse<'py>(
+        &self,
+        py: Python<'py>,
+        input: &[u8],
+        skip_empty: bool,
+        collapse_single: bool,
+    ) -> anyhow::Result<(Bound<'py, PyDict>, Vec<u8>)> {
+        let (parse, end) = self
+            .inner
+            .prefix_parse(input, skip_empty, collapse_single)
+            .map_err(|e| anyhow!("failed to parse input: {e}"))?;
+        let parse_dict = parse_into_py(&parse, self.inner.byte_mode(), py)?;
+        Ok((parse_dict, end.to_vec()))
+    }
+
+    #[pyo3(signature = (input, skip_empty = false, collapse_single = false))]
+    fn parse<'py>(
+        &self,
+        py: Python<'py>,
+        input: TextOrBytes,
+        skip_empty: bool,
+        collapse_single: bool,
+    ) -> anyhow::Result<Bound<'py, PyDict>> {
+        let parse = self
+            .inner
+            .parse(&input, skip_empty, collapse_single)
+            .map_err(|e| anyhow!("failed to parse input: {e}"))?;
+        Ok(parse_into_py(&parse, self.inner.byte_mode(), py)?)
+    }
+
+    #[pyo3(signature = (input, spans = false))]
+    fn to_value<'py>(
+        &self,
+        py: Python<'py>,
+        input: TextOrBytes,
+        spans: bool,
+    ) -> anyhow::Result<Bound<'py, PyAny>> {
+        let parse = self
+            .inner
+            .parse(&input, true, true)
+            .map_err(|e| anyhow!("failed to parse input: {e}"))?;
+        if !spans {
+            let value = parse
+                .to_value()
+                .map_err(|e| anyhow!("failed to convert parse to json value: {e}"))?;
+            return Ok(value_into_py(&value, py)?);
+        }
+        let (value, spans) = parse
+            .to_value_with_spans()
+            .map_err(|e| anyhow!("failed to convert parse to json value: {e}"))?;
+        Ok((value_into_py(&value, py)?, spans)
+            .into_pyobject(py)?
+            .into_any())
+    }
+
+    fn lex(&self, input: TextOrBytes) -> anyhow::Result<Vec<TokenAndSpan<'_>>> {
+        self.inner
+            .lex(&input)
+            .map_err(|e| anyhow!("failed to lex input: {e}"))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn lex_with_recovery(
+        &self,
+        input: TextOrBytes,
+    ) -> (Vec<TokenAndSpan<'_>>, Vec<(&'static str, (usize, usize))>) {
+        let (tokens, errors) = self.inner.lex_with_recovery(&input);
+        let errors = errors
+            .into_iter()
+            .map(|error| {
+                let kind = match error.kind {
+                    LexErrorKind::NoMatch => "no_match",
+                    LexErrorKind::Incomplete => "incomplete",
+                };
+                (kind, error.span)
+            })
+            .collect();
+        (tokens, errors)
+    }
+}
+
+#[derive(Clone)]
+struct CoreInner<S> {
+    state: S,
+    indices: Array1<i32>,
+    is_match: bool,
+    is_invalid: bool,
+    last_match: Option<S>,
+}
+
+type CoreCache<S> = Mutex<LruCache<S, (Array1<i32>, bool)>>;
+
+// python binding machinery (current state, valid continuations, optional cache
+// and the reset / clone / get / next methods) for any constraint; wrap it in a
+// python class with the py_constraint macro, which lets downstream crates ship
+// their own constraints without changes to this module
+pub struct PyConstraintCore<C: Constraint> {
+    constraint: Arc<C>,
+    inner: Arc<Mutex<CoreInner<C::State>>>,
+    cache: Option<Arc<CoreCache<C::State>>>,
+    // shared with clones, grows and shrinks with constraints changed in place
+    memory: Arc<Mutex<MemoryReservation<'static>>>,
+    on_invalid: InvalidPolicy,
+}
+
+impl<C> PyConstraintCore<C>
+where
+    C: Constraint + MemoryUsage + Send + Sync + 'static,
+    C::State: Clone + Hash + Eq + Send + 'static,
+{
+    pub fn new(constraint: C) -> anyhow::Result<Self> {
+        Self::build(constraint, None)
+    }
+
+    // caches valid continuations and match status of up to capacity states
+    pub fn with_cache(constraint: C, capacity: NonZeroUsize) -> anyhow::Result<Self> {
+        Self::build(constraint, Some(capacity))
+    }
+
+    fn build(constraint: C, capacity: Option<NonZeroUsize>) -> anyhow::Result<Self> {
+        let memory = MemoryBudget::global().reserve(constraint.memory_usage())?;
+        let cache = capacity.map(|capacity| Arc::new(Mutex::new(LruCache::new(capacity))));
+        let inner = Self::compute(&constraint, cache.as_deref(), constraint.get_start_state());
+        Ok(Self {
+            constraint: Arc::new(constraint),
+            inner: Arc::new(Mutex::new(inner)),
+            cache,
+            memory: Arc::new(Mutex::new(memory)),
+            on_invalid: InvalidPolicy::default(),
+        })
+    }
+
+    // what next does with invalid continuations, sticky by default
+    pub fn with_invalid_policy(mut self, on_invalid: InvalidPolicy) -> Self {
+        self.on_invalid = on_invalid;
+        {
+            let mut guard = self.inner.lock().expect("error locking inner state");
+            let inner = &mut *guard;
+            inner.last_match = None;
+            on_invalid.remember(&mut inner.last_match, &inner.state, inner.is_match);
+        }
+        self
+    }
+
+    fn compute(
+        constraint: &C,
+        cache: Option<&CoreCache<C::State>>,
+        state: C::State,
+    ) -> CoreInner<C::State> {
+        // the cache is only locked for lookups and insertions, so that clones
+        // sharing it can compute continuations of different states in parallel
+        let cached = cache.and_then(|cache| {
+            cache
+                .lock()
+                .expect("error locking cache")
+                .get(&state)
+                .cloned()
+        });
+        let (indices, is_match) = match cached {
+            Some(entry) => entry,
+            None => {
+                let indices: Array1<i32> = constraint
+                    .get_valid_continuations(&state)
+                    .into_iter()
+                    .map(|v| v as i32)
+                    .collect();
+                let is_match = constraint.is_match_state(&state);
+                if let Some(cache) = cache {
+                    cache
+                        .lock()
+                        .expect("error locking cache")
+                        .put(state.clone(), (indices.clone(), is_match));
+                }
+                (indices, is_match)
+            }
+        };
+        CoreInner {
+            state,
+            indices,
+            is_match,
+            is_invalid: false,
+            last_match: None,
+        }
+    }
+
+    pub fn constraint(&self) -> &Arc<C> {
+        &self.constraint
+    }
+
+    // runs f on the current state, e.g. for constraint specific methods
+    pub fn with_state<T: Send>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&C::State) -> T + Send,
+    ) -> anyhow::Result<T> {
+        with_lock(py, &self.inner, |inner| f(&inner.state))
+    }
+
+    // resizes the memory reservation to the memory usage of the constraint,
+    // e.g. after it was changed in place; fails if the budget is exceeded
+    pub fn update_memory(&self) -> anyhow::Result<()> {
+        let mut memory = self
+            .memory
+            .lock()
+            .map_err(|_| anyhow!("error locking memory reservation"))?;
+        let usage = self.constraint.memory_usage();
+        let reserved = memory.bytes();
+        if usage > reserved {
+            memory.grow(usage - reserved)?;
+        } else {
+            memory.shrink(reserved - usage);
+        }
+        Ok(())
+    }
+
+    // recomputes the valid continuations of the current state, e.g. after the
+    // constraint was changed in place; the cache may be outdated as well, so
+    // it is cleared
+    pub fn refresh(&self, py: Python<'_>) -> anyhow::Result<()> {
+        if let Some(cache) = &self.cache {
+            cache.lock().expect("error locking cache").clear();
+        }
+        with_lock(py, &self.inner, |inner| {
+            let next = Self::compute(&self.constraint, None, inner.state.clone());
+            inner.indices = next.indices;
+            inner.is_match = next.is_match;
+        })
+    }
+
+    pub fn reset(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
+        let Some(state) = self.constraint.get_state(&prefix.unwrap_or_default()) else {
+            return Err(anyhow!("failed to reset to given prefix"));
+        };
+        let mut next = Self::compute(&self.constraint, self.cache.as_deref(), state);
+        self.on_invalid
+            .remember(&mut next.last_match, &next.state, next.is_match);
+        with_lock(py, &self.inner, |inner| *inner = next)
+    }
+
+    // clones share the constraint and cache, but not the current state
+    pub fn try_clone(&self, py: Python<'_>) -> anyhow::Result<Self> {
+        with_lock(py, &self.inner, |inner| Self {
+            constraint: self.constraint.clone(),
+            inner: Arc::new(Mutex::new(inner.clone())),
+            cache: self.cache.clone(),
+            memory: self.memory.clone(),
+            on_invalid: self.on_invalid,
+        })
+    }
+
+    pub fn get<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<i32>>> {
+        with_lock(py, &self.inner, |inner| inner.indices.clone())
+            .map(|indices| indices.into_pyarray(py))
+    }
+
+    pub fn get_ranges(&self, py: Python<'_>) -> anyhow::Result<Vec<(u32, u32)>> {
+        with_lock(py, &self.inner, |inner| {
+            index_ranges(inner.indices.iter().map(|&i| i as usize))
+        })
+    }
+
+    pub fn pack_mask_u32(
+        &self,
+        py: Python<'_>,
+        out: &Bound<'_, PyArray1<u32>>,
+    ) -> anyhow::Result<()> {
+        let indices = with_lock(py, &self.inner, |inner| inner.indices.clone())?;
+        pack_into(indices.as_slice().unwrap_or_default(), out)
+    }
+
+    pub fn is_invalid(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| {
+            inner.is_invalid || (inner.indices.is_empty() && !inner.is_match)
+        })
+    }
+
+    pub fn is_match(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| inner.is_match)
+    }
+
+    pub fn next(&self, py: Python<'_>, index: usize) -> anyhow::Result<()> {
+        let inner = self.inner.clone();
+        let constraint = self.constraint.clone();
+        let cache = self.cache.clone();
+        let policy = self.on_invalid;
+        spawn_next(py, index, policy, move |signal| {
+            let mut inner = inner.lock().expect("error locking inner state");
+            if policy != InvalidPolicy::Raise {
+                signal(true);
+            }
+            let next_state = constraint.get_next_state(&inner.state, index);
+            if policy == InvalidPolicy::Raise {
+                signal(next_state.is_some());
+            }
+            let next_state = match (next_state, policy) {
+                (Some(next_state), _) => next_state,
+                (None, InvalidPolicy::Reset) => inner
+                    .last_match
+                    .clone()
+                    .unwrap_or_else(|| constraint.get_start_state()),
+                (None, InvalidPolicy::Sticky) => {
+                    inner.is_invalid = true;
+                    return;
+                }
+                (None, InvalidPolicy::Raise) => return,
+            };
+            if constraint.has_same_continuations(&inner.state, &next_state) {
+                return;
+            }
+            let mut next = Self::compute(&constraint, cache.as_deref(), next_state);
+            next.last_match = inner.last_match.take();
+            policy.remember(&mut next.last_match, &next.state, next.is_match);
+            *inner = next;
+        })
+    }
+
+    pub fn check(&self, text: &[u8]) -> bool {
+        self.constraint.check(text)
+    }
+
+    pub fn check_detailed(&self, text: &[u8]) -> CheckReport {
+        self.constraint.check_detailed(text).into()
+    }
+
+    // classifies the prefix, or the current state if there is none
+    pub fn classify(
+        &self,
+        py: Python<'_>,
+        prefix: Option<&[u8]>,
+    ) -> anyhow::Result<Classification> {
+        if let Some(prefix) = prefix {
+            return Ok(self.constraint.classify(prefix).into());
+        }
+        with_lock(py, &self.inner, |inner| {
+            classify_current(inner.is_invalid, inner.is_match, &inner.indices)
+        })
+    }
+
+    pub fn last_valid_truncation(&self, text: &[u8]) -> Option<usize> {
+        self.constraint.last_valid_truncation(text)
+    }
+
+    pub fn memory_usage(&self, py: Python<'_>) -> anyhow::Result<usize> {
+        let cached = match &self.cache {
+            Some(cache) => cache
+                .lock()
+                .map_err(|_| anyhow!("error locking cache"))?
+                .iter()
+                .map(|(_, (indices, _))| indices.len() * size_of::<i32>())
+                .sum(),
+            None => 0,
+        };
+        let reserved = self
+            .memory
+            .lock()
+            .map_err(|_| anyhow!("error locking memory reservation"))?
+            .bytes();
+        with_lock(py, &self.inner, |inner| {
+            reserved + cached + inner.indices.len() * size_of::<i32>()
+        })
+    }
+}
+
+impl<C> PyConstraintCore<C>
+where
+    C: ByteConstraint + MemoryUsage + Send + Sync + 'static,
+    C::State: Clone + Hash + Eq + Send + 'static,
+{
+    pub fn encode(&self, input: &[u8]) -> anyhow::Result<Vec<usize>> {
+        Ok(encode_with_constraint(self.constraint.as_ref(), input)?)
+    }
+}
+
+// defines a python class wrapping a PyConstraintCore of the given constraint,
+// with the common constraint methods (reset, clone, get, get_ranges, is_invalid,
+// is_match, next, check, check_detailed, classify, last_valid_truncation and
+// memory_usage), and encode for byte constraints declared "with encode";
+// further methods, like the constructor, are given after the struct and can
+// access the core as self.0, e.g.
+//
+// py_constraint! {
+//     pub struct MyConstraint(my_crate::MyConstraint) with encode;
+//
+//     #[new]
+//     fn new(continuations: Vec<Vec<u8>>) -> anyhow::Result<Self> {
+//         Ok(Self(PyConstraintCore::new(my_crate::MyConstraint::new(continuations))?))
+//     }
+// }
+//
+// the class is then registered with m.add_class::<MyConstraint>() in the
+// pymodule of the downstream crate, which has to depend on pyo3 itself
+#[macro_export]
+macro_rules! py_constraint {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident($constraint:ty) with encode;
+        $($methods:tt)*
+    ) => {
+        $crate::py_constraint! {
+            $(#[$meta])*
+            $vis struct $name($constraint);
+            $($methods)*
+
+            fn encode(&self, input: &[u8]) -> $crate::__private::anyhow::Result<Vec<usize>> {
+                self.0.encode(input)
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident($constraint:ty);
+        $($methods:tt)*
+    ) => {
+        $(#[$meta])*
+        #[::pyo3::pyclass(frozen)]
+        $vis struct $name($crate::PyConstraintCore<$constraint>);
+
+        #[::pyo3::pymethods]
+        impl $name {
+            $($methods)*
+
+            #[pyo3(signature = (prefix = None))]
+            fn reset(
+                &self,
+                py: ::pyo3::Python<'_>,
+                prefix: Option<Vec<u8>>) -> $crate::__private::anyhow::Result<()> {
+                self.0.reset(py, prefix)
+            }
+
+            fn clone(&self, py: ::pyo3::Python<'_>) -> $crate::__private::anyhow::Result<Self> {
+                self.0.try_clone(py).map(Self)
+            }
+
+            fn get<'py>(
+                &self,
+                py: ::pyo3::Python<'py>,
+            ) -> $crate::__private::anyhow::Result<
+                ::pyo3::Bound<'py, $crate::__private::numpy::PyArray1<i32>>,
+            > {
+                self.0.get(py)
+            }
+
+            fn get_ranges(&self, py: ::pyo3::Python<'_>) -> $crate::__private::anyhow::Result<Vec<(u32, u32)>> {
+                self.0.get_ranges(py)
+            }
+
+            fn pack_mask_u32(
+                &self,
+                py: ::pyo3::Python<'_>,
+                out: &::pyo3::Bound<'_, $crate::__private::numpy::PyArray1<u32>>,
+            ) -> $crate::__private::anyhow::Result<()> {
+                self.0.pack_mask_u32(py, out)
+            }
+
+            fn is_invalid(&self, py: ::pyo3::Python<'_>) -> $crate::__private::anyhow::Result<bool> {
+                self.0.is_invalid(py)
+            }
+
+            fn is_match(&self, py: ::pyo3::Python<'_>) -> $crate::__private::anyhow::Result<bool> {
+                self.0.is_match(py)
+            }
+
+            fn next(&self, py: ::pyo3::Python<'_>, index: usize) -> $crate::__private::anyhow::Result<()> {
+                self.0.next(py, index)
+            }
+
+            fn check(&self, text: $crate::__private::TextOrBytes) -> bool {
+                self.0.check(text.as_ref())
+            }
+
+            fn check_detailed(
+                &self,
+                text: $crate::__private::TextOrBytes,
+            ) -> $crate::__private::CheckReport {
+                self.0.check_detailed(text.as_ref())
+            }
+
+            #[pyo3(signature = (prefix = None))]
+            fn classify(
+                &self,
+                py: ::pyo3::Python<'_>,
+                prefix: Option<$crate::__private::TextOrBytes>,
+            ) -> $crate::__private::anyhow::Result<$crate::__private::Classification> {
+                self.0.classify(py, prefix.as_ref().map(|prefix| prefix.as_ref()))
+            }
+
+            fn last_valid_truncation(&self, text: $crate::__private::TextOrBytes) -> Option<usize> {
+                self.0.last_valid_truncation(text.as_ref())
+            }
+
+            fn memory_usage(&self, py: ::pyo3::Python<'_>) -> $crate::__private::anyhow::Result<usize> {
+                self.0.memory_usage(py)
+            }
+        }
+    };
+}
+
+py_constraint! {
+    struct LexicalConstraint(Lexical) with encode;
+
+    #[new]
+    #[pyo3(signature = (lexer, continuations, terminals = None, on_invalid = "sticky"))]
+    fn new(
+        lexer: &str,
+        continuations: PyContinuations,
+        terminals: Option<&str>,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = Lexical::new(lexer, terminals, continuations)
+            .map_err(|e| anyhow!("failed to create lexical constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (path, continuations, terminals = None, on_invalid = "sticky"))]
+    fn from_file(
+        path: &str,
+        continuations: PyContinuations,
+        terminals: Option<&str>,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = Lexical::from_file(path, terminals, continuations)
+            .map_err(|e| anyhow!("failed to create lexical constraint from file '{path}': {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn terminal_names(&self) -> Vec<String> {
+        self.0.constraint().terminal_names().to_vec()
+    }
+
+    fn lex(&self, input: TextOrBytes) -> anyhow::Result<Vec<TokenAndSpan<'_>>> {
+        self.0
+            .constraint()
+            .lex(&input)
+            .map_err(|e| anyhow!("failed to lex input: {e}"))
+    }
+}
+
+py_constraint! {
+    struct EarleyConstraint(EarleyGrammarConstraint) with encode;
+
+    #[new]
+    #[pyo3(signature = (grammar, lexer, continuations, on_invalid = "sticky"))]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = EarleyGrammarConstraint::new(grammar, lexer, continuations)
+            .map_err(|e| anyhow!("failed to create Earley grammar constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (grammar_path, lexer_path, continuations, on_invalid = "sticky"))]
+    fn from_files(
+        grammar_path: &str,
+        lexer_path: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint =
+            EarleyGrammarConstraint::from_files(grammar_path, lexer_path, continuations)
+                .map_err(|e| anyhow!("failed to create Earley grammar constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn expected_terminals(&self, py: Python<'_>) -> anyhow::Result<Vec<String>> {
+        let constraint = self.0.constraint().clone();
+        self.0.with_state(py, move |state| {
+            constraint
+                .expected_terminals(state)
+                .into_iter()
+                .map(String::from)
+                .collect()
+        })
+    }
+}
+
+py_constraint! {
+    struct GLRConstraint(GLRGrammarConstraint) with encode;
+
+    #[new]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        continuations,
+        on_invalid = "sticky",
+        max_stacks = DEFAULT_MAX_STACKS,
+    ))]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+        max_stacks: usize,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = GLRGrammarConstraint::new(grammar, lexer, continuations)
+            .map_err(|e| anyhow!("failed to create GLR grammar constraint: {e}"))?
+            .with_max_stacks(max_stacks);
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        grammar_path,
+        lexer_path,
+        continuations,
+        on_invalid = "sticky",
+        max_stacks = DEFAULT_MAX_STACKS,
+    ))]
+    fn from_files(
+        grammar_path: &str,
+        lexer_path: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+        max_stacks: usize,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint =
+            GLRGrammarConstraint::from_files(grammar_path, lexer_path, continuations)
+                .map_err(|e| anyhow!("failed to create GLR grammar constraint: {e}"))?
+                .with_max_stacks(max_stacks);
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn overflowed(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        self.0.with_state(py, |state| state.overflowed())
+    }
+
+    fn expected_terminals(&self, py: Python<'_>) -> anyhow::Result<Vec<String>> {
+        let constraint = self.0.constraint().clone();
+        self.0.with_state(py, move |state| {
+            constraint
+                .expected_terminals(state)
+                .into_iter()
+                .map(String::from)
+                .collect()
+        })
+    }
+
+    fn num_conflicts(&self) -> usize {
+        self.0.constraint().num_conflicts()
+    }
+}
+
+// hooks are called from the thread pool, exceptions count as rejections
+fn with_py_hooks(
+    mut constraint: SemanticGrammarConstraint,
+    hooks: HashMap<String, Py<PyAny>>,
+) -> anyhow::Result<SemanticGrammarConstraint> {
+    for (rule, hook) in hooks {
+        constraint = constraint
+            .with_hook(&rule, move |text| {
+                Python::attach(|py| {
+                    hook.call1(py, (PyBytes::new(py, text),))
+                        .and_then(|result| result.bind(py).is_truthy())
+                        .unwrap_or_else(|e| {
+                            e.write_unraisable(py, None);
+                            false
+                        })
+                })
+            })
+            .map_err(|e| anyhow!("failed to add semantic hook: {e}"))?;
+    }
+    Ok(constraint)
+}
+
+// computed terminals, python functions get the latest text of every terminal
+// seen so far and return the text of their terminal or None
+fn with_py_terminals(
+    mut constraint: SemanticGrammarConstraint,
+    length_prefixed: Option<HashMap<String, String>>,
+    computed: Option<HashMap<String, Py<PyAny>>>,
+) -> anyhow::Result<SemanticGrammarConstraint> {
+    for (terminal, length) in length_prefixed.unwrap_or_default() {
+        constraint = constraint
+            .with_terminal_machine(&terminal, LengthPrefixed::new(&length))
+            .map_err(|e| anyhow!("failed to add length prefixed terminal: {e}"))?;
+    }
+    for (terminal, compute) in computed.unwrap_or_default() {
+        let machine = ComputedText::new(move |context: &TerminalContext| {
+            Python::attach(|py| {
+                let terminals = PyDict::new(py);
+                for (name, text) in context.terminals() {
+                    terminals.set_item(name, PyBytes::new(py, &text)).ok()?;
+                }
+                compute
+                    .call1(py, (terminals,))
+                    .and_then(|text| text.extract::<Option<Vec<u8>>>(py))
+                    .unwrap_or_else(|e| {
+                        e.write_unraisable(py, None);
+                        None
+                    })
+            })
+        });
+        constraint = constraint
+            .with_terminal_machine(&terminal, machine)
+            .map_err(|e| anyhow!("failed to add computed terminal: {e}"))?;
+    }
+    Ok(constraint)
+}
+
+py_constraint! {
+    struct PushdownConstraint(PushdownGrammarConstraint) with encode;
+
+    #[new]
+    #[pyo3(signature = (grammar, lexer, continuations, on_invalid = "sticky"))]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = PushdownGrammarConstraint::new(grammar, lexer, continuations)
+            .map_err(|e| anyhow!("failed to create pushdown grammar constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (grammar_path, lexer_path, continuations, on_invalid = "sticky"))]
+    fn from_files(
+        grammar_path: &str,
+        lexer_path: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint =
+            PushdownGrammarConstraint::from_files(grammar_path, lexer_path, continuations)
+                .map_err(|e| anyhow!("failed to create pushdown grammar constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn expected_terminals(&self, py: Python<'_>) -> anyhow::Result<Vec<String>> {
+        let constraint = self.0.constraint().clone();
+        self.0.with_state(py, move |state| {
+            constraint
+                .expected_terminals(state)
+                .into_iter()
+                .map(String::from)
+                .collect()
+        })
+    }
+}
+
+py_constraint! {
+    struct SemanticConstraint(SemanticGrammarConstraint) with encode;
+
+    #[new]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        continuations,
+        hooks,
+        on_invalid = "sticky",
+        length_prefixed = None,
+        computed = None,
+    ))]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        continuations: PyContinuations,
+        hooks: HashMap<String, Py<PyAny>>,
+        on_invalid: &str,
+        length_prefixed: Option<HashMap<String, String>>,
+        computed: Option<HashMap<String, Py<PyAny>>>,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = SemanticGrammarConstraint::new(grammar, lexer, continuations)
+            .map_err(|e| anyhow!("failed to create semantic grammar constraint: {e}"))?;
+        let constraint = with_py_hooks(constraint, hooks)?;
+        let constraint = with_py_terminals(constraint, length_prefixed, computed)?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (
+        grammar_path,
+        lexer_path,
+        continuations,
+        hooks,
+        on_invalid = "sticky",
+        length_prefixed = None,
+        computed = None,
+    ))]
+    fn from_files(
+        grammar_path: &str,
+        lexer_path: &str,
+        continuations: PyContinuations,
+        hooks: HashMap<String, Py<PyAny>>,
+        on_invalid: &str,
+        length_prefixed: Option<HashMap<String, String>>,
+        computed: Option<HashMap<String, Py<PyAny>>>,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint =
+            SemanticGrammarConstraint::from_files(grammar_path, lexer_path, continuations)
+                .map_err(|e| anyhow!("failed to create semantic grammar constraint: {e}"))?;
+        let constraint = with_py_hooks(constraint, hooks)?;
+        let constraint = with_py_terminals(constraint, length_prefixed, computed)?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+}
+
+py_constraint! {
+    struct PegConstraint(PegGrammarConstraint) with encode;
+
+    #[new]
+    #[pyo3(signature = (grammar, continuations, on_invalid = "sticky"))]
+    fn new(grammar: &str, continuations: PyContinuations, on_invalid: &str) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = PegGrammarConstraint::new(grammar, continuations)
+            .map_err(|e| anyhow!("failed to create PEG grammar constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (grammar_path, continuations, on_invalid = "sticky"))]
+    fn from_file(
+        grammar_path: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = PegGrammarConstraint::from_file(grammar_path, continuations)
+            .map_err(|e| anyhow!("failed to create PEG grammar constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+}
+
+py_constraint! {
+    struct ChoiceConstraint(Choice) with encode;
+
+    #[new]
+    #[pyo3(signature = (options, continuations, on_invalid = "sticky"))]
+    fn new(
+        options: Vec<String>,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = Choice::new(options, continuations)
+            .map_err(|e| anyhow!("failed to create choice constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (path, continuations, on_invalid = "sticky"))]
+    fn from_file(path: &str, continuations: PyContinuations, on_invalid: &str) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = Choice::from_file(path, continuations)
+            .map_err(|e| anyhow!("failed to create choice constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+}
+
+py_constraint! {
+    struct RegexSetConstraint(RegexSet);
+
+    #[new]
+    #[pyo3(signature = (patterns, continuations, on_invalid = "sticky"))]
+    fn new(
+        patterns: Vec<String>,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = RegexSet::new(&patterns, continuations)
+            .map_err(|e| anyhow!("failed to create regex set constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn patterns(&self) -> Vec<String> {
+        self.0.constraint().patterns().to_vec()
+    }
+
+    fn viable(&self, py: Python<'_>) -> anyhow::Result<Vec<usize>> {
+        self.0
+            .with_state(py, |state| self.0.constraint().viable(state).to_vec())
+    }
+
+    fn matched(&self, py: Python<'_>) -> anyhow::Result<Vec<usize>> {
+        self.0
+            .with_state(py, |state| self.0.constraint().matched(state).to_vec())
+    }
+}
+
+py_constraint! {
+    struct LazyRegexConstraint(LazyRegex);
+
+    #[new]
+    #[pyo3(signature = (regex, continuations, cache_capacity = 65536, on_invalid = "sticky"))]
+    fn new(
+        regex: &str,
+        continuations: PyContinuations,
+        cache_capacity: usize,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = LazyRegex::with_cache_capacity(regex, continuations, cache_capacity)
+            .map_err(|e| anyhow!("failed to create lazy regex constraint from regex '{regex}': {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
 
     #[staticmethod]
-    #[pyo3(signature = (grammar_path, lexer_path, continuations, exact=false, lru_cache_size=None))]
-    fn from_files(
-        grammar_path: &str,
-        lexer_path: &str,
-        continuations: Vec<Vec<u8>>,
-        exact: bool,
-        lru_cache_size: Option<usize>,
+    #[pyo3(signature = (path, continuations, on_invalid = "sticky"))]
+    fn from_file(
+        path: &str,
+        continuations: PyContinuations,
+        on_invalid: &str,
     ) -> anyhow::Result<Self> {
-        let constraint = if exact {
-            LR1Type::Exact(
-                ExactLR1GrammarConstraint::from_files(grammar_path, lexer_path, continuations)
-                    .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?,
-            )
-        } else {
-            LR1Type::Regular(
-                LR1GrammarConstraint::from_files(grammar_path, lexer_path, continuations)
-                    .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {}", e))?,
-            )
-        };
-        Ok(Self::init(constraint, lru_cache_size))
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = LazyRegex::from_file(path, continuations)
+            .map_err(|e| anyhow!("failed to create lazy regex constraint from file '{path}': {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
     }
 
-    #[pyo3(signature = (prefix = None))]
-    fn reset(&self, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
-        let Some(state) = self.constraint.get_state(&prefix.unwrap_or_default()) else {
-            return Err(anyhow!("failed to reset to given prefix"));
-        };
-        let mut inner = self
-            .inner
-            .lock()
-            .map_err(|_| anyhow!("error locking inner state"))?;
-        let mut cache = self
-            .cache
-            .lock()
-            .map_err(|_| anyhow!("error locking cache"))?;
-
-        inner.state = state;
-        inner.is_invalid = false;
-        if let Some((indices, is_match)) = cache.get(&inner.state).cloned() {
-            inner.indices = indices;
-            inner.is_match = is_match;
-        } else {
-            inner.indices = self.constraint.get_valid_continuations(&inner.state);
-            inner.is_match = self.constraint.is_match_state(&inner.state);
-            cache.put(inner.state.clone(), (inner.indices.clone(), inner.is_match));
-        }
-        Ok(())
+    fn pattern(&self) -> String {
+        self.0.constraint().pattern().to_string()
     }
+}
 
-    fn clone(&self) -> anyhow::Result<Self> {
-        self.inner
-            .lock()
-            .map(|inner| Self {
-                constraint: self.constraint.clone(),
-                inner: Arc::new(Mutex::new(inner.clone())),
-                cache: self.cache.clone(),
-            })
-            .map_err(|_| anyhow!("error locking inner state"))
+py_constraint! {
+    struct Utf8Constraint(Utf8<RegularExpressionConstraint>);
+
+    #[new]
+    #[pyo3(signature = (regex, continuations, on_invalid = "sticky"))]
+    fn new(regex: &str, continuations: PyContinuations, on_invalid: &str) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let inner = RegularExpressionConstraint::new(regex, continuations)
+            .map_err(|e| anyhow!("failed to create regex constraint from regex '{regex}': {e}"))?;
+        Ok(Self(PyConstraintCore::new(Utf8::new(inner))?.with_invalid_policy(on_invalid)))
     }
 
-    fn get<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<i32>>> {
-        self.inner
-            .lock()
-            .map(|inner| {
-                if inner.is_match && self.constraint.only_skippable_matching(&inner.state) {
-                    // should stop, return empty indices
-                    vec![].into()
-                } else {
-                    inner.indices.clone()
-                }
-                .into_pyarray(py)
-            })
-            .map_err(|_| anyhow!("error locking inner state"))
+    fn is_complete(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        self.0.with_state(py, |state| state.is_complete())
     }
+}
 
-    fn is_invalid(&self) -> anyhow::Result<bool> {
-        self.inner
-            .lock()
-            .map(|inner| inner.is_invalid || (inner.indices.is_empty() && !inner.is_match))
-            .map_err(|_| anyhow!("error locking inner state"))
+py_constraint! {
+    struct LiteralSetConstraint(LiteralSet);
+
+    #[new]
+    #[pyo3(signature = (literals, continuations, on_invalid = "sticky"))]
+    fn new(
+        literals: Vec<TextOrBytes>,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = LiteralSet::new(literals, continuations)
+            .map_err(|e| anyhow!("failed to create literal set constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
     }
 
-    fn is_match(&self) -> anyhow::Result<bool> {
-        self.inner
-            .lock()
-            .map(|inner| inner.is_match)
-            .map_err(|_| anyhow!("error locking inner state"))
+    #[staticmethod]
+    #[pyo3(signature = (path, continuations, on_invalid = "sticky"))]
+    fn from_file(path: &str, continuations: PyContinuations, on_invalid: &str) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let constraint = LiteralSet::from_file(path, continuations)
+            .map_err(|e| anyhow!("failed to create literal set constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
     }
 
-    fn next(&self, index: usize) -> anyhow::Result<()> {
-        let inner = self.inner.clone();
-        let constraint = self.constraint.clone();
-        let cache = self.cache.clone();
-        let (tx, rx) = channel();
-        spawn_fifo(move || {
-            let mut inner = inner.lock().expect("error locking inner state");
-            let mut cache = cache.lock().expect("error locking cache");
-            tx.send(()).expect("failed to send on channel");
-            let Some(next_state) = constraint.get_next_state(&inner.state, index) else {
-                inner.is_invalid = true;
-                return;
-            };
-            inner.state = next_state;
-            if let Some((indices, is_match)) = cache.get(&inner.state).cloned() {
-                inner.indices = indices;
-                inner.is_match = is_match;
-            } else {
-                inner.indices = constraint.get_valid_continuations(&inner.state);
-                inner.is_match = constraint.is_match_state(&inner.state);
-                cache.put(inner.state.clone(), (inner.indices.clone(), inner.is_match));
+    // changes are shared with all clones, each picks them up at its next step
+    fn insert(&self, py: Python<'_>, literal: TextOrBytes) -> anyhow::Result<bool> {
+        let constraint = self.0.constraint();
+        let inserted = constraint
+            .insert(&literal)
+            .map_err(|e| anyhow!("failed to insert literal: {e}"))?;
+        if let Err(e) = self.0.update_memory() {
+            // over the memory budget, the literal is removed again; its trie
+            // nodes are kept and reserved by the next successful insert
+            if inserted {
+                constraint.remove(&literal);
             }
-        });
-        // wait until spawned thread signals that is has locked
-        // the inner state, otherwise some unexpected behavior could occurr
-        rx.recv()?;
-        Ok(())
+            return Err(e);
+        }
+        self.0.refresh(py)?;
+        Ok(inserted)
     }
-}
 
-#[pyclass]
-pub struct LR1Parser {
-    inner: LR1GrammarParser,
+    // removing frees no memory, trie nodes are kept for later inserts,
+    // so the memory reservation stays as is
+    fn remove(&self, py: Python<'_>, literal: TextOrBytes) -> anyhow::Result<bool> {
+        let removed = self.0.constraint().remove(literal);
+        self.0.refresh(py)?;
+        Ok(removed)
+    }
+
+    fn __contains__(&self, literal: TextOrBytes) -> bool {
+        self.0.constraint().contains(literal)
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.constraint().len()
+    }
 }
 
-#[pymethods]
-impl LR1Parser {
+py_constraint! {
+    struct TaggedUnionConstraint(TaggedUnion);
+
     #[new]
-    fn new(grammar: &str, lexer: &str) -> anyhow::Result<Self> {
-        let inner = LR1GrammarParser::new(grammar, lexer).map_err(|e| {
-            anyhow!(
-                "failed to create LR(1) grammar parser from grammar {} and lexer {}: {}",
-                grammar,
-                lexer,
-                e
-            )
-        })?;
-        Ok(Self { inner })
+    #[pyo3(signature = (grammars, continuations, on_invalid = "sticky"))]
+    fn new(
+        grammars: Vec<(String, String, String)>,
+        continuations: PyContinuations,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let grammars: Vec<_> = grammars
+            .iter()
+            .map(|(name, grammar, lexer)| (name.as_str(), grammar.as_str(), lexer.as_str()))
+            .collect();
+        let constraint = TaggedUnion::new(&grammars, continuations)
+            .map_err(|e| anyhow!("failed to create tagged union constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
     }
 
-    #[staticmethod]
-    fn from_files(grammar_path: &str, lexer_path: &str) -> anyhow::Result<Self> {
-        let inner = LR1GrammarParser::from_files(grammar_path, lexer_path).map_err(|e| {
-            anyhow!(
-                "failed to create LR(1) grammar parser from files {} and {}: {}",
-                grammar_path,
-                lexer_path,
-                e
-            )
-        })?;
-        Ok(Self { inner })
+    fn names(&self) -> Vec<String> {
+        self.0.constraint().names().to_vec()
     }
 
-    #[pyo3(signature = (input, skip_empty = false, collapse_single = false))]
-    fn prefix_parse<'py>(
-        &self,
-        py: Python<'py>,
-        input: &[u8],
-        skip_empty: bool,
-        collapse_single: bool,
-    ) -> anyhow::Result<(Bound<'py, PyDict>, Vec<u8>)> {
-        let (parse, end) = self
-            .inner
-            .prefix_parse(input, skip_empty, collapse_single)
-            .map_err(|e| anyhow!("failed to parse input: {e}"))?;
-        let parse_dict = parse_into_py(std::str::from_utf8(input)?, &parse, py)?;
-        Ok((parse_dict, end.to_vec()))
+    fn candidates(&self, py: Python<'_>) -> anyhow::Result<Vec<String>> {
+        self.0.with_state(py, |state| {
+            self.0
+                .constraint()
+                .candidates(state)
+                .map(String::from)
+                .collect()
+        })
+    }
+
+    fn matched(&self, py: Python<'_>) -> anyhow::Result<Option<String>> {
+        self.0
+            .with_state(py, |state| self.0.constraint().matched(state).map(String::from))
     }
 
     #[pyo3(signature = (input, skip_empty = false, collapse_single = false))]
     fn parse<'py>(
         &self,
         py: Python<'py>,
-        input: &str,
+        input: TextOrBytes,
         skip_empty: bool,
         collapse_single: bool,
-    ) -> anyhow::Result<Bound<'py, PyDict>> {
-        let parse = self
-            .inner
-            .parse(input, skip_empty, collapse_single)
+    ) -> anyhow::Result<(String, Bound<'py, PyDict>)> {
+        let constraint = self.0.constraint();
+        let (name, parse) = constraint
+            .parse(&input, skip_empty, collapse_single)
             .map_err(|e| anyhow!("failed to parse input: {e}"))?;
-        Ok(parse_into_py(input, &parse, py)?)
+        let byte_mode = constraint
+            .parser(name)
+            .is_some_and(|parser| parser.byte_mode());
+        Ok((name.to_string(), parse_into_py(&parse, byte_mode, py)?))
     }
+}
 
-    fn lex(&self, input: &str) -> anyhow::Result<Vec<TokenAndSpan<'_>>> {
-        self.inner
-            .lex(input)
-            .map_err(|e| anyhow!("failed to lex input: {e}"))
+py_constraint! {
+    struct TemplateConstraint(Template);
+
+    #[new]
+    #[pyo3(signature = (template, continuations, grammars = vec![], on_invalid = "sticky"))]
+    fn new(
+        template: &str,
+        continuations: PyContinuations,
+        grammars: Vec<(String, String, String)>,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let grammars: Vec<_> = grammars
+            .iter()
+            .map(|(name, grammar, lexer)| (name.as_str(), grammar.as_str(), lexer.as_str()))
+            .collect();
+        let constraint = Template::new(template, &grammars, continuations)
+            .map_err(|e| anyhow!("failed to create template constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn part(&self, py: Python<'_>) -> anyhow::Result<usize> {
+        self.0.with_state(py, |state| self.0.constraint().part(state))
+    }
+}
+
+py_constraint! {
+    struct DispatchConstraint(Dispatch);
+
+    #[new]
+    #[pyo3(signature = (tools, continuations, base = None, on_invalid = "sticky"))]
+    fn new(
+        tools: Vec<(String, String, String, String, String)>,
+        continuations: PyContinuations,
+        base: Option<&str>,
+        on_invalid: &str,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let tools: Vec<_> = tools
+            .iter()
+            .map(|(name, start, end, grammar, lexer)| {
+                (
+                    name.as_str(),
+                    start.as_str(),
+                    end.as_str(),
+                    grammar.as_str(),
+                    lexer.as_str(),
+                )
+            })
+            .collect();
+        let constraint = Dispatch::new(&tools, base, continuations)
+            .map_err(|e| anyhow!("failed to create dispatch constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.0.constraint().names().map(String::from).collect()
+    }
+
+    fn active(&self, py: Python<'_>) -> anyhow::Result<Option<String>> {
+        self.0
+            .with_state(py, |state| self.0.constraint().active(state).map(String::from))
+    }
+}
+
+py_constraint! {
+    struct RepeatedConstraint(Repeated<LR1GrammarConstraint>);
+
+    #[new]
+    #[pyo3(signature = (
+        grammar,
+        lexer,
+        separator,
+        continuations,
+        min = 1,
+        max = None,
+        on_invalid = "sticky",
+        literal = false,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        grammar: &str,
+        lexer: &str,
+        separator: Option<&str>,
+        continuations: PyContinuations,
+        min: usize,
+        max: Option<usize>,
+        on_invalid: &str,
+        literal: bool,
+    ) -> anyhow::Result<Self> {
+        let continuations = continuations.0;
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let inner = LR1GrammarConstraint::new(grammar, lexer, continuations)
+            .map_err(|e| anyhow!("failed to create LR(1) grammar constraint: {e}"))?;
+        let constraint = match separator {
+            Some(separator) if !literal => Repeated::new(inner, separator, min, max),
+            separator => Repeated::with_literal(inner, separator, min, max),
+        }
+        .map_err(|e| anyhow!("failed to create repeated constraint: {e}"))?;
+        Ok(Self(PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid)))
+    }
+
+    fn completed(&self, py: Python<'_>) -> anyhow::Result<usize> {
+        self.0.with_state(py, |state| self.0.constraint().completed(state))
+    }
+
+    fn spans(&self, text: TextOrBytes) -> Option<Vec<(usize, usize)>> {
+        self.0.constraint().spans(text.as_ref())
     }
 }
 
 fn parse_into_py<'py>(
-    text: impl AsRef<[u8]>,
     parse: &LR1Parse<'_>,
+    byte_mode: bool,
     py: Python<'py>,
 ) -> PyResult<Bound<'py, PyDict>> {
     let dict = PyDict::new(py);
-    let bytes = text.as_ref();
     match parse {
         LR1Parse::Empty(name) => {
             dict.set_item("name", name)?;
@@ -482,7 +3846,11 @@ fn parse_into_py<'py>(
         LR1Parse::Terminal(name, span, value) => {
             dict.set_item("name", name)?;
             let &(start, end) = span;
-            dict.set_item("value", String::from_utf8_lossy(value))?;
+            if byte_mode {
+                dict.set_item("value", PyBytes::new(py, value))?;
+            } else {
+                dict.set_item("value", String::from_utf8_lossy(value))?;
+            }
             dict.set_item("byte_span", (start, end))?;
         }
         LR1Parse::NonTerminal(name, children) => {
@@ -491,7 +3859,7 @@ fn parse_into_py<'py>(
                 py,
                 children
                     .iter()
-                    .map(|c| parse_into_py(bytes, c, py))
+                    .map(|c| parse_into_py(c, byte_mode, py))
                     .collect::<PyResult<Vec<_>>>()?,
             )?;
             dict.set_item("children", children)?;
@@ -500,11 +3868,482 @@ fn parse_into_py<'py>(
     Ok(dict)
 }
 
+// object safe view on a PyConstraintCore, so that the high level guides can
+// wrap any constraint behind the same python class
+trait GuideCore: Send + Sync {
+    fn indices(&self, py: Python<'_>) -> anyhow::Result<Array1<i32>>;
+    fn next(&self, py: Python<'_>, index: usize) -> anyhow::Result<()>;
+    fn reset(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<()>;
+    fn is_match(&self, py: Python<'_>) -> anyhow::Result<bool>;
+    fn is_finished(&self, py: Python<'_>) -> anyhow::Result<bool>;
+    fn try_clone(&self, py: Python<'_>) -> anyhow::Result<Box<dyn GuideCore>>;
+}
+
+impl<C> GuideCore for PyConstraintCore<C>
+where
+    C: Constraint + MemoryUsage + Send + Sync + 'static,
+    C::State: Clone + Hash + Eq + Send + Sync + 'static,
+{
+    fn indices(&self, py: Python<'_>) -> anyhow::Result<Array1<i32>> {
+        with_lock(py, &self.inner, |inner| inner.indices.clone())
+    }
+
+    fn next(&self, py: Python<'_>, index: usize) -> anyhow::Result<()> {
+        PyConstraintCore::next(self, py, index)
+    }
+
+    fn reset(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
+        PyConstraintCore::reset(self, py, prefix)
+    }
+
+    fn is_match(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        PyConstraintCore::is_match(self, py)
+    }
+
+    // nothing can follow, either because the text is complete or invalid
+    fn is_finished(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        with_lock(py, &self.inner, |inner| {
+            inner.is_invalid || inner.indices.is_empty()
+        })
+    }
+
+    fn try_clone(&self, py: Python<'_>) -> anyhow::Result<Box<dyn GuideCore>> {
+        PyConstraintCore::try_clone(self, py).map(|core| Box::new(core) as Box<dyn GuideCore>)
+    }
+}
+
+// high level constraint returned by json_schema, regex and grammar, with the
+// same interface regardless of the constraint behind it
+#[pyclass(frozen)]
+struct Guide {
+    core: Box<dyn GuideCore>,
+    vocab_size: usize,
+}
+
+impl Guide {
+    fn new<C>(constraint: C, vocab_size: usize, on_invalid: &str) -> anyhow::Result<Self>
+    where
+        C: Constraint + MemoryUsage + Send + Sync + 'static,
+        C::State: Clone + Hash + Eq + Send + Sync + 'static,
+    {
+        let on_invalid = InvalidPolicy::parse(on_invalid)?;
+        let core = PyConstraintCore::new(constraint)?.with_invalid_policy(on_invalid);
+        Ok(Self {
+            core: Box::new(core),
+            vocab_size,
+        })
+    }
+}
+
+#[pymethods]
+impl Guide {
+    fn mask<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<bool>>> {
+        let mut mask = Array1::from_elem(self.vocab_size, false);
+        for index in self.core.indices(py)? {
+            mask[index as usize] = true;
+        }
+        Ok(mask.into_pyarray(py))
+    }
+
+    fn allowed<'py>(&self, py: Python<'py>) -> anyhow::Result<Bound<'py, PyArray1<i32>>> {
+        self.core
+            .indices(py)
+            .map(|indices| indices.into_pyarray(py))
+    }
+
+    fn advance(&self, py: Python<'_>, token: usize) -> anyhow::Result<()> {
+        self.core.next(py, token)
+    }
+
+    #[pyo3(signature = (prefix = None))]
+    fn reset(&self, py: Python<'_>, prefix: Option<Vec<u8>>) -> anyhow::Result<()> {
+        self.core.reset(py, prefix)
+    }
+
+    fn clone(&self, py: Python<'_>) -> anyhow::Result<Self> {
+        Ok(Self {
+            core: self.core.try_clone(py)?,
+            vocab_size: self.vocab_size,
+        })
+    }
+
+    #[getter]
+    fn finished(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        self.core.is_finished(py)
+    }
+
+    #[getter]
+    fn is_match(&self, py: Python<'_>) -> anyhow::Result<bool> {
+        self.core.is_match(py)
+    }
+
+    #[getter]
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+}
+
+#[pyfunction(name = "json_schema")]
+#[pyo3(signature = (schema, vocab, on_invalid = "sticky"))]
+fn py_json_schema(
+    py: Python<'_>,
+    schema: &Bound<'_, PyAny>,
+    vocab: PyContinuations,
+    on_invalid: &str,
+) -> anyhow::Result<Guide> {
+    let vocab = vocab.0;
+    // schemas can be given as json strings or as python dicts
+    let schema: String = match schema.extract() {
+        Ok(schema) => schema,
+        Err(_) => py
+            .import("json")?
+            .call_method1("dumps", (schema,))?
+            .extract()?,
+    };
+    let vocab_size = vocab.len();
+    let constraint = py
+        .detach(|| JsonSchemaConstraint::new(&schema, vocab).map_err(|e| e.to_string()))
+        .map_err(|e| anyhow!("failed to create json schema guide: {e}"))?;
+    Guide::new(constraint, vocab_size, on_invalid)
+}
+
+#[pyfunction(name = "regex")]
+#[pyo3(signature = (pattern, vocab, on_invalid = "sticky"))]
+fn py_regex(
+    py: Python<'_>,
+    pattern: &str,
+    vocab: PyContinuations,
+    on_invalid: &str,
+) -> anyhow::Result<Guide> {
+    let vocab = vocab.0;
+    let vocab_size = vocab.len();
+    let constraint = py
+        .detach(|| RegularExpressionConstraint::new(pattern, vocab).map_err(|e| e.to_string()))
+        .map_err(|e| anyhow!("failed to create regex guide from '{pattern}': {e}"))?;
+    Guide::new(constraint, vocab_size, on_invalid)
+}
+
+#[pyfunction(name = "grammar")]
+#[pyo3(signature = (grammar, lexer, vocab, on_invalid = "sticky"))]
+fn py_grammar(
+    py: Python<'_>,
+    grammar: &str,
+    lexer: &str,
+    vocab: PyContinuations,
+    on_invalid: &str,
+) -> anyhow::Result<Guide> {
+    let vocab = vocab.0;
+    let vocab_size = vocab.len();
+    let constraint = py
+        .detach(|| LR1GrammarConstraint::new(grammar, lexer, vocab).map_err(|e| e.to_string()))
+        .map_err(|e| anyhow!("failed to create grammar guide: {e}"))?;
+    Guide::new(constraint, vocab_size, on_invalid)
+}
+
+#[pyfunction]
+#[pyo3(signature = (limit = None, policy = "deny"))]
+fn set_memory_limit(limit: Option<usize>, policy: &str) -> anyhow::Result<()> {
+    let policy = match policy {
+        "deny" => MemoryPolicy::Deny,
+        "evict" => MemoryPolicy::Evict,
+        _ => {
+            return Err(anyhow!(
+                "unknown memory policy {policy}, expected deny or evict"
+            ))
+        }
+    };
+    let budget = MemoryBudget::global();
+    budget.set_limit(limit);
+    budget.set_policy(policy);
+    Ok(())
+}
+
+#[pyfunction]
+fn memory_used() -> usize {
+    MemoryBudget::global().used()
+}
+
+type PyDistinction<'py> = (Option<Bound<'py, PyBytes>>, Option<Bound<'py, PyBytes>>);
+
+fn py_distinction(py: Python<'_>, distinction: Distinction) -> PyDistinction<'_> {
+    let bytes = |input: Option<Vec<u8>>| input.map(|input| PyBytes::new(py, &input));
+    (bytes(distinction.left_only), bytes(distinction.right_only))
+}
+
+#[pyfunction(name = "distinguish_regex")]
+fn py_distinguish_regex<'py>(
+    py: Python<'py>,
+    left: &str,
+    right: &str,
+) -> anyhow::Result<PyDistinction<'py>> {
+    let distinction = py
+        .detach(|| distinguish_regex(left, right).map_err(|e| e.to_string()))
+        .map_err(|e| anyhow!("failed to compare regular expressions: {e}"))?;
+    Ok(py_distinction(py, distinction))
+}
+
+#[pyfunction(name = "abnf_to_lr1")]
+fn py_abnf_to_lr1(abnf: &str) -> anyhow::Result<(String, String)> {
+    abnf_to_lr1(abnf).map_err(|e| anyhow!("failed to convert abnf grammar: {e}"))
+}
+
+#[pyfunction(name = "ebnf_to_lr1")]
+fn py_ebnf_to_lr1(ebnf: &str) -> anyhow::Result<(String, String)> {
+    ebnf_to_lr1(ebnf).map_err(|e| anyhow!("failed to convert ebnf grammar: {e}"))
+}
+
+#[pyfunction(name = "lark_to_lr1")]
+fn py_lark_to_lr1(lark: &str) -> anyhow::Result<(String, String)> {
+    lark_to_lr1(lark).map_err(|e| anyhow!("failed to convert lark grammar: {e}"))
+}
+
+#[pyfunction(name = "gbnf_to_lr1")]
+fn py_gbnf_to_lr1(gbnf: &str) -> anyhow::Result<(String, String)> {
+    gbnf_to_lr1(gbnf).map_err(|e| anyhow!("failed to convert gbnf grammar: {e}"))
+}
+
+#[pyfunction(name = "guidance_to_lr1")]
+fn py_guidance_to_lr1(json: &str) -> anyhow::Result<(String, String)> {
+    guidance_to_lr1(json).map_err(|e| anyhow!("failed to convert guidance grammar: {e}"))
+}
+
+#[pyfunction(name = "lr1_to_guidance")]
+fn py_lr1_to_guidance(grammar: &str, lexer: &str) -> anyhow::Result<String> {
+    lr1_to_guidance(grammar, lexer)
+        .map_err(|e| anyhow!("failed to convert grammar to guidance format: {e}"))
+}
+
+#[pyfunction(name = "grammar_docs")]
+#[pyo3(signature = (grammar, lexer, format = "markdown"))]
+fn py_grammar_docs(grammar: &str, lexer: &str, format: &str) -> anyhow::Result<String> {
+    let format = match format {
+        "markdown" => DocFormat::Markdown,
+        "html" => DocFormat::Html,
+        _ => {
+            return Err(anyhow!(
+                "unknown docs format {format}, expected markdown or html"
+            ))
+        }
+    };
+    grammar_docs(grammar, lexer, format).map_err(|e| anyhow!("failed to generate docs: {e}"))
+}
+
+fn value_into_py<'py>(value: &Value, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        Value::Null => py.None().into_bound(py),
+        Value::Bool(b) => PyBool::new(py, *b).to_owned().into_any(),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => i.into_pyobject(py)?.into_any(),
+            (_, Some(u)) => u.into_pyobject(py)?.into_any(),
+            _ => n.as_f64().unwrap_or(f64::NAN).into_pyobject(py)?.into_any(),
+        },
+        Value::String(s) => s.into_pyobject(py)?.into_any(),
+        Value::Array(values) => PyList::new(
+            py,
+            values
+                .iter()
+                .map(|value| value_into_py(value, py))
+                .collect::<PyResult<Vec<_>>>()?,
+        )?
+        .into_any(),
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, value) in map {
+                dict.set_item(key, value_into_py(value, py)?)?;
+            }
+            dict.into_any()
+        }
+    })
+}
+
+// owned view of a parse dict for queries, text as in LR1Parse::flatten
+struct PyParseNode {
+    name: String,
+    text: String,
+    children: Vec<PyParseNode>,
+}
+
+impl PyParseNode {
+    fn from_py(tree: &Bound<'_, PyDict>) -> anyhow::Result<Self> {
+        let name = tree
+            .get_item("name")?
+            .ok_or_else(|| anyhow!("parse tree node without name"))?
+            .extract()?;
+        let children: Vec<_> = match tree.get_item("children")? {
+            Some(children) => children
+                .cast::<PyList>()
+                .map_err(|_| anyhow!("children of {name} must be a list"))?
+                .iter()
+                .map(|child| {
+                    let child = child
+                        .cast_into::<PyDict>()
+                        .map_err(|_| anyhow!("children of {name} must be dicts"))?;
+                    Self::from_py(&child)
+                })
+                .collect::<anyhow::Result<_>>()?,
+            None => vec![],
+        };
+        let text = match tree.get_item("value")? {
+            Some(value) => match value.cast::<PyBytes>() {
+                Ok(bytes) => String::from_utf8_lossy(bytes.as_bytes()).to_string(),
+                Err(_) => value.extract()?,
+            },
+            None => children
+                .iter()
+                .filter(|child| !child.text.is_empty())
+                .map(|child| child.text.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        Ok(Self {
+            name,
+            text,
+            children,
+        })
+    }
+}
+
+impl QueryNode for PyParseNode {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn children(&self) -> &[Self] {
+        &self.children
+    }
+
+    fn text(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.text)
+    }
+}
+
+#[pyfunction(name = "select")]
+fn py_select<'py>(
+    tree: &Bound<'py, PyDict>,
+    query: &str,
+) -> anyhow::Result<Vec<Bound<'py, PyDict>>> {
+    let query = ParseQuery::new(query).map_err(|e| anyhow!("failed to parse query: {e}"))?;
+    let root = PyParseNode::from_py(tree)?;
+    // return the matching dicts of the tree itself, not copies
+    query
+        .select_paths(&root)
+        .into_iter()
+        .map(|(path, _)| {
+            let mut node = tree.clone();
+            for i in path {
+                node = node
+                    .get_item("children")?
+                    .ok_or_else(|| anyhow!("parse tree changed during query"))?
+                    .get_item(i)?
+                    .cast_into()
+                    .map_err(|_| anyhow!("parse tree changed during query"))?;
+            }
+            Ok(node)
+        })
+        .collect()
+}
+
+#[pyfunction(name = "json_schema_to_lr1")]
+fn py_json_schema_to_lr1(schema: &str) -> anyhow::Result<(String, String)> {
+    json_schema_to_lr1(schema).map_err(|e| anyhow!("failed to convert json schema: {e}"))
+}
+
+#[pyfunction(name = "proto_to_lr1")]
+#[pyo3(signature = (proto, message, format = "text"))]
+fn py_proto_to_lr1(proto: &str, message: &str, format: &str) -> anyhow::Result<(String, String)> {
+    let format: ProtoFormat = format.parse().map_err(|e: String| anyhow!(e))?;
+    proto_to_lr1(proto, message, format).map_err(|e| anyhow!("failed to convert proto: {e}"))
+}
+
+#[pyfunction(name = "dtd_to_lr1")]
+fn py_dtd_to_lr1(dtd: &str, root: &str) -> anyhow::Result<(String, String)> {
+    dtd_to_lr1(dtd, root).map_err(|e| anyhow!("failed to convert dtd: {e}"))
+}
+
+#[pyfunction(name = "strftime_to_regex")]
+fn py_strftime_to_regex(layout: &str) -> anyhow::Result<String> {
+    strftime_to_regex(layout).map_err(|e| anyhow!("failed to convert strftime layout: {e}"))
+}
+
+#[pyfunction(name = "inline_rules")]
+fn py_inline_rules(grammar: &str) -> anyhow::Result<String> {
+    inline_rules(grammar)
+        .map(|inlined| inlined.into_parts().0)
+        .map_err(|e| anyhow!("failed to inline grammar rules: {e}"))
+}
+
+#[pyfunction(name = "builtin_grammar")]
+fn py_builtin_grammar(name: &str) -> anyhow::Result<(&'static str, &'static str)> {
+    builtin::grammar(name).ok_or_else(|| {
+        anyhow!(
+            "unknown built-in grammar {name}, expected one of {}",
+            builtin::NAMES.join(", ")
+        )
+    })
+}
+
+#[pyfunction(name = "builtin_grammars")]
+fn py_builtin_grammars() -> Vec<&'static str> {
+    builtin::NAMES.to_vec()
+}
+
+#[pyfunction(name = "run_length_order")]
+fn py_run_length_order(continuations: PyContinuations) -> Vec<usize> {
+    let continuations = continuations.0;
+    run_length_order(&continuations)
+}
+
 /// The module containing all python bindings for the grammar utils library.
-#[pymodule]
+#[pymodule(gil_used = false)]
 fn _internal(_: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(set_memory_limit, m)?)?;
+    m.add_function(wrap_pyfunction!(memory_used, m)?)?;
+    m.add_function(wrap_pyfunction!(py_run_length_order, m)?)?;
+    m.add_function(wrap_pyfunction!(py_distinguish_regex, m)?)?;
+    m.add_function(wrap_pyfunction!(py_abnf_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_ebnf_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_gbnf_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_lark_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_guidance_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_lr1_to_guidance, m)?)?;
+    m.add_function(wrap_pyfunction!(py_json_schema_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_proto_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_dtd_to_lr1, m)?)?;
+    m.add_function(wrap_pyfunction!(py_strftime_to_regex, m)?)?;
+    m.add_function(wrap_pyfunction!(py_inline_rules, m)?)?;
+    m.add_function(wrap_pyfunction!(py_builtin_grammar, m)?)?;
+    m.add_function(wrap_pyfunction!(py_builtin_grammars, m)?)?;
+    m.add_function(wrap_pyfunction!(py_grammar_docs, m)?)?;
+    m.add_function(wrap_pyfunction!(py_select, m)?)?;
+    m.add_function(wrap_pyfunction!(py_json_schema, m)?)?;
+    m.add_function(wrap_pyfunction!(py_regex, m)?)?;
+    m.add_function(wrap_pyfunction!(py_grammar, m)?)?;
+    m.add_class::<Guide>()?;
     m.add_class::<RegexConstraint>()?;
     m.add_class::<LR1Constraint>()?;
+    m.add_class::<LR1Compilation>()?;
+    m.add_class::<MultiVocabConstraint>()?;
+    m.add_class::<ConstraintScheduler>()?;
     m.add_class::<LR1Parser>()?;
+    m.add_class::<LexicalConstraint>()?;
+    m.add_class::<EarleyConstraint>()?;
+    m.add_class::<GLRConstraint>()?;
+    m.add_class::<PushdownConstraint>()?;
+    m.add_class::<PegConstraint>()?;
+    m.add_class::<ChoiceConstraint>()?;
+    m.add_class::<SemanticConstraint>()?;
+    m.add_class::<RegexSetConstraint>()?;
+    m.add_class::<LazyRegexConstraint>()?;
+    m.add_class::<LiteralSetConstraint>()?;
+    m.add_class::<TaggedUnionConstraint>()?;
+    m.add_class::<TemplateConstraint>()?;
+    m.add_class::<DispatchConstraint>()?;
+    m.add_class::<RepeatedConstraint>()?;
+    m.add_class::<Utf8Constraint>()?;
+    m.add_class::<CheckReport>()?;
+    m.add_class::<Explanation>()?;
+    m.add_class::<Classification>()?;
+    m.add_class::<Transcript>()?;
+    m.add_class::<TranscriptVerification>()?;
     Ok(())
 }
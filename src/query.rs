@@ -0,0 +1,419 @@
+use std::{borrow::Cow, error::Error};
+
+use crate::LR1Parse;
+
+// nodes of a tree that can be queried, e.g. parse trees
+pub trait QueryNode: Sized {
+    fn name(&self) -> &str;
+
+    fn children(&self) -> &[Self];
+
+    // text of the node that value predicates compare against
+    fn text(&self) -> Cow<'_, str>;
+}
+
+impl QueryNode for LR1Parse<'_> {
+    fn name(&self) -> &str {
+        LR1Parse::name(self)
+    }
+
+    fn children(&self) -> &[Self] {
+        match self {
+            LR1Parse::NonTerminal(_, children) => children,
+            _ => &[],
+        }
+    }
+
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            LR1Parse::Terminal(.., value) => String::from_utf8_lossy(value),
+            _ => Cow::Owned(self.flatten()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Predicate {
+    // 1-based position among the matches of a step below the same node,
+    // negative positions count from the end
+    Position(i64),
+    // node text equals the value
+    Text(String),
+    // some child has the name, and the text if given
+    Child(String, Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    // descendants instead of children
+    descendants: bool,
+    // none matches any name
+    name: Option<String>,
+    predicates: Vec<Predicate>,
+}
+
+// xpath-like queries over parse trees, e.g. call/args/arg[2] for the second arg
+// in the args of every call below the root:
+// - steps are separated by / for children and // for descendants
+// - a leading / matches the first step against the root itself
+// - * matches any name
+// - predicates are [n] for the n-th match below the same node (1-based,
+//   negative from the end), [="text"] for the node text (terminal values,
+//   joined by spaces for non-terminals), [name] for nodes with a child of
+//   that name, and [name="text"] for nodes with such a child with that text
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseQuery {
+    absolute: bool,
+    steps: Vec<Step>,
+}
+
+struct QueryParser<'q> {
+    query: &'q str,
+    pos: usize,
+}
+
+impl QueryParser<'_> {
+    fn error(&self, expected: &str) -> Box<dyn Error> {
+        format!(
+            "invalid query {:?} at position {}: expected {expected}",
+            self.query, self.pos
+        )
+        .into()
+    }
+
+    fn rest(&self) -> &str {
+        &self.query[self.pos..]
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(self.rest().len());
+        let name = self.rest()[..len].to_string();
+        self.pos += len;
+        (!name.is_empty()).then_some(name)
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        let Some(quote) = self
+            .rest()
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+        else {
+            return Err(self.error("a quoted string"));
+        };
+        self.pos += 1;
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    let Some((_, escaped)) = chars.next() else {
+                        break;
+                    };
+                    value.push(escaped);
+                }
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                }
+                c => value.push(c),
+            }
+        }
+        self.pos = self.query.len();
+        Err(self.error("a closing quote"))
+    }
+
+    fn predicate(&mut self) -> Result<Predicate, Box<dyn Error>> {
+        let digits = self
+            .rest()
+            .char_indices()
+            .take_while(|&(i, c)| c.is_ascii_digit() || (i == 0 && c == '-'))
+            .count();
+        if digits > 0 {
+            let position = self.rest()[..digits]
+                .parse()
+                .ok()
+                .filter(|&p| p != 0)
+                .ok_or_else(|| self.error("a non-zero position"))?;
+            self.pos += digits;
+            return Ok(Predicate::Position(position));
+        }
+        let name = self.name();
+        let text = if self.eat("=") {
+            Some(self.string()?)
+        } else {
+            None
+        };
+        match (name, text) {
+            (Some(name), text) => Ok(Predicate::Child(name, text)),
+            (None, Some(text)) => Ok(Predicate::Text(text)),
+            (None, None) => Err(self.error("a position, a name or =")),
+        }
+    }
+
+    fn step(&mut self, descendants: bool) -> Result<Step, Box<dyn Error>> {
+        let name = if self.eat("*") {
+            None
+        } else {
+            Some(self.name().ok_or_else(|| self.error("a name or *"))?)
+        };
+        let mut predicates = vec![];
+        while self.eat("[") {
+            predicates.push(self.predicate()?);
+            if !self.eat("]") {
+                return Err(self.error("]"));
+            }
+        }
+        Ok(Step {
+            descendants,
+            name,
+            predicates,
+        })
+    }
+}
+
+impl ParseQuery {
+    pub fn new(query: &str) -> Result<Self, Box<dyn Error>> {
+        let mut parser = QueryParser { query, pos: 0 };
+        let mut steps = vec![];
+        let absolute = !parser.rest().starts_with("//") && parser.eat("/");
+        loop {
+            let descendants = parser.eat("//") || (!steps.is_empty() && !parser.eat("/"));
+            if descendants && steps.is_empty() && absolute {
+                return Err(parser.error("a name or *"));
+            }
+            steps.push(parser.step(descendants)?);
+            if parser.rest().is_empty() {
+                break;
+            }
+            if !parser.rest().starts_with('/') {
+                return Err(parser.error("/ or the end of the query"));
+            }
+        }
+        Ok(Self { absolute, steps })
+    }
+
+    // matching nodes in document order, each at most once
+    pub fn select<'n, N: QueryNode>(&self, root: &'n N) -> Vec<&'n N> {
+        self.select_paths(root)
+            .into_iter()
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    // matching nodes together with their paths of child indices from the root
+    pub(crate) fn select_paths<'n, N: QueryNode>(&self, root: &'n N) -> Vec<(Vec<usize>, &'n N)> {
+        let mut context = vec![(vec![], root)];
+        for (i, step) in self.steps.iter().enumerate() {
+            let mut next = vec![];
+            for &(ref path, node) in &context {
+                let candidates = if i == 0 && self.absolute {
+                    vec![(path.clone(), node)]
+                } else if i == 0 && step.descendants {
+                    // a leading // also matches the root
+                    let mut candidates = vec![(path.clone(), node)];
+                    descendants(node, path, &mut candidates);
+                    candidates
+                } else if step.descendants {
+                    let mut candidates = vec![];
+                    descendants(node, path, &mut candidates);
+                    candidates
+                } else {
+                    children(node, path)
+                };
+                next.extend(step.filter(candidates));
+            }
+            next.sort_by(|(a, _), (b, _)| a.cmp(b));
+            next.dedup_by(|(a, _), (b, _)| a == b);
+            context = next;
+        }
+        context
+    }
+}
+
+fn children<'n, N: QueryNode>(node: &'n N, path: &[usize]) -> Vec<(Vec<usize>, &'n N)> {
+    node.children()
+        .iter()
+        .enumerate()
+        .map(|(i, child)| {
+            let mut path = path.to_vec();
+            path.push(i);
+            (path, child)
+        })
+        .collect()
+}
+
+// proper descendants in document order
+fn descendants<'n, N: QueryNode>(
+    node: &'n N,
+    path: &[usize],
+    candidates: &mut Vec<(Vec<usize>, &'n N)>,
+) {
+    for (path, child) in children(node, path) {
+        candidates.push((path.clone(), child));
+        descendants(child, &path, candidates);
+    }
+}
+
+impl Step {
+    fn filter<'n, N: QueryNode>(
+        &self,
+        candidates: Vec<(Vec<usize>, &'n N)>,
+    ) -> Vec<(Vec<usize>, &'n N)> {
+        let mut matches: Vec<_> = candidates
+            .into_iter()
+            .filter(|(_, node)| self.name.as_ref().is_none_or(|name| node.name() == name))
+            .collect();
+        for predicate in &self.predicates {
+            matches = match predicate {
+                &Predicate::Position(position) => {
+                    let index = if position > 0 {
+                        usize::try_from(position - 1).ok()
+                    } else {
+                        matches.len().checked_sub(position.unsigned_abs() as usize)
+                    };
+                    index
+                        .filter(|&i| i < matches.len())
+                        .map(|i| vec![matches.swap_remove(i)])
+                        .unwrap_or_default()
+                }
+                Predicate::Text(text) => matches
+                    .into_iter()
+                    .filter(|(_, node)| node.text() == text.as_str())
+                    .collect(),
+                Predicate::Child(name, text) => matches
+                    .into_iter()
+                    .filter(|(_, node)| {
+                        node.children().iter().any(|child| {
+                            child.name() == name
+                                && text
+                                    .as_ref()
+                                    .is_none_or(|text| child.text() == text.as_str())
+                        })
+                    })
+                    .collect(),
+            };
+        }
+        matches
+    }
+}
+
+impl<'a> LR1Parse<'a> {
+    // nodes matching an xpath-like query, see ParseQuery
+    pub fn select(&self, query: &str) -> Result<Vec<&LR1Parse<'a>>, Box<dyn Error>> {
+        Ok(ParseQuery::new(query)?.select(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{fs, path::PathBuf};
+
+    use crate::LR1GrammarParser;
+
+    const GRAMMAR: &str = r#"
+%start Calls
+%%
+Calls: Call | Calls Call ;
+Call: 'NAME' 'LPAREN' Args 'RPAREN' ;
+Args: | Arg | Args 'COMMA' Arg ;
+Arg: 'NAME' | 'NUMBER' | Call ;
+"#;
+    const LEXER: &str = r#"
+%%
+NAME [a-z]+
+NUMBER [0-9]+
+LPAREN \(
+RPAREN \)
+COMMA ,
+; \s+
+"#;
+
+    fn texts(nodes: &[&LR1Parse<'_>]) -> Vec<String> {
+        nodes.iter().map(|node| node.flatten()).collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let parser = LR1GrammarParser::new(GRAMMAR, LEXER).unwrap();
+        let parse = parser.parse("f(a, 1, g(2)) h() f(b)", true, false).unwrap();
+
+        let calls = parse.select("Call").unwrap();
+        assert_eq!(texts(&calls), ["f ( b )"]);
+        let calls = parse.select("//Call").unwrap();
+        assert_eq!(
+            texts(&calls),
+            ["f ( a , 1 , g ( 2 ) )", "g ( 2 )", "h ( )", "f ( b )"]
+        );
+        assert_eq!(parse.select("/Calls").unwrap().len(), 1);
+        assert!(parse.select("/Call").unwrap().is_empty());
+
+        // predicates on names and values
+        let args = parse.select("//Call[NAME=\"f\"]//Arg").unwrap();
+        assert_eq!(texts(&args), ["a", "1", "g ( 2 )", "2", "b"]);
+        let names = parse.select("//Call[NAME='f']/NAME").unwrap();
+        assert_eq!(texts(&names), ["f", "f"]);
+        let numbers = parse.select("//NUMBER[=\"2\"]").unwrap();
+        assert_eq!(numbers[0].span(), Some(&(10, 11)));
+        assert_eq!(texts(&parse.select("//Call[-1]").unwrap()), ["f ( b )"]);
+        assert_eq!(texts(&parse.select("//*[=\"h ( )\"]").unwrap()), ["h ( )"]);
+
+        // positions count the matches below the same node
+        let args = parse.select("//Call/Args//Arg[2]").unwrap();
+        assert_eq!(texts(&args), ["1"]);
+        let args = parse.select("//Call/Args//Arg[-1]").unwrap();
+        assert_eq!(texts(&args), ["2", "b"]);
+        let names = parse.select("//Calls/Call[1]/NAME").unwrap();
+        assert_eq!(texts(&names), ["f", "h", "f"]);
+        assert!(parse.select("Call[2]").unwrap().is_empty());
+        assert_eq!(parse.select("*").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_select_json() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("grammars/json");
+        let grammar = fs::read_to_string(dir.join("json.y")).unwrap();
+        let lexer = fs::read_to_string(dir.join("json.l")).unwrap();
+        let parser = LR1GrammarParser::new(&grammar, &lexer).unwrap();
+        let parse = parser
+            .parse(r#"{"name": "x", "tags": ["a", "b", "c"]}"#, true, false)
+            .unwrap();
+        let values = parse.select(r#"//pair[STRING='"name"']/value"#).unwrap();
+        assert_eq!(texts(&values), [r#""x""#]);
+        let tags = parse
+            .select(r#"//pair[STRING='"tags"']/value//value[-1]"#)
+            .unwrap();
+        assert_eq!(texts(&tags), [r#""c""#]);
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        for query in [
+            "", "a/", "a[", "a[0]", "a[1", "a[=b]", "a[=\"b]", "//", "a b", "/[1]", "a]",
+        ] {
+            assert!(ParseQuery::new(query).is_err(), "{query}");
+        }
+        let query = ParseQuery::new("a//b[2][c=\"d\\\"\"]").unwrap();
+        assert_eq!(
+            query.steps[1].predicates,
+            [
+                Predicate::Position(2),
+                Predicate::Child("c".to_string(), Some("d\"".to_string()))
+            ]
+        );
+    }
+}
@@ -1,20 +1,252 @@
-use std::{collections::HashMap, error::Error, fs::File, io::read_to_string, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::read_to_string,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
 
 use crate::{
-    utils::{extract_parts, pattern_from_parts, Part, PrefixDFA},
-    Constraint,
+    lr1::build_pdfa,
+    memory::{continuations_memory_usage, MemoryUsage},
+    strftime_to_regex,
+    unroll::{unroll_lr1, UnrollOverflow},
+    utils::{
+        extract_parts, pattern_from_parts, run_length_order, write_section, ByteReader, Part,
+        PrefixDFA,
+    },
+    ByteConstraint, CompileLimits, Constraint,
 };
 use indexmap::IndexMap;
-use regex::Regex;
+use rayon::prelude::*;
+use regex::{bytes, Regex};
 use regex_automata::util::primitives::StateID;
+use rustc_hash::FxHasher;
 
 pub struct RegularExpressionConstraint {
+    pattern: String,
     pdfa: PrefixDFA,
-    continuations: Vec<Vec<u8>>,
+    continuations: Arc<Continuations>,
+    sorted: bool,
+    segmenter: OnceLock<bytes::Regex>,
+    // computed on first use, see can_match
+    live: OnceLock<HashSet<StateID>>,
+    // see with_precomputed_continuations
+    precomputed: Option<HashMap<StateID, Vec<usize>>>,
+}
+
+// compilation options of a regular expression, applied as inline flags around
+// the whole pattern, so they can still be overridden within it, e.g. (?-i:ID)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RegexFlags {
+    pub case_insensitive: bool,
+    // without unicode, classes like \w and . match single bytes
+    // instead of utf8 encoded characters
+    pub unicode: bool,
+    pub dot_matches_new_line: bool,
+    // by default the whole output has to match, unanchored at the start any
+    // output may come before the match, and unanchored at the end any output
+    // may follow it, e.g. both for outputs that contain a match somewhere
+    pub unanchored_start: bool,
+    pub unanchored_end: bool,
+}
+
+impl Default for RegexFlags {
+    fn default() -> Self {
+        Self {
+            case_insensitive: false,
+            unicode: true,
+            dot_matches_new_line: false,
+            unanchored_start: false,
+            unanchored_end: false,
+        }
+    }
+}
+
+impl RegexFlags {
+    fn apply(&self, pattern: &str) -> String {
+        let mut flags = String::new();
+        if self.case_insensitive {
+            flags.push('i');
+        }
+        if self.dot_matches_new_line {
+            flags.push('s');
+        }
+        if !self.unicode {
+            flags.push_str("-u");
+        }
+        let mut pattern = if flags.is_empty() {
+            pattern.to_string()
+        } else {
+            format!("(?{flags}:{pattern})")
+        };
+        // any bytes, including newlines and invalid utf8
+        if self.unanchored_start {
+            pattern = format!("(?s-u:.*)(?:{pattern})");
+        }
+        if self.unanchored_end {
+            pattern = format!("(?:{pattern})(?s-u:.*)");
+        }
+        pattern
+    }
+
+    fn is_anchored(&self) -> bool {
+        !self.unanchored_start && !self.unanchored_end
+    }
+}
+
+// the vocabulary, shared between all constraints of a batch, so the sorted
+// order is computed at most once for all of them
+struct Continuations {
+    tokens: Vec<Vec<u8>>,
+    sorted: OnceLock<SortedContinuations>,
+}
+
+impl Continuations {
+    fn new(tokens: Vec<Vec<u8>>) -> Arc<Self> {
+        Arc::new(Self {
+            tokens,
+            sorted: OnceLock::new(),
+        })
+    }
+
+    fn sorted(&self) -> &SortedContinuations {
+        self.sorted
+            .get_or_init(|| SortedContinuations::new(&self.tokens))
+    }
+}
+
+// continuations in lexicographic order, together with the length of the
+// prefix each one shares with its predecessor in that order
+struct SortedContinuations {
+    order: Vec<usize>,
+    shared: Vec<usize>,
+}
+
+impl SortedContinuations {
+    fn new(continuations: &[Vec<u8>]) -> Self {
+        let order = run_length_order(continuations);
+        let shared = order
+            .iter()
+            .enumerate()
+            .map(|(i, &j)| {
+                let Some(&prev) = i.checked_sub(1).and_then(|i| order.get(i)) else {
+                    return 0;
+                };
+                continuations[prev]
+                    .iter()
+                    .zip(&continuations[j])
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+            .collect();
+        Self { order, shared }
+    }
 }
 
 impl RegularExpressionConstraint {
     pub fn new(content: &str, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        Self::with_flags(content, RegexFlags::default(), continuations)
+    }
+
+    // the pattern of the constraint includes the flags, see RegexFlags
+    pub fn with_flags(
+        content: &str,
+        flags: RegexFlags,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile(content, flags, continuations, &CompileLimits::default())
+    }
+
+    // checks the dfa of the pattern against the lexer limits, e.g. for
+    // patterns from untrusted sources
+    pub fn with_limits(
+        content: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::compile(content, RegexFlags::default(), continuations, limits)
+    }
+
+    fn compile(
+        content: &str,
+        flags: RegexFlags,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pattern = flags.apply(&Self::parse(content)?);
+        // with leftmost first semantics, the leading .* would take
+        // priority over the pattern and hide some of its matches
+        let pdfa = build_pdfa("the regex", &pattern, !flags.is_anchored(), limits)?;
+        Ok(Self::from_parts(
+            pattern,
+            pdfa,
+            Continuations::new(continuations),
+        ))
+    }
+
+    // accepts exactly the outputs the pattern does not match, e.g. (?s-u:.*bad.*)
+    // for outputs that never contain bad; note that . matches neither newlines
+    // nor invalid utf8 by default, so .*bad.* would still allow bad\n
+    pub fn complement(content: &str, continuations: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        let pattern = Self::parse(content)?;
+        // all matches, with leftmost first semantics the dfa would not match
+        // longer alternatives like ab in a|ab, and the complement would allow them
+        let pdfa = PrefixDFA::with_options(&pattern, None, true)?.complement();
+        Ok(Self::from_parts(
+            pattern,
+            pdfa,
+            Continuations::new(continuations),
+        ))
+    }
+
+    // accepts the valid dates and times in a strftime layout, see strftime_to_regex
+    pub fn from_strftime(
+        layout: &str,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::new(&strftime_to_regex(layout)?, continuations)
+    }
+
+    // compiles all patterns in parallel, the constraints share a single copy
+    // of the continuations and their sorted order
+    pub fn new_batch(
+        patterns: &[impl AsRef<str> + Sync],
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let continuations = Continuations::new(continuations);
+        patterns
+            .par_iter()
+            .enumerate()
+            .map(|(i, content)| {
+                Self::parse(content.as_ref())
+                    .and_then(|pattern| {
+                        let pdfa = PrefixDFA::new(&pattern)?;
+                        Ok((pattern, pdfa))
+                    })
+                    .map_err(|e| format!("pattern {i}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|(pattern, pdfa)| Ok(Self::from_parts(pattern, pdfa, continuations.clone())))
+            .collect()
+    }
+
+    fn from_parts(pattern: String, pdfa: PrefixDFA, continuations: Arc<Continuations>) -> Self {
+        RegularExpressionConstraint {
+            pattern,
+            pdfa,
+            continuations,
+            sorted: false,
+            segmenter: OnceLock::new(),
+            live: OnceLock::new(),
+            precomputed: None,
+        }
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<String, Box<dyn Error>> {
         let fragment_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
         let fragment_line = Regex::new(r"(?Rm)^([A-Z][A-Z0-9_]*)\s+(.+)$")?;
         let sep = Regex::new("(?Rm)^%%$")?;
@@ -45,11 +277,118 @@ impl RegularExpressionConstraint {
         } else {
             content.to_string()
         };
-        let pdfa = PrefixDFA::new(&pattern)?;
-        Ok(RegularExpressionConstraint {
+        Ok(pattern)
+    }
+
+    // walk the continuations in sorted order like a trie when computing the
+    // valid continuations of a state, so shared prefixes are only driven once
+    // and all continuations below a dead prefix are skipped together
+    pub fn with_sorted_continuations(mut self) -> Self {
+        self.continuations.sorted();
+        self.sorted = true;
+        self
+    }
+
+    // computes the valid continuations of all reachable states up front, so
+    // getting them while decoding is a single lookup; meant for small dfas,
+    // fails if the dfa has more than max_states states; the precomputed
+    // continuations are not serialized, see to_bytes
+    pub fn with_precomputed_continuations(
+        mut self,
+        max_states: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let states: Vec<_> = self.pdfa.predecessors().into_keys().collect();
+        if states.len() > max_states {
+            return Err(format!(
+                "dfa has {} states, more than the maximum of {max_states} to precompute",
+                states.len()
+            )
+            .into());
+        }
+        let precomputed = states
+            .into_par_iter()
+            .map(|state| (state, self.get_valid_continuations(&state)))
+            .collect();
+        self.precomputed = Some(precomputed);
+        Ok(self)
+    }
+
+    pub fn has_precomputed_continuations(&self) -> bool {
+        self.precomputed.is_some()
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations.tokens
+    }
+
+    // stable across runs, processes and thread counts, e.g. for caching artifacts
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.pdfa.to_bytes().hash(&mut hasher);
+        self.pdfa.is_complement().hash(&mut hasher);
+        self.continuations.tokens.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // whether a match state can still be reached from the state, e.g. to stop
+    // doomed generations early; all states of the dfa are explored on first use
+    pub fn can_match(&self, state: &StateID) -> bool {
+        self.live
+            .get_or_init(|| self.pdfa.live_states())
+            .contains(state)
+    }
+
+    pub fn is_dead_state(&self, state: &StateID) -> bool {
+        !self.can_match(state)
+    }
+
+    // none if the constraint does not accept the output, and always for
+    // complements, outputs do not match their pattern
+    pub fn segment(&self, output: &[u8]) -> Option<IndexMap<String, (usize, usize)>> {
+        // the dfa matches leftmost first and rejects some outputs the anchored
+        // segmenter would still match, e.g. ab for a|ab
+        if self.pdfa.is_complement() || !self.check(output) {
+            return None;
+        }
+        // the segmenter uses the same pattern and syntax as the dfa,
+        // anchored on both sides, because the constraint only accepts full matches
+        let segmenter = self.segmenter.get_or_init(|| {
+            bytes::Regex::new(&format!("^(?:{})$", self.pattern))
+                .expect("pattern should be valid if the dfa compiled")
+        });
+        let captures = segmenter.captures(output)?;
+        Some(
+            segmenter
+                .capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    let m = captures.name(name)?;
+                    Some((name.to_string(), (m.start(), m.end())))
+                })
+                .collect(),
+        )
+    }
+
+    // unrolls the recursive rules of an LR(1) grammar up to the given depth,
+    // for grammars with a known nesting limit that should be as fast as a regex
+    pub fn unrolled(
+        grammar: &str,
+        lexer: &str,
+        depth: usize,
+        overflow: UnrollOverflow,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pattern = unroll_lr1(grammar, lexer, depth, overflow)?;
+        let pdfa = PrefixDFA::with_options(&pattern, None, true)?;
+        Ok(Self::from_parts(
+            pattern,
             pdfa,
-            continuations,
-        })
+            Continuations::new(continuations),
+        ))
     }
 
     pub fn from_file(
@@ -60,6 +399,145 @@ impl RegularExpressionConstraint {
         let content = read_to_string(file)?;
         Self::new(&content, continuations)
     }
+
+    pub fn from_file_with_flags(
+        path: impl AsRef<Path>,
+        flags: RegexFlags,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path.as_ref())?;
+        let content = read_to_string(file)?;
+        Self::with_flags(&content, flags, continuations)
+    }
+
+    // the compiled constraint, e.g. to build it once offline and load it
+    // at startup without compiling the pattern again
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SERIALIZED_MAGIC.to_vec();
+        out.extend(SERIALIZED_VERSION.to_le_bytes());
+        write_section(&mut out, self.pattern.as_bytes());
+        out.push(self.sorted as u8);
+        out.extend((self.continuations.tokens.len() as u64).to_le_bytes());
+        for cont in &self.continuations.tokens {
+            write_section(&mut out, cont);
+        }
+        self.pdfa.serialize(&mut out);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(SERIALIZED_MAGIC.len()).ok() != Some(SERIALIZED_MAGIC) {
+            return Err("not a serialized regular expression constraint".into());
+        }
+        let version = reader.u32()?;
+        if version != SERIALIZED_VERSION {
+            return Err(format!(
+                "unsupported serialization version {version}, expected {SERIALIZED_VERSION}"
+            )
+            .into());
+        }
+        let pattern = String::from_utf8(reader.section()?.to_vec())?;
+        let sorted = reader.byte()? != 0;
+        let len = reader.u64()?;
+        let continuations = (0..len)
+            .map(|_| reader.section().map(<[u8]>::to_vec))
+            .collect::<Result<_, _>>()?;
+        let pdfa = PrefixDFA::deserialize(&mut reader)?;
+        if !reader.is_empty() {
+            return Err("trailing bytes after serialized constraint".into());
+        }
+        let re = Self::from_parts(pattern, pdfa, Continuations::new(continuations));
+        Ok(if sorted {
+            re.with_sorted_continuations()
+        } else {
+            re
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+const SERIALIZED_MAGIC: &[u8] = b"GURE";
+const SERIALIZED_VERSION: u32 = 1;
+
+impl MemoryUsage for RegularExpressionConstraint {
+    fn memory_usage(&self) -> usize {
+        self.pattern.capacity()
+            + self.pdfa.memory_usage()
+            + continuations_memory_usage(&self.continuations.tokens)
+            + self.continuations.sorted.get().map_or(0, |sorted| {
+                (sorted.order.capacity() + sorted.shared.capacity()) * size_of::<usize>()
+            })
+            + self
+                .live
+                .get()
+                .map_or(0, |live| live.capacity() * size_of::<StateID>())
+            + self.precomputed.as_ref().map_or(0, |precomputed| {
+                precomputed
+                    .values()
+                    .map(|conts| size_of::<StateID>() + conts.capacity() * size_of::<usize>())
+                    .sum()
+            })
+    }
+}
+
+impl RegularExpressionConstraint {
+    // calls valid with the index of every valid continuation, in no particular order
+    fn for_each_valid_continuation(&self, state: StateID, mut valid: impl FnMut(usize)) {
+        if self.sorted {
+            self.for_each_valid_continuation_sorted(state, self.continuations.sorted(), valid);
+            return;
+        }
+        for (i, cont) in self.continuations.tokens.iter().enumerate() {
+            if self.pdfa.drive(state, cont).is_some() {
+                valid(i);
+            }
+        }
+    }
+
+    fn for_each_valid_continuation_sorted(
+        &self,
+        state: StateID,
+        sorted: &SortedContinuations,
+        mut valid: impl FnMut(usize),
+    ) {
+        // states[d] is the state after the first d bytes of the current continuation
+        let mut states = vec![state];
+        let mut i = 0;
+        while i < sorted.order.len() {
+            let j = sorted.order[i];
+            states.truncate(sorted.shared[i] + 1);
+            let mut dead = None;
+            for (d, &b) in self.continuations.tokens[j]
+                .iter()
+                .enumerate()
+                .skip(sorted.shared[i])
+            {
+                let Some(next) = self.pdfa.step(states[d], b) else {
+                    dead = Some(d);
+                    break;
+                };
+                states.push(next);
+            }
+            i += 1;
+            if let Some(d) = dead {
+                // all following continuations sharing the dead prefix are invalid
+                while i < sorted.order.len() && sorted.shared[i] > d {
+                    i += 1;
+                }
+            } else if self.pdfa.is_valid(states[states.len() - 1]) {
+                valid(j);
+            }
+        }
+    }
 }
 
 impl Constraint for RegularExpressionConstraint {
@@ -78,29 +556,83 @@ impl Constraint for RegularExpressionConstraint {
     }
 
     fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
-        self.continuations
-            .iter()
-            .enumerate()
-            .filter_map(|(i, cont)| {
-                if self.pdfa.drive(*state, cont).is_some() {
-                    Some(i)
-                } else {
-                    None
-                }
-            })
-            .collect()
+        let mut conts = vec![];
+        self.get_valid_continuations_into(state, &mut conts);
+        conts
+    }
+
+    fn get_valid_continuations_into(&self, state: &Self::State, out: &mut Vec<usize>) {
+        out.clear();
+        if let Some(conts) = self.precomputed.as_ref().and_then(|p| p.get(state)) {
+            out.extend_from_slice(conts);
+            return;
+        }
+        self.for_each_valid_continuation(*state, |i| out.push(i));
+        if self.sorted {
+            out.sort_unstable();
+        }
     }
 
     fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
         self.pdfa
-            .drive(*state, self.continuations.get(continuation)?)
+            .drive(*state, self.continuations.tokens.get(continuation)?)
+    }
+
+    fn last_valid_truncation(&self, bytes: &[u8]) -> Option<usize> {
+        let mut state = self.get_start_state();
+        let mut last = self.is_match_state(&state).then_some(0);
+        for (i, &b) in bytes.iter().enumerate() {
+            let Some(next) = self.pdfa.drive(state, &[b]) else {
+                break;
+            };
+            state = next;
+            if self.is_match_state(&state) {
+                last = Some(i + 1);
+            }
+        }
+        last
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        // only an unchanged dfa state, e.g. while looping inside a character
+        // class like [a-z]*; equivalent but distinct dfa states are not detected
+        state == next
+    }
+}
+
+impl ByteConstraint for RegularExpressionConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations.tokens
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.pdfa.drive(*state, bytes)
+    }
+
+    fn minimal_completion(&self, state: &Self::State, max_len: usize) -> Option<Vec<u8>> {
+        // the dfa is finite, so the search needs no bound
+        self.pdfa
+            .shortest_completion(*state)
+            .filter(|completion| completion.len() <= max_len)
+    }
+
+    fn get_valid_continuations_mask(&self, state: &Self::State) -> Vec<bool> {
+        // filled directly, without collecting and sorting the indices first
+        let mut mask = vec![false; self.continuations.tokens.len()];
+        if let Some(conts) = self.precomputed.as_ref().and_then(|p| p.get(state)) {
+            conts.iter().for_each(|&i| mask[i] = true);
+            return mask;
+        }
+        self.for_each_valid_continuation(*state, |i| mask[i] = true);
+        mask
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use rand::seq::IteratorRandom;
+    use crate::Classification;
+    use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
     use std::{fs, path::PathBuf};
 
     fn load_continuations() -> Vec<Vec<u8>> {
@@ -152,6 +684,572 @@ mod test {
         assert!(re.pdfa.get_state(b"c").is_none());
     }
 
+    #[test]
+    fn test_re_complement() {
+        let conts: Vec<_> = ["a", "b", "ab", "ba", "bad", "d"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let re = RegularExpressionConstraint::complement("(?s-u:.*bad.*)", conts.clone()).unwrap();
+        let sorted = RegularExpressionConstraint::complement("(?s-u:.*bad.*)", conts.clone())
+            .unwrap()
+            .with_sorted_continuations();
+        let state = re.get_start_state();
+        assert!(re.is_match_state(&state));
+        assert_eq!(re.get_valid_continuations(&state), [0, 1, 2, 3, 5]);
+        let state = re.get_next_state(&state, 3).unwrap();
+        assert_eq!(re.get_valid_continuations(&state), [0, 1, 2, 3]);
+        assert_eq!(sorted.get_valid_continuations(&state), [0, 1, 2, 3]);
+        assert!(re.get_next_state(&state, 5).is_none());
+        assert!(re.check(b"abdad"));
+        assert!(!re.check(b"abbad"));
+        assert!(re.segment(b"ab").is_none());
+
+        // outputs can pass through matches of the pattern
+        let re = RegularExpressionConstraint::complement("yes|no", conts.clone()).unwrap();
+        assert!(re.check(b""));
+        assert!(!re.check(b"no"));
+        assert!(re.check(b"nod"));
+        assert_ne!(
+            re.fingerprint(),
+            RegularExpressionConstraint::new("yes|no", conts.clone())
+                .unwrap()
+                .fingerprint()
+        );
+
+        // longer alternatives are excluded, even if a shorter one is a prefix
+        let re = RegularExpressionConstraint::complement("ab|abc", conts.clone()).unwrap();
+        assert!(!re.check(b"ab"));
+        assert!(!re.check(b"abc"));
+        assert!(re.check(b"a"));
+        assert!(re.check(b"abcd"));
+        let re = RegularExpressionConstraint::complement("a|ab", conts.clone()).unwrap();
+        assert!(!re.check(b"ab"));
+        assert!(re.check(b"abd"));
+
+        // nothing is left if the pattern matches everything
+        let re = RegularExpressionConstraint::complement("(?s-u:.*)", conts).unwrap();
+        let state = re.get_start_state();
+        assert!(!re.is_match_state(&state));
+        assert!(re.get_valid_continuations(&state).is_empty());
+    }
+
+    #[test]
+    fn test_re_fingerprint() {
+        let conts = load_continuations();
+        let pattern = r"[a-z]{10}@[a-z]{10}\.(com|org|de)";
+        let fingerprints: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        RegularExpressionConstraint::new(pattern, conts.clone())
+                            .unwrap()
+                            .fingerprint()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(fingerprints.iter().all(|&f| f == fingerprints[0]));
+        let other = RegularExpressionConstraint::new("[a-z]+", conts).unwrap();
+        assert_ne!(other.fingerprint(), fingerprints[0]);
+    }
+
+    #[test]
+    fn test_re_serialize() {
+        let conts = load_continuations();
+        for (pattern, complement) in [
+            (r"[a-z]{10}@[a-z]{10}\.(com|org|de)", false),
+            ("a.*b", true),
+        ] {
+            let re = if complement {
+                RegularExpressionConstraint::complement(pattern, conts.clone())
+            } else {
+                RegularExpressionConstraint::new(pattern, conts.clone())
+            }
+            .unwrap()
+            .with_sorted_continuations();
+            let bytes = re.to_bytes();
+            // the serialized dfa is read from unaligned memory as well
+            for offset in 0..4 {
+                let mut shifted = vec![0; offset];
+                shifted.extend(&bytes);
+                let loaded = RegularExpressionConstraint::from_bytes(&shifted[offset..]).unwrap();
+                assert_eq!(loaded.fingerprint(), re.fingerprint());
+                assert_eq!(loaded.pattern(), pattern);
+                for prefix in [&b""[..], b"abcdefghij", b"a", b"ab"] {
+                    let state = re.get_state(prefix);
+                    assert_eq!(loaded.get_state(prefix), state);
+                    if let Some(state) = state {
+                        assert_eq!(
+                            loaded.get_valid_continuations(&state),
+                            re.get_valid_continuations(&state)
+                        );
+                        assert_eq!(loaded.is_match_state(&state), re.is_match_state(&state));
+                    }
+                }
+            }
+            assert!(RegularExpressionConstraint::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+            let mut trailing = bytes.clone();
+            trailing.push(0);
+            assert!(RegularExpressionConstraint::from_bytes(&trailing).is_err());
+        }
+        assert!(RegularExpressionConstraint::from_bytes(b"GURX").is_err());
+
+        let re = RegularExpressionConstraint::new("ab+", conts).unwrap();
+        let path = std::env::temp_dir().join(format!("re-{}.bin", std::process::id()));
+        re.save(&path).unwrap();
+        let loaded = RegularExpressionConstraint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.check(b"abbb"));
+        assert!(!loaded.check(b"a"));
+    }
+
+    #[test]
+    fn test_re_flags() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let flags = RegexFlags {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let re = RegularExpressionConstraint::with_flags("select (?-i:id)", flags, conts.clone())
+            .unwrap();
+        assert!(re.check(b"SeLeCt id"));
+        assert!(!re.check(b"select ID"));
+        assert_eq!(re.segment(b"SELECT id"), Some(IndexMap::new()));
+
+        let re = RegularExpressionConstraint::new("a.b", conts.clone()).unwrap();
+        assert!(!re.check(b"a\nb"));
+        assert!(re.check("aäb".as_bytes()));
+        assert!(!re.check(b"a\xffb"));
+        let flags = RegexFlags {
+            dot_matches_new_line: true,
+            unicode: false,
+            ..Default::default()
+        };
+        let re = RegularExpressionConstraint::with_flags("a.b", flags, conts.clone()).unwrap();
+        assert_eq!(re.pattern(), "(?s-u:a.b)");
+        assert!(re.check(b"a\nb"));
+        // single bytes instead of characters
+        assert!(re.check(b"a\xffb"));
+        assert!(!re.check("aäb".as_bytes()));
+
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let path = PathBuf::from(dir).join("resources/test/re-examples/template.txt");
+        let flags = RegexFlags {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let lower = RegularExpressionConstraint::from_file(&path, conts.clone()).unwrap();
+        let any = RegularExpressionConstraint::from_file_with_flags(&path, flags, conts).unwrap();
+        assert!(lower.get_state(b"<name>abcdefghij</name>").is_some());
+        assert!(lower.get_state(b"<NAME>ABCDEFGHIJ</NAME>").is_none());
+        assert!(any.get_state(b"<NAME>ABCDEFGHIJ</NAME>").is_some());
+    }
+
+    #[test]
+    fn test_re_unanchored() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let pattern = r"\[[0-9]+\]";
+        let contains = RegexFlags {
+            unanchored_start: true,
+            unanchored_end: true,
+            ..Default::default()
+        };
+        let re = RegularExpressionConstraint::with_flags(pattern, contains, conts.clone()).unwrap();
+        assert!(re.check(b"as shown in [12]."));
+        assert!(re.check(b"[1]\n\xff"));
+        assert!(re.check(b"[[1]]"));
+        assert!(!re.check(b"as shown in [12"));
+        // anything can still become a match
+        let state = re.get_state(b"no citation").unwrap();
+        assert_eq!(re.get_valid_continuations(&state).len(), 256);
+
+        let starts_with = RegexFlags {
+            unanchored_end: true,
+            ..Default::default()
+        };
+        let re =
+            RegularExpressionConstraint::with_flags(pattern, starts_with, conts.clone()).unwrap();
+        assert!(re.check(b"[1] and more"));
+        assert!(re.get_state(b"x[1]").is_none());
+
+        let ends_with = RegexFlags {
+            unanchored_start: true,
+            ..Default::default()
+        };
+        let re = RegularExpressionConstraint::with_flags(pattern, ends_with, conts).unwrap();
+        assert!(re.check(b"see [1][2]"));
+        assert!(!re.check(b"[1] and more"));
+        let state = re.get_state(b"[1] and more").unwrap();
+        assert!(!re.is_match_state(&state));
+    }
+
+    #[test]
+    fn test_re_can_match() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new(r"[a-z]{2}@(com|org)", conts.clone()).unwrap();
+        for prefix in [&b""[..], b"ab", b"ab@", b"ab@co", b"ab@com"] {
+            let state = re.get_state(prefix).unwrap();
+            assert!(re.can_match(&state));
+            assert!(!re.is_dead_state(&state));
+        }
+
+        // there is no word boundary between y and z, so nothing matches,
+        // although the states have valid continuations for a while
+        let re = RegularExpressionConstraint::new(r"(?-u)x..\by\bz", conts).unwrap();
+        for prefix in [&b""[..], b"x", b"x-"] {
+            let state = re.get_state(prefix).unwrap();
+            assert!(!re.get_valid_continuations(&state).is_empty());
+            assert!(re.is_dead_state(&state));
+        }
+    }
+
+    #[test]
+    fn test_re_precomputed_continuations() {
+        let conts: Vec<_> = ["a", "b", "ab", "@", "@c", "com", ".", ".org", "x"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let pattern = r"[ab]+@[a-z]+\.(com|org)";
+        let re = RegularExpressionConstraint::new(pattern, conts.clone()).unwrap();
+        let states: Vec<_> = re.pdfa.predecessors().into_keys().collect();
+        assert!(RegularExpressionConstraint::new(pattern, conts.clone())
+            .unwrap()
+            .with_precomputed_continuations(states.len() - 1)
+            .is_err());
+        for sorted in [false, true] {
+            let mut pre = RegularExpressionConstraint::new(pattern, conts.clone()).unwrap();
+            if sorted {
+                pre = pre.with_sorted_continuations();
+            }
+            let pre = pre.with_precomputed_continuations(states.len()).unwrap();
+            assert!(pre.has_precomputed_continuations());
+            assert!(pre.memory_usage() > re.memory_usage());
+            for state in &states {
+                assert_eq!(
+                    pre.get_valid_continuations(state),
+                    re.get_valid_continuations(state)
+                );
+                assert_eq!(
+                    pre.get_valid_continuations_mask(state),
+                    re.get_valid_continuations_mask(state)
+                );
+            }
+            assert!(pre.check(b"ab@c.org"));
+        }
+    }
+
+    #[test]
+    fn test_re_minimal_completion() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new(r#"\{"id": [0-9]+(, "tags": \[\])?\}"#, conts)
+            .unwrap();
+        let state = re.get_state(br#"{"id"#).unwrap();
+        let completion = re.minimal_completion(&state, 64).unwrap();
+        assert_eq!(completion, br#"": 0}"#);
+        assert!(re.minimal_completion(&state, 4).is_none());
+        let state = re.get_state(br#"{"id": 12, "t"#).unwrap();
+        let completion = re.minimal_completion(&state, 64).unwrap();
+        assert_eq!(completion, br#"ags": []}"#);
+        let state = re.get_state(br#"{"id": 1}"#).unwrap();
+        assert_eq!(re.minimal_completion(&state, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn test_re_sample() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re =
+            RegularExpressionConstraint::new(r"(yes|no|[0-9]{2,4})(, [a-z]+)*", conts).unwrap();
+        let mut rng = StdRng::seed_from_u64(22);
+        let mut samples = HashSet::new();
+        for _ in 0..100 {
+            let sample = re.sample(&mut rng, 16).unwrap();
+            assert!(sample.len() <= 16);
+            assert!(re.check(&sample), "{}", String::from_utf8_lossy(&sample));
+            samples.insert(sample);
+        }
+        assert!(samples.len() > 50);
+        // the alternatives are picked evenly
+        assert!(samples.iter().any(|s| s.starts_with(b"yes")));
+        assert!(samples.iter().any(|s| s.starts_with(b"no")));
+        // nothing fits
+        assert!(re.sample(&mut rng, 1).is_none());
+    }
+
+    #[test]
+    fn test_re_enumerate_completions() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new(r"(true|false|[0-9]{2})x?", conts).unwrap();
+        let state = re.get_start_state();
+        let completions = re.enumerate_completions(&state, 4, 8);
+        assert_eq!(
+            completions,
+            [
+                b"00".to_vec(),
+                b"01".to_vec(),
+                b"02".to_vec(),
+                b"03".to_vec()
+            ]
+        );
+        let completions = re.enumerate_completions(&state, 1000, 4);
+        // 100 numbers with and without x, and true
+        assert_eq!(completions.len(), 201);
+        assert!(completions.windows(2).all(|w| w[0].len() <= w[1].len()));
+        let state = re.get_state(b"f").unwrap();
+        let completions = re.enumerate_completions(&state, 10, 8);
+        assert_eq!(completions, [b"alse".to_vec(), b"alsex".to_vec()]);
+        assert!(re.enumerate_completions(&state, 10, 3).is_empty());
+    }
+
+    #[test]
+    fn test_re_check() {
+        let re = RegularExpressionConstraint::new(r"[a-z]+@[a-z]+\.com", vec![]).unwrap();
+        assert!(re.check(b"ab@cd.com"));
+        assert!(!re.check(b"ab@cd"));
+        let report = |text: &[u8]| {
+            let report = re.check_detailed(text);
+            (report.is_match, report.is_prefix, report.valid_up_to)
+        };
+        assert_eq!(report(b"ab@cd.com"), (true, true, 9));
+        assert_eq!(report(b"ab@cd"), (false, true, 5));
+        assert_eq!(report(b"ab@cd.org"), (false, false, 6));
+        assert_eq!(report(b"@"), (false, false, 0));
+        assert_eq!(report(b""), (false, true, 0));
+    }
+
+    #[test]
+    fn test_re_classify() {
+        let conts: Vec<_> = ["a", "b", "@", ".com"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let re = RegularExpressionConstraint::new(r"[a-z]+@[a-z]+\.com", conts).unwrap();
+        assert_eq!(re.classify(b"ab@cd.com"), Classification::Valid);
+        assert_eq!(re.classify(b"ab@cd"), Classification::ValidPrefix);
+        assert_eq!(re.classify(b"ab@cd.org"), Classification::Invalid);
+        // valid prefix, but not with the continuations
+        assert_eq!(re.classify(b"ab@cd.c"), Classification::Invalid);
+    }
+
+    #[test]
+    fn test_re_last_valid_truncation() {
+        let re = RegularExpressionConstraint::new(r"[a-z]+(\.com)?", vec![]).unwrap();
+        for (text, expected) in [
+            (&b"ab.com.org"[..], Some(6)),
+            (b"ab.co", Some(2)),
+            (b"ab", Some(2)),
+            (b".com", None),
+            (b"", None),
+        ] {
+            assert_eq!(re.last_valid_truncation(text), expected);
+            let dynamic: &dyn crate::DynConstraint = &re;
+            assert_eq!(dynamic.last_valid_truncation(text), expected);
+        }
+        let re = RegularExpressionConstraint::new(r"(ab)*", vec![]).unwrap();
+        assert_eq!(re.last_valid_truncation(b"ababa"), Some(4));
+    }
+
+    #[test]
+    fn test_re_states_equal() {
+        let re = RegularExpressionConstraint::new(r"[a-z]+@[a-z]+\.com", vec![]).unwrap();
+        assert!(re.states_equal(b"ab@", b"xyz@"));
+        assert!(re.states_equal(b"a@b", b"a@bcd"));
+        assert!(!re.states_equal(b"a", b"a@"));
+        assert!(!re.states_equal(b"@", b"@"));
+    }
+
+    #[test]
+    fn test_re_same_continuations() {
+        let conts: Vec<_> = ["a", "b", "@", "a@b"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let re = RegularExpressionConstraint::new(r"[ab]+@b", conts).unwrap();
+        let state = re.get_state(b"a").unwrap();
+        let valid = re.get_valid_continuations(&state);
+        let next = re.get_next_state(&state, 1).unwrap();
+        assert!(re.has_same_continuations(&state, &next));
+        assert_eq!(
+            re.get_next_valid_continuations(&state, &valid, &next),
+            valid
+        );
+        let next = re.get_next_state(&state, 2).unwrap();
+        assert!(!re.has_same_continuations(&state, &next));
+        assert_eq!(
+            re.get_next_valid_continuations(&state, &valid, &next),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_re_sorted() {
+        let continuations = load_continuations();
+        for pat in load_patterns() {
+            let re = RegularExpressionConstraint::new(&pat, continuations.clone()).unwrap();
+            let sorted = RegularExpressionConstraint::new(&pat, continuations.clone())
+                .unwrap()
+                .with_sorted_continuations();
+            let mut state = re.get_start_state();
+            for _ in 0..16 {
+                let conts = re.get_valid_continuations(&state);
+                assert_eq!(conts, sorted.get_valid_continuations(&state));
+                let Some(&cont) = conts.last() else {
+                    break;
+                };
+                state = re.get_next_state(&state, cont).unwrap();
+            }
+        }
+
+        // empty and duplicate continuations
+        let conts: Vec<_> = ["b", "", "ab", "a", "ab", "abc", "ac"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let re = RegularExpressionConstraint::new("ab", conts)
+            .unwrap()
+            .with_sorted_continuations();
+        let state = re.get_start_state();
+        assert_eq!(re.get_valid_continuations(&state), vec![1, 2, 3, 4]);
+        let state = re.get_state(b"a").unwrap();
+        assert_eq!(re.get_valid_continuations(&state), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_re_batch() {
+        let continuations = load_continuations();
+        let patterns = load_patterns();
+        let batch =
+            RegularExpressionConstraint::new_batch(&patterns, continuations.clone()).unwrap();
+        assert_eq!(batch.len(), patterns.len());
+        for (pat, re) in patterns.iter().zip(batch) {
+            let single = RegularExpressionConstraint::new(pat, continuations.clone()).unwrap();
+            assert_eq!(re.pattern(), single.pattern());
+            assert_eq!(re.fingerprint(), single.fingerprint());
+            let re = re.with_sorted_continuations();
+            let mut state = single.get_start_state();
+            for _ in 0..8 {
+                let conts = single.get_valid_continuations(&state);
+                assert_eq!(conts, re.get_valid_continuations(&state));
+                let Some(&cont) = conts.last() else {
+                    break;
+                };
+                state = single.get_next_state(&state, cont).unwrap();
+            }
+        }
+
+        // the index of the first invalid pattern is reported
+        let err = RegularExpressionConstraint::new_batch(&["a", "(b", "c"], continuations)
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("pattern 1:"));
+    }
+
+    #[test]
+    fn test_re_pack_mask() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new("[a-c]|!", conts).unwrap();
+        let state = re.get_start_state();
+        let mut mask = [u32::MAX; 8];
+        re.pack_mask_u32(&state, &mut mask);
+        assert_eq!(mask[1], 1 << (b'!' - 32));
+        assert_eq!(mask[3], 0b1110);
+        for (i, word) in mask.iter().enumerate() {
+            for bit in 0..32 {
+                let valid = re.get_next_state(&state, i * 32 + bit).is_some();
+                assert_eq!(word & (1 << bit) != 0, valid);
+            }
+        }
+    }
+
+    #[test]
+    fn test_re_mask_and_buffer() {
+        let conts = load_continuations();
+        let pattern = r"[a-z]{3,}@gmail\.com";
+        let re = RegularExpressionConstraint::new(pattern, conts.clone()).unwrap();
+        let sorted = RegularExpressionConstraint::new(pattern, conts)
+            .unwrap()
+            .with_sorted_continuations();
+        for re in [re, sorted] {
+            for prefix in [&b""[..], b"abc", b"abc@gm"] {
+                let state = re.get_state(prefix).unwrap();
+                let mask = re.get_valid_continuations_mask(&state);
+                assert_eq!(mask.len(), re.continuations().len());
+                let mut indices: Vec<_> = (0..mask.len()).filter(|&i| mask[i]).collect();
+                assert_eq!(indices, re.get_valid_continuations(&state));
+                // the buffer is cleared before the continuations are written
+                indices.push(usize::MAX);
+                re.get_valid_continuations_into(&state, &mut indices);
+                assert_eq!(indices, re.get_valid_continuations(&state));
+            }
+        }
+    }
+
+    #[test]
+    fn test_re_apply_mask() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new("[a-c]|!", conts).unwrap();
+        let state = re.get_start_state();
+        // padded vocabulary
+        let mut logits = vec![1.0; 260];
+        re.apply_mask(&state, &mut logits, f32::MIN);
+        let valid: Vec<_> = (0..logits.len()).filter(|&i| logits[i] == 1.0).collect();
+        assert_eq!(valid, [b'!' as usize, 97, 98, 99]);
+        // fewer logits than continuations
+        let mut logits = vec![1.0; 98];
+        re.apply_mask(&state, &mut logits, f32::NEG_INFINITY);
+        assert_eq!(logits.iter().filter(|l| l.is_finite()).count(), 2);
+    }
+
+    #[test]
+    fn test_re_bytes() {
+        let conts: Vec<_> = (0..=255).map(|b| vec![b]).collect();
+        let re = RegularExpressionConstraint::new(r"(?-u:\xFF[\x80-\xFF]+)", conts).unwrap();
+        let state = re.get_state(b"\xFF").unwrap();
+        assert_eq!(
+            re.get_valid_continuations(&state),
+            (128..256).collect::<Vec<_>>()
+        );
+        let state = re.get_state(b"\xFF\x80\xFE").unwrap();
+        assert!(re.is_match_state(&state));
+        assert!(re.get_state(b"\xC3\xBF").is_none());
+    }
+
+    #[test]
+    fn test_re_segment() {
+        let re = RegularExpressionConstraint::new(
+            r"(?<user>[a-z]+)@(?<domain>[a-z]+\.(com|de))( \((?<note>[a-z]+)\))?",
+            vec![],
+        )
+        .unwrap();
+        let segments = re.segment(b"alice@example.com").unwrap();
+        assert_eq!(
+            segments.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("user".to_string(), (0, 5)),
+                ("domain".to_string(), (6, 17))
+            ]
+        );
+        let segments = re.segment(b"bob@test.de (work)").unwrap();
+        assert_eq!(segments["note"], (13, 17));
+        assert!(re.segment(b"alice@example.org").is_none());
+        assert!(re.segment(b"alice@example.com ").is_none());
+
+        // fragments are expanded before segmenting
+        let re =
+            RegularExpressionConstraint::new("NUM [0-9]+\n%%\n(?<a>{NUM})-(?<b>{NUM})", vec![])
+                .unwrap();
+        let segments = re.segment(b"12-345").unwrap();
+        assert_eq!(segments["a"], (0, 2));
+        assert_eq!(segments["b"], (3, 6));
+
+        // no segments for outputs the leftmost first dfa rejects
+        let re = RegularExpressionConstraint::new("(?<x>a|ab)", vec![]).unwrap();
+        assert!(!re.check(b"ab"));
+        assert!(re.segment(b"ab").is_none());
+        assert_eq!(re.segment(b"a").unwrap()["x"], (0, 1));
+    }
+
     #[test]
     fn test_re_patterns() {
         let continuations = load_continuations();
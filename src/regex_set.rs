@@ -0,0 +1,219 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+};
+
+use regex_automata::util::primitives::StateID;
+
+use crate::{
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::PrefixDFA,
+    ByteConstraint, Constraint,
+};
+
+// several regular expressions compiled into a single dfa, the output has to
+// match one of them; besides the continuations, it tells which patterns are
+// still viable, e.g. to know which of several output formats the output
+// has committed to, and which of them it matches
+pub struct RegexSetConstraint {
+    patterns: Vec<String>,
+    pdfa: PrefixDFA,
+    // viable and matching patterns of every live state,
+    // states from which no pattern can match are left out
+    patterns_of: HashMap<StateID, (Vec<usize>, Vec<usize>)>,
+    continuations: Vec<Vec<u8>>,
+}
+
+impl RegexSetConstraint {
+    pub fn new(
+        patterns: &[impl AsRef<str>],
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if patterns.is_empty() {
+            return Err("regex set needs at least one pattern".into());
+        }
+        let pdfa = PrefixDFA::new_many(patterns)?;
+
+        let predecessors = pdfa.predecessors();
+
+        // a pattern is viable in a state if it matches there or in a
+        // successor, so matches are propagated back to the predecessors
+        let mut viable: HashMap<_, _> = predecessors
+            .keys()
+            .map(|&state| (state, BTreeSet::from_iter(pdfa.eoi_matches(state))))
+            .collect();
+        let mut stack: Vec<_> = viable
+            .iter()
+            .filter_map(|(&state, patterns)| (!patterns.is_empty()).then_some(state))
+            .collect();
+        while let Some(state) = stack.pop() {
+            let patterns = viable[&state].clone();
+            for prev in &predecessors[&state] {
+                let prev_patterns = viable
+                    .get_mut(prev)
+                    .expect("predecessor should be reachable");
+                if !patterns.is_subset(prev_patterns) {
+                    prev_patterns.extend(&patterns);
+                    stack.push(*prev);
+                }
+            }
+        }
+        let patterns_of = viable
+            .into_iter()
+            .filter(|(_, patterns)| !patterns.is_empty())
+            .map(|(state, patterns)| {
+                let matched = pdfa.eoi_matches(state);
+                (state, (patterns.into_iter().collect(), matched))
+            })
+            .collect();
+
+        Ok(Self {
+            patterns: patterns.iter().map(|p| p.as_ref().to_string()).collect(),
+            pdfa,
+            patterns_of,
+            continuations,
+        })
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // indices of the patterns the output can still match
+    pub fn viable(&self, state: &StateID) -> &[usize] {
+        self.patterns_of
+            .get(state)
+            .map_or(&[], |(viable, _)| viable.as_slice())
+    }
+
+    // indices of the patterns the output matches, empty if it is no match
+    pub fn matched(&self, state: &StateID) -> &[usize] {
+        self.patterns_of
+            .get(state)
+            .map_or(&[], |(_, matched)| matched.as_slice())
+    }
+
+    fn drive(&self, mut state: StateID, bytes: &[u8]) -> Option<StateID> {
+        for &b in bytes {
+            state = self.pdfa.step(state, b)?;
+            if !self.patterns_of.contains_key(&state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+}
+
+impl MemoryUsage for RegexSetConstraint {
+    fn memory_usage(&self) -> usize {
+        self.patterns.iter().map(String::capacity).sum::<usize>()
+            + self.pdfa.memory_usage()
+            + self
+                .patterns_of
+                .values()
+                .map(|(viable, matched)| {
+                    size_of::<StateID>()
+                        + (viable.capacity() + matched.capacity()) * size_of::<usize>()
+                })
+                .sum::<usize>()
+            + continuations_memory_usage(&self.continuations)
+    }
+}
+
+impl Constraint for RegexSetConstraint {
+    type State = StateID;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.drive(self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        self.pdfa.get_start_state()
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        !self.matched(state).is_empty()
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.continuations
+            .iter()
+            .enumerate()
+            .filter_map(|(i, cont)| self.drive(*state, cont).map(|_| i))
+            .collect()
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.drive(*state, self.continuations.get(continuation)?)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+}
+
+impl ByteConstraint for RegexSetConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        self.drive(*state, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_regex_set() {
+        let conts: Vec<_> = ["{", "}", "<", ">", "a", "1", "yes", "no", "x"]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let set = RegexSetConstraint::new(&[r"\{a*\}", "<a+>", "yes|no", "a+1?"], conts).unwrap();
+        assert_eq!(set.patterns().len(), 4);
+
+        let state = set.get_start_state();
+        assert_eq!(set.viable(&state), [0, 1, 2, 3]);
+        assert!(set.matched(&state).is_empty());
+        assert_eq!(set.get_valid_continuations(&state), [0, 2, 4, 6, 7]);
+
+        // the output committed to the first format
+        let state = set.get_state(b"{a").unwrap();
+        assert_eq!(set.viable(&state), [0]);
+        assert_eq!(set.get_valid_continuations(&state), [1, 4]);
+        let state = set.get_next_state(&state, 1).unwrap();
+        assert_eq!(set.matched(&state), [0]);
+        assert!(set.get_valid_continuations(&state).is_empty());
+
+        let state = set.get_state(b"a").unwrap();
+        assert_eq!(set.viable(&state), [3]);
+        assert_eq!(set.matched(&state), [3]);
+        assert_eq!(set.get_valid_continuations(&state), [4, 5]);
+        let state = set.get_state(b"a1").unwrap();
+        assert!(set.is_match_state(&state));
+        assert!(set.get_valid_continuations(&state).is_empty());
+
+        assert!(set.check(b"<aa>"));
+        assert!(set.check(b"no"));
+        assert!(!set.check(b"<>"));
+        assert!(set.get_state(b"x").is_none());
+        assert!(set.get_state(b"ye1").is_none());
+
+        // overlapping patterns all match
+        let set = RegexSetConstraint::new(&["a+", "a{2}", "b"], vec![b"a".to_vec()]).unwrap();
+        let state = set.get_state(b"aa").unwrap();
+        assert_eq!(set.matched(&state), [0, 1]);
+        let state = set.get_next_state(&state, 0).unwrap();
+        assert_eq!(set.viable(&state), [0]);
+
+        assert!(RegexSetConstraint::new(&[] as &[&str], vec![]).is_err());
+        assert!(RegexSetConstraint::new(&["("], vec![]).is_err());
+    }
+}
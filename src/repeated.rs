@@ -0,0 +1,527 @@
+use std::{error::Error, hash::Hash};
+
+use regex_automata::util::primitives::StateID;
+
+use crate::{memory::MemoryUsage, state_fingerprint, utils::PrefixDFA, ByteConstraint, Constraint};
+
+// stream of documents conforming to an inner constraint, separated by matches
+// of a separator regular expression, e.g. one json object per line, or directly
+// following each other without a separator;
+// single continuations can end one document and start the next
+pub struct RepeatedConstraint<C> {
+    inner: C,
+    separator: Option<PrefixDFA>,
+    min: usize,
+    max: Option<usize>,
+    // continuations containing bytes of the separator, or without one bytes
+    // after the first that can start a document, the only ones that can
+    // cross a document boundary
+    crossing: Vec<usize>,
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+enum Position<S> {
+    Document(S),
+    Separator(StateID),
+}
+
+enum Boundary {
+    None,
+    // the byte is the first one of a separator
+    End,
+    // the byte is the first one of a document
+    Start,
+    // the byte is the first one of a document directly following the previous one
+    Adjacent,
+}
+
+// positions the output can be at, together with the number of documents
+// completed before them; more than one if the end of a document is ambiguous,
+// e.g. for a separator matching one or more newlines
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct RepeatedState<S> {
+    alternatives: Vec<(usize, Position<S>)>,
+    empty: bool,
+}
+
+impl<C> RepeatedConstraint<C>
+where
+    C: ByteConstraint,
+    C::State: Clone + Eq + Hash,
+{
+    // between min and max documents, unbounded if max is none;
+    // the separator is a regular expression, so literals need to be escaped
+    pub fn new(
+        inner: C,
+        separator: &str,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::build(inner, Some(separator), min, max)
+    }
+
+    // same as new, but with a literal separator, or none at all
+    pub fn with_literal(
+        inner: C,
+        separator: Option<&str>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::build(inner, separator.map(regex::escape).as_deref(), min, max)
+    }
+
+    fn build(
+        inner: C,
+        separator: Option<&str>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if let Some(max) = max {
+            if max == 0 || max < min {
+                return Err(format!(
+                    "invalid number of documents, expected 1 <= max and min <= max, \
+                    got min {min} and max {max}"
+                )
+                .into());
+            }
+        }
+        let separator = separator
+            .map(|separator| {
+                PrefixDFA::new(separator).map_err(|e| format!("invalid separator {separator}: {e}"))
+            })
+            .transpose()?;
+        if separator
+            .as_ref()
+            .is_some_and(|separator| separator.is_eoi_match(separator.get_start_state()))
+        {
+            return Err("separator must not match the empty string".into());
+        }
+        let start = inner.get_start_state();
+        if inner.is_match_state(&start) {
+            return Err("documents must not match the empty string".into());
+        }
+        let crossing = match &separator {
+            Some(separator) => {
+                let live = separator.live_bytes();
+                inner
+                    .continuations()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, cont)| cont.iter().any(|&b| live[b as usize]).then_some(i))
+                    .collect()
+            }
+            None => {
+                let mut starts = [false; 256];
+                for (b, starts) in starts.iter_mut().enumerate() {
+                    *starts = inner
+                        .get_next_state_with_bytes(&start, &[b as u8])
+                        .is_some();
+                }
+                inner
+                    .continuations()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, cont)| {
+                        cont.iter()
+                            .skip(1)
+                            .any(|&b| starts[b as usize])
+                            .then_some(i)
+                    })
+                    .collect()
+            }
+        };
+        Ok(Self {
+            inner,
+            separator,
+            min,
+            max,
+            crossing,
+        })
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn can_continue(&self, count: usize) -> bool {
+        self.max.is_none_or(|max| count + 1 < max)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        self.inner.continuations()
+    }
+
+    // number of completed documents, the smallest one
+    // if the end of the current document is ambiguous
+    pub fn completed(&self, state: &RepeatedState<C::State>) -> usize {
+        state
+            .alternatives
+            .iter()
+            .map(|&(count, _)| count)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn step(
+        &self,
+        count: usize,
+        position: &Position<C::State>,
+        byte: u8,
+        mut f: impl FnMut(usize, Position<C::State>, Boundary),
+    ) {
+        match position {
+            Position::Document(state) => {
+                if let Some(next) = self.inner.get_next_state_with_bytes(state, &[byte]) {
+                    f(count, Position::Document(next), Boundary::None);
+                }
+                if !self.inner.is_match_state(state) || !self.can_continue(count) {
+                    return;
+                }
+                match &self.separator {
+                    Some(separator) => {
+                        if let Some(next) = separator.drive(separator.get_start_state(), &[byte]) {
+                            f(count + 1, Position::Separator(next), Boundary::End);
+                        }
+                    }
+                    None => {
+                        if let Some(next) = self
+                            .inner
+                            .get_next_state_with_bytes(&self.inner.get_start_state(), &[byte])
+                        {
+                            f(count + 1, Position::Document(next), Boundary::Adjacent);
+                        }
+                    }
+                }
+            }
+            Position::Separator(state) => {
+                let separator = self
+                    .separator
+                    .as_ref()
+                    .expect("separator positions only with a separator");
+                if let Some(next) = separator.drive(*state, &[byte]) {
+                    f(count, Position::Separator(next), Boundary::None);
+                }
+                if separator.is_eoi_match(*state) {
+                    if let Some(next) = self
+                        .inner
+                        .get_next_state_with_bytes(&self.inner.get_start_state(), &[byte])
+                    {
+                        f(count, Position::Document(next), Boundary::Start);
+                    }
+                }
+            }
+        }
+    }
+
+    fn advance(
+        &self,
+        state: &RepeatedState<C::State>,
+        bytes: &[u8],
+    ) -> Option<RepeatedState<C::State>> {
+        let mut alternatives = state.alternatives.clone();
+        for &b in bytes {
+            let mut next = vec![];
+            for (count, position) in &alternatives {
+                self.step(*count, position, b, |count, position, _| {
+                    let alternative = (count, position);
+                    if !next.contains(&alternative) {
+                        next.push(alternative);
+                    }
+                });
+            }
+            if next.is_empty() {
+                return None;
+            }
+            alternatives = next;
+        }
+        // canonical order, so equal sets of alternatives are equal states
+        alternatives.sort_by_cached_key(state_fingerprint);
+        Some(RepeatedState {
+            alternatives,
+            empty: state.empty && bytes.is_empty(),
+        })
+    }
+
+    fn is_match_alternative(&self, count: usize, position: &Position<C::State>) -> bool {
+        match position {
+            Position::Document(state) => self.inner.is_match_state(state) && count + 1 >= self.min,
+            Position::Separator(_) => false,
+        }
+    }
+
+    // byte spans of the documents in the text, none if it does not conform
+    pub fn spans(&self, text: &[u8]) -> Option<Vec<(usize, usize)>> {
+        if text.is_empty() {
+            return (self.min == 0).then(Vec::new);
+        }
+        let mut alternatives = vec![(0, Position::Document(self.inner.get_start_state()))];
+        // per byte and alternative the previous alternative and the boundary crossed
+        let mut history = vec![];
+        for &b in text {
+            let mut next = vec![];
+            let mut steps = vec![];
+            for (i, (count, position)) in alternatives.iter().enumerate() {
+                self.step(*count, position, b, |count, position, boundary| {
+                    let alternative = (count, position);
+                    if !next.contains(&alternative) {
+                        next.push(alternative);
+                        steps.push((i, boundary));
+                    }
+                });
+            }
+            if next.is_empty() {
+                return None;
+            }
+            alternatives = next;
+            history.push(steps);
+        }
+        let mut i = alternatives
+            .iter()
+            .position(|(count, position)| self.is_match_alternative(*count, position))?;
+        let mut spans = vec![];
+        let mut end = text.len();
+        for (pos, steps) in history.iter().enumerate().rev() {
+            let (prev, boundary) = &steps[i];
+            match boundary {
+                Boundary::End => end = pos,
+                Boundary::Start => spans.push((pos, end)),
+                Boundary::Adjacent => {
+                    spans.push((pos, end));
+                    end = pos;
+                }
+                Boundary::None => {}
+            }
+            i = *prev;
+        }
+        spans.push((0, end));
+        spans.reverse();
+        Some(spans)
+    }
+}
+
+impl<C: MemoryUsage> MemoryUsage for RepeatedConstraint<C> {
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage()
+            + self
+                .separator
+                .as_ref()
+                .map_or(0, |separator| separator.memory_usage())
+            + self.crossing.capacity() * size_of::<usize>()
+    }
+}
+
+impl<C> Constraint for RepeatedConstraint<C>
+where
+    C: ByteConstraint,
+    C::State: Clone + Eq + Hash,
+{
+    type State = RepeatedState<C::State>;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.advance(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        RepeatedState {
+            alternatives: vec![(0, Position::Document(self.inner.get_start_state()))],
+            empty: true,
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        (state.empty && self.min == 0)
+            || state
+                .alternatives
+                .iter()
+                .any(|(count, position)| self.is_match_alternative(*count, position))
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        // continuations without separator bytes stay within the current document,
+        // or start the next one right after a complete separator, or without
+        // a separator right after a complete document
+        let mut conts = vec![];
+        for (count, position) in &state.alternatives {
+            match position {
+                Position::Document(state) => {
+                    conts.extend(self.inner.get_valid_continuations(state));
+                    if self.separator.is_none()
+                        && self.inner.is_match_state(state)
+                        && self.can_continue(*count)
+                    {
+                        conts.extend(
+                            self.inner
+                                .get_valid_continuations(&self.inner.get_start_state()),
+                        );
+                    }
+                }
+                Position::Separator(state)
+                    if self
+                        .separator
+                        .as_ref()
+                        .is_some_and(|separator| separator.is_eoi_match(*state)) =>
+                {
+                    conts.extend(
+                        self.inner
+                            .get_valid_continuations(&self.inner.get_start_state()),
+                    );
+                }
+                Position::Separator(_) => {}
+            }
+        }
+        let continuations = self.inner.continuations();
+        conts.extend(
+            self.crossing
+                .iter()
+                .copied()
+                .filter(|&i| self.advance(state, &continuations[i]).is_some()),
+        );
+        conts.sort_unstable();
+        conts.dedup();
+        conts
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.advance(state, self.inner.continuations().get(continuation)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{fs, path::PathBuf};
+
+    use crate::{LR1GrammarConstraint, RegularExpressionConstraint};
+
+    fn json(continuations: &[&str]) -> LR1GrammarConstraint {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("grammars")
+            .join("json");
+        LR1GrammarConstraint::new(
+            &fs::read_to_string(dir.join("json.y")).unwrap(),
+            &fs::read_to_string(dir.join("json.l")).unwrap(),
+            continuations
+                .iter()
+                .map(|c| c.as_bytes().to_vec())
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_repeated_jsonl() {
+        let conts = ["{", "}", "\"a\"", ":", "1", "}\n", "\n", "}\n{", "\n\n"];
+        let jsonl = RepeatedConstraint::new(json(&conts), r"\n", 1, Some(3)).unwrap();
+
+        let state = jsonl.get_start_state();
+        assert!(!jsonl.is_match_state(&state));
+        // json allows leading whitespace
+        assert_eq!(jsonl.get_valid_continuations(&state), [0, 2, 4, 6, 8]);
+
+        let state = jsonl.get_state(b"{\"a\":1").unwrap();
+        assert_eq!(jsonl.get_valid_continuations(&state), [1, 4, 5, 6, 7, 8]);
+        let state = jsonl.get_next_state(&state, 7).unwrap();
+        assert_eq!(jsonl.completed(&state), 1);
+        assert!(!jsonl.is_match_state(&state));
+        let state = jsonl.get_next_state(&state, 1).unwrap();
+        assert!(jsonl.is_match_state(&state));
+        // trailing whitespace of the document or the separator
+        assert_eq!(jsonl.get_valid_continuations(&state), [6, 8]);
+        let state = jsonl.get_next_state(&state, 6).unwrap();
+        assert_eq!(jsonl.get_valid_continuations(&state), [0, 2, 4, 6, 8]);
+
+        // at most three documents
+        let state = jsonl.get_state(b"1\n2\n3").unwrap();
+        assert_eq!(jsonl.completed(&state), 2);
+        assert!(jsonl.is_match_state(&state));
+        // the newline can only be trailing whitespace of the last document
+        let state = jsonl.get_next_state(&state, 6).unwrap();
+        assert_eq!(jsonl.get_valid_continuations(&state), [6, 8]);
+        assert!(jsonl.get_state(b"1\n2\n3\n4").is_none());
+
+        let text = b"{\"a\": 1}\n[1, 2]\n\"b\"";
+        assert_eq!(jsonl.spans(text), Some(vec![(0, 8), (9, 15), (16, 19)]));
+        assert_eq!(jsonl.spans(b"1\n"), None);
+        assert_eq!(jsonl.spans(b""), None);
+    }
+
+    #[test]
+    fn test_repeated_ambiguous() {
+        // both the documents and the separator can consume another space
+        let conts = ["a", " ", "b", "a b"];
+        let inner = RegularExpressionConstraint::new(
+            "[ab]+ ?",
+            conts.iter().map(|c| c.as_bytes().to_vec()).collect(),
+        )
+        .unwrap();
+        let repeated = RepeatedConstraint::new(inner, " +", 0, None).unwrap();
+        assert!(repeated.is_match_state(&repeated.get_start_state()));
+        assert_eq!(repeated.spans(b""), Some(vec![]));
+
+        let state = repeated.get_state(b"a ").unwrap();
+        assert!(repeated.is_match_state(&state));
+        assert_eq!(repeated.completed(&state), 0);
+        assert_eq!(repeated.get_valid_continuations(&state), [0, 1, 2, 3]);
+        let state = repeated.get_next_state(&state, 1).unwrap();
+        assert!(!repeated.is_match_state(&state));
+        assert_eq!(repeated.completed(&state), 1);
+        assert_eq!(repeated.get_valid_continuations(&state), [0, 1, 2, 3]);
+
+        // documents are as long as possible
+        assert_eq!(repeated.spans(b"ab  b"), Some(vec![(0, 3), (4, 5)]));
+        assert_eq!(repeated.spans(b"a b "), Some(vec![(0, 1), (2, 4)]));
+        assert_eq!(repeated.check_detailed(b"a  b").valid_up_to, 4);
+    }
+
+    #[test]
+    fn test_repeated_unseparated() {
+        let conts = ["a", "b", "c", "ab", "ca", "cab"];
+        let inner = RegularExpressionConstraint::new(
+            "a|bc",
+            conts.iter().map(|c| c.as_bytes().to_vec()).collect(),
+        )
+        .unwrap();
+        let repeated = RepeatedConstraint::with_literal(inner, None, 2, Some(3)).unwrap();
+
+        let state = repeated.get_start_state();
+        assert_eq!(repeated.get_valid_continuations(&state), [0, 1, 3]);
+        let state = repeated.get_next_state(&state, 0).unwrap();
+        assert!(!repeated.is_match_state(&state));
+        // the next document starts right away
+        assert_eq!(repeated.get_valid_continuations(&state), [0, 1, 3]);
+        let state = repeated.get_next_state(&state, 1).unwrap();
+        assert_eq!(repeated.completed(&state), 1);
+        // the continuation ends the second document and starts the third
+        assert_eq!(repeated.get_valid_continuations(&state), [2, 4]);
+        let state = repeated.get_next_state(&state, 4).unwrap();
+        assert!(repeated.is_match_state(&state));
+        assert!(repeated.get_valid_continuations(&state).is_empty());
+
+        assert_eq!(repeated.spans(b"abca"), Some(vec![(0, 1), (1, 3), (3, 4)]));
+        assert!(repeated.check(b"aa"));
+        assert!(!repeated.check(b"a"));
+        assert!(!repeated.check(b"aaaa"));
+    }
+
+    #[test]
+    fn test_repeated_literal() {
+        let inner = RegularExpressionConstraint::new("a+", vec![]).unwrap();
+        let repeated = RepeatedConstraint::with_literal(inner, Some("."), 1, None).unwrap();
+        assert_eq!(repeated.spans(b"a.aa"), Some(vec![(0, 1), (2, 4)]));
+        assert_eq!(repeated.spans(b"a,aa"), None);
+    }
+
+    #[test]
+    fn test_repeated_errors() {
+        let inner = || RegularExpressionConstraint::new("a+", vec![]).unwrap();
+        assert!(RepeatedConstraint::new(inner(), ",", 2, Some(1)).is_err());
+        assert!(RepeatedConstraint::new(inner(), ",", 0, Some(0)).is_err());
+        assert!(RepeatedConstraint::new(inner(), ",*", 1, None).is_err());
+        assert!(RepeatedConstraint::new(inner(), "(", 1, None).is_err());
+        assert!(RepeatedConstraint::with_literal(inner(), Some(""), 1, None).is_err());
+        assert!(RepeatedConstraint::with_literal(inner(), Some("("), 1, None).is_ok());
+        let empty = RegularExpressionConstraint::new("a*", vec![]).unwrap();
+        assert!(RepeatedConstraint::new(empty, ",", 1, None).is_err());
+    }
+}
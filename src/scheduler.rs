@@ -0,0 +1,923 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    future::Future,
+    hash::Hash,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+
+use crate::Constraint;
+
+pub type SessionId = u64;
+
+#[derive(Debug, Clone)]
+pub struct SchedulerOptions {
+    // worker threads of the scheduler's own pool, 0 for rayon's default
+    pub num_threads: usize,
+    // most requests run in one batch
+    pub batch_size: usize,
+    // most queued requests, submitting more blocks or fails
+    pub max_pending: usize,
+    // how long to wait for a batch to fill up after the first request
+    pub max_delay: Duration,
+    // sessions without requests for this long are evicted, none to keep them
+    pub session_ttl: Option<Duration>,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        Self {
+            num_threads: 0,
+            batch_size: 64,
+            max_pending: 1024,
+            max_delay: Duration::from_millis(1),
+            session_ttl: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerError {
+    Full,
+    Closed,
+    UnknownSession(SessionId),
+    // the constraint panicked while running the batch of the request
+    Panicked,
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::Full => write!(f, "too many pending requests"),
+            SchedulerError::Closed => write!(f, "scheduler is closed"),
+            SchedulerError::UnknownSession(session) => write!(f, "unknown session {session}"),
+            SchedulerError::Panicked => write!(f, "constraint panicked while running the request"),
+        }
+    }
+}
+
+impl Error for SchedulerError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledRequest {
+    // advance the session by a continuation
+    Advance(SessionId, usize),
+    // valid continuations of the session
+    Mask(SessionId),
+}
+
+impl ScheduledRequest {
+    fn session(&self) -> SessionId {
+        match self {
+            ScheduledRequest::Advance(session, _) | ScheduledRequest::Mask(session) => *session,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mask {
+    pub continuations: Vec<usize>,
+    pub is_match: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduledResponse {
+    // whether the continuation was valid, invalid ones invalidate the session
+    Advance(bool),
+    Mask(Mask),
+}
+
+type Respond = Box<dyn FnOnce(Result<ScheduledResponse, SchedulerError>) + Send>;
+type Responses = Vec<(Respond, Result<ScheduledResponse, SchedulerError>)>;
+type EvictListener = Box<dyn Fn(SessionId) + Send + Sync>;
+
+enum Callback {
+    // completes a ticket, which never blocks, so it runs on the dispatcher
+    Ticket(Respond),
+    // may block, e.g. by submitting to a full queue, so it runs on the
+    // callback thread while the dispatcher keeps draining the queue
+    User(Respond),
+}
+
+struct Job {
+    request: ScheduledRequest,
+    callback: Callback,
+}
+
+struct Session<S> {
+    state: S,
+    invalid: bool,
+    last_used: Instant,
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+struct Shared<C: Constraint> {
+    constraint: C,
+    options: SchedulerOptions,
+    sessions: Mutex<HashMap<SessionId, Session<C::State>>>,
+    next_session: AtomicU64,
+    queue: Mutex<Queue>,
+    // signaled when jobs are queued or the scheduler closes
+    queued: Condvar,
+    // signaled when a batch frees up queue space
+    space: Condvar,
+    on_evict: Mutex<Option<EvictListener>>,
+}
+
+// queues advance and mask requests from many sessions and runs them in
+// batches on a bounded thread pool; a batch contains at most one request
+// per session, so requests of a session run in submission order, and masks
+// of sessions in the same state are only computed once per batch; callbacks
+// run in order on their own thread, they must not wait for tickets
+pub struct ConstraintScheduler<C: Constraint> {
+    shared: Arc<Shared<C>>,
+    dispatcher: Option<JoinHandle<()>>,
+    callbacks: Option<JoinHandle<()>>,
+}
+
+impl<C> ConstraintScheduler<C>
+where
+    C: Constraint + Send + Sync + 'static,
+    C::State: Clone + Eq + Hash + Send + Sync + 'static,
+{
+    pub fn new(constraint: C, options: SchedulerOptions) -> Result<Self, Box<dyn Error>> {
+        if options.batch_size == 0 || options.max_pending == 0 {
+            return Err("batch size and max pending must be positive".into());
+        }
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(options.num_threads)
+            .thread_name(|i| format!("constraint-scheduler-{i}"))
+            .build()?;
+        let shared = Arc::new(Shared {
+            constraint,
+            options,
+            sessions: Mutex::new(HashMap::new()),
+            next_session: AtomicU64::new(0),
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                closed: false,
+            }),
+            queued: Condvar::new(),
+            space: Condvar::new(),
+            on_evict: Mutex::new(None),
+        });
+        let (tx, rx) = channel::<Responses>();
+        let callbacks = thread::Builder::new()
+            .name("constraint-scheduler-callbacks".to_string())
+            .spawn(move || {
+                for batch in rx {
+                    for (callback, response) in batch {
+                        // a panicking callback does not keep the others from running
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| callback(response)));
+                    }
+                }
+            })?;
+        let dispatcher = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("constraint-scheduler".to_string())
+                .spawn(move || shared.dispatch(pool, tx))?
+        };
+        Ok(Self {
+            shared,
+            dispatcher: Some(dispatcher),
+            callbacks: Some(callbacks),
+        })
+    }
+
+    pub fn constraint(&self) -> &C {
+        &self.shared.constraint
+    }
+
+    // opens a session at the state after the prefix, none if it is invalid
+    pub fn open_session(&self, prefix: &[u8]) -> Option<SessionId> {
+        let state = self.shared.constraint.get_state(prefix)?;
+        let session = self.shared.next_session.fetch_add(1, Ordering::Relaxed);
+        self.shared.sessions().insert(
+            session,
+            Session {
+                state,
+                invalid: false,
+                last_used: Instant::now(),
+            },
+        );
+        Some(session)
+    }
+
+    // queued requests of a closed session fail with an unknown session error
+    pub fn close_session(&self, session: SessionId) -> bool {
+        self.shared.sessions().remove(&session).is_some()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.shared.queue().jobs.len()
+    }
+
+    // called with the id of every session evicted for being idle
+    pub fn on_evict(&self, listener: impl Fn(SessionId) + Send + Sync + 'static) {
+        *self
+            .shared
+            .on_evict
+            .lock()
+            .expect("error locking scheduler evict listener") = Some(Box::new(listener));
+    }
+
+    // evicts sessions idle for longer than the session ttl and returns their
+    // ids; with a ttl the dispatcher also does this periodically by itself
+    pub fn evict_idle(&self) -> Vec<SessionId> {
+        self.shared.evict_idle()
+    }
+
+    // queues a request, blocking while the queue is full
+    pub fn submit(
+        &self,
+        request: ScheduledRequest,
+        callback: impl FnOnce(Result<ScheduledResponse, SchedulerError>) + Send + 'static,
+    ) -> Result<(), SchedulerError> {
+        self.shared
+            .submit(request, Callback::User(Box::new(callback)), true)
+    }
+
+    // queues a request, failing instead of blocking if the queue is full
+    pub fn try_submit(
+        &self,
+        request: ScheduledRequest,
+        callback: impl FnOnce(Result<ScheduledResponse, SchedulerError>) + Send + 'static,
+    ) -> Result<(), SchedulerError> {
+        self.shared
+            .submit(request, Callback::User(Box::new(callback)), false)
+    }
+
+    pub fn advance(
+        &self,
+        session: SessionId,
+        continuation: usize,
+    ) -> Result<Ticket<bool>, SchedulerError> {
+        let (ticket, complete) = Ticket::new();
+        self.shared.submit(
+            ScheduledRequest::Advance(session, continuation),
+            Callback::Ticket(Box::new(move |response| {
+                complete(response.map(|response| match response {
+                    ScheduledResponse::Advance(valid) => valid,
+                    ScheduledResponse::Mask(_) => unreachable!("advance answered with a mask"),
+                }))
+            })),
+            true,
+        )?;
+        Ok(ticket)
+    }
+
+    pub fn mask(&self, session: SessionId) -> Result<Ticket<Mask>, SchedulerError> {
+        let (ticket, complete) = Ticket::new();
+        self.shared.submit(
+            ScheduledRequest::Mask(session),
+            Callback::Ticket(Box::new(move |response| {
+                complete(response.map(|response| match response {
+                    ScheduledResponse::Mask(mask) => mask,
+                    ScheduledResponse::Advance(_) => unreachable!("mask answered with an advance"),
+                }))
+            })),
+            true,
+        )?;
+        Ok(ticket)
+    }
+}
+
+impl<C: Constraint> Drop for ConstraintScheduler<C> {
+    // queued requests and their callbacks still run before the threads stop
+    fn drop(&mut self) {
+        self.shared
+            .queue
+            .lock()
+            .expect("error locking scheduler queue")
+            .closed = true;
+        self.shared.queued.notify_all();
+        self.shared.space.notify_all();
+        if let Some(dispatcher) = self.dispatcher.take() {
+            dispatcher.join().expect("scheduler dispatcher panicked");
+        }
+        // the callback thread stops once the dispatcher dropped its sender
+        if let Some(callbacks) = self.callbacks.take() {
+            callbacks
+                .join()
+                .expect("scheduler callback thread panicked");
+        }
+    }
+}
+
+impl<C> Shared<C>
+where
+    C: Constraint + Send + Sync,
+    C::State: Clone + Eq + Hash + Send + Sync,
+{
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, Session<C::State>>> {
+        self.sessions
+            .lock()
+            .expect("error locking scheduler sessions")
+    }
+
+    fn queue(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().expect("error locking scheduler queue")
+    }
+
+    fn submit(
+        &self,
+        request: ScheduledRequest,
+        callback: Callback,
+        block: bool,
+    ) -> Result<(), SchedulerError> {
+        match self.sessions().get_mut(&request.session()) {
+            Some(session) => session.last_used = Instant::now(),
+            None => return Err(SchedulerError::UnknownSession(request.session())),
+        }
+        let mut queue = self.queue();
+        while !queue.closed && queue.jobs.len() >= self.options.max_pending {
+            if !block {
+                return Err(SchedulerError::Full);
+            }
+            queue = self
+                .space
+                .wait(queue)
+                .expect("error locking scheduler queue");
+        }
+        if queue.closed {
+            return Err(SchedulerError::Closed);
+        }
+        queue.jobs.push_back(Job { request, callback });
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    fn evict_idle(&self) -> Vec<SessionId> {
+        let Some(ttl) = self.options.session_ttl else {
+            return vec![];
+        };
+        let mut evicted = vec![];
+        self.sessions().retain(|&id, session| {
+            let idle = session.last_used.elapsed() >= ttl;
+            if idle {
+                evicted.push(id);
+            }
+            !idle
+        });
+        evicted.sort();
+        // call the listener outside of the sessions lock, so it can use the
+        // scheduler itself
+        if let Some(listener) = &*self
+            .on_evict
+            .lock()
+            .expect("error locking scheduler evict listener")
+        {
+            for &session in &evicted {
+                listener(session);
+            }
+        }
+        evicted
+    }
+
+    // sweeps for idle sessions twice per ttl
+    fn sweep_interval(&self) -> Option<Duration> {
+        self.options.session_ttl.map(|ttl| ttl / 2)
+    }
+
+    // takes the next batch, none if the scheduler is closed and drained;
+    // with a session ttl the batch is empty if no job arrived in time for
+    // the next sweep
+    fn next_batch(&self) -> Option<Vec<Job>> {
+        let mut queue = self.queue();
+        while queue.jobs.is_empty() {
+            if queue.closed {
+                return None;
+            }
+            queue = match self.sweep_interval() {
+                Some(interval) => {
+                    let (queue, timeout) = self
+                        .queued
+                        .wait_timeout(queue, interval)
+                        .expect("error locking scheduler queue");
+                    if timeout.timed_out() && queue.jobs.is_empty() {
+                        return Some(vec![]);
+                    }
+                    queue
+                }
+                None => self
+                    .queued
+                    .wait(queue)
+                    .expect("error locking scheduler queue"),
+            };
+        }
+        // give concurrent sessions a moment to fill up the batch
+        let deadline = Instant::now() + self.options.max_delay;
+        while !queue.closed && queue.jobs.len() < self.options.batch_size {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            queue = self
+                .queued
+                .wait_timeout(queue, timeout)
+                .expect("error locking scheduler queue")
+                .0;
+        }
+        let mut batch = vec![];
+        let mut sessions = HashSet::new();
+        let mut deferred = VecDeque::new();
+        while let Some(job) = queue.jobs.pop_front() {
+            if batch.len() < self.options.batch_size && sessions.insert(job.request.session()) {
+                batch.push(job);
+            } else {
+                deferred.push_back(job);
+            }
+        }
+        queue.jobs = deferred;
+        self.space.notify_all();
+        Some(batch)
+    }
+
+    fn dispatch(&self, pool: ThreadPool, callbacks: Sender<Responses>) {
+        let mut last_sweep = Instant::now();
+        while let Some(batch) = self.next_batch() {
+            if let Some(interval) = self.sweep_interval() {
+                if last_sweep.elapsed() >= interval {
+                    self.evict_idle();
+                    last_sweep = Instant::now();
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+            let requests: Vec<_> = batch.iter().map(|job| job.request).collect();
+            // a panicking constraint fails the requests of its batch instead of
+            // killing the dispatcher, which would leave all later requests pending
+            let responses =
+                panic::catch_unwind(AssertUnwindSafe(|| pool.install(|| self.run(&requests))))
+                    .unwrap_or_else(|_| vec![Err(SchedulerError::Panicked); requests.len()]);
+            let mut deferred = vec![];
+            for (job, response) in batch.into_iter().zip(responses) {
+                match job.callback {
+                    Callback::Ticket(complete) => complete(response),
+                    Callback::User(callback) => deferred.push((callback, response)),
+                }
+            }
+            if !deferred.is_empty() {
+                // the callback thread only stops after the dispatcher
+                callbacks
+                    .send(deferred)
+                    .expect("scheduler callback thread stopped");
+            }
+        }
+    }
+
+    fn mask(&self, state: &C::State) -> Mask {
+        Mask {
+            continuations: self.constraint.get_valid_continuations(state),
+            is_match: self.constraint.is_match_state(state),
+        }
+    }
+
+    fn run(&self, batch: &[ScheduledRequest]) -> Vec<Result<ScheduledResponse, SchedulerError>> {
+        // snapshot the states, each session occurs at most once per batch
+        let states: Vec<_> = {
+            let sessions = self.sessions();
+            batch
+                .iter()
+                .map(|request| {
+                    let session = request.session();
+                    sessions
+                        .get(&session)
+                        .map(|session| (!session.invalid).then(|| session.state.clone()))
+                        .ok_or(SchedulerError::UnknownSession(session))
+                })
+                .collect()
+        };
+
+        // coalesce masks of sessions in the same state
+        let mut unique = HashMap::new();
+        for (request, state) in batch.iter().zip(&states) {
+            if let (ScheduledRequest::Mask(_), Ok(Some(state))) = (request, state) {
+                let len = unique.len();
+                unique.entry(state).or_insert(len);
+            }
+        }
+        let mut unique_states: Vec<_> = unique.iter().map(|(&state, &i)| (i, state)).collect();
+        unique_states.sort_by_key(|&(i, _)| i);
+        let masks: Vec<_> = unique_states
+            .par_iter()
+            .map(|(_, state)| self.mask(state))
+            .collect();
+
+        let advanced: Vec<_> = batch
+            .par_iter()
+            .zip(&states)
+            .map(|(request, state)| match (request, state) {
+                (ScheduledRequest::Advance(_, continuation), Ok(Some(state))) => {
+                    Some(self.constraint.get_next_state(state, *continuation))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut sessions = self.sessions();
+        let now = Instant::now();
+        batch
+            .iter()
+            .zip(&states)
+            .zip(advanced)
+            .map(|((request, state), next)| {
+                let state = state.as_ref().map_err(|e| *e)?;
+                if let Some(session) = sessions.get_mut(&request.session()) {
+                    session.last_used = now;
+                }
+                let response = match *request {
+                    ScheduledRequest::Advance(session, _) => {
+                        let next = next.flatten();
+                        let valid = next.is_some();
+                        // the session might have been closed in the meantime
+                        let session = sessions
+                            .get_mut(&session)
+                            .ok_or(SchedulerError::UnknownSession(session))?;
+                        match next {
+                            Some(next) => session.state = next,
+                            None => session.invalid = true,
+                        }
+                        ScheduledResponse::Advance(valid)
+                    }
+                    ScheduledRequest::Mask(_) => ScheduledResponse::Mask(match state {
+                        Some(state) => masks[unique[state]].clone(),
+                        None => Mask {
+                            continuations: vec![],
+                            is_match: false,
+                        },
+                    }),
+                };
+                Ok(response)
+            })
+            .collect()
+    }
+}
+
+struct TicketSlot<T> {
+    value: Option<Result<T, SchedulerError>>,
+    waker: Option<Waker>,
+}
+
+// result of a scheduled request, either awaited as a future or waited
+// for by blocking the current thread
+pub struct Ticket<T> {
+    slot: Arc<(Mutex<TicketSlot<T>>, Condvar)>,
+}
+
+impl<T: Send + 'static> Ticket<T> {
+    fn new() -> (
+        Self,
+        impl FnOnce(Result<T, SchedulerError>) + Send + 'static,
+    ) {
+        let slot = Arc::new((
+            Mutex::new(TicketSlot {
+                value: None,
+                waker: None,
+            }),
+            Condvar::new(),
+        ));
+        let complete = {
+            let slot = slot.clone();
+            move |value| {
+                let (lock, ready) = &*slot;
+                let mut slot = lock.lock().expect("error locking ticket");
+                slot.value = Some(value);
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+                ready.notify_all();
+            }
+        };
+        (Self { slot }, complete)
+    }
+
+    pub fn wait(self) -> Result<T, SchedulerError> {
+        let (lock, ready) = &*self.slot;
+        let mut slot = lock.lock().expect("error locking ticket");
+        loop {
+            if let Some(value) = slot.value.take() {
+                return value;
+            }
+            slot = ready.wait(slot).expect("error locking ticket");
+        }
+    }
+}
+
+impl<T> Future for Ticket<T> {
+    type Output = Result<T, SchedulerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.0.lock().expect("error locking ticket");
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{
+        sync::{atomic::AtomicUsize, mpsc::channel},
+        task::Wake,
+    };
+
+    use crate::RegularExpressionConstraint;
+
+    fn constraint() -> RegularExpressionConstraint {
+        let conts = ["a", "b", "ab", "c"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        RegularExpressionConstraint::new("(ab)+c", conts).unwrap()
+    }
+
+    fn scheduler(options: SchedulerOptions) -> ConstraintScheduler<RegularExpressionConstraint> {
+        ConstraintScheduler::new(constraint(), options).unwrap()
+    }
+
+    // panics when advancing by c, masks wait while the gate is locked
+    struct Faulty {
+        inner: RegularExpressionConstraint,
+        gate: Mutex<()>,
+    }
+
+    impl Constraint for Faulty {
+        type State = <RegularExpressionConstraint as Constraint>::State;
+
+        fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+            self.inner.get_state(prefix)
+        }
+
+        fn get_start_state(&self) -> Self::State {
+            self.inner.get_start_state()
+        }
+
+        fn is_match_state(&self, state: &Self::State) -> bool {
+            self.inner.is_match_state(state)
+        }
+
+        fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+            drop(self.gate.lock().unwrap());
+            self.inner.get_valid_continuations(state)
+        }
+
+        fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+            assert_ne!(continuation, 3, "advanced by c");
+            self.inner.get_next_state(state, continuation)
+        }
+    }
+
+    fn faulty(options: SchedulerOptions) -> ConstraintScheduler<Faulty> {
+        let constraint = Faulty {
+            inner: constraint(),
+            gate: Mutex::new(()),
+        };
+        ConstraintScheduler::new(constraint, options).unwrap()
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = scheduler(SchedulerOptions::default());
+        let session = scheduler.open_session(b"").unwrap();
+        assert!(scheduler.open_session(b"b").is_none());
+
+        let mask = scheduler.mask(session).unwrap().wait().unwrap();
+        assert_eq!(mask.continuations, [0, 2]);
+        assert!(!mask.is_match);
+
+        // requests of a session run in order, even within one batch
+        let tickets: Vec<_> = [0, 1, 3]
+            .into_iter()
+            .map(|cont| scheduler.advance(session, cont).unwrap())
+            .collect();
+        let mask = scheduler.mask(session).unwrap();
+        for ticket in tickets {
+            assert!(ticket.wait().unwrap());
+        }
+        assert_eq!(
+            mask.wait().unwrap(),
+            Mask {
+                continuations: vec![],
+                is_match: true
+            }
+        );
+
+        // invalid continuations invalidate the session
+        let other = scheduler.open_session(b"ab").unwrap();
+        assert!(!scheduler.advance(other, 1).unwrap().wait().unwrap());
+        assert!(scheduler
+            .mask(other)
+            .unwrap()
+            .wait()
+            .unwrap()
+            .continuations
+            .is_empty());
+
+        assert!(scheduler.close_session(other));
+        assert_eq!(
+            scheduler.mask(other).err(),
+            Some(SchedulerError::UnknownSession(other))
+        );
+    }
+
+    #[test]
+    fn test_scheduler_many_sessions() {
+        let scheduler = Arc::new(scheduler(SchedulerOptions {
+            num_threads: 2,
+            batch_size: 8,
+            max_pending: 4,
+            max_delay: Duration::from_millis(5),
+            ..Default::default()
+        }));
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let scheduler = scheduler.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let session = scheduler.open_session(b"").unwrap();
+                    for cont in [2, 0, 1, 3] {
+                        let tx = tx.clone();
+                        scheduler
+                            .submit(ScheduledRequest::Advance(session, cont), move |response| {
+                                tx.send(response).unwrap()
+                            })
+                            .unwrap();
+                        // the queue never grows beyond max pending
+                        assert!(scheduler.pending() <= 4);
+                    }
+                    scheduler.mask(session).unwrap().wait().unwrap()
+                })
+            })
+            .collect();
+        drop(tx);
+        for handle in handles {
+            assert!(handle.join().unwrap().is_match);
+        }
+        let responses: Vec<_> = rx.iter().collect();
+        assert_eq!(responses.len(), 64);
+        assert!(responses
+            .iter()
+            .all(|response| response == &Ok(ScheduledResponse::Advance(true))));
+    }
+
+    #[test]
+    fn test_scheduler_back_pressure() {
+        let scheduler = faulty(SchedulerOptions {
+            num_threads: 1,
+            batch_size: 1,
+            max_pending: 1,
+            max_delay: Duration::ZERO,
+            ..Default::default()
+        });
+        let session = scheduler.open_session(b"").unwrap();
+        // block the dispatcher in the first mask until all requests are submitted
+        let gate = scheduler.constraint().gate.lock().unwrap();
+        let first = scheduler.mask(session).unwrap();
+        let mut full = false;
+        for _ in 0..100 {
+            if scheduler.try_submit(ScheduledRequest::Mask(session), |_| {})
+                == Err(SchedulerError::Full)
+            {
+                full = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(full);
+        drop(gate);
+        assert!(!first.wait().unwrap().is_match);
+        assert!(!scheduler.mask(session).unwrap().wait().unwrap().is_match);
+    }
+
+    #[test]
+    fn test_scheduler_blocking_callback() {
+        let scheduler = Arc::new(scheduler(SchedulerOptions {
+            num_threads: 1,
+            batch_size: 1,
+            max_pending: 1,
+            max_delay: Duration::ZERO,
+            ..Default::default()
+        }));
+        let session = scheduler.open_session(b"").unwrap();
+        // the callback blocks on the full queue, which the dispatcher
+        // keeps draining while the callback waits
+        let (tx, rx) = channel();
+        let inner = scheduler.clone();
+        scheduler
+            .submit(ScheduledRequest::Mask(session), move |_| {
+                for _ in 0..8 {
+                    let tx = tx.clone();
+                    inner
+                        .submit(ScheduledRequest::Mask(session), move |response| {
+                            tx.send(response).unwrap()
+                        })
+                        .unwrap();
+                }
+            })
+            .unwrap();
+        for _ in 0..8 {
+            let response = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(matches!(response, Ok(ScheduledResponse::Mask(_))));
+        }
+    }
+
+    #[test]
+    fn test_scheduler_panic() {
+        let scheduler = faulty(SchedulerOptions {
+            batch_size: 2,
+            max_delay: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let session = scheduler.open_session(b"ab").unwrap();
+        let other = scheduler.open_session(b"").unwrap();
+        // the panic fails the tickets of its batch
+        let panicked = scheduler.advance(session, 3).unwrap();
+        let mask = scheduler.mask(other).unwrap();
+        assert_eq!(panicked.wait(), Err(SchedulerError::Panicked));
+        assert!(matches!(mask.wait(), Ok(_) | Err(SchedulerError::Panicked)));
+        // and later requests still run
+        assert!(scheduler.advance(session, 0).unwrap().wait().unwrap());
+        assert_eq!(
+            scheduler.mask(other).unwrap().wait().unwrap().continuations,
+            [0, 2]
+        );
+    }
+
+    #[test]
+    fn test_scheduler_session_ttl() {
+        let scheduler = scheduler(SchedulerOptions {
+            session_ttl: Some(Duration::from_millis(40)),
+            ..Default::default()
+        });
+        let (tx, rx) = channel();
+        scheduler.on_evict(move |session| tx.send(session).unwrap());
+        let idle = scheduler.open_session(b"").unwrap();
+        let active = scheduler.open_session(b"").unwrap();
+        assert!(scheduler.evict_idle().is_empty());
+
+        // requests keep a session alive, the dispatcher evicts the idle one
+        let deadline = Instant::now() + Duration::from_millis(200);
+        while Instant::now() < deadline {
+            scheduler.mask(active).unwrap().wait().unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [idle]);
+        assert_eq!(
+            scheduler.mask(idle).err(),
+            Some(SchedulerError::UnknownSession(idle))
+        );
+        assert!(scheduler.advance(active, 0).unwrap().wait().unwrap());
+
+        // sessions never expire without a ttl
+        let without_ttl = self::scheduler(SchedulerOptions::default());
+        let session = without_ttl.open_session(b"").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        assert!(without_ttl.evict_idle().is_empty());
+        assert!(without_ttl.mask(session).is_ok());
+    }
+
+    #[test]
+    fn test_ticket_future() {
+        struct CountWaker(AtomicUsize);
+
+        impl Wake for CountWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (mut ticket, complete) = Ticket::<usize>::new();
+        let counter = Arc::new(CountWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut ticket).poll(&mut cx).is_pending());
+        complete(Ok(3));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(Pin::new(&mut ticket).poll(&mut cx), Poll::Ready(Ok(3)));
+    }
+}
@@ -0,0 +1,826 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::read_to_string,
+    mem::size_of,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use cfgrammar::{
+    yacc::{YaccGrammar, YaccKind, YaccOriginalActionKind},
+    RIdx, TIdx,
+};
+use lrtable::{Action, StIdx, StateTable};
+use regex_automata::util::primitives::StateID;
+
+use crate::{
+    limits::CompileLimits,
+    lr1::{
+        build_table, eoi_terminals, find_token_or_matching, grammar_memory_usage,
+        initial_prefix_matches, is_valid_remainder, load_grammar_and_pdfas,
+        valid_continuations_in_order, Matching, PdfaList, TokenOrMatching,
+    },
+    memory::{continuations_memory_usage, MemoryUsage},
+    utils::optimized_prefix_order,
+    ByteConstraint, Constraint,
+};
+
+// predicate on the text of a completed nonterminal
+pub type SemanticHook = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+const HOOK_CACHE_CAPACITY: usize = 4096;
+
+// parser state together with the byte span of the symbol shifted into it
+type Entry = (StIdx<u32>, usize, usize);
+
+// terminal with the byte span of its latest occurrence
+type Occurrence = (TIdx<u32>, usize, usize);
+
+// small state machine deciding the text of a computed terminal, e.g. a field
+// whose length is given by a terminal before it; states are opaque bytes
+// the machine is free to interpret
+pub trait TerminalMachine: Send + Sync {
+    // state at the start of the terminal, none if it cannot start here
+    fn start(&self, context: &TerminalContext) -> Option<Vec<u8>>;
+
+    // state after the byte, none if the byte is not allowed
+    fn step(&self, state: &[u8], byte: u8) -> Option<Vec<u8>>;
+
+    fn is_final(&self, state: &[u8]) -> bool;
+}
+
+// what a terminal machine gets to see of the output before its terminal
+pub struct TerminalContext<'a> {
+    grammar: &'a YaccGrammar<u32>,
+    text: &'a Text<'a>,
+    last: &'a [Occurrence],
+}
+
+impl TerminalContext<'_> {
+    // text of the latest occurrence of the terminal
+    pub fn last(&self, terminal: &str) -> Option<Cow<'_, [u8]>> {
+        let tidx = self.grammar.token_idx(terminal)?;
+        self.last
+            .iter()
+            .find(|&&(t, ..)| t == tidx)
+            .map(|&(_, start, end)| self.text.slice(start, end))
+    }
+
+    // latest occurrences of all terminals seen so far
+    pub fn terminals(&self) -> impl Iterator<Item = (&str, Cow<'_, [u8]>)> {
+        self.last.iter().filter_map(|&(tidx, start, end)| {
+            let name = self.grammar.token_name(tidx)?;
+            Some((name, self.text.slice(start, end)))
+        })
+    }
+}
+
+// field with as many bytes as given by the latest occurrence of a decimal
+// length terminal, e.g. the data of netstrings like 5:hello,
+pub struct LengthPrefixed {
+    length: String,
+}
+
+impl LengthPrefixed {
+    pub fn new(length: &str) -> Self {
+        Self {
+            length: length.to_string(),
+        }
+    }
+}
+
+fn remaining(state: &[u8]) -> u64 {
+    u64::from_le_bytes(state.try_into().unwrap_or_default())
+}
+
+impl TerminalMachine for LengthPrefixed {
+    fn start(&self, context: &TerminalContext) -> Option<Vec<u8>> {
+        let length = context.last(&self.length)?;
+        let length: u64 = std::str::from_utf8(&length).ok()?.trim().parse().ok()?;
+        Some(length.to_le_bytes().to_vec())
+    }
+
+    fn step(&self, state: &[u8], _: u8) -> Option<Vec<u8>> {
+        let left = remaining(state).checked_sub(1)?;
+        Some(left.to_le_bytes().to_vec())
+    }
+
+    fn is_final(&self, state: &[u8]) -> bool {
+        remaining(state) == 0
+    }
+}
+
+// field whose text is computed from the output before it, e.g. a checksum
+// over an earlier field
+pub struct ComputedText<F> {
+    compute: F,
+}
+
+impl<F> ComputedText<F>
+where
+    F: Fn(&TerminalContext) -> Option<Vec<u8>> + Send + Sync,
+{
+    pub fn new(compute: F) -> Self {
+        Self { compute }
+    }
+}
+
+impl<F> TerminalMachine for ComputedText<F>
+where
+    F: Fn(&TerminalContext) -> Option<Vec<u8>> + Send + Sync,
+{
+    // the state is the part of the text still to come
+    fn start(&self, context: &TerminalContext) -> Option<Vec<u8>> {
+        (self.compute)(context)
+    }
+
+    fn step(&self, state: &[u8], byte: u8) -> Option<Vec<u8>> {
+        let (&first, rest) = state.split_first()?;
+        (first == byte).then(|| rest.to_vec())
+    }
+
+    fn is_final(&self, state: &[u8]) -> bool {
+        state.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ParseState {
+    stack: Vec<Entry>,
+    matching: Matching,
+    // start of the pending lexeme
+    lexeme_start: usize,
+    // computed terminal being generated, with the state of its machine
+    machine: Option<(TIdx<u32>, Vec<u8>)>,
+    last: Vec<Occurrence>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SemanticState {
+    parse: ParseState,
+    text: Vec<u8>,
+}
+
+impl MemoryUsage for SemanticState {
+    fn memory_usage(&self) -> usize {
+        let parse = &self.parse;
+        size_of::<Self>()
+            + parse.stack.capacity() * size_of::<Entry>()
+            + parse.matching.capacity() * size_of::<(usize, StateID)>()
+            + parse
+                .machine
+                .as_ref()
+                .map_or(0, |(_, state)| state.capacity())
+            + parse.last.capacity() * size_of::<Occurrence>()
+            + self.text.capacity()
+    }
+}
+
+// text of a state followed by the bytes of a continuation, without copying
+struct Text<'a> {
+    old: &'a [u8],
+    new: &'a [u8],
+}
+
+impl Text<'_> {
+    fn slice(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        let len = self.old.len();
+        if end <= len {
+            Cow::Borrowed(&self.old[start..end])
+        } else if start >= len {
+            Cow::Borrowed(&self.new[start - len..end - len])
+        } else {
+            Cow::Owned([&self.old[start..], &self.new[..end - len]].concat())
+        }
+    }
+}
+
+type HookKey = (RIdx<u32>, Vec<u8>);
+
+// hook results by rule and text, hooks are assumed to be deterministic
+#[derive(Default)]
+struct HookCache(Mutex<HashMap<HookKey, bool>>);
+
+impl HookCache {
+    fn get(&self, ridx: RIdx<u32>, text: &[u8], compute: impl FnOnce() -> bool) -> bool {
+        let key = (ridx, text.to_vec());
+        if let Some(&result) = self.0.lock().unwrap().get(&key) {
+            return result;
+        }
+        // not holding the lock while running the hooks
+        let result = compute();
+        let mut cache = self.0.lock().unwrap();
+        if cache.len() >= HOOK_CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(key, result);
+        result
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(_, text)| size_of::<(HookKey, bool)>() + text.capacity())
+            .sum()
+    }
+}
+
+// LR(1) constraint with semantic checks on top of the grammar; hooks are
+// registered per rule and run on the text of every reduced production of the
+// rule, from its first to its last token, and a continuation is invalid if
+// a hook rejects a nonterminal it completes; as usual for LR(1) parsers,
+// a nonterminal is completed once the terminal after it is known;
+// computed terminals are generated by terminal machines instead of the
+// lexer, as soon as the parser can shift one its machine takes over the
+// following bytes, without skipping whitespace before it
+pub struct SemanticGrammarConstraint {
+    grammar: YaccGrammar<u32>,
+    table: StateTable<u32>,
+    num_states: usize,
+    pdfas: PdfaList,
+    hooks: Vec<Vec<SemanticHook>>,
+    cache: HookCache,
+    machines: Vec<(TIdx<u32>, Arc<dyn TerminalMachine>)>,
+    continuations: Vec<Vec<u8>>,
+    permutation: Vec<usize>,
+    skips: Vec<usize>,
+}
+
+// computed terminals in a row are bounded when checking for a match
+const MAX_COMPUTED_AT_END: usize = 64;
+
+impl SemanticGrammarConstraint {
+    pub fn new(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_limits(grammar, tokens, continuations, &CompileLimits::default())
+    }
+
+    pub fn with_limits(
+        grammar: &str,
+        tokens: &str,
+        continuations: Vec<Vec<u8>>,
+        limits: &CompileLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let (grammar, pdfas, _, _) = load_grammar_and_pdfas(
+            grammar,
+            YaccKind::Original(YaccOriginalActionKind::NoAction),
+            tokens,
+            limits,
+            start,
+            &mut |_| {},
+        )?;
+        let (grammar, table, num_states) = build_table(grammar, limits, start, &mut |_| {})?;
+        let (permutation, skips) = optimized_prefix_order(&continuations);
+        Ok(Self {
+            hooks: vec![vec![]; usize::from(grammar.rules_len())],
+            grammar,
+            table,
+            num_states,
+            pdfas,
+            cache: HookCache::default(),
+            machines: vec![],
+            continuations,
+            permutation,
+            skips,
+        })
+    }
+
+    pub fn from_files(
+        grammar_path: impl AsRef<Path>,
+        tokens_path: impl AsRef<Path>,
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(grammar_path.as_ref())?;
+        let grammar = read_to_string(file)?;
+        let file = File::open(tokens_path.as_ref())?;
+        let tokens = read_to_string(file)?;
+        Self::new(&grammar, &tokens, continuations)
+    }
+
+    // adds a check for the given rule, a rule can have multiple hooks
+    // and all of them have to accept the text
+    pub fn with_hook(
+        mut self,
+        rule: &str,
+        hook: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Result<Self, Box<dyn Error>> {
+        let ridx = self
+            .grammar
+            .rule_idx(rule)
+            .ok_or_else(|| format!("unknown rule {rule}"))?;
+        self.hooks[usize::from(ridx)].push(Arc::new(hook));
+        self.cache.clear();
+        Ok(self)
+    }
+
+    // makes the terminal a computed one, generated by the machine instead
+    // of its lexer definition
+    pub fn with_terminal_machine(
+        mut self,
+        terminal: &str,
+        machine: impl TerminalMachine + 'static,
+    ) -> Result<Self, Box<dyn Error>> {
+        let tidx = self
+            .grammar
+            .token_idx(terminal)
+            .ok_or_else(|| format!("unknown terminal {terminal}"))?;
+        if self.machines.iter().any(|&(t, _)| t == tidx) {
+            return Err(format!("terminal {terminal} already has a machine").into());
+        }
+        self.pdfas.retain(|(_, t)| *t != Some(tidx));
+        self.machines.push((tidx, Arc::new(machine)));
+        Ok(self)
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    // generated text, needed by the hooks of nonterminals completed later
+    pub fn text<'s>(&self, state: &'s SemanticState) -> &'s [u8] {
+        &state.text
+    }
+
+    fn accepts(&self, ridx: RIdx<u32>, text: &[u8]) -> bool {
+        let hooks = &self.hooks[usize::from(ridx)];
+        hooks.is_empty()
+            || self
+                .cache
+                .get(ridx, text, || hooks.iter().all(|hook| hook(text)))
+    }
+
+    // reduces and shifts the terminal, false if the table or a hook rejects it
+    fn step(
+        &self,
+        stack: &mut Vec<Entry>,
+        tidx: TIdx<u32>,
+        span: (usize, usize),
+        text: &Text,
+    ) -> bool {
+        loop {
+            let Some(&(stidx, _, prev_end)) = stack.last() else {
+                return false;
+            };
+            match self.table.action(stidx, tidx) {
+                Action::Shift(next) => {
+                    stack.push((next, span.0, span.1));
+                    return true;
+                }
+                Action::Reduce(pidx) => {
+                    let len = self.grammar.prod(pidx).len();
+                    if len >= stack.len() {
+                        return false;
+                    }
+                    let keep = stack.len() - len;
+                    let (start, end) = if len == 0 {
+                        (prev_end, prev_end)
+                    } else {
+                        (stack[keep].1, prev_end)
+                    };
+                    let ridx = self.grammar.prod_to_rule(pidx);
+                    if !self.accepts(ridx, &text.slice(start, end)) {
+                        return false;
+                    }
+                    stack.truncate(keep);
+                    let Some(next) = self.table.goto(stack[keep - 1].0, ridx) else {
+                        return false;
+                    };
+                    stack.push((next, start, end));
+                }
+                Action::Accept => return true,
+                Action::Error => return false,
+            }
+        }
+    }
+
+    // the pending lexeme has to be skippable or the prefix of a terminal
+    // the parser can shift, including the reduces and hooks before it
+    fn is_valid_matching(&self, stack: &[Entry], matching: &Matching, text: &Text) -> bool {
+        let end = text.old.len() + text.new.len();
+        is_valid_remainder(
+            &self.pdfas,
+            matching,
+            self.grammar.eof_token_idx(),
+            |tidx| self.step(&mut stack.to_vec(), tidx, (end, end), text),
+        )
+    }
+
+    fn machine(&self, tidx: TIdx<u32>) -> &dyn TerminalMachine {
+        self.machines
+            .iter()
+            .find(|&&(t, _)| t == tidx)
+            .map(|(_, machine)| machine.as_ref())
+            .expect("terminal without machine")
+    }
+
+    // first computed terminal the parser can shift next and whose machine
+    // can start here
+    fn start_machine(&self, parse: &ParseState, text: &Text) -> Option<(TIdx<u32>, Vec<u8>)> {
+        let end = text.old.len() + text.new.len();
+        self.machines.iter().find_map(|(tidx, machine)| {
+            if !self.step(&mut parse.stack.clone(), *tidx, (end, end), text) {
+                return None;
+            }
+            let context = TerminalContext {
+                grammar: &self.grammar,
+                text,
+                last: &parse.last,
+            };
+            Some((*tidx, machine.start(&context)?))
+        })
+    }
+
+    // shifts the terminal and starts over with the lexer or a machine
+    fn shift(
+        &self,
+        parse: &mut ParseState,
+        tidx: TIdx<u32>,
+        end: usize,
+        text: &Text,
+    ) -> Option<()> {
+        let span = (parse.lexeme_start, end);
+        if !self.step(&mut parse.stack, tidx, span, text) {
+            return None;
+        }
+        match parse.last.iter_mut().find(|(t, ..)| *t == tidx) {
+            Some(occurrence) => *occurrence = (tidx, span.0, span.1),
+            None => parse.last.push((tidx, span.0, span.1)),
+        }
+        parse.lexeme_start = end;
+        parse.matching = initial_prefix_matches(&self.pdfas);
+        parse.machine = self.start_machine(parse, text);
+        Some(())
+    }
+
+    fn advance(&self, state: &SemanticState, bytes: &[u8]) -> Option<ParseState> {
+        let mut parse = state.parse.clone();
+        let offset = state.text.len();
+        let text = Text {
+            old: &state.text,
+            new: bytes,
+        };
+        let mut i = 0;
+        while i < bytes.len() {
+            if let Some((tidx, mut machine_state)) = parse.machine.take() {
+                let machine = self.machine(tidx);
+                while let Some(next) = bytes
+                    .get(i)
+                    .and_then(|&byte| machine.step(&machine_state, byte))
+                {
+                    machine_state = next;
+                    i += 1;
+                }
+                if i == bytes.len() {
+                    parse.machine = Some((tidx, machine_state));
+                    break;
+                } else if !machine.is_final(&machine_state) {
+                    return None;
+                }
+                self.shift(&mut parse, tidx, offset + i, &text)?;
+                continue;
+            }
+            match find_token_or_matching(&bytes[i..], &parse.matching, &self.pdfas)? {
+                TokenOrMatching::Token(Some(tidx), len) => {
+                    i += len;
+                    self.shift(&mut parse, tidx, offset + i, &text)?;
+                }
+                TokenOrMatching::Token(None, len) => {
+                    i += len;
+                    parse.lexeme_start = offset + i;
+                    parse.matching = initial_prefix_matches(&self.pdfas);
+                }
+                TokenOrMatching::Matching(matching) => {
+                    parse.matching = matching;
+                    break;
+                }
+            }
+        }
+        // a running machine was only started if the parser can shift its terminal
+        if parse.machine.is_none() && !self.is_valid_matching(&parse.stack, &parse.matching, &text)
+        {
+            return None;
+        }
+        Some(parse)
+    }
+
+    // the parser accepts after the pending computed terminals, if any
+    fn accepts_end(&self, mut parse: ParseState, text: &Text, depth: usize) -> bool {
+        let end = text.old.len();
+        let eof = self.grammar.eof_token_idx();
+        if self.step(&mut parse.stack.clone(), eof, (end, end), text) {
+            return true;
+        }
+        let Some((tidx, machine_state)) = parse.machine.take() else {
+            return false;
+        };
+        depth < MAX_COMPUTED_AT_END
+            && self.machine(tidx).is_final(&machine_state)
+            && self.shift(&mut parse, tidx, end, text).is_some()
+            && self.accepts_end(parse, text, depth + 1)
+    }
+}
+
+impl MemoryUsage for SemanticGrammarConstraint {
+    fn memory_usage(&self) -> usize {
+        grammar_memory_usage(&self.grammar, self.num_states, &self.pdfas)
+            + self.cache.memory_usage()
+            + continuations_memory_usage(&self.continuations)
+            + (self.permutation.len() + self.skips.len()) * size_of::<usize>()
+    }
+}
+
+impl Constraint for SemanticGrammarConstraint {
+    type State = SemanticState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.get_next_state_with_bytes(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        let mut parse = ParseState {
+            stack: vec![(self.table.start_state(), 0, 0)],
+            matching: initial_prefix_matches(&self.pdfas),
+            lexeme_start: 0,
+            machine: None,
+            last: vec![],
+        };
+        parse.machine = self.start_machine(&parse, &Text { old: &[], new: &[] });
+        SemanticState {
+            parse,
+            text: vec![],
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        let text = Text {
+            old: &state.text,
+            new: &[],
+        };
+        let end = state.text.len();
+        if state.parse.machine.is_some() {
+            return self.accepts_end(state.parse.clone(), &text, 0);
+        }
+        eoi_terminals(&self.pdfas, &state.parse.matching).any(|tidx| {
+            let mut parse = state.parse.clone();
+            self.shift(&mut parse, tidx, end, &text).is_some() && self.accepts_end(parse, &text, 0)
+        })
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        valid_continuations_in_order(
+            &self.continuations,
+            &self.permutation,
+            &self.skips,
+            |cont| self.advance(state, cont).is_some(),
+        )
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let cont = self.continuations.get(continuation)?;
+        self.get_next_state_with_bytes(state, cont)
+    }
+}
+
+impl ByteConstraint for SemanticGrammarConstraint {
+    fn continuations(&self) -> &[Vec<u8>] {
+        &self.continuations
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        let parse = self.advance(state, bytes)?;
+        let mut text = state.text.clone();
+        text.extend_from_slice(bytes);
+        Some(SemanticState { parse, text })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::LR1GrammarConstraint;
+
+    fn conts() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    const GRAMMAR: &str = "
+%start Dates
+%%
+Dates: Date | Dates ',' Date ;
+Date: 'NUM' '-' 'NUM' '-' 'NUM' ;
+";
+    const LEXER: &str = "%%\nNUM [0-9]+\n; [\\x20]+";
+
+    fn is_calendar_date(text: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(text);
+        let parts: Vec<u32> = text
+            .split('-')
+            .filter_map(|part| part.trim().parse().ok())
+            .collect();
+        let [year, month, day] = parts[..] else {
+            return false;
+        };
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days = match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return false,
+        };
+        (1..=days).contains(&day)
+    }
+
+    #[test]
+    fn test_semantic_hooks() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let constraint = SemanticGrammarConstraint::new(GRAMMAR, LEXER, conts())
+            .unwrap()
+            .with_hook("Date", move |text| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                is_calendar_date(text)
+            })
+            .unwrap();
+        assert!(constraint.check(b"2024-02-29"));
+        assert!(constraint.check(b"2024-02-29 , 2023-12-31"));
+        assert!(!constraint.check(b"2023-02-29"));
+        assert!(!constraint.check(b"2024-13-01"));
+
+        // the date closes once the comma after it is known
+        let state = constraint.get_state(b"2023-02-29").unwrap();
+        assert!(!constraint.is_match_state(&state));
+        assert!(constraint.get_next_state_with_bytes(&state, b"1").is_some());
+        assert!(constraint.get_next_state_with_bytes(&state, b",").is_none());
+        assert!(constraint.get_next_state_with_bytes(&state, b" ").is_some());
+        assert!(constraint.get_state(b"2023-02-29 ,").is_none());
+        let valid = constraint.get_valid_continuations(&state);
+        let expected: Vec<usize> = b" 0123456789".iter().map(|&b| b as usize).collect();
+        assert_eq!(valid, expected);
+        assert_eq!(constraint.text(&state), b"2023-02-29");
+
+        // the text of a completed date does not include skipped whitespace
+        let state = constraint.get_state(b"2024-02-29  , 2023-1-3").unwrap();
+        assert!(constraint.is_match_state(&state));
+
+        // results are cached per rule and text
+        let before = calls.load(Ordering::Relaxed);
+        constraint.get_valid_continuations(&constraint.get_state(b"2024-02-29").unwrap());
+        assert_eq!(calls.load(Ordering::Relaxed), before);
+
+        assert!(SemanticGrammarConstraint::new(GRAMMAR, LEXER, conts())
+            .unwrap()
+            .with_hook("Unknown", |_| true)
+            .is_err());
+    }
+
+    #[test]
+    fn test_semantic_without_hooks() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let semantic = SemanticGrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let lr1 = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap();
+        let text = br#"{"a": [1, -2.5e3, {"b": null}], "c": "d"}"#;
+        for len in 0..=text.len() {
+            let prefix = &text[..len];
+            let (Some(s), Some(l)) = (semantic.get_state(prefix), lr1.get_state(prefix)) else {
+                panic!("invalid prefix {}", String::from_utf8_lossy(prefix));
+            };
+            assert_eq!(
+                semantic.get_valid_continuations(&s),
+                lr1.get_valid_continuations(&l)
+            );
+            assert_eq!(semantic.is_match_state(&s), lr1.is_match_state(&l));
+        }
+
+        // hooks see the text of nested nonterminals
+        let values = Arc::new(Mutex::new(vec![]));
+        let seen = values.clone();
+        let semantic = SemanticGrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts(),
+        )
+        .unwrap()
+        .with_hook("value", move |text| {
+            seen.lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(text).to_string());
+            text != b"null"
+        })
+        .unwrap();
+        assert!(semantic.check(br#"{"a": [1, {"b": true}]}"#));
+        assert!(!semantic.check(br#"{"a": [1, {"b": null}]}"#));
+        let values = values.lock().unwrap();
+        assert!(values.contains(&r#"{"b": true}"#.to_string()));
+        assert!(values.contains(&r#"[1, {"b": true}]"#.to_string()));
+    }
+
+    #[test]
+    fn test_semantic_length_prefixed() {
+        let grammar = "
+%start Strings
+%%
+Strings: String | Strings String ;
+String: 'LEN' ':' 'DATA' ',' ;
+";
+        let constraint = SemanticGrammarConstraint::new(grammar, "%%\nLEN [0-9]+", conts())
+            .unwrap()
+            .with_terminal_machine("DATA", LengthPrefixed::new("LEN"))
+            .unwrap();
+        assert!(constraint.check(b"5:hello,"));
+        assert!(constraint.check(b"5:hello,3:a,b,0:,"));
+        assert!(constraint.check(b"12:DATA :,12 a5,"));
+        assert!(!constraint.check(b"5:hell,"));
+        assert!(!constraint.check(b"3:abcd,"));
+        assert!(!constraint.check(b"DATA"));
+
+        // any byte until the length is reached, then only the comma
+        let state = constraint.get_state(b"3:ab").unwrap();
+        assert_eq!(constraint.get_valid_continuations(&state).len(), 256);
+        let state = constraint.get_state(b"3:abc").unwrap();
+        assert!(!constraint.is_match_state(&state));
+        assert_eq!(
+            constraint.get_valid_continuations(&state),
+            vec![b',' as usize]
+        );
+        // the colon is only emitted once the byte after it is known
+        let state = constraint.get_state(b"0:").unwrap();
+        assert_eq!(
+            constraint.get_valid_continuations(&state),
+            vec![b',' as usize]
+        );
+
+        assert!(
+            SemanticGrammarConstraint::new(grammar, "%%\nLEN [0-9]+", conts())
+                .unwrap()
+                .with_terminal_machine("UNKNOWN", LengthPrefixed::new("LEN"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_semantic_computed_text() {
+        let grammar = "
+%start Records
+%%
+Records: Record | Records Record ;
+Record: 'WORD' '#' 'CHECK' ;
+";
+        let checksum = |context: &TerminalContext| {
+            let word = context.last("WORD")?;
+            let sum: u32 = word.iter().map(|&b| b as u32).sum();
+            Some((sum % 100).to_string().into_bytes())
+        };
+        let constraint =
+            SemanticGrammarConstraint::new(grammar, "%%\nWORD [a-z]+\n; [\\x20]+", conts())
+                .unwrap()
+                .with_terminal_machine("CHECK", ComputedText::new(checksum))
+                .unwrap();
+        // 97 + 98 + 99 = 294
+        assert!(constraint.check(b"abc#94"));
+        assert!(constraint.check(b"abc#94 b#98"));
+        assert!(!constraint.check(b"abc#95"));
+        assert!(!constraint.check(b"abc#9"));
+        assert!(!constraint.check(b"abc# 94"));
+
+        let state = constraint.get_state(b"abc#").unwrap();
+        assert_eq!(
+            constraint.get_valid_continuations(&state),
+            vec![b'9' as usize]
+        );
+        let state = constraint.get_state(b"abc#94").unwrap();
+        assert!(constraint.is_match_state(&state));
+        let valid = constraint.get_valid_continuations(&state);
+        let expected: Vec<usize> = b" abcdefghijklmnopqrstuvwxyz"
+            .iter()
+            .map(|&b| b as usize)
+            .collect();
+        assert_eq!(valid, expected);
+    }
+}
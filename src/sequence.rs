@@ -0,0 +1,246 @@
+use std::error::Error;
+
+use crate::{state_fingerprint, Constraint, DynState};
+
+// the dyn byte constraint trait is not imported, its methods would be
+// ambiguous with those of the constraint traits for concrete constraints
+type Part = Box<dyn crate::DynByteConstraint>;
+
+// concatenation of constraints over the same continuations, e.g. a json header,
+// then a rationale matching a regex, then a closing tag; each part has to reach
+// a match before the next one starts, and single continuations can end one
+// part and start the next
+pub struct SequenceConstraint {
+    parts: Vec<Part>,
+    // continuations with a byte after the first one that can start a later
+    // part, the only ones that can cross a part boundary
+    crossing: Vec<usize>,
+}
+
+// parts the output can be in together with their states; more than one if
+// the end of a part is ambiguous, e.g. a number followed by more digits
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct SequenceState {
+    alternatives: Vec<(usize, DynState)>,
+}
+
+impl SequenceConstraint {
+    pub fn new(parts: Vec<Box<dyn crate::DynByteConstraint>>) -> Result<Self, Box<dyn Error>> {
+        let Some(first) = parts.first() else {
+            return Err("sequence needs at least one constraint".into());
+        };
+        if let Some(i) = parts
+            .iter()
+            .position(|part| part.continuations() != first.continuations())
+        {
+            return Err(format!("continuations of part {i} differ from those of part 0").into());
+        }
+        let mut starts = [false; 256];
+        for part in &parts[1..] {
+            let start = part.get_start_state();
+            for (b, starts) in starts.iter_mut().enumerate() {
+                *starts |= part.get_next_state_with_bytes(&start, &[b as u8]).is_some();
+            }
+        }
+        let crossing = first
+            .continuations()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, cont)| {
+                cont.iter()
+                    .skip(1)
+                    .any(|&b| starts[b as usize])
+                    .then_some(i)
+            })
+            .collect();
+        Ok(Self { parts, crossing })
+    }
+
+    pub fn parts(&self) -> &[Box<dyn crate::DynByteConstraint>] {
+        &self.parts
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        self.parts[0].continuations()
+    }
+
+    // index of the part the output is in, the earliest one
+    // if the end of a part is ambiguous
+    pub fn part(&self, state: &SequenceState) -> usize {
+        state
+            .alternatives
+            .iter()
+            .map(|&(part, _)| part)
+            .min()
+            .unwrap_or(0)
+    }
+
+    // adds the start of the next part for every alternative at a match,
+    // repeatedly for parts matching the empty string
+    fn close(&self, alternatives: &mut Vec<(usize, DynState)>) {
+        let mut i = 0;
+        while i < alternatives.len() {
+            let (part, state) = &alternatives[i];
+            if part + 1 < self.parts.len() && self.parts[*part].is_match_state(state) {
+                let next = (part + 1, self.parts[part + 1].get_start_state());
+                if !alternatives.contains(&next) {
+                    alternatives.push(next);
+                }
+            }
+            i += 1;
+        }
+    }
+
+    fn advance(&self, state: &SequenceState, bytes: &[u8]) -> Option<SequenceState> {
+        let mut alternatives = state.alternatives.clone();
+        for &b in bytes {
+            let mut next = vec![];
+            for (part, state) in &alternatives {
+                if let Some(state) = self.parts[*part].get_next_state_with_bytes(state, &[b]) {
+                    let alternative = (*part, state);
+                    if !next.contains(&alternative) {
+                        next.push(alternative);
+                    }
+                }
+            }
+            if next.is_empty() {
+                return None;
+            }
+            self.close(&mut next);
+            alternatives = next;
+        }
+        // canonical order, so equal sets of alternatives are equal states
+        alternatives.sort_by_cached_key(state_fingerprint);
+        Some(SequenceState { alternatives })
+    }
+}
+
+impl Constraint for SequenceConstraint {
+    type State = SequenceState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.advance(&self.get_start_state(), prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        let mut alternatives = vec![(0, self.parts[0].get_start_state())];
+        self.close(&mut alternatives);
+        alternatives.sort_by_cached_key(state_fingerprint);
+        SequenceState { alternatives }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        let last = self.parts.len() - 1;
+        state
+            .alternatives
+            .iter()
+            .any(|(part, state)| *part == last && self.parts[last].is_match_state(state))
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        match (&state.alternatives[..], &next.alternatives[..]) {
+            ([(part, state)], [(next_part, next)]) if part == next_part => {
+                // within the last part no continuation can cross a boundary
+                *part + 1 == self.parts.len()
+                    && self.parts[*part].has_same_continuations(state, next)
+            }
+            _ => false,
+        }
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        // continuations without a possible part start after their first byte
+        // stay within the part of an alternative
+        let mut conts: Vec<_> = state
+            .alternatives
+            .iter()
+            .flat_map(|(part, state)| self.parts[*part].get_valid_continuations(state))
+            .collect();
+        let last = self.parts.len() - 1;
+        if state.alternatives.iter().any(|&(part, _)| part < last) {
+            let continuations = self.continuations();
+            conts.extend(
+                self.crossing
+                    .iter()
+                    .copied()
+                    .filter(|&i| self.advance(state, &continuations[i]).is_some()),
+            );
+        }
+        conts.sort_unstable();
+        conts.dedup();
+        conts
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.advance(state, self.continuations().get(continuation)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ChoiceConstraint, LR1GrammarConstraint, RegularExpressionConstraint};
+
+    #[test]
+    fn test_sequence() {
+        let conts: Vec<_> = [
+            "{", "}", "\"a\"", ":", "1", "\n", "}\n", "ok", " ", "ok</", "</", "end>", "1\n",
+        ]
+        .iter()
+        .map(|c| c.as_bytes().to_vec())
+        .collect();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let json = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts.clone(),
+        )
+        .unwrap();
+        // a json header, then a rationale, then a closing tag
+        let rationale = RegularExpressionConstraint::new(r"\n[a-z ]*", conts.clone()).unwrap();
+        let tag = ChoiceConstraint::new(vec!["</end>".to_string()], conts.clone()).unwrap();
+        let seq = SequenceConstraint::new(vec![Box::new(json), Box::new(rationale), Box::new(tag)])
+            .unwrap();
+
+        let state = seq.get_start_state();
+        assert!(!seq.is_match_state(&state));
+        assert_eq!(seq.part(&state), 0);
+
+        // the number can go on or the rationale can start
+        let state = seq.get_state(b"1").unwrap();
+        assert_eq!(seq.part(&state), 0);
+        assert_eq!(seq.get_valid_continuations(&state), [4, 5, 8, 12]);
+
+        let state = seq.get_state(b"{\"a\":1").unwrap();
+        assert_eq!(seq.get_valid_continuations(&state), [1, 4, 5, 6, 8, 12]);
+        // the continuation ends the header and starts the rationale,
+        // unless the newline is trailing whitespace of the header
+        let state = seq.get_next_state(&state, 6).unwrap();
+        assert_eq!(seq.part(&state), 0);
+        assert_eq!(seq.get_valid_continuations(&state), [5, 7, 8, 9, 10]);
+
+        // and this one ends the rationale and starts the tag
+        let state = seq.get_next_state(&state, 9).unwrap();
+        assert_eq!(seq.part(&state), 2);
+        assert_eq!(seq.get_valid_continuations(&state), [11]);
+        let state = seq.get_next_state(&state, 11).unwrap();
+        assert!(seq.is_match_state(&state));
+        assert!(seq.get_valid_continuations(&state).is_empty());
+
+        assert!(seq.check(b"{\"a\":1}\nok ok</end>"));
+        assert!(seq.check(b"1\n</end>"));
+        assert!(!seq.check(b"1</end>"));
+        assert!(!seq.check(b"{\"a\":1}\nok"));
+        assert!(seq.get_state(b"{\"a\":1}\nOK").is_none());
+
+        assert!(SequenceConstraint::new(vec![]).is_err());
+        let other = RegularExpressionConstraint::new("a", vec![b"a".to_vec()]).unwrap();
+        let json = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts,
+        )
+        .unwrap();
+        assert!(SequenceConstraint::new(vec![Box::new(json), Box::new(other)]).is_err());
+    }
+}
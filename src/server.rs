@@ -0,0 +1,783 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    io::Read,
+    net::ToSocketAddrs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use regex_automata::util::primitives::StateID;
+use serde_json::{json, Value};
+
+use crate::{
+    json_schema_to_lr1, CheckReport, CompileLimits, Constraint, ExactLR1GrammarConstraint,
+    LR1GrammarConstraint, LR1State, MemoryBudget, MemoryReservation, MemoryUsage,
+    RegularExpressionConstraint,
+};
+
+// larger request bodies are rejected with 413 before they are parsed
+const MAX_BODY: u64 = 16 << 20;
+
+enum Compiled {
+    Regex(Box<RegularExpressionConstraint>),
+    LR1(LR1GrammarConstraint),
+    ExactLR1(ExactLR1GrammarConstraint),
+}
+
+#[derive(Clone)]
+enum SessionState {
+    Regex(StateID),
+    LR1(LR1State),
+}
+
+impl Compiled {
+    fn get_state(&self, prefix: &[u8]) -> Option<SessionState> {
+        match self {
+            Compiled::Regex(c) => c.get_state(prefix).map(SessionState::Regex),
+            Compiled::LR1(c) => c.get_state(prefix).map(SessionState::LR1),
+            Compiled::ExactLR1(c) => c.get_state(prefix).map(SessionState::LR1),
+        }
+    }
+
+    fn get_next_state(&self, state: &SessionState, index: usize) -> Option<SessionState> {
+        match (self, state) {
+            (Compiled::Regex(c), SessionState::Regex(s)) => {
+                c.get_next_state(s, index).map(SessionState::Regex)
+            }
+            (Compiled::LR1(c), SessionState::LR1(s)) => {
+                c.get_next_state(s, index).map(SessionState::LR1)
+            }
+            (Compiled::ExactLR1(c), SessionState::LR1(s)) => {
+                c.get_next_state(s, index).map(SessionState::LR1)
+            }
+            _ => unreachable!("session state does not match constraint"),
+        }
+    }
+
+    fn get_valid_ranges(&self, state: &SessionState) -> Vec<(u32, u32)> {
+        match (self, state) {
+            (Compiled::Regex(c), SessionState::Regex(s)) => c.get_valid_ranges(s),
+            (Compiled::LR1(c), SessionState::LR1(s)) => c.get_valid_ranges(s),
+            (Compiled::ExactLR1(c), SessionState::LR1(s)) => c.get_valid_ranges(s),
+            _ => unreachable!("session state does not match constraint"),
+        }
+    }
+
+    fn check_detailed(&self, text: &[u8]) -> CheckReport {
+        match self {
+            Compiled::Regex(c) => c.check_detailed(text),
+            Compiled::LR1(c) => c.check_detailed(text),
+            Compiled::ExactLR1(c) => c.check_detailed(text),
+        }
+    }
+
+    fn is_match_state(&self, state: &SessionState) -> bool {
+        match (self, state) {
+            (Compiled::Regex(c), SessionState::Regex(s)) => c.is_match_state(s),
+            (Compiled::LR1(c), SessionState::LR1(s)) => c.is_match_state(s),
+            (Compiled::ExactLR1(c), SessionState::LR1(s)) => c.is_match_state(s),
+            _ => unreachable!("session state does not match constraint"),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        match self {
+            Compiled::Regex(re) => re.memory_usage(),
+            Compiled::LR1(lr1) => lr1.memory_usage(),
+            Compiled::ExactLR1(lr1) => lr1.memory_usage(),
+        }
+    }
+}
+
+// a compiled constraint with its reservation in the global memory budget,
+// released once the constraint is deleted and its last session is gone
+struct Entry {
+    compiled: Compiled,
+    _memory: MemoryReservation<'static>,
+}
+
+struct Session {
+    constraint: Arc<Entry>,
+    state: SessionState,
+    last_used: Instant,
+}
+
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn bad_request(message: impl Display) -> Self {
+        Self {
+            status: 400,
+            message: message.to_string(),
+        }
+    }
+
+    fn unavailable(message: impl Display) -> Self {
+        Self {
+            status: 503,
+            message: message.to_string(),
+        }
+    }
+
+    fn not_found(what: &str, id: u64) -> Self {
+        Self {
+            status: 404,
+            message: format!("{what} {id} not found"),
+        }
+    }
+}
+
+type HttpResult = Result<Value, HttpError>;
+
+fn field<'a>(body: &'a Value, name: &str) -> Result<&'a Value, HttpError> {
+    body.get(name)
+        .ok_or_else(|| HttpError::bad_request(format!("missing field {name}")))
+}
+
+fn str_field<'a>(body: &'a Value, name: &str) -> Result<&'a str, HttpError> {
+    field(body, name)?
+        .as_str()
+        .ok_or_else(|| HttpError::bad_request(format!("field {name} must be a string")))
+}
+
+fn continuations(body: &Value) -> Result<Vec<Vec<u8>>, HttpError> {
+    // either strings or arrays of bytes
+    let invalid = || HttpError::bad_request("continuations must be strings or arrays of bytes");
+    field(body, "continuations")?
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|c| match c {
+            Value::String(s) => Ok(s.as_bytes().to_vec()),
+            Value::Array(bytes) => bytes
+                .iter()
+                .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+                .collect::<Option<_>>()
+                .ok_or_else(invalid),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+// reads one byte past the limit to detect larger bodies
+fn read_body(reader: impl Read) -> Result<Vec<u8>, HttpError> {
+    let mut body = vec![];
+    reader
+        .take(MAX_BODY + 1)
+        .read_to_end(&mut body)
+        .map_err(HttpError::bad_request)?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(HttpError {
+            status: 413,
+            message: format!("body exceeds {MAX_BODY} bytes"),
+        });
+    }
+    Ok(body)
+}
+
+fn parse_id(id: &str) -> Result<u64, HttpError> {
+    id.parse()
+        .map_err(|_| HttpError::bad_request(format!("invalid id {id}")))
+}
+
+// a small http service for polyglot inference stacks, exchanging json:
+//   POST   /constraints                 compile a regex, lr1 or json_schema constraint
+//   DELETE /constraints/{id}
+//   POST   /constraints/{id}/check      validate a full text, e.g. a final output
+//   POST   /sessions                    start a session for a constraint, optionally with a prefix
+//   GET    /sessions/{id}               valid continuations as half-open index ranges and match status
+//   POST   /sessions/{id}/advance       advance a session by a continuation index
+//   DELETE /sessions/{id}
+// sessions unused for longer than the session ttl, if set, are evicted,
+// so abandoned generations do not accumulate in long-running services;
+// constraints come from untrusted clients, so they are compiled with limits
+// and count against the global memory budget
+pub struct ConstraintServer {
+    constraints: Mutex<HashMap<u64, Arc<Entry>>>,
+    // each session has its own lock, so advancing one does not block the others
+    sessions: Mutex<HashMap<u64, Arc<Mutex<Session>>>>,
+    next_id: AtomicU64,
+    session_ttl: Option<Duration>,
+    limits: CompileLimits,
+}
+
+impl Default for ConstraintServer {
+    fn default() -> Self {
+        Self {
+            constraints: Mutex::default(),
+            sessions: Mutex::default(),
+            next_id: AtomicU64::default(),
+            session_ttl: None,
+            limits: CompileLimits {
+                max_rules: Some(10_000),
+                max_states: Some(100_000),
+                max_lexer_dfa_states: Some(100_000),
+                max_lexer_repetition: Some(64),
+                max_compile_time: Some(Duration::from_secs(30)),
+            },
+        }
+    }
+}
+
+impl ConstraintServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
+
+    // replaces the default limits for compiling constraints
+    pub fn with_limits(mut self, limits: CompileLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // removes sessions idle for longer than the session ttl, returns their ids
+    pub fn evict_idle(&self) -> Vec<u64> {
+        let Some(ttl) = self.session_ttl else {
+            return vec![];
+        };
+        let mut evicted = vec![];
+        self.sessions
+            .lock()
+            .expect("error locking sessions")
+            .retain(|&id, session| {
+                // sessions locked by a request are in use
+                let idle = match session.try_lock() {
+                    Ok(session) => session.last_used.elapsed() >= ttl,
+                    Err(TryLockError::WouldBlock) => false,
+                    Err(TryLockError::Poisoned(_)) => panic!("error locking session"),
+                };
+                if idle {
+                    evicted.push(id);
+                }
+                !idle
+            });
+        evicted.sort();
+        evicted
+    }
+
+    // handles a single request, returns the status code and json response body
+    pub fn handle(&self, method: &str, path: &str, body: &[u8]) -> (u16, String) {
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+        let result = match (method, segments.as_slice()) {
+            ("POST", ["constraints"]) => self.parse(body).and_then(|b| self.compile(&b)),
+            ("DELETE", ["constraints", id]) => parse_id(id).and_then(|id| {
+                self.constraints
+                    .lock()
+                    .expect("error locking constraints")
+                    .remove(&id)
+                    .map(|_| json!({}))
+                    .ok_or_else(|| HttpError::not_found("constraint", id))
+            }),
+            ("POST", ["constraints", id, "check"]) => {
+                parse_id(id).and_then(|id| self.parse(body).and_then(|b| self.check(id, &b)))
+            }
+            ("POST", ["sessions"]) => self.parse(body).and_then(|b| self.start(&b)),
+            ("GET", ["sessions", id]) => parse_id(id).and_then(|id| self.mask(id)),
+            ("POST", ["sessions", id, "advance"]) => {
+                parse_id(id).and_then(|id| self.parse(body).and_then(|b| self.advance(id, &b)))
+            }
+            ("DELETE", ["sessions", id]) => parse_id(id).and_then(|id| {
+                self.sessions
+                    .lock()
+                    .expect("error locking sessions")
+                    .remove(&id)
+                    .map(|_| json!({}))
+                    .ok_or_else(|| HttpError::not_found("session", id))
+            }),
+            _ => Err(HttpError {
+                status: 404,
+                message: format!("no endpoint {method} {path}"),
+            }),
+        };
+        match result {
+            Ok(value) => (200, value.to_string()),
+            Err(e) => (e.status, json!({ "error": e.message }).to_string()),
+        }
+    }
+
+    fn parse(&self, body: &[u8]) -> HttpResult {
+        serde_json::from_slice(body)
+            .map_err(|e| HttpError::bad_request(format!("invalid json: {e}")))
+    }
+
+    fn compile(&self, body: &Value) -> HttpResult {
+        let continuations = continuations(body)?;
+        let exact = body.get("exact").and_then(Value::as_bool).unwrap_or(false);
+        let limits = &self.limits;
+        let lr1 = |grammar: &str, lexer: &str| -> Result<Compiled, Box<dyn Error>> {
+            Ok(if exact {
+                Compiled::ExactLR1(ExactLR1GrammarConstraint::with_limits(
+                    grammar,
+                    lexer,
+                    continuations.clone(),
+                    limits,
+                )?)
+            } else {
+                Compiled::LR1(LR1GrammarConstraint::with_limits(
+                    grammar,
+                    lexer,
+                    continuations.clone(),
+                    limits,
+                )?)
+            })
+        };
+        let compiled = match str_field(body, "kind")? {
+            "regex" => RegularExpressionConstraint::with_limits(
+                str_field(body, "regex")?,
+                continuations.clone(),
+                limits,
+            )
+            .map(|re| Compiled::Regex(Box::new(re))),
+            "lr1" => lr1(str_field(body, "grammar")?, str_field(body, "lexer")?),
+            "json_schema" => {
+                let schema = field(body, "schema")?.to_string();
+                json_schema_to_lr1(&schema).and_then(|(grammar, lexer)| lr1(&grammar, &lexer))
+            }
+            kind => return Err(HttpError::bad_request(format!("unknown kind {kind}"))),
+        }
+        .map_err(|e| HttpError::bad_request(format!("failed to compile constraint: {e}")))?;
+        let memory = MemoryBudget::global()
+            .reserve(compiled.memory_usage())
+            .map_err(HttpError::unavailable)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.constraints
+            .lock()
+            .expect("error locking constraints")
+            .insert(
+                id,
+                Arc::new(Entry {
+                    compiled,
+                    _memory: memory,
+                }),
+            );
+        Ok(json!({ "constraint": id }))
+    }
+
+    fn constraint(&self, id: u64) -> Result<Arc<Entry>, HttpError> {
+        self.constraints
+            .lock()
+            .expect("error locking constraints")
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| HttpError::not_found("constraint", id))
+    }
+
+    fn check(&self, id: u64, body: &Value) -> HttpResult {
+        let text = str_field(body, "text")?;
+        let report = self
+            .constraint(id)?
+            .compiled
+            .check_detailed(text.as_bytes());
+        Ok(json!({
+            "is_match": report.is_match,
+            "is_prefix": report.is_prefix,
+            "valid_up_to": report.valid_up_to,
+        }))
+    }
+
+    fn start(&self, body: &Value) -> HttpResult {
+        let id = field(body, "constraint")?
+            .as_u64()
+            .ok_or_else(|| HttpError::bad_request("constraint must be an id"))?;
+        let constraint = self.constraint(id)?;
+        let prefix = body
+            .get("prefix")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let state = constraint
+            .compiled
+            .get_state(prefix.as_bytes())
+            .ok_or_else(|| HttpError::bad_request("invalid prefix"))?;
+        let response = Self::response(&constraint.compiled, &state);
+        let session = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions
+            .lock()
+            .expect("error locking sessions")
+            .insert(
+                session,
+                Arc::new(Mutex::new(Session {
+                    constraint,
+                    state,
+                    last_used: Instant::now(),
+                })),
+            );
+        Ok(json!({ "session": session, "state": response }))
+    }
+
+    fn session(&self, id: u64) -> Result<Arc<Mutex<Session>>, HttpError> {
+        self.sessions
+            .lock()
+            .expect("error locking sessions")
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| HttpError::not_found("session", id))
+    }
+
+    fn mask(&self, id: u64) -> HttpResult {
+        let session = self.session(id)?;
+        let (constraint, state) = {
+            let mut session = session.lock().expect("error locking session");
+            session.last_used = Instant::now();
+            (session.constraint.clone(), session.state.clone())
+        };
+        Ok(Self::response(&constraint.compiled, &state))
+    }
+
+    fn advance(&self, id: u64, body: &Value) -> HttpResult {
+        let index = field(body, "index")?
+            .as_u64()
+            .ok_or_else(|| HttpError::bad_request("index must be a non-negative integer"))?;
+        let session = self.session(id)?;
+        // the session stays locked from reading to writing its state,
+        // so concurrent advances of the same session are not lost
+        let mut session = session.lock().expect("error locking session");
+        session.last_used = Instant::now();
+        let constraint = &session.constraint.compiled;
+        let state = constraint
+            .get_next_state(&session.state, index as usize)
+            .ok_or_else(|| HttpError::bad_request(format!("invalid continuation {index}")))?;
+        let response = Self::response(constraint, &state);
+        session.state = state;
+        Ok(response)
+    }
+
+    fn response(constraint: &Compiled, state: &SessionState) -> Value {
+        json!({
+            "ranges": constraint.get_valid_ranges(state),
+            "is_match": constraint.is_match_state(state),
+        })
+    }
+
+    // serves requests over http with the given number of worker threads, blocks forever
+    pub fn serve(
+        self,
+        addr: impl ToSocketAddrs,
+        num_threads: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let http = Arc::new(tiny_http::Server::http(addr)?);
+        let server = Arc::new(self);
+        if let Some(ttl) = server.session_ttl {
+            // sweep twice per ttl, so sessions live at most 1.5 ttls when idle
+            let server = Arc::downgrade(&server);
+            thread::spawn(move || {
+                while let Some(server) = server.upgrade() {
+                    server.evict_idle();
+                    drop(server);
+                    thread::sleep(ttl / 2);
+                }
+            });
+        }
+        let workers: Vec<_> = (0..num_threads.max(1))
+            .map(|_| {
+                let http = http.clone();
+                let server = server.clone();
+                thread::spawn(move || {
+                    for mut request in http.incoming_requests() {
+                        let (status, response) = match read_body(request.as_reader()) {
+                            Ok(body) => {
+                                server.handle(request.method().as_str(), request.url(), &body)
+                            }
+                            Err(e) => (e.status, json!({ "error": e.message }).to_string()),
+                        };
+                        let header = "Content-Type: application/json"
+                            .parse::<tiny_http::Header>()
+                            .expect("valid header");
+                        let response = tiny_http::Response::from_string(response)
+                            .with_status_code(status)
+                            .with_header(header);
+                        if let Err(e) = request.respond(response) {
+                            eprintln!("failed to respond: {e}");
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().map_err(|_| "server worker panicked")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::repeat, io::Read, thread, time::Duration};
+
+    use serde_json::{json, Value};
+
+    use crate::CompileLimits;
+
+    use super::{read_body, ConstraintServer, MAX_BODY};
+
+    fn request(server: &ConstraintServer, method: &str, path: &str, body: Value) -> (u16, Value) {
+        let (status, response) = server.handle(method, path, body.to_string().as_bytes());
+        (status, serde_json::from_str(&response).unwrap())
+    }
+
+    #[test]
+    fn test_server_regex() {
+        let server = ConstraintServer::new();
+        let (status, response) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({ "kind": "regex", "regex": "ab+", "continuations": ["a", "b", [99]] }),
+        );
+        assert_eq!(status, 200);
+        let constraint = &response["constraint"];
+
+        let (status, response) = request(
+            &server,
+            "POST",
+            "/sessions",
+            json!({ "constraint": constraint }),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            response["state"],
+            json!({ "ranges": [[0, 1]], "is_match": false })
+        );
+        let session = response["session"].as_u64().unwrap();
+
+        let (status, response) = request(
+            &server,
+            "POST",
+            &format!("/sessions/{session}/advance"),
+            json!({ "index": 0 }),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(response, json!({ "ranges": [[1, 2]], "is_match": false }));
+        let (status, _) = request(
+            &server,
+            "POST",
+            &format!("/sessions/{session}/advance"),
+            json!({ "index": 1 }),
+        );
+        assert_eq!(status, 200);
+        let (status, response) =
+            request(&server, "GET", &format!("/sessions/{session}"), json!({}));
+        assert_eq!(status, 200);
+        assert_eq!(response, json!({ "ranges": [[1, 2]], "is_match": true }));
+
+        // invalid continuation does not change the session
+        let (status, _) = request(
+            &server,
+            "POST",
+            &format!("/sessions/{session}/advance"),
+            json!({ "index": 2 }),
+        );
+        assert_eq!(status, 400);
+        let (_, response) = request(&server, "GET", &format!("/sessions/{session}"), json!({}));
+        assert_eq!(response["is_match"], true);
+
+        let (status, _) = request(
+            &server,
+            "DELETE",
+            &format!("/sessions/{session}"),
+            json!({}),
+        );
+        assert_eq!(status, 200);
+        let (status, _) = request(&server, "GET", &format!("/sessions/{session}"), json!({}));
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_server_json_schema() {
+        let server = ConstraintServer::new();
+        let (status, response) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({
+                "kind": "json_schema",
+                "schema": { "type": "boolean" },
+                "continuations": ["true", "false", "null"],
+            }),
+        );
+        assert_eq!(status, 200);
+        let constraint = response["constraint"].clone();
+        let (status, response) = request(
+            &server,
+            "POST",
+            "/sessions",
+            json!({ "constraint": constraint }),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(response["state"]["ranges"], json!([[0, 2]]));
+        let (status, response) = request(
+            &server,
+            "POST",
+            &format!("/constraints/{constraint}/check"),
+            json!({ "text": "tru" }),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            response,
+            json!({ "is_match": false, "is_prefix": true, "valid_up_to": 3 })
+        );
+        let (_, response) = request(
+            &server,
+            "POST",
+            &format!("/constraints/{constraint}/check"),
+            json!({ "text": "falsy" }),
+        );
+        assert_eq!(
+            response,
+            json!({ "is_match": false, "is_prefix": false, "valid_up_to": 4 })
+        );
+    }
+
+    #[test]
+    fn test_server_session_ttl() {
+        let server = ConstraintServer::new().with_session_ttl(Duration::from_millis(50));
+        let (_, response) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({ "kind": "regex", "regex": "a+", "continuations": ["a"] }),
+        );
+        let constraint = &response["constraint"];
+        let sessions: Vec<_> = (0..2)
+            .map(|_| {
+                let (_, response) = request(
+                    &server,
+                    "POST",
+                    "/sessions",
+                    json!({ "constraint": constraint }),
+                );
+                response["session"].as_u64().unwrap()
+            })
+            .collect();
+        assert!(server.evict_idle().is_empty());
+
+        // using a session keeps it alive
+        thread::sleep(Duration::from_millis(30));
+        let (status, _) = request(
+            &server,
+            "GET",
+            &format!("/sessions/{}", sessions[1]),
+            json!({}),
+        );
+        assert_eq!(status, 200);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(server.evict_idle(), [sessions[0]]);
+        let (status, _) = request(
+            &server,
+            "GET",
+            &format!("/sessions/{}", sessions[0]),
+            json!({}),
+        );
+        assert_eq!(status, 404);
+
+        // sessions never expire without a ttl
+        assert!(ConstraintServer::new().evict_idle().is_empty());
+    }
+
+    #[test]
+    fn test_server_errors() {
+        let server = ConstraintServer::new();
+        let (status, _) = server.handle("POST", "/constraints", b"not json");
+        assert_eq!(status, 400);
+        let (status, _) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({ "kind": "regex", "regex": "(", "continuations": [] }),
+        );
+        assert_eq!(status, 400);
+        let (status, _) = request(&server, "POST", "/sessions", json!({ "constraint": 7 }));
+        assert_eq!(status, 404);
+        let (status, _) = request(&server, "GET", "/unknown", json!({}));
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_server_limits() {
+        let server = ConstraintServer::new().with_limits(CompileLimits {
+            max_lexer_repetition: Some(8),
+            ..Default::default()
+        });
+        let (status, response) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({ "kind": "regex", "regex": "[ab]*a[ab]{20}", "continuations": ["a"] }),
+        );
+        assert_eq!(status, 400);
+        assert!(response["error"]
+            .as_str()
+            .unwrap()
+            .contains("at most 8 are allowed"));
+        let (status, response) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({
+                "kind": "lr1",
+                "grammar": "%start S\n%%\nS: 'X';",
+                "lexer": "%%\nX [ab]*a[ab]{20}",
+                "continuations": ["a"],
+            }),
+        );
+        assert_eq!(status, 400);
+        assert!(response["error"]
+            .as_str()
+            .unwrap()
+            .contains("at most 8 are allowed"));
+
+        assert!(read_body(repeat(b'a').take(MAX_BODY)).is_ok());
+        let err = read_body(repeat(b'a').take(MAX_BODY + 1)).unwrap_err();
+        assert_eq!(err.status, 413);
+    }
+
+    #[test]
+    fn test_server_concurrent_advance() {
+        let server = ConstraintServer::new();
+        let (_, response) = request(
+            &server,
+            "POST",
+            "/constraints",
+            json!({ "kind": "regex", "regex": "a{64}", "continuations": ["a"] }),
+        );
+        let (_, response) = request(
+            &server,
+            "POST",
+            "/sessions",
+            json!({ "constraint": response["constraint"] }),
+        );
+        let session = response["session"].as_u64().unwrap();
+        // no advance is lost, so the session matches after exactly 64 of them
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..8 {
+                        let (status, _) = request(
+                            &server,
+                            "POST",
+                            &format!("/sessions/{session}/advance"),
+                            json!({ "index": 0 }),
+                        );
+                        assert_eq!(status, 200);
+                    }
+                });
+            }
+        });
+        let (_, response) = request(&server, "GET", &format!("/sessions/{session}"), json!({}));
+        assert_eq!(response, json!({ "ranges": [], "is_match": true }));
+    }
+}
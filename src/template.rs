@@ -0,0 +1,251 @@
+use std::{collections::HashMap, error::Error};
+
+use regex::escape;
+
+use crate::{
+    memory::MemoryUsage, Constraint, LR1GrammarConstraint, RegularExpressionConstraint,
+    SequenceConstraint, SequenceState,
+};
+
+// literal text with holes, e.g. "Name: {regex:\w+}\nData: {grammar:json}";
+// literals and regex holes in between grammar holes are merged into a single
+// regular expression, the parts are then chained like in a sequence
+pub struct TemplateConstraint {
+    sequence: SequenceConstraint,
+    // memory of the parts, measured before their types are erased
+    memory: usize,
+}
+
+enum Segment<'a> {
+    Literal(String),
+    Regex(&'a str),
+    Grammar(&'a str),
+}
+
+// splits a template into literals and holes; {{ and }} are literal braces,
+// braces within a hole only need to be balanced, e.g. {regex:\d{3}}
+fn segments(template: &str) -> Result<Vec<Segment<'_>>, Box<dyn Error>> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut chars = template.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' if chars.next_if(|&(_, c)| c == '{').is_some() => literal.push('{'),
+            '}' if chars.next_if(|&(_, c)| c == '}').is_some() => literal.push('}'),
+            '}' => return Err(format!("unmatched }} at position {i}").into()),
+            '{' => {
+                let mut depth = 1;
+                let mut end = None;
+                while let Some((j, c)) = chars.next() {
+                    match c {
+                        // escaped braces do not count
+                        '\\' => {
+                            chars.next();
+                        }
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = Some(j);
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let end = end.ok_or_else(|| format!("unclosed hole at position {i}"))?;
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                let hole = &template[i + 1..end];
+                segments.push(match hole.split_once(':') {
+                    Some(("regex", pattern)) => Segment::Regex(pattern),
+                    Some(("grammar", name)) => Segment::Grammar(name.trim()),
+                    _ => {
+                        return Err(format!(
+                        "invalid hole {{{hole}}}, expected {{regex:PATTERN}} or {{grammar:NAME}}"
+                    )
+                        .into())
+                    }
+                });
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+fn regex_part(
+    pattern: &str,
+    continuations: &[Vec<u8>],
+    memory: &mut usize,
+) -> Result<Box<dyn crate::DynByteConstraint>, Box<dyn Error>> {
+    let re = RegularExpressionConstraint::new(pattern, continuations.to_vec())
+        .map_err(|e| format!("invalid regex part {pattern}: {e}"))?;
+    *memory += re.memory_usage();
+    Ok(Box::new(re))
+}
+
+impl TemplateConstraint {
+    // grammars are given as name, grammar and lexer and can be used once each
+    pub fn new(
+        template: &str,
+        grammars: &[(&str, &str, &str)],
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut grammars: HashMap<_, _> = grammars
+            .iter()
+            .map(|&(name, grammar, lexer)| (name, (grammar, lexer)))
+            .collect();
+        let mut parts: Vec<Box<dyn crate::DynByteConstraint>> = vec![];
+        let mut memory = 0;
+        let mut pattern = String::new();
+        for segment in segments(template)? {
+            match segment {
+                Segment::Literal(literal) => pattern.push_str(&escape(&literal)),
+                Segment::Regex(regex) => pattern.push_str(&format!("(?:{regex})")),
+                Segment::Grammar(name) => {
+                    let (grammar, lexer) = grammars.remove(name).ok_or_else(|| {
+                        format!("grammar {name} is unknown or used more than once")
+                    })?;
+                    if !pattern.is_empty() {
+                        parts.push(regex_part(
+                            &std::mem::take(&mut pattern),
+                            &continuations,
+                            &mut memory,
+                        )?);
+                    }
+                    let lr1 = LR1GrammarConstraint::new(grammar, lexer, continuations.clone())
+                        .map_err(|e| format!("invalid grammar {name}: {e}"))?;
+                    memory += lr1.memory_usage();
+                    parts.push(Box::new(lr1));
+                }
+            }
+        }
+        // an empty template only matches the empty string
+        if !pattern.is_empty() || parts.is_empty() {
+            parts.push(regex_part(&pattern, &continuations, &mut memory)?);
+        }
+        Ok(Self {
+            sequence: SequenceConstraint::new(parts)?,
+            memory,
+        })
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        self.sequence.continuations()
+    }
+
+    // index of the regex or grammar part the output is in
+    pub fn part(&self, state: &SequenceState) -> usize {
+        self.sequence.part(state)
+    }
+}
+
+impl MemoryUsage for TemplateConstraint {
+    fn memory_usage(&self) -> usize {
+        self.memory + size_of::<Self>()
+    }
+}
+
+impl Constraint for TemplateConstraint {
+    type State = SequenceState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.sequence.get_state(prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        self.sequence.get_start_state()
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.sequence.is_match_state(state)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        self.sequence.has_same_continuations(state, next)
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.sequence.get_valid_continuations(state)
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.sequence.get_next_state(state, continuation)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_template() {
+        let conts: Vec<_> = [
+            "Name: ", "Bob", "\n", "Age: ", "42", "1234", "{", "}", "\nData: ",
+        ]
+        .iter()
+        .map(|c| c.as_bytes().to_vec())
+        .collect();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let grammar = fs::read_to_string(format!("{dir}/grammars/json/json.y")).unwrap();
+        let lexer = fs::read_to_string(format!("{dir}/grammars/json/json.l")).unwrap();
+        let template = TemplateConstraint::new(
+            "Name: {regex:\\w+}\nAge: {regex:\\d{1,3}}\nData: {grammar:json}",
+            &[("json", &grammar, &lexer)],
+            conts,
+        )
+        .unwrap();
+
+        let state = template.get_start_state();
+        assert_eq!(template.get_valid_continuations(&state), [0]);
+        let state = template.get_state(b"Name: Bob").unwrap();
+        assert_eq!(template.get_valid_continuations(&state), [1, 2, 4, 5]);
+        let state = template.get_state(b"Name: Bob\nAge: 42").unwrap();
+        assert_eq!(template.part(&state), 0);
+        // at most three digits, then on to the json data
+        assert_eq!(template.get_valid_continuations(&state), [2, 8]);
+        let state = template.get_state(b"Name: Bob\nAge: 42\nData: ").unwrap();
+        assert_eq!(template.get_valid_continuations(&state), [2, 4, 5, 6]);
+        let state = template.get_next_state(&state, 6).unwrap();
+        assert_eq!(template.part(&state), 1);
+        assert!(template.check(b"Name: Bob\nAge: 42\nData: {}"));
+        assert!(!template.check(b"Name: Bob\nAge: 4242\nData: {}"));
+        assert!(!template.check(b"Name: Bob\nAge: 42\nData: "));
+    }
+
+    #[test]
+    fn test_template_segments() {
+        let conts = vec![b"{".to_vec(), b"}".to_vec(), b"a".to_vec()];
+        let template = TemplateConstraint::new("{{{regex:a*}}}", &[], conts.clone()).unwrap();
+        assert!(template.check(b"{aa}"));
+        assert!(!template.check(b"{{aa}}"));
+        let empty = TemplateConstraint::new("", &[], conts.clone()).unwrap();
+        assert!(empty.check(b""));
+        assert!(!empty.check(b"a"));
+
+        for invalid in ["{regex:a", "a}", "{json}", "{grammar:json}"] {
+            assert!(TemplateConstraint::new(invalid, &[], conts.clone()).is_err());
+        }
+        let grammar = "%start S\n%%\nS: 'a' ;\n";
+        assert!(TemplateConstraint::new(
+            "{grammar:a}{grammar:a}",
+            &[("a", grammar, "")],
+            conts.clone()
+        )
+        .is_err());
+        let template = TemplateConstraint::new(
+            "{grammar:a}-{grammar:b}",
+            &[("a", grammar, ""), ("b", grammar, "")],
+            conts,
+        )
+        .unwrap();
+        assert!(template.check(b"a-a"));
+    }
+}
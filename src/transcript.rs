@@ -0,0 +1,297 @@
+use std::{error::Error, hash::Hash};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{state_fingerprint, ByteConstraint};
+
+// one generated continuation, together with the fingerprint of the state
+// after it, none if the continuation was invalid at this step, and whether
+// it was the only valid continuation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TranscriptStep {
+    pub token: usize,
+    pub bytes: Vec<u8>,
+    pub fingerprint: Option<u64>,
+    pub forced: bool,
+}
+
+// record of a constrained generation, e.g. for auditing outputs
+// or checking them against a newer version of a grammar later on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transcript {
+    pub prefix: Vec<u8>,
+    pub start_fingerprint: Option<u64>,
+    pub steps: Vec<TranscriptStep>,
+    pub is_match: bool,
+}
+
+impl Transcript {
+    pub fn record<C>(constraint: &C, prefix: &[u8], tokens: &[usize]) -> Self
+    where
+        C: ByteConstraint,
+        C::State: Hash,
+    {
+        let mut recorder = TranscriptRecorder::new(constraint, prefix);
+        for &token in tokens {
+            recorder.step(token);
+        }
+        recorder.finish()
+    }
+
+    pub fn tokens(&self) -> Vec<usize> {
+        self.steps.iter().map(|step| step.token).collect()
+    }
+
+    // generated bytes, without the prefix
+    pub fn output(&self) -> Vec<u8> {
+        self.steps
+            .iter()
+            .flat_map(|step| step.bytes.iter().copied())
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("transcript is always serializable")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("transcript is always serializable")
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+
+    // replays the transcript with the given constraint and compares
+    // the result with the recorded steps
+    pub fn verify<C>(&self, constraint: &C) -> TranscriptVerification
+    where
+        C: ByteConstraint,
+        C::State: Hash,
+    {
+        let continuations = constraint.continuations();
+        let vocabulary_mismatch = self.steps.iter().position(|step| {
+            continuations
+                .get(step.token)
+                .is_none_or(|bytes| bytes != &step.bytes)
+        });
+        let replayed = Self::record(constraint, &self.prefix, &self.tokens());
+        let mismatches = |differs: fn(&TranscriptStep, &TranscriptStep) -> bool| {
+            self.steps
+                .iter()
+                .zip(&replayed.steps)
+                .enumerate()
+                .filter_map(|(i, (recorded, replayed))| differs(recorded, replayed).then_some(i))
+                .collect()
+        };
+        TranscriptVerification {
+            steps: self.steps.len(),
+            valid_steps: replayed
+                .steps
+                .iter()
+                .take_while(|step| step.fingerprint.is_some())
+                .count(),
+            is_match: replayed.is_match,
+            recorded_match: self.is_match,
+            prefix_valid: replayed.start_fingerprint.is_some(),
+            vocabulary_mismatch,
+            fingerprint_mismatches: mismatches(|a, b| a.fingerprint != b.fingerprint),
+            forced_mismatches: mismatches(|a, b| a.forced != b.forced),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptVerification {
+    pub steps: usize,
+    // number of leading steps the constraint still accepts
+    pub valid_steps: usize,
+    pub is_match: bool,
+    pub recorded_match: bool,
+    pub prefix_valid: bool,
+    // first step whose bytes differ from the continuation of its token
+    pub vocabulary_mismatch: Option<usize>,
+    // steps whose state fingerprint or forced flag changed, fingerprints
+    // change with any change to the constraint, even if the output
+    // is still accepted
+    pub fingerprint_mismatches: Vec<usize>,
+    pub forced_mismatches: Vec<usize>,
+}
+
+impl TranscriptVerification {
+    // the output is still accepted by the constraint
+    pub fn is_valid(&self) -> bool {
+        self.prefix_valid
+            && self.vocabulary_mismatch.is_none()
+            && self.valid_steps == self.steps
+            && self.is_match
+    }
+
+    // the constraint behaves exactly as during recording
+    pub fn is_unchanged(&self) -> bool {
+        self.prefix_valid
+            && self.vocabulary_mismatch.is_none()
+            && self.valid_steps == self.steps
+            && self.is_match == self.recorded_match
+            && self.fingerprint_mismatches.is_empty()
+            && self.forced_mismatches.is_empty()
+    }
+}
+
+// records a transcript step by step during generation; computing
+// the forced flags needs all valid continuations at each step
+pub struct TranscriptRecorder<'c, C: ByteConstraint> {
+    constraint: &'c C,
+    state: Option<C::State>,
+    transcript: Transcript,
+}
+
+impl<'c, C> TranscriptRecorder<'c, C>
+where
+    C: ByteConstraint,
+    C::State: Hash,
+{
+    pub fn new(constraint: &'c C, prefix: &[u8]) -> Self {
+        let state = constraint.get_state(prefix);
+        let transcript = Transcript {
+            prefix: prefix.to_vec(),
+            start_fingerprint: state.as_ref().map(state_fingerprint),
+            steps: vec![],
+            is_match: false,
+        };
+        Self {
+            constraint,
+            state,
+            transcript,
+        }
+    }
+
+    // records the token and returns whether it was valid,
+    // all steps after an invalid one are invalid as well
+    pub fn step(&mut self, token: usize) -> bool {
+        let bytes = self
+            .constraint
+            .continuations()
+            .get(token)
+            .cloned()
+            .unwrap_or_default();
+        let forced = self
+            .state
+            .as_ref()
+            .is_some_and(|state| self.constraint.get_valid_continuations(state) == [token]);
+        self.state = self
+            .state
+            .take()
+            .and_then(|state| self.constraint.get_next_state(&state, token));
+        self.transcript.steps.push(TranscriptStep {
+            token,
+            bytes,
+            fingerprint: self.state.as_ref().map(state_fingerprint),
+            forced,
+        });
+        self.state.is_some()
+    }
+
+    pub fn state(&self) -> Option<&C::State> {
+        self.state.as_ref()
+    }
+
+    pub fn finish(mut self) -> Transcript {
+        self.transcript.is_match = self
+            .state
+            .as_ref()
+            .is_some_and(|state| self.constraint.is_match_state(state));
+        self.transcript
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::RegularExpressionConstraint;
+
+    use super::*;
+
+    fn continuations() -> Vec<Vec<u8>> {
+        ["a", "b", "ab", "c"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_transcript() {
+        let constraint = RegularExpressionConstraint::new(r"(ab)+c", continuations()).unwrap();
+        let transcript = Transcript::record(&constraint, b"", &[2, 0, 1, 3]);
+        assert_eq!(transcript.output(), b"ababc");
+        assert_eq!(transcript.tokens(), vec![2, 0, 1, 3]);
+        assert!(transcript.is_match);
+        assert!(transcript
+            .steps
+            .iter()
+            .all(|step| step.fingerprint.is_some()));
+        // after a, b is the only valid continuation
+        let forced: Vec<_> = transcript.steps.iter().map(|step| step.forced).collect();
+        assert_eq!(forced, vec![false, false, true, false]);
+
+        let json = Transcript::from_json(&transcript.to_json()).unwrap();
+        assert_eq!(json, transcript);
+        let msgpack = Transcript::from_msgpack(&transcript.to_msgpack()).unwrap();
+        assert_eq!(msgpack, transcript);
+        assert!(Transcript::from_json("{").is_err());
+
+        let verification = transcript.verify(&constraint);
+        assert!(verification.is_valid());
+        assert!(verification.is_unchanged());
+
+        // newer grammar still accepting the output
+        let newer = RegularExpressionConstraint::new(r"(ab)+c?", continuations()).unwrap();
+        let verification = transcript.verify(&newer);
+        assert!(verification.is_valid());
+        assert!(!verification.is_unchanged());
+
+        // newer grammar rejecting the output
+        let newer = RegularExpressionConstraint::new(r"(ab)+d", continuations()).unwrap();
+        let verification = transcript.verify(&newer);
+        assert!(!verification.is_valid());
+        assert_eq!(verification.valid_steps, 3);
+
+        // changed vocabulary
+        let mut changed = continuations();
+        changed.swap(0, 1);
+        let newer = RegularExpressionConstraint::new(r"(ab)+c", changed).unwrap();
+        let verification = transcript.verify(&newer);
+        assert_eq!(verification.vocabulary_mismatch, Some(1));
+        assert!(!verification.is_valid());
+    }
+
+    #[test]
+    fn test_transcript_invalid() {
+        let constraint = RegularExpressionConstraint::new(r"(ab)+c", continuations()).unwrap();
+        let mut recorder = TranscriptRecorder::new(&constraint, b"a");
+        assert!(recorder.step(1));
+        assert!(!recorder.step(1));
+        assert!(!recorder.step(3));
+        assert!(recorder.state().is_none());
+        let transcript = recorder.finish();
+        assert_eq!(transcript.prefix, b"a");
+        assert!(!transcript.is_match);
+        let fingerprints: Vec<_> = transcript
+            .steps
+            .iter()
+            .map(|step| step.fingerprint.is_some())
+            .collect();
+        assert_eq!(fingerprints, vec![true, false, false]);
+        let verification = transcript.verify(&constraint);
+        assert_eq!(verification.valid_steps, 1);
+        assert!(!verification.is_valid());
+        assert!(verification.fingerprint_mismatches.is_empty());
+
+        let transcript = Transcript::record(&constraint, b"c", &[0]);
+        assert_eq!(transcript.start_fingerprint, None);
+        assert!(!transcript.verify(&constraint).prefix_valid);
+    }
+}
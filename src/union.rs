@@ -0,0 +1,427 @@
+use std::{collections::HashSet, error::Error};
+
+use itertools::Itertools;
+
+use crate::{
+    Constraint, DynState, LR1GrammarConstraint, LR1GrammarParser, LR1Parse, LR1State, MemoryUsage,
+};
+
+// the dyn constraint trait is not imported, its methods would be ambiguous
+// with those of the constraint trait for concrete constraints
+type Member = dyn crate::DynConstraint;
+
+// union of named LR(1) grammars, the output has to conform to one of them;
+// grammars are tried in order, so earlier grammars win if an output
+// matches more than one of them; a union of the grammar constraints
+// with a name and a parser for each of them
+pub struct TaggedUnionConstraint {
+    names: Vec<String>,
+    union: UnionConstraint<LR1GrammarConstraint>,
+    parsers: Vec<LR1GrammarParser>,
+}
+
+// one state per grammar, none once the output can no longer conform to it
+pub type TaggedUnionState = UnionState<LR1State>;
+
+impl TaggedUnionConstraint {
+    // grammars are given as (name, grammar, lexer) triples
+    pub fn new(
+        grammars: &[(&str, &str, &str)],
+        continuations: Vec<Vec<u8>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if grammars.is_empty() {
+            return Err("union needs at least one grammar".into());
+        }
+        let mut seen = HashSet::new();
+        let mut names = vec![];
+        let mut constraints = vec![];
+        let mut parsers = vec![];
+        for &(name, grammar, lexer) in grammars {
+            if !seen.insert(name) {
+                return Err(format!("duplicate grammar name {name}").into());
+            }
+            let constraint = LR1GrammarConstraint::new(grammar, lexer, continuations.clone())
+                .map_err(|e| format!("failed to build constraint for grammar {name}: {e}"))?;
+            let parser = LR1GrammarParser::new(grammar, lexer)
+                .map_err(|e| format!("failed to build parser for grammar {name}: {e}"))?;
+            names.push(name.to_string());
+            constraints.push(Box::new(constraint));
+            parsers.push(parser);
+        }
+        Ok(Self {
+            names,
+            union: UnionConstraint { constraints },
+            parsers,
+        })
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn continuations(&self) -> &[Vec<u8>] {
+        self.union.constraints[0].continuations()
+    }
+
+    // names of the grammars the output can still conform to
+    pub fn candidates<'a>(&'a self, state: &'a TaggedUnionState) -> impl Iterator<Item = &'a str> {
+        self.union.candidates(state).map(|i| self.names[i].as_str())
+    }
+
+    // name of the first grammar the output conforms to
+    pub fn matched(&self, state: &TaggedUnionState) -> Option<&str> {
+        self.union.matched(state).map(|i| self.names[i].as_str())
+    }
+
+    // parses the text with the first grammar it conforms to,
+    // returning the name of that grammar together with the parse
+    pub fn parse(
+        &self,
+        text: impl AsRef<[u8]>,
+        skip_empty: bool,
+        collapse_single: bool,
+    ) -> Result<(&str, LR1Parse<'_>), Box<dyn Error>> {
+        let text = text.as_ref();
+        let mut errors = vec![];
+        for (name, parser) in self.names.iter().zip(&self.parsers) {
+            match parser.parse(text, skip_empty, collapse_single) {
+                Ok(parse) => return Ok((name, parse)),
+                Err(e) => errors.push(format!("{name}: {e}")),
+            }
+        }
+        Err(format!(
+            "text does not conform to any grammar:\n{}",
+            errors.join("\n")
+        )
+        .into())
+    }
+
+    // parser of the grammar with the given name
+    pub fn parser(&self, name: &str) -> Option<&LR1GrammarParser> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| &self.parsers[i])
+    }
+}
+
+impl MemoryUsage for TaggedUnionConstraint {
+    fn memory_usage(&self) -> usize {
+        // parsers are not accounted for
+        self.union
+            .constraints
+            .iter()
+            .map(|c| c.memory_usage())
+            .sum()
+    }
+}
+
+impl Constraint for TaggedUnionConstraint {
+    type State = TaggedUnionState;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        self.union.get_state(prefix)
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        self.union.get_start_state()
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.union.is_match_state(state)
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        self.union.has_same_continuations(state, next)
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.union.get_valid_continuations(state)
+    }
+
+    fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.union.get_approximate_continuations(state)
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        self.union.get_next_state(state, continuation)
+    }
+}
+
+// union of constraints over the same continuations, by default of arbitrary
+// ones, e.g. json following a grammar or a fixed refusal; a prefix is valid
+// if it is valid for any of the constraints
+pub struct UnionConstraint<C: ?Sized = Member> {
+    constraints: Vec<Box<C>>,
+}
+
+// one state per constraint, none once the output can no longer conform to it
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct UnionState<S = DynState> {
+    states: Vec<Option<S>>,
+}
+
+impl UnionConstraint {
+    pub fn new(constraints: Vec<Box<Member>>) -> Result<Self, Box<dyn Error>> {
+        if constraints.is_empty() {
+            return Err("union needs at least one constraint".into());
+        }
+        Ok(Self { constraints })
+    }
+}
+
+impl<C: Constraint + ?Sized> UnionConstraint<C> {
+    pub fn constraints(&self) -> &[Box<C>] {
+        &self.constraints
+    }
+
+    // indices of the constraints the output can still conform to
+    pub fn candidates<'a>(
+        &'a self,
+        state: &'a UnionState<C::State>,
+    ) -> impl Iterator<Item = usize> + 'a {
+        state
+            .states
+            .iter()
+            .enumerate()
+            .filter_map(|(i, state)| state.as_ref().map(|_| i))
+    }
+
+    // index of the first constraint the output conforms to
+    pub fn matched(&self, state: &UnionState<C::State>) -> Option<usize> {
+        state
+            .states
+            .iter()
+            .zip(&self.constraints)
+            .position(|(state, constraint)| {
+                state
+                    .as_ref()
+                    .is_some_and(|state| constraint.is_match_state(state))
+            })
+    }
+
+    fn merge(
+        &self,
+        state: &UnionState<C::State>,
+        continuations: impl Fn(&C, &C::State) -> Vec<usize>,
+    ) -> Vec<usize> {
+        state
+            .states
+            .iter()
+            .zip(&self.constraints)
+            .filter_map(|(state, constraint)| {
+                state.as_ref().map(|state| continuations(constraint, state))
+            })
+            .kmerge()
+            .dedup()
+            .collect()
+    }
+}
+
+impl<C: Constraint + ?Sized> Constraint for UnionConstraint<C>
+where
+    C::State: PartialEq,
+{
+    type State = UnionState<C::State>;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        let states: Vec<_> = self
+            .constraints
+            .iter()
+            .map(|c| c.get_state(prefix))
+            .collect();
+        if states.iter().all(Option::is_none) {
+            return None;
+        }
+        Some(UnionState { states })
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        UnionState {
+            states: self
+                .constraints
+                .iter()
+                .map(|c| Some(c.get_start_state()))
+                .collect(),
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        self.matched(state).is_some()
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state == next
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.merge(state, |constraint, state| {
+            constraint.get_valid_continuations(state)
+        })
+    }
+
+    fn get_approximate_continuations(&self, state: &Self::State) -> Vec<usize> {
+        self.merge(state, |constraint, state| {
+            constraint.get_approximate_continuations(state)
+        })
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        let states: Vec<_> = state
+            .states
+            .iter()
+            .zip(&self.constraints)
+            .map(|(state, constraint)| {
+                state
+                    .as_ref()
+                    .and_then(|state| constraint.get_next_state(state, continuation))
+            })
+            .collect();
+        if states.iter().all(Option::is_none) {
+            return None;
+        }
+        Some(UnionState { states })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TOOL_GRAMMAR: &str = r#"
+%start Call
+%%
+Call: 'CALL' 'NAME' 'LPAREN' 'RPAREN' ;
+"#;
+    const TOOL_LEXER: &str = r#"
+%%
+CALL "call"
+NAME [a-z]+
+LPAREN \(
+RPAREN \)
+; \s+
+"#;
+    const ANSWER_GRAMMAR: &str = r#"
+%start Answer
+%%
+Answer: 'ANSWER' Words ;
+Words: 'WORD' | Words 'WORD' ;
+"#;
+    const ANSWER_LEXER: &str = r#"
+%%
+ANSWER "answer:"
+WORD [a-z]+
+; \s+
+"#;
+
+    fn union(continuations: &[&str]) -> TaggedUnionConstraint {
+        TaggedUnionConstraint::new(
+            &[
+                ("tool", TOOL_GRAMMAR, TOOL_LEXER),
+                ("answer", ANSWER_GRAMMAR, ANSWER_LEXER),
+            ],
+            continuations
+                .iter()
+                .map(|c| c.as_bytes().to_vec())
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tagged_union() {
+        let conts = ["call", " foo", "(", ")", "answer:", " bar", "x"];
+        let union = union(&conts);
+        assert_eq!(union.names(), ["tool", "answer"]);
+
+        let state = union.get_start_state();
+        assert_eq!(union.get_valid_continuations(&state), vec![0, 4]);
+        assert_eq!(
+            union.candidates(&state).collect::<Vec<_>>(),
+            ["tool", "answer"]
+        );
+        assert!(!union.is_match_state(&state));
+
+        let state = union.get_next_state(&state, 0).unwrap();
+        assert_eq!(union.candidates(&state).collect::<Vec<_>>(), ["tool"]);
+        // x would lex callx as a name
+        assert_eq!(union.get_valid_continuations(&state), vec![1, 5]);
+        let state = union.get_next_state(&state, 1).unwrap();
+        assert!(union.get_next_state(&state, 5).is_none());
+        let state = union.get_next_state(&state, 2).unwrap();
+        let state = union.get_next_state(&state, 3).unwrap();
+        assert!(union.is_match_state(&state));
+        assert_eq!(union.matched(&state), Some("tool"));
+
+        let state = union.get_state(b"answer: foo").unwrap();
+        assert_eq!(union.candidates(&state).collect::<Vec<_>>(), ["answer"]);
+        assert_eq!(union.get_valid_continuations(&state), vec![0, 1, 5, 6]);
+        assert_eq!(union.matched(&state), Some("answer"));
+        assert!(union.get_state(b"call (").is_none());
+
+        let (name, parse) = union.parse("call foo()", false, false).unwrap();
+        assert_eq!(name, "tool");
+        assert_eq!(parse.name(), "Call");
+        let (name, _) = union.parse("answer: foo bar", false, false).unwrap();
+        assert_eq!(name, "answer");
+        assert!(union.parse("call foo", false, false).is_err());
+        let parser = union.parser("answer").unwrap();
+        assert!(parser.parse("call foo()", false, false).is_err());
+        assert!(union.parser("unknown").is_none());
+    }
+
+    #[test]
+    fn test_tagged_union_errors() {
+        assert!(TaggedUnionConstraint::new(&[], vec![]).is_err());
+        assert!(TaggedUnionConstraint::new(
+            &[
+                ("a", TOOL_GRAMMAR, TOOL_LEXER),
+                ("a", ANSWER_GRAMMAR, ANSWER_LEXER)
+            ],
+            vec![]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_union() {
+        let conts: Vec<_> = ["{", "}", "\"a\"", ":", "1", "RE", "FUSE", "REFUSE", " "]
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let json = LR1GrammarConstraint::from_files(
+            format!("{dir}/grammars/json/json.y"),
+            format!("{dir}/grammars/json/json.l"),
+            conts.clone(),
+        )
+        .unwrap();
+        let refuse = crate::RegularExpressionConstraint::new("REFUSE", conts).unwrap();
+        let union = UnionConstraint::new(vec![Box::new(json), Box::new(refuse)]).unwrap();
+
+        let state = union.get_start_state();
+        assert!(!union.is_match_state(&state));
+        assert_eq!(union.candidates(&state).collect::<Vec<_>>(), [0, 1]);
+        let valid = union.get_valid_continuations(&state);
+        assert!(valid.contains(&0) && valid.contains(&5) && valid.contains(&7));
+        assert!(!valid.contains(&6));
+        let approximate = union.get_approximate_continuations(&state);
+        assert!(valid.iter().all(|cont| approximate.contains(cont)));
+
+        let state = union.get_next_state(&state, 5).unwrap();
+        assert_eq!(union.candidates(&state).collect::<Vec<_>>(), [1]);
+        assert_eq!(union.get_valid_continuations(&state), [6]);
+        let state = union.get_next_state(&state, 6).unwrap();
+        assert_eq!(union.matched(&state), Some(1));
+        assert!(union.get_valid_continuations(&state).is_empty());
+
+        assert!(union.check(br#"{"a": 1}"#));
+        assert!(union.check(b"REFUSE"));
+        assert!(!union.check(b"REFUSE {}"));
+        let state = union.get_state(br#"{"a": 1}"#).unwrap();
+        assert_eq!(union.matched(&state), Some(0));
+        assert!(union.get_state(b"{REFUSE").is_none());
+
+        assert!(UnionConstraint::new(vec![]).is_err());
+    }
+}
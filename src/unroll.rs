@@ -0,0 +1,470 @@
+use std::{cell::Cell, error::Error, rc::Rc, str::FromStr};
+
+use cfgrammar::{
+    yacc::{YaccGrammar, YaccKind, YaccOriginalActionKind},
+    PIdx, RIdx, Symbol,
+};
+use itertools::Itertools;
+use regex::{escape, Regex};
+
+use crate::{
+    lr1::{extract_token_aliases, format_yacc_error, parse_lexer, LexerSpec},
+    utils::pattern_from_parts,
+};
+
+// what the regex of an unrolled grammar does where rules are nested deeper
+// than the unrolling depth
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnrollOverflow {
+    // nothing is accepted beyond the depth, so the regex is exact up to
+    // the depth and rejects deeper nesting
+    #[default]
+    Reject,
+    // any sequence of tokens is accepted from the nested rule on, so the
+    // regex accepts a superset of the grammar, exact until the first overflow
+    Approximate,
+}
+
+impl FromStr for UnrollOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "approximate" => Ok(Self::Approximate),
+            _ => Err(format!(
+                "unknown overflow behavior {s}, expected reject or approximate"
+            )),
+        }
+    }
+}
+
+// upper bound on the length of an unrolled pattern, nesting multiplies
+// the pattern of a rule by the number of places it is used in
+const MAX_PATTERN_LEN: usize = 1 << 22;
+
+// matches nothing, for rules without derivations within the depth
+const NOTHING: &str = "[^\\s\\S]";
+
+// regular expression over the tokens of a grammar
+enum Re {
+    Nothing,
+    Token(usize),
+    Seq(Vec<Rc<Re>>),
+    Alt(Vec<Rc<Re>>),
+    Star(Rc<Re>),
+    // a rule nested deeper than the unrolling depth
+    Overflow,
+}
+
+fn seq(items: impl IntoIterator<Item = Rc<Re>>) -> Rc<Re> {
+    let mut flat = vec![];
+    for item in items {
+        match &*item {
+            Re::Nothing => return Rc::new(Re::Nothing),
+            Re::Seq(inner) => flat.extend(inner.iter().cloned()),
+            _ => flat.push(item),
+        }
+    }
+    if flat.len() == 1 {
+        flat.pop().unwrap()
+    } else {
+        Rc::new(Re::Seq(flat))
+    }
+}
+
+fn alt(items: impl IntoIterator<Item = Rc<Re>>) -> Rc<Re> {
+    let mut items: Vec<_> = items
+        .into_iter()
+        .filter(|item| !matches!(**item, Re::Nothing))
+        .collect();
+    match items.len() {
+        0 => Rc::new(Re::Nothing),
+        1 => items.pop().unwrap(),
+        _ => Rc::new(Re::Alt(items)),
+    }
+}
+
+fn star(item: Rc<Re>) -> Rc<Re> {
+    match *item {
+        Re::Nothing => seq([]),
+        _ => Rc::new(Re::Star(item)),
+    }
+}
+
+// strings leading up to an overflow, i.e. prefixes of the matches of
+// the expression that end right before an overflowing rule
+fn overflow_prefix(re: &Rc<Re>) -> Rc<Re> {
+    match &**re {
+        Re::Nothing | Re::Token(_) => Rc::new(Re::Nothing),
+        Re::Overflow => seq([]),
+        Re::Seq(items) => alt(items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| seq(items[..i].iter().cloned().chain([overflow_prefix(item)])))),
+        Re::Alt(items) => alt(items.iter().map(overflow_prefix)),
+        Re::Star(item) => seq([re.clone(), overflow_prefix(item)]),
+    }
+}
+
+fn render(re: &Re, tokens: &[String], pattern: &mut String) {
+    match re {
+        // overflows are handled separately with their prefixes
+        Re::Nothing | Re::Overflow => pattern.push_str(NOTHING),
+        Re::Token(tidx) => pattern.push_str(&tokens[*tidx]),
+        Re::Seq(items) => {
+            for item in items {
+                render(item, tokens, pattern);
+            }
+        }
+        Re::Alt(items) => {
+            pattern.push_str("(?:");
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    pattern.push('|');
+                }
+                render(item, tokens, pattern);
+            }
+            pattern.push(')');
+        }
+        Re::Star(item) => {
+            pattern.push_str("(?:");
+            render(item, tokens, pattern);
+            pattern.push_str(")*");
+        }
+    }
+}
+
+struct Unroller<'a> {
+    grammar: &'a YaccGrammar<u32>,
+    // pattern per token, each preceded by optional ignored tokens
+    tokens: Vec<String>,
+    depth: usize,
+    overflow: UnrollOverflow,
+    // total length of the token patterns in the unrolled expression so far
+    size: Cell<usize>,
+}
+
+enum Recursion<'a> {
+    Base(&'a [Symbol<u32>]),
+    Left(&'a [Symbol<u32>]),
+    Right(&'a [Symbol<u32>]),
+    // recursion in the middle of a production, or more than once
+    Nested(&'a [Symbol<u32>]),
+}
+
+impl<'a> Unroller<'a> {
+    fn classify(&self, ridx: RIdx<u32>, pidx: PIdx<u32>) -> Recursion<'a> {
+        let prod = self.grammar.prod(pidx);
+        let is_rule = |symbol: &Symbol<u32>| *symbol == Symbol::Rule(ridx);
+        match prod.iter().filter(|&symbol| is_rule(symbol)).count() {
+            0 => Recursion::Base(prod),
+            1 if is_rule(&prod[0]) => Recursion::Left(&prod[1..]),
+            1 if is_rule(&prod[prod.len() - 1]) => Recursion::Right(&prod[..prod.len() - 1]),
+            _ => Recursion::Nested(prod),
+        }
+    }
+
+    fn sequence(
+        &self,
+        symbols: &[Symbol<u32>],
+        nesting: &mut Vec<usize>,
+    ) -> Result<Rc<Re>, Box<dyn Error>> {
+        let items: Vec<_> = symbols
+            .iter()
+            .map(|symbol| match *symbol {
+                Symbol::Rule(ridx) => self.rule(ridx, nesting),
+                Symbol::Token(tidx) => {
+                    let tidx = usize::from(tidx);
+                    self.size.set(self.size.get() + self.tokens[tidx].len());
+                    if self.size.get() > MAX_PATTERN_LEN {
+                        return Err(format!(
+                            "unrolled pattern exceeds {MAX_PATTERN_LEN} bytes, \
+                            try a smaller depth"
+                        )
+                        .into());
+                    }
+                    Ok(Rc::new(Re::Token(tidx)))
+                }
+            })
+            .try_collect()?;
+        Ok(seq(items))
+    }
+
+    fn alternatives(
+        &self,
+        sequences: &[&[Symbol<u32>]],
+        nesting: &mut Vec<usize>,
+    ) -> Result<Rc<Re>, Box<dyn Error>> {
+        let items: Vec<_> = sequences
+            .iter()
+            .map(|symbols| self.sequence(symbols, nesting))
+            .try_collect()?;
+        Ok(alt(items))
+    }
+
+    fn rule(&self, ridx: RIdx<u32>, nesting: &mut Vec<usize>) -> Result<Rc<Re>, Box<dyn Error>> {
+        let r = usize::from(ridx);
+        if nesting[r] > self.depth {
+            return Ok(Rc::new(match self.overflow {
+                UnrollOverflow::Reject => Re::Nothing,
+                UnrollOverflow::Approximate => Re::Overflow,
+            }));
+        }
+        nesting[r] += 1;
+        let (mut base, mut left, mut right, mut nested) = (vec![], vec![], vec![], vec![]);
+        for &pidx in self.grammar.rule_to_prods(ridx) {
+            match self.classify(ridx, pidx) {
+                Recursion::Base(symbols) => base.push(symbols),
+                // a rule deriving itself adds nothing
+                Recursion::Left([]) | Recursion::Right([]) => {}
+                Recursion::Left(symbols) => left.push(symbols),
+                Recursion::Right(symbols) => right.push(symbols),
+                Recursion::Nested(symbols) => nested.push(symbols),
+            }
+        }
+        let re = if nested.is_empty() {
+            // left and right recursion only repeat the rest of their
+            // productions around a base production, which is regular
+            let base = self.alternatives(&base, nesting)?;
+            let right = self.alternatives(&right, nesting)?;
+            let left = self.alternatives(&left, nesting)?;
+            seq([star(right), base, star(left)])
+        } else {
+            // everything else is unrolled by nesting the rule into itself
+            let sequences: Vec<_> = self
+                .grammar
+                .rule_to_prods(ridx)
+                .iter()
+                .map(|&pidx| self.grammar.prod(pidx))
+                .collect();
+            self.alternatives(&sequences, nesting)?
+        };
+        nesting[r] -= 1;
+        Ok(re)
+    }
+}
+
+// converts an LR(1) grammar and lexer into a regular expression by unrolling
+// recursive rules; a rule can be nested within itself up to depth times,
+// direct left or right recursion as for lists is repeated without limit;
+// tokens are matched by their patterns preceded by optional ignored tokens,
+// adjacent tokens are not split by longest match as in the lexer, e.g. two
+// numbers without whitespace in between; the pattern needs all matches
+// semantics, with leftmost first a|ab would not match ab
+pub(crate) fn unroll_lr1(
+    grammar: &str,
+    lexer: &str,
+    depth: usize,
+    overflow: UnrollOverflow,
+) -> Result<String, Box<dyn Error>> {
+    let (source, _) = extract_token_aliases(grammar)?;
+    let grammar = YaccGrammar::new(
+        YaccKind::Original(YaccOriginalActionKind::NoAction),
+        &source,
+    )
+    .map_err(|e| {
+        format!(
+            "errors creating grammar:\n{}",
+            e.iter().map(|e| format_yacc_error(&source, e)).join("\n")
+        )
+    })?;
+    let LexerSpec {
+        fragments,
+        tokens,
+        ignore_tokens,
+        byte_mode,
+        ..
+    } = parse_lexer(lexer)?;
+    let token_name = Regex::new(r"\{([A-Z][A-Z0-9_]*)\}")?;
+    let pattern = |name: &str, parts| -> Result<String, Box<dyn Error>> {
+        let pattern = pattern_from_parts(name, parts, &token_name, &fragments, &tokens)?;
+        Ok(if byte_mode {
+            format!("(?-u:{pattern})")
+        } else {
+            pattern
+        })
+    };
+    let skip = ignore_tokens
+        .iter()
+        .map(|parts| pattern("ignore token", parts).map(|p| format!("(?:{p})")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let skip = if skip.is_empty() {
+        String::new()
+    } else {
+        format!("(?:{})*", skip.join("|"))
+    };
+    // the end of input token has no name and is never used in productions
+    let mut named = vec![];
+    let token_patterns = grammar
+        .iter_tidxs()
+        .map(|tidx| {
+            let Some(name) = grammar.token_name(tidx) else {
+                return Ok(String::new());
+            };
+            let pattern = if let Some(parts) = tokens.get(name) {
+                pattern(name, parts)?
+            } else if fragments.contains_key(name) {
+                return Err(format!("token {name} is only defined as fragment").into());
+            } else {
+                escape(name)
+            };
+            named.push(usize::from(tidx));
+            Ok(format!("{skip}(?:{pattern})"))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    let unroller = Unroller {
+        grammar: &grammar,
+        tokens: token_patterns,
+        depth,
+        overflow,
+        size: Cell::new(0),
+    };
+    let mut nesting = vec![0; grammar.rules_len().into()];
+    let re = unroller.rule(grammar.start_rule_idx(), &mut nesting)?;
+    // after an overflow any sequence of tokens is accepted, also in place
+    // of the rest of the enclosing rules, so the dfa does not have to track
+    // where an overflowing rule ends
+    let prefix = overflow_prefix(&re);
+    let re = match *prefix {
+        Re::Nothing => re,
+        _ => alt([
+            re,
+            seq([
+                prefix,
+                star(alt(named.into_iter().map(|t| Rc::new(Re::Token(t))))),
+            ]),
+        ]),
+    };
+    let mut pattern = String::new();
+    render(&re, &unroller.tokens, &mut pattern);
+    if pattern.len() > MAX_PATTERN_LEN {
+        return Err(format!(
+            "unrolled pattern exceeds {MAX_PATTERN_LEN} bytes, try a smaller depth"
+        )
+        .into());
+    }
+    Ok(pattern)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::{Constraint, LR1GrammarConstraint, RegularExpressionConstraint};
+
+    fn byte_continuations() -> Vec<Vec<u8>> {
+        (0..=255).map(|b| vec![b]).collect()
+    }
+
+    #[test]
+    fn test_unroll_json() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("grammars")
+            .join("json");
+        let grammar = fs::read_to_string(dir.join("json.y")).unwrap();
+        let lexer = fs::read_to_string(dir.join("json.l")).unwrap();
+        let lr1 = LR1GrammarConstraint::new(&grammar, &lexer, byte_continuations()).unwrap();
+        let unrolled = |overflow| {
+            RegularExpressionConstraint::unrolled(
+                &grammar,
+                &lexer,
+                2,
+                overflow,
+                byte_continuations(),
+            )
+            .unwrap()
+        };
+        let re = unrolled(UnrollOverflow::Reject);
+
+        // exact up to the depth, lists of any length
+        for text in [
+            "1",
+            " {\"a\": [1, 2, 3, 4, 5], \"b\": {\"c\": null}}",
+            "[[1, 2], [3], []]",
+            "[[1, 2], [3], ",
+            "{\"a\": }",
+            "[1 2]",
+            "",
+        ] {
+            let text = text.as_bytes();
+            assert_eq!(re.get_state(text).is_some(), lr1.get_state(text).is_some());
+            assert_eq!(re.check(text), lr1.check(text));
+        }
+        for text in ["[[1, 2], [3], ", "{\"a\": ", "[1", "{"] {
+            let text = text.as_bytes();
+            assert_eq!(
+                re.get_valid_continuations(&re.get_state(text).unwrap()),
+                lr1.get_valid_continuations(&lr1.get_state(text).unwrap())
+            );
+        }
+        // the innermost value is nested three times
+        assert!(lr1.check(b"[[[1]]]"));
+        assert!(!re.check(b"[[[1]]]"));
+        assert!(re.check(b"[[[]]]"));
+        assert!(re.get_state(b"[[[[").is_none());
+
+        let approx = unrolled(UnrollOverflow::Approximate);
+        assert!(approx.check(b"[[[1]]]"));
+        assert!(approx.check(b"[[[]]]"));
+        // beyond the depth anything goes, before it the grammar is enforced
+        assert!(approx.check(b"[[[1}]"));
+        assert!(!approx.check(b"[[1]}]"));
+        assert!(approx.get_state(b"[}").is_none());
+    }
+
+    #[test]
+    fn test_unroll_recursion() {
+        let grammar = r#"
+%start S
+%%
+S: S ',' item | item ;
+item: 'x' | '(' S ')' | '[' tail ;
+tail: 'y' tail | ']' ;
+"#;
+        let unrolled = |depth| {
+            RegularExpressionConstraint::unrolled(
+                grammar,
+                "",
+                depth,
+                UnrollOverflow::Reject,
+                vec![],
+            )
+            .unwrap()
+        };
+        let re = unrolled(0);
+        assert!(re.check(b"x,[yy],x"));
+        assert!(!re.check(b"(x)"));
+        let re = unrolled(1);
+        assert!(re.check(b"(x,x),[]"));
+        assert!(!re.check(b"((x))"));
+
+        // alternatives that are prefixes of each other
+        let re = RegularExpressionConstraint::unrolled(
+            "%start S\n%%\nS: 'a' | 'a' 'b' ;\n",
+            "",
+            0,
+            UnrollOverflow::Reject,
+            vec![],
+        )
+        .unwrap();
+        assert!(re.check(b"a"));
+        assert!(re.check(b"ab"));
+
+        // rules without a base production derive nothing
+        let re = RegularExpressionConstraint::unrolled(
+            "%start S\n%%\nS: 'a' S ;\n",
+            "",
+            3,
+            UnrollOverflow::Reject,
+            vec![],
+        )
+        .unwrap();
+        assert!(re.get_state(b"a").is_none());
+
+        assert!("approximate".parse::<UnrollOverflow>().is_ok());
+        assert!("fail".parse::<UnrollOverflow>().is_err());
+    }
+}
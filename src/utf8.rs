@@ -0,0 +1,260 @@
+use std::{collections::HashMap, hash::Hash};
+
+use crate::{memory::MemoryUsage, ByteConstraint, Constraint};
+
+// position within a utf-8 encoded character: the number of continuation
+// bytes still missing and the range the next one has to be in, which is
+// narrower than 0x80..=0xbf after some lead bytes to rule out overlong
+// encodings, surrogates, and code points above u+10ffff
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Utf8Position {
+    missing: u8,
+    low: u8,
+    high: u8,
+}
+
+const COMPLETE: Utf8Position = Utf8Position {
+    missing: 0,
+    low: 0x80,
+    high: 0xbf,
+};
+
+impl Utf8Position {
+    fn step(self, byte: u8) -> Option<Self> {
+        let (missing, low, high) = if self.missing > 0 {
+            if !(self.low..=self.high).contains(&byte) {
+                return None;
+            }
+            (self.missing - 1, 0x80, 0xbf)
+        } else {
+            match byte {
+                0x00..=0x7f => (0, 0x80, 0xbf),
+                0xc2..=0xdf => (1, 0x80, 0xbf),
+                0xe0 => (2, 0xa0, 0xbf),
+                0xed => (2, 0x80, 0x9f),
+                0xe1..=0xef => (2, 0x80, 0xbf),
+                0xf0 => (3, 0x90, 0xbf),
+                0xf4 => (3, 0x80, 0x8f),
+                0xf1..=0xf3 => (3, 0x80, 0xbf),
+                _ => return None,
+            }
+        };
+        Some(Self { missing, low, high })
+    }
+
+    fn drive(self, bytes: &[u8]) -> Option<Self> {
+        bytes.iter().try_fold(self, |pos, &b| pos.step(b))
+    }
+}
+
+// wraps a constraint such that its output is always valid utf-8, up to a
+// last character that is not complete yet; continuations that would produce
+// an invalid byte sequence, e.g. byte fallback tokens in the wrong order,
+// are rejected right away instead of leading to undecodable states, and a
+// match additionally requires the last character to be complete
+pub struct Utf8Constraint<C> {
+    inner: C,
+    // for every position within a character, whether each continuation
+    // keeps the output valid utf-8
+    valid: HashMap<Utf8Position, Vec<bool>>,
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct Utf8State<S> {
+    inner: S,
+    position: Utf8Position,
+}
+
+impl<S> Utf8State<S> {
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    // whether the output so far ends with a complete character
+    pub fn is_complete(&self) -> bool {
+        self.position.missing == 0
+    }
+}
+
+impl<C> Utf8Constraint<C>
+where
+    C: ByteConstraint,
+{
+    pub fn new(inner: C) -> Self {
+        // all positions reachable from the start of a character
+        let mut positions = vec![COMPLETE];
+        let mut i = 0;
+        while let Some(&pos) = positions.get(i) {
+            for b in 0..=255 {
+                if let Some(next) = pos.step(b) {
+                    if !positions.contains(&next) {
+                        positions.push(next);
+                    }
+                }
+            }
+            i += 1;
+        }
+        let valid = positions
+            .into_iter()
+            .map(|pos| {
+                let valid = inner
+                    .continuations()
+                    .iter()
+                    .map(|cont| pos.drive(cont).is_some())
+                    .collect();
+                (pos, valid)
+            })
+            .collect();
+        Self { inner, valid }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn is_valid(&self, position: Utf8Position, continuation: usize) -> bool {
+        self.valid[&position]
+            .get(continuation)
+            .copied()
+            .unwrap_or(false)
+    }
+}
+
+impl<C: MemoryUsage> MemoryUsage for Utf8Constraint<C> {
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage()
+            + self
+                .valid
+                .values()
+                .map(|valid| size_of::<Utf8Position>() + valid.capacity())
+                .sum::<usize>()
+    }
+}
+
+impl<C> Constraint for Utf8Constraint<C>
+where
+    C: ByteConstraint,
+{
+    type State = Utf8State<C::State>;
+
+    fn get_state(&self, prefix: &[u8]) -> Option<Self::State> {
+        let position = COMPLETE.drive(prefix)?;
+        let inner = self.inner.get_state(prefix)?;
+        Some(Utf8State { inner, position })
+    }
+
+    fn get_start_state(&self) -> Self::State {
+        Utf8State {
+            inner: self.inner.get_start_state(),
+            position: COMPLETE,
+        }
+    }
+
+    fn is_match_state(&self, state: &Self::State) -> bool {
+        state.is_complete() && self.inner.is_match_state(&state.inner)
+    }
+
+    fn get_valid_continuations(&self, state: &Self::State) -> Vec<usize> {
+        let mut valid = self.inner.get_valid_continuations(&state.inner);
+        let valid_utf8 = &self.valid[&state.position];
+        valid.retain(|&i| valid_utf8[i]);
+        valid
+    }
+
+    fn get_next_state(&self, state: &Self::State, continuation: usize) -> Option<Self::State> {
+        if !self.is_valid(state.position, continuation) {
+            return None;
+        }
+        let position = state
+            .position
+            .drive(&self.inner.continuations()[continuation])?;
+        let inner = self.inner.get_next_state(&state.inner, continuation)?;
+        Some(Utf8State { inner, position })
+    }
+
+    fn has_same_continuations(&self, state: &Self::State, next: &Self::State) -> bool {
+        state.position == next.position
+            && self.inner.has_same_continuations(&state.inner, &next.inner)
+    }
+}
+
+impl<C> ByteConstraint for Utf8Constraint<C>
+where
+    C: ByteConstraint,
+{
+    fn continuations(&self) -> &[Vec<u8>] {
+        self.inner.continuations()
+    }
+
+    fn get_next_state_with_bytes(&self, state: &Self::State, bytes: &[u8]) -> Option<Self::State> {
+        let position = state.position.drive(bytes)?;
+        let inner = self.inner.get_next_state_with_bytes(&state.inner, bytes)?;
+        Some(Utf8State { inner, position })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::RegularExpressionConstraint;
+
+    use super::*;
+
+    #[test]
+    fn test_utf8() {
+        // byte fallback tokens next to regular ones
+        let conts: Vec<Vec<u8>> = vec![
+            b"a".to_vec(),
+            b"\xe4".to_vec(),
+            b"\xb8".to_vec(),
+            b"\xad".to_vec(),
+            b"\xb8\xad".to_vec(),
+            "中".as_bytes().to_vec(),
+            b"\xff".to_vec(),
+            b"\x80".to_vec(),
+            b"\xc0\xaf".to_vec(),
+            b"\xed\xa0\x80".to_vec(),
+        ];
+        let inner = RegularExpressionConstraint::new(r"(?s-u).*", conts.clone()).unwrap();
+        // the byte level regex allows everything
+        let start = inner.get_start_state();
+        assert_eq!(inner.get_valid_continuations(&start).len(), conts.len());
+
+        let utf8 = Utf8Constraint::new(inner);
+        let start = utf8.get_start_state();
+        assert!(start.is_complete());
+        assert_eq!(utf8.get_valid_continuations(&start), [0, 1, 5]);
+        assert!(utf8.is_match_state(&start));
+
+        // inside a three byte character
+        let state = utf8.get_next_state(&start, 1).unwrap();
+        assert!(!state.is_complete());
+        assert!(!utf8.is_match_state(&state));
+        assert_eq!(utf8.get_valid_continuations(&state), [2, 3, 4, 7]);
+        assert!(utf8.get_next_state(&state, 0).is_none());
+        let state = utf8.get_next_state(&state, 4).unwrap();
+        assert!(state.is_complete());
+        assert!(utf8.is_match_state(&state));
+        assert_eq!(utf8.get_valid_continuations(&state), [0, 1, 5]);
+
+        // step by step is the same as all at once
+        let steps = [1, 2, 3]
+            .iter()
+            .try_fold(start.clone(), |state, &i| utf8.get_next_state(&state, i));
+        assert_eq!(steps, utf8.get_state("中".as_bytes()));
+
+        assert!(utf8.check("a中a".as_bytes()));
+        assert!(!utf8.check(b"\xe4\xb8"));
+        assert!(utf8.get_state(b"\xe4\xb8").is_some());
+        assert!(utf8.get_state(b"\xff").is_none());
+        assert!(utf8.get_state(b"a\x80").is_none());
+        // overlong encoding and surrogate
+        assert!(utf8.get_state(b"\xc0\xaf").is_none());
+        assert!(utf8.get_state(b"\xed\xa0").is_none());
+        assert!(utf8
+            .get_next_state_with_bytes(&start, b"\xf4\x90")
+            .is_none());
+        assert!(utf8
+            .get_next_state_with_bytes(&start, b"\xf4\x8f\xbf\xbf")
+            .is_some());
+    }
+}
@@ -1,14 +1,30 @@
-use std::{collections::HashMap, error::Error, fmt::Debug};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    mem::size_of,
+};
 
 use indexmap::IndexMap;
 use itertools::Itertools;
 use regex::{escape, Regex};
 use regex_automata::{
-    dfa::{dense::DFA, Automaton},
-    util::primitives::StateID,
-    Input,
+    dfa::{
+        dense::{self, BuildError, DFA},
+        Automaton,
+    },
+    nfa::thompson,
+    util::{primitives::StateID, syntax},
+    Input, MatchKind,
+};
+use rustc_hash::FxHasher;
+use unicode_normalization::{
+    char::canonical_combining_class, is_nfc_quick, IsNormalized, UnicodeNormalization,
 };
 
+use crate::memory::MemoryUsage;
+
 #[derive(Debug)]
 pub(crate) enum Part {
     Literal(String),
@@ -75,6 +91,34 @@ pub(crate) fn pattern_from_parts(
     Ok(pattern)
 }
 
+// token patterns in lexer files are split at whitespace, so
+// whitespace within a pattern needs to be written as escapes
+pub(crate) fn lexer_pattern(pattern: &str) -> String {
+    let mut escaped = String::from("(?:");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some(next) if next.is_whitespace() => next,
+                Some(next) => {
+                    escaped.push(c);
+                    escaped.push(next);
+                    continue;
+                }
+                None => c,
+            },
+            c => c,
+        };
+        if c.is_whitespace() {
+            escaped.push_str(&format!("\\x{{{:x}}}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped.push(')');
+    escaped
+}
+
 fn make_anchored(pat: &str) -> String {
     assert!(!pat.ends_with('$'), "prefix pattern should not end with $");
     if pat.starts_with('^') {
@@ -86,6 +130,20 @@ fn make_anchored(pat: &str) -> String {
 
 pub(crate) struct PrefixDFA {
     dfa: DFA<Vec<u32>>,
+    // for the complement of the pattern, the states from which every
+    // continuation matches the pattern, these are dead in the complement
+    complement: Option<HashSet<StateID>>,
+}
+
+impl MemoryUsage for PrefixDFA {
+    fn memory_usage(&self) -> usize {
+        self.dfa.memory_usage()
+            + size_of::<Self>()
+            + self
+                .complement
+                .as_ref()
+                .map_or(0, |universal| universal.capacity() * size_of::<StateID>())
+    }
 }
 
 impl Debug for PrefixDFA {
@@ -101,10 +159,287 @@ pub(crate) enum PrefixMatch {
     UpTo(usize),
 }
 
+// all bytes, lowercase letters, digits and other printable ascii first,
+// so that generated examples are readable where possible
+pub(crate) fn preferred_bytes() -> Vec<u8> {
+    (b'a'..=b'z')
+        .chain(b'0'..=b'9')
+        .chain(b'A'..=b'Z')
+        .chain((0x20..0x7f).filter(|b: &u8| !b.is_ascii_alphanumeric()))
+        .chain((0..0x20).chain(0x7f..=0xff))
+        .collect()
+}
+
 impl PrefixDFA {
     pub(crate) fn new(pattern: &str) -> Result<Self, Box<dyn Error>> {
-        let dfa = DFA::new(&make_anchored(pattern))?;
-        Ok(PrefixDFA { dfa })
+        Ok(Self::with_size_limit(pattern, None)?)
+    }
+
+    pub(crate) fn with_size_limit(
+        pattern: &str,
+        size_limit: Option<usize>,
+    ) -> Result<Self, Box<BuildError>> {
+        Self::with_options(pattern, size_limit, false)
+    }
+
+    // with all_matches, alternatives do not take priority over each other like
+    // in the default leftmost-first semantics, e.g. a|ab also matches ab
+    pub(crate) fn with_options(
+        pattern: &str,
+        size_limit: Option<usize>,
+        all_matches: bool,
+    ) -> Result<Self, Box<BuildError>> {
+        let match_kind = if all_matches {
+            MatchKind::All
+        } else {
+            MatchKind::LeftmostFirst
+        };
+        let dfa = Self::builder(size_limit, match_kind).build(&make_anchored(pattern))?;
+        Ok(PrefixDFA {
+            dfa,
+            complement: None,
+        })
+    }
+
+    // a single dfa for several patterns, every pattern matching
+    // an input is reported at the match state, see eoi_matches
+    pub(crate) fn new_many(patterns: &[impl AsRef<str>]) -> Result<Self, Box<BuildError>> {
+        let patterns: Vec<_> = patterns
+            .iter()
+            .map(|pattern| make_anchored(pattern.as_ref()))
+            .collect();
+        let dfa = Self::builder(None, MatchKind::All).build_many(&patterns)?;
+        Ok(PrefixDFA {
+            dfa,
+            complement: None,
+        })
+    }
+
+    fn builder(size_limit: Option<usize>, match_kind: MatchKind) -> dense::Builder {
+        // allow patterns that match invalid utf8, e.g. (?-u:[\x80-\xFF]),
+        // unicode mode is still the default
+        let mut builder = DFA::builder();
+        builder
+            .configure(
+                DFA::config()
+                    .dfa_size_limit(size_limit)
+                    .match_kind(match_kind),
+            )
+            .syntax(syntax::Config::new().utf8(false))
+            .thompson(thompson::Config::new().utf8(false));
+        builder
+    }
+
+    // indices of the patterns matching the input that led to the state
+    pub(crate) fn eoi_matches(&self, state: StateID) -> Vec<usize> {
+        let eoi = self.dfa.next_eoi_state(state);
+        if !self.dfa.is_match_state(eoi) {
+            return vec![];
+        }
+        let mut matches: Vec<_> = (0..self.dfa.match_len(eoi))
+            .map(|i| self.dfa.match_pattern(eoi, i).as_usize())
+            .collect();
+        matches.sort_unstable();
+        matches
+    }
+
+    // dfa accepting exactly the byte sequences the pattern does not match,
+    // only for constraints, lexers use the dfa of the pattern itself
+    pub(crate) fn complement(mut self) -> Self {
+        // all reachable states, including the dead state, which the
+        // complement never leaves again
+        let start = self.get_start_state();
+        let mut reachable = HashSet::from([start]);
+        let mut stack = vec![start];
+        let mut predecessors: HashMap<StateID, Vec<StateID>> = HashMap::new();
+        while let Some(state) = stack.pop() {
+            for b in 0..=255 {
+                let next = self.dfa.next_state(state, b);
+                predecessors.entry(next).or_default().push(state);
+                if reachable.insert(next) {
+                    stack.push(next);
+                }
+            }
+        }
+        // states that can reach a non-matching state are live in the complement
+        let mut live: HashSet<_> = reachable
+            .iter()
+            .copied()
+            .filter(|&state| !self.is_eoi_match(state))
+            .collect();
+        let mut stack: Vec<_> = live.iter().copied().collect();
+        while let Some(state) = stack.pop() {
+            for &prev in predecessors.get(&state).into_iter().flatten() {
+                if live.insert(prev) {
+                    stack.push(prev);
+                }
+            }
+        }
+        self.complement = Some(reachable.difference(&live).copied().collect());
+        self
+    }
+
+    pub(crate) fn is_complement(&self) -> bool {
+        self.complement.is_some()
+    }
+
+    // serialized dfa, identical for identical patterns and build configs
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.dfa.to_bytes_little_endian().0
+    }
+
+    // the dfa together with the states universal for its complement
+    pub(crate) fn serialize(&self, out: &mut Vec<u8>) {
+        write_section(out, &self.to_bytes());
+        let Some(universal) = &self.complement else {
+            out.push(0);
+            return;
+        };
+        out.push(1);
+        let mut states: Vec<_> = universal.iter().map(|state| state.as_u32()).collect();
+        states.sort_unstable();
+        out.extend((states.len() as u64).to_le_bytes());
+        for state in states {
+            out.extend(state.to_le_bytes());
+        }
+    }
+
+    pub(crate) fn deserialize(reader: &mut ByteReader<'_>) -> Result<Self, Box<dyn Error>> {
+        let bytes = reader.section()?;
+        // the dfa is read from u32 aligned memory, which the input need not be
+        let mut buffer = vec![0; bytes.len() + 3];
+        let offset = buffer.as_ptr().align_offset(4).min(3);
+        buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let (dfa, _) = DFA::from_bytes(&buffer[offset..offset + bytes.len()])
+            .map_err(|e| format!("invalid serialized dfa: {e}"))?;
+        let dfa = dfa.to_owned();
+        let complement = match reader.byte()? {
+            0 => None,
+            1 => {
+                let len = reader.u64()?;
+                let mut universal = HashSet::new();
+                for _ in 0..len {
+                    let state = StateID::new(reader.u32()? as usize)
+                        .map_err(|e| format!("invalid serialized state: {e}"))?;
+                    universal.insert(state);
+                }
+                Some(universal)
+            }
+            flag => return Err(format!("invalid complement flag {flag}").into()),
+        };
+        Ok(Self { dfa, complement })
+    }
+
+    pub(crate) fn num_states(&self) -> usize {
+        let start = self.get_start_state();
+        let mut seen = HashSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(state) = stack.pop() {
+            for b in 0..=255 {
+                let next = self.dfa.next_state(state, b);
+                if seen.insert(next) {
+                    stack.push(next);
+                }
+            }
+        }
+        seen.len()
+    }
+
+    // states reachable from the start state with their predecessors,
+    // leaving out dead states and those that cannot be left
+    pub(crate) fn predecessors(&self) -> HashMap<StateID, Vec<StateID>> {
+        let start = self.get_start_state();
+        let mut predecessors: HashMap<StateID, Vec<StateID>> = HashMap::from([(start, vec![])]);
+        let mut stack = vec![start];
+        while let Some(state) = stack.pop() {
+            for b in 0..=255 {
+                let Some(next) = self.step(state, b) else {
+                    continue;
+                };
+                let preds = predecessors.entry(next).or_insert_with(|| {
+                    stack.push(next);
+                    vec![]
+                });
+                preds.push(state);
+            }
+        }
+        predecessors
+    }
+
+    // reachable states from which a match state can be reached
+    pub(crate) fn live_states(&self) -> HashSet<StateID> {
+        let predecessors = self.predecessors();
+        let mut stack: Vec<_> = predecessors
+            .keys()
+            .copied()
+            .filter(|&state| self.is_eoi_match(state))
+            .collect();
+        let mut live: HashSet<_> = stack.iter().copied().collect();
+        while let Some(state) = stack.pop() {
+            for &prev in &predecessors[&state] {
+                if live.insert(prev) {
+                    stack.push(prev);
+                }
+            }
+        }
+        live
+    }
+
+    // bytes that occur in matches or valid prefixes,
+    // i.e. that lead from a reachable state to a live one
+    pub(crate) fn live_bytes(&self) -> [bool; 256] {
+        let start = self.get_start_state();
+        let mut live = [false; 256];
+        let mut seen = HashSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(state) = stack.pop() {
+            for b in 0..=255 {
+                let Some(next) = self.step(state, b) else {
+                    continue;
+                };
+                live[b as usize] = true;
+                if seen.insert(next) {
+                    stack.push(next);
+                }
+            }
+        }
+        live
+    }
+
+    // shortest match, preferring bytes in the order of preferred_bytes
+    // among matches of equal length, e.g. for examples
+    pub(crate) fn shortest_match(&self) -> Option<Vec<u8>> {
+        self.shortest_completion(self.get_start_state())
+    }
+
+    // shortest bytes leading from the state to a match, like shortest_match
+    pub(crate) fn shortest_completion(&self, start: StateID) -> Option<Vec<u8>> {
+        let preference = preferred_bytes();
+        // breadth first search, remembering the previous state and byte
+        let mut previous = HashMap::from([(start, None)]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(state) = queue.pop_front() {
+            if self.is_eoi_match(state) {
+                let mut bytes = vec![];
+                let mut current = state;
+                while let Some((prev, b)) = previous[&current] {
+                    bytes.push(b);
+                    current = prev;
+                }
+                bytes.reverse();
+                return Some(bytes);
+            }
+            for &b in &preference {
+                let Some(next) = self.step(state, b) else {
+                    continue;
+                };
+                if let Entry::Vacant(entry) = previous.entry(next) {
+                    entry.insert(Some((state, b)));
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
     }
 
     #[inline]
@@ -124,20 +459,40 @@ impl PrefixDFA {
     #[inline]
     pub(crate) fn drive(&self, mut state: StateID, continuation: &[u8]) -> Option<StateID> {
         for &b in continuation {
-            state = self.dfa.next_state(state, b);
-            if self.is_dead_or_quit(state) {
-                return None;
-            }
+            state = self.step(state, b)?;
         }
-        // normally we would only check for eoi match,
-        // but for a prefix dfa we also need to check for continuations
-        if self.is_eoi_match(state) || self.has_continuation(state) {
+        if self.is_valid(state) {
             Some(state)
         } else {
             None
         }
     }
 
+    // single transition without checking whether the
+    // resulting state can still lead to a match
+    #[inline]
+    pub(crate) fn step(&self, state: StateID, byte: u8) -> Option<StateID> {
+        let next = self.dfa.next_state(state, byte);
+        if let Some(universal) = &self.complement {
+            return (!universal.contains(&next)).then_some(next);
+        }
+        if self.is_dead_or_quit(next) {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_valid(&self, state: StateID) -> bool {
+        if let Some(universal) = &self.complement {
+            return !universal.contains(&state);
+        }
+        // normally we would only check for eoi match,
+        // but for a prefix dfa we also need to check for continuations
+        self.is_eoi_match(state) || self.has_continuation(state)
+    }
+
     #[inline]
     pub(crate) fn get_start_state(&self) -> StateID {
         self.dfa
@@ -147,7 +502,7 @@ impl PrefixDFA {
 
     #[inline]
     pub(crate) fn is_eoi_match(&self, state: StateID) -> bool {
-        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state)) != self.complement.is_some()
     }
 
     #[inline]
@@ -175,16 +530,58 @@ impl PrefixDFA {
     }
 }
 
+// sections of serialized constraints are prefixed with their length,
+// all integers are little endian
+pub(crate) fn write_section(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u64).to_le_bytes());
+    out.extend(bytes);
+}
+
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if len > self.bytes.len() {
+            return Err("unexpected end of serialized data".into());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub(crate) fn section(&mut self) -> Result<&'a [u8], Box<dyn Error>> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len)?)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
 pub fn optimized_prefix_order<C>(continuations: &[C]) -> (Vec<usize>, Vec<usize>)
 where
     C: AsRef<[u8]>,
 {
-    let permutation: Vec<_> = continuations
-        .iter()
-        .enumerate()
-        .sorted_by(|(_, a), (_, b)| a.as_ref().cmp(b.as_ref()))
-        .map(|(i, _)| i)
-        .collect();
+    let permutation = run_length_order(continuations);
     let mut skips = vec![0; continuations.len()];
     for i in 0..permutation.len() {
         // if the current key is a prefix of the next one, we can skip the
@@ -202,6 +599,275 @@ where
     (permutation, skips)
 }
 
+// fingerprint of a constraint state that is stable across runs and processes,
+// equal states have equal fingerprints; only comparable between states
+// of the same constraint
+pub fn state_fingerprint<S: Hash>(state: &S) -> u64 {
+    let mut hasher = FxHasher::default();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+// order of continuations such that continuations sharing a prefix are adjacent,
+// constraints usually allow or disallow those together, so valid continuations
+// of a state form few long runs of ids in the reordered vocabulary;
+// the i-th continuation in the new order is continuations[order[i]]
+pub fn run_length_order<C>(continuations: &[C]) -> Vec<usize>
+where
+    C: AsRef<[u8]>,
+{
+    continuations
+        .iter()
+        .enumerate()
+        .sorted_by(|(_, a), (_, b)| a.as_ref().cmp(b.as_ref()))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// sets bit i % 32 of word i / 32 for every index i and clears all other bits,
+// the packed mask layout of common gpu sampling kernels
+pub(crate) fn pack_indices_u32(indices: impl IntoIterator<Item = usize>, out: &mut [u32]) {
+    out.fill(0);
+    for i in indices {
+        out[i / 32] |= 1 << (i % 32);
+    }
+}
+
+// half-open ranges of consecutive indices, indices do not need to be sorted
+pub(crate) fn index_ranges(indices: impl IntoIterator<Item = usize>) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for i in indices.into_iter().sorted_unstable() {
+        let i = i as u32;
+        match ranges.last_mut() {
+            Some((_, end)) if *end == i => *end += 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+    ranges
+}
+
+pub(crate) fn unsigned_range_pattern(min: u64, max: u64) -> Vec<String> {
+    // split [min, max] into ranges of numbers with the same digit count,
+    // then into aligned blocks that can be expressed as
+    // <common prefix>[a-b][0-9]{k}
+    let mut alternatives = vec![];
+    let mut lower = 0;
+    for digits in 1..=20u32 {
+        let upper = 10u64.checked_pow(digits).map_or(u64::MAX, |p| p - 1);
+        let start = min.max(lower);
+        let end = max.min(upper);
+        if start <= end {
+            let mut start = start;
+            loop {
+                let mut k = 0;
+                while let Some(p) = 10u64.checked_pow(k + 1) {
+                    if k + 1 >= digits
+                        || start % p != 0
+                        || start.checked_add(p - 1).is_none_or(|e| e > end)
+                    {
+                        break;
+                    }
+                    k += 1;
+                }
+                let p = 10u64.pow(k);
+                let mut block_end = start + p - 1;
+                while block_end.checked_add(p).is_some_and(|e| e <= end)
+                    && (block_end + 1) % (p * 10) != 0
+                {
+                    block_end += p;
+                }
+                let s = start.to_string();
+                let e = block_end.to_string();
+                let pos = s.len() - k as usize - 1;
+                let mut pattern = s[..pos].to_string();
+                let (a, b) = (&s[pos..pos + 1], &e[pos..pos + 1]);
+                if a == b {
+                    pattern.push_str(a);
+                } else {
+                    pattern.push_str(&format!("[{a}-{b}]"));
+                }
+                match k {
+                    0 => (),
+                    1 => pattern.push_str("[0-9]"),
+                    _ => pattern.push_str(&format!("[0-9]{{{k}}}")),
+                }
+                alternatives.push(pattern);
+                if block_end >= end {
+                    break;
+                }
+                start = block_end + 1;
+            }
+        }
+        if upper >= max {
+            break;
+        }
+        lower = upper + 1;
+    }
+    alternatives
+}
+
+// pattern for the integers in [min, max] padded to width with the pad
+// character, e.g. 01 to 12 for months; all matches have the same length,
+// so leftmost-first matching never cuts off an alternative
+pub(crate) fn padded_range_pattern(min: u64, max: u64, width: u32, pad: char) -> String {
+    let mut alternatives = vec![];
+    for digits in 1..=width {
+        let lower = if digits == 1 {
+            0
+        } else {
+            10u64.pow(digits - 1)
+        };
+        let upper = 10u64.pow(digits) - 1;
+        if min.max(lower) > max.min(upper) {
+            continue;
+        }
+        let padding = escape(&pad.to_string()).repeat((width - digits) as usize);
+        alternatives.extend(
+            unsigned_range_pattern(min.max(lower), max.min(upper))
+                .into_iter()
+                .map(|pattern| format!("{padding}{pattern}")),
+        );
+    }
+    format!("(?:{})", alternatives.join("|"))
+}
+
+pub(crate) fn integer_range_pattern(min: i64, max: i64) -> Result<String, Box<dyn Error>> {
+    if min > max {
+        return Err(format!("invalid integer range [{min}, {max}]").into());
+    }
+    // alternatives with more digits come first, because
+    // leftmost-first matching would otherwise stop after the shorter ones
+    let mut alternatives = vec![];
+    if min < 0 {
+        let abs_min = if max < 0 { max.unsigned_abs() } else { 1 };
+        let negative = unsigned_range_pattern(abs_min, min.unsigned_abs());
+        alternatives.push(format!("-(?:{})", negative.iter().rev().join("|")));
+    }
+    if max >= 0 {
+        alternatives.extend(
+            unsigned_range_pattern(min.max(0) as u64, max as u64)
+                .into_iter()
+                .rev(),
+        );
+    }
+    Ok(format!("(?:{})", alternatives.join("|")))
+}
+
+// maps byte offsets of a transformed text back to byte offsets of the original
+// text, e.g. to report spans found in a normalized text in terms of the input;
+// the text is transformed segment by segment, offsets within a transformed
+// segment map to the start or end of the original one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OffsetMap {
+    // original segment of every transformed byte
+    segments: Vec<(usize, usize)>,
+    original_len: usize,
+}
+
+impl OffsetMap {
+    // appends the transformed bytes of the next original segment
+    pub fn push(&mut self, original: (usize, usize), len: usize) {
+        assert!(
+            original.0 >= self.original_len && original.0 <= original.1,
+            "segments have to be pushed in order"
+        );
+        self.segments.extend(std::iter::repeat_n(original, len));
+        self.original_len = original.1;
+    }
+
+    // length of the transformed text
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn original_start(&self, offset: usize) -> usize {
+        self.segments
+            .get(offset)
+            .map_or(self.original_len, |&(start, _)| start)
+    }
+
+    pub fn original_end(&self, offset: usize) -> usize {
+        match offset.checked_sub(1) {
+            Some(last) => self
+                .segments
+                .get(last)
+                .map_or(self.original_len, |&(_, end)| end),
+            None => 0,
+        }
+    }
+
+    // empty spans stay empty, non-empty spans cover
+    // all original segments they overlap with
+    pub fn original_span(&self, (start, end): (usize, usize)) -> (usize, usize) {
+        let original_start = self.original_start(start);
+        if start >= end {
+            (original_start, original_start)
+        } else {
+            (original_start, self.original_end(end))
+        }
+    }
+}
+
+// unicode normalization of inputs, e.g. for case-insensitive grammars written
+// in lowercase; bytes that are not valid utf8 are kept as is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Normalization {
+    // canonical composition (nfc)
+    pub nfc: bool,
+    pub lowercase: bool,
+}
+
+impl Normalization {
+    pub fn is_identity(&self) -> bool {
+        !self.nfc && !self.lowercase
+    }
+}
+
+// whether composition never crosses the boundary before the char
+fn is_composition_boundary(c: char) -> bool {
+    canonical_combining_class(c) == 0 && is_nfc_quick(std::iter::once(c)) != IsNormalized::Maybe
+}
+
+// normalized input together with the map of its byte offsets back to the input
+pub fn normalize(input: &[u8], normalization: Normalization) -> (Vec<u8>, OffsetMap) {
+    let mut output = Vec::with_capacity(input.len());
+    let mut map = OffsetMap::default();
+    let mut offset = 0;
+    for chunk in input.utf8_chunks() {
+        let valid = chunk.valid();
+        let mut starts: Vec<_> = valid
+            .char_indices()
+            .filter(|&(i, c)| i == 0 || !normalization.nfc || is_composition_boundary(c))
+            .map(|(i, _)| i)
+            .collect();
+        starts.push(valid.len());
+        for (&start, &end) in starts.iter().tuple_windows() {
+            let segment = &valid[start..end];
+            let mut normalized = if normalization.nfc {
+                segment.nfc().collect()
+            } else {
+                segment.to_string()
+            };
+            if normalization.lowercase {
+                normalized = normalized.to_lowercase();
+            }
+            output.extend_from_slice(normalized.as_bytes());
+            map.push((offset + start, offset + end), normalized.len());
+        }
+        offset += valid.len();
+        for &b in chunk.invalid() {
+            output.push(b);
+            map.push((offset, offset + 1), 1);
+            offset += 1;
+        }
+    }
+    (output, map)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -265,6 +931,17 @@ mod test {
         assert!(pdfa.is_eoi_match(state));
     }
 
+    #[test]
+    fn test_num_states() {
+        // start, a, ab, the (delayed) match and the dead state
+        let pdfa = PrefixDFA::new("ab").unwrap();
+        assert_eq!(pdfa.num_states(), 5);
+        let err = PrefixDFA::with_size_limit("[a-z]{50}[0-9]{50}", Some(1024))
+            .err()
+            .unwrap();
+        assert!(err.is_size_limit_exceeded());
+    }
+
     #[test]
     fn test_prefix_match() {
         let pdfa = PrefixDFA::new("abcdef").unwrap();
@@ -304,6 +981,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_index_ranges() {
+        assert!(index_ranges([]).is_empty());
+        assert_eq!(
+            index_ranges([7, 1, 2, 3, 5, 8, 9]),
+            vec![(1, 4), (5, 6), (7, 10)]
+        );
+        let continuations = ["b", "ab", "c", "aa", "ba"];
+        let order = run_length_order(&continuations);
+        assert_eq!(order, vec![3, 1, 0, 4, 2]);
+        // all continuations starting with a or b form a single run
+        let reordered: Vec<_> = order.iter().map(|&i| continuations[i]).collect();
+        let ab = (0..reordered.len()).filter(|&i| !reordered[i].starts_with('c'));
+        assert_eq!(index_ranges(ab), vec![(0, 4)]);
+    }
+
     #[test]
     fn test_optimized_prefix_order() {
         let items = ["de", "a", "d", "ab", "abc", "b"];
@@ -312,9 +1005,118 @@ mod test {
         assert_eq!(skips, vec![2, 1, 0, 0, 1, 0]);
     }
 
+    #[test]
+    fn test_integer_range_pattern() {
+        for (min, max) in [
+            (0, 9),
+            (0, 255),
+            (1, 12),
+            (7, 1234),
+            (-40, 40),
+            (-99, -5),
+            (0, 0),
+        ] {
+            let pattern = integer_range_pattern(min, max).unwrap();
+            let pdfa = PrefixDFA::new(&pattern).unwrap();
+            let is_match = |s: &str| {
+                pdfa.get_state(s.as_bytes())
+                    .is_some_and(|state| pdfa.is_eoi_match(state))
+            };
+            for i in (min - 200)..=(max + 200) {
+                assert_eq!(
+                    is_match(&i.to_string()),
+                    min <= i && i <= max,
+                    "{i} with pattern {pattern} for range [{min}, {max}]"
+                );
+            }
+            // no leading zeros
+            assert!(!is_match("01"));
+            assert!(!is_match("-0"));
+        }
+        assert!(integer_range_pattern(2, 1).is_err());
+        let pattern = integer_range_pattern(i64::MIN, i64::MAX).unwrap();
+        let pdfa = PrefixDFA::new(&pattern).unwrap();
+        for i in [i64::MIN, -1, 0, i64::MAX] {
+            let state = pdfa.get_state(i.to_string().as_bytes()).unwrap();
+            assert!(pdfa.is_eoi_match(state));
+        }
+    }
+
+    #[test]
+    fn test_padded_range_pattern() {
+        for (min, max, width, pad) in [
+            (1, 12, 2, '0'),
+            (1, 31, 2, ' '),
+            (0, 23, 2, '0'),
+            (1, 366, 3, '0'),
+        ] {
+            let pattern = padded_range_pattern(min, max, width, pad);
+            let pdfa = PrefixDFA::new(&pattern).unwrap();
+            for i in 0..1000 {
+                let padded =
+                    format!("{i:>width$}", width = width as usize).replace(' ', &pad.to_string());
+                let is_match = pdfa
+                    .get_state(padded.as_bytes())
+                    .is_some_and(|state| pdfa.is_eoi_match(state));
+                assert_eq!(
+                    is_match,
+                    min <= i && i <= max,
+                    "{padded} with pattern {pattern}"
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_make_anchored() {
         assert_eq!(make_anchored("a"), "^(?:a)");
         assert_eq!(make_anchored("^a"), "^a");
     }
+
+    #[test]
+    fn test_normalize() {
+        let lowercase = Normalization {
+            nfc: false,
+            lowercase: true,
+        };
+        let (text, map) = normalize("SELECT Ä".as_bytes(), lowercase);
+        assert_eq!(text, "select ä".as_bytes());
+        assert_eq!(map.original_span((0, 6)), (0, 6));
+        assert_eq!(map.original_span((7, 9)), (7, 9));
+
+        // İ lowercases to i followed by a combining dot, 2 bytes become 3
+        let (text, map) = normalize("aİb".as_bytes(), lowercase);
+        assert_eq!(text, "ai\u{307}b".as_bytes());
+        assert_eq!(map.len(), 5);
+        assert_eq!(map.original_span((4, 5)), (3, 4));
+        assert_eq!(map.original_span((1, 2)), (1, 3));
+        assert_eq!(map.original_span((2, 2)), (1, 1));
+        assert_eq!(map.original_span((5, 5)), (4, 4));
+
+        // a followed by a combining diaeresis composes to ä, 3 bytes become 2
+        let nfc = Normalization {
+            nfc: true,
+            lowercase: false,
+        };
+        let input = "xa\u{308}y".as_bytes();
+        let (text, map) = normalize(input, nfc);
+        assert_eq!(text, "xäy".as_bytes());
+        assert_eq!(map.original_span((1, 3)), (1, 4));
+        assert_eq!(map.original_span((3, 4)), (4, 5));
+
+        // invalid utf8 is kept
+        let (text, map) = normalize(
+            b"A\xffB",
+            Normalization {
+                nfc: true,
+                lowercase: true,
+            },
+        );
+        assert_eq!(text, b"a\xffb");
+        assert_eq!(map.original_span((1, 2)), (1, 2));
+
+        let (text, map) = normalize(b"", nfc);
+        assert!(text.is_empty() && map.is_empty());
+        assert_eq!(map.original_span((0, 0)), (0, 0));
+    }
 }
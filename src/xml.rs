@@ -0,0 +1,659 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fmt::Write,
+};
+
+use indexmap::IndexMap;
+use regex::escape;
+
+use crate::utils::lexer_pattern;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    One,
+    Optional,
+    Many,
+    OneOrMore,
+}
+
+#[derive(Debug, Clone)]
+enum Particle {
+    Element(String, Repeat),
+    Sequence(Vec<Particle>, Repeat),
+    Choice(Vec<Particle>, Repeat),
+}
+
+#[derive(Debug, Clone)]
+enum Content {
+    Empty,
+    Any,
+    // text mixed with the given elements in any order
+    Mixed(Vec<String>),
+    Children(Particle),
+}
+
+#[derive(Debug, Clone)]
+enum AttributeDefault {
+    Required,
+    Implied,
+    Fixed(String),
+    Value,
+}
+
+#[derive(Debug, Clone)]
+struct Attribute {
+    name: String,
+    // allowed values of enumerated and notation attributes
+    values: Option<Vec<String>>,
+    default: AttributeDefault,
+}
+
+// elements and attributes in declaration order
+#[derive(Debug, Default)]
+struct Dtd {
+    elements: IndexMap<String, Content>,
+    attributes: HashMap<String, Vec<Attribute>>,
+    entities: BTreeSet<String>,
+}
+
+// splits the declarations of a dtd into their tokens, e.g.
+// <!ELEMENT a (b, c*)> into ELEMENT a ( b , c * ); comments and processing
+// instructions are skipped, so are the doctype around an internal subset
+fn declarations(dtd: &str) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let mut declarations = vec![];
+    let mut rest = dtd.trim_start();
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let end = comment.find("-->").ok_or("unterminated comment")?;
+            rest = &comment[end + 3..];
+        } else if let Some(pi) = rest.strip_prefix("<?") {
+            let end = pi.find("?>").ok_or("unterminated processing instruction")?;
+            rest = &pi[end + 2..];
+        } else if let Some(doctype) = rest.strip_prefix("<!DOCTYPE") {
+            let end = doctype
+                .find(['[', '>'])
+                .ok_or("unterminated doctype declaration")?;
+            rest = &doctype[end + 1..];
+        } else if let Some(end) = rest.strip_prefix(']') {
+            rest = end
+                .trim_start()
+                .strip_prefix('>')
+                .ok_or("expected > after the internal subset")?;
+        } else if let Some(declaration) = rest.strip_prefix("<!") {
+            let mut tokens = vec![];
+            let mut chars = declaration.char_indices().peekable();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '>' => {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    c if c.is_whitespace() => {}
+                    '"' | '\'' => {
+                        let close = declaration[i + 1..]
+                            .find(c)
+                            .ok_or("unterminated literal in declaration")?;
+                        tokens.push(declaration[i..i + close + 2].to_string());
+                        while chars.next_if(|&(j, _)| j <= i + close + 1).is_some() {}
+                    }
+                    '(' | ')' | '|' | ',' | '?' | '*' | '+' => tokens.push(c.to_string()),
+                    _ => {
+                        let mut word = c.to_string();
+                        while let Some((_, c)) = chars
+                            .next_if(|&(_, c)| !c.is_whitespace() && !"()|,?*+>\"'".contains(c))
+                        {
+                            word.push(c);
+                        }
+                        if word.starts_with('%') {
+                            return Err(format!(
+                                "parameter entities are not supported, found {word}"
+                            )
+                            .into());
+                        }
+                        tokens.push(word);
+                    }
+                }
+            }
+            let end = end.ok_or("unterminated declaration")?;
+            declarations.push(tokens);
+            rest = &declaration[end..];
+        } else {
+            let line: String = rest.chars().take_while(|&c| c != '\n').collect();
+            return Err(format!("expected a declaration, found {line}").into());
+        }
+        rest = rest.trim_start();
+    }
+    Ok(declarations)
+}
+
+fn unquote(literal: &str) -> String {
+    literal[1..literal.len() - 1].to_string()
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String, Box<dyn Error>> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of declaration")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Box<dyn Error>> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("expected {expected}, found {token}").into());
+        }
+        Ok(())
+    }
+
+    fn repeat(&mut self) -> Repeat {
+        let repeat = match self.peek() {
+            Some("?") => Repeat::Optional,
+            Some("*") => Repeat::Many,
+            Some("+") => Repeat::OneOrMore,
+            _ => return Repeat::One,
+        };
+        self.pos += 1;
+        repeat
+    }
+
+    fn content(&mut self) -> Result<Content, Box<dyn Error>> {
+        match self.peek() {
+            Some("EMPTY") => {
+                self.pos += 1;
+                return Ok(Content::Empty);
+            }
+            Some("ANY") => {
+                self.pos += 1;
+                return Ok(Content::Any);
+            }
+            _ => {}
+        }
+        self.expect("(")?;
+        if self.peek() != Some("#PCDATA") {
+            return self.group().map(Content::Children);
+        }
+        self.pos += 1;
+        let mut names = vec![];
+        while self.peek() == Some("|") {
+            self.pos += 1;
+            names.push(self.next()?);
+        }
+        self.expect(")")?;
+        // (#PCDATA) can be followed by a star, mixed content with elements must
+        if self.repeat() != Repeat::Many && !names.is_empty() {
+            return Err("mixed content with elements must end with )*".into());
+        }
+        Ok(Content::Mixed(names))
+    }
+
+    // a choice or sequence after its opening parenthesis
+    fn group(&mut self) -> Result<Particle, Box<dyn Error>> {
+        let mut particles = vec![self.particle()?];
+        let separator = match self.peek() {
+            Some(sep @ ("|" | ",")) => Some(sep.to_string()),
+            _ => None,
+        };
+        while let Some(separator) = &separator {
+            match self.peek() {
+                Some(sep) if sep == separator => {
+                    self.pos += 1;
+                    particles.push(self.particle()?);
+                }
+                _ => break,
+            }
+        }
+        self.expect(")")?;
+        let repeat = self.repeat();
+        Ok(match separator.as_deref() {
+            Some("|") => Particle::Choice(particles, repeat),
+            _ => Particle::Sequence(particles, repeat),
+        })
+    }
+
+    fn particle(&mut self) -> Result<Particle, Box<dyn Error>> {
+        let token = self.next()?;
+        if token == "(" {
+            return self.group();
+        } else if "()|,?*+".contains(token.as_str()) || token.starts_with('#') {
+            return Err(format!("unexpected {token} in content model").into());
+        }
+        Ok(Particle::Element(token, self.repeat()))
+    }
+
+    fn attributes(&mut self) -> Result<Vec<Attribute>, Box<dyn Error>> {
+        let mut attributes = vec![];
+        while self.peek().is_some() {
+            let name = self.next()?;
+            let ty = self.next()?;
+            let values = match ty.as_str() {
+                "(" | "NOTATION" => {
+                    if ty == "NOTATION" {
+                        self.expect("(")?;
+                    }
+                    let mut values = vec![self.next()?];
+                    while self.peek() == Some("|") {
+                        self.pos += 1;
+                        values.push(self.next()?);
+                    }
+                    self.expect(")")?;
+                    Some(values)
+                }
+                "CDATA" | "ID" | "IDREF" | "IDREFS" | "ENTITY" | "ENTITIES" | "NMTOKEN"
+                | "NMTOKENS" => None,
+                _ => return Err(format!("unknown type {ty} of attribute {name}").into()),
+            };
+            let default = match self.next()?.as_str() {
+                "#REQUIRED" => AttributeDefault::Required,
+                "#IMPLIED" => AttributeDefault::Implied,
+                "#FIXED" => {
+                    let value = self.next()?;
+                    if !value.starts_with(['"', '\'']) {
+                        return Err(format!("expected fixed value of attribute {name}").into());
+                    }
+                    AttributeDefault::Fixed(unquote(&value))
+                }
+                value if value.starts_with(['"', '\'']) => AttributeDefault::Value,
+                other => return Err(format!("invalid default {other} of attribute {name}").into()),
+            };
+            attributes.push(Attribute {
+                name,
+                values,
+                default,
+            });
+        }
+        Ok(attributes)
+    }
+}
+
+fn parse(dtd: &str) -> Result<Dtd, Box<dyn Error>> {
+    let mut parsed = Dtd::default();
+    for tokens in declarations(dtd)? {
+        let mut parser = Parser { tokens, pos: 0 };
+        match parser.next()?.as_str() {
+            "ELEMENT" => {
+                let name = parser.next()?;
+                let content = parser.content()?;
+                if parser.peek().is_some() {
+                    return Err(format!("trailing tokens in declaration of element {name}").into());
+                }
+                if parsed.elements.insert(name.clone(), content).is_some() {
+                    return Err(format!("element {name} is declared twice").into());
+                }
+            }
+            "ATTLIST" => {
+                let element = parser.next()?;
+                let attributes = parser.attributes()?;
+                // the first declaration of an attribute is binding
+                let declared = parsed.attributes.entry(element).or_default();
+                for attribute in attributes {
+                    if declared.iter().all(|a| a.name != attribute.name) {
+                        declared.push(attribute);
+                    }
+                }
+            }
+            "ENTITY" => {
+                parsed.entities.insert(parser.next()?);
+            }
+            "NOTATION" => {}
+            other => return Err(format!("unknown declaration {other}").into()),
+        }
+    }
+    if let Some(element) = parsed
+        .attributes
+        .keys()
+        .find(|element| !parsed.elements.contains_key(*element))
+    {
+        return Err(format!("attributes declared for undeclared element {element}").into());
+    }
+    Ok(parsed)
+}
+
+const LEXER_FRAGMENTS: &str = r#"WS [\x20\t\r\n]
+REF &({ENTITIES}|#[0-9]+|#x[0-9a-fA-F]+);
+"#;
+
+struct Builder<'a> {
+    dtd: &'a Dtd,
+    // rule names and their alternatives
+    rules: Vec<(String, Vec<String>)>,
+    elements: HashMap<String, String>,
+    // literal attribute values and their token names
+    values: IndexMap<String, String>,
+    // attribute names and their token names
+    attributes: IndexMap<String, String>,
+}
+
+impl Builder<'_> {
+    fn rule(&mut self, mut alternatives: Vec<String>) -> String {
+        let mut seen = BTreeSet::new();
+        alternatives.retain(|alternative| seen.insert(alternative.clone()));
+        let name = format!("s{}", self.rules.len());
+        self.rules.push((name.clone(), alternatives));
+        name
+    }
+
+    fn element(&mut self, name: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(rule) = self.elements.get(name) {
+            return Ok(rule.clone());
+        }
+        let index = self
+            .dtd
+            .elements
+            .get_index_of(name)
+            .ok_or_else(|| format!("element {name} is not declared"))?;
+        // reserve the rule before descending, elements can be recursive
+        let idx = self.rules.len();
+        let rule = self.rule(vec![]);
+        self.elements.insert(name.to_string(), rule.clone());
+        let attributes = self.attributes(name)?;
+        let (content, nullable) = self.content(&self.dtd.elements[index].clone())?;
+        let open = format!("'XML_OPEN{index}' {attributes}");
+        let mut alternatives = vec![format!("{open} '>' {content} 'XML_CLOSE{index}'")];
+        if nullable {
+            alternatives.push(format!("{open} '/>'"));
+        }
+        self.rules[idx].1 = alternatives;
+        Ok(rule)
+    }
+
+    // attributes in declaration order, required ones have to be present
+    fn attributes(&mut self, element: &str) -> Result<String, Box<dyn Error>> {
+        let attributes = self
+            .dtd
+            .attributes
+            .get(element)
+            .cloned()
+            .unwrap_or_default();
+        let mut next = String::new();
+        for attribute in attributes.iter().rev() {
+            let len = self.attributes.len();
+            let token = self
+                .attributes
+                .entry(attribute.name.clone())
+                .or_insert_with(|| format!("XML_ATTR{len}"))
+                .clone();
+            let values = match (&attribute.default, &attribute.values) {
+                (AttributeDefault::Fixed(value), _) => vec![self.value(value)],
+                (_, Some(values)) => values.iter().map(|value| self.value(value)).collect(),
+                (_, None) => vec!["xml_cdata".to_string()],
+            };
+            let value = self.rule(values);
+            let present = format!("'{token}' {value} {next}");
+            next = match attribute.default {
+                AttributeDefault::Required => self.rule(vec![present]),
+                _ => self.rule(vec![present, next]),
+            };
+        }
+        Ok(next)
+    }
+
+    fn value(&mut self, value: &str) -> String {
+        let len = self.values.len();
+        let name = self
+            .values
+            .entry(value.to_string())
+            .or_insert_with(|| format!("XML_VAL{len}"));
+        format!("'{name}'")
+    }
+
+    // rule for the content and whether it can be empty
+    fn content(&mut self, content: &Content) -> Result<(String, bool), Box<dyn Error>> {
+        let items = match content {
+            Content::Empty => return Ok((String::new(), true)),
+            Content::Children(particle) => return self.particle(particle),
+            Content::Any => self.dtd.elements.keys().cloned().collect(),
+            Content::Mixed(names) => names.clone(),
+        };
+        let mut alternatives = vec!["xml_text".to_string()];
+        for name in &items {
+            alternatives.push(self.element(name)?);
+        }
+        let item = self.rule(alternatives);
+        Ok((self.repeat(&item, Repeat::Many), true))
+    }
+
+    fn particle(&mut self, particle: &Particle) -> Result<(String, bool), Box<dyn Error>> {
+        let (rule, nullable, repeat) = match particle {
+            Particle::Element(name, repeat) => (self.element(name)?, false, *repeat),
+            Particle::Sequence(particles, repeat) => {
+                let mut body = vec![];
+                let mut nullable = true;
+                for particle in particles {
+                    let (rule, n) = self.particle(particle)?;
+                    body.push(rule);
+                    nullable &= n;
+                }
+                (self.rule(vec![body.join(" ")]), nullable, *repeat)
+            }
+            Particle::Choice(particles, repeat) => {
+                let mut alternatives = vec![];
+                let mut nullable = false;
+                for particle in particles {
+                    let (rule, n) = self.particle(particle)?;
+                    alternatives.push(rule);
+                    nullable |= n;
+                }
+                (self.rule(alternatives), nullable, *repeat)
+            }
+        };
+        let nullable = nullable || matches!(repeat, Repeat::Optional | Repeat::Many);
+        Ok((self.repeat(&rule, repeat), nullable))
+    }
+
+    fn repeat(&mut self, rule: &str, repeat: Repeat) -> String {
+        match repeat {
+            Repeat::One => rule.to_string(),
+            Repeat::Optional => self.rule(vec![String::new(), rule.to_string()]),
+            Repeat::Many | Repeat::OneOrMore => {
+                let list = format!("s{}", self.rules.len());
+                let first = match repeat {
+                    Repeat::Many => String::new(),
+                    _ => rule.to_string(),
+                };
+                self.rule(vec![first, format!("{list} {rule}")])
+            }
+        }
+    }
+}
+
+// converts a document type definition into an LR(1) grammar and lexer for
+// documents with the given root element, e.g. a root element that has to
+// contain an optional title followed by any number of items; content models,
+// declared attributes with enumerated or fixed values and matching close tags
+// are enforced, attributes have to be written in declaration order, > in
+// text as &gt; and text cannot start with an unmatched quote; parameter
+// entities are not supported
+pub fn dtd_to_lr1(dtd: &str, root: &str) -> Result<(String, String), Box<dyn Error>> {
+    let parsed = parse(dtd)?;
+    let mut builder = Builder {
+        dtd: &parsed,
+        rules: vec![],
+        elements: HashMap::new(),
+        values: IndexMap::new(),
+        attributes: IndexMap::new(),
+    };
+    let element = builder.element(root)?;
+    let start = builder.rule(vec![element.clone(), format!("'XML_DECL' {element}")]);
+
+    let mut grammar = format!("%start {start}\n\n%%\n");
+    // quoted text is lexed as an attribute value if it matches one exactly
+    let mut text = vec!["'XML_TEXT'".to_string(), "'XML_CDATA'".to_string()];
+    text.extend(builder.values.values().map(|name| format!("'{name}'")));
+    let mut cdata = vec!["'XML_CDATA'".to_string()];
+    cdata.extend(builder.values.values().map(|name| format!("'{name}'")));
+    let token_rules = [
+        ("xml_text".to_string(), text),
+        ("xml_cdata".to_string(), cdata),
+    ];
+    for (name, alternatives) in builder.rules.iter().chain(&token_rules) {
+        let alternatives: Vec<_> = alternatives.iter().map(|a| a.trim()).collect();
+        write!(
+            grammar,
+            "\n{name}\n    : {}\n    ;\n",
+            alternatives.join("\n    | ")
+        )?;
+    }
+
+    let entities: Vec<_> = ["lt", "gt", "amp", "apos", "quot"]
+        .into_iter()
+        .chain(parsed.entities.iter().map(String::as_str))
+        .map(escape)
+        .collect();
+    let mut lexer = LEXER_FRAGMENTS.replace("{ENTITIES}", &entities.join("|"));
+    lexer.push_str("\n%%\n\n");
+    writeln!(
+        lexer,
+        "XML_DECL {}",
+        lexer_pattern(r"<\?xml([^?]|\?[^>])*\?>")
+    )?;
+    for (i, name) in parsed.elements.keys().enumerate() {
+        let name = escape(name);
+        writeln!(lexer, "XML_OPEN{i} {}", lexer_pattern(&format!("<{name}")))?;
+        writeln!(
+            lexer,
+            "XML_CLOSE{i} {}",
+            lexer_pattern(&format!("</{name}{{WS}}*>"))
+        )?;
+    }
+    for (name, token) in &builder.attributes {
+        let pattern = format!("{{WS}}+{}{{WS}}*={{WS}}*", escape(name));
+        writeln!(lexer, "{token} {}", lexer_pattern(&pattern))?;
+    }
+    // literal values before generic ones, so they win ties
+    for (value, token) in &builder.values {
+        let value = escape(value);
+        let pattern = format!(r#""{value}"|'{value}'"#);
+        writeln!(lexer, "{token} {}", lexer_pattern(&pattern))?;
+    }
+    writeln!(
+        lexer,
+        "XML_CDATA {}",
+        lexer_pattern(r#""([^<&"]|{REF})*"|'([^<&']|{REF})*'"#)
+    )?;
+    // text never contains > and never starts with whitespace, which is
+    // skipped, or a quote, so neither the end of a tag nor the attributes
+    // after a value are lexed as text; quoted text is lexed as a value
+    writeln!(
+        lexer,
+        "XML_TEXT {}",
+        lexer_pattern(r#"([^<&>"'\x20\t\r\n]|{REF})([^<&>]|{REF})*"#)
+    )?;
+    lexer.push_str("; {WS}+\n; <!--([^-]|-[^-])*-->\n");
+    Ok((grammar, lexer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{inline_rules, Constraint, LR1GrammarConstraint};
+
+    const DTD: &str = r#"
+        <!DOCTYPE note [
+        <!-- a note with a list of items -->
+        <!ELEMENT note (to+, from?, body, (item | group)*)>
+        <!ELEMENT to (#PCDATA)>
+        <!ELEMENT from (#PCDATA)>
+        <!ELEMENT body (#PCDATA | b | i)*>
+        <!ELEMENT b (#PCDATA)>
+        <!ELEMENT i (#PCDATA)>
+        <!ELEMENT item EMPTY>
+        <!ELEMENT group (item*)>
+        <!ATTLIST note
+            id CDATA #REQUIRED
+            lang (en | de) "en"
+            version CDATA #FIXED "1.0">
+        <!ATTLIST item key NMTOKEN #IMPLIED>
+        <!ENTITY sig "Bob">
+        ]>
+    "#;
+
+    fn constraint(dtd: &str, root: &str) -> LR1GrammarConstraint {
+        let (grammar, lexer) = dtd_to_lr1(dtd, root).unwrap();
+        let grammar = inline_rules(&grammar).unwrap();
+        LR1GrammarConstraint::new(
+            grammar.grammar(),
+            &lexer,
+            (0..=255).map(|b| vec![b]).collect(),
+        )
+        .unwrap()
+    }
+
+    fn is_match(constraint: &LR1GrammarConstraint, input: &str) -> bool {
+        constraint
+            .get_state(input.as_bytes())
+            .is_some_and(|state| constraint.is_match_state(&state))
+    }
+
+    #[test]
+    fn test_dtd() {
+        let c = constraint(DTD, "note");
+        assert!(is_match(
+            &c,
+            r#"<note id="1"><to>Ada</to><body>Hi</body></note>"#
+        ));
+        assert!(is_match(
+            &c,
+            r#"<?xml version="1.0"?>
+<note id='2' lang="de" version="1.0">
+  <to>Ada</to>
+  <to>Bob &amp; Eve</to>
+  <from>&sig;</from>
+  <!-- comment -->
+  <body>Hello <b>bold</b> and <i>"quoted"</i> text</body>
+  <item key="a"/>
+  <group><item></item><item /></group>
+  <group/>
+</note >"#
+        ));
+        // missing required attribute, wrong order, mismatched tags
+        assert!(!is_match(&c, r#"<note><to>A</to><body/></note>"#));
+        assert!(!is_match(
+            &c,
+            r#"<note id="1"><body>Hi</body><to>A</to></note>"#
+        ));
+        assert!(!is_match(&c, r#"<note id="1"><to>A</from><body/></note>"#));
+        assert!(c.get_state(b"<note id=\"1\"><to>A</to></note>").is_none());
+        // enumerated and fixed values, unknown attributes and entities
+        assert!(c.get_state(b"<note id=\"1\" lang=\"fr\"").is_none());
+        assert!(c.get_state(b"<note id=\"1\" version=\"2.0\"").is_none());
+        assert!(c.get_state(b"<note id=\"1\" color=").is_none());
+        assert!(c.get_state(b"<note id=\"1\"><to>&nbsp;").is_none());
+        // empty elements have no content, text is only allowed in mixed content
+        assert!(c
+            .get_state(b"<note id=\"1\"><to>A</to><body/><item>x")
+            .is_none());
+        assert!(c.get_state(b"<note id=\"1\">text").is_none());
+        assert!(c.get_state(b"<note id=\"1\"><to>").is_some());
+
+        let c = constraint(DTD, "group");
+        assert!(is_match(&c, "<group><item/></group>"));
+    }
+
+    #[test]
+    fn test_dtd_errors() {
+        assert!(dtd_to_lr1(DTD, "unknown").is_err());
+        assert!(dtd_to_lr1("<!ELEMENT a (b)>", "a").is_err());
+        assert!(dtd_to_lr1("<!ELEMENT a (#PCDATA | b)>", "a").is_err());
+        assert!(dtd_to_lr1("<!ELEMENT a EMPTY><!ELEMENT a ANY>", "a").is_err());
+        assert!(dtd_to_lr1("<!ELEMENT a EMPTY><!ATTLIST b c CDATA #IMPLIED>", "a").is_err());
+        assert!(dtd_to_lr1("<!ENTITY % p \"x\"><!ELEMENT a EMPTY>", "a").is_err());
+        assert!(dtd_to_lr1("<!ELEMENT a (b,c", "a").is_err());
+        let c = constraint("<!ELEMENT a ANY><!ELEMENT b EMPTY>", "a");
+        assert!(is_match(&c, "<a>x<b/><a><b></b></a></a>"));
+    }
+}